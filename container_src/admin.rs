@@ -0,0 +1,172 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::ToSocketAddrs;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::backend;
+use crate::error::Locked;
+use crate::lifecycle;
+use crate::logging;
+use crate::transport::{Address, Conn, Listener};
+use crate::RemoteFSClient;
+
+const COMMANDS: &str =
+    "stats, flush-cache, drop-connection [<index>], set-log-level <level>, dump-pending, reload or commit";
+
+// A unix socket, or TCP on a loopback address so the commands never leave
+// the container
+pub fn parse_address(value: &str) -> Result<Address, String> {
+    let invalid = || "expected unix://<path> or a loopback host:port".to_string();
+    match Address::parse(value).map_err(|_| invalid())? {
+        Address::Tcp(address) => {
+            let mut resolved = address.to_socket_addrs().map_err(|_| invalid())?.peekable();
+            if resolved.peek().is_none() || !resolved.all(|address| address.ip().is_loopback()) {
+                return Err(invalid());
+            }
+            Ok(Address::Tcp(address))
+        }
+        Address::Unix(path) => Ok(Address::Unix(path)),
+        Address::Vsock { .. } => Err(invalid()),
+    }
+}
+
+// Accepts operators' connections on `address`. Each line sent is a command,
+// answered with one line of JSON: `{"ok":true,"result":...}` or
+// `{"ok":false,"error":"..."}`.
+pub fn spawn(address: &Address, client: Arc<RemoteFSClient>) -> io::Result<()> {
+    let listener = Listener::bind(address)?;
+    info!("Accepting admin commands on {}", address);
+    thread::spawn(move || loop {
+        match listener.accept() {
+            Ok(stream) => {
+                let client = client.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(stream, &client) {
+                        warn!("Admin connection failed: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept an admin connection: {}", e),
+        }
+    });
+    Ok(())
+}
+
+fn serve(stream: Conn, client: &RemoteFSClient) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match run(client, &line) {
+            Ok(result) => json!({ "ok": true, "result": result }),
+            Err(error) => json!({ "ok": false, "error": error }),
+        };
+        writeln!(writer, "{}", reply)?;
+    }
+    Ok(())
+}
+
+fn run(client: &RemoteFSClient, line: &str) -> Result<Value, String> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let argument = words.next();
+    info!(command = line.trim(), "Admin command");
+    match command {
+        "stats" => Ok(stats(client)),
+        "flush-cache" => {
+            client.flush_caches();
+            Ok(Value::Null)
+        }
+        "drop-connection" => {
+            let index = match argument {
+                Some(index) => index.parse().map_err(|_| format!("invalid connection index '{}'", index))?,
+                None => 0,
+            };
+            match client.drop_connection(index) {
+                true => Ok(Value::Null),
+                false => Err(format!("no live connection {}", index)),
+            }
+        }
+        "set-log-level" => {
+            let level = argument.ok_or("set-log-level needs a level, such as debug or info,fsdaemon=trace")?;
+            logging::set_level(level).map(|_| Value::Null)
+        }
+        "dump-pending" => Ok(dump_pending(client)),
+        "reload" => crate::reload(client).map(|tunables| {
+            // Each mount's cache policy, the main one first
+            let caches: Vec<Value> = tunables
+                .caches
+                .iter()
+                .map(|cache| {
+                    json!({
+                        "cache_size": cache.cache_size,
+                        "dir_cache_ttl_ms": cache.dir_cache_ttl.as_millis() as u64,
+                        "readahead_window": cache.readahead_window,
+                        "readahead_memory": cache.readahead_memory,
+                    })
+                })
+                .collect();
+            json!({
+                "log_level": tunables.log_level,
+                "timeouts_ms": {
+                    "metadata": tunables.timeouts.metadata.as_millis() as u64,
+                    "read": tunables.timeouts.read.as_millis() as u64,
+                    "write": tunables.timeouts.write.as_millis() as u64,
+                    "sync": tunables.timeouts.sync.as_millis() as u64,
+                },
+                "mounts": caches,
+            })
+        }),
+        // Writes still buffered in the mounts aren't in the overlay or tier
+        // yet; the control file's commit flushes them first
+        "commit" => backend::commit(),
+        _ => Err(format!("unknown command '{}', expected {}", command, COMMANDS)),
+    }
+}
+
+pub fn stats(client: &RemoteFSClient) -> Value {
+    let negotiated = client.negotiated();
+    let channels = client.channels.locked();
+    let (state, since) = lifecycle::connection().state();
+    json!({
+        "state": state.as_str(),
+        "state_secs": since.as_secs(),
+        "connections": channels.iter().filter(|channel| channel.liveness.is_alive()).count(),
+        "primary": client.primary.locked().clone(),
+        "protocol_version": negotiated.map(|negotiated| negotiated.protocol_version),
+        "features": negotiated.map(|negotiated| {
+            let mut features: Vec<_> = negotiated.features.iter().collect();
+            features.sort();
+            features
+        }),
+        "in_flight": client.outstanding.locked().len(),
+        "max_in_flight": client.max_in_flight,
+        "waiting_for_connection": client.waiting.load(Ordering::Relaxed),
+        "pending_invalidations": client.pending_invalidations(),
+    })
+}
+
+fn dump_pending(client: &RemoteFSClient) -> Value {
+    let requests: Vec<Value> = client
+        .outstanding()
+        .into_iter()
+        .map(|(id, request)| {
+            json!({
+                "id": id,
+                "op": request.operation.to_string(),
+                "path": request.path,
+                "connection": request.connection,
+                "cid": request.correlation,
+                "age_ms": request.sent.elapsed().as_millis() as u64,
+                "stuck": request.stuck,
+            })
+        })
+        .collect();
+    Value::Array(requests)
+}
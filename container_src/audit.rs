@@ -0,0 +1,228 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use fuser::Request;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::operation::FsOperation;
+use crate::RemoteFSClient;
+
+pub const DEFAULT_AUDIT_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+// Rotated files kept beside the live one, <path>.1 being the newest
+const AUDIT_ROTATIONS: usize = 5;
+
+// Records are mirrored to the DO in batches of at most this many, at least
+// this often
+const MIRROR_BATCH: usize = 256;
+const MIRROR_INTERVAL: Duration = Duration::from_secs(1);
+
+// Records kept for the DO while it can't be reached; older ones are only in
+// the local file after that
+const MIRROR_BACKLOG: usize = 64 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct AuditOptions {
+    pub path: PathBuf,
+    // Size the live file rotates at
+    pub max_size: u64,
+    // Also send every record to the DO
+    pub mirror: bool,
+}
+
+// The mutations recorded
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditOp {
+    Create,
+    Write,
+    Delete,
+}
+
+// Who made a mutation, as the frontend it came through knows them
+#[derive(Clone, Copy, Debug)]
+pub struct Caller {
+    pub uid: u32,
+    pub gid: u32,
+    // 0 when the frontend can't tell
+    pub pid: u32,
+}
+
+impl From<&Request<'_>> for Caller {
+    fn from(req: &Request) -> Self {
+        Self { uid: req.uid(), gid: req.gid(), pid: req.pid() }
+    }
+}
+
+// One line of the audit log
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    op: AuditOp,
+    path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    uid: u32,
+    gid: u32,
+    pid: u32,
+    // What the mutation failed with, absent when it succeeded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errno: Option<i32>,
+}
+
+// An append-only JSON-lines log of the mutations applications make through
+// the mount. Records are written by a thread of their own, so a slow disk
+// holds up the log rather than the filesystem.
+#[derive(Clone)]
+pub struct AuditLog {
+    records: mpsc::Sender<String>,
+}
+
+impl AuditLog {
+    pub fn open(options: &AuditOptions, client: Arc<RemoteFSClient>) -> io::Result<Self> {
+        let mut file = RotatingFile::open(&options.path, options.max_size)?;
+        let mirror = options.mirror.then(|| spawn_mirror(client));
+        let (records, received) = mpsc::channel::<String>();
+        thread::spawn(move || {
+            for line in received {
+                if let Err(e) = file.append(&line) {
+                    warn!("Failed to write to the audit log {}: {}", file.path.display(), e);
+                }
+                if let Some(mirror) = &mirror {
+                    let _ = mirror.send(line);
+                }
+            }
+        });
+        Ok(Self { records })
+    }
+
+    pub fn record(
+        &self,
+        caller: Caller,
+        op: AuditOp,
+        path: &str,
+        offset: Option<u64>,
+        size: Option<u64>,
+        errno: Option<i32>,
+    ) {
+        let record = Record {
+            time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            op,
+            path,
+            offset,
+            size,
+            uid: caller.uid,
+            gid: caller.gid,
+            pid: caller.pid,
+            errno,
+        };
+        if let Ok(line) = serde_json::to_string(&record) {
+            let _ = self.records.send(line);
+        }
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    fn append(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 + 1 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    // Shifts <path>.1 to <path>.2 and so on, dropping the oldest, and starts
+    // a new file at <path>
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        for n in (1..AUDIT_ROTATIONS).rev() {
+            // Earlier rotations may not have happened yet
+            let _ = fs::rename(rotated(n), rotated(n + 1));
+        }
+        fs::rename(&self.path, rotated(1))?;
+        *self = Self::open(&self.path, self.max_size)?;
+        Ok(())
+    }
+}
+
+// Sends records to the DO in batches, holding them while it is unreachable
+fn spawn_mirror(client: Arc<RemoteFSClient>) -> mpsc::Sender<String> {
+    let (sender, received) = mpsc::channel::<String>();
+    thread::spawn(move || {
+        let mut unsent = VecDeque::new();
+        let mut next_send = Instant::now() + MIRROR_INTERVAL;
+        let mut dropped = 0;
+        loop {
+            match received.recv_timeout(next_send.saturating_duration_since(Instant::now())) {
+                Ok(line) => unsent.push_back(line),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            if unsent.len() > MIRROR_BACKLOG {
+                unsent.pop_front();
+                dropped += 1;
+            }
+            if unsent.len() < MIRROR_BATCH && Instant::now() < next_send {
+                continue;
+            }
+            next_send = Instant::now() + MIRROR_INTERVAL;
+            // A lazy connection may not be up yet
+            if unsent.is_empty() || client.negotiated().is_none() {
+                continue;
+            }
+            if !client.has_feature("audit") {
+                warn!("The DO does not keep audit records, no longer mirroring them");
+                break;
+            }
+            if dropped > 0 {
+                warn!("Dropped {} audit records while the DO was unreachable; they are only in the local log", dropped);
+                dropped = 0;
+            }
+            while !unsent.is_empty() {
+                let count = unsent.len().min(MIRROR_BATCH);
+                let mut batch = Vec::new();
+                for line in unsent.range(..count) {
+                    batch.extend_from_slice(line.as_bytes());
+                    batch.push(b'\n');
+                }
+                let sent = client.block_on(client.send_request(FsOperation::Audit, "", Some(batch), None, None));
+                match sent {
+                    Ok(_) => drop(unsent.drain(..count)),
+                    Err(e) => {
+                        warn!("Failed to mirror audit records to the DO, will retry: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    sender
+}
@@ -0,0 +1,487 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::error::Locked;
+use crate::overlay::{OverlayStore, OVERLAY};
+use crate::tier::{TieredStore, Tiering, TIERED};
+use crate::transport::{Address, Conn, Listener};
+
+// Frames a backend takes from the daemon; its requests are far smaller
+const MAX_REQUEST_FRAME: usize = 64 * 1024 * 1024;
+
+// Where the files behind the mounts live
+#[derive(Clone, Debug, PartialEq)]
+pub enum Backend {
+    // A Durable Object, reached over the wire protocol
+    Remote,
+    // The daemon's own memory, gone when it exits
+    Memory,
+    // A directory on the daemon's side, served as it is
+    Local(PathBuf),
+    // The DO seen through a scratch directory on the daemon's side, which
+    // keeps every change until it is committed
+    Overlay(PathBuf),
+    // The DO with a working set of the files in use kept in a directory on
+    // the daemon's side, written back once changes settle
+    Tier(Tiering),
+}
+
+impl Backend {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "do" => Ok(Backend::Remote),
+            "memory" => Ok(Backend::Memory),
+            _ => match value.split_once(':') {
+                Some(("local" | "overlay" | "tier", "")) => Err(format!("expected {}<directory>", &value)),
+                Some(("local", dir)) => Ok(Backend::Local(PathBuf::from(dir))),
+                Some(("overlay", dir)) => Ok(Backend::Overlay(PathBuf::from(dir))),
+                Some(("tier", dir)) => Ok(Backend::Tier(Tiering::new(PathBuf::from(dir)))),
+                _ => Err("expected do, memory, local:<directory>, overlay:<directory> or tier:<directory>".to_string()),
+            },
+        }
+    }
+
+    // Starts answering for a backend that isn't a DO, returning the address
+    // the client should dial in place of one. An overlay or a tier reaches the
+    // DO under it through `lower`.
+    pub fn start(&self, lower: impl FnOnce() -> io::Result<Box<dyn Store>>) -> io::Result<Option<Address>> {
+        let store: Arc<dyn Store> = match self {
+            Backend::Remote => return Ok(None),
+            Backend::Memory => Arc::new(MemoryStore::default()),
+            Backend::Local(dir) => Arc::new(LocalStore::open(dir)?),
+            Backend::Overlay(dir) => {
+                let overlay = Arc::new(OverlayStore::open(dir, lower()?)?);
+                let _ = OVERLAY.set(overlay.clone());
+                overlay
+            }
+            Backend::Tier(tiering) => {
+                let tiered = Arc::new(TieredStore::open(tiering, lower()?)?);
+                tiered.spawn_tiering();
+                let _ = TIERED.set(tiered.clone());
+                tiered
+            }
+        };
+        serve(store).map(Some)
+    }
+}
+
+// Sends what an overlay or a tier keeps locally to the DO, for the admin
+// socket's and the control file's `commit`
+pub fn commit() -> Result<serde_json::Value, String> {
+    if let Some(overlay) = OVERLAY.get() {
+        return overlay.commit();
+    }
+    match TIERED.get() {
+        Some(tiered) => tiered.commit(),
+        None => {
+            warn!("Asked to commit, but the backend isn't an overlay or a tier");
+            Err("the backend isn't an overlay or a tier".to_string())
+        }
+    }
+}
+
+// The operations a backend answers: those of a DO that agreed to none of the
+// optional protocol features. Paths are absolute, and directories exist only
+// as the prefixes of the files in them.
+pub trait Store: Send + Sync {
+    // Up to `size` bytes from `offset`, or the rest of the file without a size
+    fn read(&self, path: &str, offset: u64, size: Option<u64>) -> io::Result<Vec<u8>>;
+    // Writes at `offset`, growing the file as needed, or replaces the file
+    // without one
+    fn write(&self, path: &str, offset: Option<u64>, data: &[u8]) -> io::Result<()>;
+    // The size, and a version that changes with every write, in milliseconds
+    // so it doubles as the modification time
+    fn stat(&self, path: &str) -> io::Result<(u64, u64)>;
+    // Names directly under a directory, sorted
+    fn list(&self, path: &str) -> io::Result<Vec<String>>;
+    // Whether there was a file to remove
+    fn unlink(&self, path: &str) -> io::Result<bool>;
+}
+
+// Files as byte vectors with their versions, as the DO keeps them
+#[derive(Default)]
+pub struct MemoryStore {
+    files: Mutex<BTreeMap<String, (Vec<u8>, u64)>>,
+    last_version: Mutex<u64>,
+}
+
+impl MemoryStore {
+    // The wall clock in milliseconds, kept moving forward like the DO's
+    fn next_version(&self) -> u64 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let mut last = self.last_version.locked();
+        *last = now.max(*last + 1);
+        *last
+    }
+}
+
+impl Store for MemoryStore {
+    fn read(&self, path: &str, offset: u64, size: Option<u64>) -> io::Result<Vec<u8>> {
+        let files = self.files.locked();
+        let (data, _) = files.get(path).ok_or_else(not_found)?;
+        let start = (offset as usize).min(data.len());
+        let end = size.map_or(data.len(), |size| start.saturating_add(size as usize).min(data.len()));
+        Ok(data[start..end].to_vec())
+    }
+
+    fn write(&self, path: &str, offset: Option<u64>, data: &[u8]) -> io::Result<()> {
+        let version = self.next_version();
+        let mut files = self.files.locked();
+        let (stored, stored_version) = files.entry(path.to_string()).or_default();
+        match offset {
+            Some(offset) => {
+                let start = offset as usize;
+                let end = start.checked_add(data.len()).ok_or_else(|| io::Error::from_raw_os_error(libc::EFBIG))?;
+                if stored.len() < end {
+                    stored.resize(end, 0);
+                }
+                stored[start..end].copy_from_slice(data);
+            }
+            None => *stored = data.to_vec(),
+        }
+        *stored_version = version;
+        Ok(())
+    }
+
+    fn stat(&self, path: &str) -> io::Result<(u64, u64)> {
+        let files = self.files.locked();
+        let (data, version) = files.get(path).ok_or_else(not_found)?;
+        Ok((data.len() as u64, *version))
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let prefix = if path.ends_with('/') { path.to_string() } else { format!("{}/", path) };
+        let mut names: Vec<String> = self
+            .files
+            .locked()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .filter_map(|(key, _)| key[prefix.len()..].split('/').next().map(str::to_string))
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    fn unlink(&self, path: &str) -> io::Result<bool> {
+        Ok(self.files.locked().remove(path).is_some())
+    }
+}
+
+// Files in a local directory, each a file under it at the same path
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn open(root: &Path) -> io::Result<Self> {
+        if !fs::metadata(root)?.is_dir() {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+        Ok(Self { root: root.to_path_buf() })
+    }
+
+    // Where a path lives under the root. Only plain names are taken, so no
+    // `..` in a request climbs out of it; symlinks inside it are followed.
+    fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        let relative = Path::new(path.trim_start_matches('/'));
+        if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+impl Store for LocalStore {
+    fn read(&self, path: &str, offset: u64, size: Option<u64>) -> io::Result<Vec<u8>> {
+        let mut file = File::open(self.resolve(path)?)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::new();
+        match size {
+            Some(size) => file.take(size).read_to_end(&mut data)?,
+            None => file.read_to_end(&mut data)?,
+        };
+        Ok(data)
+    }
+
+    fn write(&self, path: &str, offset: Option<u64>, data: &[u8]) -> io::Result<()> {
+        let file = self.resolve(path)?;
+        // Directories are only the prefixes of files, so they come with them
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match offset {
+            Some(offset) => {
+                let file = OpenOptions::new().write(true).create(true).truncate(false).open(file)?;
+                file.write_all_at(data, offset)
+            }
+            None => fs::write(file, data),
+        }
+    }
+
+    fn stat(&self, path: &str) -> io::Result<(u64, u64)> {
+        let metadata = fs::metadata(self.resolve(path)?)?;
+        if !metadata.is_file() {
+            return Err(not_found());
+        }
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+        Ok((metadata.len(), modified.as_millis() as u64))
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let entries = match fs::read_dir(self.resolve(path)?) {
+            Ok(entries) => entries,
+            // Like a prefix nothing starts with
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            names.extend(entry?.file_name().into_string().ok());
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn unlink(&self, path: &str) -> io::Result<bool> {
+        match fs::remove_file(self.resolve(path)?) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn not_found() -> io::Error {
+    io::Error::from_raw_os_error(libc::ENOENT)
+}
+
+// A request as a backend reads it off the wire
+#[derive(Deserialize)]
+struct Request {
+    id: u64,
+    operation: String,
+    #[serde(default)]
+    path: String,
+    data: Option<Vec<u8>>,
+    offset: Option<u64>,
+    size: Option<u64>,
+}
+
+#[derive(Serialize, Default)]
+struct Reply {
+    id: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    data: Vec<u8>,
+    #[serde(rename = "bytesWritten", skip_serializing_if = "Option::is_none")]
+    bytes_written: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stat: Option<Stat>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    success: bool,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errno: Option<i32>,
+}
+
+#[derive(Serialize)]
+struct Stat {
+    size: u64,
+    #[serde(rename = "isFile")]
+    is_file: bool,
+    #[serde(rename = "isDir")]
+    is_dir: bool,
+    mtime: u64,
+    version: u64,
+}
+
+// Answers the daemon's connections to `store` on a socket of the daemon's
+// own, returning its address
+pub fn serve(store: Arc<dyn Store>) -> io::Result<Address> {
+    let path = std::env::temp_dir().join(format!("fsdaemon-{}-backend.sock", std::process::id()));
+    let address = Address::Unix(path);
+    let listener = Listener::bind(&address)?;
+    info!("Serving files from the daemon's own backend on {}", address);
+    thread::spawn(move || loop {
+        match listener.accept() {
+            Ok(stream) => {
+                let store = store.clone();
+                thread::spawn(move || {
+                    if let Err(e) = answer(stream, store.as_ref()) {
+                        debug!("Backend connection closed: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept a connection to the backend: {}", e),
+        }
+    });
+    Ok(address)
+}
+
+// Answers one connection's requests in order until it closes. Every frame
+// is plain JSON: the hello is answered as a DO that predates it would, so
+// the client settles on legacy framing and no optional features.
+fn answer(mut stream: Conn, store: &dyn Store) -> io::Result<()> {
+    let mut frame = Vec::new();
+    loop {
+        let mut length = [0u8; 4];
+        stream.read_exact(&mut length)?;
+        let length = u32::from_le_bytes(length) as usize;
+        if length > MAX_REQUEST_FRAME {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} byte request frame", length)));
+        }
+        frame.resize(length, 0);
+        stream.read_exact(&mut frame)?;
+        let request: Request = match serde_json::from_slice(&frame) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Backend dropped a request it couldn't parse: {}", e);
+                continue;
+            }
+        };
+        let reply = serde_json::to_vec(&perform(store, request))?;
+        let mut out = Vec::with_capacity(4 + reply.len());
+        out.extend_from_slice(&(reply.len() as u32).to_le_bytes());
+        out.extend_from_slice(&reply);
+        stream.write_all(&out)?;
+    }
+}
+
+fn perform(store: &dyn Store, request: Request) -> Reply {
+    let Request { id, path, offset, size, .. } = request;
+    let result = match request.operation.as_str() {
+        "read" => store.read(&path, offset.unwrap_or(0), size).map(|data| Reply { data, ..Default::default() }),
+        "write" => {
+            let data = request.data.unwrap_or_default();
+            store.write(&path, offset, &data).map(|()| Reply {
+                bytes_written: Some(data.len() as u64),
+                ..Default::default()
+            })
+        }
+        "stat" => store.stat(&path).map(|(size, version)| Reply {
+            stat: Some(Stat {
+                size,
+                is_file: true,
+                is_dir: false,
+                mtime: version,
+                version,
+            }),
+            ..Default::default()
+        }),
+        // Paged like the DO's: `offset` entries skipped, at most `size` returned
+        "readdir" => store.list(&path).map(|files| {
+            let files = files.into_iter().skip(offset.unwrap_or(0) as usize);
+            let files = files.take(size.map_or(usize::MAX, |size| size as usize)).collect();
+            Reply { files: Some(files), ..Default::default() }
+        }),
+        "unlink" => store.unlink(&path).map(|success| Reply { success, ..Default::default() }),
+        _ => Err(io::Error::from_raw_os_error(libc::ENOSYS)),
+    };
+    match result {
+        Ok(reply) => Reply { id, ..reply },
+        Err(e) => {
+            let errno = e.raw_os_error().unwrap_or(libc::EIO);
+            // The messages the DO uses, which older daemons go by
+            let error = match errno {
+                libc::ENOENT => "File not found".to_string(),
+                libc::ENOSYS => "Unknown operation".to_string(),
+                _ => e.to_string(),
+            };
+            Reply {
+                id,
+                error,
+                errno: Some(errno),
+                ..Default::default()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_store_behaves_like_the_do() {
+        let store = MemoryStore::default();
+        store.write("/a", Some(2), b"xy").unwrap();
+        assert_eq!(store.read("/a", 0, None).unwrap(), b"\0\0xy");
+        assert_eq!(store.read("/a", 3, Some(10)).unwrap(), b"y");
+        let (_, first) = store.stat("/a").unwrap();
+        store.write("/a", None, b"new").unwrap();
+        let (size, second) = store.stat("/a").unwrap();
+        assert_eq!(size, 3);
+        assert!(second > first);
+
+        store.write("/dir/b", Some(0), b"").unwrap();
+        store.write("/dir/sub/c", Some(0), b"").unwrap();
+        assert_eq!(store.list("/").unwrap(), ["a", "dir"]);
+        assert_eq!(store.list("/dir").unwrap(), ["b", "sub"]);
+
+        assert!(store.unlink("/a").unwrap());
+        assert!(!store.unlink("/a").unwrap());
+        assert_eq!(store.stat("/a").unwrap_err().raw_os_error(), Some(libc::ENOENT));
+    }
+
+    #[test]
+    fn local_store_serves_a_directory() {
+        let root = std::env::temp_dir().join(format!("fsdaemon-backend-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let store = LocalStore::open(&root).unwrap();
+
+        store.write("/dir/a", Some(2), b"xy").unwrap();
+        assert_eq!(fs::read(root.join("dir/a")).unwrap(), b"\0\0xy");
+        assert_eq!(store.read("/dir/a", 3, Some(10)).unwrap(), b"y");
+        store.write("/dir/a", None, b"new").unwrap();
+        assert_eq!(store.stat("/dir/a").unwrap().0, 3);
+        assert_eq!(store.stat("/dir").unwrap_err().raw_os_error(), Some(libc::ENOENT));
+        assert_eq!(store.list("/").unwrap(), ["dir"]);
+        assert!(store.list("/missing").unwrap().is_empty());
+
+        assert_eq!(store.read("/../etc/passwd", 0, None).unwrap_err().raw_os_error(), Some(libc::EINVAL));
+        assert!(store.unlink("/dir/a").unwrap());
+        assert!(!store.unlink("/dir/a").unwrap());
+        fs::remove_dir_all(&root).unwrap();
+        assert!(LocalStore::open(&root).is_err());
+    }
+
+    #[test]
+    fn requests_are_answered_as_a_legacy_do_would() {
+        let store = MemoryStore::default();
+        let request = |operation: &str, path: &str, offset, size| Request {
+            id: 7,
+            operation: operation.to_string(),
+            path: path.to_string(),
+            data: Some(b"hello".to_vec()),
+            offset,
+            size,
+        };
+        let reply = perform(&store, request("write", "/f", Some(0), None));
+        assert_eq!((reply.id, reply.bytes_written), (7, Some(5)));
+        assert_eq!(perform(&store, request("read", "/f", Some(1), Some(3))).data, b"ell");
+
+        let missing = perform(&store, request("stat", "/g", None, None));
+        assert_eq!((missing.error.as_str(), missing.errno), ("File not found", Some(libc::ENOENT)));
+        let hello = perform(&store, request("hello", "/", None, None));
+        assert_eq!((hello.error.as_str(), hello.errno), ("Unknown operation", Some(libc::ENOSYS)));
+
+        for name in ["b", "c", "d"] {
+            store.write(&format!("/{}", name), None, b"").unwrap();
+        }
+        let page = perform(&store, request("readdir", "/", Some(1), Some(2)));
+        assert_eq!(page.files.unwrap(), ["c", "d"]);
+    }
+}
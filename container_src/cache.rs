@@ -0,0 +1,353 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant};
+
+use crate::error::Locked;
+use crate::spill::SpillDir;
+
+// Files are cached in fixed-size blocks aligned to multiples of this size
+pub const BLOCK_SIZE: u64 = 64 * 1024;
+
+// Block caches in use, one per backend namespace, so every mount of the same
+// namespace reads through the same cache instead of holding its own copy
+static SHARED_BLOCK_CACHES: OnceLock<Mutex<HashMap<String, Weak<Mutex<BlockCache>>>>> =
+    OnceLock::new();
+
+pub fn shared_block_cache(
+    namespace: &str,
+    budget: usize,
+    spill: Option<Arc<SpillDir>>,
+) -> Arc<Mutex<BlockCache>> {
+    let mut caches = SHARED_BLOCK_CACHES.get_or_init(Default::default).locked();
+    if let Some(cache) = caches.get(namespace).and_then(Weak::upgrade) {
+        return cache;
+    }
+    let cache = Arc::new(Mutex::new(BlockCache::new(budget, spill)));
+    caches.insert(namespace.to_string(), Arc::downgrade(&cache));
+    cache
+}
+
+// Blocks are keyed by remote path and content version, so mounts that saw
+// different versions never serve each other stale data and superseded
+// versions simply age out of the LRU
+pub fn content_key(path: &str, version: u64) -> String {
+    format!("{}@{}", path, version)
+}
+
+struct Block {
+    data: Vec<u8>,
+    last_used: u64,
+}
+
+// Read cache of file blocks with LRU eviction under a memory budget. A block
+// shorter than BLOCK_SIZE is the last block of its file. Files are identified
+// by an opaque key, normally from `content_key`. With a spill directory,
+// evicted blocks move to disk and are brought back on a memory miss.
+pub struct BlockCache {
+    files: HashMap<String, HashMap<u64, Block>>,
+    lru: BTreeMap<u64, (String, u64)>,
+    clock: u64,
+    used: usize,
+    budget: usize,
+    spill: Option<Arc<SpillDir>>,
+}
+
+impl BlockCache {
+    pub fn new(budget: usize, spill: Option<Arc<SpillDir>>) -> Self {
+        Self {
+            files: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            used: 0,
+            budget,
+            spill,
+        }
+    }
+
+    // Assembles `size` bytes at `offset` if every block they touch is cached
+    pub fn read(&mut self, key: &str, offset: u64, size: u64) -> Option<Vec<u8>> {
+        let mut data = Vec::with_capacity(size as usize);
+        let end = offset + size;
+        let mut index = offset / BLOCK_SIZE;
+
+        while index * BLOCK_SIZE < end {
+            let block = self.touch(key, index)?;
+            let block_start = index * BLOCK_SIZE;
+            let from = offset.saturating_sub(block_start) as usize;
+            let to = ((end - block_start) as usize).min(block.data.len());
+            if from < to {
+                data.extend_from_slice(&block.data[from..to]);
+            }
+            if (block.data.len() as u64) < BLOCK_SIZE {
+                break;
+            }
+            index += 1;
+        }
+        Some(data)
+    }
+
+    // Caches data fetched from a block-aligned `start`. The trailing partial
+    // block is only kept when it is known to end the file.
+    pub fn insert_range(&mut self, key: &str, start: u64, data: &[u8], eof: bool) {
+        debug_assert_eq!(start % BLOCK_SIZE, 0);
+        for (i, chunk) in data.chunks(BLOCK_SIZE as usize).enumerate() {
+            if (chunk.len() as u64) < BLOCK_SIZE && !eof {
+                break;
+            }
+            self.insert(key, start / BLOCK_SIZE + i as u64, chunk.to_vec());
+        }
+        if eof && data.len() as u64 % BLOCK_SIZE == 0 {
+            // Mark the end of file with an empty block so reads past it still hit
+            self.insert(key, (start + data.len() as u64) / BLOCK_SIZE, Vec::new());
+        }
+    }
+
+    pub fn invalidate(&mut self, key: &str) {
+        if let Some(blocks) = self.files.remove(key) {
+            for block in blocks.values() {
+                self.lru.remove(&block.last_used);
+                self.used -= block.data.len();
+            }
+        }
+        if let Some(spill) = &self.spill {
+            spill.remove_blocks(key);
+        }
+    }
+
+    // Marks a block as just used, loading it back from disk if it was spilled
+    fn touch(&mut self, key: &str, index: u64) -> Option<&Block> {
+        let in_memory = self.files.get(key).is_some_and(|blocks| blocks.contains_key(&index));
+        if !in_memory {
+            let data = self.spill.as_ref()?.load_block(key, index)?;
+            self.insert(key, index, data);
+        }
+
+        self.clock += 1;
+        let block = self.files.get_mut(key)?.get_mut(&index)?;
+        self.lru.remove(&block.last_used);
+        self.lru.insert(self.clock, (key.to_string(), index));
+        block.last_used = self.clock;
+        Some(block)
+    }
+
+    fn insert(&mut self, key: &str, index: u64, data: Vec<u8>) {
+        self.clock += 1;
+        self.used += data.len();
+        self.lru.insert(self.clock, (key.to_string(), index));
+        let block = Block {
+            data,
+            last_used: self.clock,
+        };
+        if let Some(old) = self.files.entry(key.to_string()).or_default().insert(index, block) {
+            self.lru.remove(&old.last_used);
+            self.used -= old.data.len();
+        }
+        self.evict();
+    }
+
+    // Evicts down to a smaller budget straight away
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.used > self.budget {
+            let Some((_, (key, index))) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some(blocks) = self.files.get_mut(&key) {
+                if let Some(block) = blocks.remove(&index) {
+                    self.used -= block.data.len();
+                    if let Some(spill) = &self.spill {
+                        spill.store_block(&key, index, &block.data);
+                    }
+                }
+                if blocks.is_empty() {
+                    self.files.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+// A directory listing as far as it has been fetched, in the DO's order
+struct Listing {
+    names: Vec<String>,
+    complete: bool,
+    fetched: Instant,
+}
+
+// Readdir results per directory path, reused until they are older than the
+// TTL or a local change to the directory invalidates them
+pub struct DirCache {
+    listings: HashMap<String, Listing>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl DirCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            listings: HashMap::new(),
+            ttl,
+            capacity,
+        }
+    }
+
+    // Cached names from `offset` on and whether they run to the end of the
+    // directory, or None when the DO has to be asked
+    pub fn page(&mut self, path: &str, offset: u64) -> Option<(Vec<String>, bool)> {
+        let listing = self.listings.get(path)?;
+        if listing.fetched.elapsed() >= self.ttl {
+            self.listings.remove(path);
+            return None;
+        }
+        let offset = offset as usize;
+        if offset >= listing.names.len() && !listing.complete {
+            return None;
+        }
+        let names = listing.names.get(offset..).unwrap_or_default().to_vec();
+        Some((names, listing.complete))
+    }
+
+    // Records a page fetched from the DO. A page at offset 0 starts a new
+    // listing; later pages are kept only when they continue the cached one.
+    pub fn extend(&mut self, path: &str, offset: u64, names: &[String], complete: bool) {
+        if offset == 0 {
+            if self.listings.len() >= self.capacity && !self.listings.contains_key(path) {
+                let oldest = self
+                    .listings
+                    .iter()
+                    .min_by_key(|(_, listing)| listing.fetched)
+                    .map(|(path, _)| path.clone());
+                if let Some(oldest) = oldest {
+                    self.listings.remove(&oldest);
+                }
+            }
+            self.listings.insert(
+                path.to_string(),
+                Listing {
+                    names: Vec::new(),
+                    complete: false,
+                    fetched: Instant::now(),
+                },
+            );
+        }
+        let Some(listing) = self.listings.get_mut(path) else {
+            return;
+        };
+        if listing.complete || listing.names.len() as u64 != offset {
+            return;
+        }
+        listing.names.extend_from_slice(names);
+        listing.complete = complete;
+    }
+
+    // Listings already cached age against the new TTL
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    pub fn invalidate(&mut self, path: &str) {
+        self.listings.remove(path);
+    }
+
+    pub fn clear(&mut self) {
+        self.listings.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = BLOCK_SIZE as usize;
+
+    fn bytes(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn read_past_an_aligned_end_hits_the_eof_marker() {
+        let mut cache = BlockCache::new(usize::MAX, None);
+        cache.insert_range("f@1", 0, &bytes(BLOCK), true);
+        assert_eq!(cache.read("f@1", 0, 2 * BLOCK_SIZE), Some(bytes(BLOCK)));
+        assert_eq!(cache.read("f@1", BLOCK_SIZE, 10), Some(Vec::new()));
+    }
+
+    #[test]
+    fn trailing_partial_block_is_only_kept_at_eof() {
+        let mut cache = BlockCache::new(usize::MAX, None);
+        cache.insert_range("f@1", 0, &bytes(BLOCK + 10), false);
+        assert_eq!(cache.read("f@1", 0, BLOCK_SIZE), Some(bytes(BLOCK)));
+        assert_eq!(cache.read("f@1", BLOCK_SIZE, 5), None);
+
+        cache.insert_range("f@2", 0, &bytes(BLOCK + 10), true);
+        assert_eq!(cache.read("f@2", 0, 3 * BLOCK_SIZE), Some(bytes(BLOCK + 10)));
+        assert_eq!(cache.read("f@2", BLOCK_SIZE + 4, 100), Some(bytes(BLOCK + 10)[BLOCK + 4..].to_vec()));
+    }
+
+    #[test]
+    fn least_recently_used_block_is_evicted_first() {
+        let mut cache = BlockCache::new(2 * BLOCK, None);
+        cache.insert_range("a@1", 0, &bytes(BLOCK), false);
+        cache.insert_range("b@1", 0, &bytes(BLOCK), false);
+        assert!(cache.read("a@1", 0, 1).is_some());
+        cache.insert_range("c@1", 0, &bytes(BLOCK), false);
+        assert!(cache.read("a@1", 0, 1).is_some());
+        assert!(cache.read("b@1", 0, 1).is_none());
+        assert!(cache.read("c@1", 0, 1).is_some());
+    }
+
+    #[test]
+    fn invalidate_drops_every_block() {
+        let mut cache = BlockCache::new(usize::MAX, None);
+        cache.insert_range("f@1", 0, &bytes(2 * BLOCK), true);
+        cache.invalidate("f@1");
+        assert_eq!(cache.read("f@1", 0, 1), None);
+        assert_eq!(cache.used, 0);
+    }
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn pages_continue_a_listing_until_it_is_complete() {
+        let mut cache = DirCache::new(Duration::from_secs(60), 8);
+        assert_eq!(cache.page("/d", 0), None);
+        cache.extend("/d", 0, &names(&["a", "b"]), false);
+        assert_eq!(cache.page("/d", 0), Some((names(&["a", "b"]), false)));
+        assert_eq!(cache.page("/d", 2), None);
+
+        // A page that doesn't follow on from what is cached is dropped
+        cache.extend("/d", 5, &names(&["x"]), true);
+        assert_eq!(cache.page("/d", 2), None);
+
+        cache.extend("/d", 2, &names(&["c"]), true);
+        assert_eq!(cache.page("/d", 1), Some((names(&["b", "c"]), true)));
+        assert_eq!(cache.page("/d", 7), Some((Vec::new(), true)));
+    }
+
+    #[test]
+    fn offset_zero_starts_a_new_listing() {
+        let mut cache = DirCache::new(Duration::from_secs(60), 8);
+        cache.extend("/d", 0, &names(&["a"]), true);
+        cache.extend("/d", 0, &names(&["b"]), false);
+        assert_eq!(cache.page("/d", 0), Some((names(&["b"]), false)));
+    }
+
+    #[test]
+    fn expired_and_evicted_listings_miss() {
+        let mut cache = DirCache::new(Duration::ZERO, 8);
+        cache.extend("/d", 0, &names(&["a"]), true);
+        assert_eq!(cache.page("/d", 0), None);
+
+        let mut cache = DirCache::new(Duration::from_secs(60), 1);
+        cache.extend("/a", 0, &names(&["x"]), true);
+        cache.extend("/b", 0, &names(&["y"]), true);
+        assert_eq!(cache.page("/a", 0), None);
+        assert_eq!(cache.page("/b", 0), Some((names(&["y"]), true)));
+    }
+}
@@ -0,0 +1,956 @@
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+
+use crate::backend::Backend;
+use crate::encryption::Key;
+use crate::logging::{self, LogFormat};
+use crate::names::NamePolicy;
+use crate::owner::{Owner, OwnerEntry};
+use crate::namespace::NamespaceSpec;
+use crate::quota::Quota;
+use crate::transport::Address;
+use crate::{
+    admin, nfs, ninep, parse_prefix, sftp, Consistency, Disconnect, Encoding, Frontend, MountSpec, Routing, MAX_IO_SIZE,
+    MIN_MAX_FRAME_SIZE, MIN_MAX_READ, MIN_TRANSFER_CHUNK_SIZE,
+};
+
+// The tables of a --config file and the options each may set, named as on
+// the command line
+const SECTIONS: &[(&str, &[&str])] = &[
+    (
+        "mount",
+        &[
+            "mount-point",
+            "prefix",
+            "mount",
+            "namespace",
+            "frontend",
+            "nfs-listen",
+            "9p-listen",
+            "read-only",
+            "status-json",
+            "consistency",
+            "write-back",
+            "leases",
+            "quota-bytes",
+            "quota-files",
+            "quota",
+            "quota-reconcile-interval",
+            "max-file-size",
+            "keep-cache",
+            "kernel-writeback-cache",
+            "direct-io",
+            "allow",
+            "default-permissions",
+            "noatime",
+            "fsname",
+            "subtype",
+            "max-read",
+            "unsupported",
+            "name-policy",
+            "owner",
+            "owner-map",
+            "post-mount-exec",
+            "pre-unmount-exec",
+            "shutdown-timeout",
+            "remount-attempts",
+            "remount-backoff",
+        ],
+    ),
+    (
+        "cache",
+        &[
+            "cache-size",
+            "dir-cache-ttl",
+            "readahead-window",
+            "readahead-memory",
+            "spill-dir",
+            "spill-size",
+            "journal",
+            "tier-high-watermark",
+            "tier-low-watermark",
+            "tier-writeback-delay",
+            "tier-promote-after",
+        ],
+    ),
+    (
+        "transport",
+        &[
+            "backend",
+            "listen",
+            "connect",
+            "clients",
+            "routing",
+            "connections",
+            "lazy-connect",
+            "encoding",
+            "legacy-framing",
+            "no-compression",
+            "max-in-flight",
+            "max-frame-size",
+            "transfer-chunk-size",
+            "transfer-parallelism",
+            "dedup",
+            "request-timeout",
+            "metadata-timeout",
+            "read-timeout",
+            "write-timeout",
+            "sync-timeout",
+            "heartbeat-interval",
+            "heartbeat-timeout",
+            "disconnect",
+            "disconnect-timeout",
+            "disconnect-errno",
+            "disconnect-queue",
+            "retry-attempts",
+            "retry-backoff",
+            "retry-max-backoff",
+            "retry-jitter",
+        ],
+    ),
+    (
+        "security",
+        &[
+            "tls-cert",
+            "tls-key",
+            "tls-ca",
+            "tls-server-name",
+            "encryption-key",
+            "encryption-key-file",
+            "admin-socket",
+            "events-socket",
+            "webdav-listen",
+            "sftp-listen",
+            "sftp-host-key",
+            "sftp-authorized-keys",
+            "sftp-allow-any-user",
+            "s3-listen",
+            "audit-log",
+            "audit-log-max-size",
+            "audit-mirror",
+        ],
+    ),
+    (
+        "logging",
+        &["log-format", "log-level", "slow-threshold", "stuck-threshold", "trace-context", "metrics-listen"],
+    ),
+];
+
+// `fsdaemon [options] [mount point]` serves the filesystem, `fsdaemon restore`
+// rebuilds the primary backend, `fsdaemon sync` mirrors a directory into it.
+// Values are checked as they are parsed, so a bad one is reported with the
+// option it was given to and the usage.
+pub fn command() -> Command {
+    Command::new("fsdaemon")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Serves a Durable Object's storage as a FUSE filesystem")
+        .args_conflicts_with_subcommands(true)
+        .arg(config())
+        .arg(
+            Arg::new("mount-point")
+                .env(env("mount-point"))
+                .value_name("MOUNT_POINT")
+                .default_value("/storage")
+                .help("Where to mount the filesystem, created if missing"),
+        )
+        .arg(
+            option("prefix", "PATH")
+                .value_parser(parse_prefix)
+                .help("Path on the DO shown at the mount point (default /)"),
+        )
+        .arg(
+            option("mount", "MOUNT_POINT:PREFIX[,SETTING=VALUE,...]")
+                .action(ArgAction::Append)
+                .value_parser(MountSpec::parse)
+                .help("Also mount PREFIX of the DO at MOUNT_POINT, with read-only, consistency or cache settings"),
+        )
+        .arg(
+            option("namespace", "NAME:PREFIX[,connect=ADDRESS|listen=ADDRESS]")
+                .action(ArgAction::Append)
+                .value_parser(NamespaceSpec::parse)
+                .help("Show PREFIX of another DO, or of the same one over a session of its own, as NAME at the root"),
+        )
+        .arg(
+            option("frontend", "FRONTEND")
+                .value_parser(Frontend::parse)
+                .help("How applications reach the files: fuse (default), or nfs or 9p to serve them over the network"),
+        )
+        .arg(
+            option("nfs-listen", "ADDRESS")
+                .value_parser(nfs::parse_listen)
+                .help("Loopback host:port the NFS frontend serves on (default 127.0.0.1:11111)"),
+        )
+        .arg(
+            option("9p-listen", "ADDRESS")
+                .value_parser(ninep::parse_listen)
+                .help("Loopback host:port the 9p frontend serves on (default 127.0.0.1:5640)"),
+        )
+        .arg(flag("read-only").help("Mount read-only, so every change fails with EROFS"))
+        .arg(
+            Arg::new("status-json")
+                .long("status-json")
+                .env(env("status-json"))
+                .value_name("PATH")
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("-")
+                .help("Write a JSON status document once mounted, to stdout unless a path is given"),
+        )
+        // Consistency and caching
+        .arg(
+            option("consistency", "MODE")
+                .value_parser(Consistency::parse)
+                .help("When changes reach the DO: strict (default), close-to-open or write-back"),
+        )
+        .arg(flag("write-back").conflicts_with("consistency").help("Same as --consistency=write-back"))
+        .arg(flag("leases").help("Ask the DO for leases on opened files and cache them aggressively"))
+        .arg(
+            option("quota-bytes", "BYTES")
+                .value_parser(positive::<u64>)
+                .help("Most bytes the files under the prefix may hold; writes past it fail with ENOSPC"),
+        )
+        .arg(
+            option("quota-files", "COUNT")
+                .value_parser(positive::<u64>)
+                .help("Most files there may be under the prefix; creating more fails with ENOSPC"),
+        )
+        .arg(
+            option("quota", "PATH:bytes=BYTES,files=COUNT")
+                .action(ArgAction::Append)
+                .value_parser(Quota::parse)
+                .help("Limit the files under a path of the DO, failing changes past it with EDQUOT"),
+        )
+        .arg(
+            option("quota-reconcile-interval", "DURATION")
+                .value_parser(humantime::parse_duration)
+                .help("How often quota usage is counted again from what the DO has (default 60s)"),
+        )
+        .arg(
+            option("max-file-size", "BYTES")
+                .value_parser(positive::<u64>)
+                .help("Largest a file may grow; writes, truncates and fallocate past it fail with EFBIG"),
+        )
+        .arg(
+            option("cache-size", "BYTES")
+                .value_parser(positive::<usize>)
+                .help("Memory for cached file blocks, shared by mounts of a namespace (default 64 MiB)"),
+        )
+        .arg(
+            option("dir-cache-ttl", "DURATION")
+                .value_parser(humantime::parse_duration)
+                .help("How long directory listings are served from cache (default 5s)"),
+        )
+        .arg(
+            option("readahead-window", "BYTES")
+                .value_parser(positive::<usize>)
+                .help("Bytes prefetched past a sequential read (default 256 KiB)"),
+        )
+        .arg(
+            option("readahead-memory", "BYTES")
+                .value_parser(positive::<usize>)
+                .help("Prefetched data all of a mount's open files may hold (default 16 MiB)"),
+        )
+        .arg(flag("keep-cache").help("Keep the kernel's cached pages across opens"))
+        .arg(flag("kernel-writeback-cache").help("Let the kernel buffer writes and send them later"))
+        .arg(
+            flag("direct-io")
+                .conflicts_with_all(["keep-cache", "kernel-writeback-cache"])
+                .help("Bypass the kernel's page cache"),
+        )
+        // How the kernel mounts it
+        .arg(
+            option("allow", "USERS")
+                .value_parser(["other", "root"])
+                .help("Who besides the daemon's user may use the mount: other (everyone, default) or root"),
+        )
+        .arg(flag("default-permissions").help("Have the kernel enforce file modes itself"))
+        .arg(flag("noatime").help("Don't update access times"))
+        .arg(option("fsname", "NAME").value_parser(mount_name).help("Source shown in /proc/mounts"))
+        .arg(option("subtype", "NAME").value_parser(mount_name).help("Filesystem type shown as fuse.NAME"))
+        .arg(
+            option("max-read", "BYTES")
+                .value_parser(max_read)
+                .help("Largest read the kernel sends at once (default 128 KiB)"),
+        )
+        .arg(option("spill-dir", "PATH").help("Local directory for evicted cache blocks and write-back data"))
+        .arg(
+            option("spill-size", "BYTES")
+                .value_parser(positive::<u64>)
+                .requires("spill-dir")
+                .help("Disk the spill directory may use (default 1 GiB)"),
+        )
+        .arg(
+            option("journal", "PATH").help("File each write is synced to before it is acknowledged, replayed at start"),
+        )
+        .arg(
+            option("unsupported", "FEATURE=POLICY,...")
+                .action(ArgAction::Append)
+                .help("How locks, xattrs and mknod are answered: enosys, succeed or emulate"),
+        )
+        .arg(
+            option("name-policy", "POLICY")
+                .value_parser(NamePolicy::parse)
+                .help("Names that aren't UTF-8 or hold control characters: reject (default), escape or replace"),
+        )
+        .arg(
+            option("owner", "OWNER")
+                .value_parser(Owner::parse)
+                .help(
+                    "Who files are shown as owned by: daemon (default), caller, or <uid>:<gid>. NFS, 9p and SFTP \
+                     show the daemon's for caller",
+                ),
+        )
+        .arg(
+            option("owner-map", "PATH=UID:GID")
+                .value_parser(OwnerEntry::parse)
+                .action(ArgAction::Append)
+                .help("Owner of everything under a directory of the DO, ahead of --owner"),
+        )
+        // Talking to the DO
+        .arg(
+            option("backend", "BACKEND")
+                .value_parser(Backend::parse)
+                .help(
+                    "Where files live: do (default), memory, or local:<directory>, served without a DO, \
+                     overlay:<directory>, keeping changes to the DO there until they are committed, or \
+                     tier:<directory>, keeping the files in use there",
+                ),
+        )
+        .arg(
+            option("tier-high-watermark", "BYTES")
+                .value_parser(positive::<u64>)
+                .help("Local disk a tier's working set may use before cold files are evicted (default 1 GiB)"),
+        )
+        .arg(
+            option("tier-low-watermark", "BYTES")
+                .value_parser(positive::<u64>)
+                .help("What eviction brings a tier's working set down to (default 4/5 of the high watermark)"),
+        )
+        .arg(
+            option("tier-writeback-delay", "DURATION")
+                .value_parser(humantime::parse_duration)
+                .help("How long a change stays in a tier before it is written back to the DO (default 30s)"),
+        )
+        .arg(
+            option("tier-promote-after", "N")
+                .value_parser(positive::<u32>)
+                .help("Reads of a file only the DO has before a tier brings it local (default 1)"),
+        )
+        .args(transport())
+        .arg(
+            option("clients", "N")
+                .value_parser(positive::<usize>)
+                .conflicts_with("connect")
+                .help("DOs served at once when listening (default 1)"),
+        )
+        .arg(
+            option("routing", "ROUTING")
+                .value_parser(Routing::parse)
+                .help("Which connected DOs requests go to: primary (default) or share"),
+        )
+        .arg(
+            option("connections", "N")
+                .value_parser(positive::<usize>)
+                .help("Connections to accept from a DO that pools them (default 3)"),
+        )
+        .arg(flag("lazy-connect").help("Mount without waiting for the DO's first connection"))
+        .arg(
+            option("encoding", "ENCODING")
+                .value_parser(Encoding::parse)
+                .help("Message encoding: msgpack (default), json or legacy"),
+        )
+        .arg(flag("legacy-framing").conflicts_with("encoding").help("Same as --encoding=legacy"))
+        .arg(flag("no-compression").help("Don't offer LZ4 compression of large payloads"))
+        .arg(
+            option("max-in-flight", "N")
+                .value_parser(positive::<usize>)
+                .help("Requests awaiting a response at once (default 256)"),
+        )
+        .arg(
+            option("max-frame-size", "BYTES")
+                .value_parser(frame_size)
+                .help("Longest frame accepted from the DO (default 1 MiB)"),
+        )
+        .arg(
+            option("transfer-chunk-size", "BYTES")
+                .value_parser(chunk_size)
+                .help("Size large reads and flushed writes are split into (default 32 KiB)"),
+        )
+        .arg(
+            option("transfer-parallelism", "N")
+                .value_parser(positive::<usize>)
+                .help("Chunks of one transfer in flight at once (default 4)"),
+        )
+        // Timeouts
+        .arg(
+            option("heartbeat-interval", "DURATION")
+                .value_parser(humantime::parse_duration)
+                .help("How often idle connections are pinged, 0 to never (default 10s)"),
+        )
+        .arg(
+            option("heartbeat-timeout", "DURATION")
+                .value_parser(humantime::parse_duration)
+                .help("How long a connection may go unanswered before it is closed (default 30s)"),
+        )
+        // Observability and operations
+        .arg(
+            option("log-format", "FORMAT")
+                .value_parser(LogFormat::parse)
+                .help("Log lines as text (default) or json"),
+        )
+        .arg(
+            option("log-level", "DIRECTIVES")
+                .value_parser(logging::parse_level)
+                .help("Levels to log, such as debug or info,fsdaemon=trace (default RUST_LOG, or info)"),
+        )
+        .arg(
+            option("slow-threshold", "DURATION")
+                .value_parser(humantime::parse_duration)
+                .help("Log requests that take at least this long"),
+        )
+        .arg(
+            option("stuck-threshold", "DURATION")
+                .value_parser(humantime::parse_duration)
+                .help("Report requests unanswered for this long, 0 to never (default 5s)"),
+        )
+        .arg(flag("trace-context").help("Send each request's W3C trace context to the DO"))
+        .arg(option("metrics-listen", "ADDRESS").help("Serve Prometheus metrics and health probes here"))
+        .arg(
+            option("admin-socket", "ADDRESS")
+                .value_parser(admin::parse_address)
+                .help("Take admin commands on unix://<path> or a loopback host:port"),
+        )
+        .arg(
+            option("events-socket", "ADDRESS")
+                .value_parser(admin::parse_address)
+                .help("Stream changes pushed by the DO to subscribers on unix://<path> or a loopback host:port"),
+        )
+        .arg(
+            option("webdav-listen", "ADDRESS")
+                .value_parser(admin::parse_address)
+                .help("Also serve the mounts over WebDAV on unix://<path> or a loopback host:port"),
+        )
+        .arg(
+            option("sftp-listen", "ADDRESS")
+                .value_parser(sftp::parse_listen)
+                .help("Also serve the mounts over SFTP on a loopback host:port, to the user the daemon runs as"),
+        )
+        .arg(
+            option("sftp-host-key", "PATH")
+                .requires("sftp-listen")
+                .help("Ed25519 host key for SFTP, made here if missing (default: a new key every start)"),
+        )
+        .arg(
+            option("sftp-authorized-keys", "PATH")
+                .requires("sftp-listen")
+                .help("Keys SFTP clients log in with (default: the daemon user's ~/.ssh/authorized_keys)"),
+        )
+        .arg(
+            flag("sftp-allow-any-user")
+                .requires("sftp-listen")
+                .help("Let SFTP clients log in as any user, not only the one the daemon runs as"),
+        )
+        .arg(
+            option("s3-listen", "ADDRESS")
+                .value_parser(admin::parse_address)
+                .help("Also serve the mounts as path-style S3 buckets on unix://<path> or a loopback host:port"),
+        )
+        .arg(option("audit-log", "PATH").help("Append a JSON line for every create, write and delete"))
+        .arg(
+            option("audit-log-max-size", "BYTES")
+                .value_parser(positive::<u64>)
+                .requires("audit-log")
+                .help("Size the audit log rotates at (default 64 MiB)"),
+        )
+        .arg(flag("audit-mirror").requires("audit-log").help("Also send audit records to the DO"))
+        .arg(option("post-mount-exec", "COMMAND").help("Shell command started once the filesystem is mounted"))
+        .arg(option("pre-unmount-exec", "COMMAND").help("Shell command run to completion before unmounting"))
+        .arg(
+            option("shutdown-timeout", "DURATION")
+                .value_parser(humantime::parse_duration)
+                .help("Exit this long after SIGTERM or SIGINT even if shutdown hasn't finished (default 30s)"),
+        )
+        .arg(
+            option("remount-attempts", "N")
+                .value_parser(clap::value_parser!(usize))
+                .help("Times in a row a mount that went away is mounted again, 0 for never (default 3)"),
+        )
+        .arg(
+            option("remount-backoff", "DURATION")
+                .value_parser(humantime::parse_duration)
+                .help("Wait before the first remount, doubling for each after it (default 1s)"),
+        )
+        .subcommand(restore())
+        .subcommand(sync())
+}
+
+// Parses the command line, exiting with the usage if it is invalid. Settings
+// from a --config file are parsed as if given before the command line, except
+// those for options the command line or environment sets or conflicts with.
+// `restore` and `sync` only take the settings they have options for.
+pub fn matches() -> ArgMatches {
+    try_matches().unwrap_or_else(|e| e.exit())
+}
+
+pub fn try_matches() -> Result<ArgMatches, clap::Error> {
+    try_matches_from(std::env::args_os())
+}
+
+// Whether the binary was run as `fsctl`, through a link or a copy under that
+// name, so it is the tool rather than the daemon
+pub fn is_fsctl() -> bool {
+    std::env::args_os().next().is_some_and(|arg0| invoked_as_fsctl(&arg0))
+}
+
+fn invoked_as_fsctl(arg0: &OsStr) -> bool {
+    Path::new(arg0).file_name().is_some_and(|name| name == "fsctl")
+}
+
+fn try_matches_from(args: impl IntoIterator<Item = OsString>) -> Result<ArgMatches, clap::Error> {
+    let args: Vec<OsString> = args.into_iter().collect();
+    let root = match args.first().is_some_and(|arg0| invoked_as_fsctl(arg0)) {
+        true => fsctl,
+        false => command,
+    };
+    let matches = root().try_get_matches_from(&args)?;
+    // Nothing can come before the subcommand, so its options start after it.
+    // fsctl's come before its commands.
+    let (target, given, skip) = match matches.subcommand() {
+        Some(("restore", given)) => (restore(), given, 2),
+        Some(("sync", given)) => (sync(), given, 2),
+        _ => (root(), &matches, 1),
+    };
+    let Some(path) = given.get_one::<String>("config") else {
+        return Ok(matches);
+    };
+    let settings = config_args(path, given, target).map_err(|e| {
+        root().error(ErrorKind::InvalidValue, format!("invalid --config '{}': {}", path, e))
+    })?;
+    let mut merged = args[..skip].to_vec();
+    merged.extend(settings.into_iter().map(OsString::from));
+    merged.extend_from_slice(&args[skip..]);
+    root().try_get_matches_from(merged)
+}
+
+// The settings in the config file at `path` that `command` has options for,
+// as command line arguments
+fn config_args(path: &str, given: &ArgMatches, mut command: Command) -> Result<Vec<String>, String> {
+    let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let config: toml::Table = toml::from_str(&text).map_err(|e| e.to_string())?;
+    command.build();
+    let mut args = Vec::new();
+    for (section, settings) in config.iter() {
+        let Some((_, keys)) = SECTIONS.iter().find(|(name, _)| name == section) else {
+            let names: Vec<_> = SECTIONS.iter().map(|(name, _)| format!("[{}]", name)).collect();
+            return Err(format!("unknown section '{}', expected {}", section, names.join(", ")));
+        };
+        let toml::Value::Table(settings) = settings else {
+            return Err(format!("'{}' must be a [{}] table", section, section));
+        };
+        for (key, value) in settings.iter() {
+            if !keys.contains(&key.as_str()) {
+                return Err(format!("unknown setting '{}' in [{}], expected one of {}", key, section, keys.join(", ")));
+            }
+            let ours = command.get_arguments().any(|arg| arg.get_id().as_str() == key);
+            if !ours || overridden(&command, given, key) {
+                continue;
+            }
+            // Repeatable options are given once per value, the rest take the list
+            let repeatable = command
+                .get_arguments()
+                .any(|arg| arg.get_id().as_str() == key && matches!(arg.get_action(), ArgAction::Append));
+            let value = match value {
+                toml::Value::Boolean(false) => continue,
+                toml::Value::Boolean(true) => None,
+                toml::Value::Array(values) => {
+                    let values: Option<Vec<_>> = values.iter().map(scalar).collect();
+                    let values = values.ok_or_else(|| format!("[{}] {} must list strings or numbers", section, key))?;
+                    if repeatable {
+                        args.extend(values.iter().map(|value| format!("--{}={}", key, value)));
+                        continue;
+                    }
+                    Some(values.join(","))
+                }
+                value => Some(scalar(value).ok_or_else(|| {
+                    format!("[{}] {} must be a string, number, boolean or list", section, key)
+                })?),
+            };
+            args.push(match (key.as_str(), value) {
+                ("mount-point", Some(mount_point)) => mount_point,
+                (_, Some(value)) => format!("--{}={}", key, value),
+                (_, None) => format!("--{}", key),
+            });
+        }
+    }
+    Ok(args)
+}
+
+fn scalar(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(value) => Some(value.clone()),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+// Whether the command line or environment set `key` or an option either side
+// of a conflict with it, such as --connect for `listen`
+fn overridden(command: &Command, given: &ArgMatches, key: &str) -> bool {
+    let set = |arg: &Arg| {
+        let source = given.value_source(arg.get_id().as_str());
+        matches!(source, Some(ValueSource::CommandLine | ValueSource::EnvVariable))
+    };
+    let Some(arg) = command.get_arguments().find(|arg| arg.get_id().as_str() == key) else {
+        return false;
+    };
+    set(arg)
+        || command.get_arg_conflicts_with(arg).into_iter().any(set)
+        || command.get_arguments().filter(|other| set(other)).any(|other| {
+            command.get_arg_conflicts_with(other).iter().any(|conflict| conflict.get_id().as_str() == key)
+        })
+}
+
+fn restore() -> Command {
+    Command::new("restore")
+        .about("Repopulate the primary backend from the replica or a snapshot")
+        .arg(
+            Arg::new("from")
+                .long("from")
+                .value_name("SOURCE")
+                .required(true)
+                .value_parser(["snapshot", "replica"])
+                .help("Replay the replica's mutation log, or copy the newest snapshot"),
+        )
+        .arg(
+            Arg::new("at")
+                .long("at")
+                .value_name("TIME")
+                .value_parser(humantime::parse_rfc3339_weak)
+                .help("RFC 3339 time to restore to (default now)"),
+        )
+        .arg(
+            Arg::new("yes")
+                .long("yes")
+                .short('y')
+                .action(ArgAction::SetTrue)
+                .help("Don't ask for confirmation"),
+        )
+        .arg(config())
+        .args(transport())
+}
+
+fn sync() -> Command {
+    Command::new("sync")
+        .about("Mirror a local directory to a prefix in the DO, once or as it changes")
+        .arg(Arg::new("source").value_name("LOCAL_DIR").required(true).help("Directory whose files are sent"))
+        .arg(
+            Arg::new("prefix")
+                .value_name("REMOTE_PREFIX")
+                .required(true)
+                .value_parser(parse_prefix)
+                .help("Where in the DO they go, such as /backup"),
+        )
+        .arg(
+            Arg::new("watch")
+                .long("watch")
+                .action(ArgAction::SetTrue)
+                .help("Keep sending changes until signalled instead of exiting after one pass"),
+        )
+        .arg(
+            Arg::new("interval")
+                .long("interval")
+                .value_name("DURATION")
+                .value_parser(humantime::parse_duration)
+                .requires("watch")
+                .help("How often --watch looks for changes (default 2s)"),
+        )
+        .arg(
+            Arg::new("delete")
+                .long("delete")
+                .action(ArgAction::SetTrue)
+                .help("Remove files under the prefix that aren't in the directory"),
+        )
+        .arg(
+            Arg::new("checksum")
+                .long("checksum")
+                .action(ArgAction::SetTrue)
+                .help("Compare same-sized files by content rather than by modification time"),
+        )
+        .arg(config())
+        .args(transport())
+}
+
+// `fsctl <command>`, the daemon binary run under that name: file operations
+// straight against the DO, without mounting anything
+fn fsctl() -> Command {
+    let path = |help: &'static str| Arg::new("path").value_name("PATH").value_parser(parse_prefix).help(help);
+    let paths = |help: &'static str| path(help).required(true).num_args(1..);
+    Command::new("fsctl")
+        .about("Run file operations against the DO directly, without mounting it")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("ls")
+                .about("List a directory, directories ending in a slash")
+                .arg(
+                    Arg::new("long")
+                        .short('l')
+                        .action(ArgAction::SetTrue)
+                        .help("Give each file's size and when it was last written"),
+                )
+                .arg(path("Directory or file to list (default /)")),
+        )
+        .subcommand(Command::new("cat").about("Write files to stdout").arg(paths("Files to read")))
+        .subcommand(
+            Command::new("put")
+                .about("Replace a file with a local one, or with stdin for -")
+                .arg(Arg::new("source").value_name("LOCAL").required(true).help("Local file to send, or -"))
+                .arg(path("Where in the DO it goes").required(true)),
+        )
+        .subcommand(Command::new("rm").about("Remove files").arg(paths("Files to remove")))
+        .subcommand(Command::new("stat").about("Describe files and directories").arg(paths("Paths to describe")))
+        .subcommand(
+            Command::new("du")
+                .about("Total the bytes and files under paths: bytes, files and the path, tab separated")
+                .arg(paths("Directories or files to total")),
+        )
+        .arg(config())
+        .args(transport())
+}
+
+// The settings for finding and talking to the DO, which `restore`, `sync` and
+// `fsctl` need as much as the mount does
+fn transport() -> Vec<Arg> {
+    vec![
+        option("listen", "ADDRESS")
+            .value_parser(Address::parse)
+            .help("Wait for the DO to connect here (default 10.0.0.1:8000)"),
+        option("connect", "ADDRESS,...")
+            .value_parser(addresses)
+            .conflicts_with("listen")
+            .help("Connect out to the first of these DOs that answers instead of listening"),
+        option("tls-cert", "PEM").requires("tls-key").requires("tls-ca").help("Run connections over TLS"),
+        option("tls-key", "PEM").requires("tls-cert").help("Key for --tls-cert"),
+        option("tls-ca", "PEM").requires("tls-cert").help("CA the DO's certificate must chain to"),
+        option("tls-server-name", "NAME")
+            .requires("tls-cert")
+            .help("Name to check the DO's certificate against instead of the dialed host"),
+        option("encryption-key", "HEX")
+            .value_parser(Key::parse)
+            .help("Encrypt file contents in the DO with this key, as 64 hex digits; best set in the environment"),
+        option("encryption-key-file", "PATH")
+            .value_parser(Key::read)
+            .conflicts_with("encryption-key")
+            .help("Encrypt file contents in the DO with the key in this file, as 32 bytes or 64 hex digits"),
+        // Sealed chunks differ every time, so there is nothing to deduplicate
+        flag("dedup")
+            .conflicts_with_all(["encryption-key", "encryption-key-file"])
+            .help("Send writes as content-defined chunks, skipping those the DO already has"),
+        option("request-timeout", "DURATION")
+            .value_parser(humantime::parse_duration)
+            .help("How long any request waits for the DO's answer, unless its class's timeout is given"),
+        option("metadata-timeout", "DURATION")
+            .value_parser(humantime::parse_duration)
+            .help("How long stats, listings, unlinks and leases wait for an answer (default 10s)"),
+        option("read-timeout", "DURATION")
+            .value_parser(humantime::parse_duration)
+            .help("How long a read waits for an answer (default 30s)"),
+        option("write-timeout", "DURATION")
+            .value_parser(humantime::parse_duration)
+            .help("How long a write or chunk of one waits for an answer (default 1m)"),
+        option("sync-timeout", "DURATION")
+            .value_parser(humantime::parse_duration)
+            .help("How long committing a streamed write or a restore batch waits (default 2m)"),
+        option("disconnect", "MODE")
+            .value_parser(["soft", "hard"])
+            .help("Whether requests give up on an unreachable DO: soft (default) or hard"),
+        option("disconnect-timeout", "DURATION")
+            .value_parser(humantime::parse_duration)
+            .help("How long soft requests wait for the DO to come back (default 30s)"),
+        option("disconnect-errno", "ERRNO")
+            .value_parser(Disconnect::parse_errno)
+            .help("What requests fail with when the DO is unreachable (default eio)"),
+        option("disconnect-queue", "N")
+            .value_parser(positive::<usize>)
+            .help("Requests that may wait for the DO at once (default 1024)"),
+        option("retry-attempts", "N")
+            .value_parser(clap::value_parser!(usize))
+            .help("Times a request that failed for a passing reason is resent, 0 for never (default 2)"),
+        option("retry-backoff", "DURATION")
+            .value_parser(humantime::parse_duration)
+            .help("Wait before the first resend, doubling for each after it (default 100ms)"),
+        option("retry-max-backoff", "DURATION")
+            .value_parser(humantime::parse_duration)
+            .help("Longest wait between resends (default 5s)"),
+        option("retry-jitter", "FRACTION")
+            .value_parser(fraction)
+            .help("Share of each wait left to chance, from 0 to 1 (default 0.5)"),
+    ]
+}
+
+fn config() -> Arg {
+    Arg::new("config")
+        .long("config")
+        .env(env("config"))
+        .value_name("PATH")
+        .help("TOML file of settings, for options not given on the command line")
+}
+
+// Flags are also set by FSDAEMON_<NAME>=true
+fn flag(name: &'static str) -> Arg {
+    Arg::new(name).long(name).env(env(name)).action(ArgAction::SetTrue)
+}
+
+// Options only take their value as --name=value, like they always have, or
+// from FSDAEMON_<NAME>
+fn option(name: &'static str, value: &'static str) -> Arg {
+    Arg::new(name).long(name).env(env(name)).value_name(value).require_equals(true)
+}
+
+// FSDAEMON_CACHE_SIZE for --cache-size
+fn env(name: &str) -> String {
+    format!("FSDAEMON_{}", name.to_uppercase().replace('-', "_"))
+}
+
+pub fn positive<T: FromStr + PartialOrd + Default>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|number| *number > T::default())
+        .ok_or_else(|| "expected a positive number".to_string())
+}
+
+fn fraction(value: &str) -> Result<f64, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|number| (0.0..=1.0).contains(number))
+        .ok_or_else(|| "expected a number from 0 to 1".to_string())
+}
+
+fn frame_size(value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|size| *size >= MIN_MAX_FRAME_SIZE)
+        .ok_or_else(|| format!("expected at least {}", MIN_MAX_FRAME_SIZE))
+}
+
+fn chunk_size(value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|size| (MIN_TRANSFER_CHUNK_SIZE..=MAX_IO_SIZE).contains(size))
+        .ok_or_else(|| format!("expected {} to {}", MIN_TRANSFER_CHUNK_SIZE, MAX_IO_SIZE))
+}
+
+fn max_read(value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|size| (MIN_MAX_READ..=MAX_IO_SIZE).contains(size))
+        .ok_or_else(|| format!("expected {} to {}", MIN_MAX_READ, MAX_IO_SIZE))
+}
+
+// Mount options are comma separated, so a name can't hold one
+fn mount_name(value: &str) -> Result<String, String> {
+    match !value.is_empty() && value.chars().all(|c| c.is_ascii_graphic() && c != ',') {
+        true => Ok(value.to_string()),
+        false => Err("expected a name without spaces or commas".to_string()),
+    }
+}
+
+fn addresses(list: &str) -> Result<Vec<Address>, String> {
+    let addresses = list
+        .split(',')
+        .filter(|address| !address.is_empty())
+        .map(Address::parse)
+        .collect::<Result<Vec<_>, _>>()?;
+    match addresses.is_empty() {
+        true => Err("expected host:port[,...]".to_string()),
+        false => Ok(addresses),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::*;
+
+    // Writes `settings` to a config file for one test
+    fn config(name: &str, settings: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("fsdaemon-cli-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, settings).unwrap();
+        path
+    }
+
+    fn parse(args: &[&str]) -> Result<ArgMatches, clap::Error> {
+        try_matches_from(std::iter::once("fsdaemon").chain(args.iter().copied()).map(OsString::from))
+    }
+
+    #[test]
+    fn command_line_wins_over_config() {
+        let path = config("precedence", "[cache]\ncache-size = 1234\nreadahead-window = 8\n");
+        let matches = parse(&[&format!("--config={}", path.display()), "--readahead-window=16"]).unwrap();
+        assert_eq!(matches.get_one::<usize>("cache-size"), Some(&1234));
+        assert_eq!(matches.get_one::<usize>("readahead-window"), Some(&16));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn environment_wins_over_config() {
+        let path = config("environment", "[cache]\ndir-cache-ttl = \"9s\"\n");
+        std::env::set_var("FSDAEMON_DIR_CACHE_TTL", "2s");
+        let matches = parse(&[&format!("--config={}", path.display())]);
+        std::env::remove_var("FSDAEMON_DIR_CACHE_TTL");
+        assert_eq!(matches.unwrap().get_one::<Duration>("dir-cache-ttl"), Some(&Duration::from_secs(2)));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn config_settings_give_way_to_conflicting_options() {
+        let path = config("conflict", "[transport]\nlisten = \"0.0.0.0:9000\"\n[mount]\nwrite-back = true\n");
+        let args = [&format!("--config={}", path.display()), "--connect=do:1", "--consistency=strict"];
+        let matches = parse(&args).unwrap();
+        assert_eq!(matches.get_one::<Address>("listen"), None);
+        assert!(matches.get_one::<Vec<Address>>("connect").is_some());
+        assert!(!matches.get_flag("write-back"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn lists_repeat_repeatable_options() {
+        let path = config("lists", "[mount]\nmount = [\"/a:/a\", \"/b:/b,read-only\"]\n");
+        let matches = parse(&[&format!("--config={}", path.display())]).unwrap();
+        let mounts: Vec<_> = matches.get_many::<MountSpec>("mount").unwrap().map(|spec| &spec.mount_point).collect();
+        assert_eq!(mounts, ["/a", "/b"]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        for (name, settings) in [("section", "[nope]\nx = 1\n"), ("key", "[cache]\nnope = 1\n")] {
+            let path = config(name, settings);
+            assert!(parse(&[&format!("--config={}", path.display())]).is_err(), "{}", settings);
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn restore_takes_only_the_settings_it_has() {
+        let path = config("restore", "[mount]\nread-only = true\n[transport]\nconnect = \"do:1\"\n");
+        let matches = parse(&["restore", "--from=replica", &format!("--config={}", path.display())]).unwrap();
+        let Some(("restore", restore)) = matches.subcommand() else {
+            panic!("expected the restore subcommand");
+        };
+        assert_eq!(restore.get_one::<Vec<Address>>("connect"), Some(&vec![Address::Tcp("do:1".to_string())]));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+use ring::digest::{digest, SHA256};
+
+// Bounds on the chunks a write is cut into. Cuts fall where a rolling hash
+// of the content matches a mask, so they move with the data rather than the
+// offset: bytes inserted into a file only change the chunks around them.
+pub const MIN_CHUNK: usize = 4 * 1024;
+const MAX_CHUNK: usize = 64 * 1024;
+// About one cut every 16 KiB past the minimum
+const MASK: u64 = (1 << 14) - 1;
+
+// Random values the rolling hash mixes in for each byte, fixed so every
+// daemon cuts the same content in the same places
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut i = 0;
+    while i < 256 {
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+// The content-defined chunks of `data`, in order
+pub fn chunks(data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (chunk, after) = rest.split_at(cut(rest));
+        chunks.push(chunk);
+        rest = after;
+    }
+    chunks
+}
+
+// Where the chunk at the start of `data` ends
+fn cut(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK {
+        return data.len();
+    }
+    let mut hash: u64 = 0;
+    for (i, &byte) in data.iter().enumerate().take(MAX_CHUNK).skip(MIN_CHUNK) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        if hash & MASK == 0 {
+            return i + 1;
+        }
+    }
+    data.len().min(MAX_CHUNK)
+}
+
+// What the DO knows a chunk by: its SHA-256, in hex
+pub fn hash(chunk: &[u8]) -> String {
+    digest(&SHA256, chunk).as_ref().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cuts_follow_the_content() {
+        let mut state: u64 = 1;
+        let data: Vec<u8> = (0..400_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let before = chunks(&data);
+        assert_eq!(before.concat(), data);
+        assert!(before.len() > 4);
+        assert!(before.iter().rev().skip(1).all(|chunk| (MIN_CHUNK..=MAX_CHUNK).contains(&chunk.len())));
+
+        // A few bytes in front only change the first chunk
+        let mut shifted = b"inserted".to_vec();
+        shifted.extend_from_slice(&data);
+        let after = chunks(&shifted);
+        assert_eq!(after[1..], before[1..]);
+        assert_eq!(hash(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+}
@@ -0,0 +1,230 @@
+use std::fmt;
+use std::fs;
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::error::DaemonError;
+
+// Plaintext sealed at a time. A file is stored as its sealed chunks one after
+// another, each with a nonce of its own before it and its tag after, so a
+// range is read by opening only the chunks it covers.
+pub const CHUNK_SIZE: u64 = 32 * 1024;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+pub const SEALED_CHUNK_SIZE: u64 = CHUNK_SIZE + (NONCE_LEN + TAG_LEN) as u64;
+
+// A 256-bit key, kept out of Debug output
+#[derive(Clone)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    // --encryption-key: 64 hex digits
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        let digits = value.as_bytes();
+        if digits.len() != 64 || !digits.iter().all(u8::is_ascii_hexdigit) {
+            return Err("expected 64 hex digits".to_string());
+        }
+        let mut key = [0; 32];
+        for (byte, pair) in key.iter_mut().zip(digits.chunks(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(pair).unwrap_or_default(), 16).unwrap_or_default();
+        }
+        Ok(Self(key))
+    }
+
+    // --encryption-key-file: the key as 32 bytes or 64 hex digits, as a
+    // mounted secret holds it
+    pub fn read(path: &str) -> Result<Self, String> {
+        let contents = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        match <[u8; 32]>::try_from(contents.as_slice()) {
+            Ok(key) => Ok(Self(key)),
+            Err(_) => Self::parse(&String::from_utf8_lossy(&contents))
+                .map_err(|_| format!("{}: expected 32 bytes or 64 hex digits", path)),
+        }
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+// Seals and opens file contents with XChaCha20-Poly1305. Each chunk is bound
+// to its file's path and its place in it, so chunks moved to another file or
+// position in the DO fail to open rather than read as something else.
+pub struct Cipher {
+    key: [u8; 32],
+    random: SystemRandom,
+}
+
+impl Cipher {
+    pub fn new(key: &Key) -> Self {
+        Self { key: key.0, random: SystemRandom::new() }
+    }
+
+    // Chunk `index` of the file at `path`, sealed under a fresh random nonce
+    pub fn seal(&self, path: &str, index: u64, plaintext: &[u8]) -> Result<Vec<u8>, DaemonError> {
+        let mut nonce = [0; NONCE_LEN];
+        self.random.fill(&mut nonce).map_err(|_| DaemonError::Protocol("No randomness for a nonce".to_string()))?;
+        let mut sealed = Vec::with_capacity(NONCE_LEN + plaintext.len() + TAG_LEN);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(plaintext);
+        let (key, nonce) = self.subkey(&nonce);
+        let tag = key
+            .seal_in_place_separate_tag(nonce, associated_data(path, index), &mut sealed[NONCE_LEN..])
+            .map_err(|_| DaemonError::Protocol(format!("Failed to encrypt {}", path)))?;
+        sealed.extend_from_slice(tag.as_ref());
+        Ok(sealed)
+    }
+
+    pub fn open(&self, path: &str, index: u64, sealed: &[u8]) -> Result<Vec<u8>, DaemonError> {
+        let failed = || DaemonError::Protocol(format!("Failed to decrypt chunk {} of {}", index, path));
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return Err(failed());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let (key, nonce) = self.subkey(nonce.try_into().map_err(|_| failed())?);
+        let mut plaintext = ciphertext.to_vec();
+        let len = key.open_in_place(nonce, associated_data(path, index), &mut plaintext).map_err(|_| failed())?.len();
+        plaintext.truncate(len);
+        Ok(plaintext)
+    }
+
+    // Consecutive sealed chunks, the first of them chunk `first`, as one run
+    // of plaintext
+    pub fn open_chunks(&self, path: &str, first: u64, sealed: &[u8]) -> Result<Vec<u8>, DaemonError> {
+        let mut plaintext = Vec::with_capacity(sealed.len());
+        for (i, chunk) in sealed.chunks(SEALED_CHUNK_SIZE as usize).enumerate() {
+            plaintext.extend_from_slice(&self.open(path, first + i as u64, chunk)?);
+        }
+        Ok(plaintext)
+    }
+
+    // XChaCha20-Poly1305 is ChaCha20-Poly1305 under a subkey HChaCha20
+    // derives from the key and the nonce's first 16 bytes, with the last 8
+    // as the nonce
+    fn subkey(&self, nonce: &[u8; NONCE_LEN]) -> (LessSafeKey, Nonce) {
+        let (head, tail) = nonce.split_at(16);
+        let mut input = [0; 16];
+        input.copy_from_slice(head);
+        let subkey = hchacha20(&self.key, &input);
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &subkey).expect("a ChaCha20 key is 32 bytes");
+        let mut short = [0; 12];
+        short[4..].copy_from_slice(tail);
+        (LessSafeKey::new(key), Nonce::assume_unique_for_key(short))
+    }
+}
+
+fn associated_data(path: &str, index: u64) -> Aad<Vec<u8>> {
+    let mut data = index.to_be_bytes().to_vec();
+    data.extend_from_slice(path.as_bytes());
+    Aad::from(data)
+}
+
+fn hchacha20(key: &[u8; 32], input: &[u8; 16]) -> [u8; 32] {
+    let word = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap_or_default());
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    for (i, bytes) in key.chunks(4).enumerate() {
+        state[4 + i] = word(bytes);
+    }
+    for (i, bytes) in input.chunks(4).enumerate() {
+        state[12 + i] = word(bytes);
+    }
+    let quarter = |state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize| {
+        state[a] = state[a].wrapping_add(state[b]);
+        state[d] = (state[d] ^ state[a]).rotate_left(16);
+        state[c] = state[c].wrapping_add(state[d]);
+        state[b] = (state[b] ^ state[c]).rotate_left(12);
+        state[a] = state[a].wrapping_add(state[b]);
+        state[d] = (state[d] ^ state[a]).rotate_left(8);
+        state[c] = state[c].wrapping_add(state[d]);
+        state[b] = (state[b] ^ state[c]).rotate_left(7);
+    };
+    for _ in 0..10 {
+        quarter(&mut state, 0, 4, 8, 12);
+        quarter(&mut state, 1, 5, 9, 13);
+        quarter(&mut state, 2, 6, 10, 14);
+        quarter(&mut state, 3, 7, 11, 15);
+        quarter(&mut state, 0, 5, 10, 15);
+        quarter(&mut state, 1, 6, 11, 12);
+        quarter(&mut state, 2, 7, 8, 13);
+        quarter(&mut state, 3, 4, 9, 14);
+    }
+    let mut subkey = [0; 32];
+    for (bytes, word) in subkey.chunks_mut(4).zip(state[..4].iter().chain(&state[12..])) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    subkey
+}
+
+// The size of a file whose sealed chunks take `stored` bytes in the DO
+pub fn plain_size(stored: u64) -> u64 {
+    let partial = stored % SEALED_CHUNK_SIZE;
+    stored / SEALED_CHUNK_SIZE * CHUNK_SIZE + partial.saturating_sub((NONCE_LEN + TAG_LEN) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(value: &str) -> Vec<u8> {
+        let digits: String = value.split_whitespace().collect();
+        (0..digits.len()).step_by(2).map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap()).collect()
+    }
+
+    // From draft-irtf-cfrg-xchacha, sections 2.2.1 and A.3.1
+    #[test]
+    fn matches_the_xchacha_test_vectors() {
+        let key: [u8; 32] = hex("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f").try_into().unwrap();
+        let input = hex("000000090000004a0000000031415927").try_into().unwrap();
+        assert_eq!(
+            hchacha20(&key, &input).to_vec(),
+            hex("82413b4227b27bfed30e42508a877d73a0f9e4d58a74a853c12ec41326d3ecdc")
+        );
+
+        let cipher = Cipher {
+            key: hex("808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f").try_into().unwrap(),
+            random: SystemRandom::new(),
+        };
+        let nonce = hex("404142434445464748494a4b4c4d4e4f5051525354555657");
+        let mut sealed = concat!(
+            "Ladies and Gentlemen of the class of '99: ",
+            "If I could offer you only one tip for the future, sunscreen would be it."
+        )
+        .as_bytes()
+        .to_vec();
+        let (key, short) = cipher.subkey(nonce.as_slice().try_into().unwrap());
+        let tag =
+            key.seal_in_place_separate_tag(short, Aad::from(hex("50515253c0c1c2c3c4c5c6c7")), &mut sealed).unwrap();
+        assert_eq!(
+            sealed,
+            hex("bd6d179d3e83d43b9576579493c0e939572a1700252bfaccbed2902c21396cbb \
+                 731c7f1b0b4aa6440bf3a82f4eda7e39ae64c6708c54c216cb96b72e1213b452 \
+                 2f8c9ba40db5d945b11b69b982c1bb9e3f3fac2bc369488f76b2383565d3fff9 \
+                 21f9664c97637da9768812f615c68b13b52e")
+        );
+        assert_eq!(tag.as_ref(), hex("c0875924c1c7987947deafd8780acf49"));
+    }
+
+    #[test]
+    fn chunks_open_only_where_they_were_sealed() {
+        let cipher = Cipher::new(&Key::parse(&"ab".repeat(32)).unwrap());
+        let sealed = cipher.seal("/a", 3, b"hello").unwrap();
+        assert_eq!(sealed.len(), 5 + NONCE_LEN + TAG_LEN);
+        assert_eq!(cipher.open("/a", 3, &sealed).unwrap(), b"hello");
+        assert!(cipher.open("/b", 3, &sealed).is_err());
+        assert!(cipher.open("/a", 4, &sealed).is_err());
+        let mut damaged = sealed.clone();
+        damaged[NONCE_LEN] ^= 1;
+        assert!(cipher.open("/a", 3, &damaged).is_err());
+        // Nonces are never reused
+        assert_ne!(cipher.seal("/a", 3, b"hello").unwrap(), sealed);
+
+        assert_eq!(plain_size(0), 0);
+        assert_eq!(plain_size(SEALED_CHUNK_SIZE * 2 + 41), CHUNK_SIZE * 2 + 1);
+        assert!(Key::parse("abc").is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+
+use fuser::FileType;
+use tracing::debug;
+
+use crate::audit::{AuditOp, Caller};
+use crate::error::Locked;
+use crate::events::under;
+use crate::metrics::METRICS;
+use crate::names::NamePolicy;
+use crate::{join, RemoteFS, MAX_IO_SIZE};
+
+// One mount's filesystem, served under its mount point
+pub struct Mount {
+    pub point: String,
+    // The path on the DO the mount shows
+    pub root: String,
+    // What becomes of names the DO can't hold, as the mount's filesystem has it
+    pub names: NamePolicy,
+    pub fs: Mutex<RemoteFS>,
+}
+
+impl Mount {
+    // The path on the DO of `rest`, a path under the mount point, with each
+    // name in it as the name policy stores it. Fails with EINVAL when the
+    // policy rejects one.
+    pub fn path(&self, rest: &str) -> Result<String, i32> {
+        let mut path = self.root.clone();
+        for name in rest.split('/').filter(|name| !name.is_empty()) {
+            path = join(&path, &self.names.encode(OsStr::new(name))?);
+        }
+        Ok(path)
+    }
+
+    // A name the DO lists as clients of the endpoints see it
+    pub fn name(&self, listed: &str) -> String {
+        self.names.decode(listed).to_string_lossy().into_owned()
+    }
+
+    // Sends `path` from `start` up to `end` to `out` a piece at a time,
+    // letting go of the filesystem in between. A file that shrinks while it
+    // is sent ends it early, which is how HTTP clients learn they didn't get
+    // all of it.
+    pub fn send(&self, path: &str, start: u64, end: u64, out: &mut dyn Write) -> io::Result<()> {
+        let fh = self.fs.locked().next_handle();
+        let mut offset = start;
+        let result = loop {
+            if offset >= end {
+                break Ok(());
+            }
+            let size = (end - offset).min(MAX_IO_SIZE as u64);
+            let mut sent = Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            self.fs.locked().read_data(fh, path, offset, size, |data| {
+                sent = match data {
+                    Ok([]) => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                    Ok(data) => out.write_all(data).map(|()| data.len()),
+                    Err(e) => Err(io::Error::other(e.to_string())),
+                };
+            });
+            match sent {
+                Ok(sent) => offset += sent as u64,
+                Err(e) => break Err(e),
+            }
+        };
+        if let Err(e) = self.fs.locked().release_handle(fh) {
+            debug!("Failed to release a read of {}: {}", path, e);
+        }
+        result
+    }
+
+    // Replaces the file at `path` with what `body` reads, returning once the
+    // DO has all of it with whether the file is new. What failed is an errno,
+    // or the body's error if it couldn't be read.
+    pub fn replace(&self, path: &str, body: &mut dyn Read, caller: Caller) -> Result<bool, Result<i32, io::Error>> {
+        let created = {
+            let mut fs = self.fs.locked();
+            let existing = fs.refuses_changes(path).and_then(|()| fs.path_attr(path).map_err(|e| e.errno()));
+            let existing = existing.map_err(Ok)?;
+            if existing.as_ref().is_some_and(|attr| attr.kind == FileType::Directory) {
+                return Err(Ok(libc::EISDIR));
+            }
+            // Writes buffered for what is being replaced go first
+            let created = fs.sync_path(path).and_then(|()| fs.create_file(path, None));
+            fs.audit(caller, AuditOp::Create, path, None, None, created.as_ref().err().map(|e| e.errno()));
+            created.map_err(|e| Ok(e.errno()))?;
+            existing.is_none()
+        };
+
+        let fh = self.fs.locked().next_handle();
+        let mut offset = 0;
+        let mut buffer = vec![0; MAX_IO_SIZE];
+        let written = loop {
+            let read = match body.read(&mut buffer) {
+                Ok(0) => break Ok(()),
+                Ok(read) => read,
+                Err(e) => break Err(Err(e)),
+            };
+            let mut fs = self.fs.locked();
+            if let Err(errno) = fs.write_data(fh, path, offset, &buffer[..read]) {
+                break Err(Ok(errno));
+            }
+            METRICS.written(read);
+            offset += read as u64;
+        };
+        let mut fs = self.fs.locked();
+        let sent = fs.release_handle(fh).and_then(|()| fs.sync_path(path)).map_err(|e| e.errno());
+        let errno = match &written {
+            Err(Ok(errno)) => Some(*errno),
+            Err(Err(_)) => Some(libc::EIO),
+            Ok(()) => sent.err(),
+        };
+        fs.audit(caller, AuditOp::Write, path, Some(0), Some(offset), errno);
+        match (written, errno) {
+            (Err(e), _) => Err(e),
+            (_, Some(errno)) => Err(Ok(errno)),
+            (_, None) => Ok(created),
+        }
+    }
+}
+
+// The mounts as the endpoints next to the frontend serve them, such as
+// WebDAV and SFTP, with filesystems of their own
+pub struct Endpoints {
+    pub mounts: Vec<Mount>,
+}
+
+// What a path under the endpoints refers to
+pub enum Resolved<'a> {
+    // `path` on the DO, in `mount`
+    In { mount: &'a Mount, path: String },
+    // Somewhere above mount points, with the names of what is under it
+    Above(Vec<String>),
+}
+
+impl Endpoints {
+    pub fn new(filesystems: Vec<RemoteFS>) -> Self {
+        let mounts = filesystems
+            .into_iter()
+            .map(|fs| Mount {
+                point: fs.mount_point.clone(),
+                root: fs.inodes.path(1).unwrap_or_else(|| "/".to_string()),
+                names: fs.names,
+                fs: Mutex::new(fs),
+            })
+            .collect();
+        Endpoints { mounts }
+    }
+
+    // Sends whatever was written through the endpoints that the DO doesn't have yet
+    pub fn stop(&self) {
+        for mount in &self.mounts {
+            mount.fs.locked().stopped();
+        }
+    }
+
+    // The mount `path` is in, the deepest if mounts nest, or what is under it
+    // if it is above them. Fails with EINVAL for a name in the mount that its
+    // name policy rejects.
+    pub fn resolve(&self, path: &str) -> Result<Option<Resolved<'_>>, i32> {
+        let mount = self.mounts.iter().filter(|mount| under(path, &mount.point)).max_by_key(|mount| mount.point.len());
+        if let Some(mount) = mount {
+            let path = mount.path(&path[mount.point.len()..])?;
+            return Ok(Some(Resolved::In { mount, path }));
+        }
+        let mut names: Vec<String> = self
+            .mounts
+            .iter()
+            .filter(|mount| under(&mount.point, path))
+            .filter_map(|mount| {
+                let rest = mount.point[if path == "/" { 0 } else { path.len() }..].trim_start_matches('/');
+                rest.split('/').next().map(str::to_string)
+            })
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok((!names.is_empty()).then_some(Resolved::Above(names)))
+    }
+}
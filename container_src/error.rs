@@ -0,0 +1,143 @@
+use std::io;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use thiserror::Error;
+
+// Why a request to the DO, or the work around it, failed. Everything past
+// startup reports one of these, and the FUSE callbacks turn it into an errno.
+#[derive(Debug, Error)]
+pub enum DaemonError {
+    // The DO answered with an error, and its errno when it sent one
+    #[error("{message}")]
+    Remote { message: String, errno: Option<i32> },
+    // The DO couldn't be reached, with the errno the disconnect policy gives
+    // it. `lost` is set when the connection went with the request on it, so
+    // the DO may or may not have seen it.
+    #[error("{message}")]
+    Disconnected { message: &'static str, errno: i32, lost: bool },
+    // The DO didn't answer within the request timeout
+    #[error("Request timeout")]
+    TimedOut,
+    // The process waiting on the request was signalled, and the request was
+    // abandoned for it
+    #[error("Interrupted")]
+    Interrupted,
+    // A request or response that doesn't make sense: a missing field, a
+    // checksum mismatch, a frame that wouldn't encode
+    #[error("{0}")]
+    Protocol(String),
+    // A local failure: the spill directory, the journal
+    #[error("{0}")]
+    Io(#[from] io::Error),
+}
+
+impl DaemonError {
+    // The errno to give the kernel: the DO's own code when it sent one, the
+    // disconnect policy's when the DO was unreachable, the OS's for local
+    // failures, EIO for anything else on the way there and back
+    pub fn errno(&self) -> i32 {
+        match self {
+            DaemonError::Remote { errno: Some(errno), .. } => *errno,
+            DaemonError::Remote { message, errno: None } => errno_of_message(message),
+            DaemonError::Disconnected { errno, .. } => *errno,
+            DaemonError::Io(e) => e.raw_os_error().unwrap_or(libc::EIO),
+            DaemonError::TimedOut | DaemonError::Protocol(_) => libc::EIO,
+            DaemonError::Interrupted => libc::EINTR,
+        }
+    }
+
+    // Failures where the request may or may not have reached the DO, as
+    // opposed to an error the DO answered with
+    pub fn is_transport(&self) -> bool {
+        matches!(self, DaemonError::TimedOut | DaemonError::Disconnected { lost: true, .. })
+    }
+
+    // Failures that trying again may get past: a lost response, or the DO
+    // saying it is busy or hit a passing error, such as storage failing
+    // underneath it
+    pub fn is_transient(&self) -> bool {
+        self.is_transport() || matches!(self, DaemonError::Remote { errno: Some(libc::EAGAIN | libc::EBUSY), .. })
+    }
+}
+
+// DOs that predate error codes send a few messages the daemon recognises as
+// they are
+fn errno_of_message(message: &str) -> i32 {
+    match message {
+        "File not found" => libc::ENOENT,
+        "Unknown operation" => libc::ENOSYS,
+        _ => libc::EIO,
+    }
+}
+
+// The conversions below do more than wrap a source error, so they aren't #[from]
+impl From<String> for DaemonError {
+    fn from(message: String) -> Self {
+        DaemonError::Protocol(message)
+    }
+}
+
+impl From<serde_json::Error> for DaemonError {
+    fn from(e: serde_json::Error) -> Self {
+        DaemonError::Protocol(format!("Failed to encode request: {}", e))
+    }
+}
+
+impl From<rmp_serde::encode::Error> for DaemonError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        DaemonError::Protocol(format!("Failed to encode request: {}", e))
+    }
+}
+
+// The request semaphore only closes on shutdown
+impl From<tokio::sync::AcquireError> for DaemonError {
+    fn from(_: tokio::sync::AcquireError) -> Self {
+        DaemonError::Disconnected { message: "Shutting down", errno: libc::ESHUTDOWN, lost: false }
+    }
+}
+
+// Locks a mutex whether or not a thread panicked while holding it. The state
+// behind the daemon's locks stays usable after a panic, and one failed
+// callback shouldn't take every later one down with it.
+pub trait Locked<T> {
+    fn locked(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> Locked<T> for Mutex<T> {
+    fn locked(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errnos_follow_the_failure() {
+        let remote = |errno| DaemonError::Remote { message: "File not found".to_string(), errno };
+        assert_eq!(remote(Some(libc::EACCES)).errno(), libc::EACCES);
+        assert_eq!(remote(None).errno(), libc::ENOENT);
+        let lost = DaemonError::Disconnected { message: "Connection closed", errno: libc::ETIMEDOUT, lost: true };
+        assert_eq!(lost.errno(), libc::ETIMEDOUT);
+        assert!(lost.is_transient());
+        assert!(!remote(Some(libc::ENOENT)).is_transient());
+        assert!(remote(Some(libc::EAGAIN)).is_transient());
+        assert_eq!(DaemonError::from(io::Error::from_raw_os_error(libc::ENOSPC)).errno(), libc::ENOSPC);
+        assert_eq!(DaemonError::Protocol("Checksum mismatch".to_string()).errno(), libc::EIO);
+    }
+
+    #[test]
+    fn poisoned_locks_still_lock() {
+        let lock = std::sync::Arc::new(Mutex::new(1));
+        let poisoner = lock.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+        assert!(lock.lock().is_err());
+        *lock.locked() += 1;
+        assert_eq!(*lock.locked(), 2);
+    }
+}
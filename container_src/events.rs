@@ -0,0 +1,300 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread;
+
+use serde_json::json;
+use tracing::{debug, info, warn};
+
+use crate::error::Locked;
+use crate::transport::{Address, Conn, Listener};
+
+// Lines queued for a subscriber that isn't reading. Events past it are
+// dropped, and the subscriber told so once it catches up.
+const QUEUE_LENGTH: usize = 1024;
+
+// Where changes the DO pushes are streamed to applications in the container
+// that asked to hear of them
+pub static EVENTS: Events = Events::new();
+
+pub struct Events {
+    // Each mount's mount point and the path on the DO it shows, so pushes
+    // reach subscribers under the paths applications know
+    mounts: Mutex<Vec<(String, String)>>,
+    subscribers: Mutex<Vec<Subscriber>>,
+    next_id: AtomicU64,
+}
+
+struct Subscriber {
+    id: u64,
+    // Local paths subscribed to, each standing for everything under it too
+    paths: Vec<String>,
+    queue: SyncSender<String>,
+    // Set when an event was dropped, until the subscriber is told
+    overflowed: bool,
+    // Set once its connection is found closed
+    closed: bool,
+}
+
+impl Events {
+    const fn new() -> Self {
+        Self {
+            mounts: Mutex::new(Vec::new()),
+            subscribers: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    fn set_mounts(&self, mounts: Vec<(String, String)>) {
+        *self.mounts.locked() = mounts;
+    }
+
+    // A new subscriber, with nothing subscribed yet, and its queue of lines
+    fn connect(&self) -> (u64, SyncSender<String>, Receiver<String>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (queue, lines) = mpsc::sync_channel(QUEUE_LENGTH);
+        self.subscribers.locked().push(Subscriber {
+            id,
+            paths: Vec::new(),
+            queue: queue.clone(),
+            overflowed: false,
+            closed: false,
+        });
+        (id, queue, lines)
+    }
+
+    fn disconnect(&self, id: u64) {
+        self.subscribers.locked().retain(|subscriber| subscriber.id != id);
+    }
+
+    fn subscribe(&self, id: u64, path: &str) -> Result<(), String> {
+        let path = normalize(path)?;
+        let mounts = self.mounts.locked();
+        if !mounts.iter().any(|(mount_point, _)| under(&path, mount_point) || under(mount_point, &path)) {
+            return Err(format!("{} is not on a mount", path));
+        }
+        let mut subscribers = self.subscribers.locked();
+        if let Some(subscriber) = subscribers.iter_mut().find(|subscriber| subscriber.id == id) {
+            if !subscriber.paths.contains(&path) {
+                subscriber.paths.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    fn unsubscribe(&self, id: u64, path: &str) -> Result<(), String> {
+        let path = normalize(path)?;
+        let mut subscribers = self.subscribers.locked();
+        let subscriber = subscribers.iter_mut().find(|subscriber| subscriber.id == id);
+        match subscriber.and_then(|subscriber| {
+            let index = subscriber.paths.iter().position(|subscribed| *subscribed == path)?;
+            Some(subscriber.paths.remove(index))
+        }) {
+            Some(_) => Ok(()),
+            None => Err(format!("not subscribed to {}", path)),
+        }
+    }
+
+    // Tells the subscribers of each mount showing `path` on the DO of how it
+    // changed: "modified", "created" or "removed"
+    pub fn publish(&self, path: &str, change: &str) {
+        let mut subscribers = self.subscribers.locked();
+        if subscribers.is_empty() {
+            return;
+        }
+        for (mount_point, prefix) in self.mounts.locked().iter() {
+            let Some(local) = local_path(mount_point, prefix, path) else {
+                continue;
+            };
+            let line = json!({ "event": change, "path": local }).to_string();
+            for subscriber in subscribers.iter_mut() {
+                if subscriber.paths.iter().any(|subscribed| under(&local, subscribed)) {
+                    subscriber.send(&line);
+                }
+            }
+        }
+        subscribers.retain(|subscriber| !subscriber.closed);
+    }
+
+    // Pushes may have been missed while the DO was out of reach, so every
+    // subscriber should look again at what it watches
+    pub fn resync(&self) {
+        let line = json!({ "event": "resync" }).to_string();
+        let mut subscribers = self.subscribers.locked();
+        for subscriber in subscribers.iter_mut().filter(|subscriber| !subscriber.paths.is_empty()) {
+            subscriber.send(&line);
+        }
+        subscribers.retain(|subscriber| !subscriber.closed);
+    }
+}
+
+impl Subscriber {
+    fn send(&mut self, line: &str) {
+        if self.overflowed {
+            match self.queue.try_send(json!({ "event": "overflow" }).to_string()) {
+                Ok(()) => self.overflowed = false,
+                Err(TrySendError::Full(_)) => return,
+                Err(TrySendError::Disconnected(_)) => self.closed = true,
+            }
+        }
+        match self.queue.try_send(line.to_string()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => self.overflowed = true,
+            Err(TrySendError::Disconnected(_)) => self.closed = true,
+        }
+    }
+}
+
+// `path` without a trailing slash, if it is absolute
+fn normalize(path: &str) -> Result<String, String> {
+    if !path.starts_with('/') {
+        return Err(format!("expected an absolute path, not '{}'", path));
+    }
+    match path.trim_end_matches('/') {
+        "" => Ok("/".to_string()),
+        path => Ok(path.to_string()),
+    }
+}
+
+// Whether `path` is `dir` or somewhere under it
+pub fn under(path: &str, dir: &str) -> bool {
+    dir == "/" || path.strip_prefix(dir).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+// Where `path` on the DO shows up in the mount at `mount_point` showing
+// `prefix`, if it does
+fn local_path(mount_point: &str, prefix: &str, path: &str) -> Option<String> {
+    if !under(path, prefix) {
+        return None;
+    }
+    match path[if prefix == "/" { 0 } else { prefix.len() }..].trim_start_matches('/') {
+        "" => Some(mount_point.to_string()),
+        rest => Some(format!("{}/{}", mount_point.trim_end_matches('/'), rest)),
+    }
+}
+
+// Streams changes on `address` to the mounts in `mounts`, given as mount
+// point and path on the DO. Each line sent is `subscribe <path>` or
+// `unsubscribe <path>` for a path in the container, answered with
+// `{"ok":true}` or `{"ok":false,"error":"..."}`. Changes at or under a
+// subscribed path follow as `{"event":"modified","path":"..."}`, with
+// "created" and "removed" too, along with `{"event":"resync"}` when changes
+// may have been missed and `{"event":"overflow"}` when some were dropped.
+pub fn spawn(address: &Address, mounts: Vec<(String, String)>) -> io::Result<()> {
+    EVENTS.set_mounts(mounts);
+    let listener = Listener::bind(address)?;
+    info!("Streaming file change events on {}", address);
+    thread::spawn(move || loop {
+        match listener.accept() {
+            Ok(stream) => {
+                thread::spawn(move || {
+                    if let Err(e) = serve(stream) {
+                        debug!("Event subscriber went away: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept an event subscriber: {}", e),
+        }
+    });
+    Ok(())
+}
+
+fn serve(stream: Conn) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    // Answers and events share the queue, so they never interleave mid-line
+    let (id, replies, lines) = EVENTS.connect();
+    let streaming = thread::spawn(move || -> io::Result<()> {
+        for line in lines {
+            writeln!(writer, "{}", line)?;
+        }
+        Ok(())
+    });
+    let result = (|| {
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            let reply = match run(id, line.trim()) {
+                None => continue,
+                Some(Ok(())) => json!({ "ok": true }),
+                Some(Err(error)) => json!({ "ok": false, "error": error }),
+            };
+            if replies.send(reply.to_string()).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    })();
+    EVENTS.disconnect(id);
+    drop(replies);
+    let _ = streaming.join();
+    result
+}
+
+fn run(id: u64, line: &str) -> Option<Result<(), String>> {
+    let (command, path) = match line.split_once(char::is_whitespace) {
+        Some((command, path)) => (command, path.trim()),
+        None => (line, ""),
+    };
+    Some(match (command, path) {
+        ("", _) => return None,
+        ("subscribe" | "unsubscribe", "") => Err(format!("{} needs a path", command)),
+        ("subscribe", path) => EVENTS.subscribe(id, path),
+        ("unsubscribe", path) => EVENTS.unsubscribe(id, path),
+        _ => Err(format!("unknown command '{}', expected subscribe <path> or unsubscribe <path>", command)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pushes_reach_subscribers_of_the_paths_they_show_up_at() {
+        let events = Events::new();
+        events.set_mounts(vec![
+            ("/storage".to_string(), "/".to_string()),
+            ("/data".to_string(), "/projects/a".to_string()),
+        ]);
+        let (id, _, lines) = events.connect();
+        assert!(events.subscribe(id, "/elsewhere").unwrap_err().contains("not on a mount"));
+        assert!(events.subscribe(id, "storage").is_err());
+        events.subscribe(id, "/storage/projects/").unwrap();
+        events.subscribe(id, "/data/b.txt").unwrap();
+
+        events.publish("/projects/a/b.txt", "modified");
+        events.publish("/other.txt", "created");
+        events.publish("/projects/ab", "removed");
+        assert_eq!(
+            lines.try_iter().collect::<Vec<_>>(),
+            [
+                r#"{"event":"modified","path":"/storage/projects/a/b.txt"}"#,
+                r#"{"event":"modified","path":"/data/b.txt"}"#,
+                r#"{"event":"removed","path":"/storage/projects/ab"}"#,
+            ]
+        );
+
+        events.unsubscribe(id, "/storage/projects").unwrap();
+        assert!(events.unsubscribe(id, "/storage/projects").is_err());
+        events.publish("/projects/a/b.txt", "modified");
+        events.resync();
+        assert_eq!(
+            lines.try_iter().collect::<Vec<_>>(),
+            [r#"{"event":"modified","path":"/data/b.txt"}"#, r#"{"event":"resync"}"#]
+        );
+
+        // A subscriber that stopped reading loses events, and hears it did
+        for _ in 0..QUEUE_LENGTH + 10 {
+            events.publish("/projects/a/b.txt", "modified");
+        }
+        assert_eq!(lines.try_iter().count(), QUEUE_LENGTH);
+        events.publish("/projects/a/b.txt", "created");
+        assert_eq!(
+            lines.try_iter().collect::<Vec<_>>(),
+            [r#"{"event":"overflow"}"#, r#"{"event":"created","path":"/data/b.txt"}"#]
+        );
+
+        drop(lines);
+        events.publish("/projects/a/b.txt", "modified");
+        assert!(events.subscribers.locked().is_empty());
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+
+use fuser::FileType;
+use serde_json::{json, Value};
+
+use crate::join;
+use crate::unsupported::SpecialNode;
+
+// The control file at the root of every mount. Like the snapshot directory it
+// is left out of the root's listing.
+pub const FSCTL_FILE: &str = ".fsctl";
+
+const COMMANDS: &str = "flush, invalidate <path>, stats or commit";
+
+// Only the daemon's user may control it
+pub const FSCTL_NODE: SpecialNode = SpecialNode {
+    kind: FileType::RegularFile,
+    perm: 0o600,
+    rdev: 0,
+};
+
+// What can be written to the control file, one command a line
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    // Sends every write the mount has buffered to the DO
+    Flush,
+    // Drops what the mount has cached for a path, given from the mount's root
+    Invalidate(String),
+    // Answers with the mount's status
+    Stats,
+    // Flushes, then sends what an overlay backend kept locally to the DO
+    Commit,
+}
+
+// Every command in `text`, or why one of them isn't
+pub fn parse(text: &str) -> Result<Vec<Command>, String> {
+    let mut commands = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let (command, argument) = match line.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, Some(argument.trim())),
+            None => (line, None),
+        };
+        commands.push(match (command, argument) {
+            ("flush", None) => Command::Flush,
+            ("stats", None) => Command::Stats,
+            ("commit", None) => Command::Commit,
+            ("invalidate", Some(path)) => Command::Invalidate(path.to_string()),
+            ("invalidate", None) => return Err("invalidate needs a path".to_string()),
+            ("flush" | "stats" | "commit", Some(_)) => return Err(format!("{} takes no argument", command)),
+            _ => return Err(format!("unknown command '{}', expected {}", command, COMMANDS)),
+        });
+    }
+    Ok(commands)
+}
+
+// The control file of one mount and what each of its open handles reads:
+// the mount's status as of the open, then the answer to the last command
+// written through the handle, shaped like the admin socket's
+pub struct ControlFile {
+    root: String,
+    path: String,
+    handles: HashMap<u64, Vec<u8>>,
+}
+
+impl ControlFile {
+    pub fn new(root: &str) -> Self {
+        Self {
+            root: root.to_string(),
+            path: join(root, FSCTL_FILE),
+            handles: HashMap::new(),
+        }
+    }
+
+    pub fn is(&self, path: &str) -> bool {
+        path == self.path
+    }
+
+    pub fn is_open(&self, fh: u64) -> bool {
+        self.handles.contains_key(&fh)
+    }
+
+    pub fn open(&mut self, fh: u64, status: Value) {
+        self.handles.insert(fh, render(&status));
+    }
+
+    pub fn answer(&mut self, fh: u64, result: &Result<Value, String>) {
+        let answer = match result {
+            Ok(result) => json!({ "ok": true, "result": result }),
+            Err(error) => json!({ "ok": false, "error": error }),
+        };
+        self.handles.insert(fh, render(&answer));
+    }
+
+    pub fn read(&self, fh: u64, offset: u64, size: u64) -> Option<&[u8]> {
+        let content = self.handles.get(&fh)?;
+        let start = (offset as usize).min(content.len());
+        let end = start.saturating_add(size as usize).min(content.len());
+        Some(&content[start..end])
+    }
+
+    pub fn release(&mut self, fh: u64) {
+        self.handles.remove(&fh);
+    }
+
+    // The path on the DO a command means, given from the mount's root
+    pub fn target(&self, path: &str) -> String {
+        match path.trim_matches('/') {
+            "" => self.root.clone(),
+            path => join(&self.root, path),
+        }
+    }
+}
+
+fn render(value: &Value) -> Vec<u8> {
+    let mut content = serde_json::to_vec_pretty(value).unwrap_or_default();
+    content.push(b'\n');
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_parse_one_a_line() {
+        assert_eq!(
+            parse("flush\n\n  invalidate /a dir/b.txt \nstats\ncommit\n").unwrap(),
+            [Command::Flush, Command::Invalidate("/a dir/b.txt".to_string()), Command::Stats, Command::Commit]
+        );
+        assert!(parse("invalidate").unwrap_err().contains("needs a path"));
+        assert!(parse("flush now").unwrap_err().contains("no argument"));
+        assert!(parse("stats\nreboot").unwrap_err().contains("unknown command 'reboot'"));
+
+        let control = ControlFile::new("/data");
+        assert!(control.is("/data/.fsctl"));
+        assert_eq!(control.target("a/b.txt"), "/data/a/b.txt");
+        assert_eq!(control.target("/"), "/data");
+    }
+}
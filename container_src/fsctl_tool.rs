@@ -0,0 +1,210 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::time::{Duration, UNIX_EPOCH};
+
+use clap::ArgMatches;
+
+use crate::error::DaemonError;
+use crate::operation::FsOperation;
+use crate::sync::{list, remote_files, send, PIECE_SIZE};
+use crate::{join, Encoding, FileStat, RemoteFSClient, Transport, DEFAULT_MAX_FRAME_SIZE, DEFAULT_MAX_IN_FLIGHT};
+
+// What `fsctl` was asked to do, on which paths in the DO
+enum Action {
+    Ls { path: String, long: bool },
+    Cat { paths: Vec<String> },
+    // From a local file, or stdin for `-`
+    Put { source: String, path: String },
+    Rm { paths: Vec<String> },
+    Stat { paths: Vec<String> },
+    Du { paths: Vec<String> },
+}
+
+pub struct FsctlOptions {
+    action: Action,
+    transport: Transport,
+}
+
+impl FsctlOptions {
+    pub fn parse(matches: &ArgMatches) -> Self {
+        let paths = |matches: &ArgMatches| matches.get_many::<String>("path").into_iter().flatten().cloned().collect();
+        let path = |matches: &ArgMatches| matches.get_one::<String>("path").cloned().unwrap_or_else(|| "/".to_string());
+        let action = match matches.subcommand() {
+            Some(("ls", matches)) => Action::Ls { path: path(matches), long: matches.get_flag("long") },
+            Some(("cat", matches)) => Action::Cat { paths: paths(matches) },
+            Some(("put", matches)) => Action::Put {
+                source: matches.get_one::<String>("source").cloned().expect("the source is required"),
+                path: path(matches),
+            },
+            Some(("rm", matches)) => Action::Rm { paths: paths(matches) },
+            Some(("stat", matches)) => Action::Stat { paths: paths(matches) },
+            Some(("du", matches)) => Action::Du { paths: paths(matches) },
+            _ => unreachable!("fsctl requires a command"),
+        };
+        Self { action, transport: Transport::parse(matches) }
+    }
+}
+
+// What is at a path in the DO: a file, or a directory, which is only the
+// prefix of the files in it
+enum Entry {
+    File(FileStat),
+    Dir,
+}
+
+// Runs the command against the DO, reporting each path that fails on stderr
+// as it goes. Returns whether every path succeeded.
+pub async fn run_fsctl(options: FsctlOptions) -> Result<bool, Box<dyn std::error::Error>> {
+    let client = RemoteFSClient::new(
+        Encoding::Json,
+        false,
+        1,
+        None,
+        DEFAULT_MAX_IN_FLIGHT,
+        DEFAULT_MAX_FRAME_SIZE,
+        &options.transport,
+    )?;
+    let mut out = io::stdout().lock();
+    let mut ok = true;
+    let mut report = |path: &str, result: Result<(), DaemonError>| {
+        if let Err(e) = result {
+            eprintln!("fsctl: {}: {}", path, io::Error::from_raw_os_error(e.errno()));
+            ok = false;
+        }
+    };
+    match &options.action {
+        Action::Ls { path, long } => report(path, ls(&client, path, *long, &mut out).await),
+        Action::Cat { paths } => {
+            for path in paths {
+                report(path, cat(&client, path, &mut out).await);
+            }
+        }
+        Action::Put { source, path } => {
+            let sent = match source.as_str() {
+                "-" => send(&client, io::stdin().lock(), path).await,
+                source => match File::open(source) {
+                    Ok(file) => send(&client, file, path).await,
+                    Err(e) => Err(e.into()),
+                },
+            };
+            report(path, sent.map(|_| ()));
+        }
+        Action::Rm { paths } => {
+            for path in paths {
+                let removed = match client.send_request(FsOperation::Unlink, path, None, None, None).await {
+                    Ok(response) if response.success => Ok(()),
+                    Ok(_) => Err(io::Error::from_raw_os_error(libc::ENOENT).into()),
+                    Err(e) => Err(e),
+                };
+                report(path, removed);
+            }
+        }
+        Action::Stat { paths } => {
+            for path in paths {
+                report(path, stat(&client, path, &mut out).await);
+            }
+        }
+        Action::Du { paths } => {
+            for path in paths {
+                report(path, du(&client, path, &mut out).await);
+            }
+        }
+    }
+    out.flush()?;
+    Ok(ok)
+}
+
+// A file, or a directory with anything in it. The root is always there.
+async fn entry(client: &RemoteFSClient, path: &str) -> Result<Entry, DaemonError> {
+    match client.send_request(FsOperation::Stat, path, None, None, None).await {
+        Ok(response) => match response.stat {
+            Some(stat) if stat.is_file => return Ok(Entry::File(stat)),
+            _ => return Ok(Entry::Dir),
+        },
+        Err(e) if e.errno() == libc::ENOENT => {}
+        Err(e) => return Err(e),
+    }
+    let listed = client.send_request(FsOperation::Readdir, path, None, Some(0), Some(1)).await;
+    match listed {
+        Ok(response) if path == "/" || !response.files.is_empty() => Ok(Entry::Dir),
+        Ok(_) => Err(io::Error::from_raw_os_error(libc::ENOENT).into()),
+        Err(e) => Err(e),
+    }
+}
+
+// The names in a directory, one a line and directories ending in a slash.
+// Long listings give each one's size and when it was last written, as ls -l.
+async fn ls(client: &RemoteFSClient, path: &str, long: bool, out: &mut impl Write) -> Result<(), DaemonError> {
+    if let Entry::File(stat) = entry(client, path).await? {
+        return Ok(line(out, &stat, path, long)?);
+    }
+    for name in list(client, path).await? {
+        let child = join(path, &name);
+        match entry(client, &child).await {
+            Ok(Entry::File(stat)) => line(out, &stat, &name, long)?,
+            Ok(Entry::Dir) if long => writeln!(out, "{:>12}  {:<20}  {}/", "-", "-", name)?,
+            Ok(Entry::Dir) => writeln!(out, "{}/", name)?,
+            // Gone since it was listed
+            Err(e) if e.errno() == libc::ENOENT => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn line(out: &mut impl Write, stat: &FileStat, name: &str, long: bool) -> io::Result<()> {
+    match long {
+        true => writeln!(out, "{:>12}  {:<20}  {}", stat.size, modified(stat), name),
+        false => writeln!(out, "{}", name),
+    }
+}
+
+fn modified(stat: &FileStat) -> String {
+    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_millis(stat.mtime)).to_string()
+}
+
+// Copies the file to `out` a piece at a time
+async fn cat(client: &RemoteFSClient, path: &str, out: &mut impl Write) -> Result<(), DaemonError> {
+    let Entry::File(stat) = entry(client, path).await? else {
+        return Err(io::Error::from_raw_os_error(libc::EISDIR).into());
+    };
+    let mut offset = 0;
+    while offset < stat.size {
+        let data = client.read_chunked(path, offset, PIECE_SIZE.min(stat.size - offset)).await?;
+        if data.is_empty() {
+            break;
+        }
+        out.write_all(&data)?;
+        offset += data.len() as u64;
+    }
+    Ok(())
+}
+
+async fn stat(client: &RemoteFSClient, path: &str, out: &mut impl Write) -> Result<(), DaemonError> {
+    writeln!(out, "    Path: {}", path)?;
+    match entry(client, path).await? {
+        Entry::File(stat) => {
+            writeln!(out, "    Type: file")?;
+            writeln!(out, "    Size: {}", stat.size)?;
+            writeln!(out, "Modified: {}", modified(&stat))?;
+            if let Some(version) = stat.version {
+                writeln!(out, " Version: {}", version)?;
+            }
+        }
+        Entry::Dir => writeln!(out, "    Type: directory")?,
+    }
+    Ok(())
+}
+
+// The bytes in the files under `path`, and how many there are, as du -sb
+async fn du(client: &RemoteFSClient, path: &str, out: &mut impl Write) -> Result<(), DaemonError> {
+    let (bytes, files) = match entry(client, path).await? {
+        Entry::File(stat) => (stat.size, 1),
+        Entry::Dir => {
+            let files = remote_files(client, path).await?;
+            (files.values().map(|stat| stat.size).sum(), files.len())
+        }
+    };
+    writeln!(out, "{}\t{}\t{}", bytes, files, path)?;
+    Ok(())
+}
@@ -1,22 +1,282 @@
-use std::collections::HashMap;
+mod admin;
+mod audit;
+mod backend;
+mod cache;
+mod cli;
+mod dedup;
+mod encryption;
+mod endpoints;
+mod error;
+mod events;
+mod fsctl;
+mod fsctl_tool;
+mod health;
+mod hooks;
+mod http;
+mod interrupt;
+mod journal;
+mod kernel_notify;
+mod lifecycle;
+mod lease;
+mod logging;
+mod metrics;
+mod names;
+mod namespace;
+mod nfs;
+mod ninep;
+mod operation;
+mod owner;
+mod overlay;
+mod quota;
+mod s3;
+mod sftp;
+mod snapshots;
+mod sparse;
+mod spill;
+mod ssh;
+mod supervisor;
+mod sync;
+mod tier;
+mod trace;
+mod transport;
+mod unsupported;
+mod vsock;
+mod webdav;
+mod writeback;
+
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
-use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::sync::{Arc, Mutex};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use clap::error::ErrorKind;
+use clap::ArgMatches;
+use futures::stream::{self, StreamExt};
 use fuser::{
-    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
-    ReplyOpen, ReplyWrite, ReplyCreate, Request,
+    consts, FileAttr, FileType, Filesystem, KernelConfig, MountOption, ReplyAttr, ReplyData, ReplyDirectory,
+    ReplyEntry, ReplyEmpty, ReplyLock, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, ReplyCreate, Request, TimeOrNow,
 };
 use serde::{Deserialize, Serialize};
-use tokio::sync::oneshot;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot, Semaphore, SemaphorePermit};
+use tracing::{debug, error, info, warn, Instrument};
 
-#[derive(Serialize)]
+use audit::{AuditLog, AuditOp, AuditOptions, Caller, DEFAULT_AUDIT_MAX_SIZE};
+use backend::Backend;
+use cache::{content_key, shared_block_cache, BlockCache, DirCache, BLOCK_SIZE};
+use error::{DaemonError, Locked};
+use encryption::{plain_size, Cipher, Key, CHUNK_SIZE, SEALED_CHUNK_SIZE};
+use endpoints::Endpoints;
+use events::EVENTS;
+use fsctl::{Command, ControlFile, FSCTL_NODE};
+use fsctl_tool::{run_fsctl, FsctlOptions};
+use health::HEALTH;
+use journal::{Journal, JournalEntry};
+use kernel_notify::{InodeIndex, Notice};
+use lease::{LeaseMode, Leases};
+use logging::LogFormat;
+use metrics::{ReadSource, METRICS};
+use namespace::{Graft, NamespaceSpec, Namespaces};
+use overlay::RemoteStore;
+use operation::{Field, FsOperation, OperationClass};
+use quota::{Quota, Quotas, Space};
+use snapshots::{SnapshotEntry, SnapshotPath, Snapshots};
+use spill::SpillDir;
+use ssh::HostKey;
+use supervisor::{Remount, Supervisor};
+use sync::{run_sync, SyncOptions};
+use trace::{Correlation, TraceContext};
+use transport::{Address, Conn, Listener, Tls, TlsFiles};
+use names::NamePolicy;
+use owner::{Owner, OwnerEntry, Ownership};
+use unsupported::{special_kind, Policy, SpecialNode, UnsupportedPolicy, XattrStore};
+use writeback::{WriteBack, WRITEBACK_MEMORY_LIMIT};
+
+// Address the DO connects to, unless --listen or --connect says otherwise
+const LISTEN_ADDRESS: &str = "10.0.0.1:8000";
+
+// Wait before redialing the DO after a failed attempt, doubling on each
+// further failure up to the maximum
+const DIAL_BACKOFF_INITIAL: Duration = Duration::from_millis(100);
+const DIAL_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+// Frames that may be queued for the writer thread before senders wait
+const SEND_QUEUE_DEPTH: usize = 64;
+
+// Largest read or write payload carried in a single request. The kernel is
+// never told a larger limit at mount time, so it never issues a larger operation.
+const MAX_IO_SIZE: usize = 128 * 1024;
+// Least --max-read, a page
+const MIN_MAX_READ: usize = 4096;
+
+// Legacy frames encode each payload byte as up to four JSON characters, plus the envelope
+const FRAME_BUFFER_SIZE: usize = MAX_IO_SIZE * 4 + 1024;
+
+// First byte of a binary frame: a u32 header length, the header, then the raw payload.
+// Legacy frames are plain JSON and so always start with '{'.
+const BINARY_FRAME_TAG: u8 = 1;
+const MSGPACK_FRAME_TAG: u8 = 2;
+// First byte of a batch frame, followed by complete length-prefixed frames
+const BATCH_FRAME_TAG: u8 = 3;
+// First byte of a fragment frame, followed by a flag set on the last fragment
+// and a slice of the body of a frame too long to send whole
+const FRAGMENT_FRAME_TAG: u8 = 4;
+
+// Longest frame body accepted from the DO by default, and the least that can
+// be configured, which still fits a transfer chunk and its header. A longer
+// length prefix closes the connection.
+const DEFAULT_MAX_FRAME_SIZE: usize = 1024 * 1024;
+const MIN_MAX_FRAME_SIZE: usize = 64 * 1024;
+// Longest message reassembled from fragments
+const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+// Payloads from this size up are LZ4-compressed when the DO agreed to it
+const COMPRESSION_THRESHOLD: usize = 4096;
+
+// Queued frames up to this size are sent together in one batch frame
+const BATCH_FRAME_LIMIT: usize = 4096;
+const BATCH_MAX_MESSAGES: usize = 64;
+
+// Wire protocol versions this daemon speaks; the DO picks the highest it shares
+const PROTOCOL_VERSIONS: &[u32] = &[1];
+
+// How long to wait for the DO to answer the hello
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Connections accepted from the DO: the first carries metadata, the rest bulk reads and writes
+const DEFAULT_CONNECTIONS: usize = 3;
+
+// Requests awaiting a response before new ones wait their turn
+const DEFAULT_MAX_IN_FLIGHT: usize = 256;
+
+// Idle connections are pinged this often, and closed after this long without
+// hearing anything back
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Contiguous writes on a handle are merged until they reach this size
+const WRITE_COALESCE_LIMIT: usize = MAX_IO_SIZE;
+
+// Large reads and flushed writes go out in chunks of this size, writes verified by checksum
+const DEFAULT_TRANSFER_CHUNK_SIZE: usize = 32 * 1024;
+const MIN_TRANSFER_CHUNK_SIZE: usize = 4 * 1024;
+
+// Room a frame keeps next to a chunk of file data for the message header
+const FRAME_HEADER_ROOM: usize = 4 * 1024;
+
+// Chunk requests of a single transfer kept in flight at once
+const DEFAULT_TRANSFER_PARALLELISM: usize = 4;
+
+// Chunks of a streamed write sent ahead of the DO's acknowledgements
+const STREAM_WRITE_WINDOW: usize = 8;
+
+// Times a request is resent after its response was lost or the DO failed it
+// for a passing reason, and how long it waits before each: the backoff doubles
+// up to the most, less a random share of up to the jitter. Mutations are only
+// resent when the DO dedupes them.
+const DEFAULT_RETRY_ATTEMPTS: usize = 2;
+const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(5);
+const DEFAULT_RETRY_JITTER: f64 = 0.5;
+
+// How long a request waits for the DO to reconnect when no connection is up,
+// checking every RECONNECT_POLL, before it fails; and how many may wait
+const DEFAULT_DISCONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_DISCONNECT_QUEUE: usize = 1024;
+const RECONNECT_POLL: Duration = Duration::from_millis(100);
+
+// How long a request waits for its response once sent, by operation class
+const DEFAULT_METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_SYNC_TIMEOUT: Duration = Duration::from_secs(120);
+
+// A request still unanswered this long after it was sent is reported as
+// stuck, well before it times out
+const DEFAULT_STUCK_THRESHOLD: Duration = Duration::from_secs(5);
+
+// How long a signalled daemon has to flush, unmount and say goodbye before it
+// exits anyway
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How often pending requests nobody waits on any more are removed
+const PENDING_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+// Bytes prefetched past a sequential read
+const DEFAULT_READAHEAD_WINDOW: usize = 256 * 1024;
+
+// Total memory all handles' readahead buffers may hold
+const DEFAULT_READAHEAD_MEMORY: usize = 16 * 1024 * 1024;
+
+// Memory the block cache may use for file data
+const DEFAULT_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+// Disk the spill directory may use for evicted blocks and spilled write-back data
+const DEFAULT_SPILL_SIZE: u64 = 1024 * 1024 * 1024;
+
+// Inode numbers kept for paths the kernel holds no reference to, before evicting them
+const MAX_INODES: usize = 100_000;
+
+// Directory entries requested from the DO per readdir round trip
+const READDIR_PAGE_SIZE: u64 = 256;
+
+// How long a directory listing is reused before the DO is asked again
+const DEFAULT_DIR_CACHE_TTL: Duration = Duration::from_secs(5);
+
+// Directory listings kept in memory at once
+const DIR_CACHE_ENTRIES: usize = 256;
+
+// Largest file the DO may inline in a stat response (the DO may apply a lower cap)
+const INLINE_LIMIT: u64 = 4096;
+
+// Inlined files kept in memory at once
+const INLINE_CACHE_ENTRIES: usize = 1024;
+
+// Idle buffers kept around for reuse
+const POOL_BUFFERS: usize = 32;
+
+// Recycles frame and payload buffers so steady-state I/O doesn't hit the allocator
+#[derive(Clone)]
+struct BufferPool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl BufferPool {
+    fn new() -> Self {
+        Self {
+            buffers: Arc::new(Mutex::new(Vec::with_capacity(POOL_BUFFERS))),
+        }
+    }
+
+    fn take(&self) -> Vec<u8> {
+        self.buffers
+            .locked()
+            .pop()
+            .unwrap_or_else(|| Vec::with_capacity(FRAME_BUFFER_SIZE))
+    }
+
+    fn give(&self, mut buffer: Vec<u8>) {
+        // Oversized one-off buffers are dropped rather than pinned in memory
+        if buffer.capacity() > FRAME_BUFFER_SIZE * 2 {
+            return;
+        }
+        let mut buffers = self.buffers.locked();
+        if buffers.len() < POOL_BUFFERS {
+            buffer.clear();
+            buffers.push(buffer);
+        }
+    }
+}
+
+#[derive(Serialize, Default, Clone)]
 struct FSMessage {
     id: u64,
-    operation: String,
+    operation: FsOperation,
     path: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     data: Option<Vec<u8>>,
@@ -24,6 +284,118 @@ struct FSMessage {
     offset: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    restore: Option<RestoreRequest>,
+    #[serde(rename = "inlineLimit", skip_serializing_if = "Option::is_none")]
+    inline_limit: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checksum: Option<u32>,
+    // For lease: the lease wanted, or release
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lease: Option<LeaseMode>,
+    // For hello: what the daemon can speak
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hello: Option<HelloRequest>,
+    // For read: answer with a sequence of chunk frames rather than one frame
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream: Option<bool>,
+    // For write-chunk, write-end and write-abort: the streamed write they belong to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transfer: Option<u64>,
+    // For mutations: the same on every retry, so the DO applies them once
+    #[serde(rename = "idempotencyKey", skip_serializing_if = "Option::is_none")]
+    idempotency_key: Option<String>,
+    // For cancel: the request the daemon no longer wants answered
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cancel: Option<u64>,
+    // Codec of a compressed payload and its size before compression
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<&'static str>,
+    #[serde(rename = "rawSize", skip_serializing_if = "Option::is_none")]
+    raw_size: Option<u64>,
+    // W3C trace context of the daemon's span for the request, for DOs that
+    // agreed to `trace-context`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    traceparent: Option<String>,
+    // For write and write-end: the file's write sequence number, for DOs that
+    // agreed to `sync`. For sync: the highest acknowledged write to cover.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    // For snapshots and snapshot-read: when the snapshot was taken, in ms
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snapshot: Option<u64>,
+    // For has-chunks and write-chunks: the hashes of a write's chunks, in
+    // order. For put-chunk: the hash of the one sent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunks: Option<Vec<String>>,
+    // For write and chmod: the file's permission bits, for DOs that agreed
+    // to `modes`. A write without one leaves them as they are.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mode: Option<u32>,
+    // The correlation id of the FUSE operation the request is part of, for
+    // DOs that agreed to `correlation`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    correlation: Option<String>,
+}
+
+impl FSMessage {
+    // The first field the operation needs that the message leaves out
+    fn missing_field(&self) -> Option<Field> {
+        let present = |field: &Field| match field {
+            Field::Path => !self.path.is_empty(),
+            Field::Data => self.data.is_some(),
+            Field::Offset => self.offset.is_some(),
+            Field::Size => self.size.is_some(),
+            Field::Transfer => self.transfer.is_some(),
+            Field::Checksum => self.checksum.is_some(),
+            Field::Lease => self.lease.is_some(),
+            Field::Hello => self.hello.is_some(),
+            Field::Restore => self.restore.is_some(),
+            Field::Cancel => self.cancel.is_some(),
+            Field::Seq => self.seq.is_some(),
+            Field::Snapshot => self.snapshot.is_some(),
+            Field::Chunks => self.chunks.is_some(),
+            Field::Mode => self.mode.is_some(),
+        };
+        self.operation.required_fields().iter().find(|field| !present(field)).copied()
+    }
+}
+
+#[derive(Serialize, Clone)]
+struct HelloRequest {
+    versions: Vec<u32>,
+    features: Vec<&'static str>,
+    // Same on every connection of this daemon, so the DO treats them as one client
+    session: String,
+    // Position in the pool; 0 is the connection that gets pushes
+    channel: usize,
+    // Longest frame body the daemon accepts, when offering `fragments`
+    #[serde(rename = "maxFrame")]
+    max_frame: usize,
+}
+
+// What the DO chose from the hello
+#[derive(Deserialize)]
+struct HelloResponse {
+    // Absent when the DO shares no version with the daemon
+    version: Option<u32>,
+    // The versions the DO speaks, for the error message when none is shared
+    #[serde(default)]
+    versions: Vec<u32>,
+    // The offered features the DO agreed to
+    #[serde(default)]
+    features: Vec<String>,
+    // Longest frame body the DO accepts, when it agreed to `fragments`
+    #[serde(rename = "maxFrame")]
+    max_frame: Option<usize>,
+    // Names the DO, so connections from several can be told apart
+    instance: Option<String>,
+}
+
+#[derive(Serialize, Clone)]
+struct RestoreRequest {
+    source: String,
+    at: u64,
 }
 
 #[derive(Deserialize)]
@@ -36,352 +408,5556 @@ struct FSResponse {
     #[serde(default)]
     files: Vec<String>,
     stat: Option<FileStat>,
+    inline: Option<Vec<u8>>,
+    checksum: Option<u32>,
     #[serde(default)]
     success: bool,
+    restore: Option<RestoreProgress>,
+    // For lease: the lease granted, absent when refused
+    lease: Option<LeaseMode>,
+    hello: Option<HelloResponse>,
+    // In binary frames, which field the payload fills: "data" (the default) or "inline"
+    payload: Option<String>,
+    // Codec of a compressed payload and its size before compression
+    compression: Option<String>,
+    #[serde(rename = "rawSize")]
+    raw_size: Option<u64>,
+    // Set on every chunk of a streamed read but the last
+    #[serde(default)]
+    more: bool,
+    // For write-begin: the id the rest of the streamed write refers to
+    transfer: Option<u64>,
+    // Set on frames the DO pushes unprompted rather than in answer to a request
+    invalidate: Option<Invalidation>,
+    recall: Option<LeaseRecall>,
+    // For snapshots: when each snapshot was taken, or the entries of the
+    // directory listed in one
+    #[serde(default)]
+    snapshots: Vec<u64>,
+    #[serde(default)]
+    entries: Vec<SnapshotEntry>,
+    // For has-chunks: the chunks asked about that the DO doesn't have
+    #[serde(default)]
+    chunks: Vec<String>,
+    // For read, from DOs that agreed to `sparse`: the all-zero runs left out
+    // of `data`, as file offsets and lengths
+    #[serde(default)]
+    holes: Vec<[u64; 2]>,
     #[serde(default)]
     error: String,
+    // The errno the DO chose for `error`, absent from DOs that predate it
+    errno: Option<i32>,
+    // With `timing`: when the DO took the request off the wire and when it
+    // answered, on its own clock
+    timing: Option<Timing>,
+    // The request's correlation id, echoed by DOs that agreed to `correlation`
+    correlation: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Timing {
+    received: u64,
+    sent: u64,
+}
+
+impl Timing {
+    // Time the request spent in the DO, which its clock can tell apart from
+    // the trip there and back
+    fn in_do(&self) -> Duration {
+        Duration::from_millis(self.sent.saturating_sub(self.received))
+    }
+}
+
+// Where one attempt at a request spent its time: waiting in the daemon for an
+// in-flight slot and a connection, on the round trip, and of that in the DO
+// when it said
+struct Latency {
+    queue: Duration,
+    round_trip: Duration,
+    in_do: Option<Duration>,
+}
+
+impl Latency {
+    // Adds it to the histograms, and logs it with its context when it took
+    // longer than --slow-threshold
+    fn record(&self, operation: FsOperation, path: &str, sent: usize, received: usize) {
+        METRICS.latency(operation, self.queue, self.round_trip, self.in_do);
+        let total = self.queue + self.round_trip;
+        if logging::is_slow(total) {
+            let network = self.round_trip.saturating_sub(self.in_do.unwrap_or_default());
+            warn!(
+                op = %operation,
+                path,
+                sent,
+                received,
+                ?total,
+                queue = ?self.queue,
+                ?network,
+                in_do = ?self.in_do,
+                "Slow request"
+            );
+        }
+    }
+}
+
+// Demand from the DO to flush and release the lease on a path
+#[derive(Deserialize)]
+struct LeaseRecall {
+    path: String,
+}
+
+// Notice from the DO that a path was changed through another connection
+#[derive(Deserialize)]
+struct Invalidation {
+    path: String,
+    // "modified" for content changes; "created" and "removed" also change the parent listing
+    change: String,
 }
 
 #[derive(Deserialize)]
+struct RestoreProgress {
+    processed: u64,
+    total: u64,
+    done: bool,
+}
+
+#[derive(Deserialize, Clone)]
 struct FileStat {
     size: u64,
     #[serde(rename = "isFile")]
     is_file: bool,
-    #[serde(rename = "isDir")]
-    is_dir: bool,
     mtime: u64,
+    // Bumped by the DO on every change; absent from DOs that don't track it
+    version: Option<u64>,
+    // Bytes the DO stores for the file, less than its size when it has holes;
+    // from DOs that agreed to `sparse`
+    allocated: Option<u64>,
+    // Permission bits set on create or chmod, from DOs that agreed to `modes`
+    mode: Option<u32>,
 }
 
-struct RemoteFSClient {
-    stream: Arc<Mutex<TcpStream>>,
-    request_id: Arc<Mutex<u64>>,
-    pending_requests: Arc<Mutex<HashMap<u64, oneshot::Sender<FSResponse>>>>,
+// How message headers are put on the wire
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Encoding {
+    // Plain JSON frames with file data as byte arrays, for DOs that predate binary frames
+    Legacy,
+    // Binary frames with a JSON header
+    Json,
+    // Binary frames with a MessagePack header
+    MessagePack,
 }
 
-impl RemoteFSClient {
-    fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        // Listen for incoming connection from DO
-        let listener = std::net::TcpListener::bind("10.0.0.1:8000")?;
-        println!("Filesystem daemon listening on 10.0.0.1:8000");
-        
-        let (stream, _) = listener.accept()?;
-        println!("Filesystem daemon connected to DO");
-        let stream = Arc::new(Mutex::new(stream));
-        let request_id = Arc::new(Mutex::new(0));
-        let pending_requests = Arc::new(Mutex::new(HashMap::new()));
+impl Encoding {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "legacy" => Ok(Encoding::Legacy),
+            "json" => Ok(Encoding::Json),
+            "msgpack" => Ok(Encoding::MessagePack),
+            _ => Err("expected legacy, json or msgpack".to_string()),
+        }
+    }
+}
 
-        // Start reader thread
-        let stream_clone = stream.clone();
-        let pending_clone = pending_requests.clone();
-        thread::spawn(move || {
-            Self::reader_loop(stream_clone, pending_clone);
-        });
+// One span per request to the DO, carrying what it asks for, and the trace
+// it starts when tracing across the DO is on. The id is recorded once the
+// request is queued, and again if it is resent.
+fn request_span(operation: FsOperation, path: &str, size: Option<u64>) -> (tracing::Span, Option<TraceContext>) {
+    let trace = TraceContext::start();
+    let trace_id = trace.as_ref().map(TraceContext::trace_id);
+    let span = tracing::debug_span!("request", op = %operation, path, size, id = tracing::field::Empty, trace_id);
+    (span, trace)
+}
 
-        Ok(Self {
-            stream,
-            request_id,
-            pending_requests,
-        })
+fn log_finished<T>(
+    operation: FsOperation,
+    span: &tracing::Span,
+    started: Instant,
+    result: &Result<T, DaemonError>,
+) {
+    METRICS.request(operation, result.as_ref().err().map(|e| e.errno()));
+    if result.is_ok() {
+        HEALTH.succeeded();
     }
+    let duration = started.elapsed();
+    span.in_scope(|| match result {
+        Ok(_) => debug!(?duration, "request finished"),
+        Err(e) => debug!(?duration, error = %e, "request failed"),
+    });
+}
 
-    fn reader_loop(
-        stream: Arc<Mutex<TcpStream>>,
-        pending: Arc<Mutex<HashMap<u64, oneshot::Sender<FSResponse>>>>,
-    ) {
-        loop {
-            let mut length_buf = [0u8; 4];
-            let mut stream = stream.lock().unwrap();
-            
-            if stream.read_exact(&mut length_buf).is_err() {
-                break;
-            }
-            
-            let message_length = u32::from_le_bytes(length_buf) as usize;
-            let mut message_buf = vec![0u8; message_length];
-            
-            if stream.read_exact(&mut message_buf).is_err() {
-                break;
-            }
-            
-            drop(stream);
+// Where the reader thread delivers the responses to a request
+enum Pending {
+    Single(oneshot::Sender<FSResponse>),
+    // Every chunk frame of a streamed read, up to the one without `more`
+    Stream(mpsc::UnboundedSender<FSResponse>),
+}
 
-            if let Ok(response) = serde_json::from_slice::<FSResponse>(&message_buf) {
-                let mut pending = pending.lock().unwrap();
-                if let Some(sender) = pending.remove(&response.id) {
-                    let _ = sender.send(response);
-                }
-            }
-        }
+// What a request awaiting a response is, for the admin socket to list
+#[derive(Clone)]
+struct Outstanding {
+    operation: FsOperation,
+    path: String,
+    connection: u64,
+    sent: Instant,
+    // The FUSE operation it is part of, if any
+    correlation: Option<String>,
+    // Already reported by the watchdog
+    stuck: bool,
+}
+
+// How the daemon and the DO find each other
+#[derive(Clone, Debug)]
+enum Endpoint {
+    // Wait for the DO to connect to this address
+    Listen(Address),
+    // Connect out to the DO at the first of these addresses that answers
+    Dial(Vec<Address>),
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Endpoint::Listen(Address::Tcp(LISTEN_ADDRESS.to_string()))
     }
+}
 
-    async fn send_request(
-        &self,
-        operation: &str,
-        path: &str,
-        data: Option<Vec<u8>>,
-        offset: Option<u64>,
-        size: Option<u64>,
-    ) -> Result<FSResponse, Box<dyn std::error::Error>> {
-        let (tx, rx) = oneshot::channel();
-        
-        let id = {
-            let mut request_id = self.request_id.lock().unwrap();
-            *request_id += 1;
-            *request_id
-        };
+// Where the DO is, the certificates to authenticate it with when the
+// connection runs over TLS, and how requests are spread when several DOs
+// connect to a listening daemon
+#[derive(Clone, Debug)]
+struct Transport {
+    endpoint: Endpoint,
+    tls: Option<TlsFiles>,
+    clients: usize,
+    routing: Routing,
+    // Mount without waiting for the DO's first connection
+    lazy: bool,
+    disconnect: Disconnect,
+    timeouts: Timeouts,
+    retry: Retry,
+    // How large transfers are split, and how many chunks are in flight at once
+    chunk_size: usize,
+    parallelism: usize,
+    // What file contents are encrypted with before they go to the DO, if anything
+    encryption: Option<Key>,
+    // Send writes as content-defined chunks, skipping those the DO has
+    dedup: bool,
+}
 
-        {
-            let mut pending = self.pending_requests.lock().unwrap();
-            pending.insert(id, tx);
+impl Default for Transport {
+    fn default() -> Self {
+        Self {
+            endpoint: Endpoint::default(),
+            tls: None,
+            clients: 1,
+            routing: Routing::default(),
+            lazy: false,
+            disconnect: Disconnect::default(),
+            timeouts: Timeouts::default(),
+            retry: Retry::default(),
+            chunk_size: DEFAULT_TRANSFER_CHUNK_SIZE,
+            parallelism: DEFAULT_TRANSFER_PARALLELISM,
+            encryption: None,
+            dedup: false,
         }
+    }
+}
 
-        let message = FSMessage {
-            id,
-            operation: operation.to_string(),
-            path: path.to_string(),
-            data,
-            offset,
-            size,
+impl Transport {
+    // The options `restore` shares with the mount: where the DO is, TLS, the
+    // encryption key and how long to wait for it. The rest keep their defaults.
+    fn parse(matches: &ArgMatches) -> Self {
+        let string = |id: &str| matches.get_one::<String>(id).cloned();
+        let duration = |id: &str, default: Duration| matches.get_one(id).copied().unwrap_or(default);
+        let endpoint = match (matches.get_one::<Vec<Address>>("connect"), matches.get_one::<Address>("listen")) {
+            (Some(addresses), _) => Endpoint::Dial(addresses.clone()),
+            (None, Some(address)) => Endpoint::Listen(address.clone()),
+            (None, None) => Endpoint::default(),
         };
+        // --tls-cert requires the key and CA, and they require it
+        let tls = match (string("tls-cert"), string("tls-key"), string("tls-ca")) {
+            (Some(cert), Some(key), Some(ca)) => Some(TlsFiles {
+                cert,
+                key,
+                ca,
+                server_name: string("tls-server-name"),
+            }),
+            _ => None,
+        };
+        Self {
+            endpoint,
+            tls,
+            disconnect: Disconnect {
+                hard: string("disconnect").is_some_and(|mode| mode == "hard"),
+                timeout: duration("disconnect-timeout", DEFAULT_DISCONNECT_TIMEOUT),
+                errno: matches.get_one("disconnect-errno").copied().unwrap_or(libc::EIO),
+                queue: matches.get_one("disconnect-queue").copied().unwrap_or(DEFAULT_DISCONNECT_QUEUE),
+            },
+            timeouts: Timeouts::parse(matches),
+            retry: Retry::parse(matches),
+            encryption: matches.get_one::<Key>("encryption-key").or(matches.get_one("encryption-key-file")).cloned(),
+            dedup: matches.get_flag("dedup"),
+            ..Self::default()
+        }
+    }
+}
 
-        let message_data = serde_json::to_vec(&message)?;
-        let length_prefix = (message_data.len() as u32).to_le_bytes();
+// Which connected DOs requests go to
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+enum Routing {
+    // Everything goes to one DO; the rest only push invalidations
+    #[default]
+    Primary,
+    // Stats, reads and listings take turns across every DO
+    Share,
+}
 
-        {
-            let mut stream = self.stream.lock().unwrap();
-            stream.write_all(&length_prefix)?;
-            stream.write_all(&message_data)?;
+impl Routing {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "primary" => Ok(Routing::Primary),
+            "share" => Ok(Routing::Share),
+            _ => Err("expected primary or share".to_string()),
         }
+    }
 
-        match tokio::time::timeout(Duration::from_secs(30), rx).await {
-            Ok(Ok(response)) => {
-                if !response.error.is_empty() {
-                    return Err(response.error.into());
-                }
-                Ok(response)
-            }
-            Ok(Err(_)) => Err("Channel error".into()),
-            Err(_) => Err("Request timeout".into()),
+    fn as_str(self) -> &'static str {
+        match self {
+            Routing::Primary => "primary",
+            Routing::Share => "share",
         }
     }
 }
 
-struct RemoteFS {
-    client: RemoteFSClient,
-    next_fh: Arc<Mutex<u64>>,
+// Picks which of several DO addresses to dial. Every connection goes to the
+// current one until it fails to connect or to handshake, and then the next in
+// turn becomes current; a full round of failures waits out a backoff.
+struct Dialer {
+    addresses: Vec<Address>,
+    current: AtomicUsize,
+    backoff: Mutex<Duration>,
 }
 
-impl RemoteFS {
-    fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        let client = RemoteFSClient::new()?;
-        Ok(Self {
-            client,
-            next_fh: Arc::new(Mutex::new(1)),
-        })
+impl Dialer {
+    fn new(addresses: Vec<Address>) -> Self {
+        Self {
+            addresses,
+            current: AtomicUsize::new(0),
+            backoff: Mutex::new(DIAL_BACKOFF_INITIAL),
+        }
     }
 
-    fn get_attr_from_stat(&self, stat: &FileStat) -> FileAttr {
-        FileAttr {
-            ino: 1,
-            size: stat.size,
-            blocks: (stat.size + 511) / 512,
-            atime: UNIX_EPOCH + Duration::from_millis(stat.mtime),
-            mtime: UNIX_EPOCH + Duration::from_millis(stat.mtime),
-            ctime: UNIX_EPOCH + Duration::from_millis(stat.mtime),
-            crtime: UNIX_EPOCH + Duration::from_millis(stat.mtime),
-            kind: if stat.is_file { FileType::RegularFile } else { FileType::Directory },
-            perm: if stat.is_file { 0o644 } else { 0o755 },
-            nlink: 1,
-            uid: unsafe { libc::getuid() },
-            gid: unsafe { libc::getgid() },
-            rdev: 0,
-            flags: 0,
-            blksize: 4096,
-        }
+    fn current_address(&self) -> &Address {
+        &self.addresses[self.current.load(Ordering::SeqCst)]
     }
-}
 
-impl Filesystem for RemoteFS {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let path = format!("/{}", name.to_string_lossy());
-        
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        match rt.block_on(self.client.send_request("stat", &path, None, None, None)) {
-            Ok(response) => {
-                if let Some(stat) = response.stat {
-                    let attr = self.get_attr_from_stat(&stat);
-                    reply.entry(&Duration::from_secs(1), &attr, 0);
-                } else {
-                    reply.error(libc::ENOENT);
+    // Connects to the current address, failing over for as long as it takes,
+    // and returns which address answered
+    fn dial(&self) -> (usize, Conn) {
+        loop {
+            let index = self.current.load(Ordering::SeqCst);
+            match transport::connect(&self.addresses[index]) {
+                Ok(stream) => return (index, stream),
+                Err(e) => {
+                    warn!("Failed to connect to DO at {}: {}", self.addresses[index], e);
+                    self.failed(index);
                 }
             }
-            Err(_) => reply.error(libc::ENOENT),
         }
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        let path = if ino == 1 { "/" } else { "/unknown" };
-        
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        match rt.block_on(self.client.send_request("stat", path, None, None, None)) {
-            Ok(response) => {
-                if let Some(stat) = response.stat {
-                    let attr = self.get_attr_from_stat(&stat);
-                    reply.attr(&Duration::from_secs(1), &attr);
-                } else {
-                    reply.error(libc::ENOENT);
-                }
-            }
-            Err(_) => reply.error(libc::ENOENT),
+    // Moves on from an address that failed, unless another thread already did
+    fn failed(&self, index: usize) {
+        let next = (index + 1) % self.addresses.len();
+        if self.current.compare_exchange(index, next, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return;
+        }
+        if next != index {
+            warn!("Failing over from DO at {} to {}", self.addresses[index], self.addresses[next]);
+        }
+        if next == 0 {
+            let mut backoff = self.backoff.locked();
+            warn!("No DO address answered, retrying in {:?}", *backoff);
+            thread::sleep(*backoff);
+            *backoff = (*backoff * 2).min(DIAL_BACKOFF_MAX);
         }
     }
 
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock: Option<u64>,
-        reply: ReplyData,
-    ) {
-        let path = "/"; // Would need to track path by inode
-        
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        match rt.block_on(self.client.send_request(
-            "read",
-            path,
-            None,
-            Some(offset as u64),
-            Some(size as u64),
-        )) {
-            Ok(response) => reply.data(&response.data),
-            Err(_) => reply.error(libc::EIO),
-        }
+    fn connected(&self) {
+        *self.backoff.locked() = DIAL_BACKOFF_INITIAL;
     }
+}
 
-    fn write(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        data: &[u8],
-        _write_flags: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyWrite,
-    ) {
-        let path = "/"; // Would need to track path by inode
-        
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        match rt.block_on(self.client.send_request(
-            "write",
-            path,
-            Some(data.to_vec()),
-            Some(offset as u64),
-            None,
-        )) {
-            Ok(response) => reply.written(response.bytes_written as u32),
-            Err(_) => reply.error(libc::EIO),
+// What operations do while no connection to the DO is up
+#[derive(Clone, Copy, Debug)]
+struct Disconnect {
+    // Hard waits for the DO however long it takes, like a hard NFS mount
+    hard: bool,
+    // How long a soft request waits for a connection before failing
+    timeout: Duration,
+    // What requests the DO couldn't be reached for fail with
+    errno: i32,
+    // Requests that may wait for a connection at once; more fail straight away
+    queue: usize,
+}
+
+impl Default for Disconnect {
+    fn default() -> Self {
+        Self {
+            hard: false,
+            timeout: DEFAULT_DISCONNECT_TIMEOUT,
+            errno: libc::EIO,
+            queue: DEFAULT_DISCONNECT_QUEUE,
         }
     }
+}
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        let path = if ino == 1 { "/" } else { "/unknown" };
-        
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        match rt.block_on(self.client.send_request("readdir", path, None, None, None)) {
-            Ok(response) => {
-                for (i, file) in response.files.iter().enumerate() {
-                    if i as i64 >= offset {
-                        reply.add(i as u64 + 2, (i + 1) as i64, FileType::RegularFile, file);
-                    }
-                }
-                reply.ok();
-            }
-            Err(_) => reply.error(libc::EIO),
+impl Disconnect {
+    fn parse_errno(value: &str) -> Result<i32, String> {
+        match value {
+            "eio" => Ok(libc::EIO),
+            "eagain" => Ok(libc::EAGAIN),
+            "etimedout" => Ok(libc::ETIMEDOUT),
+            "enotconn" => Ok(libc::ENOTCONN),
+            "ehostdown" => Ok(libc::EHOSTDOWN),
+            _ => Err("expected eio, eagain, etimedout, enotconn or ehostdown".to_string()),
         }
     }
+}
 
-    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
-        let fh = {
-            let mut next_fh = self.next_fh.lock().unwrap();
-            *next_fh += 1;
-            *next_fh
-        };
-        reply.opened(fh, 0);
+// Holds a place among the requests waiting for the DO to reconnect
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl<'a> Waiting<'a> {
+    fn join(waiting: &'a AtomicUsize, limit: usize) -> Option<Self> {
+        let joined =
+            waiting.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| (count < limit).then_some(count + 1));
+        joined.ok().map(|_| Self(waiting))
     }
+}
 
-    fn create(
-        &mut self,
-        _req: &Request,
-        parent: u64,
-        name: &OsStr,
-        mode: u32,
-        umask: u32,
-        flags: i32,
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// How long requests wait for their response once sent, per operation class
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Timeouts {
+    metadata: Duration,
+    read: Duration,
+    write: Duration,
+    sync: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            metadata: DEFAULT_METADATA_TIMEOUT,
+            read: DEFAULT_READ_TIMEOUT,
+            write: DEFAULT_WRITE_TIMEOUT,
+            sync: DEFAULT_SYNC_TIMEOUT,
+        }
+    }
+}
+
+impl Timeouts {
+    // Each class takes its own option, else --request-timeout, else its default
+    fn parse(matches: &ArgMatches) -> Self {
+        let all = matches.get_one::<Duration>("request-timeout").copied();
+        let defaults = Self::default();
+        let timeout = |id: &str, default: Duration| matches.get_one(id).copied().or(all).unwrap_or(default);
+        Self {
+            metadata: timeout("metadata-timeout", defaults.metadata),
+            read: timeout("read-timeout", defaults.read),
+            write: timeout("write-timeout", defaults.write),
+            sync: timeout("sync-timeout", defaults.sync),
+        }
+    }
+
+    fn of(&self, operation: FsOperation) -> Duration {
+        match operation.class() {
+            OperationClass::Metadata => self.metadata,
+            OperationClass::Read => self.read,
+            OperationClass::Write => self.write,
+            OperationClass::Sync => self.sync,
+        }
+    }
+}
+
+// How often and how patiently requests that failed for a passing reason are
+// resent
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Retry {
+    attempts: usize,
+    backoff: Duration,
+    max_backoff: Duration,
+    // Share of each wait that is random, so daemons that failed together don't
+    // come back together
+    jitter: f64,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: DEFAULT_RETRY_ATTEMPTS,
+            backoff: DEFAULT_RETRY_BACKOFF,
+            max_backoff: DEFAULT_RETRY_MAX_BACKOFF,
+            jitter: DEFAULT_RETRY_JITTER,
+        }
+    }
+}
+
+impl Retry {
+    fn parse(matches: &ArgMatches) -> Self {
+        let defaults = Self::default();
+        Self {
+            attempts: matches.get_one("retry-attempts").copied().unwrap_or(defaults.attempts),
+            backoff: matches.get_one("retry-backoff").copied().unwrap_or(defaults.backoff),
+            max_backoff: matches.get_one("retry-max-backoff").copied().unwrap_or(defaults.max_backoff),
+            jitter: matches.get_one("retry-jitter").copied().unwrap_or(defaults.jitter),
+        }
+    }
+
+    // How long to wait before resending after `attempt` earlier resends
+    fn delay(&self, attempt: usize) -> Duration {
+        let doubled = self.backoff.saturating_mul(1 << attempt.min(31)).min(self.max_backoff);
+        let share = trace::random() as f64 / u64::MAX as f64;
+        doubled.mul_f64(1.0 - self.jitter * share)
+    }
+}
+
+// Ping cadence on idle connections, and the silence after which one is
+// declared dead and closed so the DO can replace it
+#[derive(Clone, Copy)]
+struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+}
+
+// When anything last arrived on a connection, and whether it is still usable
+struct Liveness {
+    // Numbers connections in the order they were attached, never reused
+    connection: u64,
+    last_heard: Mutex<Instant>,
+    alive: AtomicBool,
+}
+
+impl Liveness {
+    fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::SeqCst)
+    }
+}
+
+// One socket to the DO, served by its own reader and writer threads
+struct Channel {
+    outgoing: mpsc::Sender<Vec<u8>>,
+    encoding: Encoding,
+    // Large payloads are LZ4-compressed
+    compression: bool,
+    // Kept to shut the connection down when the heartbeat gives up on it
+    socket: Conn,
+    liveness: Arc<Liveness>,
+    // Which of the dialed addresses it goes to; 0 for accepted connections
+    endpoint: usize,
+    // The DO it belongs to; empty for DOs that don't name themselves, which
+    // all count as one
+    client: String,
+    // Agreed in its hello, which may differ between DOs
+    features: HashSet<String>,
+}
+
+// The client whose connections carry the daemon's requests. Once none of its
+// connections is left, the client connected longest takes over; that DO holds
+// none of the daemon's leases, so caches are resynced.
+fn primary_client(channels: &[Channel], primary: &Mutex<Option<String>>, inboxes: &Inboxes) -> Option<String> {
+    let mut primary = primary.locked();
+    let live = || channels.iter().filter(|channel| channel.liveness.is_alive());
+    if let Some(client) = primary.as_ref() {
+        if live().any(|channel| &channel.client == client) {
+            return Some(client.clone());
+        }
+    }
+    let successor = live().min_by_key(|channel| channel.liveness.connection)?.client.clone();
+    if let Some(previous) = primary.replace(successor.clone()) {
+        warn!("No connection left to DO '{}', sending requests to DO '{}'", previous, successor);
+        inboxes.resync();
+    }
+    Some(successor)
+}
+
+// What a connection needs to join the client, shared with the thread that
+// accepts the rest of the pool
+#[derive(Clone)]
+struct ChannelSetup {
+    preferred: Encoding,
+    compression: bool,
+    session: String,
+    channels: Arc<Mutex<Vec<Channel>>>,
+    pending_requests: Arc<Mutex<HashMap<u64, (u64, Pending)>>>,
+    inboxes: Arc<Inboxes>,
+    buffers: BufferPool,
+    max_frame: usize,
+    next_connection: Arc<AtomicU64>,
+    tls: Option<Arc<Tls>>,
+    // How many DOs may be connected at once, and which of them is primary
+    clients: usize,
+    primary: Arc<Mutex<Option<String>>>,
+}
+
+impl ChannelSetup {
+    // Runs TLS over a new connection when configured; `address` is what was
+    // dialed or listened on
+    fn secure(&self, stream: Conn, address: &Address) -> Result<Conn, Box<dyn std::error::Error>> {
+        match &self.tls {
+            Some(tls) => tls.wrap(stream, address),
+            None => Ok(stream),
+        }
+    }
+
+    fn live_channels(&self) -> usize {
+        self.channels.locked().iter().filter(|channel| channel.liveness.is_alive()).count()
+    }
+
+    // Closes connections to any DO but the one at `endpoint`, so a pool never
+    // spans two DOs after a failover
+    fn close_other_endpoints(&self, endpoint: usize) {
+        for channel in self.channels.locked().iter() {
+            if channel.endpoint != endpoint && channel.liveness.is_alive() {
+                channel.liveness.alive.store(false, Ordering::SeqCst);
+                let _ = channel.socket.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    // Dials until a connection to some DO completes its handshake
+    fn dial(&self, dialer: &Dialer) -> (u32, HashSet<String>) {
+        loop {
+            let (endpoint, stream) = dialer.dial();
+            self.close_other_endpoints(endpoint);
+            let address = &dialer.addresses[endpoint];
+            match self.secure(stream, address).and_then(|stream| self.attach(stream, endpoint)) {
+                Ok(agreed) => {
+                    dialer.connected();
+                    return agreed;
+                }
+                Err(e) => {
+                    warn!("Failed to add a connection to DO at {}: {}", address, e);
+                    dialer.failed(endpoint);
+                }
+            }
+        }
+    }
+
+    // Accepts until a connection completes its handshake. One that fails, say
+    // a client without the right certificate or a port scan, is dropped and
+    // the listener waits for the next.
+    fn accept(&self, listener: &Listener, address: &Address) -> io::Result<(u32, HashSet<String>)> {
+        loop {
+            let stream = listener.accept()?;
+            match self.secure(stream, address).and_then(|stream| self.attach(stream, 0)) {
+                Ok(agreed) => return Ok(agreed),
+                Err(e) => warn!("Failed to add a connection from DO: {}", e),
+            }
+        }
+    }
+
+    // Greets the DO over a new connection and starts serving it in place of
+    // the first dead channel, or as the next one. The DO drops a session's
+    // leases along with its first connection and pushes nothing while it is
+    // down, so replacing that one asks for a resync.
+    fn attach(
+        &self,
+        mut stream: Conn,
+        endpoint: usize,
+    ) -> Result<(u32, HashSet<String>), Box<dyn std::error::Error>> {
+        let index = {
+            let channels = self.channels.locked();
+            let dead = channels.iter().position(|channel| !channel.liveness.is_alive());
+            dead.unwrap_or(channels.len())
+        };
+        let (protocol_version, features, peer_max_frame, instance) = RemoteFSClient::handshake(
+            &mut stream,
+            self.preferred,
+            self.compression,
+            &self.session,
+            index,
+            self.max_frame,
+        )?;
+        // Each step down in encoding needs one feature less from the DO
+        let encoding = match self.preferred {
+            Encoding::MessagePack if features.contains("msgpack") => Encoding::MessagePack,
+            Encoding::MessagePack | Encoding::Json if features.contains("binary-frames") => Encoding::Json,
+            _ => Encoding::Legacy,
+        };
+        info!(
+            "Negotiated protocol version {} with features {:?}, {:?} encoding on connection {}",
+            protocol_version, features, encoding, index
+        );
+        let client = instance.unwrap_or_default();
+        {
+            let channels = self.channels.locked();
+            let live: HashSet<&str> = channels
+                .iter()
+                .filter(|channel| channel.liveness.is_alive())
+                .map(|channel| channel.client.as_str())
+                .collect();
+            if !live.contains(client.as_str()) && live.len() >= self.clients {
+                return Err(format!("already serving {} DOs, refusing another", live.len()).into());
+            }
+        }
+
+        // Split the socket so the blocking reader never holds the lock writers need
+        let reader = stream.try_clone()?;
+        let socket = stream.try_clone()?;
+        let (outgoing, queue) = mpsc::channel(SEND_QUEUE_DEPTH);
+        let liveness = Arc::new(Liveness {
+            connection: self.next_connection.fetch_add(1, Ordering::Relaxed),
+            last_heard: Mutex::new(Instant::now()),
+            alive: AtomicBool::new(true),
+        });
+
+        // Start reader thread
+        let pending_clone = self.pending_requests.clone();
+        let inboxes_clone = self.inboxes.clone();
+        let reader_buffers = self.buffers.clone();
+        let reader_liveness = liveness.clone();
+        let max_frame = self.max_frame;
+        thread::spawn(move || {
+            RemoteFSClient::reader_loop(
+                reader,
+                pending_clone,
+                inboxes_clone,
+                reader_buffers,
+                reader_liveness,
+                max_frame,
+            );
+        });
+
+        // Start writer thread, the only place that writes to the socket
+        let writer_buffers = self.buffers.clone();
+        let batching = features.contains("batch");
+        let fragment_limit = peer_max_frame.filter(|_| features.contains("fragments"));
+        thread::spawn(move || {
+            RemoteFSClient::writer_loop(stream, queue, writer_buffers, batching, fragment_limit);
+        });
+
+        let channel = Channel {
+            outgoing,
+            encoding,
+            compression: features.contains("lz4"),
+            socket,
+            liveness,
+            endpoint,
+            client,
+            features: features.clone(),
+        };
+        let mut channels = self.channels.locked();
+        METRICS.connected(index < channels.len());
+        lifecycle::connection().connected();
+        if index < channels.len() {
+            channels[index] = channel;
+            if index == 0 {
+                self.inboxes.resync();
+            }
+        } else {
+            channels.push(channel);
+        }
+        primary_client(&channels, &self.primary, &self.inboxes);
+        Ok((protocol_version, features))
+    }
+}
+
+// Negotiated once when the DO first connects
+struct Negotiated {
+    encoding: Encoding,
+    compression: bool,
+    // Protocol version and optional features agreed in the hello; 0 and none
+    // for DOs that predate it
+    protocol_version: u32,
+    features: HashSet<String>,
+    // Connections the pool may grow to, 1 when the DO doesn't pool
+    pool_size: usize,
+    // Set when the DO answers pings
+    heartbeat: Option<Heartbeat>,
+}
+
+// What reaches one mounted filesystem from outside its own operations, for it
+// to apply before its next one
+struct Inbox {
+    // Path on the DO the mount shows; pushes for paths outside it are dropped
+    prefix: String,
+    // Paths invalidated by the DO since they were last taken, and whether
+    // their directory entry changed too
+    invalidations: Mutex<HashMap<String, bool>>,
+    // Where lease recalls for the mount go, once its leases subscribed
+    recalls: Mutex<Option<std::sync::mpsc::Sender<String>>>,
+    // Where changes the kernel may have cached go, once the mount subscribed
+    notices: Mutex<Option<std::sync::mpsc::Sender<Notice>>>,
+    // Set when the first connection was replaced, so cached state and leases
+    // may be stale
+    resync: AtomicBool,
+    // A reloaded cache policy the mount hasn't applied yet
+    reloaded: Mutex<Option<CachePolicy>>,
+}
+
+impl Inbox {
+    fn shows(&self, path: &str) -> bool {
+        self.prefix == "/"
+            || path.strip_prefix(self.prefix.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    // Lease recalls pushed by the DO from now on
+    fn subscribe_recalls(&self) -> std::sync::mpsc::Receiver<String> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        *self.recalls.locked() = Some(sender);
+        receiver
+    }
+
+    // Changes to drop from the kernel's caches from now on
+    fn subscribe_notices(&self) -> std::sync::mpsc::Receiver<Notice> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        *self.notices.locked() = Some(sender);
+        receiver
+    }
+
+    fn notify(&self, notice: Notice) {
+        if let Some(notices) = self.notices.locked().as_ref() {
+            let _ = notices.send(notice);
+        }
+    }
+
+    // Invalidations pushed since the last call, by path
+    fn take_invalidations(&self) -> HashMap<String, bool> {
+        std::mem::take(&mut *self.invalidations.locked())
+    }
+
+    // Whether a resync was asked for since the last call
+    fn take_resync(&self) -> bool {
+        self.resync.swap(false, Ordering::SeqCst)
+    }
+
+    fn take_reloaded(&self) -> Option<CachePolicy> {
+        self.reloaded.locked().take()
+    }
+}
+
+// The inbox of every mount sharing the client, in the order of
+// `MountOptions::mounts`. A namespace's client has none of its own and hands
+// what its DO pushes to the main client's, at the paths the mounts know.
+#[derive(Default)]
+struct Inboxes {
+    mounts: Mutex<Vec<Arc<Inbox>>>,
+    graft: OnceLock<(Graft, Arc<Inboxes>)>,
+}
+
+impl Inboxes {
+    // Where a path the DO pushed shows in the mounts, if it does
+    fn outer(&self, path: &str) -> Option<String> {
+        match self.graft.get() {
+            Some((graft, _)) => graft.outer(path),
+            None => Some(path.to_string()),
+        }
+    }
+
+    fn open(&self, prefix: &str) -> Arc<Inbox> {
+        let inbox = Arc::new(Inbox {
+            prefix: prefix.to_string(),
+            invalidations: Mutex::new(HashMap::new()),
+            recalls: Mutex::new(None),
+            notices: Mutex::new(None),
+            resync: AtomicBool::new(false),
+            reloaded: Mutex::new(None),
+        });
+        self.mounts.locked().push(inbox.clone());
+        inbox
+    }
+
+    fn invalidate(&self, path: &str, entry_changed: bool) {
+        if let Some((graft, main)) = self.graft.get() {
+            if let Some(path) = graft.outer(path) {
+                main.invalidate(&path, entry_changed);
+            }
+            return;
+        }
+        for inbox in self.mounts.locked().iter().filter(|inbox| inbox.shows(path)) {
+            *inbox.invalidations.locked().entry(path.to_string()).or_default() |= entry_changed;
+            inbox.notify(Notice::Path(path.to_string(), entry_changed));
+        }
+    }
+
+    // Someone else is about to touch the path, so the mounts showing it stop
+    // trusting their caches now and hand their leases back
+    fn recall(&self, path: &str) {
+        if let Some((graft, main)) = self.graft.get() {
+            if let Some(path) = graft.outer(path) {
+                main.recall(&path);
+            }
+            return;
+        }
+        for inbox in self.mounts.locked().iter().filter(|inbox| inbox.shows(path)) {
+            inbox.invalidations.locked().entry(path.to_string()).or_default();
+            if let Some(recalls) = inbox.recalls.locked().as_ref() {
+                let _ = recalls.send(path.to_string());
+            }
+        }
+    }
+
+    fn resync(&self) {
+        if let Some((_, main)) = self.graft.get() {
+            return main.resync();
+        }
+        for inbox in self.mounts.locked().iter() {
+            inbox.resync.store(true, Ordering::SeqCst);
+            inbox.notify(Notice::All);
+        }
+        EVENTS.resync();
+    }
+
+    // Hands each mount its policy, in mount order. Mounts added or removed
+    // since startup are left alone.
+    fn reload(&self, caches: &[CachePolicy]) {
+        for (inbox, cache) in self.mounts.locked().iter().zip(caches) {
+            *inbox.reloaded.locked() = Some(*cache);
+        }
+    }
+
+    fn pending(&self) -> usize {
+        self.mounts.locked().iter().map(|inbox| inbox.invalidations.locked().len()).sum()
+    }
+}
+
+// Where the first and every later connection to the DO comes from
+enum Source {
+    Accept(Listener, Address),
+    Dial(Arc<Dialer>),
+}
+
+struct RemoteFSClient {
+    // Connections to the DO; metadata requests use the first, bulk transfers the rest
+    channels: Arc<Mutex<Vec<Channel>>>,
+    next_bulk_channel: AtomicUsize,
+    buffers: BufferPool,
+    request_id: Arc<Mutex<u64>>,
+    // Requests awaiting a response, with the connection each went out on
+    pending_requests: Arc<Mutex<HashMap<u64, (u64, Pending)>>>,
+    outstanding: Mutex<HashMap<u64, Outstanding>>,
+    // Identifies this daemon to the DO; idempotency keys are drawn within it
+    session: String,
+    next_idempotency_key: AtomicU64,
+    // Per file, the last write sequence number handed out and the highest the
+    // DO acknowledged, which fsync asks it to vouch for
+    write_seqs: Mutex<HashMap<String, (u64, u64)>>,
+    // One per mount served over the client, where pushes from the DO go
+    inboxes: Arc<Inboxes>,
+    // The connected DO that requests go to, and whether reads are spread
+    // across the others
+    primary: Arc<Mutex<Option<String>>>,
+    routing: Routing,
+    next_shared_channel: AtomicUsize,
+    // Set once the DO first connects, which with a lazy connection may be
+    // after the filesystem is mounted
+    negotiated: Arc<OnceLock<Negotiated>>,
+    // How requests behave while the DO is unreachable, and how many are
+    // waiting for it
+    disconnect: Disconnect,
+    waiting: AtomicUsize,
+    // How long a sent request waits for its response
+    timeouts: Mutex<Timeouts>,
+    // How failed requests are resent
+    retry: Retry,
+    // One permit per request awaiting a response. Tokio hands permits out in
+    // the order they were asked for, so a burst can't starve earlier callers.
+    in_flight: Semaphore,
+    max_in_flight: usize,
+    // Longest frame body accepted from the DO
+    max_frame: usize,
+    // How large reads and flushes are split, and how many chunks go at once
+    chunk_size: usize,
+    parallelism: usize,
+    endpoint: Endpoint,
+    tls: bool,
+    // Set when dialing out, to report the DO currently dialed
+    dialer: Option<Arc<Dialer>>,
+    // Seals file contents before they leave the daemon, when given a key
+    cipher: Option<Cipher>,
+    // Sealed writes rewrite whole chunks, read first, so they go one at a time
+    sealing: tokio::sync::Mutex<()>,
+    // Whether writes go as chunks the DO may already have, when it agreed to `dedup`
+    dedup: bool,
+    // Other DOs grafted onto this one's tree, each over a client of its own
+    namespaces: OnceLock<Namespaces>,
+    // The daemon's runtime, which FUSE callbacks, frontends and background
+    // threads block on to wait for the DO
+    runtime: tokio::runtime::Handle,
+}
+
+// Protocol version, agreed features, the longest frame the DO accepts when it
+// agreed to `fragments`, and the DO's name if it gave one
+type Agreed = (u32, HashSet<String>, Option<usize>, Option<String>);
+
+impl RemoteFSClient {
+    fn new(
+        preferred: Encoding,
+        compression: bool,
+        connections: usize,
+        heartbeat: Option<Heartbeat>,
+        max_in_flight: usize,
+        max_frame: usize,
+        transport: &Transport,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let endpoint = transport.endpoint.clone();
+        let dialing = matches!(endpoint, Endpoint::Dial(_));
+        // Hard mounts wait for the DO however long it takes, so never fail
+        if !transport.disconnect.hard {
+            lifecycle::connection().set_fail_after(transport.disconnect.timeout);
+        }
+        let tls = match &transport.tls {
+            Some(files) => Some(Arc::new(Tls::load(files, dialing)?)),
+            None => None,
+        };
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let setup = ChannelSetup {
+            preferred,
+            compression,
+            session: format!("{}-{}", std::process::id(), started.as_nanos()),
+            channels: Arc::new(Mutex::new(Vec::new())),
+            pending_requests: Arc::new(Mutex::new(HashMap::new())),
+            inboxes: Arc::new(Inboxes::default()),
+            buffers: BufferPool::new(),
+            max_frame,
+            next_connection: Arc::new(AtomicU64::new(0)),
+            tls,
+            clients: transport.clients,
+            primary: Arc::new(Mutex::new(None)),
+        };
+        let source = match &endpoint {
+            Endpoint::Listen(address) => {
+                // Listen for incoming connection from DO
+                let listener = Listener::bind(address)?;
+                info!("Filesystem daemon listening on {}", address);
+                Source::Accept(listener, address.clone())
+            }
+            Endpoint::Dial(addresses) => Source::Dial(Arc::new(Dialer::new(addresses.clone()))),
+        };
+        let dialer = match &source {
+            Source::Accept(..) => None,
+            Source::Dial(dialer) => Some(dialer.clone()),
+        };
+        let negotiated = Arc::new(OnceLock::new());
+        if transport.lazy {
+            let (setup, negotiated) = (setup.clone(), negotiated.clone());
+            thread::spawn(move || match Self::establish(setup, source, connections, heartbeat, true) {
+                Ok(agreed) => {
+                    let _ = negotiated.set(agreed);
+                }
+                Err(e) => error!("Failed to accept a connection from DO: {}", e),
+            });
+        } else {
+            let _ = negotiated.set(Self::establish(setup.clone(), source, connections, heartbeat, false)?);
+        }
+
+        Ok(Self {
+            channels: setup.channels,
+            next_bulk_channel: AtomicUsize::new(0),
+            buffers: setup.buffers,
+            request_id: Arc::new(Mutex::new(0)),
+            pending_requests: setup.pending_requests,
+            outstanding: Mutex::new(HashMap::new()),
+            session: setup.session,
+            next_idempotency_key: AtomicU64::new(0),
+            write_seqs: Mutex::new(HashMap::new()),
+            inboxes: setup.inboxes,
+            primary: setup.primary,
+            routing: transport.routing,
+            next_shared_channel: AtomicUsize::new(0),
+            negotiated,
+            disconnect: transport.disconnect,
+            waiting: AtomicUsize::new(0),
+            timeouts: Mutex::new(transport.timeouts),
+            retry: transport.retry,
+            in_flight: Semaphore::new(max_in_flight),
+            max_in_flight,
+            max_frame,
+            chunk_size: transport.chunk_size,
+            parallelism: transport.parallelism,
+            endpoint,
+            tls: transport.tls.is_some(),
+            dialer,
+            cipher: transport.encryption.as_ref().map(Cipher::new),
+            sealing: tokio::sync::Mutex::new(()),
+            dedup: transport.dedup,
+            namespaces: OnceLock::new(),
+            runtime: tokio::runtime::Handle::try_current()?,
+        })
+    }
+
+    // Waits out async work from a thread outside the runtime
+    fn block_on<F: std::future::Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    // Routes requests for paths under each namespace to its client from now
+    // on, and has what its DO pushes reach this client's mounts
+    fn graft(&self, namespaces: Namespaces) {
+        for namespace in namespaces.iter() {
+            let _ = namespace.client.inboxes.graft.set((namespace.graft.clone(), self.inboxes.clone()));
+        }
+        let _ = self.namespaces.set(namespaces);
+    }
+
+    // The namespace's client a path goes to and its path there, unless it
+    // is this client's own
+    fn route(&self, path: &str) -> Option<(&Arc<RemoteFSClient>, String)> {
+        self.namespaces.get()?.route(path)
+    }
+
+    // Whether a path is where a namespace is grafted
+    fn is_namespace(&self, path: &str) -> bool {
+        self.namespaces.get().is_some_and(|namespaces| namespaces.is_root(path))
+    }
+
+    // The names of the namespaces grafted directly under a directory
+    fn namespaces_in(&self, dir: &str) -> Vec<String> {
+        let names = self.namespaces.get().map(|namespaces| namespaces.names_in(dir)).unwrap_or_default();
+        names.into_iter().map(str::to_string).collect()
+    }
+
+    // Waits for the first connection to the DO, whose hello settles what the
+    // client speaks, then keeps the pool full in the background. A lazy client
+    // is already mounted, so it keeps accepting until a DO gets through rather
+    // than leave requests waiting on a connection nothing will make.
+    fn establish(
+        setup: ChannelSetup,
+        source: Source,
+        connections: usize,
+        heartbeat: Option<Heartbeat>,
+        lazy: bool,
+    ) -> io::Result<Negotiated> {
+        let (protocol_version, features) = match &source {
+            Source::Accept(listener, address) => {
+                let mut backoff = DIAL_BACKOFF_INITIAL;
+                loop {
+                    match setup.accept(listener, address) {
+                        Ok(agreed) => break agreed,
+                        Err(e) if lazy => {
+                            warn!("Failed to accept a connection from DO, retrying in {:?}: {}", backoff, e);
+                            thread::sleep(backoff);
+                            backoff = (backoff * 2).min(DIAL_BACKOFF_MAX);
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            Source::Dial(dialer) => setup.dial(dialer),
+        };
+        info!("Filesystem daemon connected to DO");
+        let (encoding, compression) = {
+            let channels = setup.channels.locked();
+            (channels[0].encoding, channels[0].compression)
+        };
+
+        // The rest of the pool joins in the background, and a connection that
+        // died is replaced by the next one the DO opens, or that the daemon
+        // dials; requests use whatever is connected
+        let pool_size = if features.contains("pool") { connections.max(1) } else { 1 };
+        match source {
+            Source::Accept(listener, address) => Self::spawn_acceptor(listener, address, setup.clone(), pool_size),
+            Source::Dial(dialer) => Self::spawn_dialer(dialer, setup.clone(), pool_size),
+        }
+
+        let heartbeat = heartbeat.filter(|_| features.contains("heartbeat"));
+        if let Some(heartbeat) = heartbeat {
+            Self::spawn_heartbeat(setup.channels.clone(), heartbeat);
+        }
+        Ok(Negotiated {
+            encoding,
+            compression,
+            protocol_version,
+            features,
+            pool_size,
+            heartbeat,
+        })
+    }
+
+    // Times a failed request is resent; without limit on a hard mount
+    fn retries(&self) -> usize {
+        if self.disconnect.hard {
+            usize::MAX
+        } else {
+            self.retry.attempts
+        }
+    }
+
+    fn disconnected(&self, message: &'static str) -> DaemonError {
+        DaemonError::Disconnected {
+            message,
+            errno: self.disconnect.errno,
+            lost: false,
+        }
+    }
+
+    // The connection a request went out on closed before it was answered
+    fn connection_lost(&self) -> DaemonError {
+        DaemonError::Disconnected {
+            message: "Connection closed",
+            errno: self.disconnect.errno,
+            lost: true,
+        }
+    }
+
+    // What the DO agreed to, once it has connected
+    fn negotiated(&self) -> Option<&Negotiated> {
+        self.negotiated.get()
+    }
+
+    // Blocks until the DO has connected for the first time
+    fn wait_connected(&self) {
+        while self.negotiated().is_none() {
+            thread::sleep(RECONNECT_POLL);
+        }
+    }
+
+    fn spawn_acceptor(listener: Listener, address: Address, acceptor: ChannelSetup, pool_size: usize) {
+        let capacity = pool_size * acceptor.clients;
+        thread::spawn(move || loop {
+            let stream = listener.accept();
+            if acceptor.live_channels() >= capacity {
+                warn!("Refusing a connection from DO, all {} connections are up", capacity);
+                continue;
+            }
+            let attached = stream
+                .map_err(Into::into)
+                .and_then(|stream| acceptor.secure(stream, &address))
+                .and_then(|stream| acceptor.attach(stream, 0));
+            match attached {
+                Ok(_) => info!("Filesystem daemon added a connection to DO"),
+                Err(e) => warn!("Failed to add a connection: {}", e),
+            }
+        });
+    }
+
+    // Keeps the pool full by dialing the DO whenever a connection is missing
+    fn spawn_dialer(dialer: Arc<Dialer>, setup: ChannelSetup, pool_size: usize) {
+        thread::spawn(move || loop {
+            if setup.live_channels() >= pool_size {
+                thread::sleep(DIAL_BACKOFF_INITIAL);
+                continue;
+            }
+            setup.dial(&dialer);
+            info!("Filesystem daemon added a connection to DO at {}", dialer.current_address());
+        });
+    }
+
+    // How the DO is reached, and where it is or was last dialed
+    fn transport(&self) -> (String, String) {
+        match (&self.endpoint, &self.dialer) {
+            (Endpoint::Listen(address), _) => (format!("{}-listen", address.kind()), address.to_string()),
+            (Endpoint::Dial(_), Some(dialer)) => {
+                let address = dialer.current_address();
+                (format!("{}-dial", address.kind()), address.to_string())
+            }
+            (Endpoint::Dial(addresses), None) => (format!("{}-dial", addresses[0].kind()), addresses[0].to_string()),
+        }
+    }
+
+    // Pings connections that have been quiet for an interval, since any frame
+    // counts as a sign of life, and shuts down those silent past the timeout.
+    // The reader and writer threads of a shut connection exit, and the DO sees
+    // it close and can open a replacement.
+    fn spawn_heartbeat(channels: Arc<Mutex<Vec<Channel>>>, heartbeat: Heartbeat) {
+        thread::spawn(move || loop {
+            thread::sleep(heartbeat.interval);
+            for (index, channel) in channels.locked().iter().enumerate() {
+                if !channel.liveness.is_alive() {
+                    continue;
+                }
+                let silent = channel.liveness.last_heard.locked().elapsed();
+                if silent >= heartbeat.timeout {
+                    warn!("Nothing heard from DO on connection {} for {:?}, closing it", index, silent);
+                    channel.liveness.alive.store(false, Ordering::SeqCst);
+                    let _ = channel.socket.shutdown(Shutdown::Both);
+                } else if silent >= heartbeat.interval {
+                    let mut ping = FSMessage {
+                        operation: FsOperation::Ping,
+                        ..Default::default()
+                    };
+                    let mut frame = Vec::new();
+                    if Self::encode_frame(channel.encoding, false, &mut ping, &mut frame).is_ok() {
+                        let _ = channel.outgoing.try_send(frame);
+                    }
+                }
+            }
+        });
+    }
+
+    fn has_feature(&self, feature: &str) -> bool {
+        self.negotiated().is_some_and(|negotiated| negotiated.features.contains(feature))
+    }
+
+    // Tells the DO the daemon is going away on purpose, so it drops the
+    // session's leases now rather than when the connections close
+    async fn goodbye(&self) -> Result<(), DaemonError> {
+        for namespace in self.namespaces.get().into_iter().flat_map(Namespaces::iter) {
+            if let Err(e) = Box::pin(namespace.client.goodbye()).await {
+                warn!("Failed to say goodbye to the DO of {}: {}", namespace.graft.dir, e);
+            }
+        }
+        if !self.has_feature("goodbye") {
+            return Ok(());
+        }
+        self.send_message(FSMessage {
+            operation: FsOperation::Goodbye,
+            path: "/".to_string(),
+            ..Default::default()
+        })
+        .await?;
+        Ok(())
+    }
+
+    // Metadata requests keep the first live connection to themselves so they
+    // never queue behind bulk transfers; reads and writes take turns on the
+    // rest. None when every connection is down.
+    fn pick_channel(&self, message: &FSMessage) -> Option<(mpsc::Sender<Vec<u8>>, Encoding, bool, u64)> {
+        let channels = self.channels.locked();
+        let primary = primary_client(&channels, &self.primary, &self.inboxes)?;
+        let live: Vec<&Channel> =
+            channels.iter().filter(|channel| channel.liveness.is_alive() && channel.client == primary).collect();
+        let channel = if self.routing == Routing::Share && message.operation.is_shareable() {
+            // Other DOs take a turn if they agreed to everything the primary did
+            let shared: Vec<&Channel> = channels
+                .iter()
+                .filter(|channel| channel.liveness.is_alive())
+                .filter(|channel| {
+                    channel.client == primary
+                        || self.negotiated().is_some_and(|agreed| channel.features.is_superset(&agreed.features))
+                })
+                .collect();
+            shared[self.next_shared_channel.fetch_add(1, Ordering::Relaxed) % shared.len()]
+        } else if message.operation.is_bulk() && live.len() > 1 {
+            let turn = self.next_bulk_channel.fetch_add(1, Ordering::Relaxed);
+            live[1 + turn % (live.len() - 1)]
+        } else {
+            live.first()?
+        };
+        Some((channel.outgoing.clone(), channel.encoding, channel.compression, channel.liveness.connection))
+    }
+
+    // Exchanges hellos before any other traffic: the daemon offers the protocol
+    // versions and optional features it speaks, and the DO answers with the
+    // version and the subset of features it agrees to. The hello goes out as
+    // legacy JSON, which every DO can parse; DOs that predate it answer with an
+    // error and get version 0 with no optional features.
+    fn handshake(
+        stream: &mut Conn,
+        preferred: Encoding,
+        compression: bool,
+        session: &str,
+        channel: usize,
+        max_frame: usize,
+    ) -> Result<Agreed, Box<dyn std::error::Error>> {
+        let mut features = vec![
+            "push",
+            "leases",
+            "batch",
+            "pool",
+            "checksums",
+            "stream-reads",
+            "stream-writes",
+            "idempotency",
+            "cancel",
+            "heartbeat",
+            "fragments",
+            "timing",
+            "audit",
+            "trace-context",
+            "goodbye",
+            "sync",
+            "snapshots",
+            "dedup",
+            "sparse",
+            "modes",
+            "correlation",
+        ];
+        if preferred != Encoding::Legacy {
+            features.push("binary-frames");
+        }
+        // Compressed payloads only exist in binary frames
+        if preferred != Encoding::Legacy && compression {
+            features.push("lz4");
+        }
+        if preferred == Encoding::MessagePack {
+            features.push("msgpack");
+        }
+        let mut message = FSMessage {
+            operation: FsOperation::Hello,
+            path: "/".to_string(),
+            hello: Some(HelloRequest {
+                versions: PROTOCOL_VERSIONS.to_vec(),
+                features,
+                session: session.to_string(),
+                channel,
+                max_frame,
+            }),
+            ..Default::default()
+        };
+        let mut frame = Vec::new();
+        Self::encode_frame(Encoding::Legacy, false, &mut message, &mut frame)?;
+        stream.write_all(&frame)?;
+
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let response = loop {
+            let mut length_buf = [0u8; 4];
+            stream.read_exact(&mut length_buf)?;
+            let length = u32::from_le_bytes(length_buf) as usize;
+            if length > max_frame {
+                return Err(format!("DO sent a {} byte frame, over the {} byte limit", length, max_frame).into());
+            }
+            frame.clear();
+            frame.resize(length, 0);
+            stream.read_exact(&mut frame)?;
+            // Nothing is cached yet, so pushes that arrive first can be dropped
+            match Self::decode_response(&frame) {
+                Some(response) if response.invalidate.is_none() && response.recall.is_none() => break response,
+                _ => continue,
+            }
+        };
+        stream.set_read_timeout(None)?;
+
+        let Some(hello) = response.hello else {
+            return Ok((0, HashSet::new(), None, None));
+        };
+        let Some(version) = hello.version else {
+            return Err(format!(
+                "DO speaks protocol versions {:?}, daemon speaks {:?}",
+                hello.versions, PROTOCOL_VERSIONS
+            )
+            .into());
+        };
+        Ok((version, hello.features.into_iter().collect(), hello.max_frame, hello.instance))
+    }
+
+    fn reader_loop(
+        mut stream: Conn,
+        pending: Arc<Mutex<HashMap<u64, (u64, Pending)>>>,
+        inboxes: Arc<Inboxes>,
+        buffers: BufferPool,
+        liveness: Arc<Liveness>,
+        max_frame: usize,
+    ) {
+        let mut message_buf = buffers.take();
+        // Fragments of a message still being received
+        let mut assembled = Vec::new();
+        loop {
+            let mut length_buf = [0u8; 4];
+
+            if stream.read_exact(&mut length_buf).is_err() {
+                break;
+            }
+            
+            let message_length = u32::from_le_bytes(length_buf) as usize;
+            // A corrupt length leaves nothing to resynchronize on, so the connection goes
+            if message_length > max_frame {
+                warn!("Closing connection: DO sent a {} byte frame, over the {} byte limit", message_length, max_frame);
+                let _ = stream.shutdown(Shutdown::Both);
+                break;
+            }
+            message_buf.clear();
+            message_buf.resize(message_length, 0);
+
+            if stream.read_exact(&mut message_buf).is_err() {
+                break;
+            }
+            *liveness.last_heard.locked() = Instant::now();
+
+            let fragment = message_buf.first() == Some(&FRAGMENT_FRAME_TAG);
+            if fragment {
+                if assembled.len() + message_buf.len() > MAX_MESSAGE_SIZE {
+                    warn!("Closing connection: DO sent a fragmented message over {} bytes", MAX_MESSAGE_SIZE);
+                    let _ = stream.shutdown(Shutdown::Both);
+                    break;
+                }
+                assembled.extend_from_slice(message_buf.get(2..).unwrap_or_default());
+                if message_buf.get(1) != Some(&1) {
+                    continue;
+                }
+            }
+            let responses = Self::decode_responses(if fragment { &assembled } else { &message_buf });
+            assembled.clear();
+
+            for mut response in responses {
+                if let Some(invalidation) = response.invalidate.take() {
+                    inboxes.invalidate(&invalidation.path, invalidation.change != "modified");
+                    if let Some(path) = inboxes.outer(&invalidation.path) {
+                        EVENTS.publish(&path, &invalidation.change);
+                    }
+                    continue;
+                }
+                if let Some(recall) = response.recall.take() {
+                    inboxes.recall(&recall.path);
+                    continue;
+                }
+                let mut pending = pending.locked();
+                match pending.remove(&response.id) {
+                    Some((_, Pending::Single(sender))) => {
+                        let _ = sender.send(response);
+                    }
+                    Some((connection, Pending::Stream(sender))) => {
+                        let (id, more) = (response.id, response.more);
+                        if sender.send(response).is_ok() && more {
+                            pending.insert(id, (connection, Pending::Stream(sender)));
+                        }
+                    }
+                    // Pings go out with id 0 and only show the connection is alive
+                    None if response.id == 0 => {}
+                    // Cancelled, timed out or reaped; whatever it says is too late
+                    None => {
+                        METRICS.late_response();
+                        let cid = response.correlation.as_deref();
+                        debug!(id = response.id, cid, "Dropping a response nobody is waiting for");
+                    }
+                }
+            }
+        }
+        liveness.alive.store(false, Ordering::SeqCst);
+        METRICS.disconnected();
+        if METRICS.connections() == 0 {
+            lifecycle::connection().disconnected();
+        }
+        // Nothing more will be answered on this connection, so its requests
+        // fail now rather than at their timeout and can be resent on another
+        pending.locked().retain(|_, (connection, _)| *connection != liveness.connection);
+    }
+
+    // Appends a length-prefixed frame for `message`, taking any file data out
+    // of it to send as the raw payload, compressed if `compress` and it helps
+    fn encode_frame(
+        encoding: Encoding,
+        compress: bool,
+        message: &mut FSMessage,
+        frame: &mut Vec<u8>,
+    ) -> Result<(), DaemonError> {
+        let start = frame.len();
+        frame.extend_from_slice(&[0u8; 4]);
+        if encoding == Encoding::Legacy {
+            serde_json::to_writer(&mut *frame, &message)?;
+        } else {
+            let payload = message.data.take();
+            let packed = match &payload {
+                Some(data) if compress && data.len() >= COMPRESSION_THRESHOLD => {
+                    Some(lz4_flex::block::compress(data)).filter(|packed| packed.len() < data.len())
+                }
+                _ => None,
+            };
+            if packed.is_some() {
+                message.compression = Some("lz4");
+                message.raw_size = payload.as_ref().map(|data| data.len() as u64);
+            }
+            let tag = if encoding == Encoding::MessagePack { MSGPACK_FRAME_TAG } else { BINARY_FRAME_TAG };
+            frame.push(tag);
+            frame.extend_from_slice(&[0u8; 4]);
+            let encoded: Result<(), DaemonError> = match encoding {
+                Encoding::MessagePack => rmp_serde::encode::write_named(&mut *frame, &message).map_err(Into::into),
+                _ => serde_json::to_writer(&mut *frame, &message).map_err(Into::into),
+            };
+            let header_length = (frame.len() - start - 9) as u32;
+            frame[start + 5..start + 9].copy_from_slice(&header_length.to_le_bytes());
+            if let Some(bytes) = packed.as_ref().or(payload.as_ref()) {
+                frame.extend_from_slice(bytes);
+            }
+            // Handed back so the caller can return it to the pool
+            message.data = payload;
+            encoded?;
+        }
+        let message_length = (frame.len() - start - 4) as u32;
+        frame[start..start + 4].copy_from_slice(&message_length.to_le_bytes());
+        Ok(())
+    }
+
+    // The responses in a frame, splitting batch frames into their parts
+    fn decode_responses(frame: &[u8]) -> Vec<FSResponse> {
+        if frame.first() != Some(&BATCH_FRAME_TAG) {
+            return Self::decode_response(frame).into_iter().collect();
+        }
+        let mut responses = Vec::new();
+        let mut rest = &frame[1..];
+        while let Some(length) = rest.get(..4) {
+            let length = u32::from_le_bytes(length.try_into().unwrap()) as usize;
+            let Some(inner) = rest.get(4..4 + length) else {
+                break;
+            };
+            responses.extend(Self::decode_response(inner));
+            rest = &rest[4 + length..];
+        }
+        responses
+    }
+
+    // Responses come back in whichever framing the request used, so all are accepted
+    fn decode_response(frame: &[u8]) -> Option<FSResponse> {
+        let tag = *frame.first()?;
+        if tag != BINARY_FRAME_TAG && tag != MSGPACK_FRAME_TAG {
+            return serde_json::from_slice(frame).ok();
+        }
+        let header_length = u32::from_le_bytes(frame.get(1..5)?.try_into().ok()?) as usize;
+        let header = frame.get(5..5 + header_length)?;
+        let payload = &frame[5 + header_length..];
+
+        let mut response: FSResponse = if tag == MSGPACK_FRAME_TAG {
+            rmp_serde::from_slice(header).ok()?
+        } else {
+            serde_json::from_slice(header).ok()?
+        };
+        let payload = match response.compression.as_deref() {
+            None => payload.to_vec(),
+            // The size is the DO's word, so it is held to what a message may carry
+            Some("lz4") if response.raw_size.unwrap_or(0) > MAX_MESSAGE_SIZE as u64 => {
+                response.error = format!("Compressed payload claims {} bytes", response.raw_size.unwrap_or(0));
+                Vec::new()
+            }
+            Some("lz4") => {
+                let raw_size = response.raw_size.unwrap_or(0) as usize;
+                lz4_flex::block::decompress(payload, raw_size).unwrap_or_else(|e| {
+                    response.error = format!("Failed to decompress payload: {}", e);
+                    Vec::new()
+                })
+            }
+            Some(other) => {
+                response.error = format!("Unknown payload compression '{}'", other);
+                Vec::new()
+            }
+        };
+        match response.payload.as_deref() {
+            Some("inline") => response.inline = Some(payload),
+            _ => response.data = payload,
+        }
+        Some(response)
+    }
+
+    // Sends queued frames in order. Small frames already waiting behind one
+    // another go out together as a batch frame, so a burst of metadata
+    // requests costs one write; nothing is held back waiting for company.
+    fn writer_loop(
+        mut stream: Conn,
+        mut queue: mpsc::Receiver<Vec<u8>>,
+        buffers: BufferPool,
+        batching: bool,
+        fragment_limit: Option<usize>,
+    ) {
+        let mut next = None;
+        while let Some(frame) = next.take().or_else(|| queue.blocking_recv()) {
+            let mut frames = vec![frame];
+            if batching && frames[0].len() <= BATCH_FRAME_LIMIT {
+                while frames.len() < BATCH_MAX_MESSAGES {
+                    match queue.try_recv() {
+                        Ok(frame) if frame.len() <= BATCH_FRAME_LIMIT => frames.push(frame),
+                        Ok(frame) => {
+                            next = Some(frame);
+                            break;
+                        }
+                        Err(_) => break,
+                    }
+                }
+            }
+
+            let result = if frames.len() == 1 {
+                Self::write_frame(&mut stream, &frames[0], fragment_limit)
+            } else {
+                let mut batch = buffers.take();
+                let length = frames.iter().map(Vec::len).sum::<usize>() + 1;
+                batch.extend_from_slice(&(length as u32).to_le_bytes());
+                batch.push(BATCH_FRAME_TAG);
+                for frame in &frames {
+                    batch.extend_from_slice(frame);
+                }
+                let result = Self::write_frame(&mut stream, &batch, fragment_limit);
+                buffers.give(batch);
+                result
+            };
+            for frame in frames {
+                buffers.give(frame);
+            }
+            if result.is_err() {
+                break;
+            }
+        }
+        // Wakes the reader, which marks the connection dead
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    // Writes one length-prefixed frame, splitting its body into fragment
+    // frames when the DO accepts no more than `limit` bytes at once
+    fn write_frame(stream: &mut Conn, frame: &[u8], limit: Option<usize>) -> std::io::Result<()> {
+        let body = &frame[4..];
+        let Some(limit) = limit.filter(|limit| body.len() > *limit) else {
+            return stream.write_all(frame);
+        };
+        let mut fragments = Vec::with_capacity(body.len() + body.len() / (limit - 2) * 6 + 6);
+        let mut pieces = body.chunks(limit - 2).peekable();
+        while let Some(piece) = pieces.next() {
+            fragments.extend_from_slice(&(piece.len() as u32 + 2).to_le_bytes());
+            fragments.push(FRAGMENT_FRAME_TAG);
+            fragments.push(pieces.peek().is_none() as u8);
+            fragments.extend_from_slice(piece);
+        }
+        stream.write_all(&fragments)
+    }
+
+    // Where pushes for the mount showing `prefix` of the DO's tree go
+    fn open_inbox(&self, prefix: &str) -> Arc<Inbox> {
+        self.inboxes.open(prefix)
+    }
+
+    // Has every mount drop its cached state before its next operation, as
+    // after a reconnect
+    fn flush_caches(&self) {
+        self.inboxes.resync();
+    }
+
+    fn pending_invalidations(&self) -> usize {
+        self.inboxes.pending()
+    }
+
+    fn request_timeout(&self, operation: FsOperation) -> Duration {
+        self.timeouts.locked().of(operation)
+    }
+
+    // Takes the request timeouts at once and hands each mount its cache
+    // policy, which it applies before its next operation
+    fn reload(&self, tunables: &Tunables) {
+        *self.timeouts.locked() = tunables.timeouts;
+        for namespace in self.namespaces.get().into_iter().flat_map(Namespaces::iter) {
+            *namespace.client.timeouts.locked() = tunables.timeouts;
+        }
+        self.inboxes.reload(&tunables.caches);
+    }
+
+    // Closes a connection the way the heartbeat does, so the DO opens another
+    fn drop_connection(&self, index: usize) -> bool {
+        let channels = self.channels.locked();
+        let Some(channel) = channels.get(index).filter(|channel| channel.liveness.is_alive()) else {
+            return false;
+        };
+        warn!("Closing connection {} to DO as asked", index);
+        channel.liveness.alive.store(false, Ordering::SeqCst);
+        let _ = channel.socket.shutdown(Shutdown::Both);
+        true
+    }
+
+    // Checks the requests awaiting a response every so often, logging each
+    // the first time it is found outstanding for longer than `threshold`
+    fn spawn_watchdog(self: &Arc<Self>, threshold: Duration) {
+        let client = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(threshold / 4);
+            let mut outstanding = client.outstanding.locked();
+            let mut stuck = 0;
+            let mut newly_stuck = 0;
+            for (id, request) in outstanding.iter_mut() {
+                let age = request.sent.elapsed();
+                if age < threshold {
+                    continue;
+                }
+                stuck += 1;
+                if !request.stuck {
+                    request.stuck = true;
+                    newly_stuck += 1;
+                    warn!(
+                        id,
+                        op = %request.operation,
+                        path = %request.path,
+                        connection = request.connection,
+                        cid = request.correlation.as_deref(),
+                        ?age,
+                        "Request stuck waiting for the DO"
+                    );
+                }
+            }
+            METRICS.stuck(newly_stuck, stuck);
+        });
+    }
+
+    // Removes pending requests whose caller has gone, such as one dropped
+    // while its frame was still being queued, before the guard that cancels
+    // it existed, so their entries don't stay forever
+    fn spawn_sweeper(self: &Arc<Self>) {
+        let client = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(PENDING_SWEEP_INTERVAL);
+            let mut reaped = Vec::new();
+            client.pending_requests.locked().retain(|id, (_, pending)| {
+                let abandoned = match pending {
+                    Pending::Single(sender) => sender.is_closed(),
+                    Pending::Stream(sender) => sender.is_closed(),
+                };
+                if abandoned {
+                    reaped.push(*id);
+                }
+                !abandoned
+            });
+            if reaped.is_empty() {
+                continue;
+            }
+            let mut outstanding = client.outstanding.locked();
+            for id in &reaped {
+                outstanding.remove(id);
+            }
+            METRICS.reaped(reaped.len());
+            debug!(count = reaped.len(), "Removed abandoned pending requests");
+        });
+    }
+
+    // Requests awaiting a response, oldest first
+    fn outstanding(&self) -> Vec<(u64, Outstanding)> {
+        let mut outstanding: Vec<_> =
+            self.outstanding.locked().iter().map(|(id, request)| (*id, request.clone())).collect();
+        outstanding.sort_by_key(|(id, _)| *id);
+        outstanding
+    }
+
+    async fn send_request(
+        &self,
+        operation: FsOperation,
+        path: &str,
+        data: Option<Vec<u8>>,
+        offset: Option<u64>,
+        size: Option<u64>,
+    ) -> Result<FSResponse, DaemonError> {
+        self.send_message(FSMessage {
+            operation,
+            path: path.to_string(),
+            data,
+            offset,
+            size,
+            ..Default::default()
+        })
+        .await
+    }
+
+    // Splits a large read into concurrent chunk requests and reassembles them
+    // in order, or asks for it as one streamed response when the DO can
+    async fn read_chunked(
+        &self,
+        path: &str,
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, DaemonError> {
+        if let Some((client, path)) = self.route(path) {
+            return Box::pin(client.read_chunked(&path, offset, size)).await;
+        }
+        // Sealed chunks are opened as they come back, so aren't streamed
+        if size > self.chunk_size as u64 && self.has_feature("stream-reads") && self.cipher.is_none() {
+            let (span, trace) = request_span(FsOperation::Read, path, Some(size));
+            let started = Instant::now();
+            // Reads change nothing, so a stream cut short is simply asked for again
+            let attempts = async {
+                let mut attempts = 0;
+                loop {
+                    match self.read_streamed(path, offset, size).await {
+                        Err(e) if attempts < self.retries() && e.is_transient() => {
+                            warn!("Retrying streamed read of {} after: {}", path, e);
+                            tokio::time::sleep(self.retry.delay(attempts)).await;
+                            attempts += 1;
+                        }
+                        result => return result,
+                    }
+                }
+            }
+            .instrument(span.clone());
+            let result = TraceContext::scope(trace, attempts).await;
+            log_finished(FsOperation::Read, &span, started, &result);
+            return result;
+        }
+        let chunk_size = self.chunk_size as u64;
+        let mut chunks = stream::iter((0..size).step_by(self.chunk_size).map(|start| {
+            let len = (size - start).min(chunk_size);
+            async move {
+                let response = self
+                    .send_request(FsOperation::Read, path, None, Some(offset + start), Some(len))
+                    .await;
+                (len, response)
+            }
+        }))
+        .buffered(self.parallelism);
+
+        let mut data = self.buffers.take();
+        while let Some((len, response)) = chunks.next().await {
+            let response = response?;
+            // Damage on the way surfaces as an I/O error rather than as file content
+            if response.checksum.is_some_and(|checksum| checksum != crc32fast::hash(&response.data)) {
+                self.buffers.give(data);
+                return Err(format!("Checksum mismatch reading {}", path).into());
+            }
+            data.extend_from_slice(&response.data);
+            let short = (response.data.len() as u64) < len;
+            self.buffers.give(response.data);
+            // A short chunk means end of file, so anything after it is empty
+            if short {
+                break;
+            }
+        }
+        Ok(data)
+    }
+
+    // Sends `data` at `offset` as concurrent checksum-verified chunks. Rewriting
+    // a chunk at the same offset is harmless, so chunks can be retried out of
+    // order; returns how many leading bytes were verified and the error that
+    // stopped the transfer, if any.
+    async fn write_chunked(&self, path: &str, offset: u64, data: &[u8]) -> (usize, Option<DaemonError>) {
+        if let Some((client, path)) = self.route(path) {
+            return Box::pin(client.write_chunked(&path, offset, data)).await;
+        }
+        // Sealed writes rewrite the chunks they touch themselves
+        if self.cipher.is_some() {
+            let message = FSMessage {
+                operation: FsOperation::Write,
+                path: path.to_string(),
+                data: Some(data.to_vec()),
+                offset: Some(offset),
+                ..Default::default()
+            };
+            return match self.send_message(message).await {
+                Ok(_) => (data.len(), None),
+                Err(e) => (0, Some(e)),
+            };
+        }
+        // Chunks the DO turns away, such as one deleted since it said it had
+        // it, leave the write to go the usual way
+        if self.dedup && data.len() > dedup::MIN_CHUNK && self.has_feature("dedup") {
+            match self.write_deduped(path, offset, data).await {
+                Ok(()) => return (data.len(), None),
+                Err(e) => debug!("Writing {} without deduplication: {}", path, e),
+            }
+        }
+        if data.len() > self.chunk_size && self.has_feature("stream-writes") {
+            return match self.write_streamed(path, offset, data).await {
+                Ok(()) => (data.len(), None),
+                Err(e) => (0, Some(e)),
+            };
+        }
+        let mut chunks = stream::iter(data.chunks(self.chunk_size).enumerate().map(
+            |(i, bytes)| {
+                let mut chunk = self.buffers.take();
+                chunk.extend_from_slice(bytes);
+                let checksum = crc32fast::hash(bytes);
+                let message = FSMessage {
+                    operation: FsOperation::Write,
+                    path: path.to_string(),
+                    data: Some(chunk),
+                    offset: Some(offset + (i * self.chunk_size) as u64),
+                    checksum: Some(checksum),
+                    ..Default::default()
+                };
+                async move { (bytes.len(), checksum, self.send_message(message).await) }
+            },
+        ))
+        .buffered(self.parallelism);
+
+        let mut verified = 0;
+        while let Some((len, checksum, response)) = chunks.next().await {
+            match response {
+                Ok(response) if self.write_acknowledged(&response, len, checksum) => verified += len,
+                Ok(_) => return (verified, Some(format!("Checksum mismatch writing {}", path).into())),
+                Err(e) => return (verified, Some(e)),
+            }
+        }
+        (verified, None)
+    }
+
+    // Sends `data` at `offset` as content-defined chunks, uploading only those
+    // the DO doesn't have, then has the DO write the range from them
+    async fn write_deduped(&self, path: &str, offset: u64, data: &[u8]) -> Result<(), DaemonError> {
+        let chunks = dedup::chunks(data);
+        let hashes: Vec<String> = chunks.iter().map(|chunk| dedup::hash(chunk)).collect();
+        let asked = FSMessage { operation: FsOperation::HasChunks, chunks: Some(hashes.clone()), ..Default::default() };
+        let missing: HashSet<String> = self.send_message(asked).await?.chunks.into_iter().collect();
+        // A chunk that repeats within the write goes once
+        let mut uploads: HashMap<&str, &[u8]> = HashMap::new();
+        for (hash, chunk) in hashes.iter().zip(&chunks).filter(|(hash, _)| missing.contains(*hash)) {
+            uploads.insert(hash, chunk);
+        }
+        let sent: usize = uploads.values().map(|chunk| chunk.len()).sum();
+        let mut puts = stream::iter(uploads.into_iter().map(|(hash, chunk)| {
+            let checksum = crc32fast::hash(chunk);
+            let message = FSMessage {
+                operation: FsOperation::PutChunk,
+                data: Some(chunk.to_vec()),
+                checksum: Some(checksum),
+                chunks: Some(vec![hash.to_string()]),
+                ..Default::default()
+            };
+            async move { (chunk.len(), checksum, self.send_message(message).await) }
+        }))
+        .buffered(self.parallelism);
+        while let Some((len, checksum, response)) = puts.next().await {
+            if !self.write_acknowledged(&response?, len, checksum) {
+                return Err(format!("Checksum mismatch uploading a chunk of {}", path).into());
+            }
+        }
+        let checksum = crc32fast::hash(data);
+        let message = FSMessage {
+            operation: FsOperation::WriteChunks,
+            path: path.to_string(),
+            offset: Some(offset),
+            checksum: Some(checksum),
+            chunks: Some(hashes.clone()),
+            ..Default::default()
+        };
+        if !self.write_acknowledged(&self.send_message(message).await?, data.len(), checksum) {
+            return Err(format!("Checksum mismatch writing {}", path).into());
+        }
+        METRICS.deduplicated(sent, data.len() - sent);
+        Ok(())
+    }
+
+    // Whether the DO stored `len` bytes sent with `checksum`. A DO that agreed
+    // to `checksums` echoes the checksum of what it stored; from any other, all
+    // there is to go on is the byte count it reports.
+    fn write_acknowledged(&self, response: &FSResponse, len: usize, checksum: u32) -> bool {
+        match self.has_feature("checksums") {
+            true => response.checksum == Some(checksum),
+            false => response.bytes_written == len as u64,
+        }
+    }
+
+    // Sends a range as write-begin, chunks with at most STREAM_WRITE_WINDOW of
+    // them unacknowledged, then write-end. The DO stages the chunks and stores
+    // them together at the end, so the range lands whole or not at all.
+    async fn write_streamed(&self, path: &str, offset: u64, data: &[u8]) -> Result<(), DaemonError> {
+        let begin = FSMessage {
+            operation: FsOperation::WriteBegin,
+            path: path.to_string(),
+            offset: Some(offset),
+            size: Some(data.len() as u64),
+            ..Default::default()
+        };
+        let transfer = self
+            .send_message(begin)
+            .await?
+            .transfer
+            .ok_or_else(|| DaemonError::Protocol("DO did not open a write stream".to_string()))?;
+
+        let mut acks = stream::iter(data.chunks(self.chunk_size).enumerate().map(|(i, bytes)| {
+            let mut chunk = self.buffers.take();
+            chunk.extend_from_slice(bytes);
+            let checksum = crc32fast::hash(bytes);
+            let message = FSMessage {
+                operation: FsOperation::WriteChunk,
+                path: path.to_string(),
+                data: Some(chunk),
+                offset: Some(offset + (i * self.chunk_size) as u64),
+                checksum: Some(checksum),
+                transfer: Some(transfer),
+                ..Default::default()
+            };
+            async move { (bytes.len(), checksum, self.send_message(message).await) }
+        }))
+        .buffered(STREAM_WRITE_WINDOW);
+
+        let mut result = Ok(());
+        while let Some((len, checksum, response)) = acks.next().await {
+            result = match response {
+                Ok(response) if self.write_acknowledged(&response, len, checksum) => Ok(()),
+                Ok(_) => Err(format!("Checksum mismatch writing {}", path).into()),
+                Err(e) => Err(e),
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+
+        let checksum = crc32fast::hash(data);
+        let end = FSMessage {
+            operation: if result.is_ok() { FsOperation::WriteEnd } else { FsOperation::WriteAbort },
+            path: path.to_string(),
+            checksum: Some(checksum),
+            transfer: Some(transfer),
+            ..Default::default()
+        };
+        let response = self.send_message(end).await;
+        result?;
+        match response {
+            Ok(response) if self.write_acknowledged(&response, data.len(), checksum) => Ok(()),
+            Ok(_) => Err(format!("Checksum mismatch writing {}", path).into()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Reads a range with a single request that the DO answers chunk by chunk,
+    // appending each chunk as it arrives so no frame holds the whole range
+    async fn read_streamed(&self, path: &str, offset: u64, size: u64) -> Result<Vec<u8>, DaemonError> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let message = FSMessage {
+            operation: FsOperation::Read,
+            path: path.to_string(),
+            offset: Some(offset),
+            size: Some(size),
+            stream: Some(true),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let permit = self.in_flight.acquire().await?;
+        let id = self.enqueue(message, Pending::Stream(tx)).await?;
+        let _in_flight = InFlight::new(self, id, permit);
+        let queued = Instant::now();
+
+        let mut data = self.buffers.take();
+        let interrupted = interrupt::interrupted();
+        tokio::pin!(interrupted);
+        loop {
+            let chunk: Result<FSResponse, DaemonError> = tokio::select! {
+                received = tokio::time::timeout(self.request_timeout(FsOperation::Read), rx.recv()) => match received {
+                    Ok(Some(chunk)) => Ok(chunk),
+                    Ok(None) => Err(self.connection_lost()),
+                    Err(_) => Err(DaemonError::TimedOut),
+                },
+                // Returning drops the request, which cancels it
+                _ = &mut interrupted => Err(DaemonError::Interrupted),
+            };
+            let chunk = chunk
+                .and_then(|chunk| match chunk.error.is_empty() {
+                    true => Ok(chunk),
+                    false => Err(DaemonError::Remote { message: chunk.error, errno: chunk.errno }),
+                })
+                .and_then(|chunk| match chunk.checksum {
+                    Some(checksum) if checksum != crc32fast::hash(&chunk.data) => {
+                        Err(format!("Checksum mismatch reading {}", path).into())
+                    }
+                    _ => Ok(chunk),
+                });
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    self.buffers.give(data);
+                    return Err(e);
+                }
+            };
+            data.extend_from_slice(&chunk.data);
+            self.buffers.give(chunk.data);
+            if !chunk.more {
+                // The last chunk's timing covers the whole read
+                let latency = Latency {
+                    queue: queued - started,
+                    round_trip: queued.elapsed(),
+                    in_do: chunk.timing.as_ref().map(Timing::in_do),
+                };
+                latency.record(FsOperation::Read, path, 0, data.len());
+                return Ok(data);
+            }
+        }
+    }
+
+    // Sends a request, sealing and opening file contents on the way when
+    // they are encrypted
+    async fn send_message(&self, mut message: FSMessage) -> Result<FSResponse, DaemonError> {
+        if let Some((client, path)) = self.route(&message.path) {
+            message.path = path;
+            return Box::pin(client.send_message(message)).await;
+        }
+        let Some(cipher) = &self.cipher else {
+            return self.send_plain(message).await;
+        };
+        match message.operation {
+            FsOperation::Read | FsOperation::SnapshotRead => self.read_sealed(cipher, message).await,
+            FsOperation::Write => self.write_sealed(cipher, message).await,
+            FsOperation::Stat => {
+                let path = message.path.clone();
+                let mut response = self.send_plain(message).await?;
+                if let Some(stat) = response.stat.as_mut().filter(|stat| stat.is_file) {
+                    stat.size = plain_size(stat.size);
+                }
+                // Inline content is opened here, so the caches only see plaintext
+                if let Some(inline) = response.inline.take() {
+                    let intact = response.checksum.map_or(true, |checksum| checksum == crc32fast::hash(&inline));
+                    response.inline = intact.then(|| cipher.open_chunks(&path, 0, &inline).ok()).flatten();
+                    response.checksum = None;
+                }
+                Ok(response)
+            }
+            FsOperation::Snapshots => {
+                let mut response = self.send_plain(message).await?;
+                for entry in response.entries.iter_mut().filter(|entry| !entry.is_dir) {
+                    entry.size = plain_size(entry.size);
+                }
+                Ok(response)
+            }
+            _ => self.send_plain(message).await,
+        }
+    }
+
+    // Reads the sealed chunks a range covers and answers with the plaintext
+    async fn read_sealed(&self, cipher: &Cipher, mut message: FSMessage) -> Result<FSResponse, DaemonError> {
+        let (offset, size) = (message.offset.unwrap_or(0), message.size.unwrap_or(0));
+        let first = offset / CHUNK_SIZE;
+        message.offset = Some(first * SEALED_CHUNK_SIZE);
+        message.size = Some(((offset + size).div_ceil(CHUNK_SIZE) - first) * SEALED_CHUNK_SIZE);
+        let path = message.path.clone();
+        let mut response = self.send_plain(message).await?;
+        if response.checksum.is_some_and(|checksum| checksum != crc32fast::hash(&response.data)) {
+            return Err(format!("Checksum mismatch reading {}", path).into());
+        }
+        let mut data = cipher.open_chunks(&path, first, &response.data)?;
+        data.drain(..((offset - first * CHUNK_SIZE) as usize).min(data.len()));
+        data.truncate(size as usize);
+        self.buffers.give(std::mem::replace(&mut response.data, data));
+        response.checksum = None;
+        Ok(response)
+    }
+
+    // Writes a range as whole sealed chunks. Those it covers only in part are
+    // read and opened first, and a gap it leaves past the end of the file is
+    // filled with zeros, as the DO would.
+    async fn write_sealed(&self, cipher: &Cipher, message: FSMessage) -> Result<FSResponse, DaemonError> {
+        let _sealing = self.sealing.lock().await;
+        let path = message.path.as_str();
+        let data = message.data.as_deref().unwrap_or_default();
+        let (first, plain) = match message.offset {
+            None => (0, data.to_vec()),
+            // Nothing to write, and no gap to fill
+            Some(_) if data.is_empty() => {
+                let message = FSMessage { data: Some(vec![]), offset: Some(0), ..message.clone() };
+                return self.send_plain(message).await;
+            }
+            Some(offset) => {
+                let size = match self.send_plain(FSMessage { path: path.to_string(), ..Default::default() }).await {
+                    Ok(response) => response.stat.map_or(0, |stat| plain_size(stat.size)),
+                    Err(e) if e.errno() == libc::ENOENT => 0,
+                    Err(e) => return Err(e),
+                };
+                let first = offset.min(size) / CHUNK_SIZE;
+                let start = first * CHUNK_SIZE;
+                let end = offset + data.len() as u64;
+                let tail = (end.div_ceil(CHUNK_SIZE) * CHUNK_SIZE).min(size);
+                let mut plain = self.read_plain(cipher, path, start, offset.min(size) - start).await?;
+                plain.resize((offset - start) as usize, 0);
+                plain.extend_from_slice(data);
+                if tail > end {
+                    plain.extend_from_slice(&self.read_plain(cipher, path, end, tail - end).await?);
+                }
+                (first, plain)
+            }
+        };
+
+        let mut sealed = Vec::new();
+        for (i, chunk) in plain.chunks(CHUNK_SIZE as usize).enumerate() {
+            sealed.push(cipher.seal(path, first + i as u64, chunk)?);
+        }
+        let sealed_write = |index: usize, sealed: Vec<u8>| FSMessage {
+            operation: FsOperation::Write,
+            path: path.to_string(),
+            checksum: Some(crc32fast::hash(&sealed)),
+            data: Some(sealed),
+            // Replacing the file truncates it with the first chunk
+            offset: (message.offset.is_some() || index > 0).then_some((first + index as u64) * SEALED_CHUNK_SIZE),
+            ..Default::default()
+        };
+        let acknowledged = |response: &FSResponse, message: &FSMessage| {
+            let sealed = message.data.as_deref().unwrap_or_default();
+            self.write_acknowledged(response, sealed.len(), crc32fast::hash(sealed))
+        };
+        let mut sealed = sealed.into_iter().enumerate();
+        let head = sealed_write(0, sealed.next().map(|(_, chunk)| chunk).unwrap_or_default());
+        let mut response = self.send_plain(head.clone()).await?;
+        if !acknowledged(&response, &head) {
+            return Err(format!("Checksum mismatch writing {}", path).into());
+        }
+        let mut rest = stream::iter(sealed.map(|(index, chunk)| {
+            let message = sealed_write(index, chunk);
+            async move { (self.send_plain(message.clone()).await, message) }
+        }))
+        .buffered(self.parallelism);
+        while let Some((sent, message)) = rest.next().await {
+            match sent {
+                Ok(sent) if acknowledged(&sent, &message) => response = sent,
+                Ok(_) => return Err(format!("Checksum mismatch writing {}", path).into()),
+                Err(e) => return Err(e),
+            }
+        }
+        // As the DO would answer for the plaintext
+        response.bytes_written = data.len() as u64;
+        response.checksum = Some(crc32fast::hash(data));
+        Ok(response)
+    }
+
+    // Plaintext of a range of a sealed file, for the chunk a write rewrites
+    async fn read_plain(&self, cipher: &Cipher, path: &str, offset: u64, size: u64) -> Result<Vec<u8>, DaemonError> {
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        let message = FSMessage {
+            operation: FsOperation::Read,
+            path: path.to_string(),
+            offset: Some(offset),
+            size: Some(size),
+            ..Default::default()
+        };
+        Ok(self.read_sealed(cipher, message).await?.data)
+    }
+
+    async fn send_plain(&self, mut message: FSMessage) -> Result<FSResponse, DaemonError> {
+        let size = message.size.or(message.data.as_ref().map(|data| data.len() as u64));
+        let operation = message.operation;
+        let (span, trace) = request_span(operation, &message.path, size);
+        let started = Instant::now();
+        // Assigned once, so every resend of a write carries the same number
+        let seq = self.next_write_seq(&mut message);
+        let path = seq.map(|_| message.path.clone());
+        let range = (message.offset.unwrap_or(0), message.size.unwrap_or(u64::MAX));
+        let mut result = TraceContext::scope(trace, self.send_retrying(message).instrument(span.clone())).await;
+        // Holes are filled before anything looks at the data, checksums included
+        let filled = match &mut result {
+            Ok(response) if !response.holes.is_empty() => {
+                Some(sparse::fill(range.0, range.1, &response.data, &response.holes).map(|data| {
+                    METRICS.sparse_read(sparse::hole_bytes(&response.holes));
+                    response.data = data;
+                }))
+            }
+            _ => None,
+        };
+        if let Some(Err(e)) = filled {
+            result = Err(format!("The DO's holes don't fit the read: {}", e).into());
+        }
+        if let (Some(seq), Some(path), Ok(_)) = (seq, path, &result) {
+            let mut write_seqs = self.write_seqs.locked();
+            let acked = &mut write_seqs.entry(path).or_default().1;
+            *acked = (*acked).max(seq);
+        }
+        log_finished(operation, &span, started, &result);
+        result
+    }
+
+    // Numbers the writes that commit data to a file, when the DO agreed to `sync`
+    fn next_write_seq(&self, message: &mut FSMessage) -> Option<u64> {
+        let commits =
+            matches!(message.operation, FsOperation::Write | FsOperation::WriteEnd | FsOperation::WriteChunks);
+        if !commits || !self.has_feature("sync") {
+            return None;
+        }
+        let mut write_seqs = self.write_seqs.locked();
+        let last = &mut write_seqs.entry(message.path.clone()).or_default().0;
+        *last += 1;
+        message.seq = Some(*last);
+        message.seq
+    }
+
+    // Has the DO confirm that every write to the file it acknowledged is
+    // durable. Callers flush the file's buffered writes first, so that covers
+    // every write made before the call.
+    async fn sync(&self, path: &str) -> Result<(), DaemonError> {
+        if let Some((client, path)) = self.route(path) {
+            return Box::pin(client.sync(&path)).await;
+        }
+        if !self.has_feature("sync") {
+            return Ok(());
+        }
+        let Some(&(_, acked)) = self.write_seqs.locked().get(path).filter(|(_, acked)| *acked > 0) else {
+            return Ok(());
+        };
+        self.send_message(FSMessage {
+            operation: FsOperation::Sync,
+            path: path.to_string(),
+            seq: Some(acked),
+            ..Default::default()
+        })
+        .await?;
+        Ok(())
+    }
+
+    // Sends a request and waits for its response, resending it when the
+    // response is lost, as when its connection drops. Mutations get an
+    // idempotency key when the DO dedupes and are resent under it; without one
+    // they can't safely be resent and fail instead.
+    async fn send_retrying(
+        &self,
+        mut message: FSMessage,
+    ) -> Result<FSResponse, DaemonError> {
+        if message.operation.is_mutation() {
+            if !self.has_feature("idempotency") {
+                return self.send_once(message).await;
+            }
+            let key = self.next_idempotency_key.fetch_add(1, Ordering::Relaxed);
+            message.idempotency_key = Some(format!("{}-{}", self.session, key));
+        }
+
+        let mut attempts = 0;
+        loop {
+            let retry = (attempts < self.retries()).then(|| message.clone());
+            match (self.send_once(message).await, retry) {
+                (Err(e), Some(retry)) if e.is_transient() => {
+                    warn!("Retrying {} of {} after: {}", retry.operation, retry.path, e);
+                    tokio::time::sleep(self.retry.delay(attempts)).await;
+                    attempts += 1;
+                    message = retry;
+                }
+                (result, _) => return result,
+            }
+        }
+    }
+
+    async fn send_once(&self, message: FSMessage) -> Result<FSResponse, DaemonError> {
+        let (tx, rx) = oneshot::channel();
+        let operation = message.operation;
+        let path = message.path.clone();
+        let sent = message.data.as_ref().map_or(0, Vec::len);
+        // Queue time runs until the request is handed to a connection
+        let started = Instant::now();
+        let permit = self.in_flight.acquire().await?;
+        let id = self.enqueue(message, Pending::Single(tx)).await?;
+        let _in_flight = InFlight::new(self, id, permit);
+        let queued = Instant::now();
+
+        let received = tokio::select! {
+            received = tokio::time::timeout(self.request_timeout(operation), rx) => received,
+            // Returning drops the request, which cancels it
+            _ = interrupt::interrupted() => return Err(DaemonError::Interrupted),
+        };
+        match received {
+            Ok(Ok(response)) => {
+                let latency = Latency {
+                    queue: queued - started,
+                    round_trip: queued.elapsed(),
+                    in_do: response.timing.as_ref().map(Timing::in_do),
+                };
+                latency.record(operation, &path, sent, response.data.len());
+                if response.correlation.is_some() && response.correlation != Correlation::current() {
+                    let cid = response.correlation.as_deref();
+                    warn!(cid, "DO answered {} of {} as part of another operation", operation, path);
+                }
+                if !response.error.is_empty() {
+                    return Err(DaemonError::Remote { message: response.error, errno: response.errno });
+                }
+                Ok(response)
+            }
+            Ok(Err(_)) => Err(self.connection_lost()),
+            Err(_) => Err(DaemonError::TimedOut),
+        }
+    }
+
+    // Gives the message an id, registers where its responses go and queues it
+    // for a connection, returning the id
+    async fn enqueue(&self, mut message: FSMessage, pending: Pending) -> Result<u64, DaemonError> {
+        if let Some(field) = message.missing_field() {
+            return Err(format!("{} request for '{}' without {}", message.operation, message.path, field).into());
+        }
+        let id = {
+            let mut request_id = self.request_id.locked();
+            *request_id += 1;
+            *request_id
+        };
+        message.id = id;
+        tracing::Span::current().record("id", id);
+        if self.has_feature("trace-context") {
+            message.traceparent = TraceContext::current().map(|trace| trace.traceparent());
+        }
+        let correlation = Correlation::current();
+        if self.has_feature("correlation") {
+            message.correlation = correlation.clone();
+        }
+
+        // With every connection down, wait for the DO to open another, for a
+        // while or on a hard mount for good, unless too many already are
+        let deadline = Instant::now() + self.disconnect.timeout;
+        let mut waiting = None;
+        let (outgoing, encoding, compress, connection) = loop {
+            if let Some(channel) = self.pick_channel(&message) {
+                break channel;
+            }
+            if waiting.is_none() {
+                waiting = Waiting::join(&self.waiting, self.disconnect.queue);
+            }
+            if waiting.is_none() || (!self.disconnect.hard && Instant::now() >= deadline) {
+                return Err(self.disconnected("Not connected to DO"));
+            }
+            tokio::time::sleep(RECONNECT_POLL).await;
+        };
+        drop(waiting);
+        self.pending_requests.locked().insert(id, (connection, pending));
+        let outstanding = Outstanding {
+            operation: message.operation,
+            path: message.path.clone(),
+            connection,
+            sent: Instant::now(),
+            correlation,
+            stuck: false,
+        };
+        self.outstanding.locked().insert(id, outstanding);
+
+        // Serialize straight into a pooled frame
+        let mut frame = self.buffers.take();
+        let encoded = Self::encode_frame(encoding, compress, &mut message, &mut frame);
+        if let Some(data) = message.data.take() {
+            self.buffers.give(data);
+        }
+        if let Err(e) = encoded {
+            self.pending_requests.locked().remove(&id);
+            self.outstanding.locked().remove(&id);
+            return Err(e);
+        }
+
+        if outgoing.send(frame).await.is_err() {
+            self.pending_requests.locked().remove(&id);
+            self.outstanding.locked().remove(&id);
+            return Err(self.connection_lost());
+        }
+        Ok(id)
+    }
+
+    // Abandons a request that hasn't been fully answered: its responses are
+    // dropped from now on, and a DO that agreed to `cancel` is told to skip it
+    // if it hasn't started, or to stop streaming it
+    fn cancel(&self, id: u64) {
+        self.outstanding.locked().remove(&id);
+        if self.pending_requests.locked().remove(&id).is_none() || !self.has_feature("cancel") {
+            return;
+        }
+        let mut message = FSMessage {
+            operation: FsOperation::Cancel,
+            cancel: Some(id),
+            ..Default::default()
+        };
+        let Some((outgoing, encoding, compress, _)) = self.pick_channel(&message) else {
+            return;
+        };
+        let mut frame = Vec::new();
+        if Self::encode_frame(encoding, compress, &mut message, &mut frame).is_ok() {
+            // Best effort: a full queue means the DO is behind anyway
+            let _ = outgoing.try_send(frame);
+        }
+    }
+}
+
+// Cancels a request when dropped before its response arrived, which covers
+// timeouts, callers interrupted by a signal and the sibling chunks of a
+// transfer that already failed, and frees its place in the in-flight window
+// either way
+struct InFlight<'a> {
+    client: &'a RemoteFSClient,
+    id: u64,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<'a> InFlight<'a> {
+    fn new(client: &'a RemoteFSClient, id: u64, permit: SemaphorePermit<'a>) -> Self {
+        METRICS.request_started();
+        Self {
+            client,
+            id,
+            _permit: permit,
+        }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        METRICS.request_ended();
+        self.client.cancel(self.id);
+    }
+}
+
+// Maps kernel inode numbers to remote paths and back. Entries are only
+// created when the kernel learns about a path, and those the kernel holds no
+// lookup reference to are evicted once the table grows past MAX_INODES, so
+// memory follows the working set rather than the namespace.
+struct InodeTable {
+    entries: HashMap<u64, InodeEntry>,
+    inodes: InodeIndex,
+    next_ino: u64,
+}
+
+struct InodeEntry {
+    path: String,
+    // Lookup references held by the kernel, released through forget
+    lookups: u64,
+}
+
+impl InodeTable {
+    // Inode 1 is `root`, the path on the DO the mount shows
+    fn new(root: &str) -> Self {
+        let mut table = Self {
+            entries: HashMap::new(),
+            inodes: InodeIndex::default(),
+            next_ino: 2,
+        };
+        table.entries.insert(
+            1,
+            InodeEntry {
+                path: root.to_string(),
+                lookups: 1,
+            },
+        );
+        table.inodes.locked().insert(root.to_string(), 1);
+        table
+    }
+
+    // The path to inode index, for the kernel's invalidations
+    fn index(&self) -> InodeIndex {
+        self.inodes.clone()
+    }
+
+    fn path(&self, ino: u64) -> Option<String> {
+        self.entries.get(&ino).map(|entry| entry.path.clone())
+    }
+
+    fn child_path(&self, parent: u64, name: &str) -> Option<String> {
+        let parent = &self.entries.get(&parent)?.path;
+        Some(join(parent, name))
+    }
+
+    // Inode for a path the kernel is only told about in passing, e.g. readdir
+    fn assign(&mut self, path: &str) -> u64 {
+        if let Some(&ino) = self.inodes.locked().get(path) {
+            return ino;
+        }
+        self.evict_unreferenced();
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.entries.insert(
+            ino,
+            InodeEntry {
+                path: path.to_string(),
+                lookups: 0,
+            },
+        );
+        self.inodes.locked().insert(path.to_string(), ino);
+        ino
+    }
+
+    // Inode for a path returned in a lookup/create reply, which the kernel keeps a reference to
+    fn lookup(&mut self, path: &str) -> u64 {
+        let ino = self.assign(path);
+        if let Some(entry) = self.entries.get_mut(&ino) {
+            entry.lookups += 1;
+        }
+        ino
+    }
+
+    fn forget(&mut self, ino: u64, nlookup: u64) {
+        if ino == 1 {
+            return;
+        }
+        let Some(entry) = self.entries.get_mut(&ino) else {
+            return;
+        };
+        entry.lookups = entry.lookups.saturating_sub(nlookup);
+        if entry.lookups == 0 {
+            if let Some(entry) = self.entries.remove(&ino) {
+                self.inodes.locked().remove(&entry.path);
+            }
+        }
+    }
+
+    fn evict_unreferenced(&mut self) {
+        if self.entries.len() < MAX_INODES {
+            return;
+        }
+        // Drop down to 90% so eviction isn't repeated on every new entry
+        let target = MAX_INODES * 9 / 10;
+        let cold: Vec<u64> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.lookups == 0)
+            .map(|(ino, _)| *ino)
+            .take(self.entries.len().saturating_sub(target))
+            .collect();
+        for ino in cold {
+            if let Some(entry) = self.entries.remove(&ino) {
+                self.inodes.locked().remove(&entry.path);
+            }
+        }
+    }
+}
+
+// Per-handle readahead state: where the last read ended and what was prefetched
+struct Readahead {
+    path: String,
+    next_offset: u64,
+    buffer_offset: u64,
+    buffer: Vec<u8>,
+    eof: bool,
+}
+
+impl Readahead {
+    fn serve(&self, offset: u64, size: u64) -> Option<&[u8]> {
+        let end = self.buffer_offset + self.buffer.len() as u64;
+        if offset < self.buffer_offset || offset > end {
+            return None;
+        }
+        // A read running past the buffer is only complete if the buffer reached EOF
+        if offset + size > end && !self.eof {
+            return None;
+        }
+        let start = (offset - self.buffer_offset) as usize;
+        let stop = ((offset + size).min(end) - self.buffer_offset) as usize;
+        Some(&self.buffer[start..stop])
+    }
+}
+
+// Writes buffered on an open handle, sent once flushed. Chunks are only
+// dropped from `data` after the DO confirms their checksum, so a flush that
+// fails part way resumes from the last verified chunk on the next attempt.
+struct PendingWrite {
+    path: String,
+    offset: u64,
+    data: Vec<u8>,
+    // Journal records of the writes buffered, marked done once they are sent
+    journaled: Vec<u64>,
+}
+
+// Machine-readable description of the running daemon for orchestration scripts
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StatusReport {
+    daemon: &'static str,
+    version: &'static str,
+    state: &'static str,
+    mount_point: String,
+    transport: TransportStatus,
+    // Negotiated in the hello, absent for DOs that predate it
+    protocol_version: Option<u32>,
+    features: Vec<&'static str>,
+    limits: LimitsStatus,
+}
+
+#[derive(Serialize)]
+struct TransportStatus {
+    kind: String,
+    address: String,
+    tls: bool,
+    encryption: bool,
+    routing: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LimitsStatus {
+    max_read: u32,
+    max_write: u32,
+    max_readahead: u32,
+    inline_limit: u64,
+    max_in_flight: usize,
+    max_frame_size: usize,
+}
+
+impl StatusReport {
+    fn emit(&self, target: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let document = serde_json::to_string(self)?;
+        if target == "-" {
+            println!("{}", document);
+            return Ok(());
+        }
+        // Write then rename so readers never observe a partial document
+        let staging = format!("{}.tmp", target);
+        std::fs::write(&staging, format!("{}\n", document))?;
+        std::fs::rename(&staging, target)?;
+        Ok(())
+    }
+}
+
+struct RemoteFS {
+    client: Arc<RemoteFSClient>,
+    // Where the DO's pushes and reloads for this mount arrive
+    inbox: Arc<Inbox>,
+    mount_point: String,
+    status_json: Option<String>,
+    next_fh: Arc<Mutex<u64>>,
+    inodes: InodeTable,
+    write_buffers: HashMap<u64, PendingWrite>,
+    inline_cache: HashMap<String, Vec<u8>>,
+    readahead: HashMap<u64, Readahead>,
+    block_cache: Arc<Mutex<BlockCache>>,
+    // Content version of each path as of its last stat, used to key the block cache
+    versions: HashMap<String, u64>,
+    dir_cache: DirCache,
+    writeback: Option<Arc<WriteBack>>,
+    consistency: Consistency,
+    // Last stat of each path, which close-to-open mode serves attributes from until the next open
+    stats: HashMap<String, FileStat>,
+    leases: Option<Arc<Leases>>,
+    spill: Option<Arc<SpillDir>>,
+    journal: Option<Arc<Journal>>,
+    // Journaled writes left from before a crash, replayed once the main mount is up
+    unreplayed: Vec<JournalEntry>,
+    unsupported: UnsupportedPolicy,
+    names: NamePolicy,
+    ownership: Ownership,
+    xattrs: XattrStore,
+    special_nodes: HashMap<String, SpecialNode>,
+    // The read-only `.snapshots` tree, for DOs that agreed to `snapshots`
+    snapshots: Snapshots,
+    // The `.fsctl` file applications control the mount through
+    fsctl: ControlFile,
+    // The session's notifier, once it is mounted, for the kernel's invalidations
+    notifier: Arc<OnceLock<fuser::Notifier>>,
+    post_mount_exec: Option<String>,
+    kernel_cache: KernelCache,
+    max_read: usize,
+    audit: Option<AuditLog>,
+    // The quotas of every mount, when there are any
+    quotas: Option<Arc<Quotas>>,
+    // Largest size a file may reach through the mount
+    max_file_size: Option<u64>,
+    read_only: bool,
+    readahead_window: usize,
+    readahead_memory: usize,
+}
+
+// Mounts a filesystem in the background, handing it the session's notifier
+fn spawn_mount(fs: RemoteFS, mount: &Mount, options: &[MountOption]) -> io::Result<fuser::BackgroundSession> {
+    let notifier = fs.notifier.clone();
+    let session = fuser::spawn_mount2(fs, &mount.mount_point, options)?;
+    let _ = notifier.set(session.notifier());
+    Ok(session)
+}
+
+// The path of `name` in the directory at `parent`
+fn join(parent: &str, name: &str) -> String {
+    if parent == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+// The client every mount shares, with its background threads started and
+// the namespaces grafted onto it, each connected over a client of its own
+fn connect(options: &MountOptions) -> Result<Arc<RemoteFSClient>, Box<dyn std::error::Error>> {
+    let client = connect_to(options, &options.transport)?;
+    if !options.namespaces.is_empty() {
+        let mut namespaces = Vec::new();
+        for spec in &options.namespaces {
+            let endpoint = spec.endpoint.clone().unwrap_or_else(|| options.transport.endpoint.clone());
+            info!(prefix = %spec.prefix, "Connecting namespace {}", spec.name);
+            let transport = Transport { endpoint, ..options.transport.clone() };
+            namespaces.push((spec.clone(), connect_to(options, &transport)?));
+        }
+        client.graft(Namespaces::new(&options.mounts[0].prefix, namespaces));
+    }
+    Ok(client)
+}
+
+fn connect_to(
+    options: &MountOptions,
+    transport: &Transport,
+) -> Result<Arc<RemoteFSClient>, Box<dyn std::error::Error>> {
+    let client = Arc::new(RemoteFSClient::new(
+        options.encoding,
+        options.compression,
+        options.connections,
+        options.heartbeat,
+        options.max_in_flight,
+        options.max_frame_size,
+        transport,
+    )?);
+    client.spawn_sweeper();
+    if let Some(threshold) = options.stuck_threshold {
+        client.spawn_watchdog(threshold);
+    }
+    Ok(client)
+}
+
+impl RemoteFS {
+    // The filesystem for `options.mounts[index]`. Only the main mount, the
+    // first, spills to disk, replays the journal, reports status and runs the
+    // post-mount hook.
+    fn new(
+        options: &MountOptions,
+        index: usize,
+        client: Arc<RemoteFSClient>,
+        audit: Option<AuditLog>,
+        journal: Option<Arc<Journal>>,
+        spill: Option<Arc<SpillDir>>,
+        quotas: Option<Arc<Quotas>>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mount = &options.mounts[index];
+        let main = index == 0;
+        let spill = spill.filter(|_| main);
+        let inbox = client.open_inbox(&mount.prefix);
+        // Close-to-open buffers writes like write-back but only ever flushes them on close
+        let writeback = (mount.consistency != Consistency::Strict).then(|| {
+            let writeback = WriteBack::new(spill.clone(), journal.clone());
+            if mount.consistency == Consistency::WriteBack {
+                writeback.spawn_flusher(client.clone());
+            }
+            writeback
+        });
+        // Whether the DO grants them is only known after the handshake, which a
+        // lazy connection may not have had yet, so each acquire checks
+        let leases = options.leases.then(|| {
+            let leases = Leases::new(writeback.clone());
+            leases.spawn_recall_handler(client.clone(), &inbox);
+            leases
+        });
+        let inodes = InodeTable::new(&mount.prefix);
+        let notifier = Arc::new(OnceLock::new());
+        kernel_notify::spawn_invalidator(inbox.subscribe_notices(), inodes.index(), notifier.clone());
+        Ok(Self {
+            client,
+            inbox,
+            mount_point: mount.mount_point.clone(),
+            status_json: options.status_json.clone().filter(|_| main),
+            next_fh: Arc::new(Mutex::new(1)),
+            inodes,
+            write_buffers: HashMap::new(),
+            inline_cache: HashMap::new(),
+            readahead: HashMap::new(),
+            block_cache: shared_block_cache(&mount.prefix, mount.cache.cache_size, spill.clone()),
+            versions: HashMap::new(),
+            dir_cache: DirCache::new(mount.cache.dir_cache_ttl, DIR_CACHE_ENTRIES),
+            writeback,
+            consistency: mount.consistency,
+            stats: HashMap::new(),
+            leases,
+            spill,
+            unsupported: options.unsupported,
+            names: options.names,
+            ownership: options.ownership.clone(),
+            xattrs: XattrStore::default(),
+            special_nodes: HashMap::new(),
+            snapshots: Snapshots::new(&mount.prefix),
+            fsctl: ControlFile::new(&mount.prefix),
+            notifier,
+            post_mount_exec: options.post_mount_exec.clone().filter(|_| main),
+            kernel_cache: options.kernel_cache,
+            max_read: options.kernel_mount.max_read,
+            audit,
+            quotas,
+            max_file_size: options.max_file_size,
+            read_only: mount.read_only,
+            readahead_window: mount.cache.readahead_window,
+            readahead_memory: mount.cache.readahead_memory,
+            unreplayed: journal.as_ref().filter(|_| main).map(|journal| journal.take_recovered()).unwrap_or_default(),
+            journal,
+        })
+    }
+
+    // Writes acknowledged before a crash but only ever spilled to disk go out
+    // before the filesystem serves anything that could overwrite them
+    fn recover_spilled_writes(&self) {
+        let Some(spill) = &self.spill else {
+            return;
+        };
+        let recovery = WriteBack::new(Some(spill.clone()), None);
+        if !recovery.adopt_recovered() {
+            return;
+        }
+        // With a lazy connection the DO may not be there yet, and nothing is
+        // served until the data is back
+        self.client.wait_connected();
+        info!("Replaying write-back data spilled before the last shutdown");
+        if let Err(e) = self.client.block_on(recovery.flush_older_than(&self.client, Duration::ZERO)) {
+            error!("Failed to replay spilled write-back data, will retry on next start: {}", e);
+        }
+    }
+
+    // Writes the journal recorded before a crash but never saw reach the DO
+    // go out in the order they were made. They run after the spilled data,
+    // which they may be newer than.
+    fn replay_journal(&mut self) {
+        let (Some(journal), entries) = (&self.journal, std::mem::take(&mut self.unreplayed)) else {
+            return;
+        };
+        if entries.is_empty() {
+            return;
+        }
+        self.client.wait_connected();
+        info!("Replaying {} journaled writes made before the last shutdown", entries.len());
+        for entry in entries {
+            let write = self.client.write_chunked(&entry.path, entry.offset, &entry.data);
+            if let (_, Some(e)) = self.client.block_on(write) {
+                error!("Failed to replay journaled writes, will retry on next start: {}", e);
+                return;
+            }
+            journal.done(&[entry.seq]);
+        }
+    }
+
+    // Once the daemon is shutting down no files are opened, created or removed,
+    // so what is in flight can drain before the unmount
+    fn draining(&self) -> bool {
+        lifecycle::connection().state().0 == lifecycle::State::Draining
+    }
+
+    // Pushes write-back data for a path to the DO before it is read or synced
+    fn flush_writeback(&self, path: &str) -> Result<(), DaemonError> {
+        let Some(writeback) = &self.writeback else {
+            return Ok(());
+        };
+        self.client.block_on(writeback.flush_path(&self.client, path))
+    }
+
+    // Attributes as the application should see them, including unflushed write-back data
+    fn attr_for(&self, ino: u64, path: &str, stat: &FileStat) -> FileAttr {
+        let mut attr = self.get_attr_from_stat(ino, path, stat);
+        if let Some(end) = self.writeback.as_ref().and_then(|writeback| writeback.dirty_end(path)) {
+            attr.size = attr.size.max(end);
+            attr.blocks = attr.size.div_ceil(512);
+        }
+        attr
+    }
+
+    // Drops everything cached locally about a path's contents
+    fn invalidate_path(&mut self, path: &str) {
+        self.inline_cache.remove(path);
+        self.readahead.retain(|_, readahead| readahead.path != path);
+        // Until the next stat reports the new version nothing is cached for the path
+        if let Some(version) = self.versions.remove(path) {
+            self.block_cache.locked().invalidate(&content_key(path, version));
+        }
+    }
+
+    // Drops the cached listing of the directory holding `path` after a local change to it
+    fn invalidate_parent(&mut self, path: &str) {
+        let parent = match path.rfind('/') {
+            Some(0) | None => "/",
+            Some(at) => &path[..at],
+        };
+        self.dir_cache.invalidate(parent);
+    }
+
+    // Whether a lease lets the path be served from cache without asking the DO
+    fn leased(&self, path: &str, mode: LeaseMode) -> bool {
+        self.leases.as_ref().is_some_and(|leases| leases.holds(path, mode))
+    }
+
+    // Drops cached state for paths the DO reported as changed by another client,
+    // or for every path after a reconnect, since pushes may have been missed
+    // and leases lost while the first connection was down
+    fn apply_invalidations(&mut self) {
+        if let Some(cache) = self.inbox.take_reloaded() {
+            self.block_cache.locked().set_budget(cache.cache_size);
+            self.dir_cache.set_ttl(cache.dir_cache_ttl);
+            self.readahead_window = cache.readahead_window;
+            self.readahead_memory = cache.readahead_memory;
+        }
+        if self.inbox.take_resync() {
+            let paths: Vec<String> = self.versions.keys().chain(self.inline_cache.keys()).cloned().collect();
+            for path in paths {
+                self.invalidate_path(&path);
+            }
+            self.stats.clear();
+            self.dir_cache.clear();
+            if let Some(leases) = &self.leases {
+                leases.forget_all();
+            }
+        }
+        for (path, entry_changed) in self.inbox.take_invalidations() {
+            self.invalidate_path(&path);
+            if entry_changed {
+                self.invalidate_parent(&path);
+            }
+        }
+    }
+
+    // Stats a path, keeping the inline cache in step with whatever the DO sent back
+    fn stat_path(&mut self, path: &str) -> Result<Option<FileStat>, DaemonError> {
+        self.apply_invalidations();
+        let response = self.client.block_on(self.client.send_message(FSMessage {
+            operation: FsOperation::Stat,
+            path: path.to_string(),
+            inline_limit: Some(INLINE_LIMIT),
+            ..Default::default()
+        }))?;
+
+        let has_room =
+            self.inline_cache.len() < INLINE_CACHE_ENTRIES || self.inline_cache.contains_key(path);
+        // Damaged inline content is dropped, leaving the next read to fetch it again
+        let intact =
+            |content: &Vec<u8>| response.checksum.map_or(true, |checksum| checksum == crc32fast::hash(content));
+        match response.inline {
+            Some(content) if has_room && intact(&content) => {
+                self.inline_cache.insert(path.to_string(), content);
+            }
+            _ => {
+                self.inline_cache.remove(path);
+            }
+        }
+
+        match response.stat.as_ref().and_then(|stat| stat.version) {
+            Some(version) => self.versions.insert(path.to_string(), version),
+            None => self.versions.remove(path),
+        };
+        match &response.stat {
+            Some(stat) => self.stats.insert(path.to_string(), stat.clone()),
+            None => self.stats.remove(path),
+        };
+        Ok(response.stat)
+    }
+
+    // Buffers or sends a write, returning the errno it failed with. A write
+    // that fails before it is buffered gives back what it was charged; once
+    // buffered it stays charged until it is sent or dropped.
+    fn write_data(&mut self, fh: u64, path: &str, offset: u64, data: &[u8]) -> Result<(), i32> {
+        self.fits(offset.saturating_add(data.len() as u64))?;
+        let before = self.charge_write(path, offset + data.len() as u64)?;
+        self.invalidate_path(path);
+        let journaled = match self.journal.as_ref().map(|journal| journal.record(path, offset, data)) {
+            Some(Ok(journaled)) => Some(journaled),
+            Some(Err(e)) => {
+                error!("Failed to journal a write to {}: {}", path, e);
+                self.uncharge(path, before);
+                return Err(libc::EIO);
+            }
+            None => None,
+        };
+
+        // In write-back mode the write is acknowledged as soon as it is buffered
+        if let Some(writeback) = &self.writeback {
+            let dirty = writeback.buffer(path, offset, data, journaled);
+            if dirty > WRITEBACK_MEMORY_LIMIT {
+                self.client
+                    .block_on(writeback.flush_until_below(&self.client, WRITEBACK_MEMORY_LIMIT / 2))
+                    .map_err(|e| e.errno())?;
+            }
+            return Ok(());
+        }
+
+        // Anything that doesn't extend the buffered run has to go out first
+        let contiguous = matches!(
+            self.write_buffers.get(&fh),
+            Some(pending) if pending.path == path && pending.offset + pending.data.len() as u64 == offset
+        );
+        if !contiguous {
+            if let Err(e) = self.flush_handle(fh) {
+                self.uncharge(path, before);
+                return Err(e.errno());
+            }
+        }
+
+        let pending = self.write_buffers.entry(fh).or_insert_with(|| PendingWrite {
+            path: path.to_string(),
+            offset,
+            data: self.client.buffers.take(),
+            journaled: Vec::new(),
+        });
+        pending.data.extend_from_slice(data);
+        pending.journaled.extend(journaled);
+
+        if pending.data.len() >= WRITE_COALESCE_LIMIT {
+            self.flush_handle(fh).map_err(|e| e.errno())?;
+        }
+        Ok(())
+    }
+
+    fn unlink_path(&mut self, path: &str) -> Result<(), i32> {
+        if self.fsctl.is(path) {
+            return Err(libc::EPERM);
+        }
+        if self.snapshot_path(path).is_some() {
+            return Err(libc::EROFS);
+        }
+        self.invalidate_parent(path);
+        self.stats.remove(path);
+        self.xattrs.forget(path);
+        if self.special_nodes.remove(path).is_some() {
+            return Ok(());
+        }
+        self.invalidate_path(path);
+        if let Some(writeback) = &self.writeback {
+            writeback.discard(path);
+        }
+
+        let unlink = self.client.send_request(FsOperation::Unlink, path, None, None, None);
+        match self.client.block_on(unlink) {
+            Ok(response) if response.success => {
+                if let Some(quotas) = &self.quotas {
+                    quotas.remove(path);
+                }
+                Ok(())
+            }
+            Ok(_) => Err(libc::ENOENT),
+            Err(e) => Err(e.errno()),
+        }
+    }
+
+    // Fails with EFBIG when a file of `size` would be over --max-file-size
+    fn fits(&self, size: u64) -> Result<(), i32> {
+        match self.max_file_size {
+            Some(max) if size > max => Err(libc::EFBIG),
+            _ => Ok(()),
+        }
+    }
+
+    // Charges a write ending at `end` to the quotas over the path, returning
+    // the size the file was counted at before, or failing with their errno
+    // when it would go over one
+    fn charge_write(&mut self, path: &str, end: u64) -> Result<Option<u64>, i32> {
+        let Some(quotas) = self.quotas.clone().filter(|quotas| quotas.covers(path)) else {
+            return Ok(None);
+        };
+        let current = match quotas.size(path) {
+            Some(size) => Some(size),
+            None => self.current_size(path).map_err(|e| e.errno())?,
+        };
+        quotas.grow(path, current, end)
+    }
+
+    // Gives back a charge for a change to `path` the DO never got, counting
+    // the file at `before` again
+    fn uncharge(&self, path: &str, before: Option<u64>) {
+        if let Some(quotas) = self.quotas.as_ref().filter(|quotas| quotas.covers(path)) {
+            quotas.restore(path, before);
+        }
+    }
+
+    // Counts the file at `path` at what the DO has, after writes charged for
+    // were dropped unsent, or leaves it to the reconciler if the DO can't say
+    fn recount(&mut self, path: &str) {
+        let Some(quotas) = self.quotas.clone().filter(|quotas| quotas.covers(path)) else {
+            return;
+        };
+        self.stats.remove(path);
+        match self.current_size(path) {
+            Ok(size) => quotas.restore(path, size),
+            Err(e) => warn!("Failed to count {} again after dropping writes to it: {}", path, e),
+        }
+    }
+
+    // The size of the file at `path` as last seen, asking the DO if it wasn't
+    fn current_size(&mut self, path: &str) -> Result<Option<u64>, DaemonError> {
+        if let Some(stat) = self.stats.get(path) {
+            return Ok(stat.is_file.then_some(stat.size));
+        }
+        match self.stat_path(path) {
+            Ok(stat) => Ok(stat.filter(|stat| stat.is_file).map(|stat| stat.size)),
+            Err(e) if e.errno() == libc::ENOENT => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // The room under the mount's quota, for statfs
+    fn space(&self) -> Space {
+        match (&self.quotas, self.inodes.path(1)) {
+            (Some(quotas), Some(prefix)) => quotas.space(&prefix),
+            _ => Space::UNLIMITED,
+        }
+    }
+
+    // Records a mutation an application made, when auditing is on
+    fn audit(
+        &self,
+        caller: impl Into<Caller>,
+        op: AuditOp,
+        path: &str,
+        offset: Option<u64>,
+        size: Option<u64>,
+        errno: Option<i32>,
+    ) {
+        if let Some(audit) = &self.audit {
+            audit.record(caller.into(), op, path, offset, size, errno);
+        }
+    }
+
+    fn flush_handle(&mut self, fh: u64) -> Result<(), DaemonError> {
+        let Some(pending) = self.write_buffers.get_mut(&fh) else {
+            return Ok(());
+        };
+
+        // Only the verified prefix is dropped from the buffer
+        let (verified, error) =
+            self.client.block_on(self.client.write_chunked(&pending.path, pending.offset, &pending.data));
+
+        pending.data.drain(..verified);
+        pending.offset += verified as u64;
+        if let Some(error) = error {
+            return Err(error);
+        }
+
+        if let Some(pending) = self.write_buffers.remove(&fh) {
+            if let Some(journal) = &self.journal {
+                journal.done(&pending.journaled);
+            }
+            self.client.buffers.give(pending.data);
+        }
+        Ok(())
+    }
+
+    // Creates an empty regular file in the DO, replacing whatever was at the path.
+    // A DO that agreed to `modes` gives it `mode`, or without one keeps the
+    // mode the path had.
+    fn create_file(&mut self, path: &str, mode: Option<u32>) -> Result<FileAttr, DaemonError> {
+        if self.fsctl.is(path) {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST).into());
+        }
+        if self.snapshot_path(path).is_some() {
+            return Err(io::Error::from_raw_os_error(libc::EROFS).into());
+        }
+        let before = match self.quotas.clone().filter(|quotas| quotas.covers(path)) {
+            Some(quotas) => {
+                // Whatever is there was looked up on the way in
+                let current = self.stats.get(path).filter(|stat| stat.is_file).map(|stat| stat.size);
+                quotas.create(path, current).map_err(io::Error::from_raw_os_error)?
+            }
+            None => None,
+        };
+        self.invalidate_path(path);
+        self.invalidate_parent(path);
+        self.stats.remove(path);
+        self.special_nodes.remove(path);
+        self.xattrs.forget(path);
+        if let Some(writeback) = &self.writeback {
+            writeback.discard(path);
+        }
+
+        let mode = mode.filter(|_| self.client.has_feature("modes")).map(|mode| mode & 0o7777);
+        let created = self.client.block_on(self.client.send_message(FSMessage {
+            operation: FsOperation::Write,
+            path: path.to_string(),
+            data: Some(vec![]),
+            mode,
+            ..Default::default()
+        }));
+        if let Err(e) = created {
+            self.uncharge(path, before);
+            return Err(e);
+        }
+
+        // Return fake attributes for created file
+        let (uid, gid) = self.ownership.of(path, None);
+        Ok(FileAttr {
+            ino: self.inodes.lookup(path),
+            size: 0,
+            blocks: 0,
+            atime: SystemTime::now(),
+            mtime: SystemTime::now(),
+            ctime: SystemTime::now(),
+            crtime: SystemTime::now(),
+            kind: FileType::RegularFile,
+            perm: mode.map_or(0o644, |mode| mode as u16),
+            nlink: 1,
+            uid,
+            gid,
+            rdev: 0,
+            flags: 0,
+            blksize: 4096,
+        })
+    }
+
+    // Attributes of `path` with the owner `caller` sees, which differs from
+    // the one stats give only for --owner=caller
+    fn seen_by(&self, path: &str, attr: FileAttr, caller: impl Into<Caller>) -> FileAttr {
+        let (uid, gid) = self.ownership.of(path, Some(caller.into()));
+        FileAttr { uid, gid, ..attr }
+    }
+
+    // Gives the file at `path` new permission bits in the DO, answering with
+    // its attributes after
+    fn chmod(&mut self, path: &str, mode: u32) -> Result<FileAttr, i32> {
+        self.refuses_changes(path)?;
+        if self.special_nodes.contains_key(path) {
+            return Err(libc::ENOSYS);
+        }
+        let chmod = FSMessage {
+            operation: FsOperation::Chmod,
+            path: path.to_string(),
+            mode: Some(mode & 0o7777),
+            ..Default::default()
+        };
+        self.client.block_on(self.client.send_message(chmod)).map_err(|e| e.errno())?;
+        self.stats.remove(path);
+        self.path_attr(path).map_err(|e| e.errno())?.ok_or(libc::ENOENT)
+    }
+
+    fn special_attr(&self, ino: u64, path: &str, node: &SpecialNode) -> FileAttr {
+        let (uid, gid) = self.ownership.of(path, None);
+        FileAttr {
+            ino,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: node.kind,
+            perm: node.perm,
+            nlink: 1,
+            uid,
+            gid,
+            rdev: node.rdev,
+            flags: 0,
+            blksize: 4096,
+        }
+    }
+
+    fn get_attr_from_stat(&self, ino: u64, path: &str, stat: &FileStat) -> FileAttr {
+        let (uid, gid) = self.ownership.of(path, None);
+        FileAttr {
+            ino,
+            size: stat.size,
+            blocks: stat.allocated.unwrap_or(stat.size).min(stat.size).div_ceil(512),
+            atime: UNIX_EPOCH + Duration::from_millis(stat.mtime),
+            mtime: UNIX_EPOCH + Duration::from_millis(stat.mtime),
+            ctime: UNIX_EPOCH + Duration::from_millis(stat.mtime),
+            crtime: UNIX_EPOCH + Duration::from_millis(stat.mtime),
+            kind: if stat.is_file { FileType::RegularFile } else { FileType::Directory },
+            perm: stat.mode.map_or(if stat.is_file { 0o644 } else { 0o755 }, |mode| (mode & 0o7777) as u16),
+            nlink: 1,
+            uid,
+            gid,
+            rdev: 0,
+            flags: 0,
+            blksize: 4096,
+        }
+    }
+
+    // Where a path is in the snapshot tree, for DOs that have one to show
+    fn snapshot_path(&self, path: &str) -> Option<SnapshotPath> {
+        if !self.client.has_feature("snapshots") {
+            return None;
+        }
+        self.snapshots.resolve(path)
+    }
+
+    // Attributes of a path in the snapshot tree, or None if it isn't there,
+    // with the inode left for the caller to fill in. Everything in it is
+    // read-only and dated to when its snapshot was taken, and owned as the
+    // path it was taken from is now.
+    fn snapshot_attr(&mut self, path: &str, snapshot: &SnapshotPath) -> Result<Option<FileAttr>, DaemonError> {
+        let (at, size, is_dir) = match snapshot {
+            SnapshotPath::Root => (0, 0, true),
+            SnapshotPath::In { at, path } => {
+                // A snapshot the DO no longer has takes everything in it along
+                if !self.client.block_on(self.snapshots.list(&self.client))?.contains(at) {
+                    return Ok(None);
+                }
+                match self.client.block_on(self.snapshots.entry(&self.client, *at, path))? {
+                    Some(entry) => (*at, entry.size, entry.is_dir),
+                    None => return Ok(None),
+                }
+            }
+        };
+        let (uid, gid) = match snapshot {
+            SnapshotPath::Root => self.ownership.of(path, None),
+            SnapshotPath::In { path: from, .. } => self.ownership.of(from, None),
+        };
+        let taken = UNIX_EPOCH + Duration::from_millis(at);
+        Ok(Some(FileAttr {
+            ino: 0,
+            size,
+            blocks: size.div_ceil(512),
+            atime: taken,
+            mtime: taken,
+            ctime: taken,
+            crtime: taken,
+            kind: if is_dir { FileType::Directory } else { FileType::RegularFile },
+            perm: if is_dir { 0o555 } else { 0o444 },
+            nlink: 1,
+            uid,
+            gid,
+            rdev: 0,
+            flags: 0,
+            blksize: 4096,
+        }))
+    }
+
+    // The names in a directory of the snapshot tree and what each is
+    fn snapshot_entries(&mut self, snapshot: &SnapshotPath) -> Result<Vec<(String, FileType)>, DaemonError> {
+        Ok(match snapshot {
+            SnapshotPath::Root => self
+                .client
+                .block_on(self.snapshots.list(&self.client))?
+                .into_iter()
+                .map(|at| (snapshots::name(at), FileType::Directory))
+                .collect(),
+            SnapshotPath::In { at, path } => self
+                .client
+                .block_on(self.snapshots.listing(&self.client, *at, path))?
+                .into_iter()
+                .map(|entry| (entry.name, if entry.is_dir { FileType::Directory } else { FileType::RegularFile }))
+                .collect(),
+        })
+    }
+}
+
+impl RemoteFS {
+    // What a fresh open of the control file reads
+    fn control_status(&self) -> serde_json::Value {
+        serde_json::json!({
+            "mount_point": self.mount_point,
+            "prefix": self.inodes.path(1),
+            "connection": admin::stats(&self.client),
+            "buffered_handles": self.write_buffers.len(),
+            "cached_paths": self.versions.len(),
+            "inlined_paths": self.inline_cache.len(),
+            "quotas": self.quotas.as_ref().map(|quotas| quotas.status()),
+            "tier": tier::TIERED.get().map(|tiered| tiered.status()),
+        })
+    }
+
+    // Runs commands written to the control file in order, stopping at the
+    // first that fails, and answers with what the last one returned
+    fn control(&mut self, text: &str) -> Result<serde_json::Value, (i32, String)> {
+        let commands = fsctl::parse(text).map_err(|e| (libc::EINVAL, e))?;
+        let mut answer = serde_json::Value::Null;
+        for command in commands {
+            info!(command = ?command, "Control file command on {}", self.mount_point);
+            answer = match command {
+                Command::Flush | Command::Commit => {
+                    let handles: Vec<u64> = self.write_buffers.keys().copied().collect();
+                    for fh in handles {
+                        self.flush_handle(fh).map_err(|e| (e.errno(), e.to_string()))?;
+                    }
+                    if let Some(writeback) = &self.writeback {
+                        let flush = writeback.flush_older_than(&self.client, Duration::ZERO);
+                        self.client.block_on(flush).map_err(|e| (e.errno(), e.to_string()))?;
+                    }
+                    match command {
+                        Command::Commit => backend::commit().map_err(|e| (libc::EIO, e))?,
+                        _ => serde_json::Value::Null,
+                    }
+                }
+                Command::Invalidate(path) => {
+                    let path = self.fsctl.target(&path);
+                    self.invalidate_path(&path);
+                    self.invalidate_parent(&path);
+                    self.stats.remove(&path);
+                    serde_json::Value::Null
+                }
+                Command::Stats => self.control_status(),
+            };
+        }
+        Ok(answer)
+    }
+}
+
+// What every frontend does, whether the kernel asks through FUSE or a client
+// over NFS or 9p
+impl RemoteFS {
+    // Runs once the filesystem is being served, with the largest write and
+    // readahead its frontend agreed to
+    fn started(&mut self, max_write: u32, max_readahead: u32) {
+        self.recover_spilled_writes();
+        self.replay_journal();
+
+        if let Some(target) = &self.status_json {
+            let (kind, address) = self.client.transport();
+            // Absent until the DO connects, with a lazy connection
+            let negotiated = self.client.negotiated();
+            let report = StatusReport {
+                daemon: env!("CARGO_PKG_NAME"),
+                version: env!("CARGO_PKG_VERSION"),
+                state: "mounted",
+                mount_point: self.mount_point.clone(),
+                transport: TransportStatus {
+                    kind,
+                    address,
+                    tls: self.client.tls,
+                    encryption: self.client.cipher.is_some(),
+                    routing: self.client.routing.as_str(),
+                },
+                protocol_version: negotiated.map(|agreed| agreed.protocol_version).filter(|version| *version > 0),
+                features: [
+                    "write-coalescing",
+                    "chunked-transfer",
+                    "readahead",
+                    "block-cache",
+                    "inline-small-files",
+                ]
+                .into_iter()
+                .chain((self.consistency == Consistency::WriteBack).then_some("write-back"))
+                .chain((self.consistency == Consistency::CloseToOpen).then_some("close-to-open"))
+                .chain((self.leases.is_some() && self.client.has_feature("leases")).then_some("leases"))
+                .chain(self.read_only.then_some("read-only"))
+                .chain(negotiated.is_some_and(|agreed| agreed.encoding != Encoding::Legacy).then_some("binary-framing"))
+                .chain(negotiated.is_some_and(|agreed| agreed.encoding == Encoding::MessagePack).then_some("msgpack"))
+                .chain(negotiated.is_some_and(|agreed| agreed.pool_size > 1).then_some("connection-pool"))
+                .chain(negotiated.is_some_and(|agreed| agreed.compression).then_some("lz4"))
+                .chain(self.client.has_feature("checksums").then_some("write-checksums"))
+                .chain(self.client.has_feature("checksums").then_some("read-checksums"))
+                .chain(self.client.has_feature("stream-reads").then_some("streaming-reads"))
+                .chain(self.client.has_feature("stream-writes").then_some("streaming-writes"))
+                .chain(self.client.has_feature("idempotency").then_some("idempotent-retries"))
+                .chain(self.client.has_feature("cancel").then_some("cancellation"))
+                .chain(negotiated.is_some_and(|agreed| agreed.heartbeat.is_some()).then_some("heartbeat"))
+                .chain(self.spill.is_some().then_some("disk-spill"))
+                .chain(self.kernel_cache.keep_cache.then_some("keep-cache"))
+                .chain(self.kernel_cache.writeback_cache.then_some("kernel-writeback-cache"))
+                .chain(self.kernel_cache.direct_io.then_some("direct-io"))
+                .collect(),
+                limits: LimitsStatus {
+                    max_read: self.max_read as u32,
+                    max_write,
+                    max_readahead,
+                    inline_limit: INLINE_LIMIT,
+                    max_in_flight: self.client.max_in_flight,
+                    max_frame_size: self.client.max_frame,
+                },
+            };
+            if let Err(e) = report.emit(target) {
+                warn!("Failed to write status document to {}: {}", target, e);
+            }
+        }
+
+        hooks::notify("READY=1");
+        if let Some(command) = &self.post_mount_exec {
+            hooks::run_post_mount(command, &self.mount_point);
+        }
+    }
+
+    // Runs once the filesystem is no longer served: whatever applications
+    // wrote that the DO doesn't have yet is pushed out before the daemon
+    // exits. Write-back data that can't be sent stays in the spill directory,
+    // if there is one, for the next start to replay.
+    fn stopped(&mut self) {
+        let handles: Vec<u64> = self.write_buffers.keys().copied().collect();
+        for fh in handles {
+            if let Err(e) = self.flush_handle(fh) {
+                error!("Failed to flush buffered writes on {}: {}", self.mount_point, e);
+            }
+        }
+        if let Some(writeback) = &self.writeback {
+            let flush = writeback.flush_older_than(&self.client, Duration::ZERO);
+            if let Err(e) = self.client.block_on(flush) {
+                error!("Failed to flush write-back data on {}: {}", self.mount_point, e);
+            }
+        }
+    }
+
+    fn next_handle(&self) -> u64 {
+        let mut next_fh = self.next_fh.locked();
+        *next_fh += 1;
+        *next_fh
+    }
+
+    // Attributes of `path`, or None if there is nothing there, with the inode
+    // left for the caller to fill in
+    fn path_attr(&mut self, path: &str) -> Result<Option<FileAttr>, DaemonError> {
+        if let Some(node) = self.special_nodes.get(path) {
+            return Ok(Some(self.special_attr(0, path, node)));
+        }
+        if self.fsctl.is(path) {
+            return Ok(Some(self.special_attr(0, path, &FSCTL_NODE)));
+        }
+        if let Some(snapshot) = self.snapshot_path(path) {
+            return self.snapshot_attr(path, &snapshot);
+        }
+        let stat = match self.stat_path(path) {
+            Err(e) if e.errno() == libc::ENOENT => None,
+            stat => stat?,
+        };
+        match stat {
+            Some(stat) => Ok(Some(self.attr_for(0, path, &stat))),
+            // The mount's root is a directory whether or not the DO has anything
+            // in it, and so is where a namespace is grafted
+            None if self.inodes.path(1).as_deref() == Some(path) || self.client.is_namespace(path) => {
+                let root = FileStat { size: 0, is_file: false, mtime: 0, version: None, allocated: None, mode: None };
+                Ok(Some(self.get_attr_from_stat(0, path, &root)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Attributes of a path already looked up, served from its last stat when
+    // nothing can have changed since
+    fn current_attr(&mut self, path: &str) -> Result<Option<FileAttr>, DaemonError> {
+        let plain =
+            !self.special_nodes.contains_key(path) && !self.fsctl.is(path) && self.snapshot_path(path).is_none();
+        // Close-to-open only revalidates on open, and nobody else can change a leased path
+        if plain && (self.consistency == Consistency::CloseToOpen || self.leased(path, LeaseMode::Read)) {
+            if let Some(stat) = self.stats.get(path) {
+                return Ok(Some(self.attr_for(0, path, stat)));
+            }
+        }
+        self.path_attr(path)
+    }
+
+    // Attributes of a path already looked up, counting writes its handles
+    // still buffer, for clients that don't keep track of those themselves
+    fn buffered_attr(&mut self, path: &str) -> Result<Option<FileAttr>, DaemonError> {
+        let Some(mut attr) = self.current_attr(path)? else {
+            return Ok(None);
+        };
+        let buffered = self.write_buffers.values().filter(|pending| pending.path == path);
+        if let Some(end) = buffered.map(|pending| pending.offset + pending.data.len() as u64).max() {
+            attr.size = attr.size.max(end);
+            attr.blocks = attr.size.div_ceil(512);
+        }
+        Ok(Some(attr))
+    }
+
+    // Reads up to `size` bytes of `path` at `offset` through handle `fh`,
+    // handing `reply` the data, or why there is none, exactly once
+    fn read_data(
+        &mut self,
+        fh: u64,
+        path: &str,
+        offset: u64,
+        size: u64,
+        reply: impl FnOnce(Result<&[u8], DaemonError>),
+    ) {
+        if let Some(content) = self.fsctl.read(fh, offset, size) {
+            reply(Ok(content));
+            return;
+        }
+        if let Some(SnapshotPath::In { at, path }) = self.snapshot_path(path) {
+            let read = snapshots::read(&self.client, at, &path, offset, size);
+            match self.client.block_on(read) {
+                Ok(data) => {
+                    METRICS.read(ReadSource::Remote, data.len());
+                    reply(Ok(&data));
+                }
+                Err(e) => reply(Err(e)),
+            }
+            return;
+        }
+        self.apply_invalidations();
+
+        // Tiny files inlined by an earlier stat are served without a round trip
+        if let Some(content) = self.inline_cache.get(path) {
+            let start = (offset as usize).min(content.len());
+            let end = (start + size as usize).min(content.len());
+            METRICS.read(ReadSource::Inline, end - start);
+            reply(Ok(&content[start..end]));
+            return;
+        }
+
+        // Reads must observe this handle's own buffered writes
+        if self.write_buffers.contains_key(&fh) {
+            if let Err(e) = self.flush_handle(fh) {
+                reply(Err(e));
+                return;
+            }
+        }
+        if let Err(e) = self.flush_writeback(path) {
+            reply(Err(e));
+            return;
+        }
+
+        if let Some(readahead) = self.readahead.get_mut(&fh) {
+            if readahead.path == path {
+                if let Some(data) = readahead.serve(offset, size) {
+                    let served = data.len() as u64;
+                    METRICS.read(ReadSource::Readahead, data.len());
+                    reply(Ok(data));
+                    readahead.next_offset = offset + served;
+                    return;
+                }
+            }
+        }
+
+        let cache_key = self.versions.get(path).map(|version| content_key(path, *version));
+        let cached = cache_key
+            .as_ref()
+            .and_then(|key| self.block_cache.locked().read(key, offset, size));
+        if let Some(data) = cached {
+            METRICS.read(ReadSource::BlockCache, data.len());
+            reply(Ok(&data));
+            if let Some(readahead) = self.readahead.get_mut(&fh) {
+                if readahead.path == path {
+                    readahead.next_offset = offset + data.len() as u64;
+                }
+            }
+            return;
+        }
+
+        // Sequential reads fetch an extra window, as long as the memory budget allows
+        let sequential = self
+            .readahead
+            .get(&fh)
+            .is_some_and(|readahead| readahead.path == path && readahead.next_offset == offset);
+        let buffered: usize = self
+            .readahead
+            .iter()
+            .filter(|(handle, _)| **handle != fh)
+            .map(|(_, readahead)| readahead.buffer.len())
+            .sum();
+        let window = if sequential && buffered + self.readahead_window <= self.readahead_memory {
+            self.readahead_window as u64
+        } else {
+            0
+        };
+
+        // Fetch whole blocks so the result can populate the block cache
+        let start = offset - offset % BLOCK_SIZE;
+        let end = (offset + size + window).div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+
+        match self.client.block_on(self.client.read_chunked(path, start, end - start)) {
+            Ok(data) => {
+                let skip = ((offset - start) as usize).min(data.len());
+                let served = (data.len() - skip).min(size as usize);
+                METRICS.read(ReadSource::Remote, served);
+                reply(Ok(&data[skip..skip + served]));
+
+                let eof = (data.len() as u64) < end - start;
+                if let Some(key) = &cache_key {
+                    self.block_cache.locked().insert_range(key, start, &data, eof);
+                }
+                let buffer = if window > 0 {
+                    data
+                } else {
+                    self.client.buffers.give(data);
+                    Vec::new()
+                };
+                let previous = self.readahead.insert(
+                    fh,
+                    Readahead {
+                        path: path.to_string(),
+                        next_offset: offset + served as u64,
+                        buffer_offset: start,
+                        buffer,
+                        eof,
+                    },
+                );
+                if let Some(previous) = previous {
+                    self.client.buffers.give(previous.buffer);
+                }
+            }
+            Err(e) => reply(Err(e)),
+        }
+    }
+
+    // Lists the directory at `path`, inode `ino`, from position `offset`,
+    // handing `add` each entry's inode, the position after it, its type and
+    // name until it answers that it is full
+    fn list_dir(
+        &mut self,
+        ino: u64,
+        path: &str,
+        offset: u64,
+        mut add: impl FnMut(u64, u64, FileType, &str) -> bool,
+    ) -> Result<(), DaemonError> {
+        if let Some(snapshot) = self.snapshot_path(path) {
+            let entries = self.snapshot_entries(&snapshot)?;
+            for (position, (name, kind)) in entries.iter().enumerate().skip(offset as usize) {
+                let Some(child) = self.inodes.child_path(ino, name) else {
+                    continue;
+                };
+                let child_ino = self.inodes.assign(&child);
+                if add(child_ino, position as u64 + 1, *kind, name) {
+                    break;
+                }
+            }
+            return Ok(());
+        }
+        self.apply_invalidations();
+
+        // Namespaces grafted here come first, hiding entries of the same name
+        let grafted = self.client.namespaces_in(path);
+        for (position, name) in grafted.iter().enumerate().skip(offset as usize) {
+            let Some(child) = self.inodes.child_path(ino, name) else {
+                continue;
+            };
+            let child_ino = self.inodes.assign(&child);
+            if add(child_ino, position as u64 + 1, FileType::Directory, name) {
+                return Ok(());
+            }
+        }
+        let shift = grafted.len() as u64;
+
+        // Entries are fetched a page at a time, only as far as the caller's buffer reaches
+        let mut position = offset.saturating_sub(shift);
+        loop {
+            let page = self.dir_cache.page(path, position);
+            METRICS.listing(page.is_some());
+            let (files, complete) = match page {
+                Some(page) => page,
+                None => {
+                    let response = self.client.block_on(self.client.send_request(
+                        FsOperation::Readdir,
+                        path,
+                        None,
+                        Some(position),
+                        Some(READDIR_PAGE_SIZE),
+                    ))?;
+                    let complete = (response.files.len() as u64) < READDIR_PAGE_SIZE;
+                    self.dir_cache.extend(path, position, &response.files, complete);
+                    (response.files, complete)
+                }
+            };
+
+            for file in &files {
+                position += 1;
+                if grafted.contains(file) {
+                    continue;
+                }
+                let Some(child) = self.inodes.child_path(ino, file) else {
+                    continue;
+                };
+                let child_ino = self.inodes.assign(&child);
+                if add(child_ino, position + shift, FileType::RegularFile, file) {
+                    return Ok(());
+                }
+            }
+            if complete {
+                return Ok(());
+            }
+        }
+    }
+
+    // Every entry of the directory at `path`, by name and type
+    fn list_all(&mut self, path: &str) -> Result<Vec<(String, FileType)>, i32> {
+        let ino = self.inodes.assign(path);
+        let mut entries = Vec::new();
+        self.list_dir(ino, path, 0, |_, _, kind, name| {
+            entries.push((name.to_string(), kind));
+            false
+        })
+        .map_err(|e| e.errno())?;
+        Ok(entries)
+    }
+
+    // The path of `name` in the directory `parent`, named as the name policy
+    // has the DO hold it
+    fn named_path(&self, parent: u64, name: &OsStr) -> Result<String, i32> {
+        let name = self.names.encode(name)?;
+        self.inodes.child_path(parent, &name).ok_or(libc::ENOENT)
+    }
+
+    // Why a change can't be made at `path`, if it can't: the mount is
+    // read-only, the path is in a snapshot, or the daemon is draining
+    fn refuses_changes(&self, path: &str) -> Result<(), i32> {
+        if self.read_only || self.snapshot_path(path).is_some() {
+            return Err(libc::EROFS);
+        }
+        match self.draining() {
+            true => Err(libc::ESHUTDOWN),
+            false => Ok(()),
+        }
+    }
+
+    // Empties the file at `path`, after sending what was buffered for it so it
+    // doesn't land on the emptied file later
+    fn truncate(&mut self, req: &Request, path: &str) -> Result<FileAttr, i32> {
+        self.refuses_changes(path)?;
+        self.sync_path(path).map_err(|e| e.errno())?;
+        let created = self.create_file(path, None);
+        self.audit(req, AuditOp::Create, path, None, None, created.as_ref().err().map(|e| e.errno()));
+        created.map_err(|e| e.errno())
+    }
+
+    // Sends every write to `path` made so far, through any handle, and waits
+    // for the DO to make them durable
+    fn sync_path(&mut self, path: &str) -> Result<(), DaemonError> {
+        self.flush_writeback(path)?;
+        let handles: Vec<u64> =
+            self.write_buffers.iter().filter(|(_, pending)| pending.path == path).map(|(fh, _)| *fh).collect();
+        for fh in handles {
+            self.flush_handle(fh)?;
+        }
+        self.client.block_on(self.client.sync(path))
+    }
+
+    // Sends what handle `fh` buffered and forgets it
+    fn release_handle(&mut self, fh: u64) -> Result<(), DaemonError> {
+        self.fsctl.release(fh);
+        let result = self.flush_handle(fh);
+        // The handle is gone either way, so don't keep its unsent data around
+        if let Some(pending) = self.write_buffers.remove(&fh) {
+            self.recount(&pending.path);
+        }
+        if let Some(readahead) = self.readahead.remove(&fh) {
+            self.client.buffers.give(readahead.buffer);
+        }
+        result
+    }
+}
+
+impl Filesystem for RemoteFS {
+    fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> Result<(), libc::c_int> {
+        // Keep kernel requests within what one protocol frame can carry
+        let max_write = match config.set_max_write(MAX_IO_SIZE as u32) {
+            Ok(_) => MAX_IO_SIZE as u32,
+            Err(nearest) => {
+                let _ = config.set_max_write(nearest);
+                nearest
+            }
+        };
+        let max_readahead = match config.set_max_readahead(MAX_IO_SIZE as u32) {
+            Ok(_) => MAX_IO_SIZE as u32,
+            Err(nearest) => {
+                let _ = config.set_max_readahead(nearest);
+                nearest
+            }
+        };
+        // Unless emulated, locks come to the daemon so its policy decides the answer
+        if self.unsupported.locks != Policy::Emulate && config.add_capabilities(consts::FUSE_POSIX_LOCKS).is_err() {
+            warn!("Kernel does not support remote POSIX locks, locking stays local");
+        }
+        if self.kernel_cache.writeback_cache && config.add_capabilities(consts::FUSE_WRITEBACK_CACHE).is_err() {
+            warn!("Kernel does not support the writeback cache, writes go through to the daemon");
+        }
+
+        info!(
+            "Negotiated kernel limits: max_read={} max_write={} max_readahead={}",
+            MAX_IO_SIZE, max_write, max_readahead
+        );
+        self.started(max_write, max_readahead);
+        Ok(())
+    }
+
+    // Runs once the filesystem is unmounted: whatever applications wrote that
+    // the DO doesn't have yet is pushed out before the daemon exits. Write-back
+    // data that can't be sent stays in the spill directory, if there is one,
+    // for the next start to replay.
+    fn destroy(&mut self) {
+        self.stopped();
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let _correlation = Correlation::begin("lookup", req.pid());
+        let path = match self.named_path(parent, name) {
+            Ok(path) => path,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        match self.path_attr(&path) {
+            Ok(Some(attr)) => {
+                let ino = self.inodes.lookup(&path);
+                reply.entry(&Duration::from_secs(1), &self.seen_by(&path, FileAttr { ino, ..attr }, req), 0);
+            }
+            Ok(None) => reply.error(libc::ENOENT),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
+        let _correlation = Correlation::begin("getattr", req.pid());
+        let Some(path) = self.inodes.path(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        match self.current_attr(&path) {
+            Ok(Some(attr)) => reply.attr(&Duration::from_secs(1), &self.seen_by(&path, FileAttr { ino, ..attr }, req)),
+            Ok(None) => reply.error(libc::ENOENT),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    // Checked against the mode and the owner the caller sees, for access(2)
+    // and chdir; without --default-permissions the kernel checks nothing else
+    fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let _correlation = Correlation::begin("access", req.pid());
+        let Some(path) = self.inodes.path(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        if mask & libc::W_OK != 0 && (self.read_only || self.snapshot_path(&path).is_some()) {
+            reply.error(libc::EROFS);
+            return;
+        }
+        match self.path_attr(&path) {
+            Ok(Some(attr)) if owner::permitted(&self.seen_by(&path, attr, req), req.into(), mask) => reply.ok(),
+            Ok(Some(_)) => reply.error(libc::EACCES),
+            Ok(None) => reply.error(libc::ENOENT),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn read(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let _correlation = Correlation::begin("read", req.pid());
+        let Some(path) = self.inodes.path(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        self.read_data(fh, &path, offset as u64, size as u64, |data| match data {
+            Ok(data) => reply.data(data),
+            Err(e) => reply.error(e.errno()),
+        });
+    }
+
+    fn write(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        let _correlation = Correlation::begin("write", req.pid());
+        let Some(path) = self.inodes.path(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        if self.fsctl.is_open(fh) {
+            let result = self.control(&String::from_utf8_lossy(data));
+            let errno = result.as_ref().err().map(|(errno, _)| *errno);
+            self.fsctl.answer(fh, &result.map_err(|(_, message)| message));
+            match errno {
+                None => reply.written(data.len() as u32),
+                Some(errno) => reply.error(errno),
+            }
+            return;
+        }
+        let offset = offset as u64;
+        let result = self.write_data(fh, &path, offset, data);
+        self.audit(req, AuditOp::Write, &path, Some(offset), Some(data.len() as u64), result.err());
+        match result {
+            Ok(()) => {
+                METRICS.written(data.len());
+                reply.written(data.len() as u32);
+            }
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    // The control file takes attribute changes and ignores them, so that
+    // `echo flush > .fsctl` can truncate it on the way in. Files take a new
+    // mode from DOs that keep them and can be truncated to nothing; other
+    // changes fail, with EFBIG for a size over the limit.
+    fn setattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let _correlation = Correlation::begin("setattr", req.pid());
+        match (self.inodes.path(ino), mode) {
+            (Some(path), _) if self.fsctl.is(&path) => {
+                let attr = self.special_attr(ino, &path, &FSCTL_NODE);
+                reply.attr(&Duration::from_secs(1), &self.seen_by(&path, attr, req));
+            }
+            (Some(path), _) if size == Some(0) => {
+                let truncated = self.truncate(req, &path);
+                let result = match (truncated, mode) {
+                    (Ok(_), Some(mode)) if self.client.has_feature("modes") => self.chmod(&path, mode),
+                    (result, _) => result,
+                };
+                match result {
+                    Ok(attr) => {
+                        reply.attr(&Duration::from_secs(1), &self.seen_by(&path, FileAttr { ino, ..attr }, req))
+                    }
+                    Err(errno) => reply.error(errno),
+                }
+            }
+            (Some(path), Some(mode)) if size.is_none() && self.client.has_feature("modes") => {
+                match self.chmod(&path, mode) {
+                    Ok(attr) => {
+                        reply.attr(&Duration::from_secs(1), &self.seen_by(&path, FileAttr { ino, ..attr }, req))
+                    }
+                    Err(errno) => reply.error(errno),
+                }
+            }
+            _ => reply.error(size.and_then(|size| self.fits(size).err()).unwrap_or(libc::ENOSYS)),
+        }
+    }
+
+    // The DO can't reserve space, so fallocate fails: with EFBIG past the
+    // limit, and otherwise EOPNOTSUPP rather than ENOSYS, which would stop the
+    // kernel asking at all
+    fn fallocate(
+        &mut self,
+        req: &Request,
+        _ino: u64,
+        _fh: u64,
+        offset: i64,
+        length: i64,
+        _mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        let _correlation = Correlation::begin("fallocate", req.pid());
+        match self.fits((offset as u64).saturating_add(length as u64)) {
+            Ok(()) => reply.error(libc::EOPNOTSUPP),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn flush(&mut self, req: &Request, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        let _correlation = Correlation::begin("flush", req.pid());
+        if let (Some(writeback), Some(path)) = (&self.writeback, self.inodes.path(ino)) {
+            // Close-to-open promises the data is in the DO by the time close returns
+            if self.consistency == Consistency::CloseToOpen {
+                if let Err(e) = self.flush_writeback(&path) {
+                    reply.error(e.errno());
+                    return;
+                }
+            } else {
+                writeback.request_flush(&path);
+            }
+        }
+        match self.flush_handle(fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    // A barrier: every write to the file made before fsync, through any
+    // handle, is in the DO and durable by the time it returns
+    fn fsync(&mut self, req: &Request, ino: u64, fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let _correlation = Correlation::begin("fsync", req.pid());
+        let Some(path) = self.inodes.path(ino) else {
+            match self.flush_handle(fh) {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e.errno()),
+            }
+            return;
+        };
+        match self.sync_path(&path) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        // The kernel never interrupts a release, which may come after the
+        // process that opened the file is gone, so what it flushes can't be cut short
+        let _correlation = Correlation::begin("release", 0);
+        match self.release_handle(fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let _correlation = Correlation::begin("readdir", req.pid());
+        let Some(path) = self.inodes.path(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        let names = self.names;
+        let listed = self.list_dir(ino, &path, offset.max(0) as u64, |ino, position, kind, name| {
+            reply.add(ino, position as i64, kind, names.decode(name))
+        });
+        match listed {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _correlation = Correlation::begin("unlink", req.pid());
+        if self.draining() {
+            reply.error(libc::ESHUTDOWN);
+            return;
+        }
+        let path = match self.named_path(parent, name) {
+            Ok(path) => path,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        let result = self.unlink_path(&path);
+        self.audit(req, AuditOp::Delete, &path, None, None, result.err());
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    // The room under the mount's quota, in 4 KiB blocks
+    fn statfs(&mut self, req: &Request, _ino: u64, reply: ReplyStatfs) {
+        let _correlation = Correlation::begin("statfs", req.pid());
+        let space = self.space();
+        let (blocks, free) = (space.bytes / 4096, space.bytes_free / 4096);
+        reply.statfs(blocks, free, free, space.files, space.files_free, 4096, 255, 4096);
+    }
+
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        self.inodes.forget(ino, nlookup);
+    }
+
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        let _correlation = Correlation::begin("open", req.pid());
+        if self.draining() {
+            reply.error(libc::ESHUTDOWN);
+            return;
+        }
+        let mut open_flags = self.kernel_cache.open_flags();
+        let Some(path) = self.inodes.path(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        let mode = if flags & libc::O_ACCMODE == libc::O_RDONLY {
+            LeaseMode::Read
+        } else {
+            LeaseMode::Write
+        };
+
+        if self.fsctl.is(&path) {
+            let fh = self.next_handle();
+            self.fsctl.open(fh, self.control_status());
+            // Its size is always 0, so reads have to get past the page cache
+            reply.opened(fh, consts::FOPEN_DIRECT_IO);
+            return;
+        }
+        let snapshot = self.snapshot_path(&path).is_some();
+        if snapshot && mode == LeaseMode::Write {
+            reply.error(libc::EROFS);
+            return;
+        }
+
+        if snapshot {
+            // Nothing in a snapshot ever changes, so the kernel's pages are good
+            if !self.kernel_cache.direct_io {
+                open_flags |= consts::FOPEN_KEEP_CACHE;
+            }
+        } else if self.leased(&path, mode) {
+            // Nothing can have changed while the lease was held, so the kernel's pages are good
+            if !self.kernel_cache.direct_io {
+                open_flags |= consts::FOPEN_KEEP_CACHE;
+            }
+        } else if !self.special_nodes.contains_key(&path) {
+            // The lease comes first so the attributes fetched below stay valid while it is held
+            let acquired = match self.leases.clone() {
+                Some(leases) => self.client.block_on(leases.acquire(&self.client, &path, mode)),
+                None => false,
+            };
+
+            // Revalidate on open, keeping the kernel's pages if the file hasn't changed since
+            if acquired || self.consistency == Consistency::CloseToOpen {
+                let previous = self.versions.get(&path).copied();
+                match self.stat_path(&path) {
+                    Ok(Some(_)) => {}
+                    Ok(None) => {
+                        reply.error(libc::ENOENT);
+                        return;
+                    }
+                    Err(e) => {
+                        reply.error(e.errno());
+                        return;
+                    }
+                }
+                if previous.is_some() && previous == self.versions.get(&path).copied() && !self.kernel_cache.direct_io {
+                    open_flags |= consts::FOPEN_KEEP_CACHE;
+                }
+            }
+        }
+
+        reply.opened(self.next_handle(), open_flags);
+    }
+
+    fn create(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _flags: i32,
         reply: ReplyCreate,
     ) {
-        let path = format!("/{}", name.to_string_lossy());
-        
-        let rt = tokio::runtime::Runtime::new().unwrap();
-        match rt.block_on(self.client.send_request("write", &path, Some(vec![]), None, None)) {
-            Ok(_) => {
-                // Return fake attributes for created file
-                let attr = FileAttr {
-                    ino: 2,
-                    size: 0,
-                    blocks: 0,
-                    atime: SystemTime::now(),
-                    mtime: SystemTime::now(),
-                    ctime: SystemTime::now(),
-                    crtime: SystemTime::now(),
-                    kind: FileType::RegularFile,
-                    perm: 0o644,
-                    nlink: 1,
-                    uid: unsafe { libc::getuid() },
-                    gid: unsafe { libc::getgid() },
-                    rdev: 0,
-                    flags: 0,
-                    blksize: 4096,
-                };
-                let fh = {
-                    let mut next_fh = self.next_fh.lock().unwrap();
-                    *next_fh += 1;
-                    *next_fh
-                };
-                reply.created(&Duration::from_secs(1), &attr, 0, fh, 0);
+        let _correlation = Correlation::begin("create", req.pid());
+        if self.draining() {
+            reply.error(libc::ESHUTDOWN);
+            return;
+        }
+        let path = match self.named_path(parent, name) {
+            Ok(path) => path,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        let created = self.create_file(&path, Some(mode & !umask));
+        self.audit(req, AuditOp::Create, &path, None, None, created.as_ref().err().map(|e| e.errno()));
+        match created {
+            Ok(attr) => {
+                let fh = self.next_handle();
+                let attr = self.seen_by(&path, attr, req);
+                reply.created(&Duration::from_secs(1), &attr, 0, fh, self.kernel_cache.open_flags());
+            }
+            Err(e) => reply.error(e.errno()),
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let _correlation = Correlation::begin("mknod", req.pid());
+        if self.draining() {
+            reply.error(libc::ESHUTDOWN);
+            return;
+        }
+        let path = match self.named_path(parent, name) {
+            Ok(path) => path,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        if self.snapshot_path(&path).is_some() {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let Some(kind) = special_kind(mode) else {
+            let created = self.create_file(&path, Some(mode & !umask));
+            let errno = created.as_ref().err().map(|e| e.errno());
+            self.audit(req, AuditOp::Create, &path, None, None, errno);
+            match created {
+                Ok(attr) => reply.entry(&Duration::from_secs(1), &self.seen_by(&path, attr, req), 0),
+                Err(e) => reply.error(e.errno()),
+            }
+            return;
+        };
+        if self.unsupported.mknod == Policy::Enosys {
+            reply.error(libc::ENOSYS);
+            return;
+        }
+
+        let node = SpecialNode {
+            kind,
+            perm: (mode & !umask & 0o7777) as u16,
+            rdev,
+        };
+        let ino = self.inodes.lookup(&path);
+        let attr = self.seen_by(&path, self.special_attr(ino, &path, &node), req);
+        if self.unsupported.mknod == Policy::Emulate {
+            self.invalidate_parent(&path);
+            self.audit(req, AuditOp::Create, &path, None, None, None);
+            self.special_nodes.insert(path, node);
+        }
+        reply.entry(&Duration::from_secs(1), &attr, 0);
+    }
+
+    fn getlk(
+        &mut self,
+        req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+        start: u64,
+        end: u64,
+        _typ: i32,
+        _pid: u32,
+        reply: ReplyLock,
+    ) {
+        let _correlation = Correlation::begin("getlk", req.pid());
+        match self.unsupported.locks {
+            Policy::Succeed => reply.locked(start, end, libc::F_UNLCK, 0),
+            _ => reply.error(libc::ENOSYS),
+        }
+    }
+
+    fn setlk(
+        &mut self,
+        req: &Request,
+        _ino: u64,
+        _fh: u64,
+        _lock_owner: u64,
+        _start: u64,
+        _end: u64,
+        _typ: i32,
+        _pid: u32,
+        _sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        let _correlation = Correlation::begin("setlk", req.pid());
+        match self.unsupported.locks {
+            Policy::Succeed => reply.ok(),
+            _ => reply.error(libc::ENOSYS),
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let _correlation = Correlation::begin("setxattr", req.pid());
+        let Some(path) = self.inodes.path(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        match self.unsupported.xattrs {
+            Policy::Enosys => reply.error(libc::ENOSYS),
+            Policy::Succeed => reply.ok(),
+            Policy::Emulate => match self.xattrs.set(&path, name, value, flags) {
+                Ok(()) => reply.ok(),
+                Err(errno) => reply.error(errno),
+            },
+        }
+    }
+
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let _correlation = Correlation::begin("getxattr", req.pid());
+        let Some(path) = self.inodes.path(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        if self.unsupported.xattrs == Policy::Enosys {
+            reply.error(libc::ENOSYS);
+            return;
+        }
+
+        // Under the succeed policy nothing was ever stored, so every lookup misses
+        match self.xattrs.get(&path, name) {
+            Some(value) if size == 0 => reply.size(value.len() as u32),
+            Some(value) if value.len() > size as usize => reply.error(libc::ERANGE),
+            Some(value) => reply.data(value),
+            None => reply.error(libc::ENODATA),
+        }
+    }
+
+    fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        let _correlation = Correlation::begin("listxattr", req.pid());
+        let Some(path) = self.inodes.path(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        if self.unsupported.xattrs == Policy::Enosys {
+            reply.error(libc::ENOSYS);
+            return;
+        }
+
+        let names = self.xattrs.list(&path);
+        if size == 0 {
+            reply.size(names.len() as u32);
+        } else if names.len() > size as usize {
+            reply.error(libc::ERANGE);
+        } else {
+            reply.data(&names);
+        }
+    }
+
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _correlation = Correlation::begin("removexattr", req.pid());
+        let Some(path) = self.inodes.path(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        match self.unsupported.xattrs {
+            Policy::Enosys => reply.error(libc::ENOSYS),
+            Policy::Succeed => reply.ok(),
+            Policy::Emulate => match self.xattrs.remove(&path, name) {
+                Ok(()) => reply.ok(),
+                Err(errno) => reply.error(errno),
+            },
+        }
+    }
+}
+
+// What the kernel may cache on its own, trading consistency with the DO for fewer round trips
+#[derive(Clone, Copy, Default)]
+struct KernelCache {
+    // Keep cached pages when a file is reopened instead of dropping them
+    keep_cache: bool,
+    // Let the kernel buffer writes in its page cache and send them later
+    writeback_cache: bool,
+    // Bypass the page cache so every read and write reaches the daemon
+    direct_io: bool,
+}
+
+// How every mount is presented to the kernel, beyond read-only, which each
+// mount sets for itself
+#[derive(Clone)]
+struct KernelMount {
+    // Let root in besides the daemon's user, rather than every user
+    allow_root: bool,
+    // Have the kernel check the mode bits before asking the daemon
+    default_permissions: bool,
+    noatime: bool,
+    // Source and type shown in /proc/mounts
+    fsname: Option<String>,
+    subtype: Option<String>,
+    // Largest read the kernel sends at once
+    max_read: usize,
+}
+
+impl Default for KernelMount {
+    fn default() -> Self {
+        Self {
+            allow_root: false,
+            default_permissions: false,
+            noatime: false,
+            fsname: None,
+            subtype: None,
+            max_read: MAX_IO_SIZE,
+        }
+    }
+}
+
+impl KernelMount {
+    fn options(&self, read_only: bool) -> Vec<MountOption> {
+        let mut options = vec![
+            match self.allow_root {
+                true => MountOption::AllowRoot,
+                false => MountOption::AllowOther,
+            },
+            MountOption::AutoUnmount,
+            MountOption::CUSTOM(format!("max_read={}", self.max_read)),
+        ];
+        options.extend(self.default_permissions.then_some(MountOption::DefaultPermissions));
+        options.extend(self.noatime.then_some(MountOption::NoAtime));
+        options.extend(self.fsname.clone().map(MountOption::FSName));
+        options.extend(self.subtype.clone().map(MountOption::Subtype));
+        if read_only {
+            options.push(MountOption::RO);
+        }
+        options
+    }
+}
+
+impl KernelCache {
+    fn open_flags(&self) -> u32 {
+        let mut flags = 0;
+        if self.keep_cache {
+            flags |= consts::FOPEN_KEEP_CACHE;
+        }
+        if self.direct_io {
+            flags |= consts::FOPEN_DIRECT_IO;
+        }
+        flags
+    }
+}
+
+// How applications reach the filesystem
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Frontend {
+    // Mounted through the kernel's FUSE driver
+    Fuse,
+    // Exported over NFSv3 on loopback, for containers that can't use FUSE
+    Nfs,
+    // Served over 9P2000.L on loopback, which kernels and VM runtimes mount natively
+    NineP,
+}
+
+impl Frontend {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "fuse" => Ok(Frontend::Fuse),
+            "nfs" => Ok(Frontend::Nfs),
+            "9p" => Ok(Frontend::NineP),
+            _ => Err("expected fuse, nfs or 9p".to_string()),
+        }
+    }
+}
+
+// When changes made through this mount reach the DO, and when changes made
+// elsewhere become visible here
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Consistency {
+    // Writes reach the DO by the time close or a full coalescing buffer returns
+    // and attributes are fetched on every getattr
+    Strict,
+    // Attributes are revalidated on open and dirty data flushed on close;
+    // everything in between is served from cache
+    CloseToOpen,
+    // Writes are acknowledged once buffered and flushed in the background
+    WriteBack,
+}
+
+impl Consistency {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "strict" => Ok(Consistency::Strict),
+            "close-to-open" | "cto" => Ok(Consistency::CloseToOpen),
+            "write-back" => Ok(Consistency::WriteBack),
+            _ => Err("expected strict, close-to-open or write-back".to_string()),
+        }
+    }
+}
+
+struct MountOptions {
+    // The main mount, then those added with --mount
+    mounts: Vec<Mount>,
+    // Other DOs shown as directories at the main mount's root
+    namespaces: Vec<NamespaceSpec>,
+    // Where to write the status document once mounted, "-" for stdout
+    status_json: Option<String>,
+    // How locks, xattrs and mknod are answered, since the DO supports none of them
+    unsupported: UnsupportedPolicy,
+    // What becomes of names the DO can't hold as they are
+    names: NamePolicy,
+    // Who files are shown as owned by
+    ownership: Ownership,
+    // Shell command started once the filesystem is serving requests
+    post_mount_exec: Option<String>,
+    // Shell command run to completion on shutdown, before the filesystem is unmounted
+    pre_unmount_exec: Option<String>,
+    // How long shutdown may take once signalled before the daemon exits regardless
+    shutdown_timeout: Duration,
+    // How a mount that goes away underneath the daemon is mounted again
+    remount: Remount,
+    // Whether mounts go through FUSE or are exported over NFS or 9p
+    frontend: Frontend,
+    // Where the NFS frontend takes clients
+    nfs_listen: String,
+    // Where the 9p frontend takes clients
+    ninep_listen: String,
+    kernel_cache: KernelCache,
+    kernel_mount: KernelMount,
+    tunables: Tunables,
+    // Local directory that evicted cache blocks and write-back data overflow into
+    spill_dir: Option<String>,
+    spill_size: u64,
+    // File every write is recorded in before it is acknowledged
+    journal: Option<String>,
+    // Ask the DO for leases on opened files and cache leased files aggressively
+    leases: bool,
+    // Limits on directories of the DO, besides the mounts' own
+    quotas: Vec<Quota>,
+    // Largest size a file may reach, past which writes fail with EFBIG
+    max_file_size: Option<u64>,
+    // How often what counts against the quotas is counted again in the DO
+    quota_reconcile_interval: Duration,
+    // Where files live, when not in a DO
+    backend: Backend,
+    // Preferred message encoding; MessagePack falls back to JSON if the DO declines
+    encoding: Encoding,
+    // Connections to accept from the DO, when it pools them
+    connections: usize,
+    // Offer LZ4 compression of large payloads
+    compression: bool,
+    // Ping idle connections and close those that stop answering; None disables
+    heartbeat: Option<Heartbeat>,
+    // Requests awaiting a response at once; further operations wait for a slot
+    max_in_flight: usize,
+    // Longest frame accepted from the DO; longer messages arrive as fragments
+    max_frame_size: usize,
+    // Whether to wait for the DO to connect or connect out to it, and over TLS or not
+    transport: Transport,
+    log_format: LogFormat,
+    // Where to serve Prometheus metrics, if anywhere
+    metrics_listen: Option<String>,
+    // Where operators send commands to the running daemon, if anywhere
+    admin_socket: Option<Address>,
+    // Where applications subscribe to changes the DO pushes, if anywhere
+    events_socket: Option<Address>,
+    // Where the mounts are served over WebDAV too, if anywhere
+    webdav_listen: Option<Address>,
+    // Where the mounts are served over SFTP too, if anywhere, where the SSH
+    // host key it identifies itself with is kept, and whether clients may log
+    // in as anyone rather than only as the daemon's user
+    sftp_listen: Option<String>,
+    sftp_host_key: Option<PathBuf>,
+    sftp_authorized_keys: Option<PathBuf>,
+    sftp_allow_any_user: bool,
+    // Where the mounts are served as S3 buckets too, if anywhere
+    s3_listen: Option<Address>,
+    // Where to record the mutations applications make, if anywhere
+    audit: Option<AuditOptions>,
+    // Send each request's trace context to the DO
+    trace_context: bool,
+    // Requests taking longer are logged with their timings
+    slow_threshold: Option<Duration>,
+    // Requests unanswered for longer are reported as stuck; None turns the
+    // watchdog off
+    stuck_threshold: Option<Duration>,
+}
+
+// One filesystem the daemon serves over the shared connection to the DO
+#[derive(Clone, Debug)]
+struct Mount {
+    mount_point: String,
+    // Path on the DO shown as the mount's root. Mounts of the same prefix
+    // share a block cache.
+    prefix: String,
+    read_only: bool,
+    consistency: Consistency,
+    cache: CachePolicy,
+    // Limits on the bytes and files under the prefix, if any
+    quota: Option<Quota>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct CachePolicy {
+    // Memory for cached file data, and how long listings are reused
+    cache_size: usize,
+    dir_cache_ttl: Duration,
+    // How far past a sequential read to prefetch, and how much prefetched
+    // data the mount's handles may hold between them
+    readahead_window: usize,
+    readahead_memory: usize,
+}
+
+// A --mount value, `<mount point>:<prefix>` then settings of its own, such as
+// `/config:/config,read-only,cache-size=1048576`. Settings left out are the
+// main mount's, except for the quota.
+#[derive(Clone, Debug)]
+struct MountSpec {
+    mount_point: String,
+    prefix: String,
+    read_only: bool,
+    consistency: Option<Consistency>,
+    cache_size: Option<usize>,
+    dir_cache_ttl: Option<Duration>,
+    readahead_window: Option<usize>,
+    readahead_memory: Option<usize>,
+    quota_bytes: Option<u64>,
+    quota_files: Option<u64>,
+}
+
+impl MountSpec {
+    fn parse(value: &str) -> Result<Self, String> {
+        let mut settings = value.split(',');
+        let (mount_point, prefix) = settings
+            .next()
+            .and_then(|paths| paths.split_once(':'))
+            .filter(|(mount_point, _)| !mount_point.is_empty())
+            .ok_or("expected <mount point>:<prefix>[,<setting>=<value>,...]")?;
+        let mut spec = Self {
+            mount_point: mount_point.to_string(),
+            prefix: parse_prefix(prefix)?,
+            read_only: false,
+            consistency: None,
+            cache_size: None,
+            dir_cache_ttl: None,
+            readahead_window: None,
+            readahead_memory: None,
+            quota_bytes: None,
+            quota_files: None,
+        };
+        for setting in settings {
+            let named = |e: String| format!("{}: {}", setting, e);
+            match setting.split_once('=') {
+                None if setting == "read-only" => spec.read_only = true,
+                Some(("consistency", mode)) => spec.consistency = Some(Consistency::parse(mode).map_err(named)?),
+                Some(("cache-size", size)) => spec.cache_size = Some(cli::positive(size).map_err(named)?),
+                Some(("dir-cache-ttl", ttl)) => {
+                    spec.dir_cache_ttl = Some(humantime::parse_duration(ttl).map_err(|e| named(e.to_string()))?)
+                }
+                Some(("readahead-window", size)) => spec.readahead_window = Some(cli::positive(size).map_err(named)?),
+                Some(("readahead-memory", size)) => spec.readahead_memory = Some(cli::positive(size).map_err(named)?),
+                Some(("quota-bytes", bytes)) => spec.quota_bytes = Some(cli::positive(bytes).map_err(named)?),
+                Some(("quota-files", files)) => spec.quota_files = Some(cli::positive(files).map_err(named)?),
+                _ => {
+                    return Err(format!(
+                        "unknown setting '{}', expected read-only, consistency, cache-size, dir-cache-ttl, \
+                         readahead-window, readahead-memory, quota-bytes or quota-files",
+                        setting
+                    ))
+                }
+            }
+        }
+        Ok(spec)
+    }
+}
+
+// An absolute path on the DO without a trailing slash, unless it is the root,
+// or empty, . or .. components
+fn parse_prefix(value: &str) -> Result<String, String> {
+    let valid = value == "/"
+        || value.strip_prefix('/').is_some_and(|rest| rest.split('/').all(|part| !matches!(part, "" | "." | "..")));
+    match valid {
+        true => Ok(value.to_string()),
+        false => Err("expected an absolute path, such as /config".to_string()),
+    }
+}
+
+// Settings that can change while mounted, on SIGHUP or the admin `reload`
+// command
+#[derive(Clone, Debug)]
+struct Tunables {
+    // Levels to log in place of RUST_LOG
+    log_level: Option<String>,
+    timeouts: Timeouts,
+    // Each mount's, the main one first
+    caches: Vec<CachePolicy>,
+}
+
+impl MountOptions {
+    fn parse(matches: &ArgMatches) -> Result<Self, clap::Error> {
+        let string = |id: &str| matches.get_one::<String>(id).cloned();
+        let duration = |id: &str, default: Duration| matches.get_one(id).copied().unwrap_or(default);
+        let count = |id: &str, default: usize| matches.get_one(id).copied().unwrap_or(default);
+
+        let mut unsupported = UnsupportedPolicy::default();
+        for spec in matches.get_many::<String>("unsupported").into_iter().flatten() {
+            unsupported.apply(spec).map_err(|e| {
+                cli::command().error(ErrorKind::InvalidValue, format!("invalid --unsupported '{}': {}", spec, e))
+            })?;
+        }
+        let heartbeat = Heartbeat {
+            interval: duration("heartbeat-interval", DEFAULT_HEARTBEAT_INTERVAL),
+            timeout: duration("heartbeat-timeout", DEFAULT_HEARTBEAT_TIMEOUT),
+        };
+        if !heartbeat.interval.is_zero() && heartbeat.timeout <= heartbeat.interval {
+            return Err(cli::command().error(
+                ErrorKind::ArgumentConflict,
+                "--heartbeat-timeout must be longer than --heartbeat-interval",
+            ));
+        }
+        let mut backend = matches.get_one("backend").cloned().unwrap_or(Backend::Remote);
+        let tier_options = ["tier-high-watermark", "tier-low-watermark", "tier-writeback-delay", "tier-promote-after"];
+        match &mut backend {
+            Backend::Tier(tiering) => {
+                if let Some(&high) = matches.get_one("tier-high-watermark") {
+                    tiering.high_watermark = high;
+                }
+                tiering.low_watermark = matches.get_one("tier-low-watermark").copied();
+                tiering.writeback_delay = duration("tier-writeback-delay", tiering.writeback_delay);
+                if let Some(&reads) = matches.get_one("tier-promote-after") {
+                    tiering.promote_after = reads;
+                }
+                if tiering.low_watermark.is_some_and(|low| low > tiering.high_watermark) {
+                    return Err(cli::command().error(
+                        ErrorKind::ArgumentConflict,
+                        "--tier-low-watermark can't be above --tier-high-watermark",
+                    ));
+                }
+            }
+            _ => {
+                if let Some(id) = tier_options.into_iter().find(|id| matches.contains_id(id)) {
+                    let message = format!("--{} needs --backend=tier:<directory>", id);
+                    return Err(cli::command().error(ErrorKind::ArgumentConflict, message));
+                }
+            }
+        }
+        // Only an overlay or a tier has a DO under it to find
+        let served = matches!(backend, Backend::Memory | Backend::Local(_));
+        if let Some(id) = ["listen", "connect", "tls-cert"].into_iter().find(|id| served && matches.contains_id(id)) {
+            let message = format!("--{} can't be used with a backend served without a DO", id);
+            return Err(cli::command().error(ErrorKind::ArgumentConflict, message));
+        }
+        let stuck_threshold = duration("stuck-threshold", DEFAULT_STUCK_THRESHOLD);
+        let max_frame_size = count("max-frame-size", DEFAULT_MAX_FRAME_SIZE);
+        let chunk_size = count("transfer-chunk-size", DEFAULT_TRANSFER_CHUNK_SIZE);
+        if chunk_size + FRAME_HEADER_ROOM > max_frame_size {
+            return Err(cli::command().error(
+                ErrorKind::ArgumentConflict,
+                "--transfer-chunk-size must leave 4 KiB of --max-frame-size for the message header",
+            ));
+        }
+        let prefix = string("prefix").unwrap_or_else(|| "/".to_string());
+        let main = Mount {
+            mount_point: string("mount-point").unwrap_or_else(|| "/storage".to_string()),
+            quota: Quota::mount(
+                &prefix,
+                matches.get_one("quota-bytes").copied(),
+                matches.get_one("quota-files").copied(),
+            ),
+            prefix,
+            read_only: matches.get_flag("read-only"),
+            consistency: match matches.get_flag("write-back") {
+                true => Consistency::WriteBack,
+                false => matches.get_one("consistency").copied().unwrap_or(Consistency::Strict),
+            },
+            cache: CachePolicy {
+                cache_size: count("cache-size", DEFAULT_CACHE_SIZE),
+                dir_cache_ttl: duration("dir-cache-ttl", DEFAULT_DIR_CACHE_TTL),
+                readahead_window: count("readahead-window", DEFAULT_READAHEAD_WINDOW),
+                readahead_memory: count("readahead-memory", DEFAULT_READAHEAD_MEMORY),
+            },
+        };
+        let mut mounts = vec![main.clone()];
+        for spec in matches.get_many::<MountSpec>("mount").into_iter().flatten() {
+            if mounts.iter().any(|mount| mount.mount_point == spec.mount_point) {
+                let message = format!("{} is mounted more than once", spec.mount_point);
+                return Err(cli::command().error(ErrorKind::ArgumentConflict, message));
             }
-            Err(_) => reply.error(libc::EIO),
+            mounts.push(Mount {
+                mount_point: spec.mount_point.clone(),
+                prefix: spec.prefix.clone(),
+                read_only: spec.read_only || main.read_only,
+                consistency: spec.consistency.unwrap_or(main.consistency),
+                cache: CachePolicy {
+                    cache_size: spec.cache_size.unwrap_or(main.cache.cache_size),
+                    dir_cache_ttl: spec.dir_cache_ttl.unwrap_or(main.cache.dir_cache_ttl),
+                    readahead_window: spec.readahead_window.unwrap_or(main.cache.readahead_window),
+                    readahead_memory: spec.readahead_memory.unwrap_or(main.cache.readahead_memory),
+                },
+                quota: Quota::mount(&spec.prefix, spec.quota_bytes, spec.quota_files),
+            });
+        }
+        let mut namespaces: Vec<NamespaceSpec> = Vec::new();
+        for spec in matches.get_many::<NamespaceSpec>("namespace").into_iter().flatten() {
+            if namespaces.iter().any(|namespace| namespace.name == spec.name) {
+                let message = format!("namespace {} is given more than once", spec.name);
+                return Err(cli::command().error(ErrorKind::ArgumentConflict, message));
+            }
+            // A daemon listening for the DO can't listen for a second session
+            // there; a backend in the daemon is dialed either way
+            if spec.endpoint.is_none() && backend == Backend::Remote && !matches.contains_id("connect") {
+                let message = format!("namespace {} needs connect= or listen= unless --connect is given", spec.name);
+                return Err(cli::command().error(ErrorKind::ArgumentConflict, message));
+            }
+            namespaces.push(spec.clone());
+        }
+        let tunables = Tunables {
+            log_level: string("log-level"),
+            timeouts: Timeouts::parse(matches),
+            caches: mounts.iter().map(|mount| mount.cache).collect(),
+        };
+
+        Ok(Self {
+            mounts,
+            namespaces,
+            status_json: string("status-json"),
+            unsupported,
+            names: matches.get_one("name-policy").copied().unwrap_or(NamePolicy::Reject),
+            ownership: Ownership::new(
+                matches.get_one("owner").copied().unwrap_or(Owner::Daemon),
+                matches.get_many::<OwnerEntry>("owner-map").into_iter().flatten().cloned().collect(),
+            ),
+            post_mount_exec: string("post-mount-exec"),
+            pre_unmount_exec: string("pre-unmount-exec"),
+            shutdown_timeout: duration("shutdown-timeout", DEFAULT_SHUTDOWN_TIMEOUT),
+            remount: Remount {
+                attempts: count("remount-attempts", supervisor::DEFAULT_REMOUNT_ATTEMPTS),
+                backoff: duration("remount-backoff", supervisor::DEFAULT_REMOUNT_BACKOFF),
+            },
+            kernel_cache: KernelCache {
+                keep_cache: matches.get_flag("keep-cache"),
+                writeback_cache: matches.get_flag("kernel-writeback-cache"),
+                direct_io: matches.get_flag("direct-io"),
+            },
+            frontend: matches.get_one("frontend").copied().unwrap_or(Frontend::Fuse),
+            nfs_listen: string("nfs-listen").unwrap_or_else(|| nfs::DEFAULT_NFS_LISTEN.to_string()),
+            ninep_listen: string("9p-listen").unwrap_or_else(|| ninep::DEFAULT_9P_LISTEN.to_string()),
+            kernel_mount: KernelMount {
+                allow_root: string("allow").is_some_and(|allow| allow == "root"),
+                default_permissions: matches.get_flag("default-permissions"),
+                noatime: matches.get_flag("noatime"),
+                fsname: string("fsname"),
+                subtype: string("subtype"),
+                max_read: count("max-read", MAX_IO_SIZE),
+            },
+            spill_dir: string("spill-dir"),
+            spill_size: matches.get_one("spill-size").copied().unwrap_or(DEFAULT_SPILL_SIZE),
+            journal: string("journal"),
+            leases: matches.get_flag("leases"),
+            quotas: matches.get_many::<Quota>("quota").into_iter().flatten().cloned().collect(),
+            max_file_size: matches.get_one("max-file-size").copied(),
+            quota_reconcile_interval: duration("quota-reconcile-interval", quota::DEFAULT_RECONCILE_INTERVAL),
+            backend,
+            encoding: match matches.get_flag("legacy-framing") {
+                true => Encoding::Legacy,
+                false => matches.get_one("encoding").copied().unwrap_or(Encoding::MessagePack),
+            },
+            connections: count("connections", DEFAULT_CONNECTIONS),
+            compression: !matches.get_flag("no-compression"),
+            heartbeat: (!heartbeat.interval.is_zero()).then_some(heartbeat),
+            max_in_flight: count("max-in-flight", DEFAULT_MAX_IN_FLIGHT),
+            max_frame_size,
+            transport: Transport {
+                clients: count("clients", 1),
+                routing: matches.get_one("routing").copied().unwrap_or_default(),
+                lazy: matches.get_flag("lazy-connect"),
+                chunk_size,
+                parallelism: count("transfer-parallelism", DEFAULT_TRANSFER_PARALLELISM),
+                ..Transport::parse(matches)
+            },
+            log_format: matches.get_one("log-format").copied().unwrap_or_default(),
+            metrics_listen: string("metrics-listen"),
+            admin_socket: matches.get_one::<Address>("admin-socket").cloned(),
+            events_socket: matches.get_one::<Address>("events-socket").cloned(),
+            webdav_listen: matches.get_one::<Address>("webdav-listen").cloned(),
+            sftp_listen: string("sftp-listen"),
+            sftp_host_key: string("sftp-host-key").map(PathBuf::from),
+            sftp_authorized_keys: string("sftp-authorized-keys").map(PathBuf::from),
+            sftp_allow_any_user: matches.get_flag("sftp-allow-any-user"),
+            s3_listen: matches.get_one::<Address>("s3-listen").cloned(),
+            audit: string("audit-log").map(|path| AuditOptions {
+                path: PathBuf::from(path),
+                max_size: matches.get_one("audit-log-max-size").copied().unwrap_or(DEFAULT_AUDIT_MAX_SIZE),
+                mirror: matches.get_flag("audit-mirror"),
+            }),
+            trace_context: matches.get_flag("trace-context"),
+            slow_threshold: matches.get_one("slow-threshold").copied(),
+            stuck_threshold: (!stuck_threshold.is_zero()).then_some(stuck_threshold),
+            tunables,
+        })
+    }
+}
+
+// Parses the command line, environment and --config file again and applies
+// the settings that can change while mounted. The rest only take effect on
+// a restart.
+fn reload(client: &RemoteFSClient) -> Result<Tunables, String> {
+    let matches = cli::try_matches().map_err(|e| e.to_string())?;
+    let tunables = MountOptions::parse(&matches).map_err(|e| e.to_string())?.tunables;
+    logging::reload_level(tunables.log_level.as_deref())?;
+    client.reload(&tunables);
+    info!(?tunables, "Reloaded settings");
+    Ok(tunables)
+}
+
+struct RestoreOptions {
+    source: String,
+    at: SystemTime,
+    assume_yes: bool,
+    transport: Transport,
+}
+
+impl RestoreOptions {
+    fn parse(matches: &ArgMatches) -> Self {
+        Self {
+            source: matches.get_one::<String>("from").cloned().expect("--from is required"),
+            at: matches.get_one("at").copied().unwrap_or_else(SystemTime::now),
+            assume_yes: matches.get_flag("yes"),
+            transport: Transport::parse(matches),
+        }
+    }
+}
+
+// Repopulates the primary backend from the replica or a snapshot, batch by batch
+async fn run_restore(options: RestoreOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let at_ms = options.at.duration_since(UNIX_EPOCH)?.as_millis() as u64;
+    info!(
+        "Restoring from {} as of {}",
+        options.source,
+        humantime::format_rfc3339_seconds(options.at)
+    );
+
+    if !options.assume_yes {
+        print!("This replaces every file in the primary backend. Continue? [y/N] ");
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            info!("Restore aborted");
+            return Ok(());
         }
     }
+
+    let client = RemoteFSClient::new(
+        Encoding::Json,
+        false,
+        1,
+        None,
+        DEFAULT_MAX_IN_FLIGHT,
+        DEFAULT_MAX_FRAME_SIZE,
+        &options.transport,
+    )?;
+    let mut cursor = 0;
+    loop {
+        let response = client
+            .send_message(FSMessage {
+                operation: FsOperation::Restore,
+                path: "/".to_string(),
+                offset: Some(cursor),
+                restore: Some(RestoreRequest {
+                    source: options.source.clone(),
+                    at: at_ms,
+                }),
+                ..Default::default()
+            })
+            .await?;
+        let progress = response.restore.ok_or("DO returned no restore progress")?;
+        info!("Restored {}/{} entries", progress.processed, progress.total);
+
+        if progress.done {
+            break;
+        }
+        cursor = progress.processed;
+    }
+
+    info!("Restore complete");
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mount_point = "/storage";
-    std::fs::create_dir_all(mount_point)?;
+    let matches = cli::matches();
+    if cli::is_fsctl() {
+        let options = FsctlOptions::parse(&matches);
+        // Only what goes wrong, so output meant for scripts stands alone
+        let level = std::env::var("RUST_LOG").unwrap_or_else(|_| "warn".to_string());
+        logging::init(LogFormat::Text, Some(&level));
+        let ok = run_fsctl(options).await?;
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+    if let Some(("restore", matches)) = matches.subcommand() {
+        let options = RestoreOptions::parse(matches);
+        logging::init(LogFormat::Text, None);
+        return run_restore(options).await;
+    }
+    if let Some(("sync", matches)) = matches.subcommand() {
+        let options = SyncOptions::parse(matches);
+        logging::init(LogFormat::Text, None);
+        return run_sync(options).await;
+    }
+
+    let mut options = MountOptions::parse(&matches).unwrap_or_else(|e| e.exit());
+    logging::init(options.log_format, options.tunables.log_level.as_deref());
+    if options.trace_context {
+        TraceContext::enable();
+    }
+    if let Some(threshold) = options.slow_threshold {
+        logging::log_slow_requests(threshold);
+    }
+    // Up before the DO connects, so a daemon stuck waiting for it shows up
+    if let Some(address) = &options.metrics_listen {
+        metrics::spawn_server(address)?;
+    }
+    let mount_point = options.mounts[0].mount_point.clone();
+    // Exported mounts are only paths clients ask for
+    for mount in options.mounts.iter().filter(|_| options.frontend == Frontend::Fuse) {
+        std::fs::create_dir_all(&mount.mount_point)?;
+    }
+
+    // A backend in the daemon is dialed like a DO would be
+    let lower = || -> io::Result<Box<dyn backend::Store>> {
+        let client = connect_to(&options, &options.transport).map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(Box::new(RemoteStore::new(client)))
+    };
+    if let Some(address) = options.backend.start(lower)? {
+        options.transport.endpoint = Endpoint::Dial(vec![address]);
+        // What reaches the DO is sealed by the overlay's or tier's own client,
+        // and what they keep locally is in the clear like the caches
+        if matches!(options.backend, Backend::Overlay(_) | Backend::Tier(_)) {
+            options.transport.encryption = None;
+        }
+    }
+    let client = connect(&options)?;
+    if let Some(address) = &options.admin_socket {
+        admin::spawn(address, client.clone())?;
+    }
+    if let Some(address) = &options.events_socket {
+        let mounts = options.mounts.iter().map(|mount| (mount.mount_point.clone(), mount.prefix.clone())).collect();
+        events::spawn(address, mounts)?;
+    }
+    let audit = match &options.audit {
+        Some(audit) => Some(AuditLog::open(audit, client.clone())?),
+        None => None,
+    };
+
+    let journal = match &options.journal {
+        Some(path) => Some(Arc::new(Journal::open(path)?)),
+        None => None,
+    };
+
+    // Opened once, so a remounted filesystem takes over what is on disk
+    // rather than finding it again as if after a crash
+    let spill = match &options.spill_dir {
+        Some(dir) => Some(Arc::new(SpillDir::open(dir, options.spill_size)?)),
+        None => None,
+    };
+
+    // Shared by every mount, so overlapping ones count the same files once
+    let quotas: Vec<Quota> =
+        options.mounts.iter().filter_map(|mount| mount.quota.clone()).chain(options.quotas.clone()).collect();
+    let quotas = (!quotas.is_empty()).then(|| Quotas::new(quotas));
+    if let Some(quotas) = &quotas {
+        quotas.spawn_reconciler(client.clone(), options.quota_reconcile_interval);
+    }
+
+    let filesystem = |index| {
+        let (audit, journal, spill) = (audit.clone(), journal.clone(), spill.clone());
+        RemoteFS::new(&options, index, client.clone(), audit, journal, spill, quotas.clone())
+    };
+    let filesystems = (0..options.mounts.len()).map(filesystem).collect::<Result<Vec<_>, _>>()?;
+    let mut supervisor = Supervisor::new(options.remount);
+    // How a frontend serving the mounts itself is told they are going away
+    let mut exported: Option<Box<dyn FnOnce()>> = None;
+    match options.frontend {
+        Frontend::Fuse => {
+            // The main mount goes last, so every mount is up by the time it reports ready
+            let mut sessions = Vec::new();
+            for (fs, mount) in filesystems.into_iter().zip(&options.mounts).rev() {
+                info!("Mounting remote filesystem at {}, showing {} of the DO", mount.mount_point, mount.prefix);
+                let mount_options = options.kernel_mount.options(mount.read_only);
+                sessions.push((mount, spawn_mount(fs, mount, &mount_options)?));
+            }
+            for (mount, session) in sessions.into_iter().rev() {
+                supervisor.add(&mount.mount_point, session);
+            }
+        }
+        Frontend::Nfs => {
+            let server = nfs::spawn(&options.nfs_listen, filesystems)?;
+            exported = Some(Box::new(move || server.stop()));
+        }
+        Frontend::NineP => {
+            let server = ninep::spawn(&options.ninep_listen, filesystems)?;
+            exported = Some(Box::new(move || server.stop()));
+        }
+    }
+    // Served next to the frontend, with filesystems of their own
+    let served = options.webdav_listen.is_some() || options.sftp_listen.is_some() || options.s3_listen.is_some();
+    let endpoints = match served {
+        true => {
+            let filesystems = (0..options.mounts.len()).map(filesystem).collect::<Result<_, _>>()?;
+            Some(Arc::new(Endpoints::new(filesystems)))
+        }
+        false => None,
+    };
+    if let (Some(address), Some(endpoints)) = (&options.webdav_listen, &endpoints) {
+        webdav::spawn(address, endpoints.clone())?;
+    }
+    if let (Some(address), Some(endpoints)) = (&options.sftp_listen, &endpoints) {
+        let host_key = HostKey::load(options.sftp_host_key.as_deref())?;
+        let authorized = sftp::authorized_keys(options.sftp_authorized_keys.as_deref())?;
+        sftp::spawn(address, endpoints.clone(), host_key, authorized, options.sftp_allow_any_user)?;
+    }
+    if let (Some(address), Some(endpoints)) = (&options.s3_listen, &endpoints) {
+        s3::spawn(address, endpoints.clone())?;
+    }
+    HEALTH.set_mounted(true);
+    // A mount that goes away gets a filesystem of its own, as if it were new
+    let remount = |index: usize| -> Result<fuser::BackgroundSession, Box<dyn std::error::Error>> {
+        let mount = &options.mounts[index];
+        let fs = filesystem(index)?;
+        Ok(spawn_mount(fs, mount, &options.kernel_mount.options(mount.read_only))?)
+    };
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    let reloaded = client.clone();
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            if let Err(e) = reload(&reloaded) {
+                warn!("Failed to reload settings, keeping the current ones: {}", e);
+            }
+        }
+    });
 
-    println!("Mounting remote filesystem at {}", mount_point);
+    // Run until asked to stop, or until a mount goes away for good
+    let mut terminate = signal(SignalKind::terminate())?;
+    let signalled = tokio::select! {
+        _ = tokio::signal::ctrl_c() => true,
+        _ = terminate.recv() => true,
+        _ = supervisor.watch(remount) => false,
+    };
+    lifecycle::connection().draining();
+    HEALTH.set_mounted(false);
 
-    let fs = RemoteFS::new()?;
-    
-    let options = vec![
-        MountOption::AllowOther,
-        MountOption::AutoUnmount,
-    ];
+    let deadline = Instant::now() + options.shutdown_timeout;
+    if signalled {
+        let mount_points: Vec<_> = options.mounts.iter().map(|mount| mount.mount_point.as_str()).collect();
+        info!("Shutting down, unmounting {}", mount_points.join(", "));
+        hooks::notify("STOPPING=1");
+        // A busy mount or an unreachable DO must not keep the container from stopping
+        let timeout = options.shutdown_timeout;
+        thread::spawn(move || {
+            thread::sleep(timeout);
+            error!("Shutdown took longer than {:?}, exiting without finishing it", timeout);
+            std::process::exit(1);
+        });
+        if let Some(command) = &options.pre_unmount_exec {
+            hooks::run_pre_unmount(command, &mount_point);
+        }
+    }
+    // Each session flushes its mount's buffered writes once it is unmounted
+    supervisor.join();
+    if let Some(stop) = exported {
+        stop();
+    }
+    if let Some(endpoints) = &endpoints {
+        endpoints.stop();
+    }
+    if signalled {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match tokio::time::timeout(remaining, client.goodbye()).await {
+            Ok(Ok(())) => info!("Shut down cleanly"),
+            Ok(Err(e)) => warn!("Failed to say goodbye to the DO: {}", e),
+            Err(_) => warn!("The DO did not answer the goodbye in time"),
+        }
+    }
 
-    fuser::mount2(fs, mount_point, &options)?;
-    
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+
+    fn message(id: u64, data: Vec<u8>) -> FSMessage {
+        FSMessage {
+            id,
+            operation: FsOperation::Write,
+            path: "/f".to_string(),
+            data: Some(data),
+            offset: Some(0),
+            ..Default::default()
+        }
+    }
+
+    // The frame for `message` without its length prefix, as the reader sees it
+    fn encode(encoding: Encoding, compress: bool, mut message: FSMessage) -> Vec<u8> {
+        let mut frame = Vec::new();
+        RemoteFSClient::encode_frame(encoding, compress, &mut message, &mut frame).unwrap();
+        assert_eq!(u32::from_le_bytes(frame[..4].try_into().unwrap()) as usize, frame.len() - 4);
+        frame.split_off(4)
+    }
+
+    #[test]
+    fn pushes_reach_the_kernel_of_mounts_showing_them() {
+        let inboxes = Inboxes::default();
+        let data = inboxes.open("/data").subscribe_notices();
+        let other = inboxes.open("/other").subscribe_notices();
+        inboxes.invalidate("/data/a.txt", true);
+        inboxes.resync();
+        assert_eq!(data.try_iter().collect::<Vec<_>>(), [Notice::Path("/data/a.txt".to_string(), true), Notice::All]);
+        assert_eq!(other.try_iter().collect::<Vec<_>>(), [Notice::All]);
+    }
+
+    #[test]
+    fn class_timeouts_fall_back_to_the_request_timeout() {
+        let args = ["fsdaemon", "--request-timeout=5s", "--write-timeout=3m"];
+        let timeouts = Timeouts::parse(&cli::command().try_get_matches_from(args).unwrap());
+        assert_eq!(timeouts.of(FsOperation::Stat), Duration::from_secs(5));
+        assert_eq!(timeouts.of(FsOperation::WriteChunk), Duration::from_secs(180));
+        assert_eq!(timeouts.of(FsOperation::WriteEnd), Duration::from_secs(5));
+
+        let timeouts = Timeouts::parse(&cli::command().try_get_matches_from(["fsdaemon"]).unwrap());
+        assert_eq!(timeouts, Timeouts::default());
+        assert_eq!(timeouts.of(FsOperation::Read), DEFAULT_READ_TIMEOUT);
+    }
+
+    #[test]
+    fn retry_backoff_doubles_up_to_the_most_less_jitter() {
+        let args = ["fsdaemon", "--retry-backoff=1s", "--retry-max-backoff=5s", "--retry-jitter=0"];
+        let retry = Retry::parse(&cli::command().try_get_matches_from(args).unwrap());
+        let delays: Vec<_> = (0..5).map(|attempt| retry.delay(attempt).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5]);
+        assert_eq!(retry.delay(usize::MAX), Duration::from_secs(5));
+
+        let retry = Retry { jitter: 0.5, ..retry };
+        for attempt in 0..100 {
+            let delay = retry.delay(attempt);
+            assert!(delay >= Duration::from_millis(500) && delay <= Duration::from_secs(5));
+        }
+        assert!(cli::command().try_get_matches_from(["fsdaemon", "--retry-jitter=1.5"]).is_err());
+    }
+
+    #[test]
+    fn kernel_mount_options_follow_the_command_line() {
+        let args = ["fsdaemon", "--allow=root", "--noatime", "--fsname=do", "--max-read=65536"];
+        let options = MountOptions::parse(&cli::command().try_get_matches_from(args).unwrap()).unwrap();
+        let mount = options.kernel_mount.options(true);
+        for option in [MountOption::AllowRoot, MountOption::NoAtime, MountOption::FSName("do".to_string())] {
+            assert!(mount.contains(&option), "{:?}", option);
+        }
+        assert!(mount.contains(&MountOption::CUSTOM("max_read=65536".to_string())));
+        assert!(mount.contains(&MountOption::RO));
+        assert!(!mount.contains(&MountOption::AllowOther));
+
+        for bad in ["--max-read=1024", "--max-read=1048576", "--fsname=a,b", "--subtype=", "--allow=everyone"] {
+            assert!(cli::command().try_get_matches_from(["fsdaemon", bad]).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn frames_decode_to_what_was_encoded() {
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 7) as u8).collect();
+        for encoding in [Encoding::Legacy, Encoding::Json, Encoding::MessagePack] {
+            for compress in [false, true] {
+                let frame = encode(encoding, compress, message(9, data.clone()));
+                let response = RemoteFSClient::decode_response(&frame).unwrap();
+                assert_eq!((response.id, &response.data), (9, &data), "{:?}, compress {}", encoding, compress);
+            }
+        }
+    }
+
+    #[test]
+    fn compressed_payloads_are_smaller_and_marked() {
+        let data = vec![0u8; 64 * 1024];
+        let frame = encode(Encoding::MessagePack, true, message(1, data.clone()));
+        assert!(frame.len() < data.len() / 10);
+        // Below the threshold nothing is compressed
+        let frame = encode(Encoding::MessagePack, true, message(1, vec![0; 100]));
+        assert!(frame.len() > 100);
+    }
+
+    #[test]
+    fn oversized_decompression_claims_are_refused() {
+        let mut claim = message(1, vec![0; 100]);
+        claim.compression = Some("lz4");
+        claim.raw_size = Some(u64::MAX);
+        let response = RemoteFSClient::decode_response(&encode(Encoding::MessagePack, false, claim)).unwrap();
+        assert!(response.error.starts_with("Compressed payload claims"), "{}", response.error);
+        assert!(response.data.is_empty());
+    }
+
+    #[test]
+    fn batch_frames_split_into_their_parts() {
+        let mut batch = vec![BATCH_FRAME_TAG];
+        for id in [1, 2, 3] {
+            let mut frame = Vec::new();
+            RemoteFSClient::encode_frame(Encoding::MessagePack, false, &mut message(id, vec![id as u8]), &mut frame)
+                .unwrap();
+            batch.extend_from_slice(&frame);
+        }
+        let ids: Vec<_> = RemoteFSClient::decode_responses(&batch).iter().map(|response| response.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        // A part cut short is dropped along with anything after it
+        batch.truncate(batch.len() - 1);
+        let ids: Vec<_> = RemoteFSClient::decode_responses(&batch).iter().map(|response| response.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn long_frames_are_fragmented_and_reassembled() {
+        let (daemon, peer) = UnixStream::pair().unwrap();
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let mut frame = Vec::new();
+        RemoteFSClient::encode_frame(Encoding::Json, false, &mut message(7, data.clone()), &mut frame).unwrap();
+        RemoteFSClient::write_frame(&mut Conn::Unix(peer), &frame, Some(1024)).unwrap();
+
+        // Every fragment fits the limit, and only the last is flagged
+        let mut wire = Vec::new();
+        Conn::Unix(daemon).read_to_end(&mut wire).unwrap();
+        let mut flags = Vec::new();
+        let mut rest = &wire[..];
+        while !rest.is_empty() {
+            let length = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+            assert!(length <= 1024);
+            assert_eq!(rest[4], FRAGMENT_FRAME_TAG);
+            flags.push(rest[5]);
+            rest = &rest[4 + length..];
+        }
+        assert!(flags.len() > 1);
+        assert_eq!(flags.iter().filter(|last| **last == 1).count(), 1);
+        assert_eq!(flags.last(), Some(&1));
+
+        // The reader puts them back together into the response
+        let (daemon, peer) = UnixStream::pair().unwrap();
+        let (sender, receiver) = oneshot::channel();
+        let pending = Arc::new(Mutex::new(HashMap::from([(7, (0, Pending::Single(sender)))])));
+        let liveness = Arc::new(Liveness {
+            connection: 0,
+            last_heard: Mutex::new(Instant::now()),
+            alive: AtomicBool::new(true),
+        });
+        METRICS.connected(false);
+        let reader = {
+            let (pending, liveness) = (pending.clone(), liveness.clone());
+            thread::spawn(move || {
+                let inboxes = Arc::new(Inboxes::default());
+                RemoteFSClient::reader_loop(Conn::Unix(daemon), pending, inboxes, BufferPool::new(), liveness, 1024)
+            })
+        };
+        RemoteFSClient::write_frame(&mut Conn::Unix(peer), &frame, Some(1024)).unwrap();
+        let response = receiver.blocking_recv().unwrap();
+        assert_eq!((response.id, response.data), (7, data));
+        reader.join().unwrap();
+        assert!(!liveness.is_alive());
+    }
+}
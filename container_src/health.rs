@@ -0,0 +1,58 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+
+use crate::lifecycle::{self, State};
+
+// What the platform's probes are answered from: whether the mount is up,
+// the state of the connection to the DO, and when a request last succeeded
+pub static HEALTH: Health = Health::new();
+
+pub struct Health {
+    mounted: AtomicBool,
+    // Unix milliseconds of the last request the DO answered without error
+    last_success: AtomicU64,
+}
+
+impl Health {
+    const fn new() -> Self {
+        Self {
+            mounted: AtomicBool::new(false),
+            last_success: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set_mounted(&self, mounted: bool) {
+        self.mounted.store(mounted, Ordering::SeqCst);
+    }
+
+    pub fn succeeded(&self) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.last_success.store(now.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    // Live means the DO hasn't been out of reach for so long that requests
+    // are failing. Ready means requests can be served right now.
+    pub fn check(&self, ready: bool) -> (bool, String) {
+        let mounted = self.mounted.load(Ordering::SeqCst);
+        let (state, since) = lifecycle::connection().state();
+        let last_success = match self.last_success.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(millis),
+        };
+        let ok = if ready {
+            mounted && state == State::Connected
+        } else {
+            state != State::Failed
+        };
+        let body = json!({
+            "ok": ok,
+            "mounted": mounted,
+            "state": state.as_str(),
+            "state_secs": since.as_secs(),
+            "last_success_ms": last_success,
+        });
+        (ok, body.to_string())
+    }
+}
@@ -0,0 +1,63 @@
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::process::{Child, Command};
+use std::thread;
+
+use tracing::{info, warn};
+
+// Sends a state update such as "READY=1" to the service manager when it
+// asked for one through NOTIFY_SOCKET; without it this does nothing
+pub fn notify(state: &str) {
+    let Ok(socket_path) = std::env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    let result = (|| -> std::io::Result<()> {
+        // A leading '@' names a socket in the abstract namespace
+        let addr = match socket_path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&socket_path)?,
+        };
+        UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+        Ok(())
+    })();
+    if let Err(e) = result {
+        warn!("Failed to notify service manager at {}: {}", socket_path, e);
+    }
+}
+
+fn spawn_hook(command: &str, mount_point: &str) -> std::io::Result<Child> {
+    Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .env("FSDAEMON_MOUNT_POINT", mount_point)
+        .spawn()
+}
+
+// Starts the post-mount command without waiting for it, since it may be the
+// long-running service that uses the mount
+pub fn run_post_mount(command: &str, mount_point: &str) {
+    let mut child = match spawn_hook(command, mount_point) {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("Failed to start post-mount hook '{}': {}", command, e);
+            return;
+        }
+    };
+    let command = command.to_string();
+    thread::spawn(move || match child.wait() {
+        Ok(status) if status.success() => info!("Post-mount hook '{}' finished", command),
+        Ok(status) => warn!("Post-mount hook '{}' exited with {}", command, status),
+        Err(e) => warn!("Failed to wait for post-mount hook '{}': {}", command, e),
+    });
+}
+
+// Runs the pre-unmount command to completion so it can quiesce writers while
+// the filesystem is still mounted
+pub fn run_pre_unmount(command: &str, mount_point: &str) {
+    info!("Running pre-unmount hook '{}'", command);
+    match spawn_hook(command, mount_point).and_then(|mut child| child.wait()) {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("Pre-unmount hook '{}' exited with {}", command, status),
+        Err(e) => warn!("Failed to run pre-unmount hook '{}': {}", command, e),
+    }
+}
@@ -0,0 +1,338 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fuser::FileAttr;
+
+use crate::transport::Conn;
+
+// A request's line and headers past this are refused rather than buffered
+const MAX_HEADERS: usize = 16 * 1024;
+// How long a connection may sit between requests
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// What a failure means to an HTTP client
+pub fn status(errno: i32) -> &'static str {
+    match errno {
+        libc::ENOENT => "404 Not Found",
+        libc::EPERM | libc::EACCES | libc::EROFS => "403 Forbidden",
+        libc::EEXIST | libc::EISDIR | libc::ENOTDIR => "409 Conflict",
+        libc::EINVAL | libc::ENAMETOOLONG => "400 Bad Request",
+        libc::EFBIG | libc::ENOSPC | libc::EDQUOT => "507 Insufficient Storage",
+        libc::ESHUTDOWN | libc::EAGAIN | libc::ETIMEDOUT => "503 Service Unavailable",
+        libc::ENOSYS | libc::EOPNOTSUPP => "501 Not Implemented",
+        _ => "500 Internal Server Error",
+    }
+}
+
+// A request's line and headers
+pub struct Request {
+    pub method: String,
+    // Decoded, without a trailing slash, or empty if it isn't a path
+    pub path: String,
+    // Whether the target ended in a slash before it was taken off
+    pub slash: bool,
+    // As sent, without the question mark
+    pub query: String,
+    pub headers: Vec<(String, String)>,
+    // Whether the connection is to be closed once it is answered
+    pub last: bool,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    // The decoded value of the query parameter `name`, empty if it has none
+    pub fn param(&self, name: &str) -> Option<String> {
+        self.query.split('&').find_map(|param| match param.split_once('=') {
+            Some((key, value)) if key == name => Some(decode(value).unwrap_or_default()),
+            None if param == name => Some(String::new()),
+            _ => None,
+        })
+    }
+}
+
+// Answers the requests on `stream` with `answer` one after another, until
+// the client is done with the connection or leaves it idle
+pub fn serve(
+    stream: Conn,
+    mut answer: impl FnMut(&Request, &mut dyn Read, &mut Conn) -> io::Result<()>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut out = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    while let Some(request) = read_request(&mut reader)? {
+        // Clients holding back a body until they hear they may send it are
+        // told to go ahead; one not wanted is read past anyway
+        if request.header("Expect").is_some_and(|value| value.eq_ignore_ascii_case("100-continue")) {
+            out.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+        }
+        let mut body = body(&request, &mut reader)?;
+        match request.path.is_empty() {
+            true => respond(&mut out, "400 Bad Request", &[], b"bad path\n")?,
+            false => answer(&request, &mut body, &mut out)?,
+        }
+        // Whatever of the body wasn't wanted is read past, to get to the next request
+        io::copy(&mut body, &mut io::sink())?;
+        if request.last {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// The next request on the connection, or None once the client is done
+pub fn read_request(reader: &mut BufReader<Conn>) -> io::Result<Option<Request>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let mut lines = Vec::new();
+    let mut read = 0;
+    loop {
+        let mut line = String::new();
+        let len = (&mut *reader).take((MAX_HEADERS - read) as u64 + 1).read_line(&mut line)?;
+        read += len;
+        if read > MAX_HEADERS {
+            return Err(invalid("request headers too long"));
+        }
+        match (len, line.trim_end()) {
+            (0, _) if lines.is_empty() => return Ok(None),
+            (0, _) => return Err(invalid("request cut short")),
+            // Blank lines before a request are allowed
+            (_, "") if lines.is_empty() => {}
+            (_, "") => break,
+            (_, line) => lines.push(line.to_string()),
+        }
+    }
+    let mut words = lines[0].split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (words.next(), words.next(), words.next()) else {
+        return Err(invalid("bad request line"));
+    };
+    let headers: Vec<_> = lines[1..]
+        .iter()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = Request {
+        method: method.to_string(),
+        path: normalize(path).unwrap_or_default(),
+        slash: path.len() > 1 && path.ends_with('/'),
+        query: query.to_string(),
+        headers,
+        last: version == "HTTP/1.0",
+    };
+    request.last |= request.header("Connection").is_some_and(|value| value.eq_ignore_ascii_case("close"));
+    Ok(Some(request))
+}
+
+// What of the connection is the request's body
+pub fn body<'a>(request: &Request, reader: &'a mut BufReader<Conn>) -> io::Result<Box<dyn Read + 'a>> {
+    if request.header("Transfer-Encoding").is_some_and(|value| value.eq_ignore_ascii_case("chunked")) {
+        return Ok(Box::new(Chunked::new(reader)));
+    }
+    let length = match request.header("Content-Length") {
+        Some(length) => length.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad length"))?,
+        None => 0,
+    };
+    Ok(Box::new(reader.take(length)))
+}
+
+// A body sent in chunks, each after its length in hex and anything else on
+// its line
+pub struct Chunked<R> {
+    reader: R,
+    left: u64,
+    done: bool,
+}
+
+impl<R: BufRead> Chunked<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, left: 0, done: false }
+    }
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "bad chunk");
+        if self.done {
+            return Ok(0);
+        }
+        if self.left == 0 {
+            let mut line = String::new();
+            self.reader.read_line(&mut line)?;
+            let size = line.trim().split(';').next().unwrap_or_default();
+            self.left = u64::from_str_radix(size, 16).map_err(|_| invalid())?;
+            if self.left == 0 {
+                // Trailers, up to the blank line ending the body
+                loop {
+                    line.clear();
+                    if self.reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                        break;
+                    }
+                }
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(self.left as usize);
+        let read = self.reader.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        self.left -= read as u64;
+        if self.left == 0 {
+            let mut end = [0; 2];
+            self.reader.read_exact(&mut end)?;
+        }
+        Ok(read)
+    }
+}
+
+pub fn write_head(out: &mut Conn, status: &str, headers: &[(&str, String)]) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\n", status);
+    for (name, value) in headers {
+        head += &format!("{}: {}\r\n", name, value);
+    }
+    head += "\r\n";
+    out.write_all(head.as_bytes())
+}
+
+pub fn respond(out: &mut Conn, status: &str, headers: &[(&str, String)], body: &[u8]) -> io::Result<()> {
+    respond_head(out, status, headers, body, false)
+}
+
+// A response with its body, or with its length alone in answer to HEAD
+pub fn respond_head(
+    out: &mut Conn,
+    status: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+    head: bool,
+) -> io::Result<()> {
+    let mut headers = headers.to_vec();
+    headers.push(("Content-Length", body.len().to_string()));
+    write_head(out, status, &headers)?;
+    match head {
+        true => Ok(()),
+        false => out.write_all(body),
+    }
+}
+
+// A request target as a path, decoded and without a trailing slash, or None
+// if it isn't absolute or steps out of where it names
+pub fn normalize(target: &str) -> Option<String> {
+    let path = decode(target)?;
+    if !path.starts_with('/') || path.split('/').any(|segment| segment == "." || segment == "..") {
+        return None;
+    }
+    let path = path.split('/').filter(|segment| !segment.is_empty()).collect::<Vec<_>>().join("/");
+    Some(format!("/{}", path))
+}
+
+pub fn decode(encoded: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok().filter(|path| !path.contains('\0'))
+}
+
+// Everything but unreserved characters and slashes percent-encoded
+pub fn encode(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for &byte in path.as_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => encoded.push(byte as char),
+            _ => encoded += &format!("%{:02X}", byte),
+        }
+    }
+    encoded
+}
+
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// Changes whenever the file's size or modification time do
+pub fn etag(attr: &FileAttr) -> String {
+    let mtime = attr.mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("\"{:x}-{:x}\"", attr.size, mtime.as_millis())
+}
+
+// A time as HTTP dates it, e.g. Sun, 06 Nov 1994 08:49:37 GMT
+pub fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Days since the epoch to a civil date, counting years from March so leap days come last
+    let shifted = days + 719_468;
+    let era = shifted / 146_097;
+    let day_of_era = shifted % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_decode_and_dates_format_as_http_has_them() {
+        assert_eq!(normalize("/storage/a%20b.txt").as_deref(), Some("/storage/a b.txt"));
+        assert_eq!(normalize("/storage//docs/").as_deref(), Some("/storage/docs"));
+        assert_eq!(normalize("/").as_deref(), Some("/"));
+        assert_eq!(normalize("/storage/../etc/passwd"), None);
+        assert_eq!(normalize("/storage/%2e%2e/etc"), None);
+        assert_eq!(normalize("/a%zz"), None);
+        assert_eq!(normalize("/a%00b"), None);
+        assert_eq!(normalize("storage"), None);
+        assert_eq!(encode("/storage/a b&c.txt"), "/storage/a%20b%26c.txt");
+        assert_eq!(escape("<a & \"b\">"), "&lt;a &amp; &quot;b&quot;&gt;");
+
+        assert_eq!(http_date(UNIX_EPOCH + Duration::from_secs(784_111_777)), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(http_date(UNIX_EPOCH + Duration::from_secs(951_782_400)), "Tue, 29 Feb 2000 00:00:00 GMT");
+
+        assert_eq!(status(libc::ENOENT), "404 Not Found");
+        assert_eq!(status(libc::EROFS), "403 Forbidden");
+        assert_eq!(status(libc::ECONNRESET), "500 Internal Server Error");
+    }
+
+    #[test]
+    fn chunked_bodies_decode_and_bad_or_cut_short_ones_fail() {
+        let read = |sent: &[u8]| {
+            let mut body = Vec::new();
+            Chunked::new(sent).read_to_end(&mut body).map(|_| body)
+        };
+        assert_eq!(read(b"5;name=value\r\nhello\r\n6\r\n world\r\n0\r\nTrailer: x\r\n\r\n").unwrap(), b"hello world");
+        assert_eq!(read(b"0\r\n\r\n").unwrap(), b"");
+        // Sizes that aren't hex, and chunks or their line ends missing
+        assert_eq!(read(b"zz\r\nhello\r\n0\r\n\r\n").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read(b"").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read(b"a\r\nhello").unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(read(b"5\r\nhello").unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
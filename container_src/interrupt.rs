@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+use crate::error::Locked;
+use crate::trace::Correlation;
+
+// How often the callers of FUSE operations waiting on the DO are looked at
+// for a signal
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// A process, told apart from a later one given the same pid by when it
+// started, in clock ticks since boot
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Caller {
+    pid: u32,
+    started: u64,
+}
+
+impl Caller {
+    // The process with `pid` now, or None if there is none
+    fn of(pid: u32) -> Option<Self> {
+        Some(Self { pid, started: started(pid)? })
+    }
+}
+
+// The callers with operations waiting, how many each has and whether it has
+// been found signalled, and whether the thread looking at them is running
+#[derive(Default)]
+struct Watched {
+    callers: HashMap<Caller, (usize, watch::Sender<bool>)>,
+    polling: bool,
+}
+
+static WATCHED: OnceLock<Mutex<Watched>> = OnceLock::new();
+
+fn watched() -> &'static Mutex<Watched> {
+    WATCHED.get_or_init(Default::default)
+}
+
+// Resolves once the process behind the FUSE operation on this thread has a
+// signal pending that it will act on; never outside a FUSE operation, for
+// one the kernel gives no caller (pid 0), or for a caller already gone. The
+// kernel sends FUSE_INTERRUPT when that happens, but fuser answers it itself
+// without telling the filesystem, so the daemon looks for the signal
+// instead: the caller stays blocked in the kernel until it gets an answer,
+// with the signal pending. One thread looks at every waiting caller in turn,
+// however many operations each has waiting.
+pub async fn interrupted() {
+    let Some(caller) = Correlation::caller().filter(|pid| *pid != 0).and_then(Caller::of) else {
+        return std::future::pending().await;
+    };
+    let mut waiting = Waiting::new(caller);
+    if waiting.0.wait_for(|signalled| *signalled).await.is_err() {
+        std::future::pending().await
+    }
+}
+
+// An operation waiting on `caller`, which stops it being watched once the
+// last one is done
+struct Waiting(watch::Receiver<bool>, Caller);
+
+impl Waiting {
+    fn new(caller: Caller) -> Self {
+        let mut watched = watched().locked();
+        let (count, signalled) = watched.callers.entry(caller).or_insert_with(|| (0, watch::channel(false).0));
+        *count += 1;
+        let receiver = signalled.subscribe();
+        if !watched.polling {
+            watched.polling = true;
+            thread::spawn(poll);
+        }
+        Self(receiver, caller)
+    }
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        let mut watched = watched().locked();
+        if let Some((count, _)) = watched.callers.get_mut(&self.1) {
+            *count -= 1;
+            if *count == 0 {
+                watched.callers.remove(&self.1);
+            }
+        }
+    }
+}
+
+// Looks at each waiting caller every POLL_INTERVAL until none are left
+fn poll() {
+    loop {
+        thread::sleep(POLL_INTERVAL);
+        let callers = {
+            let mut watched = watched().locked();
+            if watched.callers.is_empty() {
+                watched.polling = false;
+                return;
+            }
+            watched.callers.keys().copied().collect::<Vec<_>>()
+        };
+        for caller in callers.into_iter().filter(|caller| signalled(*caller)) {
+            if let Some((_, signalled)) = watched().locked().callers.get(&caller) {
+                signalled.send_replace(true);
+            }
+        }
+    }
+}
+
+// A caller that is gone, whose pid is now another process's, or whose
+// status can't be read, isn't taken as signalled. The start time is read
+// after the status, so the status can't have been another process's.
+fn signalled(caller: Caller) -> bool {
+    let status = fs::read_to_string(format!("/proc/{}/status", caller.pid));
+    status.is_ok_and(|status| pending(&status)) && started(caller.pid) == Some(caller.started)
+}
+
+// When the process with `pid` started, the 22nd field of /proc/<pid>/stat,
+// counting past its name, which may hold spaces and parentheses of its own
+fn started(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    stat.get(stat.rfind(')')? + 1..)?.split_whitespace().nth(19)?.parse().ok()
+}
+
+// Whether /proc/<pid>/status has a signal pending for the thread or its
+// process that the thread neither blocks nor ignores
+fn pending(status: &str) -> bool {
+    let mask = |field: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(field))
+            .and_then(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+            .unwrap_or(0)
+    };
+    (mask("SigPnd:") | mask("ShdPnd:")) & !mask("SigBlk:") & !mask("SigIgn:") != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(pending: &str, shared: &str, blocked: &str, ignored: &str) -> String {
+        format!(
+            "Name:\tcat\nState:\tS (sleeping)\nSigQ:\t0/63361\nSigPnd:\t{}\nShdPnd:\t{}\nSigBlk:\t{}\nSigIgn:\t{}\n\
+             SigCgt:\t0000000000000000\n",
+            pending, shared, blocked, ignored
+        )
+    }
+
+    #[test]
+    fn only_signals_the_caller_acts_on_count() {
+        let none = "0000000000000000";
+        // SIGINT (2) is bit 1, SIGTERM (15) bit 14
+        assert!(!pending(&status(none, none, none, none)));
+        assert!(pending(&status("0000000000000002", none, none, none)));
+        assert!(pending(&status(none, "0000000000004000", none, none)));
+        assert!(!pending(&status(none, "0000000000000002", "0000000000000002", none)));
+        assert!(!pending(&status("0000000000000002", none, none, "0000000000000002")));
+        assert!(pending(&status("0000000000000002", "0000000000004000", "0000000000000002", none)));
+        assert!(!pending("Name:\tcat\n"));
+    }
+
+    #[test]
+    fn callers_are_told_apart_by_when_they_started() {
+        let me = Caller::of(std::process::id()).unwrap();
+        assert_eq!(Caller::of(std::process::id()), Some(me));
+        assert!(!signalled(Caller { started: me.started + 1, ..me }));
+        assert_eq!(Caller::of(u32::MAX), None);
+        assert!(!signalled(Caller { pid: u32::MAX, started: 0 }));
+    }
+
+    #[test]
+    fn a_caller_is_watched_once_while_anything_waits_on_it() {
+        let me = Caller::of(std::process::id()).unwrap();
+        let count = || watched().locked().callers.get(&me).map(|(count, _)| *count);
+        let first = Waiting::new(me);
+        let second = Waiting::new(me);
+        assert_eq!(count(), Some(2));
+        drop(first);
+        assert_eq!(count(), Some(1));
+        drop(second);
+        assert_eq!(count(), None);
+    }
+
+    #[tokio::test]
+    async fn a_caller_with_a_signal_pending_is_found_signalled() {
+        // A stopped process keeps the SIGTERM sent to it pending
+        let mut child = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        let pid = child.id();
+        let caller = Caller::of(pid).unwrap();
+        let mut waiting = Waiting::new(caller);
+        unsafe {
+            libc::kill(pid as i32, libc::SIGSTOP);
+            libc::kill(pid as i32, libc::SIGTERM);
+        }
+        let found = tokio::time::timeout(Duration::from_secs(5), waiting.0.wait_for(|signalled| *signalled)).await;
+        assert!(found.is_ok_and(|found| found.is_ok()));
+        unsafe { libc::kill(pid as i32, libc::SIGKILL) };
+        child.wait().unwrap();
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use tracing::warn;
+
+use crate::error::Locked;
+
+// The journal starts with this; every record after it is a length, a CRC-32
+// of the body, then the body
+const JOURNAL_MAGIC: &[u8; 4] = b"FSJ1";
+
+// First byte of a record body
+const WRITE_RECORD: u8 = 1;
+const DONE_RECORD: u8 = 2;
+
+// Size past which the journal is rewritten with only its unfinished writes
+const JOURNAL_COMPACT_SIZE: u64 = 64 * 1024 * 1024;
+
+// A write acknowledged to an application that had not reached the DO when
+// the daemon last stopped
+#[derive(Debug, PartialEq)]
+pub struct JournalEntry {
+    pub seq: u64,
+    pub path: String,
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+struct JournalState {
+    file: File,
+    size: u64,
+    next_seq: u64,
+    // Writes recorded whose data the DO doesn't have yet
+    live: HashSet<u64>,
+    compact_at: u64,
+    // Unfinished writes found at startup, handed out once so they can be replayed
+    recovered: Vec<JournalEntry>,
+}
+
+// Append-only record of every write before the application is told it
+// succeeded, so that data buffered in the daemon survives a crash. Each write
+// is synced to disk as it is recorded; once its data is in the DO a done
+// record says so. At startup the writes never marked done are replayed in
+// the order they were made. A torn record at the end, from a crash while it
+// was being appended, is cut off.
+pub struct Journal {
+    path: PathBuf,
+    state: Mutex<JournalState>,
+}
+
+impl Journal {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let (parsed, valid) = if contents.is_empty() {
+            (parse(&[]), 0)
+        } else if contents.starts_with(JOURNAL_MAGIC) {
+            let parsed = parse(&contents[JOURNAL_MAGIC.len()..]);
+            let valid = JOURNAL_MAGIC.len() + parsed.valid;
+            (parsed, valid)
+        } else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a journal", path.display())));
+        };
+        if valid < contents.len() {
+            warn!("Discarding a torn record at the end of the journal {}", path.display());
+        }
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.set_len(valid as u64)?;
+        if valid == 0 {
+            file.write_all(JOURNAL_MAGIC)?;
+        }
+        file.sync_all()?;
+        let state = JournalState {
+            size: valid.max(JOURNAL_MAGIC.len()) as u64,
+            file,
+            next_seq: parsed.next_seq,
+            live: parsed.entries.iter().map(|entry| entry.seq).collect(),
+            compact_at: JOURNAL_COMPACT_SIZE,
+            recovered: parsed.entries,
+        };
+        Ok(Self {
+            path,
+            state: Mutex::new(state),
+        })
+    }
+
+    // Records a write durably, returning the sequence number it is marked done by
+    pub fn record(&self, path: &str, offset: u64, data: &[u8]) -> io::Result<u64> {
+        let mut state = self.state.locked();
+        let seq = state.next_seq;
+        append(&mut state, &write_body(seq, path, offset, data))?;
+        state.file.sync_data()?;
+        state.next_seq += 1;
+        state.live.insert(seq);
+        Ok(seq)
+    }
+
+    // Marks writes whose data is now in the DO. Losing this record to a crash
+    // only means the writes are sent again, so it isn't synced.
+    pub fn done(&self, seqs: &[u64]) {
+        if seqs.is_empty() {
+            return;
+        }
+        let mut state = self.state.locked();
+        for seq in seqs {
+            state.live.remove(seq);
+        }
+        let result = if state.live.is_empty() {
+            // Nothing left to replay, so start again from an empty journal
+            truncate(&mut state)
+        } else if state.size >= state.compact_at {
+            self.compact(&mut state)
+        } else {
+            let mut body = Vec::with_capacity(1 + seqs.len() * 8);
+            body.push(DONE_RECORD);
+            for seq in seqs {
+                body.extend_from_slice(&seq.to_le_bytes());
+            }
+            append(&mut state, &body)
+        };
+        if let Err(e) = result {
+            warn!("Failed to update the journal {}: {}", self.path.display(), e);
+        }
+    }
+
+    pub fn take_recovered(&self) -> Vec<JournalEntry> {
+        std::mem::take(&mut self.state.locked().recovered)
+    }
+
+    // Rewrites the journal with only the writes still live, under a temporary
+    // name renamed into place, so a crash leaves one or the other
+    fn compact(&self, state: &mut JournalState) -> io::Result<()> {
+        let contents = fs::read(&self.path)?;
+        let entries = parse(contents.get(JOURNAL_MAGIC.len()..).unwrap_or_default()).entries;
+        let staging = self.path.with_extension("tmp");
+        let mut file = File::create(&staging)?;
+        file.write_all(JOURNAL_MAGIC)?;
+        let mut size = JOURNAL_MAGIC.len() as u64;
+        for entry in entries.iter().filter(|entry| state.live.contains(&entry.seq)) {
+            let body = write_body(entry.seq, &entry.path, entry.offset, &entry.data);
+            file.write_all(&frame(&body))?;
+            size += body.len() as u64 + 8;
+        }
+        file.sync_all()?;
+        fs::rename(&staging, &self.path)?;
+        state.file = OpenOptions::new().append(true).open(&self.path)?;
+        state.size = size;
+        // Live writes alone may be past the threshold; don't rewrite on every done
+        state.compact_at = JOURNAL_COMPACT_SIZE.max(size * 2);
+        Ok(())
+    }
+}
+
+fn write_body(seq: u64, path: &str, offset: u64, data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(data.len() + path.len() + 21);
+    body.push(WRITE_RECORD);
+    body.extend_from_slice(&seq.to_le_bytes());
+    body.extend_from_slice(&(path.len() as u32).to_le_bytes());
+    body.extend_from_slice(path.as_bytes());
+    body.extend_from_slice(&offset.to_le_bytes());
+    body.extend_from_slice(data);
+    body
+}
+
+fn frame(body: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(body.len() + 8);
+    record.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(body).to_le_bytes());
+    record.extend_from_slice(body);
+    record
+}
+
+fn append(state: &mut JournalState, body: &[u8]) -> io::Result<()> {
+    state.file.write_all(&frame(body))?;
+    state.size += body.len() as u64 + 8;
+    Ok(())
+}
+
+fn truncate(state: &mut JournalState) -> io::Result<()> {
+    state.file.set_len(JOURNAL_MAGIC.len() as u64)?;
+    state.size = JOURNAL_MAGIC.len() as u64;
+    state.compact_at = JOURNAL_COMPACT_SIZE;
+    Ok(())
+}
+
+// Writes recorded in the journal
+struct Parsed {
+    // Those not marked done, in the order they were recorded
+    entries: Vec<JournalEntry>,
+    // Past every sequence number used, done or not, so none is reused
+    next_seq: u64,
+    // Bytes of records that were complete and undamaged
+    valid: usize,
+}
+
+fn parse(mut records: &[u8]) -> Parsed {
+    let total = records.len();
+    let mut entries = Vec::new();
+    let mut done = HashSet::new();
+    while let Some((body, rest)) = next_record(records) {
+        let parsed = match body.split_first() {
+            Some((&WRITE_RECORD, body)) => parse_write(body).map(|entry| entries.push(entry)),
+            Some((&DONE_RECORD, body)) if body.len() % 8 == 0 => {
+                done.extend(body.chunks(8).map(|seq| u64::from_le_bytes(seq.try_into().unwrap())));
+                Some(())
+            }
+            _ => None,
+        };
+        if parsed.is_none() {
+            break;
+        }
+        records = rest;
+    }
+    let next_seq = entries.iter().map(|entry| entry.seq + 1).max().unwrap_or(0);
+    entries.retain(|entry| !done.contains(&entry.seq));
+    Parsed {
+        entries,
+        next_seq,
+        valid: total - records.len(),
+    }
+}
+
+fn next_record(records: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = u32::from_le_bytes(records.get(..4)?.try_into().ok()?) as usize;
+    let checksum = u32::from_le_bytes(records.get(4..8)?.try_into().ok()?);
+    let body = records.get(8..8 + len)?;
+    (crc32fast::hash(body) == checksum).then(|| (body, &records[8 + len..]))
+}
+
+fn parse_write(body: &[u8]) -> Option<JournalEntry> {
+    let seq = u64::from_le_bytes(body.get(..8)?.try_into().ok()?);
+    let len = u32::from_le_bytes(body.get(8..12)?.try_into().ok()?) as usize;
+    let path = String::from_utf8(body.get(12..12 + len)?.to_vec()).ok()?;
+    let offset = u64::from_le_bytes(body.get(12 + len..20 + len)?.try_into().ok()?);
+    let data = body[20 + len..].to_vec();
+    Some(JournalEntry { seq, path, offset, data })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn journal_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("fsdaemon-journal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn unfinished_writes_are_recovered_in_order() {
+        let path = journal_path("recover");
+        let journal = Journal::open(path.to_str().unwrap()).unwrap();
+        let first = journal.record("/a", 0, b"one").unwrap();
+        let second = journal.record("/b", 4, b"two").unwrap();
+        let third = journal.record("/a", 3, b"three").unwrap();
+        let fourth = journal.record("/b", 0, b"four").unwrap();
+        journal.done(&[second, fourth]);
+        drop(journal);
+
+        let journal = Journal::open(path.to_str().unwrap()).unwrap();
+        let recovered = journal.take_recovered();
+        let seqs: Vec<_> = recovered.iter().map(|entry| entry.seq).collect();
+        assert_eq!(seqs, [first, third]);
+        assert_eq!(recovered[1].path, "/a");
+        assert_eq!((recovered[1].offset, recovered[1].data.as_slice()), (3, &b"three"[..]));
+        assert!(journal.take_recovered().is_empty());
+        // Sequence numbers carry on past every one used, so a done record never
+        // covers a later write
+        assert_eq!(journal.record("/c", 0, b"").unwrap(), fourth + 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_torn_tail_is_cut_off() {
+        let path = journal_path("torn");
+        let journal = Journal::open(path.to_str().unwrap()).unwrap();
+        journal.record("/a", 0, b"kept").unwrap();
+        journal.record("/a", 4, b"torn").unwrap();
+        drop(journal);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 2).unwrap();
+
+        let journal = Journal::open(path.to_str().unwrap()).unwrap();
+        let recovered = journal.take_recovered();
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(recovered[0].data, b"kept");
+        // Appends go after the last whole record, not after the torn one
+        journal.record("/a", 4, b"again").unwrap();
+        drop(journal);
+        let data: Vec<_> = Journal::open(path.to_str().unwrap())
+            .unwrap()
+            .take_recovered()
+            .into_iter()
+            .map(|entry| entry.data)
+            .collect();
+        assert_eq!(data, [b"kept".to_vec(), b"again".to_vec()]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn the_journal_empties_once_everything_is_done() {
+        let path = journal_path("empty");
+        let journal = Journal::open(path.to_str().unwrap()).unwrap();
+        let seqs = [journal.record("/a", 0, b"x").unwrap(), journal.record("/a", 1, b"y").unwrap()];
+        journal.done(&seqs);
+        assert_eq!(fs::metadata(&path).unwrap().len(), JOURNAL_MAGIC.len() as u64);
+
+        fs::write(&path, b"not a journal").unwrap();
+        assert!(Journal::open(path.to_str().unwrap()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compaction_keeps_only_live_writes() {
+        let path = journal_path("compact");
+        let journal = Journal::open(path.to_str().unwrap()).unwrap();
+        let live = journal.record("/a", 0, b"live").unwrap();
+        let gone = journal.record("/b", 0, b"gone").unwrap();
+        {
+            let mut state = journal.state.locked();
+            state.compact_at = 0;
+        }
+        journal.done(&[gone]);
+        drop(journal);
+
+        let contents = fs::read(&path).unwrap();
+        let parsed = parse(&contents[JOURNAL_MAGIC.len()..]);
+        assert_eq!(parsed.valid + JOURNAL_MAGIC.len(), contents.len());
+        assert_eq!(parsed.entries.iter().map(|entry| entry.seq).collect::<Vec<_>>(), [live]);
+        fs::remove_file(&path).unwrap();
+    }
+}
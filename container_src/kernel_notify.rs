@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+use fuser::Notifier;
+use tracing::debug;
+
+use crate::error::Locked;
+
+// The inode of every path a mount has told the kernel about, shared by its
+// inode table and the thread that invalidates the kernel's caches
+pub type InodeIndex = Arc<Mutex<HashMap<String, u64>>>;
+
+// A change the DO pushed that the kernel may have cached the old state of
+#[derive(Debug, PartialEq)]
+pub enum Notice {
+    // A path's content and attributes, and with `true` its directory entry
+    Path(String, bool),
+    // Anything at all, after a reconnect that may have missed pushes
+    All,
+}
+
+// Drops what the kernel cached for each path the DO reports as changed, so
+// that applications see another client's change before the attribute timeout
+// or the next open. This runs on its own thread: the kernel may wait on the
+// session to answer a request before it takes an invalidation, and the
+// session's thread would never get to answer.
+pub fn spawn_invalidator(notices: Receiver<Notice>, inodes: InodeIndex, notifier: Arc<OnceLock<Notifier>>) {
+    thread::spawn(move || {
+        for notice in notices {
+            // Until the session is up the kernel has nothing cached
+            let Some(notifier) = notifier.get() else {
+                continue;
+            };
+            match notice {
+                Notice::Path(path, entry_changed) => {
+                    let (ino, parent) = {
+                        let inodes = inodes.locked();
+                        let (parent, _) = split(&path);
+                        (inodes.get(&path).copied(), inodes.get(parent).copied())
+                    };
+                    if let Some(ino) = ino {
+                        report(&path, notifier.inval_inode(ino, 0, 0));
+                    }
+                    if let (true, Some(parent)) = (entry_changed, parent) {
+                        let (_, name) = split(&path);
+                        report(&path, notifier.inval_entry(parent, OsStr::new(name)));
+                    }
+                }
+                Notice::All => {
+                    let known: Vec<(String, u64)> =
+                        inodes.locked().iter().map(|(path, ino)| (path.clone(), *ino)).collect();
+                    for (path, ino) in known {
+                        report(&path, notifier.inval_inode(ino, 0, 0));
+                    }
+                }
+            }
+        }
+    });
+}
+
+// The kernel answers ENOENT for inodes it has already forgotten, which is
+// the usual case and nothing to act on either way
+fn report(path: &str, result: io::Result<()>) {
+    if let Err(e) = result {
+        debug!("Kernel cache of {} not invalidated: {}", path, e);
+    }
+}
+
+fn split(path: &str) -> (&str, &str) {
+    match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some((parent, name)) => (parent, name),
+        None => ("/", path),
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::error::{DaemonError, Locked};
+use crate::operation::FsOperation;
+use crate::writeback::WriteBack;
+use crate::{FSMessage, Inbox, RemoteFSClient};
+
+// Leases held at once; past this the oldest is handed back to the DO
+pub const MAX_LEASES: usize = 10_000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LeaseMode {
+    // Nobody else may write the path
+    Read,
+    // Nobody else may read or write the path
+    Write,
+    // Hands a lease back
+    Release,
+}
+
+// Leases the DO has granted this daemon. While one is held nobody else can
+// change the path, so its attributes and pages are served from cache; the DO
+// recalls a lease before letting another client touch the path.
+pub struct Leases {
+    held: Mutex<HashMap<String, (LeaseMode, Instant)>>,
+    // The mount's buffered writes, pushed out before a lease is handed back
+    writeback: Option<Arc<WriteBack>>,
+    // Set once the DO turned out not to grant leases, so that is logged once
+    refused: AtomicBool,
+}
+
+impl Leases {
+    pub fn new(writeback: Option<Arc<WriteBack>>) -> Arc<Self> {
+        Arc::new(Self {
+            held: Mutex::new(HashMap::new()),
+            writeback,
+            refused: AtomicBool::new(false),
+        })
+    }
+
+    // Whether a lease at least as strong as `mode` is held for the path
+    pub fn holds(&self, path: &str, mode: LeaseMode) -> bool {
+        match self.held.locked().get(path) {
+            Some((LeaseMode::Write, _)) => true,
+            Some((held, _)) => *held == mode,
+            None => false,
+        }
+    }
+
+    // Asks the DO for a lease, returning whether it was granted
+    pub async fn acquire(&self, client: &RemoteFSClient, path: &str, mode: LeaseMode) -> bool {
+        // A DO that connected after the mount may turn out not to grant them
+        if !client.has_feature("leases") {
+            if client.negotiated().is_some() && !self.refused.swap(true, Ordering::Relaxed) {
+                warn!("The DO does not grant leases, continuing without them");
+            }
+            return false;
+        }
+        let response = client
+            .send_message(FSMessage {
+                operation: FsOperation::Lease,
+                path: path.to_string(),
+                lease: Some(mode),
+                ..Default::default()
+            })
+            .await;
+        let granted = match response {
+            Ok(response) => response.lease,
+            Err(_) => None,
+        };
+        let Some(granted) = granted else {
+            return false;
+        };
+
+        let evicted = {
+            let mut held = self.held.locked();
+            held.insert(path.to_string(), (granted, Instant::now()));
+            if held.len() > MAX_LEASES {
+                let oldest = held.iter().min_by_key(|(_, (_, since))| *since).map(|(path, _)| path.clone());
+                if let Some(path) = &oldest {
+                    held.remove(path);
+                }
+                oldest
+            } else {
+                None
+            }
+        };
+        if let Some(evicted) = evicted {
+            self.hand_back(client, &evicted).await;
+        }
+        granted == mode || granted == LeaseMode::Write
+    }
+
+    fn revoke(&self, path: &str) {
+        self.held.locked().remove(path);
+    }
+
+    // Forgets every lease without handing them back, for when the DO has
+    // already dropped them
+    pub fn forget_all(&self) {
+        self.held.locked().clear();
+    }
+
+    // Serves recalls pushed by the DO for the mount `inbox` belongs to: forget
+    // the lease so nothing more is served from cache, then hand it back
+    pub fn spawn_recall_handler(self: &Arc<Self>, client: Arc<RemoteFSClient>, inbox: &Inbox) {
+        let recalls = inbox.subscribe_recalls();
+        let leases = self.clone();
+        thread::spawn(move || {
+            for path in recalls {
+                leases.revoke(&path);
+                client.block_on(leases.hand_back(&client, &path));
+            }
+        });
+    }
+
+    // Pushes out buffered writes for a lease no longer held, so they reach the
+    // DO before anyone else can look at the path, then releases it
+    async fn hand_back(&self, client: &RemoteFSClient, path: &str) {
+        if let Some(writeback) = &self.writeback {
+            if let Err(e) = writeback.flush_path(client, path).await {
+                warn!("Failed to flush {} before handing back its lease: {}", path, e);
+            }
+        }
+        if let Err(e) = release(client, path).await {
+            warn!("Failed to release lease on {}: {}", path, e);
+        }
+    }
+}
+
+async fn release(client: &RemoteFSClient, path: &str) -> Result<(), DaemonError> {
+    client
+        .send_message(FSMessage {
+            operation: FsOperation::Lease,
+            path: path.to_string(),
+            lease: Some(LeaseMode::Release),
+            ..Default::default()
+        })
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+use crate::error::Locked;
+
+static CONNECTION: OnceLock<Lifecycle> = OnceLock::new();
+
+// The state of the daemon's link to the DO, for the whole daemon
+pub fn connection() -> &'static Lifecycle {
+    CONNECTION.get_or_init(Lifecycle::new)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum State {
+    // Waiting for the DO's first connection, listening or dialing
+    Listening,
+    // At least one connection to the DO is up
+    Connected,
+    // Every connection went away; requests wait for the DO to come back
+    Reconnecting,
+    // Reconnecting for longer than the disconnect timeout, so soft requests
+    // fail. The DO may still come back.
+    Failed,
+    // The daemon is shutting down
+    Draining,
+}
+
+impl State {
+    pub const ALL: [State; 5] =
+        [State::Listening, State::Connected, State::Reconnecting, State::Failed, State::Draining];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            State::Listening => "listening",
+            State::Connected => "connected",
+            State::Reconnecting => "reconnecting",
+            State::Failed => "failed",
+            State::Draining => "draining",
+        }
+    }
+
+    fn allows(self, to: State) -> bool {
+        matches!(
+            (self, to),
+            (State::Listening, State::Connected)
+                | (State::Connected, State::Reconnecting)
+                | (State::Reconnecting, State::Connected)
+                | (State::Reconnecting, State::Failed)
+                | (State::Failed, State::Connected)
+                | (State::Listening | State::Connected | State::Reconnecting | State::Failed, State::Draining)
+        )
+    }
+}
+
+pub struct Lifecycle {
+    current: Mutex<(State, Instant)>,
+    // How long Reconnecting lasts before it is Failed; never for hard mounts
+    fail_after: OnceLock<Duration>,
+    // Transitions taken, indexed by `from as usize` then `to as usize`
+    transitions: [[AtomicU64; State::ALL.len()]; State::ALL.len()],
+}
+
+impl Lifecycle {
+    fn new() -> Self {
+        Self {
+            current: Mutex::new((State::Listening, Instant::now())),
+            fail_after: OnceLock::new(),
+            transitions: std::array::from_fn(|_| std::array::from_fn(|_| AtomicU64::new(0))),
+        }
+    }
+
+    pub fn set_fail_after(&self, timeout: Duration) {
+        let _ = self.fail_after.set(timeout);
+    }
+
+    // The current state and how long it has lasted
+    pub fn state(&self) -> (State, Duration) {
+        let (state, since) = *self.current.locked();
+        (state, since.elapsed())
+    }
+
+    // How many times each transition was taken, for those taken at all
+    pub fn transitions(&self) -> Vec<(State, State, u64)> {
+        let mut taken = Vec::new();
+        for from in State::ALL {
+            for to in State::ALL {
+                let count = self.transitions[from as usize][to as usize].load(Ordering::Relaxed);
+                if count > 0 {
+                    taken.push((from, to, count));
+                }
+            }
+        }
+        taken
+    }
+
+    // A connection to the DO came up
+    pub fn connected(&self) {
+        self.transition(State::Connected, "a connection to the DO is up", None);
+    }
+
+    // The last live connection to the DO went away
+    pub fn disconnected(&'static self) {
+        let Some(entered) = self.transition(State::Reconnecting, "no connection to the DO is left", None) else {
+            return;
+        };
+        if let Some(&timeout) = self.fail_after.get() {
+            thread::spawn(move || {
+                thread::sleep(timeout);
+                // Only if the DO hasn't come back in the meantime
+                let reason = "the DO did not come back within the disconnect timeout";
+                self.transition(State::Failed, reason, Some(entered));
+            });
+        }
+    }
+
+    pub fn draining(&self) {
+        self.transition(State::Draining, "the daemon is shutting down", None);
+    }
+
+    // Moves to `to` if the current state allows it, and when `entered` is
+    // given only if the current state began then. Returns when it moved.
+    fn transition(&self, to: State, reason: &str, entered: Option<Instant>) -> Option<Instant> {
+        let mut current = self.current.locked();
+        let (from, since) = *current;
+        if !from.allows(to) || entered.is_some_and(|entered| entered != since) {
+            return None;
+        }
+        let now = Instant::now();
+        *current = (to, now);
+        drop(current);
+        self.transitions[from as usize][to as usize].fetch_add(1, Ordering::Relaxed);
+        match to {
+            State::Reconnecting | State::Failed => {
+                warn!(from = from.as_str(), to = to.as_str(), "Connection state changed: {}", reason)
+            }
+            _ => info!(from = from.as_str(), to = to.as_str(), "Connection state changed: {}", reason),
+        }
+        Some(now)
+    }
+}
@@ -0,0 +1,78 @@
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+// Swaps the level filter of the running subscriber, for the admin socket
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+// Requests taking at least this long are logged at warn level, set by
+// --slow-threshold
+static SLOW_THRESHOLD: OnceLock<Duration> = OnceLock::new();
+
+// How log lines are written to stderr
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    // One JSON object per line, with the fields of the enclosing spans
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected text or json".to_string()),
+        }
+    }
+}
+
+// Installs the global subscriber. Levels come from `level` or else RUST_LOG,
+// such as `fsdaemon=debug` to see every request to the DO, and default to info.
+pub fn init(format: LogFormat, level: Option<&str>) {
+    let (filter, handle) = reload::Layer::new(filter(level));
+    let _ = FILTER.set(handle);
+    let json = format == LogFormat::Json;
+    tracing_subscriber::registry()
+        .with(filter)
+        .with((!json).then(|| fmt::layer().with_writer(std::io::stderr)))
+        .with(json.then(|| fmt::layer().json().with_current_span(true).with_writer(std::io::stderr)))
+        .init();
+}
+
+fn filter(level: Option<&str>) -> EnvFilter {
+    match level {
+        Some(level) => EnvFilter::new(level),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    }
+}
+
+// Checks levels given on the command line before logging starts
+pub fn parse_level(directives: &str) -> Result<String, String> {
+    EnvFilter::try_new(directives).map(|_| directives.to_string()).map_err(|e| e.to_string())
+}
+
+// Replaces the levels set by RUST_LOG with `directives` in the same syntax,
+// such as `debug` or `info,fsdaemon=trace`
+pub fn set_level(directives: &str) -> Result<(), String> {
+    let filter = EnvFilter::try_new(directives).map_err(|e| format!("invalid log level '{}': {}", directives, e))?;
+    let handle = FILTER.get().ok_or("logging is not initialised")?;
+    handle.reload(filter).map_err(|e| e.to_string())
+}
+
+// Goes back to the levels logging started with, or to `level` in their place
+pub fn reload_level(level: Option<&str>) -> Result<(), String> {
+    let handle = FILTER.get().ok_or("logging is not initialised")?;
+    handle.reload(filter(level)).map_err(|e| e.to_string())
+}
+
+pub fn log_slow_requests(threshold: Duration) {
+    let _ = SLOW_THRESHOLD.set(threshold);
+}
+
+pub fn is_slow(duration: Duration) -> bool {
+    SLOW_THRESHOLD.get().is_some_and(|threshold| duration >= *threshold)
+}
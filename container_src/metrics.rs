@@ -0,0 +1,397 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::error::Locked;
+use crate::health::HEALTH;
+use crate::lifecycle::{self, State};
+use crate::operation::FsOperation;
+
+// A scrape that sends nothing for this long is dropped
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// What a request's latency is split into: waiting for an in-flight slot and
+// a connection, the trip to the DO and back, and the time spent in the DO
+const PHASES: [&str; 3] = ["queue", "network", "do"];
+
+// Counters and gauges for the whole daemon, exported in the Prometheus text
+// format. Everything is a plain atomic bumped where the event happens, so
+// recording costs nothing worth measuring.
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    // Requests to the DO by operation, indexed by `FsOperation as usize`
+    requests: [AtomicU64; FsOperation::ALL.len()],
+    // Their latency by operation and phase
+    latencies: [[Histogram; PHASES.len()]; FsOperation::ALL.len()],
+    // Failed requests by the errno they were answered with
+    errors: Mutex<BTreeMap<i32, u64>>,
+    // Requests awaiting a response
+    in_flight: AtomicU64,
+    // Requests the watchdog found outstanding past its threshold, ever and now
+    stuck: AtomicU64,
+    stuck_now: AtomicU64,
+    // Pending entries nobody was waiting on any more, and responses that
+    // arrived for requests no longer pending
+    reaped: AtomicU64,
+    late_responses: AtomicU64,
+    // Bytes applications read from and wrote to the mount
+    read_bytes: AtomicU64,
+    written_bytes: AtomicU64,
+    // Bytes of deduplicated writes uploaded to the DO, and those it already had
+    dedup_uploaded: AtomicU64,
+    dedup_skipped: AtomicU64,
+    // Bytes of holes the DO left out of reads of sparse files
+    sparse_holes: AtomicU64,
+    // Where reads were served from: the daemon's caches or the DO
+    inline_hits: AtomicU64,
+    readahead_hits: AtomicU64,
+    block_hits: AtomicU64,
+    read_misses: AtomicU64,
+    dir_hits: AtomicU64,
+    dir_misses: AtomicU64,
+    // Live connections to the DO, and dead ones replaced since startup
+    connections: AtomicU64,
+    reconnects: AtomicU64,
+    // Each mount by mount point: whether it is up, and how often it has been
+    // mounted again after going away
+    mounts: Mutex<BTreeMap<String, (bool, u64)>>,
+}
+
+// Where a read was answered from
+#[derive(Clone, Copy)]
+pub enum ReadSource {
+    Inline,
+    Readahead,
+    BlockCache,
+    Remote,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        // Arrays of atomics are built by repeating a const item
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        #[allow(clippy::declare_interior_mutable_const)]
+        const PHASE_LATENCIES: [Histogram; PHASES.len()] = [Histogram::EMPTY; PHASES.len()];
+        Self {
+            requests: [ZERO; FsOperation::ALL.len()],
+            latencies: [PHASE_LATENCIES; FsOperation::ALL.len()],
+            errors: Mutex::new(BTreeMap::new()),
+            in_flight: AtomicU64::new(0),
+            stuck: AtomicU64::new(0),
+            stuck_now: AtomicU64::new(0),
+            reaped: AtomicU64::new(0),
+            late_responses: AtomicU64::new(0),
+            read_bytes: AtomicU64::new(0),
+            written_bytes: AtomicU64::new(0),
+            dedup_uploaded: AtomicU64::new(0),
+            dedup_skipped: AtomicU64::new(0),
+            sparse_holes: AtomicU64::new(0),
+            inline_hits: AtomicU64::new(0),
+            readahead_hits: AtomicU64::new(0),
+            block_hits: AtomicU64::new(0),
+            read_misses: AtomicU64::new(0),
+            dir_hits: AtomicU64::new(0),
+            dir_misses: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            mounts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // A request to the DO that finished, and the errno if it failed
+    pub fn request(&self, operation: FsOperation, errno: Option<i32>) {
+        self.requests[operation as usize].fetch_add(1, Ordering::Relaxed);
+        if let Some(errno) = errno {
+            *self.errors.locked().entry(errno).or_default() += 1;
+        }
+    }
+
+    // Time a request spent queued in the daemon and answered, and how much of
+    // the answer the DO spent on it when it says. Without that, the network
+    // phase includes the DO's time.
+    pub fn latency(&self, operation: FsOperation, queue: Duration, round_trip: Duration, in_do: Option<Duration>) {
+        let [queued, network, remote] = &self.latencies[operation as usize];
+        queued.observe(queue);
+        network.observe(round_trip.saturating_sub(in_do.unwrap_or_default()));
+        if let Some(in_do) = in_do {
+            remote.observe(in_do);
+        }
+    }
+
+    pub fn request_started(&self) {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn request_ended(&self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    // A watchdog pass: requests that became stuck since the last, and all
+    // those stuck now
+    pub fn stuck(&self, new: usize, now: usize) {
+        self.stuck.fetch_add(new as u64, Ordering::Relaxed);
+        self.stuck_now.store(now as u64, Ordering::Relaxed);
+    }
+
+    pub fn reaped(&self, count: usize) {
+        self.reaped.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn late_response(&self) {
+        self.late_responses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn read(&self, source: ReadSource, bytes: usize) {
+        let counter = match source {
+            ReadSource::Inline => &self.inline_hits,
+            ReadSource::Readahead => &self.readahead_hits,
+            ReadSource::BlockCache => &self.block_hits,
+            ReadSource::Remote => &self.read_misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.read_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn written(&self, bytes: usize) {
+        self.written_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn deduplicated(&self, uploaded: usize, skipped: usize) {
+        self.dedup_uploaded.fetch_add(uploaded as u64, Ordering::Relaxed);
+        self.dedup_skipped.fetch_add(skipped as u64, Ordering::Relaxed);
+    }
+
+    pub fn sparse_read(&self, holes: u64) {
+        self.sparse_holes.fetch_add(holes, Ordering::Relaxed);
+    }
+
+    pub fn listing(&self, cached: bool) {
+        let counter = if cached { &self.dir_hits } else { &self.dir_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // A connection joined the pool, in place of a dead one or not
+    pub fn connected(&self, replacing: bool) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        if replacing {
+            self.reconnects.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn disconnected(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn mount_up(&self, mount_point: &str, up: bool) {
+        self.mounts.locked().entry(mount_point.to_string()).or_default().0 = up;
+    }
+
+    pub fn remounted(&self, mount_point: &str) {
+        self.mounts.locked().entry(mount_point.to_string()).or_default().1 += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let value = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        family(&mut out, "fsdaemon_requests_total", "counter", "Requests sent to the DO by operation");
+        for operation in FsOperation::ALL {
+            let count = value(&self.requests[operation as usize]);
+            let _ = writeln!(out, "fsdaemon_requests_total{{op=\"{}\"}} {}", operation, count);
+        }
+        family(&mut out, "fsdaemon_request_errors_total", "counter", "Failed requests by errno");
+        for (errno, count) in self.errors.locked().iter() {
+            let _ = writeln!(out, "fsdaemon_request_errors_total{{errno=\"{}\"}} {}", errno, count);
+        }
+        gauge(&mut out, "fsdaemon_requests_in_flight", "Requests awaiting a response", value(&self.in_flight));
+        family(&mut out, "fsdaemon_stuck_requests_total", "counter", "Requests outstanding past the stuck threshold");
+        let _ = writeln!(out, "fsdaemon_stuck_requests_total {}", value(&self.stuck));
+        let stuck_now = value(&self.stuck_now);
+        gauge(&mut out, "fsdaemon_requests_stuck", "Requests outstanding past the stuck threshold now", stuck_now);
+        family(&mut out, "fsdaemon_pending_reaped_total", "counter", "Abandoned pending requests removed");
+        let _ = writeln!(out, "fsdaemon_pending_reaped_total {}", value(&self.reaped));
+        family(&mut out, "fsdaemon_late_responses_total", "counter", "Responses to requests no longer pending");
+        let _ = writeln!(out, "fsdaemon_late_responses_total {}", value(&self.late_responses));
+
+        let name = "fsdaemon_request_duration_seconds";
+        family(&mut out, name, "histogram", "Request latency by operation and phase");
+        for operation in FsOperation::ALL {
+            for (phase, histogram) in PHASES.iter().zip(&self.latencies[operation as usize]) {
+                histogram.render(&mut out, name, &format!("op=\"{}\",phase=\"{}\"", operation, phase));
+            }
+        }
+
+        family(&mut out, "fsdaemon_bytes_total", "counter", "Bytes applications read and wrote through the mount");
+        let _ = writeln!(out, "fsdaemon_bytes_total{{direction=\"read\"}} {}", value(&self.read_bytes));
+        let _ = writeln!(out, "fsdaemon_bytes_total{{direction=\"write\"}} {}", value(&self.written_bytes));
+
+        let help = "Bytes of deduplicated writes by whether they were uploaded";
+        family(&mut out, "fsdaemon_dedup_bytes_total", "counter", help);
+        let _ = writeln!(out, "fsdaemon_dedup_bytes_total{{outcome=\"uploaded\"}} {}", value(&self.dedup_uploaded));
+        let _ = writeln!(out, "fsdaemon_dedup_bytes_total{{outcome=\"skipped\"}} {}", value(&self.dedup_skipped));
+
+        let help = "Bytes of holes in sparse files that reads didn't transfer";
+        family(&mut out, "fsdaemon_sparse_hole_bytes_total", "counter", help);
+        let _ = writeln!(out, "fsdaemon_sparse_hole_bytes_total {}", value(&self.sparse_holes));
+
+        family(&mut out, "fsdaemon_reads_total", "counter", "Reads by where they were served from");
+        for (source, counter) in [
+            ("inline", &self.inline_hits),
+            ("readahead", &self.readahead_hits),
+            ("block-cache", &self.block_hits),
+            ("remote", &self.read_misses),
+        ] {
+            let _ = writeln!(out, "fsdaemon_reads_total{{source=\"{}\"}} {}", source, value(counter));
+        }
+        family(&mut out, "fsdaemon_listings_total", "counter", "Directory pages by whether they were cached");
+        let _ = writeln!(out, "fsdaemon_listings_total{{source=\"cache\"}} {}", value(&self.dir_hits));
+        let _ = writeln!(out, "fsdaemon_listings_total{{source=\"remote\"}} {}", value(&self.dir_misses));
+
+        gauge(&mut out, "fsdaemon_connections", "Live connections to the DO", value(&self.connections));
+        let lifecycle = lifecycle::connection();
+        let (current, _) = lifecycle.state();
+        family(&mut out, "fsdaemon_connection_state", "gauge", "1 for the state the connection to the DO is in");
+        for state in State::ALL {
+            let active = (state == current) as u8;
+            let _ = writeln!(out, "fsdaemon_connection_state{{state=\"{}\"}} {}", state.as_str(), active);
+        }
+        family(&mut out, "fsdaemon_connection_transitions_total", "counter", "Connection state changes");
+        for (from, to, count) in lifecycle.transitions() {
+            let labels = format!("from=\"{}\",to=\"{}\"", from.as_str(), to.as_str());
+            let _ = writeln!(out, "fsdaemon_connection_transitions_total{{{}}} {}", labels, count);
+        }
+        family(&mut out, "fsdaemon_reconnects_total", "counter", "Dead connections replaced by new ones");
+        let _ = writeln!(out, "fsdaemon_reconnects_total {}", value(&self.reconnects));
+
+        let mounts = self.mounts.locked();
+        family(&mut out, "fsdaemon_mount_up", "gauge", "1 for each mount that is serving requests");
+        for (mount_point, (up, _)) in mounts.iter() {
+            let _ = writeln!(out, "fsdaemon_mount_up{{mount=\"{}\"}} {}", mount_point, *up as u8);
+        }
+        family(&mut out, "fsdaemon_remounts_total", "counter", "Mounts brought back after going away");
+        for (mount_point, (_, remounts)) in mounts.iter() {
+            let _ = writeln!(out, "fsdaemon_remounts_total{{mount=\"{}\"}} {}", mount_point, remounts);
+        }
+        drop(mounts);
+        out
+    }
+}
+
+// A Prometheus histogram over LATENCY_BUCKETS, whose bucket counts are
+// cumulative, so quantiles come from `histogram_quantile`
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Self = {
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Self {
+            buckets: [ZERO; LATENCY_BUCKETS.len()],
+            count: ZERO,
+            sum_micros: ZERO,
+        }
+    };
+
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    // Series that never saw a request are left out
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let count = self.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return;
+        }
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, bucket.load(Ordering::Relaxed));
+        }
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum{{{}}} {}\n{}_count{{{}}} {}", name, labels, sum, name, labels, count);
+    }
+}
+
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    family(out, name, "gauge", help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// Serves GET /metrics on `address` for Prometheus to scrape, and /healthz
+// and /readyz for the platform's probes, one request at a time
+pub fn spawn_server(address: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    info!("Serving metrics on http://{}/metrics", address);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Err(e) = stream.and_then(answer) {
+                warn!("Failed to answer a request on the metrics listener: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn answer(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    let mut request_line = String::new();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    reader.read_line(&mut request_line)?;
+    // Headers are read and ignored so the client sees a clean close
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let probe = |ready| match HEALTH.check(ready) {
+        (true, body) => ("200 OK", "application/json", body),
+        (false, body) => ("503 Service Unavailable", "application/json", body),
+    };
+    let (status, content_type, body) = match path {
+        "/metrics" => ("200 OK", "text/plain; version=0.0.4", METRICS.render()),
+        "/healthz" => probe(false),
+        "/readyz" => probe(true),
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
@@ -0,0 +1,143 @@
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+// What becomes of a name the kernel hands the daemon that the DO can't hold
+// as it is: one that isn't UTF-8, or has a `/` or a control character in it
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NamePolicy {
+    // Fail the call with EINVAL
+    Reject,
+    // Store the offending bytes as `%XX`, and `%` itself as `%25`, turning
+    // them back when listing, so the name round-trips
+    Escape,
+    // Store each offending character, or byte that isn't UTF-8, as U+FFFD
+    Replace,
+}
+
+impl NamePolicy {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "reject" => Ok(NamePolicy::Reject),
+            "escape" => Ok(NamePolicy::Escape),
+            "replace" => Ok(NamePolicy::Replace),
+            _ => Err("expected reject, escape or replace".to_string()),
+        }
+    }
+
+    // The DO's name for a name from the kernel
+    pub fn encode(self, name: &OsStr) -> Result<Cow<'_, str>, libc::c_int> {
+        // Escaping takes `%` too, so that names with it in them decode as they were
+        let escaped = |c: char| offending(c) || (self == NamePolicy::Escape && c == '%');
+        let kept = name.to_str().filter(|name| !name.chars().any(escaped));
+        if let Some(name) = kept {
+            return Ok(Cow::Borrowed(name));
+        }
+        match self {
+            NamePolicy::Reject => Err(libc::EINVAL),
+            NamePolicy::Escape => Ok(Cow::Owned(escape(name.as_bytes()))),
+            NamePolicy::Replace => {
+                let lossy = name.to_string_lossy();
+                Ok(Cow::Owned(lossy.chars().map(|c| if offending(c) { '\u{FFFD}' } else { c }).collect()))
+            }
+        }
+    }
+
+    // The kernel's name for one the DO lists. Only escaped names change, and
+    // only when escaping gives the DO's name back: others, such as `%41`, which
+    // the daemon would have stored as `A`, are shown as they are.
+    pub fn decode(self, name: &str) -> Cow<'_, OsStr> {
+        if self != NamePolicy::Escape || !name.contains('%') {
+            return Cow::Borrowed(OsStr::new(name));
+        }
+        match unescape(name) {
+            Some(bytes) if escape(&bytes) == name => Cow::Owned(OsString::from_vec(bytes)),
+            _ => Cow::Borrowed(OsStr::new(name)),
+        }
+    }
+}
+
+// Characters no name in the DO has: the separator and control characters
+fn offending(c: char) -> bool {
+    c == '/' || c.is_control()
+}
+
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        // The longest UTF-8 start, then the bytes that can't begin a character
+        let (valid, invalid) = match std::str::from_utf8(rest) {
+            Ok(valid) => (valid, &[][..]),
+            Err(e) => {
+                let (valid, after) = rest.split_at(e.valid_up_to());
+                let invalid = &after[..e.error_len().unwrap_or(after.len())];
+                (std::str::from_utf8(valid).unwrap_or_default(), invalid)
+            }
+        };
+        rest = &rest[valid.len() + invalid.len()..];
+        for c in valid.chars() {
+            match c {
+                '%' => escaped.push_str("%25"),
+                c if offending(c) => {
+                    let mut utf8 = [0; 4];
+                    for byte in c.encode_utf8(&mut utf8).bytes() {
+                        escaped.push_str(&format!("%{:02X}", byte));
+                    }
+                }
+                c => escaped.push(c),
+            }
+        }
+        for byte in invalid {
+            escaped.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    escaped
+}
+
+fn unescape(name: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut rest = name.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(after.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &after[2..];
+        } else {
+            bytes.push(byte);
+            rest = after;
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_kept_escaped_replaced_or_rejected() {
+        let plain = OsStr::new("report 100%.txt");
+        let odd = OsStr::from_bytes(b"a\nb\xffc%");
+        assert_eq!(NamePolicy::Reject.encode(plain).unwrap(), "report 100%.txt");
+        assert_eq!(NamePolicy::Reject.encode(odd), Err(libc::EINVAL));
+        assert_eq!(NamePolicy::Reject.encode(OsStr::new("a/b")), Err(libc::EINVAL));
+        assert_eq!(NamePolicy::Replace.encode(odd).unwrap(), "a\u{FFFD}b\u{FFFD}c%");
+        assert_eq!(NamePolicy::Escape.encode(plain).unwrap(), "report 100%25.txt");
+        assert_eq!(NamePolicy::Escape.encode(odd).unwrap(), "a%0Ab%FFc%25");
+        assert_eq!(NamePolicy::Escape.encode(OsStr::new("héllo")).unwrap(), "héllo");
+    }
+
+    #[test]
+    fn escaped_names_round_trip() {
+        let escape = NamePolicy::Escape;
+        for name in [&b"a\nb\xffc%"[..], b"report 100%.txt", b"\x7f\xc3", "héllo".as_bytes()] {
+            let name = OsStr::from_bytes(name);
+            assert_eq!(escape.decode(&escape.encode(name).unwrap()), name);
+        }
+        // Names the daemon wouldn't have stored show as they are
+        assert_eq!(escape.decode("%41"), OsStr::new("%41"));
+        assert_eq!(escape.decode("100%"), OsStr::new("100%"));
+        assert_eq!(NamePolicy::Reject.decode("a%0Ab"), OsStr::new("a%0Ab"));
+    }
+}
@@ -0,0 +1,167 @@
+use std::sync::Arc;
+
+use crate::transport::Address;
+use crate::{join, parse_prefix, Endpoint, RemoteFSClient};
+
+// A --namespace value, `<name>:<prefix>` then where its DO is, such as
+// `shared:/,connect=10.0.0.2:8000`. Without an address the namespace dials
+// the main DO again for a session of its own.
+#[derive(Clone, Debug)]
+pub struct NamespaceSpec {
+    pub name: String,
+    pub prefix: String,
+    pub endpoint: Option<Endpoint>,
+}
+
+impl NamespaceSpec {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut settings = value.split(',');
+        let (name, prefix) = settings
+            .next()
+            .and_then(|paths| paths.split_once(':'))
+            .ok_or("expected <name>:<prefix>[,connect=<address>|listen=<address>]")?;
+        if name.is_empty() || matches!(name, "." | "..") || name.contains('/') {
+            return Err(format!("invalid name '{}', expected a single directory name", name));
+        }
+        let mut spec = Self { name: name.to_string(), prefix: parse_prefix(prefix)?, endpoint: None };
+        for setting in settings {
+            let named = |e: String| format!("{}: {}", setting, e);
+            match (setting.split_once('='), &mut spec.endpoint) {
+                (Some(("connect", address)), Some(Endpoint::Dial(addresses))) => {
+                    addresses.push(Address::parse(address).map_err(named)?)
+                }
+                (Some(("connect", address)), endpoint @ None) => {
+                    *endpoint = Some(Endpoint::Dial(vec![Address::parse(address).map_err(named)?]))
+                }
+                (Some(("listen", address)), endpoint @ None) => {
+                    *endpoint = Some(Endpoint::Listen(Address::parse(address).map_err(named)?))
+                }
+                (Some(("connect" | "listen", _)), Some(_)) => {
+                    return Err(format!("{}: a namespace either connects or listens", setting))
+                }
+                _ => return Err(format!("unknown setting '{}', expected connect or listen", setting)),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+// Where a namespace is grafted: the paths under `dir` of the main DO's tree
+// are those under `prefix` of the namespace's own DO
+#[derive(Clone, Debug)]
+pub struct Graft {
+    pub dir: String,
+    pub prefix: String,
+}
+
+impl Graft {
+    // The namespace's DO's path for a path of the main tree, if it is in it
+    fn inner(&self, path: &str) -> Option<String> {
+        match path.strip_prefix(self.dir.as_str())? {
+            "" => Some(self.prefix.clone()),
+            rest if rest.starts_with('/') => Some(match self.prefix.as_str() {
+                "/" => rest.to_string(),
+                prefix => format!("{}{}", prefix, rest),
+            }),
+            _ => None,
+        }
+    }
+
+    // The main tree's path for one of the namespace's DO, if the namespace
+    // shows it
+    pub fn outer(&self, path: &str) -> Option<String> {
+        let rest = match self.prefix.as_str() {
+            "/" => path,
+            prefix => path.strip_prefix(prefix)?,
+        };
+        match rest {
+            "" | "/" => Some(self.dir.clone()),
+            rest if rest.starts_with('/') => Some(format!("{}{}", self.dir, rest)),
+            _ => None,
+        }
+    }
+}
+
+pub struct Namespace {
+    pub graft: Graft,
+    pub client: Arc<RemoteFSClient>,
+}
+
+// Every namespace grafted onto the main DO's tree, which requests are routed
+// between by path
+#[derive(Default)]
+pub struct Namespaces(Vec<Namespace>);
+
+impl Namespaces {
+    pub fn new(main_prefix: &str, namespaces: Vec<(NamespaceSpec, Arc<RemoteFSClient>)>) -> Self {
+        Self(
+            namespaces
+                .into_iter()
+                .map(|(spec, client)| Namespace {
+                    graft: Graft { dir: join(main_prefix, &spec.name), prefix: spec.prefix },
+                    client,
+                })
+                .collect(),
+        )
+    }
+
+    // The client a path goes to and its path there, when it isn't the main DO
+    pub fn route(&self, path: &str) -> Option<(&Arc<RemoteFSClient>, String)> {
+        self.0.iter().find_map(|namespace| Some((&namespace.client, namespace.graft.inner(path)?)))
+    }
+
+    // Whether a path is where a namespace is grafted, a directory whatever
+    // its DO has
+    pub fn is_root(&self, path: &str) -> bool {
+        self.0.iter().any(|namespace| namespace.graft.dir == path)
+    }
+
+    // The names of the namespaces grafted directly under a directory
+    pub fn names_in(&self, dir: &str) -> Vec<&str> {
+        self.0.iter().filter_map(|namespace| grafted_in(&namespace.graft.dir, dir)).collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Namespace> {
+        self.0.iter()
+    }
+}
+
+// The name `path` has in `dir`, if it is directly under it
+fn grafted_in<'a>(path: &'a str, dir: &str) -> Option<&'a str> {
+    let (parent, name) = path.rsplit_once('/')?;
+    (parent == dir || (parent.is_empty() && dir == "/")).then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn specs_parse_with_their_endpoint() {
+        let spec = NamespaceSpec::parse("shared:/team,connect=10.0.0.2:8000,connect=10.0.0.3:8000").unwrap();
+        assert_eq!((spec.name.as_str(), spec.prefix.as_str()), ("shared", "/team"));
+        assert!(matches!(spec.endpoint, Some(Endpoint::Dial(addresses)) if addresses.len() == 2));
+        assert!(NamespaceSpec::parse("private:/").unwrap().endpoint.is_none());
+        assert!(NamespaceSpec::parse("a/b:/").unwrap_err().contains("single directory"));
+        assert!(NamespaceSpec::parse("a:/,listen=0.0.0.0:1,connect=h:2").unwrap_err().contains("either"));
+        assert!(NamespaceSpec::parse("a:/,ttl=1").unwrap_err().contains("unknown setting"));
+    }
+
+    #[test]
+    fn paths_map_both_ways() {
+        let at_root = Graft { dir: "/data/shared".to_string(), prefix: "/".to_string() };
+        assert_eq!(at_root.inner("/data/shared").as_deref(), Some("/"));
+        assert_eq!(at_root.inner("/data/shared/a.txt").as_deref(), Some("/a.txt"));
+        assert_eq!(at_root.inner("/data/sharedx"), None);
+        assert_eq!(at_root.outer("/a.txt").as_deref(), Some("/data/shared/a.txt"));
+        let under = Graft { prefix: "/team".to_string(), ..at_root };
+        assert_eq!(under.inner("/data/shared/a.txt").as_deref(), Some("/team/a.txt"));
+        assert_eq!(under.outer("/team").as_deref(), Some("/data/shared"));
+        assert_eq!(under.outer("/team/a.txt").as_deref(), Some("/data/shared/a.txt"));
+        assert_eq!(under.outer("/other/a.txt"), None);
+        assert_eq!(under.outer("/teamwork"), None);
+        assert_eq!(grafted_in("/shared", "/"), Some("shared"));
+        assert_eq!(grafted_in("/data/shared", "/data"), Some("shared"));
+        assert_eq!(grafted_in("/data/shared", "/"), None);
+    }
+}
@@ -0,0 +1,1269 @@
+use std::collections::VecDeque;
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use fuser::{FileAttr, FileType};
+use tracing::{debug, info, warn};
+
+use crate::admin;
+use crate::audit::{AuditOp, Caller};
+use crate::error::Locked;
+use crate::metrics::METRICS;
+use crate::transport::Address;
+use crate::{RemoteFS, MAX_IO_SIZE};
+
+pub const DEFAULT_NFS_LISTEN: &str = "127.0.0.1:11111";
+
+// Served from one port, so clients mount with port= and mountport= set to it
+// and never ask a portmapper
+const NFS_PROGRAM: u32 = 100003;
+const NFS_VERSION: u32 = 3;
+const MOUNT_PROGRAM: u32 = 100005;
+const MOUNT_VERSION: u32 = 3;
+const NFS_PROCEDURES: u32 = 22;
+const MOUNT_PROCEDURES: u32 = 6;
+
+const AUTH_UNIX: u32 = 1;
+
+// A call carries at most one write, so anything much longer is garbage
+const MAX_RECORD: usize = MAX_IO_SIZE + 4096;
+
+// Files written through an export whose buffered writes wait for a COMMIT.
+// Past this the least recently written is flushed to make room.
+const MAX_HANDLES: usize = 64;
+
+// NFSv3 statuses that aren't an errno
+const NFS3_OK: u32 = 0;
+const NFS3ERR_IO: u32 = 5;
+const NFS3ERR_INVAL: u32 = 22;
+const NFS3ERR_ROFS: u32 = 30;
+const NFS3ERR_STALE: u32 = 70;
+const NFS3ERR_BADHANDLE: u32 = 10001;
+const NFS3ERR_NOTSUPP: u32 = 10004;
+const NFS3ERR_JUKEBOX: u32 = 10008;
+const NFS3ERR_TOOSMALL: u32 = 10020;
+
+// How a write is to be stored before it is acknowledged
+const UNSTABLE: u32 = 0;
+const FILE_SYNC: u32 = 2;
+
+// The client's ACCESS bits that change something
+const ACCESS_MODIFY: u32 = 0x04 | 0x08 | 0x10;
+
+// What a failure means to an NFS client. Those that go away on their own ask
+// it to try again later.
+fn status(errno: i32) -> u32 {
+    match errno {
+        libc::EPERM => 1,
+        libc::ENOENT => 2,
+        libc::ENXIO => 6,
+        libc::EACCES => 13,
+        libc::EEXIST => 17,
+        libc::ENOTDIR => 20,
+        libc::EISDIR => 21,
+        libc::EINVAL => NFS3ERR_INVAL,
+        libc::EFBIG => 27,
+        libc::ENOSPC => 28,
+        libc::EROFS => NFS3ERR_ROFS,
+        libc::ENAMETOOLONG => 63,
+        libc::ENOTEMPTY => 66,
+        libc::EDQUOT => 69,
+        libc::ESTALE => NFS3ERR_STALE,
+        libc::ENOSYS | libc::EOPNOTSUPP => NFS3ERR_NOTSUPP,
+        libc::EAGAIN | libc::ETIMEDOUT => NFS3ERR_JUKEBOX,
+        _ => NFS3ERR_IO,
+    }
+}
+
+// Clients are trusted to say who they are, so only those in the container
+// may connect
+pub fn parse_listen(value: &str) -> Result<String, String> {
+    match admin::parse_address(value) {
+        Ok(Address::Tcp(address)) => Ok(address),
+        _ => Err("expected a loopback host:port".to_string()),
+    }
+}
+
+// Arguments that don't decode
+struct Garbage;
+
+// XDR decoding of a call's arguments
+struct Args<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Args<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn fixed(&mut self, len: usize) -> Result<&'a [u8], Garbage> {
+        let padded = len.checked_add(3).ok_or(Garbage)? & !3;
+        if self.data.len() < padded {
+            return Err(Garbage);
+        }
+        let (value, rest) = self.data.split_at(padded);
+        self.data = rest;
+        Ok(&value[..len])
+    }
+
+    fn u32(&mut self) -> Result<u32, Garbage> {
+        Ok(u32::from_be_bytes(self.fixed(4)?.try_into().map_err(|_| Garbage)?))
+    }
+
+    fn u64(&mut self) -> Result<u64, Garbage> {
+        Ok(u64::from_be_bytes(self.fixed(8)?.try_into().map_err(|_| Garbage)?))
+    }
+
+    fn bool(&mut self) -> Result<bool, Garbage> {
+        Ok(self.u32()? != 0)
+    }
+
+    fn opaque(&mut self) -> Result<&'a [u8], Garbage> {
+        let len = self.u32()? as usize;
+        self.fixed(len)
+    }
+
+    fn string(&mut self) -> Result<&'a str, Garbage> {
+        std::str::from_utf8(self.opaque()?).map_err(|_| Garbage)
+    }
+
+    // The mode and size a sattr3 sets, skipping the owner and times, which
+    // the DO has no place for
+    fn sattr(&mut self) -> Result<(Option<u32>, Option<u64>), Garbage> {
+        let mode = match self.bool()? {
+            true => Some(self.u32()?),
+            false => None,
+        };
+        for _ in 0..2 {
+            if self.bool()? {
+                self.u32()?;
+            }
+        }
+        let size = match self.bool()? {
+            true => Some(self.u64()?),
+            false => None,
+        };
+        for _ in 0..2 {
+            // Set to the client's time, which follows
+            if self.u32()? == 2 {
+                self.u64()?;
+            }
+        }
+        Ok((mode, size))
+    }
+}
+
+// XDR encoding of a reply
+#[derive(Default)]
+struct Reply(Vec<u8>);
+
+impl Reply {
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u32(value as u32);
+    }
+
+    fn fixed(&mut self, value: &[u8]) {
+        self.0.extend_from_slice(value);
+        self.0.resize(self.0.len().next_multiple_of(4), 0);
+    }
+
+    fn opaque(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.fixed(value);
+    }
+
+    fn time(&mut self, time: SystemTime) {
+        let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.u32(since.as_secs() as u32);
+        self.u32(since.subsec_nanos());
+    }
+
+    fn fattr(&mut self, attr: &FileAttr, fsid: u64) {
+        self.u32(match attr.kind {
+            FileType::RegularFile => 1,
+            FileType::Directory => 2,
+            FileType::BlockDevice => 3,
+            FileType::CharDevice => 4,
+            FileType::Symlink => 5,
+            FileType::Socket => 6,
+            FileType::NamedPipe => 7,
+        });
+        self.u32(attr.perm as u32);
+        self.u32(attr.nlink);
+        self.u32(attr.uid);
+        self.u32(attr.gid);
+        self.u64(attr.size);
+        self.u64(attr.blocks * 512);
+        // Major and minor, as Linux packs them
+        self.u32((attr.rdev >> 8) & 0xfff);
+        self.u32((attr.rdev & 0xff) | ((attr.rdev >> 12) & 0xfff00));
+        self.u64(fsid);
+        self.u64(attr.ino);
+        self.time(attr.atime);
+        self.time(attr.mtime);
+        self.time(attr.ctime);
+    }
+
+    fn post_op_attr(&mut self, attr: Option<&FileAttr>, fsid: u64) {
+        self.bool(attr.is_some());
+        if let Some(attr) = attr {
+            self.fattr(attr, fsid);
+        }
+    }
+
+    // Attributes from before an operation are never sent
+    fn wcc_data(&mut self, after: Option<&FileAttr>, fsid: u64) {
+        self.bool(false);
+        self.post_op_attr(after, fsid);
+    }
+}
+
+// One mount's filesystem, exported at its mount point
+struct Export {
+    path: String,
+    served: Mutex<Served>,
+}
+
+struct Served {
+    fs: RemoteFS,
+    // The handle each file's reads and writes go through, by inode, most
+    // recently used last. NFS has no open or close, so these stand in for
+    // the handles FUSE opens.
+    handles: VecDeque<(u64, u64)>,
+}
+
+impl Served {
+    fn handle(&mut self, ino: u64, verifier: &AtomicU64) -> u64 {
+        if let Some(index) = self.handles.iter().position(|(handled, _)| *handled == ino) {
+            let entry = self.handles.remove(index).unwrap_or((ino, 0));
+            self.handles.push_back(entry);
+            return entry.1;
+        }
+        if self.handles.len() >= MAX_HANDLES {
+            if let Some((ino, fh)) = self.handles.pop_front() {
+                self.release(ino, fh, verifier);
+            }
+        }
+        let fh = self.fs.next_handle();
+        self.handles.push_back((ino, fh));
+        fh
+    }
+
+    fn forget(&mut self, ino: u64, verifier: &AtomicU64) {
+        if let Some(index) = self.handles.iter().position(|(handled, _)| *handled == ino) {
+            if let Some((ino, fh)) = self.handles.remove(index) {
+                self.release(ino, fh, verifier);
+            }
+        }
+    }
+
+    // Writes that can't be sent are lost, so clients are told to send again
+    // whatever they haven't seen committed
+    fn release(&mut self, ino: u64, fh: u64, verifier: &AtomicU64) {
+        if let Err(e) = self.fs.release_handle(fh) {
+            warn!("Failed to flush NFS writes to inode {}, clients will resend them: {}", ino, e);
+            verifier.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    // Attributes as a client should see them, counting writes still buffered
+    fn attr(&mut self, ino: u64, path: &str) -> Result<FileAttr, u32> {
+        match self.fs.buffered_attr(path) {
+            Ok(Some(attr)) => Ok(FileAttr { ino, ..attr }),
+            Ok(None) => Err(status(libc::ENOENT)),
+            Err(e) => Err(status(e.errno())),
+        }
+    }
+
+    // Attributes from the last stat, for replies that may leave them out
+    fn cached_attr(&self, ino: u64, path: &str) -> Option<FileAttr> {
+        let stat = self.fs.stats.get(path)?;
+        Some(self.fs.attr_for(ino, path, stat))
+    }
+
+    // Why a change can't be made here, if it can't
+    fn refuses_changes(&self, path: &str) -> Option<u32> {
+        if self.fs.read_only || self.fs.snapshot_path(path).is_some() {
+            return Some(NFS3ERR_ROFS);
+        }
+        self.fs.draining().then_some(NFS3ERR_JUKEBOX)
+    }
+}
+
+// A file handle's inode in an export, and its path there
+struct Target<'a> {
+    fsid: u64,
+    served: MutexGuard<'a, Served>,
+    ino: u64,
+    path: String,
+}
+
+// The NFSv3 server every mount is exported through, instead of being
+// mounted with FUSE
+pub struct Server {
+    // Goes into every file handle, so those from before a restart are stale
+    boot: u64,
+    // Changes whenever acknowledged writes may have been lost
+    verifier: AtomicU64,
+    exports: Vec<Export>,
+}
+
+// Serves `filesystems` on `address`, each exported at its mount point
+pub fn spawn(address: &str, filesystems: Vec<RemoteFS>) -> io::Result<Arc<Server>> {
+    let listener = TcpListener::bind(address)?;
+    let boot = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+    let mut exports = Vec::new();
+    for mut fs in filesystems {
+        fs.started(MAX_IO_SIZE as u32, MAX_IO_SIZE as u32);
+        exports.push(Export {
+            path: fs.mount_point.clone(),
+            served: Mutex::new(Served { fs, handles: VecDeque::new() }),
+        });
+    }
+    let server = Arc::new(Server { boot, verifier: AtomicU64::new(boot), exports });
+    let paths: Vec<_> = server.exports.iter().map(|export| export.path.as_str()).collect();
+    info!("Serving NFSv3 on {}, exporting {}", listener.local_addr()?, paths.join(", "));
+
+    let accepting = server.clone();
+    thread::spawn(move || loop {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                let server = accepting.clone();
+                thread::spawn(move || {
+                    debug!("NFS client {} connected", peer);
+                    if let Err(e) = server.serve(stream) {
+                        debug!("NFS client {} went away: {}", peer, e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept an NFS client: {}", e),
+        }
+    });
+    Ok(server)
+}
+
+impl Server {
+    // Sends what clients wrote. The daemon is draining by now, so clients
+    // asking for more changes are told to try again later.
+    pub fn stop(&self) {
+        for export in &self.exports {
+            let mut served = export.served.locked();
+            while let Some((ino, fh)) = served.handles.pop_front() {
+                served.release(ino, fh, &self.verifier);
+            }
+            served.fs.stopped();
+        }
+    }
+
+    fn serve(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        loop {
+            let record = read_record(&mut stream)?;
+            if let Some(reply) = self.call(&record) {
+                write_record(&mut stream, &reply)?;
+            }
+        }
+    }
+
+    // The reply to an RPC call, or None for a message that isn't one
+    fn call(&self, record: &[u8]) -> Option<Vec<u8>> {
+        let mut args = Args::new(record);
+        let xid = args.u32().ok()?;
+        if args.u32().ok()? != 0 {
+            return None;
+        }
+        let mut reply = Reply::default();
+        reply.u32(xid);
+        reply.u32(1);
+        if args.u32().ok()? != 2 {
+            // Denied, as only version 2 of RPC is spoken
+            for word in [1, 0, 2, 2] {
+                reply.u32(word);
+            }
+            return Some(reply.0);
+        }
+        let (program, version, procedure) = (args.u32().ok()?, args.u32().ok()?, args.u32().ok()?);
+        let caller = credentials(&mut args).ok()?;
+        // Accepted, with an empty verifier
+        for word in [0, 0, 0] {
+            reply.u32(word);
+        }
+        let procedures = match (program, version) {
+            (NFS_PROGRAM, NFS_VERSION) => NFS_PROCEDURES,
+            (MOUNT_PROGRAM, MOUNT_VERSION) => MOUNT_PROCEDURES,
+            (NFS_PROGRAM, _) => return Some(mismatch(reply, NFS_VERSION)),
+            (MOUNT_PROGRAM, _) => return Some(mismatch(reply, MOUNT_VERSION)),
+            _ => {
+                reply.u32(1);
+                return Some(reply.0);
+            }
+        };
+        if procedure >= procedures {
+            reply.u32(3);
+            return Some(reply.0);
+        }
+
+        let accepted = reply.0.len();
+        reply.u32(0);
+        let result = match program {
+            NFS_PROGRAM => self.nfs(procedure, &mut args, caller, &mut reply),
+            _ => self.mount(procedure, &mut args, &mut reply),
+        };
+        if result.is_err() {
+            reply.0.truncate(accepted);
+            reply.u32(4);
+        }
+        Some(reply.0)
+    }
+
+    fn mount(&self, procedure: u32, args: &mut Args, reply: &mut Reply) -> Result<(), Garbage> {
+        match procedure {
+            // MNT: the handle of an export's root
+            1 => {
+                let path = args.string()?;
+                let path = match path.trim_end_matches('/') {
+                    "" => "/",
+                    path => path,
+                };
+                match self.exports.iter().position(|export| export.path == path) {
+                    Some(index) => {
+                        info!("NFS client mounted {}", path);
+                        reply.u32(0);
+                        reply.opaque(&self.handle(index, 1));
+                        reply.u32(1);
+                        reply.u32(AUTH_UNIX);
+                    }
+                    None => reply.u32(2),
+                }
+            }
+            // DUMP: mounts aren't tracked, so none are listed
+            2 => reply.bool(false),
+            // EXPORT
+            5 => {
+                for export in &self.exports {
+                    reply.bool(true);
+                    reply.opaque(export.path.as_bytes());
+                    reply.bool(false);
+                }
+                reply.bool(false);
+            }
+            // NULL, UMNT and UMNTALL answer nothing
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn nfs(&self, procedure: u32, args: &mut Args, caller: Caller, reply: &mut Reply) -> Result<(), Garbage> {
+        match procedure {
+            0 => {}
+            1 => self.getattr(args, reply)?,
+            2 => self.setattr(args, caller, reply)?,
+            3 => self.lookup(args, reply)?,
+            4 => self.access(args, reply)?,
+            6 => self.read(args, reply)?,
+            7 => self.write(args, caller, reply)?,
+            8 => self.create(args, caller, reply)?,
+            12 => self.remove(args, caller, reply)?,
+            16 => self.readdir(args, reply, false)?,
+            17 => self.readdir(args, reply, true)?,
+            18..=20 => self.fs_info(procedure, args, reply)?,
+            21 => self.commit(args, reply)?,
+            // READLINK, then MKDIR, SYMLINK, MKNOD, RMDIR, RENAME and LINK,
+            // none of which the DO has, each with its empty failure body
+            _ => {
+                let empty = match procedure {
+                    5 => 1,
+                    14 => 4,
+                    15 => 3,
+                    _ => 2,
+                };
+                reply.u32(NFS3ERR_NOTSUPP);
+                for _ in 0..empty {
+                    reply.bool(false);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&self, export: usize, ino: u64) -> [u8; 24] {
+        let mut handle = [0; 24];
+        handle[..8].copy_from_slice(&self.boot.to_be_bytes());
+        handle[8..16].copy_from_slice(&(export as u64).to_be_bytes());
+        handle[16..].copy_from_slice(&ino.to_be_bytes());
+        handle
+    }
+
+    // What a file handle refers to. Handles from before a restart, and those
+    // of inodes since evicted, are stale, and clients look the path up again.
+    fn target(&self, handle: &[u8]) -> Result<Target<'_>, u32> {
+        let word = |at: usize| u64::from_be_bytes(handle[at..at + 8].try_into().unwrap_or_default());
+        if handle.len() != 24 {
+            return Err(NFS3ERR_BADHANDLE);
+        }
+        let export = word(8) as usize;
+        if word(0) != self.boot || export >= self.exports.len() {
+            return Err(NFS3ERR_STALE);
+        }
+        let ino = word(16);
+        let served = self.exports[export].served.locked();
+        let path = served.fs.inodes.path(ino).ok_or(NFS3ERR_STALE)?;
+        Ok(Target { fsid: export as u64 + 1, served, ino, path })
+    }
+
+    fn getattr(&self, args: &mut Args, reply: &mut Reply) -> Result<(), Garbage> {
+        let handle = args.opaque()?;
+        let attr = self.target(handle).and_then(|mut target| {
+            let attr = target.served.attr(target.ino, &target.path)?;
+            Ok((attr, target.fsid))
+        });
+        match attr {
+            Ok((attr, fsid)) => {
+                reply.u32(NFS3_OK);
+                reply.fattr(&attr, fsid);
+            }
+            Err(status) => reply.u32(status),
+        }
+        Ok(())
+    }
+
+    // Only truncation is kept, which is how clients open files with O_TRUNC,
+    // and modes when the DO keeps them. Owners and times are accepted and
+    // forgotten, as the DO has no place for them.
+    fn setattr(&self, args: &mut Args, caller: Caller, reply: &mut Reply) -> Result<(), Garbage> {
+        let handle = args.opaque()?;
+        let (mode, size) = args.sattr()?;
+        let mut target = match self.target(handle) {
+            Ok(target) => target,
+            Err(status) => {
+                reply.u32(status);
+                reply.wcc_data(None, 0);
+                return Ok(());
+            }
+        };
+        let (ino, path, fsid) = (target.ino, target.path.clone(), target.fsid);
+        let served = &mut target.served;
+        let chmod = match mode {
+            Some(mode) if served.fs.client.has_feature("modes") && !served.fs.fsctl.is(&path) => {
+                match served.refuses_changes(&path) {
+                    Some(status) => Err(status),
+                    None => served.fs.chmod(&path, mode).map(drop).map_err(status),
+                }
+            }
+            _ => Ok(()),
+        };
+        let result = chmod.and_then(|()| served.attr(ino, &path)).and_then(|attr| match size {
+            Some(size) if size != attr.size && !served.fs.fsctl.is(&path) => {
+                if let Some(status) = served.refuses_changes(&path) {
+                    return Err(status);
+                }
+                served.fs.fits(size).map_err(status)?;
+                if size != 0 || attr.kind != FileType::RegularFile {
+                    return Err(NFS3ERR_NOTSUPP);
+                }
+                served.forget(ino, &self.verifier);
+                let created = served.fs.create_file(&path, None);
+                let errno = created.as_ref().err().map(|e| e.errno());
+                served.fs.audit(caller, AuditOp::Create, &path, None, None, errno);
+                created.map(|created| FileAttr { ino, ..created }).map_err(|e| status(e.errno()))
+            }
+            _ => Ok(attr),
+        });
+        match result {
+            Ok(attr) => {
+                reply.u32(NFS3_OK);
+                reply.wcc_data(Some(&attr), fsid);
+            }
+            Err(status) => {
+                reply.u32(status);
+                reply.wcc_data(None, fsid);
+            }
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, args: &mut Args, reply: &mut Reply) -> Result<(), Garbage> {
+        let (handle, name) = (args.opaque()?, args.string()?);
+        let mut target = match self.target(handle) {
+            Ok(target) => target,
+            Err(status) => {
+                reply.u32(status);
+                reply.post_op_attr(None, 0);
+                return Ok(());
+            }
+        };
+        let export = target.fsid as usize - 1;
+        let served = &mut target.served;
+        let path = match name {
+            "." => Some(target.path.clone()),
+            // The root is its own parent, as clients can't see past the export
+            ".." if target.ino == 1 => Some(target.path.clone()),
+            ".." => Some(match target.path.rfind('/') {
+                Some(0) | None => "/".to_string(),
+                Some(at) => target.path[..at].to_string(),
+            }),
+            name if valid_name(name) => served.fs.named_path(target.ino, OsStr::new(name)).ok(),
+            _ => None,
+        };
+        let found = match path.map(|path| (served.fs.path_attr(&path), path)) {
+            Some((Ok(Some(attr)), path)) => {
+                let ino = served.fs.inodes.assign(&path);
+                Ok((ino, FileAttr { ino, ..attr }))
+            }
+            Some((Ok(None), _)) | None => Err(status(libc::ENOENT)),
+            Some((Err(e), _)) => Err(status(e.errno())),
+        };
+        let dir = served.cached_attr(target.ino, &target.path);
+        match found {
+            Ok((ino, attr)) => {
+                reply.u32(NFS3_OK);
+                reply.opaque(&self.handle(export, ino));
+                reply.post_op_attr(Some(&attr), target.fsid);
+                reply.post_op_attr(dir.as_ref(), target.fsid);
+            }
+            Err(status) => {
+                reply.u32(status);
+                reply.post_op_attr(dir.as_ref(), target.fsid);
+            }
+        }
+        Ok(())
+    }
+
+    // Everything is allowed that the mount allows; the DO has no permissions
+    fn access(&self, args: &mut Args, reply: &mut Reply) -> Result<(), Garbage> {
+        let (handle, asked) = (args.opaque()?, args.u32()?);
+        let mut target = match self.target(handle) {
+            Ok(target) => target,
+            Err(status) => {
+                reply.u32(status);
+                reply.post_op_attr(None, 0);
+                return Ok(());
+            }
+        };
+        match target.served.attr(target.ino, &target.path) {
+            Ok(attr) => {
+                let allowed = match target.served.refuses_changes(&target.path) {
+                    Some(_) => !ACCESS_MODIFY,
+                    None => u32::MAX,
+                };
+                reply.u32(NFS3_OK);
+                reply.post_op_attr(Some(&attr), target.fsid);
+                reply.u32(asked & allowed);
+            }
+            Err(status) => {
+                reply.u32(status);
+                reply.post_op_attr(None, target.fsid);
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&self, args: &mut Args, reply: &mut Reply) -> Result<(), Garbage> {
+        let (handle, offset, count) = (args.opaque()?, args.u64()?, args.u32()?);
+        let mut target = match self.target(handle) {
+            Ok(target) => target,
+            Err(status) => {
+                reply.u32(status);
+                reply.post_op_attr(None, 0);
+                return Ok(());
+            }
+        };
+        let (ino, path) = (target.ino, target.path.clone());
+        let served = &mut target.served;
+        let fh = served.handle(ino, &self.verifier);
+        if served.fs.fsctl.is(&path) && !served.fs.fsctl.is_open(fh) {
+            let status = served.fs.control_status();
+            served.fs.fsctl.open(fh, status);
+        }
+        let count = (count as usize).min(MAX_IO_SIZE) as u64;
+        let mut read = Err(NFS3ERR_IO);
+        served.fs.read_data(fh, &path, offset, count, |result| {
+            read = result.map(<[u8]>::to_vec).map_err(|e| status(e.errno()));
+        });
+        let attr = served.cached_attr(ino, &path);
+        match read {
+            Ok(data) => {
+                let eof = match &attr {
+                    Some(attr) => offset + data.len() as u64 >= attr.size,
+                    None => (data.len() as u64) < count,
+                };
+                reply.u32(NFS3_OK);
+                reply.post_op_attr(attr.as_ref(), target.fsid);
+                reply.u32(data.len() as u32);
+                reply.bool(eof);
+                reply.opaque(&data);
+            }
+            Err(status) => {
+                reply.u32(status);
+                reply.post_op_attr(attr.as_ref(), target.fsid);
+            }
+        }
+        Ok(())
+    }
+
+    // Writes are buffered as FUSE's are, until the client commits them,
+    // unless it asks for them to be stable straight away
+    fn write(&self, args: &mut Args, caller: Caller, reply: &mut Reply) -> Result<(), Garbage> {
+        let (handle, offset, _count, stable, data) =
+            (args.opaque()?, args.u64()?, args.u32()?, args.u32()?, args.opaque()?);
+        let mut target = match self.target(handle) {
+            Ok(target) => target,
+            Err(status) => {
+                reply.u32(status);
+                reply.wcc_data(None, 0);
+                return Ok(());
+            }
+        };
+        let (ino, path) = (target.ino, target.path.clone());
+        let served = &mut target.served;
+        let fh = served.handle(ino, &self.verifier);
+        let result = if served.fs.fsctl.is(&path) {
+            let result = served.fs.control(&String::from_utf8_lossy(data));
+            let errno = result.as_ref().err().map(|(errno, _)| *errno);
+            served.fs.fsctl.answer(fh, &result.map_err(|(_, message)| message));
+            errno.map_or(Ok(()), |errno| Err(status(errno)))
+        } else if let Some(status) = served.refuses_changes(&path) {
+            Err(status)
+        } else {
+            let result = served.fs.write_data(fh, &path, offset, data);
+            served.fs.audit(caller, AuditOp::Write, &path, Some(offset), Some(data.len() as u64), result.err());
+            result.map_err(status).and_then(|()| match stable {
+                UNSTABLE => Ok(()),
+                _ => served.fs.sync_path(&path).map_err(|e| status(e.errno())),
+            })
+        };
+        let attr = served.cached_attr(ino, &path);
+        match result {
+            Ok(()) => {
+                METRICS.written(data.len());
+                reply.u32(NFS3_OK);
+                reply.wcc_data(attr.as_ref(), target.fsid);
+                reply.u32(data.len() as u32);
+                // Stable writes are synced, which is as good as a file sync
+                reply.u32(if stable == UNSTABLE { UNSTABLE } else { FILE_SYNC });
+                reply.u64(self.verifier.load(Ordering::SeqCst));
+            }
+            Err(status) => {
+                reply.u32(status);
+                reply.wcc_data(attr.as_ref(), target.fsid);
+            }
+        }
+        Ok(())
+    }
+
+    fn create(&self, args: &mut Args, caller: Caller, reply: &mut Reply) -> Result<(), Garbage> {
+        let (handle, name, how) = (args.opaque()?, args.string()?, args.u32()?);
+        // Unchecked and guarded creates set attributes, exclusive ones a verifier
+        let (mode, size) = match how {
+            2 => {
+                args.fixed(8)?;
+                (None, None)
+            }
+            _ => args.sattr()?,
+        };
+        let mut target = match self.target(handle) {
+            Ok(target) => target,
+            Err(status) => {
+                reply.u32(status);
+                reply.wcc_data(None, 0);
+                return Ok(());
+            }
+        };
+        let export = target.fsid as usize - 1;
+        let path = match valid_name(name) {
+            true => target.served.fs.named_path(target.ino, OsStr::new(name)).ok(),
+            false => None,
+        };
+        let served = &mut target.served;
+        let result = match path {
+            None => Err(NFS3ERR_INVAL),
+            Some(path) => match served.refuses_changes(&path) {
+                Some(status) => Err(status),
+                None => match served.fs.path_attr(&path) {
+                    Err(e) => Err(status(e.errno())),
+                    Ok(Some(_)) if how != 0 => Err(status(libc::EEXIST)),
+                    // An unchecked create leaves an existing file as it is,
+                    // unless asked to truncate it
+                    Ok(Some(attr)) if size != Some(0) => {
+                        let ino = served.fs.inodes.assign(&path);
+                        Ok(FileAttr { ino, ..attr })
+                    }
+                    Ok(_) => {
+                        // Writes buffered for what is being replaced go first
+                        if let Some(ino) = served.fs.inodes.index().locked().get(&path).copied() {
+                            served.forget(ino, &self.verifier);
+                        }
+                        let created = served.fs.create_file(&path, mode);
+                        let errno = created.as_ref().err().map(|e| e.errno());
+                        served.fs.audit(caller, AuditOp::Create, &path, None, None, errno);
+                        created.map_err(|e| status(e.errno()))
+                    }
+                },
+            },
+        };
+        match result {
+            Ok(attr) => {
+                reply.u32(NFS3_OK);
+                reply.bool(true);
+                reply.opaque(&self.handle(export, attr.ino));
+                reply.post_op_attr(Some(&attr), target.fsid);
+                reply.wcc_data(None, target.fsid);
+            }
+            Err(status) => {
+                reply.u32(status);
+                reply.wcc_data(None, target.fsid);
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&self, args: &mut Args, caller: Caller, reply: &mut Reply) -> Result<(), Garbage> {
+        let (handle, name) = (args.opaque()?, args.string()?);
+        let mut target = match self.target(handle) {
+            Ok(target) => target,
+            Err(status) => {
+                reply.u32(status);
+                reply.wcc_data(None, 0);
+                return Ok(());
+            }
+        };
+        let path = match valid_name(name) {
+            true => target.served.fs.named_path(target.ino, OsStr::new(name)).ok(),
+            false => None,
+        };
+        let served = &mut target.served;
+        let result = match path {
+            None => Err(NFS3ERR_INVAL),
+            Some(path) => match served.refuses_changes(&path) {
+                Some(status) => Err(status),
+                None => {
+                    // Whatever was buffered for the file goes out before it is removed
+                    if let Some(ino) = served.fs.inodes.index().locked().get(&path).copied() {
+                        served.forget(ino, &self.verifier);
+                    }
+                    let result = served.fs.unlink_path(&path);
+                    served.fs.audit(caller, AuditOp::Delete, &path, None, None, result.err());
+                    result.map_err(status)
+                }
+            },
+        };
+        reply.u32(result.err().unwrap_or(NFS3_OK));
+        reply.wcc_data(None, target.fsid);
+        Ok(())
+    }
+
+    // Directory positions are the cookies, so a listing picks up where the
+    // last reply ended. With `plus` each entry has its handle, and its
+    // attributes when they are cached.
+    fn readdir(&self, args: &mut Args, reply: &mut Reply, plus: bool) -> Result<(), Garbage> {
+        let (handle, cookie) = (args.opaque()?, args.u64()?);
+        args.fixed(8)?;
+        let count = args.u32()? as usize;
+        let count = match plus {
+            true => args.u32()? as usize,
+            false => count,
+        };
+        let mut target = match self.target(handle) {
+            Ok(target) => target,
+            Err(status) => {
+                reply.u32(status);
+                reply.post_op_attr(None, 0);
+                return Ok(());
+            }
+        };
+        let export = target.fsid as usize - 1;
+        let (ino, path) = (target.ino, target.path.clone());
+
+        // Room for the status, attributes, verifier and end of the list
+        let mut room = count.saturating_sub(128);
+        let mut entries = Vec::new();
+        let mut full = false;
+        let names = target.served.fs.names;
+        let listed = target.served.fs.list_dir(ino, &path, cookie, |ino, position, _, name| {
+            let size = 24 + name.len().next_multiple_of(4) + if plus { 40 } else { 0 };
+            if size > room {
+                full = true;
+                return true;
+            }
+            room -= size;
+            // Escaped names are shown as they were, when they were UTF-8
+            let shown = names.decode(name);
+            entries.push((ino, position, shown.to_str().unwrap_or(name).to_string()));
+            false
+        });
+        let dir = target.served.cached_attr(ino, &path);
+        if let Err(e) = listed {
+            reply.u32(status(e.errno()));
+            reply.post_op_attr(dir.as_ref(), target.fsid);
+            return Ok(());
+        }
+        // Not even one entry fits
+        if full && entries.is_empty() {
+            reply.u32(NFS3ERR_TOOSMALL);
+            reply.post_op_attr(dir.as_ref(), target.fsid);
+            return Ok(());
+        }
+
+        reply.u32(NFS3_OK);
+        reply.post_op_attr(dir.as_ref(), target.fsid);
+        reply.u64(0);
+        for (ino, position, name) in entries {
+            reply.bool(true);
+            reply.u64(ino);
+            reply.opaque(name.as_bytes());
+            reply.u64(position);
+            if plus {
+                let attr = target.served.fs.inodes.path(ino).and_then(|path| target.served.cached_attr(ino, &path));
+                reply.post_op_attr(attr.as_ref(), target.fsid);
+                reply.bool(true);
+                reply.opaque(&self.handle(export, ino));
+            }
+        }
+        reply.bool(false);
+        reply.bool(!full);
+        Ok(())
+    }
+
+    // FSSTAT, FSINFO and PATHCONF. The space is the export's quota, if it has one.
+    fn fs_info(&self, procedure: u32, args: &mut Args, reply: &mut Reply) -> Result<(), Garbage> {
+        let handle = args.opaque()?;
+        let (attr, fsid, space) = match self.target(handle) {
+            Ok(mut target) => {
+                let attr = target.served.attr(target.ino, &target.path).ok();
+                (attr, target.fsid, target.served.fs.space())
+            }
+            Err(status) => {
+                reply.u32(status);
+                reply.post_op_attr(None, 0);
+                return Ok(());
+            }
+        };
+        reply.u32(NFS3_OK);
+        reply.post_op_attr(attr.as_ref(), fsid);
+        match procedure {
+            18 => {
+                for value in [space.bytes, space.bytes_free, space.bytes_free] {
+                    reply.u64(value);
+                }
+                for value in [space.files, space.files_free, space.files_free] {
+                    reply.u64(value);
+                }
+                reply.u32(0);
+            }
+            19 => {
+                let io = MAX_IO_SIZE as u32;
+                for value in [io, io, 4096, io, io, 4096, 64 * 1024] {
+                    reply.u32(value);
+                }
+                reply.u64(i64::MAX as u64);
+                // Modification times are kept to the millisecond
+                reply.time(UNIX_EPOCH + Duration::from_millis(1));
+                // Homogeneous, and times can be set
+                reply.u32(0x08 | 0x10);
+            }
+            _ => {
+                reply.u32(1);
+                reply.u32(255);
+                for value in [true, true, false, true] {
+                    reply.bool(value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn commit(&self, args: &mut Args, reply: &mut Reply) -> Result<(), Garbage> {
+        let handle = args.opaque()?;
+        let mut target = match self.target(handle) {
+            Ok(target) => target,
+            Err(status) => {
+                reply.u32(status);
+                reply.wcc_data(None, 0);
+                return Ok(());
+            }
+        };
+        let path = target.path.clone();
+        let result = target.served.fs.sync_path(&path);
+        let attr = target.served.cached_attr(target.ino, &path);
+        match result {
+            Ok(()) => {
+                reply.u32(NFS3_OK);
+                reply.wcc_data(attr.as_ref(), target.fsid);
+                reply.u64(self.verifier.load(Ordering::SeqCst));
+            }
+            Err(e) => {
+                reply.u32(status(e.errno()));
+                reply.wcc_data(attr.as_ref(), target.fsid);
+            }
+        }
+        Ok(())
+    }
+}
+
+// The caller's credentials, past the call's verifier. Only AUTH_UNIX says
+// who is calling, which audit records name.
+fn credentials(args: &mut Args) -> Result<Caller, Garbage> {
+    let (flavor, body) = (args.u32()?, args.opaque()?);
+    let caller = match flavor {
+        AUTH_UNIX => {
+            let mut credentials = Args::new(body);
+            credentials.u32()?;
+            credentials.opaque()?;
+            Caller { uid: credentials.u32()?, gid: credentials.u32()?, pid: 0 }
+        }
+        _ => Caller { uid: 65534, gid: 65534, pid: 0 },
+    };
+    args.u32()?;
+    args.opaque()?;
+    Ok(caller)
+}
+
+fn mismatch(mut reply: Reply, version: u32) -> Vec<u8> {
+    reply.u32(2);
+    reply.u32(version);
+    reply.u32(version);
+    reply.0
+}
+
+fn valid_name(name: &str) -> bool {
+    !matches!(name, "" | "." | "..") && !name.contains(['/', '\0'])
+}
+
+// One RPC message, put together from its record-marked fragments
+fn read_record(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut record = Vec::new();
+    loop {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header)?;
+        let header = u32::from_be_bytes(header);
+        let len = (header & 0x7fff_ffff) as usize;
+        if record.len() + len > MAX_RECORD {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "RPC record too long"));
+        }
+        let start = record.len();
+        record.resize(start + len, 0);
+        stream.read_exact(&mut record[start..])?;
+        if header & 0x8000_0000 != 0 {
+            return Ok(record);
+        }
+    }
+}
+
+fn write_record(stream: &mut TcpStream, body: &[u8]) -> io::Result<()> {
+    let mut record = Vec::with_capacity(body.len() + 4);
+    record.extend_from_slice(&(0x8000_0000 | body.len() as u32).to_be_bytes());
+    record.extend_from_slice(body);
+    stream.write_all(&record)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Shutdown;
+
+    use super::*;
+
+    // A server exporting nothing, which is enough for everything decided
+    // before a handle's inode is looked up
+    fn server() -> Server {
+        Server { boot: 42, verifier: AtomicU64::new(42), exports: Vec::new() }
+    }
+
+    // A call as uid 1000 of `procedure` with `args` already encoded
+    fn call(rpc: u32, program: u32, version: u32, procedure: u32, args: &[u8]) -> Vec<u8> {
+        let mut credentials = Reply::default();
+        for value in [0, 0, 1000, 1000, 0] {
+            credentials.u32(value);
+        }
+        let mut call = Reply::default();
+        for value in [7, 0, rpc, program, version, procedure, AUTH_UNIX] {
+            call.u32(value);
+        }
+        call.opaque(&credentials.0);
+        call.u32(0);
+        call.opaque(&[]);
+        call.0.extend_from_slice(args);
+        call.0
+    }
+
+    // The words of a reply after its xid and that it is one
+    fn words(reply: &[u8]) -> Vec<u32> {
+        reply[8..].chunks(4).map(|word| u32::from_be_bytes(word.try_into().unwrap())).collect()
+    }
+
+    #[test]
+    fn xdr_round_trips_and_refuses_short_input() {
+        let mut reply = Reply::default();
+        reply.u32(7);
+        reply.opaque(b"hello");
+        reply.u64(1 << 40);
+        reply.bool(true);
+        assert_eq!(reply.0.len(), 4 + 4 + 8 + 8 + 4);
+
+        let mut args = Args::new(&reply.0);
+        assert_eq!(args.u32().ok(), Some(7));
+        assert_eq!(args.string().ok(), Some("hello"));
+        assert_eq!(args.u64().ok(), Some(1 << 40));
+        assert_eq!(args.bool().ok(), Some(true));
+        assert!(args.u32().is_err());
+
+        // A length running past the end is garbage, not a panic
+        let mut args = Args::new(&[0, 0, 0, 9, b'a', b'b', b'c', b'd']);
+        assert!(args.opaque().is_err());
+
+        // Mode and size kept, uid and gid skipped, and both times set by the client
+        let mut sattr = Reply::default();
+        for value in [1, 0o644, 0, 0, 1] {
+            sattr.u32(value);
+        }
+        sattr.u64(0);
+        sattr.u32(2);
+        sattr.u64(5);
+        sattr.u32(1);
+        assert_eq!(Args::new(&sattr.0).sattr().ok(), Some((Some(0o644), Some(0))));
+
+        assert_eq!(status(libc::ENOENT), 2);
+        assert_eq!(status(libc::EAGAIN), NFS3ERR_JUKEBOX);
+        assert_eq!(status(libc::ECONNRESET), NFS3ERR_IO);
+        assert!(!valid_name("..") && !valid_name("a/b") && valid_name("a.txt"));
+    }
+
+    #[test]
+    fn calls_are_answered_by_how_far_they_decode() {
+        let server = server();
+        let mut handle = Reply::default();
+        handle.opaque(&[0; 24]);
+
+        // Accepted and successful, with an empty verifier
+        assert_eq!(words(&server.call(&call(2, NFS_PROGRAM, 3, 0, &[])).unwrap()), [0, 0, 0, 0]);
+        // Denied for RPC other than version 2, with the one spoken
+        assert_eq!(words(&server.call(&call(3, NFS_PROGRAM, 3, 0, &[])).unwrap()), [1, 0, 2, 2]);
+        // No such program, version or procedure
+        assert_eq!(words(&server.call(&call(2, 100000, 2, 0, &[])).unwrap()), [0, 0, 0, 1]);
+        assert_eq!(words(&server.call(&call(2, NFS_PROGRAM, 4, 0, &[])).unwrap()), [0, 0, 0, 2, 3, 3]);
+        assert_eq!(words(&server.call(&call(2, MOUNT_PROGRAM, 1, 0, &[])).unwrap()), [0, 0, 0, 2, 3, 3]);
+        assert_eq!(words(&server.call(&call(2, NFS_PROGRAM, 3, 22, &[])).unwrap()), [0, 0, 0, 3]);
+
+        // Arguments cut short anywhere are garbage, with nothing of the
+        // procedure's results left in the reply
+        for procedure in [1, 2, 3, 4, 6, 7, 8, 12, 16, 17, 18, 21] {
+            for cut in [0, 2, 4, 20] {
+                let reply = server.call(&call(2, NFS_PROGRAM, 3, procedure, &handle.0[..cut])).unwrap();
+                assert_eq!(words(&reply), [0, 0, 0, 4], "procedure {} cut at {}", procedure, cut);
+            }
+        }
+        let mut unterminated = Reply::default();
+        unterminated.u32(u32::MAX);
+        let reply = server.call(&call(2, MOUNT_PROGRAM, 3, 1, &unterminated.0)).unwrap();
+        assert_eq!(words(&reply), [0, 0, 0, 4]);
+        let mut not_utf8 = Reply::default();
+        not_utf8.opaque(&[0xff, 0xfe]);
+        assert_eq!(words(&server.call(&call(2, MOUNT_PROGRAM, 3, 1, &not_utf8.0)).unwrap()), [0, 0, 0, 4]);
+
+        // What isn't a call, or stops before its credentials, isn't answered
+        let whole = call(2, NFS_PROGRAM, 3, 0, &[]);
+        for cut in [0, 3, 4, 8, 24, whole.len() - 1] {
+            assert_eq!(server.call(&whole[..cut]), None, "cut at {}", cut);
+        }
+        let mut reply = whole.clone();
+        reply[7] = 1;
+        assert_eq!(server.call(&reply), None);
+    }
+
+    #[test]
+    fn handles_not_made_by_this_server_are_bad_or_stale() {
+        let server = server();
+        let getattr = |handle: &[u8]| {
+            let mut args = Reply::default();
+            args.opaque(handle);
+            words(&server.call(&call(2, NFS_PROGRAM, 3, 1, &args.0)).unwrap())
+        };
+        assert_eq!(getattr(&[0; 8]), [0, 0, 0, 0, NFS3ERR_BADHANDLE]);
+        assert_eq!(getattr(&[0; 32]), [0, 0, 0, 0, NFS3ERR_BADHANDLE]);
+        // From before a restart, or of an export that isn't there
+        assert_eq!(getattr(&server.handle(0, 1)[..]), [0, 0, 0, 0, NFS3ERR_STALE]);
+        let mut restarted = server.handle(0, 1);
+        restarted[7] ^= 1;
+        assert_eq!(getattr(&restarted), [0, 0, 0, 0, NFS3ERR_STALE]);
+        assert!(server.target(&server.handle(3, 1)).is_err());
+    }
+
+    #[test]
+    fn credentials_name_only_unix_callers() {
+        let mut unix = Reply::default();
+        unix.u32(AUTH_UNIX);
+        let mut body = Reply::default();
+        for value in [0, 0, 1000, 100, 0] {
+            body.u32(value);
+        }
+        unix.opaque(&body.0);
+        unix.u32(0);
+        unix.opaque(&[]);
+        let caller = credentials(&mut Args::new(&unix.0)).ok().unwrap();
+        assert_eq!((caller.uid, caller.gid), (1000, 100));
+
+        let mut none = Reply::default();
+        for value in [0, 0, 0, 0] {
+            none.u32(value);
+        }
+        let caller = credentials(&mut Args::new(&none.0)).ok().unwrap();
+        assert_eq!((caller.uid, caller.gid), (65534, 65534));
+
+        // A body too short for its uid and gid, or no verifier after it
+        let mut short = Reply::default();
+        short.u32(AUTH_UNIX);
+        short.opaque(&body.0[..12]);
+        short.u32(0);
+        short.opaque(&[]);
+        assert!(credentials(&mut Args::new(&short.0)).is_err());
+        assert!(credentials(&mut Args::new(&unix.0[..unix.0.len() - 4])).is_err());
+    }
+
+    #[test]
+    fn records_are_put_together_from_fragments_up_to_a_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        for (last, fragment) in [(false, &b"frag"[..]), (false, b""), (true, b"ments")] {
+            let header = (last as u32) << 31 | fragment.len() as u32;
+            client.write_all(&header.to_be_bytes()).unwrap();
+            client.write_all(fragment).unwrap();
+        }
+        assert_eq!(read_record(&mut server).unwrap(), b"fragments");
+        write_record(&mut client, b"whole").unwrap();
+        assert_eq!(read_record(&mut server).unwrap(), b"whole");
+
+        // Fragments adding up past the limit are refused before the one
+        // going over is read
+        let header = MAX_RECORD as u32 / 2 + 1;
+        let writer = thread::spawn(move || {
+            client.write_all(&header.to_be_bytes()).unwrap();
+            client.write_all(&vec![0; header as usize]).unwrap();
+            client.write_all(&header.to_be_bytes()).unwrap();
+        });
+        assert_eq!(read_record(&mut server).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        writer.join().unwrap();
+
+        // A record cut short is the connection ending
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        client.write_all(&(0x8000_0000u32 | 8).to_be_bytes()).unwrap();
+        client.write_all(b"cut").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(read_record(&mut server).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
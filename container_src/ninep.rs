@@ -0,0 +1,889 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fuser::{FileAttr, FileType};
+use tracing::{debug, info, warn};
+
+use crate::audit::{AuditOp, Caller};
+use crate::error::Locked;
+use crate::metrics::METRICS;
+use crate::unsupported::Policy;
+use crate::{join, RemoteFS, MAX_IO_SIZE};
+
+pub const DEFAULT_9P_LISTEN: &str = "127.0.0.1:5640";
+
+const VERSION: &str = "9P2000.L";
+
+// Room taken by a read or write's header, so a message with a full
+// MAX_IO_SIZE of data still fits in the largest message agreed to
+const IOHDRSZ: u32 = 24;
+const MAX_MESSAGE: u32 = MAX_IO_SIZE as u32 + IOHDRSZ;
+
+const NOFID: u32 = u32::MAX;
+
+// Requests, each answered by the message numbered one above it or by RLERROR
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLOCK: u8 = 52;
+const TGETLOCK: u8 = 54;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+const QTDIR: u8 = 0x80;
+
+// What Tgetattr answers with: mode through blocks, and the times
+const GETATTR_BASIC: u64 = 0x7ff;
+// The Tsetattr fields the DO has a place for, the mode only with `modes`
+const SETATTR_MODE: u32 = 0x1;
+const SETATTR_SIZE: u32 = 0x8;
+
+// Flags of Tlopen and Tlcreate that matter here, as Linux numbers them
+const DOTL_TRUNC: u32 = 0o1000;
+const DOTL_EXCL: u32 = 0o200;
+const AT_REMOVEDIR: u32 = 0x200;
+
+const LOCK_SUCCESS: u8 = 0;
+const LOCK_TYPE_UNLCK: u8 = 2;
+
+// The filesystem type statfs reports, the one Linux's own 9p client has
+const V9FS_MAGIC: u32 = 0x0102_1997;
+
+// Clients are trusted to say who they are, so only those in the container
+// may connect, as with NFS
+pub use crate::nfs::parse_listen;
+
+// 9P2000.L decoding of a request's fields, little-endian. A request cut
+// short is invalid.
+struct Args<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Args<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn fixed(&mut self, len: usize) -> Result<&'a [u8], i32> {
+        if self.data.len() < len {
+            return Err(libc::EINVAL);
+        }
+        let (value, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(value)
+    }
+
+    fn u8(&mut self) -> Result<u8, i32> {
+        Ok(self.fixed(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, i32> {
+        Ok(u16::from_le_bytes(self.fixed(2)?.try_into().map_err(|_| libc::EINVAL)?))
+    }
+
+    fn u32(&mut self) -> Result<u32, i32> {
+        Ok(u32::from_le_bytes(self.fixed(4)?.try_into().map_err(|_| libc::EINVAL)?))
+    }
+
+    fn u64(&mut self) -> Result<u64, i32> {
+        Ok(u64::from_le_bytes(self.fixed(8)?.try_into().map_err(|_| libc::EINVAL)?))
+    }
+
+    fn string(&mut self) -> Result<&'a str, i32> {
+        let len = self.u16()? as usize;
+        std::str::from_utf8(self.fixed(len)?).map_err(|_| libc::EINVAL)
+    }
+
+    // A name in a directory, which can't be a path of its own
+    fn name(&mut self) -> Result<&'a str, i32> {
+        match self.string()? {
+            "" | "." | ".." => Err(libc::EINVAL),
+            name if name.contains(['/', '\0']) => Err(libc::EINVAL),
+            name => Ok(name),
+        }
+    }
+}
+
+// 9P2000.L encoding of a reply's fields
+#[derive(Default)]
+struct Reply(Vec<u8>);
+
+impl Reply {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u16(value.len() as u16);
+        self.0.extend_from_slice(value.as_bytes());
+    }
+
+    fn time(&mut self, time: SystemTime) {
+        let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.u64(since.as_secs());
+        self.u64(since.subsec_nanos() as u64);
+    }
+
+    // A file's identity: its type, a version that is never tracked, and its inode
+    fn qid(&mut self, kind: FileType, ino: u64) {
+        self.u8(if kind == FileType::Directory { QTDIR } else { 0 });
+        self.u32(0);
+        self.u64(ino);
+    }
+
+    fn attr(&mut self, attr: &FileAttr) {
+        self.u64(GETATTR_BASIC);
+        self.qid(attr.kind, attr.ino);
+        self.u32(mode(attr.kind) | attr.perm as u32);
+        self.u32(attr.uid);
+        self.u32(attr.gid);
+        self.u64(attr.nlink as u64);
+        self.u64(attr.rdev as u64);
+        self.u64(attr.size);
+        self.u64(attr.blksize as u64);
+        self.u64(attr.blocks);
+        self.time(attr.atime);
+        self.time(attr.mtime);
+        self.time(attr.ctime);
+        // Birth time, generation and data version aren't asked for
+        self.time(attr.crtime);
+        self.u64(0);
+        self.u64(0);
+    }
+}
+
+fn mode(kind: FileType) -> u32 {
+    match kind {
+        FileType::NamedPipe => libc::S_IFIFO,
+        FileType::CharDevice => libc::S_IFCHR,
+        FileType::BlockDevice => libc::S_IFBLK,
+        FileType::Directory => libc::S_IFDIR,
+        FileType::RegularFile => libc::S_IFREG,
+        FileType::Symlink => libc::S_IFLNK,
+        FileType::Socket => libc::S_IFSOCK,
+    }
+}
+
+// The directory entry type readdir gives for a file
+fn dirent_type(kind: FileType) -> u8 {
+    (mode(kind) >> 12) as u8
+}
+
+// One mount's filesystem, attached to by naming its mount point
+struct Export {
+    path: String,
+    fs: Mutex<RemoteFS>,
+}
+
+// The 9P2000.L server every mount is served through, instead of being
+// mounted with FUSE
+pub struct Server {
+    exports: Vec<Export>,
+}
+
+// What a client's fid refers to. Each holds a lookup reference to its inode,
+// as the kernel does to those it knows of, so it is never evicted from under
+// the fid.
+struct Fid {
+    export: usize,
+    ino: u64,
+    // The handle reads and writes go through, once opened
+    open: Option<u64>,
+    caller: Caller,
+}
+
+// Serves `filesystems` on `address`, each attached to by its mount point
+pub fn spawn(address: &str, filesystems: Vec<RemoteFS>) -> io::Result<Arc<Server>> {
+    let listener = TcpListener::bind(address)?;
+    let mut exports = Vec::new();
+    for mut fs in filesystems {
+        fs.started(MAX_IO_SIZE as u32, MAX_IO_SIZE as u32);
+        exports.push(Export { path: fs.mount_point.clone(), fs: Mutex::new(fs) });
+    }
+    let server = Arc::new(Server { exports });
+    let paths: Vec<_> = server.exports.iter().map(|export| export.path.as_str()).collect();
+    info!("Serving 9P2000.L on {}, exporting {}", listener.local_addr()?, paths.join(", "));
+
+    let accepting = server.clone();
+    thread::spawn(move || loop {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                let server = accepting.clone();
+                thread::spawn(move || {
+                    debug!("9p client {} connected", peer);
+                    let mut session = Session { server: &server, msize: MAX_MESSAGE, fids: HashMap::new() };
+                    if let Err(e) = session.serve(stream) {
+                        debug!("9p client {} went away: {}", peer, e);
+                    }
+                    session.clunk_all();
+                });
+            }
+            Err(e) => warn!("Failed to accept a 9p client: {}", e),
+        }
+    });
+    Ok(server)
+}
+
+impl Server {
+    // Sends what clients wrote. The daemon is draining by now, so nothing
+    // more is opened, created or removed.
+    pub fn stop(&self) {
+        for export in &self.exports {
+            export.fs.locked().stopped();
+        }
+    }
+}
+
+// One client connection and the fids it has made. Requests are answered in
+// the order they come, so by the time a flush is read what it would cancel
+// has been answered.
+struct Session<'a> {
+    server: &'a Server,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Session<'_> {
+    fn serve(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        loop {
+            let mut header = [0; 7];
+            stream.read_exact(&mut header)?;
+            let size = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+            if !(7..=self.msize.max(MAX_MESSAGE)).contains(&size) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "9p message of a bad size"));
+            }
+            let (kind, tag) = (header[4], u16::from_le_bytes([header[5], header[6]]));
+            let mut body = vec![0; size as usize - 7];
+            stream.read_exact(&mut body)?;
+
+            let mut reply = Reply::default();
+            let kind = match self.call(kind, &mut Args::new(&body), &mut reply) {
+                Ok(()) => kind + 1,
+                Err(errno) => {
+                    reply = Reply::default();
+                    reply.u32(errno as u32);
+                    RLERROR
+                }
+            };
+            let mut message = Vec::with_capacity(reply.0.len() + 7);
+            message.extend_from_slice(&(reply.0.len() as u32 + 7).to_le_bytes());
+            message.push(kind);
+            message.extend_from_slice(&tag.to_le_bytes());
+            message.extend_from_slice(&reply.0);
+            stream.write_all(&message)?;
+        }
+    }
+
+    fn call(&mut self, kind: u8, args: &mut Args, reply: &mut Reply) -> Result<(), i32> {
+        match kind {
+            TVERSION => self.version(args, reply),
+            TATTACH => self.attach(args, reply),
+            TFLUSH => args.u16().map(drop),
+            TWALK => self.walk(args, reply),
+            TGETATTR => self.getattr(args, reply),
+            TSETATTR => self.setattr(args),
+            TLOPEN => self.lopen(args, reply),
+            TLCREATE => self.lcreate(args, reply),
+            TREAD => self.read(args, reply),
+            TWRITE => self.write(args, reply),
+            TREADDIR => self.readdir(args, reply),
+            TFSYNC => self.fsync(args),
+            TCLUNK => self.clunk(args.u32()?),
+            TREMOVE => self.remove(args),
+            TUNLINKAT => self.unlinkat(args),
+            TSTATFS => self.statfs(args, reply),
+            TLOCK | TGETLOCK => self.lock(kind, args, reply),
+            // Authentication, extended attributes, and directories, links,
+            // renames and special files, none of which the DO has. Neither
+            // are the messages of 9P2000 before .L.
+            _ => Err(libc::EOPNOTSUPP),
+        }
+    }
+
+    // Only 9P2000.L is spoken. Agreeing on a version starts the session over.
+    fn version(&mut self, args: &mut Args, reply: &mut Reply) -> Result<(), i32> {
+        let (msize, version) = (args.u32()?, args.string()?);
+        self.clunk_all();
+        self.msize = msize.min(MAX_MESSAGE);
+        reply.u32(self.msize);
+        reply.string(if version.starts_with(VERSION) { VERSION } else { "unknown" });
+        Ok(())
+    }
+
+    // The attach name is a mount point, or empty for the first mount
+    fn attach(&mut self, args: &mut Args, reply: &mut Reply) -> Result<(), i32> {
+        let (fid, _afid, _uname, aname, uid) = (args.u32()?, args.u32()?, args.string()?, args.string()?, args.u32()?);
+        if self.fids.contains_key(&fid) {
+            return Err(libc::EBADF);
+        }
+        let aname = match aname.trim_end_matches('/') {
+            "" if aname.is_empty() => self.server.exports.first().map_or("", |export| export.path.as_str()),
+            "" => "/",
+            aname => aname,
+        };
+        let export = self.server.exports.iter().position(|export| export.path == aname).ok_or(libc::ENOENT)?;
+        let uid = if uid == NOFID { 65534 } else { uid };
+        let mut fs = self.fs(export);
+        let root = fs.inodes.path(1).ok_or(libc::ENOENT)?;
+        let attr = fs.path_attr(&root).map_err(|e| e.errno())?.ok_or(libc::ENOENT)?;
+        let ino = fs.inodes.lookup(&root);
+        drop(fs);
+        info!("9p client attached to {}", aname);
+        self.fids.insert(fid, Fid { export, ino, open: None, caller: Caller { uid, gid: 65534, pid: 0 } });
+        reply.qid(attr.kind, ino);
+        Ok(())
+    }
+
+    // Walks from `fid` one name at a time. Only a walk that gets to the end
+    // makes `newfid`; one that stops part way says how far it got.
+    fn walk(&mut self, args: &mut Args, reply: &mut Reply) -> Result<(), i32> {
+        let (fid, newfid, count) = (args.u32()?, args.u32()?, args.u16()?);
+        let names = (0..count).map(|_| args.string()).collect::<Result<Vec<_>, _>>()?;
+        let from = self.fids.get(&fid).ok_or(libc::EBADF)?;
+        if newfid != fid && self.fids.contains_key(&newfid) {
+            return Err(libc::EBADF);
+        }
+        if newfid == fid && from.open.is_some() {
+            return Err(libc::EBUSY);
+        }
+        let (export, caller) = (from.export, from.caller);
+        let mut fs = self.fs(export);
+        let mut path = fs.inodes.path(from.ino).ok_or(libc::ESTALE)?;
+        let root = fs.inodes.path(1).ok_or(libc::ESTALE)?;
+
+        let mut walked = Vec::new();
+        let mut failure = None;
+        for name in names {
+            let next = match name {
+                // The root is its own parent, as clients can't see past the mount
+                ".." if path == root => Some(root.clone()),
+                ".." => Some(match path.rfind('/') {
+                    Some(0) | None => "/".to_string(),
+                    Some(at) => path[..at].to_string(),
+                }),
+                "" | "." => None,
+                name if name.contains(['/', '\0']) => None,
+                name => fs.names.encode(OsStr::new(name)).ok().map(|name| join(&path, &name)),
+            };
+            let Some(next) = next else {
+                failure = Some(libc::ENOENT);
+                break;
+            };
+            match fs.path_attr(&next) {
+                Ok(Some(attr)) => {
+                    walked.push((fs.inodes.lookup(&next), attr.kind));
+                    path = next;
+                }
+                Ok(None) => {
+                    failure = Some(libc::ENOENT);
+                    break;
+                }
+                Err(e) => {
+                    failure = Some(e.errno());
+                    break;
+                }
+            }
+        }
+        if let Some(errno) = failure {
+            for (ino, _) in &walked {
+                fs.inodes.forget(*ino, 1);
+            }
+            if walked.is_empty() {
+                return Err(errno);
+            }
+        } else {
+            // The new fid holds the last inode walked to, and the rest are let go
+            let ino = match walked.last() {
+                Some((ino, _)) => *ino,
+                None => fs.inodes.lookup(&path),
+            };
+            for (walked, _) in walked.iter().rev().skip(1) {
+                fs.inodes.forget(*walked, 1);
+            }
+            drop(fs);
+            if newfid == fid {
+                self.clunk(fid)?;
+            }
+            self.fids.insert(newfid, Fid { export, ino, open: None, caller });
+        }
+        reply.u16(walked.len() as u16);
+        for (ino, kind) in walked {
+            reply.qid(kind, ino);
+        }
+        Ok(())
+    }
+
+    fn getattr(&mut self, args: &mut Args, reply: &mut Reply) -> Result<(), i32> {
+        let (fid, _mask) = (args.u32()?, args.u64()?);
+        let (mut fs, ino, path) = self.target(fid)?;
+        let attr = fs.buffered_attr(&path).map_err(|e| e.errno())?.ok_or(libc::ENOENT)?;
+        reply.attr(&FileAttr { ino, ..attr });
+        Ok(())
+    }
+
+    // Only truncation is kept, which is how clients open files with O_TRUNC,
+    // and modes when the DO keeps them. Owners and times are accepted and
+    // forgotten, as the DO has no place for them.
+    fn setattr(&mut self, args: &mut Args) -> Result<(), i32> {
+        let (fid, valid) = (args.u32()?, args.u32()?);
+        let (mode, _uid, _gid, size) = (args.u32()?, args.u32()?, args.u32()?, args.u64()?);
+        let caller = self.fids.get(&fid).ok_or(libc::EBADF)?.caller;
+        let (mut fs, _, path) = self.target(fid)?;
+        if valid & SETATTR_MODE != 0 && fs.client.has_feature("modes") && !fs.fsctl.is(&path) {
+            fs.chmod(&path, mode)?;
+        }
+        if valid & SETATTR_SIZE == 0 || fs.fsctl.is(&path) {
+            return Ok(());
+        }
+        let attr = fs.buffered_attr(&path).map_err(|e| e.errno())?.ok_or(libc::ENOENT)?;
+        if size == attr.size {
+            return Ok(());
+        }
+        fs.refuses_changes(&path)?;
+        fs.fits(size)?;
+        if size != 0 || attr.kind != FileType::RegularFile {
+            return Err(libc::EOPNOTSUPP);
+        }
+        truncate(&mut fs, caller, &path)
+    }
+
+    fn lopen(&mut self, args: &mut Args, reply: &mut Reply) -> Result<(), i32> {
+        let (fid, flags) = (args.u32()?, args.u32()?);
+        let caller = match self.fids.get(&fid) {
+            Some(opened) if opened.open.is_some() => return Err(libc::EBADF),
+            Some(opened) => opened.caller,
+            None => return Err(libc::EBADF),
+        };
+        let (mut fs, ino, path) = self.target(fid)?;
+        if fs.draining() {
+            return Err(libc::ESHUTDOWN);
+        }
+        let fh = fs.next_handle();
+        let kind = if fs.fsctl.is(&path) {
+            let status = fs.control_status();
+            fs.fsctl.open(fh, status);
+            FileType::RegularFile
+        } else {
+            let attr = fs.path_attr(&path).map_err(|e| e.errno())?.ok_or(libc::ENOENT)?;
+            if flags & libc::O_ACCMODE as u32 != libc::O_RDONLY as u32 {
+                fs.refuses_changes(&path)?;
+                if attr.kind == FileType::Directory {
+                    return Err(libc::EISDIR);
+                }
+                if flags & DOTL_TRUNC != 0 && attr.size != 0 {
+                    truncate(&mut fs, caller, &path)?;
+                }
+            }
+            attr.kind
+        };
+        let iounit = self.msize - IOHDRSZ;
+        drop(fs);
+        if let Some(opened) = self.fids.get_mut(&fid) {
+            opened.open = Some(fh);
+        }
+        reply.qid(kind, ino);
+        reply.u32(iounit);
+        Ok(())
+    }
+
+    // Creates a file in the directory `fid` is at, and opens `fid` on it
+    fn lcreate(&mut self, args: &mut Args, reply: &mut Reply) -> Result<(), i32> {
+        let (fid, name, flags, mode, gid) = (args.u32()?, args.name()?, args.u32()?, args.u32()?, args.u32()?);
+        let caller = match self.fids.get(&fid) {
+            Some(dir) if dir.open.is_some() => return Err(libc::EBADF),
+            Some(dir) => Caller { gid, ..dir.caller },
+            None => return Err(libc::EBADF),
+        };
+        let (mut fs, dir, _) = self.target(fid)?;
+        if fs.draining() {
+            return Err(libc::ESHUTDOWN);
+        }
+        let path = fs.named_path(dir, OsStr::new(name))?;
+        fs.refuses_changes(&path)?;
+        if flags & DOTL_EXCL != 0 && fs.path_attr(&path).map_err(|e| e.errno())?.is_some() {
+            return Err(libc::EEXIST);
+        }
+        // Writes buffered for what is being replaced go first
+        fs.sync_path(&path).map_err(|e| e.errno())?;
+        let created = fs.create_file(&path, Some(mode));
+        fs.audit(caller, AuditOp::Create, &path, None, None, created.as_ref().err().map(|e| e.errno()));
+        created.map_err(|e| e.errno())?;
+        let ino = fs.inodes.lookup(&path);
+        fs.inodes.forget(dir, 1);
+        let fh = fs.next_handle();
+        drop(fs);
+        if let Some(created) = self.fids.get_mut(&fid) {
+            created.ino = ino;
+            created.open = Some(fh);
+            created.caller = caller;
+        }
+        reply.qid(FileType::RegularFile, ino);
+        reply.u32(self.msize - IOHDRSZ);
+        Ok(())
+    }
+
+    fn read(&mut self, args: &mut Args, reply: &mut Reply) -> Result<(), i32> {
+        let (fid, offset, count) = (args.u32()?, args.u64()?, args.u32()?);
+        let fh = self.opened(fid)?;
+        let count = count.min(self.msize - IOHDRSZ) as u64;
+        let (mut fs, _, path) = self.target(fid)?;
+        let mut read = Err(libc::EIO);
+        fs.read_data(fh, &path, offset, count, |result| match result {
+            Ok(data) => {
+                reply.u32(data.len() as u32);
+                reply.0.extend_from_slice(data);
+                read = Ok(());
+            }
+            Err(e) => read = Err(e.errno()),
+        });
+        read
+    }
+
+    fn write(&mut self, args: &mut Args, reply: &mut Reply) -> Result<(), i32> {
+        let (fid, offset, count) = (args.u32()?, args.u64()?, args.u32()?);
+        let data = args.fixed(count as usize)?;
+        let fh = self.opened(fid)?;
+        let caller = self.fids.get(&fid).ok_or(libc::EBADF)?.caller;
+        let (mut fs, _, path) = self.target(fid)?;
+        if fs.fsctl.is_open(fh) {
+            let result = fs.control(&String::from_utf8_lossy(data));
+            let errno = result.as_ref().err().map(|(errno, _)| *errno);
+            fs.fsctl.answer(fh, &result.map_err(|(_, message)| message));
+            errno.map_or(Ok(()), Err)?;
+        } else {
+            fs.refuses_changes(&path)?;
+            let result = fs.write_data(fh, &path, offset, data);
+            fs.audit(caller, AuditOp::Write, &path, Some(offset), Some(data.len() as u64), result.err());
+            result?;
+            METRICS.written(data.len());
+        }
+        reply.u32(data.len() as u32);
+        Ok(())
+    }
+
+    // Directory positions are the offsets, so a listing picks up where the
+    // last reply ended
+    fn readdir(&mut self, args: &mut Args, reply: &mut Reply) -> Result<(), i32> {
+        let (fid, offset, count) = (args.u32()?, args.u64()?, args.u32()?);
+        self.opened(fid)?;
+        let mut room = count.min(self.msize - IOHDRSZ) as usize;
+        let (mut fs, ino, path) = self.target(fid)?;
+        let mut entries = Reply::default();
+        let names = fs.names;
+        fs.list_dir(ino, &path, offset, |ino, position, kind, name| {
+            // Escaped names are shown as they were, when they were UTF-8
+            let shown = names.decode(name);
+            let name = shown.to_str().unwrap_or(name);
+            let size = 13 + 8 + 1 + 2 + name.len();
+            if size > room {
+                return true;
+            }
+            room -= size;
+            entries.qid(kind, ino);
+            entries.u64(position);
+            entries.u8(dirent_type(kind));
+            entries.string(name);
+            false
+        })
+        .map_err(|e| e.errno())?;
+        reply.u32(entries.0.len() as u32);
+        reply.0.extend_from_slice(&entries.0);
+        Ok(())
+    }
+
+    fn fsync(&mut self, args: &mut Args) -> Result<(), i32> {
+        let fid = args.u32()?;
+        let (mut fs, _, path) = self.target(fid)?;
+        fs.sync_path(&path).map_err(|e| e.errno())
+    }
+
+    // The fid is gone whether or not what it wrote could be sent
+    fn clunk(&mut self, fid: u32) -> Result<(), i32> {
+        let Fid { export, ino, open, .. } = self.fids.remove(&fid).ok_or(libc::EBADF)?;
+        let mut fs = self.fs(export);
+        let released = match open {
+            Some(fh) => fs.release_handle(fh).map_err(|e| e.errno()),
+            None => Ok(()),
+        };
+        fs.inodes.forget(ino, 1);
+        released
+    }
+
+    fn clunk_all(&mut self) {
+        let fids: Vec<u32> = self.fids.keys().copied().collect();
+        for fid in fids {
+            if let Err(errno) = self.clunk(fid) {
+                warn!("Failed to flush 9p writes when fid {} went away: errno {}", fid, errno);
+            }
+        }
+    }
+
+    // Removes the file `fid` is at, and clunks it either way
+    fn remove(&mut self, args: &mut Args) -> Result<(), i32> {
+        let fid = args.u32()?;
+        let caller = self.fids.get(&fid).ok_or(libc::EBADF)?.caller;
+        let removed = self.target(fid).and_then(|(mut fs, _, path)| unlink(&mut fs, caller, &path));
+        let clunked = self.clunk(fid);
+        removed.and(clunked)
+    }
+
+    // Directories can't be removed, as the DO has none
+    fn unlinkat(&mut self, args: &mut Args) -> Result<(), i32> {
+        let (fid, name, flags) = (args.u32()?, args.name()?, args.u32()?);
+        if flags & AT_REMOVEDIR != 0 {
+            return Err(libc::EOPNOTSUPP);
+        }
+        let caller = self.fids.get(&fid).ok_or(libc::EBADF)?.caller;
+        let (mut fs, dir, _) = self.target(fid)?;
+        let path = fs.named_path(dir, OsStr::new(name))?;
+        unlink(&mut fs, caller, &path)
+    }
+
+    // The room under the export's quota, if it has one
+    fn statfs(&mut self, args: &mut Args, reply: &mut Reply) -> Result<(), i32> {
+        let fid = args.u32()?;
+        let export = self.fids.get(&fid).ok_or(libc::EBADF)?.export;
+        let space = self.fs(export).space();
+        let (blocks, free) = (space.bytes / 4096, space.bytes_free / 4096);
+        reply.u32(V9FS_MAGIC);
+        reply.u32(4096);
+        for value in [blocks, free, free, space.files, space.files_free, export as u64 + 1] {
+            reply.u64(value);
+        }
+        reply.u32(255);
+        Ok(())
+    }
+
+    // Locks go by the mount's lock policy, as through FUSE. No kernel keeps
+    // them for a 9p client, so emulated ones are granted as if they succeed.
+    fn lock(&mut self, kind: u8, args: &mut Args, reply: &mut Reply) -> Result<(), i32> {
+        let fid = args.u32()?;
+        let (fs, _, _) = self.target(fid)?;
+        if fs.unsupported.locks == Policy::Enosys {
+            return Err(libc::ENOSYS);
+        }
+        drop(fs);
+        if kind == TLOCK {
+            reply.u8(LOCK_SUCCESS);
+            return Ok(());
+        }
+        let (_type, start, length, proc_id, client_id) =
+            (args.u8()?, args.u64()?, args.u64()?, args.u32()?, args.string()?);
+        reply.u8(LOCK_TYPE_UNLCK);
+        reply.u64(start);
+        reply.u64(length);
+        reply.u32(proc_id);
+        reply.string(client_id);
+        Ok(())
+    }
+
+    fn fs(&self, export: usize) -> MutexGuard<'_, RemoteFS> {
+        self.server.exports[export].fs.locked()
+    }
+
+    // The filesystem `fid` is in, locked, its inode and the inode's path
+    fn target(&self, fid: u32) -> Result<(MutexGuard<'_, RemoteFS>, u64, String), i32> {
+        let target = self.fids.get(&fid).ok_or(libc::EBADF)?;
+        let fs = self.fs(target.export);
+        let path = fs.inodes.path(target.ino).ok_or(libc::ESTALE)?;
+        Ok((fs, target.ino, path))
+    }
+
+    // The handle `fid` was opened with
+    fn opened(&self, fid: u32) -> Result<u64, i32> {
+        self.fids.get(&fid).and_then(|opened| opened.open).ok_or(libc::EBADF)
+    }
+}
+
+// Empties the file at `path`, after sending what was buffered for it so it
+// doesn't land on the emptied file later
+fn truncate(fs: &mut RemoteFS, caller: Caller, path: &str) -> Result<(), i32> {
+    fs.sync_path(path).map_err(|e| e.errno())?;
+    let created = fs.create_file(path, None);
+    fs.audit(caller, AuditOp::Create, path, None, None, created.as_ref().err().map(|e| e.errno()));
+    created.map(drop).map_err(|e| e.errno())
+}
+
+fn unlink(fs: &mut RemoteFS, caller: Caller, path: &str) -> Result<(), i32> {
+    fs.refuses_changes(path)?;
+    let result = fs.unlink_path(path);
+    fs.audit(caller, AuditOp::Delete, path, None, None, result.err());
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Shutdown;
+
+    use super::*;
+
+    // What a session of a server exporting nothing answers `kind` with
+    fn answer(session: &mut Session, kind: u8, args: &[u8]) -> Result<Vec<u8>, i32> {
+        let mut reply = Reply::default();
+        session.call(kind, &mut Args::new(args), &mut reply).map(|()| reply.0)
+    }
+
+    #[test]
+    fn versions_are_agreed_on_with_the_smaller_message_size() {
+        let server = Server { exports: Vec::new() };
+        let mut session = Session { server: &server, msize: MAX_MESSAGE, fids: HashMap::new() };
+        let mut version = Reply::default();
+        version.u32(8192);
+        version.string("9P2000.L");
+        let mut agreed = Reply::default();
+        agreed.u32(8192);
+        agreed.string(VERSION);
+        assert_eq!(answer(&mut session, TVERSION, &version.0), Ok(agreed.0));
+        assert_eq!(session.msize, 8192);
+
+        let mut version = Reply::default();
+        version.u32(u32::MAX);
+        version.string("9P2000.u");
+        let mut refused = Reply::default();
+        refused.u32(MAX_MESSAGE);
+        refused.string("unknown");
+        assert_eq!(answer(&mut session, TVERSION, &version.0), Ok(refused.0));
+        assert_eq!(session.msize, MAX_MESSAGE);
+    }
+
+    #[test]
+    fn short_requests_are_invalid_and_fids_never_made_are_bad() {
+        let server = Server { exports: Vec::new() };
+        let mut session = Session { server: &server, msize: MAX_MESSAGE, fids: HashMap::new() };
+        let requests = [
+            TVERSION, TATTACH, TFLUSH, TWALK, TGETATTR, TSETATTR, TLOPEN, TLCREATE, TREAD, TWRITE, TREADDIR, TFSYNC,
+            TCLUNK, TREMOVE, TUNLINKAT, TSTATFS, TLOCK, TGETLOCK,
+        ];
+        for kind in requests {
+            assert_eq!(answer(&mut session, kind, &[0]), Err(libc::EINVAL), "request {}", kind);
+        }
+
+        // Every field there, but for a fid nothing attached or walked to
+        let fields = [0u8; 64];
+        for kind in [TWALK, TGETATTR, TSETATTR, TLOPEN, TREAD, TFSYNC, TCLUNK, TREMOVE, TSTATFS, TLOCK, TGETLOCK] {
+            assert_eq!(answer(&mut session, kind, &fields), Err(libc::EBADF), "request {}", kind);
+        }
+        let mut write = Reply::default();
+        write.u32(1);
+        write.u64(0);
+        write.u32(4);
+        write.0.extend_from_slice(b"data");
+        assert_eq!(answer(&mut session, TWRITE, &write.0), Err(libc::EBADF));
+        // A count past the data sent with it is cut short
+        write.0.truncate(write.0.len() - 1);
+        assert_eq!(answer(&mut session, TWRITE, &write.0), Err(libc::EINVAL));
+
+        let mut attach = Reply::default();
+        attach.u32(1);
+        attach.u32(NOFID);
+        attach.string("root");
+        attach.string("/nowhere");
+        attach.u32(0);
+        assert_eq!(answer(&mut session, TATTACH, &attach.0), Err(libc::ENOENT));
+    }
+
+    #[test]
+    fn requests_for_what_the_do_lacks_are_unsupported() {
+        let server = Server { exports: Vec::new() };
+        let mut session = Session { server: &server, msize: MAX_MESSAGE, fids: HashMap::new() };
+        // Tauth, Tmkdir, Trename, Txattrwalk and 9P2000's Topen
+        for kind in [102, 72, 20, 30, 112] {
+            assert_eq!(answer(&mut session, kind, &[0; 64]), Err(libc::EOPNOTSUPP), "request {}", kind);
+        }
+        let mut unlinkat = Reply::default();
+        unlinkat.u32(1);
+        unlinkat.string("dir");
+        unlinkat.u32(AT_REMOVEDIR);
+        assert_eq!(answer(&mut session, TUNLINKAT, &unlinkat.0), Err(libc::EOPNOTSUPP));
+    }
+
+    #[test]
+    fn replies_echo_the_tag_and_bad_sizes_end_the_session() {
+        let server = Server { exports: Vec::new() };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let mut clunk = Reply::default();
+        clunk.u32(4 + 7);
+        clunk.u8(TCLUNK);
+        clunk.u16(0x1234);
+        clunk.u32(9);
+        // A message too short to be one
+        clunk.u32(3);
+        clunk.u8(TCLUNK);
+        clunk.u16(1);
+        client.write_all(&clunk.0).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut session = Session { server: &server, msize: MAX_MESSAGE, fids: HashMap::new() };
+        assert_eq!(session.serve(stream).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).unwrap();
+        let mut error = vec![4 + 7, 0, 0, 0, RLERROR, 0x34, 0x12];
+        error.extend_from_slice(&(libc::EBADF as u32).to_le_bytes());
+        assert_eq!(reply, error);
+    }
+
+    #[test]
+    fn fields_round_trip_and_short_requests_are_invalid() {
+        let mut reply = Reply::default();
+        reply.u8(QTDIR);
+        reply.u16(7);
+        reply.string("a.txt");
+        reply.u64(1 << 40);
+        assert_eq!(reply.0.len(), 1 + 2 + 2 + 5 + 8);
+
+        let mut args = Args::new(&reply.0);
+        assert_eq!(args.u8(), Ok(QTDIR));
+        assert_eq!(args.u16(), Ok(7));
+        assert_eq!(args.name(), Ok("a.txt"));
+        assert_eq!(args.u64(), Ok(1 << 40));
+        assert_eq!(args.u32(), Err(libc::EINVAL));
+
+        // A length running past the end is invalid, not a panic
+        assert_eq!(Args::new(&[9, 0, b'a', b'b']).string(), Err(libc::EINVAL));
+        for name in ["..", "", "a/b"] {
+            let mut encoded = Reply::default();
+            encoded.string(name);
+            assert_eq!(Args::new(&encoded.0).name(), Err(libc::EINVAL));
+        }
+
+        let mut qid = Reply::default();
+        qid.qid(FileType::Directory, 5);
+        assert_eq!(qid.0, [QTDIR, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(mode(FileType::RegularFile) | 0o644, 0o100644);
+        assert_eq!(dirent_type(FileType::Directory), libc::DT_DIR);
+        assert_eq!(dirent_type(FileType::RegularFile), libc::DT_REG);
+    }
+}
@@ -0,0 +1,299 @@
+use std::fmt;
+
+use serde::Serialize;
+
+// Every operation the daemon sends the DO, named on the wire as in its
+// `FSMessage.operation` union
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FsOperation {
+    // Only so messages can be built with `..Default::default()`
+    #[default]
+    Stat,
+    Read,
+    Write,
+    WriteBegin,
+    WriteChunk,
+    WriteEnd,
+    WriteAbort,
+    Readdir,
+    Unlink,
+    Restore,
+    Lease,
+    Hello,
+    Cancel,
+    Ping,
+    Audit,
+    // Sent by a daemon shutting down cleanly, once its data is flushed
+    Goodbye,
+    // Asks the DO to confirm a file's acknowledged writes are durable
+    Sync,
+    // Lists the DO's snapshots, or a directory in one of them
+    Snapshots,
+    // Reads a file as it was in a snapshot
+    SnapshotRead,
+    // Asks which of a write's chunks the DO doesn't have yet
+    HasChunks,
+    // Uploads one chunk the DO is missing
+    PutChunk,
+    // Writes a range as chunks the DO has
+    WriteChunks,
+    // Sets a file's permission bits, for DOs that keep them
+    Chmod,
+}
+
+// Message fields an operation can't do without
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Field {
+    Path,
+    Data,
+    Offset,
+    Size,
+    Transfer,
+    Checksum,
+    Lease,
+    Hello,
+    Restore,
+    Cancel,
+    Seq,
+    Snapshot,
+    Chunks,
+    Mode,
+}
+
+// Groups of operations that each wait under their own timeout
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OperationClass {
+    Metadata,
+    Read,
+    Write,
+    // Committing a streamed or chunked write or a restore batch to storage, or
+    // confirming writes are durable
+    Sync,
+}
+
+impl FsOperation {
+    // Every operation in declaration order, so `operation as usize` indexes it
+    pub const ALL: [FsOperation; 23] = [
+        FsOperation::Stat,
+        FsOperation::Read,
+        FsOperation::Write,
+        FsOperation::WriteBegin,
+        FsOperation::WriteChunk,
+        FsOperation::WriteEnd,
+        FsOperation::WriteAbort,
+        FsOperation::Readdir,
+        FsOperation::Unlink,
+        FsOperation::Restore,
+        FsOperation::Lease,
+        FsOperation::Hello,
+        FsOperation::Cancel,
+        FsOperation::Ping,
+        FsOperation::Audit,
+        FsOperation::Goodbye,
+        FsOperation::Sync,
+        FsOperation::Snapshots,
+        FsOperation::SnapshotRead,
+        FsOperation::HasChunks,
+        FsOperation::PutChunk,
+        FsOperation::WriteChunks,
+        FsOperation::Chmod,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FsOperation::Stat => "stat",
+            FsOperation::Read => "read",
+            FsOperation::Write => "write",
+            FsOperation::WriteBegin => "write-begin",
+            FsOperation::WriteChunk => "write-chunk",
+            FsOperation::WriteEnd => "write-end",
+            FsOperation::WriteAbort => "write-abort",
+            FsOperation::Readdir => "readdir",
+            FsOperation::Unlink => "unlink",
+            FsOperation::Restore => "restore",
+            FsOperation::Lease => "lease",
+            FsOperation::Hello => "hello",
+            FsOperation::Cancel => "cancel",
+            FsOperation::Ping => "ping",
+            FsOperation::Audit => "audit",
+            FsOperation::Goodbye => "goodbye",
+            FsOperation::Sync => "sync",
+            FsOperation::Snapshots => "snapshots",
+            FsOperation::SnapshotRead => "snapshot-read",
+            FsOperation::HasChunks => "has-chunks",
+            FsOperation::PutChunk => "put-chunk",
+            FsOperation::WriteChunks => "write-chunks",
+            FsOperation::Chmod => "chmod",
+        }
+    }
+
+    // Checked before a message is queued, so a request the DO would reject
+    // or misread fails in the daemon instead
+    pub const fn required_fields(self) -> &'static [Field] {
+        match self {
+            FsOperation::Stat | FsOperation::Readdir | FsOperation::Unlink | FsOperation::Snapshots => &[Field::Path],
+            FsOperation::Read => &[Field::Path, Field::Offset, Field::Size],
+            FsOperation::Write => &[Field::Path, Field::Data],
+            FsOperation::WriteBegin => &[Field::Path, Field::Offset, Field::Size],
+            FsOperation::WriteChunk => &[Field::Transfer, Field::Data, Field::Offset, Field::Checksum],
+            FsOperation::WriteEnd => &[Field::Transfer, Field::Checksum],
+            FsOperation::WriteAbort => &[Field::Transfer],
+            FsOperation::Restore => &[Field::Restore],
+            FsOperation::Lease => &[Field::Path, Field::Lease],
+            FsOperation::Hello => &[Field::Hello],
+            FsOperation::Cancel => &[Field::Cancel],
+            FsOperation::Ping | FsOperation::Goodbye => &[],
+            FsOperation::Audit => &[Field::Data],
+            FsOperation::Sync => &[Field::Path, Field::Seq],
+            FsOperation::SnapshotRead => &[Field::Path, Field::Snapshot, Field::Offset, Field::Size],
+            FsOperation::HasChunks => &[Field::Chunks],
+            FsOperation::PutChunk => &[Field::Data, Field::Checksum, Field::Chunks],
+            FsOperation::WriteChunks => &[Field::Path, Field::Offset, Field::Checksum, Field::Chunks],
+            FsOperation::Chmod => &[Field::Path, Field::Mode],
+        }
+    }
+
+    // Large transfers, which go round the connections after the first
+    pub fn is_bulk(self) -> bool {
+        match self {
+            FsOperation::Read
+            | FsOperation::Write
+            | FsOperation::WriteChunk
+            | FsOperation::SnapshotRead
+            | FsOperation::PutChunk => true,
+            FsOperation::Stat
+            | FsOperation::WriteBegin
+            | FsOperation::WriteEnd
+            | FsOperation::WriteAbort
+            | FsOperation::Readdir
+            | FsOperation::Unlink
+            | FsOperation::Restore
+            | FsOperation::Lease
+            | FsOperation::Hello
+            | FsOperation::Cancel
+            | FsOperation::Ping
+            | FsOperation::Audit
+            | FsOperation::Goodbye
+            | FsOperation::Sync
+            | FsOperation::Snapshots
+            | FsOperation::HasChunks
+            | FsOperation::WriteChunks
+            | FsOperation::Chmod => false,
+        }
+    }
+
+    // Changes that must not be applied twice, so retries carry an idempotency
+    // key. A write-chunk only stages data, which write-end then stores, a
+    // put-chunk stores the same chunk however often it is sent, and a chmod
+    // sets the same bits.
+    pub fn is_mutation(self) -> bool {
+        match self {
+            FsOperation::Write
+            | FsOperation::WriteEnd
+            | FsOperation::Unlink
+            | FsOperation::Audit
+            | FsOperation::WriteChunks => true,
+            FsOperation::Stat
+            | FsOperation::Read
+            | FsOperation::WriteBegin
+            | FsOperation::WriteChunk
+            | FsOperation::WriteAbort
+            | FsOperation::Readdir
+            | FsOperation::Restore
+            | FsOperation::Lease
+            | FsOperation::Hello
+            | FsOperation::Cancel
+            | FsOperation::Ping
+            | FsOperation::Goodbye
+            | FsOperation::Sync
+            | FsOperation::Snapshots
+            | FsOperation::SnapshotRead
+            | FsOperation::HasChunks
+            | FsOperation::PutChunk
+            | FsOperation::Chmod => false,
+        }
+    }
+
+    // Which timeout a request waits under, so a stat fails fast while a
+    // large transfer gets longer
+    pub fn class(self) -> OperationClass {
+        match self {
+            FsOperation::Read | FsOperation::SnapshotRead => OperationClass::Read,
+            FsOperation::Write | FsOperation::WriteChunk | FsOperation::PutChunk => OperationClass::Write,
+            FsOperation::WriteEnd | FsOperation::Restore | FsOperation::Sync | FsOperation::WriteChunks => {
+                OperationClass::Sync
+            }
+            FsOperation::Stat
+            | FsOperation::WriteBegin
+            | FsOperation::WriteAbort
+            | FsOperation::Readdir
+            | FsOperation::Unlink
+            | FsOperation::Lease
+            | FsOperation::Hello
+            | FsOperation::Cancel
+            | FsOperation::Ping
+            | FsOperation::Audit
+            | FsOperation::Goodbye
+            | FsOperation::Snapshots
+            | FsOperation::HasChunks
+            | FsOperation::Chmod => OperationClass::Metadata,
+        }
+    }
+
+    // Reads any DO serving the same data can answer, which the `share` routing
+    // policy spreads across every connected client
+    pub fn is_shareable(self) -> bool {
+        match self {
+            FsOperation::Stat | FsOperation::Read | FsOperation::Readdir => true,
+            FsOperation::Write
+            | FsOperation::WriteBegin
+            | FsOperation::WriteChunk
+            | FsOperation::WriteEnd
+            | FsOperation::WriteAbort
+            | FsOperation::Unlink
+            | FsOperation::Restore
+            | FsOperation::Lease
+            | FsOperation::Hello
+            | FsOperation::Cancel
+            | FsOperation::Ping
+            | FsOperation::Audit
+            | FsOperation::Goodbye
+            | FsOperation::Sync
+            | FsOperation::Snapshots
+            | FsOperation::SnapshotRead
+            | FsOperation::HasChunks
+            | FsOperation::PutChunk
+            | FsOperation::WriteChunks
+            | FsOperation::Chmod => false,
+        }
+    }
+}
+
+impl fmt::Display for FsOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Field::Path => "path",
+            Field::Data => "data",
+            Field::Offset => "offset",
+            Field::Size => "size",
+            Field::Transfer => "transfer",
+            Field::Checksum => "checksum",
+            Field::Lease => "lease",
+            Field::Hello => "hello",
+            Field::Restore => "restore",
+            Field::Cancel => "cancel",
+            Field::Seq => "seq",
+            Field::Snapshot => "snapshot",
+            Field::Chunks => "chunks",
+            Field::Mode => "mode",
+        };
+        f.write_str(name)
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::backend::{LocalStore, Store};
+use crate::error::{DaemonError, Locked};
+use crate::operation::FsOperation;
+use crate::sync::{list, PIECE_SIZE};
+use crate::RemoteFSClient;
+
+// The overlay the daemon serves, when --backend is one, for `commit`
+pub static OVERLAY: OnceLock<Arc<OverlayStore>> = OnceLock::new();
+
+// Files in the DO seen through a local scratch directory: reads fall through
+// to the DO for whatever hasn't changed, and changes stay in the directory
+// until they are committed. Deleting a file the DO has leaves a whiteout
+// hiding it. The scratch directory holds the changed files under `upper/`
+// and the whiteouts in `whiteouts.json`, so both outlast a restart.
+pub struct OverlayStore {
+    upper: LocalStore,
+    upper_dir: PathBuf,
+    lower: Box<dyn Store>,
+    // Paths deleted here that the DO still has. Changes hold the lock while
+    // they are made, so a commit sees none of them half made; reads only
+    // while they look.
+    whiteouts: Mutex<BTreeSet<String>>,
+    whiteouts_file: PathBuf,
+}
+
+// What a commit sent to the DO
+#[derive(Default)]
+struct Committed {
+    files: usize,
+    bytes: u64,
+    deleted: usize,
+}
+
+impl OverlayStore {
+    pub fn open(scratch: &Path, lower: Box<dyn Store>) -> io::Result<Self> {
+        let upper_dir = scratch.join("upper");
+        fs::create_dir_all(&upper_dir)?;
+        let whiteouts_file = scratch.join("whiteouts.json");
+        let whiteouts = match fs::read(&whiteouts_file) {
+            Ok(json) => serde_json::from_slice(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(e) => return Err(e),
+        };
+        let overlay = Self {
+            upper: LocalStore::open(&upper_dir)?,
+            upper_dir,
+            lower,
+            whiteouts: Mutex::new(whiteouts),
+            whiteouts_file,
+        };
+        let changed = overlay.changed_files()?.len();
+        let deleted = overlay.whiteouts.locked().len();
+        info!("Overlay in {} has {} changed and {} deleted files to commit", scratch.display(), changed, deleted);
+        Ok(overlay)
+    }
+
+    // Sends every change to the DO, then forgets it. A commit cut short by a
+    // failure keeps what it didn't send, so it can be run again.
+    pub fn commit(&self) -> Result<Value, String> {
+        let mut whiteouts = self.whiteouts.locked();
+        let mut committed = Committed::default();
+        let result = self.commit_locked(&mut whiteouts, &mut committed);
+        let saved = self.save(&whiteouts);
+        info!(
+            files = committed.files,
+            bytes = committed.bytes,
+            deleted = committed.deleted,
+            "Committed the overlay to the DO"
+        );
+        result.and(saved).map_err(|e| e.to_string())?;
+        Ok(json!({ "files": committed.files, "bytes": committed.bytes, "deleted": committed.deleted }))
+    }
+
+    fn commit_locked(&self, whiteouts: &mut BTreeSet<String>, committed: &mut Committed) -> io::Result<()> {
+        for path in self.changed_files()? {
+            let (size, _) = self.upper.stat(&path)?;
+            // Pieces are written in place after the first replaces the file
+            let mut offset = 0;
+            loop {
+                let piece = self.upper.read(&path, offset, Some(PIECE_SIZE))?;
+                self.lower.write(&path, (offset > 0).then_some(offset), &piece)?;
+                offset += piece.len() as u64;
+                if piece.is_empty() || offset >= size {
+                    break;
+                }
+            }
+            self.upper.unlink(&path)?;
+            committed.files += 1;
+            committed.bytes += offset;
+        }
+        while let Some(path) = whiteouts.first().cloned() {
+            self.lower.unlink(&path)?;
+            whiteouts.remove(&path);
+            committed.deleted += 1;
+        }
+        // Directories left empty by the files sent from them
+        fs::remove_dir_all(&self.upper_dir)?;
+        fs::create_dir_all(&self.upper_dir)
+    }
+
+    // Every file in the upper layer
+    fn changed_files(&self) -> io::Result<Vec<String>> {
+        let mut files = Vec::new();
+        let mut dirs = vec!["/".to_string()];
+        while let Some(dir) = dirs.pop() {
+            for name in self.upper.list(&dir)? {
+                let path = crate::join(&dir, &name);
+                match self.upper.stat(&path) {
+                    Ok(_) => files.push(path),
+                    Err(e) if e.raw_os_error() == Some(libc::ENOENT) => dirs.push(path),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    fn save(&self, whiteouts: &BTreeSet<String>) -> io::Result<()> {
+        let json = serde_json::to_vec(whiteouts).map_err(io::Error::other)?;
+        let temporary = self.whiteouts_file.with_extension("json.tmp");
+        fs::write(&temporary, json)?;
+        fs::rename(&temporary, &self.whiteouts_file)
+    }
+
+    fn in_upper(&self, path: &str) -> io::Result<bool> {
+        match self.upper.stat(path) {
+            Ok(_) => Ok(true),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn not_found() -> io::Error {
+    io::Error::from_raw_os_error(libc::ENOENT)
+}
+
+impl Store for OverlayStore {
+    fn read(&self, path: &str, offset: u64, size: Option<u64>) -> io::Result<Vec<u8>> {
+        if self.whiteouts.locked().contains(path) {
+            return Err(not_found());
+        }
+        match self.in_upper(path)? {
+            true => self.upper.read(path, offset, size),
+            false => self.lower.read(path, offset, size),
+        }
+    }
+
+    // A write into a file only the DO has copies it up first, so the rest of
+    // it is still there
+    fn write(&self, path: &str, offset: Option<u64>, data: &[u8]) -> io::Result<()> {
+        let mut whiteouts = self.whiteouts.locked();
+        let deleted = whiteouts.remove(path);
+        if offset.is_some() && !deleted && !self.in_upper(path)? {
+            match self.lower.read(path, 0, None) {
+                Ok(lower) => self.upper.write(path, None, &lower)?,
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.upper.write(path, offset, data)?;
+        if deleted {
+            self.save(&whiteouts)?;
+        }
+        Ok(())
+    }
+
+    fn stat(&self, path: &str) -> io::Result<(u64, u64)> {
+        if self.whiteouts.locked().contains(path) {
+            return Err(not_found());
+        }
+        match self.upper.stat(path) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => self.lower.stat(path),
+            stat => stat,
+        }
+    }
+
+    // Both layers' names, less those of deleted files. A directory whose
+    // files were all deleted is listed, empty, until the commit.
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let mut names = self.upper.list(path)?;
+        match self.lower.list(path) {
+            Ok(lower) => names.extend(lower),
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {}
+            Err(e) => return Err(e),
+        }
+        names.sort();
+        names.dedup();
+        let whiteouts = self.whiteouts.locked();
+        names.retain(|name| !whiteouts.contains(&crate::join(path, name)));
+        Ok(names)
+    }
+
+    fn unlink(&self, path: &str) -> io::Result<bool> {
+        let mut whiteouts = self.whiteouts.locked();
+        if whiteouts.contains(path) {
+            return Ok(false);
+        }
+        let upper = self.upper.unlink(path)?;
+        let lower = match self.lower.stat(path) {
+            Ok(_) => true,
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => false,
+            Err(e) => return Err(e),
+        };
+        if lower {
+            whiteouts.insert(path.to_string());
+            self.save(&whiteouts)?;
+        }
+        Ok(upper || lower)
+    }
+}
+
+// The DO as the overlay's lower layer, through a client of its own
+pub struct RemoteStore {
+    client: Arc<RemoteFSClient>,
+}
+
+impl RemoteStore {
+    pub fn new(client: Arc<RemoteFSClient>) -> Self {
+        Self { client }
+    }
+
+    // Waits out a request to the DO from a backend thread, with the errno the
+    // DO gave as the error
+    fn block_on<T>(&self, request: impl std::future::Future<Output = Result<T, DaemonError>>) -> io::Result<T> {
+        self.client.block_on(request).map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+}
+
+impl Store for RemoteStore {
+    fn read(&self, path: &str, offset: u64, size: Option<u64>) -> io::Result<Vec<u8>> {
+        let size = match size {
+            Some(size) => size,
+            None => self.stat(path)?.0.saturating_sub(offset),
+        };
+        self.block_on(self.client.read_chunked(path, offset, size))
+    }
+
+    fn write(&self, path: &str, offset: Option<u64>, data: &[u8]) -> io::Result<()> {
+        self.block_on(async {
+            // Replacing is truncating and then writing, so a large file still
+            // goes in chunks
+            if offset.is_none() {
+                self.client.send_request(FsOperation::Write, path, Some(vec![]), None, None).await?;
+            }
+            match self.client.write_chunked(path, offset.unwrap_or(0), data).await {
+                (_, Some(e)) => Err(e),
+                (_, None) => self.client.sync(path).await,
+            }
+        })
+    }
+
+    fn stat(&self, path: &str) -> io::Result<(u64, u64)> {
+        let response = self.block_on(self.client.send_request(FsOperation::Stat, path, None, None, None))?;
+        match response.stat {
+            Some(stat) if stat.is_file => Ok((stat.size, stat.mtime)),
+            _ => Err(not_found()),
+        }
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let mut names = self.block_on(list(&self.client, path))?;
+        names.sort();
+        Ok(names)
+    }
+
+    fn unlink(&self, path: &str) -> io::Result<bool> {
+        let response = self.block_on(self.client.send_request(FsOperation::Unlink, path, None, None, None))?;
+        Ok(response.success)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryStore;
+
+    // A store that is another's, so the test can look at the DO's side
+    struct Shared(Arc<MemoryStore>);
+
+    impl Store for Shared {
+        fn read(&self, path: &str, offset: u64, size: Option<u64>) -> io::Result<Vec<u8>> {
+            self.0.read(path, offset, size)
+        }
+        fn write(&self, path: &str, offset: Option<u64>, data: &[u8]) -> io::Result<()> {
+            self.0.write(path, offset, data)
+        }
+        fn stat(&self, path: &str) -> io::Result<(u64, u64)> {
+            self.0.stat(path)
+        }
+        fn list(&self, path: &str) -> io::Result<Vec<String>> {
+            self.0.list(path)
+        }
+        fn unlink(&self, path: &str) -> io::Result<bool> {
+            self.0.unlink(path)
+        }
+    }
+
+    #[test]
+    fn changes_stay_local_until_committed() {
+        let scratch = std::env::temp_dir().join(format!("fsdaemon-overlay-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&scratch);
+        let lower = Arc::new(MemoryStore::default());
+        lower.write("/kept", None, b"from the DO").unwrap();
+        lower.write("/edited", None, b"0123456789").unwrap();
+        lower.write("/dir/removed", None, b"gone").unwrap();
+        let overlay = OverlayStore::open(&scratch, Box::new(Shared(lower.clone()))).unwrap();
+
+        // Writes into a file of the DO's copy it up first
+        overlay.write("/edited", Some(2), b"xy").unwrap();
+        overlay.write("/new", None, b"local").unwrap();
+        assert!(overlay.unlink("/dir/removed").unwrap());
+        assert_eq!(overlay.read("/edited", 0, None).unwrap(), b"01xy456789");
+        assert_eq!(overlay.read("/kept", 0, None).unwrap(), b"from the DO");
+        assert_eq!(overlay.stat("/dir/removed").unwrap_err().raw_os_error(), Some(libc::ENOENT));
+        assert_eq!(overlay.list("/").unwrap(), ["dir", "edited", "kept", "new"]);
+        assert!(overlay.list("/dir").unwrap().is_empty());
+        assert_eq!(lower.read("/edited", 0, None).unwrap(), b"0123456789");
+        assert!(lower.stat("/new").is_err());
+
+        // What is kept outlasts a restart
+        drop(overlay);
+        let overlay = OverlayStore::open(&scratch, Box::new(Shared(lower.clone()))).unwrap();
+        assert_eq!(overlay.commit().unwrap(), json!({ "files": 2, "bytes": 15, "deleted": 1 }));
+        assert_eq!(lower.read("/edited", 0, None).unwrap(), b"01xy456789");
+        assert_eq!(lower.read("/new", 0, None).unwrap(), b"local");
+        assert!(lower.stat("/dir/removed").is_err());
+        assert_eq!(overlay.commit().unwrap(), json!({ "files": 0, "bytes": 0, "deleted": 0 }));
+        fs::remove_dir_all(&scratch).unwrap();
+    }
+}
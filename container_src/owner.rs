@@ -0,0 +1,155 @@
+use fuser::{FileAttr, FileType};
+
+use crate::audit::Caller;
+
+// Who files are shown as owned by, since the DO keeps no owners
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Owner {
+    // The user and group the daemon runs as, as before owners were mapped
+    Daemon,
+    // Whoever asks, so every caller owns every file
+    Caller,
+    Fixed { uid: u32, gid: u32 },
+}
+
+impl Owner {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "daemon" => Ok(Owner::Daemon),
+            "caller" => Ok(Owner::Caller),
+            ids => {
+                let (uid, gid) = parse_ids(ids)?;
+                Ok(Owner::Fixed { uid, gid })
+            }
+        }
+    }
+}
+
+// An --owner-map entry, `<path>=<uid>:<gid>`: the owner of everything under
+// a directory of the DO
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct OwnerEntry {
+    pub path: String,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl OwnerEntry {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (path, ids) = value.rsplit_once('=').ok_or("expected <path>=<uid>:<gid>")?;
+        if !path.starts_with('/') {
+            return Err(format!("'{}' isn't an absolute path of the DO", path));
+        }
+        let (uid, gid) = parse_ids(ids)?;
+        let path = match path.trim_end_matches('/') {
+            "" => "/".to_string(),
+            path => path.to_string(),
+        };
+        Ok(Self { path, uid, gid })
+    }
+
+    fn covers(&self, path: &str) -> bool {
+        self.path == "/"
+            || path.strip_prefix(self.path.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+}
+
+fn parse_ids(ids: &str) -> Result<(u32, u32), String> {
+    let (uid, gid) = ids.split_once(':').ok_or_else(|| format!("expected <uid>:<gid>, got '{}'", ids))?;
+    let id = |id: &str| id.parse::<u32>().map_err(|_| format!("'{}' isn't a numeric id", id));
+    Ok((id(uid)?, id(gid)?))
+}
+
+// The owner a path is shown with: that of the deepest --owner-map entry over
+// it, or else --owner's
+#[derive(Clone, Debug)]
+pub struct Ownership {
+    owner: Owner,
+    table: Vec<OwnerEntry>,
+}
+
+impl Ownership {
+    pub fn new(owner: Owner, table: Vec<OwnerEntry>) -> Self {
+        Self { owner, table }
+    }
+
+    // The uid and gid of `path`, as `caller` sees it when there is one
+    pub fn of(&self, path: &str, caller: Option<Caller>) -> (u32, u32) {
+        let entry = self.table.iter().filter(|entry| entry.covers(path)).max_by_key(|entry| entry.path.len());
+        match (entry, self.owner, caller) {
+            (Some(entry), _, _) => (entry.uid, entry.gid),
+            (None, Owner::Fixed { uid, gid }, _) => (uid, gid),
+            (None, Owner::Caller, Some(caller)) => (caller.uid, caller.gid),
+            (None, Owner::Daemon | Owner::Caller, _) => unsafe { (libc::getuid(), libc::getgid()) },
+        }
+    }
+}
+
+// Whether `caller` may access a file with `attr` as `mask` asks, by its mode
+// bits. Root may do anything but execute a file nobody can. Only the caller's
+// primary group counts, as the kernel doesn't pass on the others.
+pub fn permitted(attr: &FileAttr, caller: Caller, mask: i32) -> bool {
+    let wanted = (mask & (libc::R_OK | libc::W_OK | libc::X_OK)) as u16;
+    if caller.uid == 0 {
+        return wanted & libc::X_OK as u16 == 0 || attr.kind == FileType::Directory || attr.perm & 0o111 != 0;
+    }
+    let granted = match (caller.uid == attr.uid, caller.gid == attr.gid) {
+        (true, _) => attr.perm >> 6,
+        (false, true) => attr.perm >> 3,
+        (false, false) => attr.perm,
+    } & 0o7;
+    wanted & !granted == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    #[test]
+    fn the_deepest_entry_over_a_path_wins() {
+        let table = ["/home=0:0", "/home/alice=1000:100", "/home/bob/=1001:100"]
+            .iter()
+            .map(|entry| OwnerEntry::parse(entry).unwrap())
+            .collect();
+        let ownership = Ownership::new(Owner::parse("caller").unwrap(), table);
+        let caller = Caller { uid: 42, gid: 43, pid: 0 };
+        assert_eq!(ownership.of("/home/alice/notes.txt", Some(caller)), (1000, 100));
+        assert_eq!(ownership.of("/home/bob", Some(caller)), (1001, 100));
+        assert_eq!(ownership.of("/home/alicex", Some(caller)), (0, 0));
+        assert_eq!(ownership.of("/tmp/a", Some(caller)), (42, 43));
+        assert_eq!(Ownership::new(Owner::parse("7:8").unwrap(), vec![]).of("/a", Some(caller)), (7, 8));
+        assert!(OwnerEntry::parse("home=1:1").is_err());
+        assert!(Owner::parse("alice:staff").is_err());
+    }
+
+    #[test]
+    fn modes_decide_access() {
+        let attr = FileAttr {
+            ino: 2,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm: 0o640,
+            nlink: 1,
+            uid: 1000,
+            gid: 100,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        };
+        let user = |uid, gid| Caller { uid, gid, pid: 0 };
+        assert!(permitted(&attr, user(1000, 1), libc::R_OK | libc::W_OK));
+        assert!(permitted(&attr, user(2000, 100), libc::R_OK));
+        assert!(!permitted(&attr, user(2000, 100), libc::W_OK));
+        assert!(!permitted(&attr, user(2000, 200), libc::R_OK));
+        assert!(permitted(&attr, user(2000, 200), libc::F_OK));
+        assert!(permitted(&attr, user(0, 0), libc::R_OK | libc::W_OK));
+        assert!(!permitted(&attr, user(0, 0), libc::X_OK));
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tracing::{debug, warn};
+
+use crate::cli;
+use crate::error::{DaemonError, Locked};
+use crate::sync::remote_files;
+use crate::{join, parse_prefix, RemoteFSClient};
+
+// How often what the daemon counted against the quotas is replaced by what
+// the DO has
+pub const DEFAULT_RECONCILE_INTERVAL: Duration = Duration::from_secs(60);
+
+// A limit on the bytes and files under a path of the DO. Going over a
+// mount's quota fills the filesystem, ENOSPC; going over a directory's is
+// EDQUOT.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Quota {
+    pub path: String,
+    pub bytes: Option<u64>,
+    pub files: Option<u64>,
+    pub mount: bool,
+}
+
+impl Quota {
+    // A --quota value, `<path>:bytes=<n>,files=<n>` with either limit left out
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (path, limits) = value.split_once(':').ok_or("expected <path>:bytes=<n>[,files=<n>]")?;
+        let mut quota = Self { path: parse_prefix(path)?, bytes: None, files: None, mount: false };
+        for limit in limits.split(',') {
+            let named = |e: String| format!("{}: {}", limit, e);
+            match limit.split_once('=') {
+                Some(("bytes", bytes)) => quota.bytes = Some(cli::positive(bytes).map_err(named)?),
+                Some(("files", files)) => quota.files = Some(cli::positive(files).map_err(named)?),
+                _ => return Err(format!("unknown limit '{}', expected bytes or files", limit)),
+            }
+        }
+        Ok(quota)
+    }
+
+    // A mount's quota on its prefix, if it has either limit
+    pub fn mount(prefix: &str, bytes: Option<u64>, files: Option<u64>) -> Option<Self> {
+        (bytes.is_some() || files.is_some()).then(|| Self { path: prefix.to_string(), bytes, files, mount: true })
+    }
+
+    fn covers(&self, path: &str) -> bool {
+        self.path == "/"
+            || path.strip_prefix(self.path.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    fn errno(&self) -> i32 {
+        match self.mount {
+            true => libc::ENOSPC,
+            false => libc::EDQUOT,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+struct Usage {
+    bytes: u64,
+    files: u64,
+}
+
+#[derive(Default)]
+struct State {
+    // What is counted against each quota, in the order of `Quotas::quotas`
+    used: Vec<Usage>,
+    // The size of every file under a quota, as last charged or reported by the DO
+    sizes: HashMap<String, u64>,
+    // While a reconcile is listing the DO, the files charged for since it
+    // began, with their sizes or None once removed, laid over what it finds
+    charged: Option<HashMap<String, Option<u64>>>,
+}
+
+// The quotas of every mount, and what counts against them. Changes are
+// charged as applications make them, before they reach the DO, so a write
+// that would go over fails where it is made; a reconciler replaces the count
+// with what the DO has from time to time, taking in changes made by others.
+pub struct Quotas {
+    quotas: Vec<Quota>,
+    state: Mutex<State>,
+}
+
+// The room under a mount's quota, as statfs reports it. Without one the DO
+// sets no limits of its own.
+pub struct Space {
+    pub bytes: u64,
+    pub bytes_free: u64,
+    pub files: u64,
+    pub files_free: u64,
+}
+
+impl Space {
+    pub const UNLIMITED: Space = Space { bytes: 1 << 50, bytes_free: 1 << 50, files: 1 << 32, files_free: 1 << 32 };
+}
+
+impl Quotas {
+    pub fn new(quotas: Vec<Quota>) -> Arc<Self> {
+        let state = State {
+            used: vec![Usage::default(); quotas.len()],
+            sizes: HashMap::new(),
+            // Until the first reconcile, what is charged is all there is
+            charged: Some(HashMap::new()),
+        };
+        Arc::new(Self { quotas, state: Mutex::new(state) })
+    }
+
+    pub fn covers(&self, path: &str) -> bool {
+        self.quotas.iter().any(|quota| quota.covers(path))
+    }
+
+    // The size a file under a quota is counted at, if it is known
+    pub fn size(&self, path: &str) -> Option<u64> {
+        self.state.locked().sizes.get(path).copied()
+    }
+
+    // Charges for the file at `path` growing to at least `end` bytes,
+    // returning the size it was counted at before, or fails with the errno of
+    // a quota it would go over. `current` is the file's size when it isn't
+    // known here, None if there is no such file.
+    pub fn grow(&self, path: &str, current: Option<u64>, end: u64) -> Result<Option<u64>, i32> {
+        let mut state = self.state.locked();
+        let before = state.sizes.get(path).copied().or(current);
+        self.charge(&mut state, path, before, Some(before.unwrap_or(0).max(end)))?;
+        Ok(before)
+    }
+
+    // Charges for an empty file replacing whatever was at `path`, with
+    // `current` and what is returned as for `grow`
+    pub fn create(&self, path: &str, current: Option<u64>) -> Result<Option<u64>, i32> {
+        let mut state = self.state.locked();
+        let before = state.sizes.get(path).copied().or(current);
+        self.charge(&mut state, path, before, Some(0))?;
+        Ok(before)
+    }
+
+    // Gives back what a removed file was counted at
+    pub fn remove(&self, path: &str) {
+        self.restore(path, None);
+    }
+
+    // Counts the file at `path` at `size` again, None if there is no such
+    // file, after a change it was charged for never reached the DO. Being
+    // what the DO has, it is counted even over a quota.
+    pub fn restore(&self, path: &str, size: Option<u64>) {
+        let mut state = self.state.locked();
+        let before = state.sizes.get(path).copied();
+        self.apply(&mut state, path, before, size);
+    }
+
+    fn charge(&self, state: &mut State, path: &str, before: Option<u64>, after: Option<u64>) -> Result<(), i32> {
+        let bytes = after.unwrap_or(0) as i64 - before.unwrap_or(0) as i64;
+        let files = after.is_some() as i64 - before.is_some() as i64;
+        let over = |limit: Option<u64>, used: u64, delta: i64| {
+            delta > 0 && limit.is_some_and(|limit| used.saturating_add_signed(delta) > limit)
+        };
+        for (quota, used) in self.quotas.iter().zip(&state.used).filter(|(quota, _)| quota.covers(path)) {
+            if over(quota.bytes, used.bytes, bytes) || over(quota.files, used.files, files) {
+                debug!("Refusing to change {}: it would go over the quota on {}", path, quota.path);
+                return Err(quota.errno());
+            }
+        }
+        self.apply(state, path, before, after);
+        Ok(())
+    }
+
+    // Moves the count of the file at `path` from `before` to `after` in every
+    // quota over it
+    fn apply(&self, state: &mut State, path: &str, before: Option<u64>, after: Option<u64>) {
+        let covering: Vec<usize> = (0..self.quotas.len()).filter(|&i| self.quotas[i].covers(path)).collect();
+        if covering.is_empty() {
+            return;
+        }
+        let bytes = after.unwrap_or(0) as i64 - before.unwrap_or(0) as i64;
+        let files = after.is_some() as i64 - before.is_some() as i64;
+        for &i in &covering {
+            let used = &mut state.used[i];
+            used.bytes = used.bytes.saturating_add_signed(bytes);
+            used.files = used.files.saturating_add_signed(files);
+        }
+        match after {
+            Some(size) => state.sizes.insert(path.to_string(), size),
+            None => state.sizes.remove(path),
+        };
+        if let Some(charged) = &mut state.charged {
+            charged.insert(path.to_string(), after);
+        }
+    }
+
+    // Counts what the DO has under the quotas every `interval`, starting now
+    pub fn spawn_reconciler(self: &Arc<Self>, client: Arc<RemoteFSClient>, interval: Duration) {
+        let quotas = self.clone();
+        thread::spawn(move || {
+            loop {
+                if let Err(e) = client.block_on(quotas.reconcile(&client)) {
+                    warn!("Failed to count quota usage in the DO, will retry: {}", e);
+                }
+                thread::sleep(interval);
+            }
+        });
+    }
+
+    async fn reconcile(&self, client: &RemoteFSClient) -> Result<(), DaemonError> {
+        self.state.locked().charged.get_or_insert_with(HashMap::new);
+        // A quota inside another's is listed with it
+        let roots: BTreeSet<&str> = self
+            .quotas
+            .iter()
+            .filter(|quota| !self.quotas.iter().any(|outer| outer.path != quota.path && outer.covers(&quota.path)))
+            .map(|quota| quota.path.as_str())
+            .collect();
+        let mut sizes = HashMap::new();
+        for root in roots {
+            for (name, stat) in remote_files(client, root).await? {
+                sizes.insert(join(root, &name), stat.size);
+            }
+        }
+        let mut state = self.state.locked();
+        for (path, size) in state.charged.take().unwrap_or_default() {
+            match size {
+                Some(size) => sizes.insert(path, size),
+                None => sizes.remove(&path),
+            };
+        }
+        state.used = self.quotas.iter().map(|quota| usage(quota, &sizes)).collect();
+        state.sizes = sizes;
+        for (quota, used) in self.quotas.iter().zip(&state.used) {
+            debug!(bytes = used.bytes, files = used.files, "Counted quota usage under {}", quota.path);
+        }
+        Ok(())
+    }
+
+    // The room under the mount's quota on `prefix`
+    pub fn space(&self, prefix: &str) -> Space {
+        let state = self.state.locked();
+        let Some((quota, used)) =
+            self.quotas.iter().zip(&state.used).find(|(quota, _)| quota.mount && quota.path == prefix)
+        else {
+            return Space::UNLIMITED;
+        };
+        let (bytes, files) =
+            (quota.bytes.unwrap_or(Space::UNLIMITED.bytes), quota.files.unwrap_or(Space::UNLIMITED.files));
+        Space {
+            bytes,
+            bytes_free: bytes.saturating_sub(used.bytes),
+            files,
+            files_free: files.saturating_sub(used.files),
+        }
+    }
+
+    // Each quota with what counts against it, for the control file's status
+    pub fn status(&self) -> Value {
+        let state = self.state.locked();
+        let quotas = self.quotas.iter().zip(&state.used).map(|(quota, used)| {
+            json!({
+                "path": quota.path,
+                "mount": quota.mount,
+                "bytes": used.bytes,
+                "max_bytes": quota.bytes,
+                "files": used.files,
+                "max_files": quota.files,
+            })
+        });
+        Value::Array(quotas.collect())
+    }
+}
+
+fn usage(quota: &Quota, sizes: &HashMap<String, u64>) -> Usage {
+    let mut usage = Usage::default();
+    for (_, size) in sizes.iter().filter(|(path, _)| quota.covers(path)) {
+        usage.bytes += size;
+        usage.files += 1;
+    }
+    usage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_are_charged_to_every_quota_over_them() {
+        let quotas = Quotas::new(vec![
+            Quota::mount("/", Some(100), None).unwrap(),
+            Quota::parse("/logs:bytes=50,files=2").unwrap(),
+        ]);
+        quotas.create("/logs/a", None).unwrap();
+        quotas.grow("/logs/a", None, 40).unwrap();
+        // Rewriting what is there costs nothing
+        quotas.grow("/logs/a", None, 30).unwrap();
+        assert_eq!(quotas.grow("/logs/a", None, 60), Err(libc::EDQUOT));
+        quotas.create("/logs/b", None).unwrap();
+        assert_eq!(quotas.create("/logs/c", None), Err(libc::EDQUOT));
+        quotas.grow("/other", Some(50), 55).unwrap();
+        assert_eq!(quotas.grow("/other", None, 120), Err(libc::ENOSPC));
+
+        // Replacing or removing a file gives its bytes back
+        quotas.create("/logs/a", None).unwrap();
+        quotas.remove("/logs/b");
+        quotas.create("/logs/c", None).unwrap();
+        quotas.grow("/logs/c", None, 45).unwrap();
+        let space = quotas.space("/");
+        assert_eq!((space.bytes, space.bytes_free), (100, 50));
+        assert_eq!(quotas.space("/logs").bytes, Space::UNLIMITED.bytes);
+    }
+
+    #[test]
+    fn changes_that_never_reach_the_do_are_given_back() {
+        let quotas = Quotas::new(vec![Quota::parse("/logs:bytes=50,files=1").unwrap()]);
+        let before = quotas.create("/logs/a", None).unwrap();
+        assert_eq!(before, None);
+        quotas.restore("/logs/a", before);
+        quotas.create("/logs/b", None).unwrap();
+        assert_eq!(quotas.grow("/logs/b", None, 30), Ok(Some(0)));
+        let before = quotas.grow("/logs/b", None, 50).unwrap();
+        quotas.restore("/logs/b", before);
+        assert_eq!(quotas.size("/logs/b"), Some(30));
+        assert_eq!(quotas.grow("/logs/b", None, 51), Err(libc::EDQUOT));
+
+        // What the DO has is counted even over the quota
+        quotas.restore("/logs/b", Some(60));
+        assert_eq!(quotas.status()[0]["bytes"], 60);
+        assert_eq!(quotas.grow("/logs/b", None, 61), Err(libc::EDQUOT));
+    }
+
+    #[test]
+    fn quotas_parse_from_the_command_line() {
+        let quota = Quota::parse("/logs:files=10").unwrap();
+        assert_eq!(quota, Quota { path: "/logs".to_string(), bytes: None, files: Some(10), mount: false });
+        assert!(Quota::parse("/logs").is_err());
+        assert!(Quota::parse("logs:bytes=1").is_err());
+        assert!(Quota::parse("/logs:bytes=0").is_err());
+        assert!(Quota::parse("/logs:inodes=1").is_err());
+        assert!(Quota::mount("/", None, None).is_none());
+        assert!(!Quota::parse("/logs:bytes=1").unwrap().covers("/logsbook"));
+    }
+}
@@ -0,0 +1,490 @@
+use std::io::{self, BufReader, Read};
+use std::sync::Arc;
+use std::thread;
+use std::time::SystemTime;
+
+use fuser::{FileAttr, FileType};
+use tracing::{debug, info, warn};
+
+use crate::audit::{AuditOp, Caller};
+use crate::endpoints::{Endpoints, Mount};
+use crate::error::Locked;
+use crate::http::{self, encode, escape, etag, http_date, respond, respond_head, write_head, Chunked, Request};
+use crate::transport::{Address, Conn, Listener};
+use crate::{join, RemoteFS};
+
+const PROLOG: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n";
+const NAMESPACE: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+// The most keys one listing gives, as with S3
+const MAX_KEYS: usize = 1000;
+
+// Query parameters of a bucket listing. Any other names a subresource, such
+// as versioning or a policy, which there are none of.
+const LIST_PARAMS: &[&str] = &[
+    "list-type",
+    "prefix",
+    "delimiter",
+    "max-keys",
+    "continuation-token",
+    "start-after",
+    "marker",
+    "encoding-type",
+    "fetch-owner",
+    "x-id",
+];
+// Query parameters naming subresources of an object: multipart uploads,
+// ACLs, tags and the like
+const OBJECT_SUBRESOURCES: &[&str] =
+    &["uploads", "uploadId", "partNumber", "acl", "tagging", "retention", "legal-hold", "attributes", "restore"];
+
+// Requests are signed, but the signatures aren't checked, so their changes
+// are audited as nobody's
+const CALLER: Caller = Caller { uid: 65534, gid: 65534, pid: 0 };
+
+// What a failure is called in an S3 error
+fn code(errno: i32) -> &'static str {
+    match errno {
+        libc::ENOENT => "NoSuchKey",
+        libc::EPERM | libc::EACCES | libc::EROFS => "AccessDenied",
+        libc::EEXIST | libc::EISDIR | libc::ENOTDIR => "InvalidRequest",
+        libc::EINVAL | libc::ENAMETOOLONG => "InvalidArgument",
+        libc::EFBIG | libc::ENOSPC | libc::EDQUOT => "EntityTooLarge",
+        libc::ESHUTDOWN | libc::EAGAIN | libc::ETIMEDOUT => "ServiceUnavailable",
+        libc::ENOSYS | libc::EOPNOTSUPP => "NotImplemented",
+        _ => "InternalError",
+    }
+}
+
+// A mount as a bucket, named by the last part of its mount point
+struct Bucket {
+    name: String,
+    mount: usize,
+}
+
+// The S3 gateway, serving every mount as a bucket next to whatever frontend
+// the mounts have
+pub struct Server {
+    endpoints: Arc<Endpoints>,
+    buckets: Vec<Bucket>,
+    // When the buckets were made, as far as clients are told
+    started: SystemTime,
+}
+
+// Serves the mounts of `endpoints` as buckets on `address`. Two mounts whose
+// mount points end the same way would be one bucket, so they can't be served.
+pub fn spawn(address: &Address, endpoints: Arc<Endpoints>) -> io::Result<()> {
+    let mut buckets: Vec<Bucket> = Vec::new();
+    for (index, mount) in endpoints.mounts.iter().enumerate() {
+        let name = bucket_name(&mount.point);
+        if let Some(other) = buckets.iter().find(|bucket| bucket.name == name) {
+            let other = &endpoints.mounts[other.mount].point;
+            let message = format!("mounts at {} and {} would both be bucket {}", other, mount.point, name);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+        buckets.push(Bucket { name, mount: index });
+    }
+    let listener = Listener::bind(address)?;
+    let names: Vec<_> = buckets.iter().map(|bucket| bucket.name.as_str()).collect();
+    info!("Serving S3 on {}, with buckets {}", address, names.join(", "));
+    let server = Arc::new(Server { endpoints, buckets, started: SystemTime::now() });
+
+    thread::spawn(move || loop {
+        match listener.accept() {
+            Ok(stream) => {
+                let server = server.clone();
+                thread::spawn(move || {
+                    if let Err(e) = http::serve(stream, |request, body, out| server.answer(request, body, out)) {
+                        debug!("S3 client went away: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept an S3 client: {}", e),
+        }
+    });
+    Ok(())
+}
+
+fn bucket_name(mount_point: &str) -> String {
+    mount_point.rsplit('/').find(|part| !part.is_empty()).unwrap_or("root").to_string()
+}
+
+impl Server {
+    // Requests are path-style, /<bucket>/<key>, as clients send them when
+    // told to force path style
+    fn answer(&self, request: &Request, body: &mut dyn Read, out: &mut Conn) -> io::Result<()> {
+        let mut parts = request.path[1..].splitn(2, '/');
+        let bucket = parts.next().unwrap_or_default();
+        // A key ending in a slash is a folder, which directories already are
+        let key = parts.next().map(|key| if request.slash { format!("{}/", key) } else { key.to_string() });
+        if bucket.is_empty() {
+            return match request.method.as_str() {
+                "GET" => self.list_buckets(out),
+                _ => respond_error(out, request, "405 Method Not Allowed", "MethodNotAllowed", "not a bucket"),
+            };
+        }
+        let Some(bucket) = self.buckets.iter().find(|found| found.name == bucket) else {
+            return match request.method.as_str() {
+                "PUT" => respond_error(out, request, "501 Not Implemented", "NotImplemented", "buckets are mounts"),
+                _ => respond_error(out, request, "404 Not Found", "NoSuchBucket", "no mount is this bucket"),
+            };
+        };
+        let mount = &self.endpoints.mounts[bucket.mount];
+        let params: Vec<&str> = request.query.split('&').filter_map(|param| param.split('=').next()).collect();
+        let params: Vec<&str> = params.into_iter().filter(|name| !name.is_empty()).collect();
+
+        let Some(key) = key else {
+            return match request.method.as_str() {
+                "GET" if params == ["location"] => {
+                    respond_xml(out, "200 OK", &format!("{}<LocationConstraint xmlns=\"{}\"/>\n", PROLOG, NAMESPACE))
+                }
+                "GET" if params.iter().all(|param| LIST_PARAMS.contains(param)) => {
+                    self.list_objects(request, bucket, mount, out)
+                }
+                "HEAD" => respond(out, "200 OK", &[("x-amz-bucket-region", "us-east-1".to_string())], b""),
+                "PUT" => {
+                    respond_error(out, request, "409 Conflict", "BucketAlreadyOwnedByYou", "the bucket is a mount")
+                }
+                // Deleting buckets, batch deletes, and bucket settings
+                _ => respond_error(out, request, "501 Not Implemented", "NotImplemented", "not supported"),
+            };
+        };
+        let copy = request.header("x-amz-copy-source").is_some();
+        if copy || params.iter().any(|param| OBJECT_SUBRESOURCES.contains(param)) {
+            // Multipart uploads and copies, and what the DO has no place for
+            return respond_error(out, request, "501 Not Implemented", "NotImplemented", "not supported");
+        }
+        let path = match mount.path(&key) {
+            Ok(path) => path,
+            Err(errno) => return respond_errno(out, request, errno),
+        };
+        if mount.fs.locked().fsctl.is(&path) {
+            let message = "the control file isn't served over S3";
+            return respond_error(out, request, "403 Forbidden", "AccessDenied", message);
+        }
+        match request.method.as_str() {
+            "GET" | "HEAD" if key.ends_with('/') => respond_errno(out, request, libc::ENOENT),
+            "GET" | "HEAD" => self.get_object(request, mount, &path, out),
+            "PUT" if key.ends_with('/') => {
+                // Nothing is kept of a folder; directories come and go with what is in them
+                io::copy(body, &mut io::sink())?;
+                respond(out, "200 OK", &[("ETag", "\"d41d8cd98f00b204e9800998ecf8427e\"".to_string())], b"")
+            }
+            "PUT" => self.put_object(request, mount, &path, body, out),
+            "DELETE" => self.delete_object(request, mount, &path, out),
+            _ => respond_error(out, request, "501 Not Implemented", "NotImplemented", "not supported"),
+        }
+    }
+
+    fn list_buckets(&self, out: &mut Conn) -> io::Result<()> {
+        let mut xml = format!("{}<ListAllMyBucketsResult xmlns=\"{}\">", PROLOG, NAMESPACE);
+        let owner = env!("CARGO_PKG_NAME");
+        xml += &format!("<Owner><ID>{0}</ID><DisplayName>{0}</DisplayName></Owner><Buckets>", owner);
+        let created = humantime::format_rfc3339_millis(self.started).to_string();
+        for bucket in &self.buckets {
+            xml += "<Bucket>";
+            element(&mut xml, "Name", &bucket.name);
+            element(&mut xml, "CreationDate", &created);
+            xml += "</Bucket>";
+        }
+        xml += "</Buckets></ListAllMyBucketsResult>\n";
+        respond_xml(out, "200 OK", &xml)
+    }
+
+    // ListObjectsV2 with list-type=2, ListObjects otherwise. Keys are paths
+    // under the mount, and listed in order, so a listing is made whole and
+    // then cut down to what was asked for.
+    fn list_objects(&self, request: &Request, bucket: &Bucket, mount: &Mount, out: &mut Conn) -> io::Result<()> {
+        let v2 = request.param("list-type").as_deref() == Some("2");
+        let prefix = request.param("prefix").unwrap_or_default();
+        let delimiter = request.param("delimiter").filter(|delimiter| !delimiter.is_empty());
+        let max_keys = match request.param("max-keys").map(|max| max.parse::<usize>()) {
+            Some(Ok(max)) => max.min(MAX_KEYS),
+            Some(Err(_)) => return respond_errno(out, request, libc::EINVAL),
+            None => MAX_KEYS,
+        };
+        let token = request.param("continuation-token").filter(|_| v2);
+        let start_after = match v2 {
+            true => request.param("start-after"),
+            false => request.param("marker"),
+        };
+        let after = token.clone().or(start_after.clone()).unwrap_or_default();
+        let url = request.param("encoding-type").as_deref() == Some("url");
+        let value = |text: &str| if url { encode(text) } else { text.to_string() };
+
+        // Only the directory the prefix ends in is looked through, and not
+        // below it when a slash rolls everything there up
+        let dir = prefix.rfind('/').map_or("", |at| &prefix[..at]);
+        let mut keys = Vec::new();
+        let deep = delimiter.as_deref() != Some("/");
+        // A prefix with a name the DO can't hold has nothing under it
+        if let Ok(path) = mount.path(dir) {
+            if let Err(errno) = walk(&mut mount.fs.locked(), mount, &path, dir, &prefix, deep, &mut keys) {
+                return respond_errno(out, request, errno);
+            }
+        }
+        keys.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut entries: Vec<(String, Option<FileAttr>)> = Vec::new();
+        for (key, attr) in keys {
+            let rolled = delimiter.as_deref().and_then(|delimiter| {
+                key[prefix.len()..].find(delimiter).map(|at| key[..prefix.len() + at + delimiter.len()].to_string())
+            });
+            match rolled {
+                Some(common) if entries.last().is_some_and(|(last, _)| *last == common) => {}
+                Some(common) => entries.push((common, None)),
+                None => entries.push((key, attr)),
+            }
+        }
+        entries.retain(|(key, _)| *key > after);
+        let truncated = entries.len() > max_keys;
+        entries.truncate(max_keys);
+
+        let mut xml = format!("{}<ListBucketResult xmlns=\"{}\">", PROLOG, NAMESPACE);
+        element(&mut xml, "Name", &bucket.name);
+        element(&mut xml, "Prefix", &value(&prefix));
+        if let Some(delimiter) = &delimiter {
+            element(&mut xml, "Delimiter", &value(delimiter));
+        }
+        element(&mut xml, "MaxKeys", &max_keys.to_string());
+        element(&mut xml, "IsTruncated", &truncated.to_string());
+        if url {
+            element(&mut xml, "EncodingType", "url");
+        }
+        let next = entries.last().map(|(key, _)| key.clone()).filter(|_| truncated);
+        if v2 {
+            element(&mut xml, "KeyCount", &entries.len().to_string());
+            if let Some(token) = &token {
+                element(&mut xml, "ContinuationToken", token);
+            }
+            if let Some(start_after) = &start_after {
+                element(&mut xml, "StartAfter", &value(start_after));
+            }
+            if let Some(next) = &next {
+                element(&mut xml, "NextContinuationToken", next);
+            }
+        } else {
+            element(&mut xml, "Marker", &value(&start_after.unwrap_or_default()));
+            if let Some(next) = &next {
+                element(&mut xml, "NextMarker", &value(next));
+            }
+        }
+        for (key, attr) in &entries {
+            match attr {
+                Some(attr) => {
+                    xml += "<Contents>";
+                    element(&mut xml, "Key", &value(key));
+                    element(&mut xml, "LastModified", &humantime::format_rfc3339_millis(attr.mtime).to_string());
+                    element(&mut xml, "ETag", &etag(attr));
+                    element(&mut xml, "Size", &attr.size.to_string());
+                    element(&mut xml, "StorageClass", "STANDARD");
+                    xml += "</Contents>";
+                }
+                None => {
+                    xml += "<CommonPrefixes>";
+                    element(&mut xml, "Prefix", &value(key));
+                    xml += "</CommonPrefixes>";
+                }
+            }
+        }
+        xml += "</ListBucketResult>\n";
+        respond_xml(out, "200 OK", &xml)
+    }
+
+    // The object, or the range of it asked for
+    fn get_object(&self, request: &Request, mount: &Mount, path: &str, out: &mut Conn) -> io::Result<()> {
+        let attr = match mount.fs.locked().path_attr(path) {
+            Ok(Some(attr)) if attr.kind != FileType::Directory => attr,
+            Ok(_) => return respond_errno(out, request, libc::ENOENT),
+            Err(e) => return respond_errno(out, request, e.errno()),
+        };
+        let size = attr.size;
+        let mut headers = vec![
+            ("Content-Type", "application/octet-stream".to_string()),
+            ("Last-Modified", http_date(attr.mtime)),
+            ("ETag", etag(&attr)),
+            ("Accept-Ranges", "bytes".to_string()),
+        ];
+        // Several ranges at once are answered with all of it, as HTTP allows
+        let (status, start, end) = match request.header("Range").filter(|range| !range.contains(',')) {
+            Some(range) => match byte_range(range, size) {
+                Some((start, end)) => {
+                    headers.push(("Content-Range", format!("bytes {}-{}/{}", start, end - 1, size)));
+                    ("206 Partial Content", start, end)
+                }
+                None => {
+                    let message = format!("the object is {} bytes", size);
+                    return respond_error(out, request, "416 Range Not Satisfiable", "InvalidRange", &message);
+                }
+            },
+            None => ("200 OK", 0, size),
+        };
+        headers.push(("Content-Length", (end - start).to_string()));
+        write_head(out, status, &headers)?;
+        if request.method == "HEAD" {
+            return Ok(());
+        }
+        mount.send(path, start, end, out)
+    }
+
+    // Replaces the object with the body, answering once the DO has all of
+    // it. A body an SDK signed chunk by chunk has the chunks taken apart.
+    fn put_object(
+        &self,
+        request: &Request,
+        mount: &Mount,
+        path: &str,
+        body: &mut dyn Read,
+        out: &mut Conn,
+    ) -> io::Result<()> {
+        let streaming = request.header("x-amz-content-sha256").is_some_and(|hash| hash.starts_with("STREAMING-"))
+            || request.header("Content-Encoding").is_some_and(|encoding| encoding.contains("aws-chunked"));
+        let replaced = match streaming {
+            true => mount.replace(path, &mut Chunked::new(BufReader::new(body)), CALLER),
+            false => mount.replace(path, body, CALLER),
+        };
+        match replaced {
+            Ok(_) => {
+                let attr = mount.fs.locked().path_attr(path).ok().flatten();
+                let headers: Vec<_> = attr.iter().map(|attr| ("ETag", etag(attr))).collect();
+                respond(out, "200 OK", &headers, b"")
+            }
+            Err(Ok(errno)) => respond_errno(out, request, errno),
+            // The client went away part way, so there is nobody to answer
+            Err(Err(e)) => Err(e),
+        }
+    }
+
+    // Deleting what isn't there succeeds, as with S3
+    fn delete_object(&self, request: &Request, mount: &Mount, path: &str, out: &mut Conn) -> io::Result<()> {
+        let mut fs = mount.fs.locked();
+        let result = fs.refuses_changes(path).and_then(|()| match fs.path_attr(path) {
+            Ok(Some(attr)) if attr.kind != FileType::Directory => fs.unlink_path(path),
+            Ok(_) => Err(libc::ENOENT),
+            Err(e) => Err(e.errno()),
+        });
+        if result != Err(libc::ENOENT) {
+            fs.audit(CALLER, AuditOp::Delete, path, None, None, result.err());
+        }
+        drop(fs);
+        match result {
+            Ok(()) | Err(libc::ENOENT) => respond(out, "204 No Content", &[], b""),
+            Err(errno) => respond_errno(out, request, errno),
+        }
+    }
+}
+
+// Adds the keys under `dir`, at `path` on the DO, that could start with
+// `prefix` to `keys`, with their attributes. Directories below are walked
+// when `deep`, and are keys ending in a slash otherwise.
+fn walk(
+    fs: &mut RemoteFS,
+    mount: &Mount,
+    path: &str,
+    dir: &str,
+    prefix: &str,
+    deep: bool,
+    keys: &mut Vec<(String, Option<FileAttr>)>,
+) -> Result<(), i32> {
+    let children = match fs.list_all(path) {
+        Err(libc::ENOENT | libc::ENOTDIR) => return Ok(()),
+        children => children?,
+    };
+    for (name, _) in children {
+        let child = join(path, &name);
+        let key = match dir {
+            "" => mount.name(&name),
+            dir => format!("{}/{}", dir, mount.name(&name)),
+        };
+        match fs.path_attr(&child).map_err(|e| e.errno())? {
+            Some(attr) if attr.kind == FileType::Directory => {
+                let folder = format!("{}/", key);
+                if !folder.starts_with(prefix) && !prefix.starts_with(&folder) {
+                    continue;
+                }
+                match deep {
+                    true => walk(fs, mount, &child, &key, prefix, deep, keys)?,
+                    false => keys.push((folder, None)),
+                }
+            }
+            Some(attr) if key.starts_with(prefix) => keys.push((key, Some(attr))),
+            // Gone since it was listed, or not under the prefix
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// The bytes a Range header asks for, from the first up to the last, or None
+// if there are none of those in an object of `size` bytes
+fn byte_range(range: &str, size: u64) -> Option<(u64, u64)> {
+    let (first, last) = range.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (first.trim(), last.trim()) {
+        ("", suffix) => (size.saturating_sub(suffix.parse().ok()?), size),
+        (first, "") => (first.parse().ok()?, size),
+        (first, last) => (first.parse().ok()?, last.parse::<u64>().ok()?.saturating_add(1).min(size)),
+    };
+    (start < end).then_some((start, end))
+}
+
+fn element(xml: &mut String, name: &str, value: &str) {
+    *xml += &format!("<{0}>{1}</{0}>", name, escape(value));
+}
+
+fn respond_xml(out: &mut Conn, status: &str, xml: &str) -> io::Result<()> {
+    respond(out, status, &[("Content-Type", "application/xml".to_string())], xml.as_bytes())
+}
+
+// An S3 error document, left out in answer to HEAD
+fn respond_error(out: &mut Conn, request: &Request, status: &str, code: &str, message: &str) -> io::Result<()> {
+    let mut xml = format!("{}<Error>", PROLOG);
+    element(&mut xml, "Code", code);
+    element(&mut xml, "Message", message);
+    element(&mut xml, "Resource", &request.path);
+    xml += "</Error>\n";
+    let headers = [("Content-Type", "application/xml".to_string())];
+    respond_head(out, status, &headers, xml.as_bytes(), request.method == "HEAD")
+}
+
+fn respond_errno(out: &mut Conn, request: &Request, errno: i32) -> io::Result<()> {
+    let message = io::Error::from_raw_os_error(errno).to_string();
+    respond_error(out, request, http::status(errno), code(errno), &message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_and_ranges_are_as_s3_has_them() {
+        assert_eq!(bucket_name("/storage"), "storage");
+        assert_eq!(bucket_name("/data/logs/"), "logs");
+        assert_eq!(bucket_name("/"), "root");
+
+        assert_eq!(byte_range("bytes=0-99", 1000), Some((0, 100)));
+        assert_eq!(byte_range("bytes=900-", 1000), Some((900, 1000)));
+        assert_eq!(byte_range("bytes=-100", 1000), Some((900, 1000)));
+        assert_eq!(byte_range("bytes=-5000", 1000), Some((0, 1000)));
+        // A last byte past the end is the end
+        assert_eq!(byte_range("bytes=990-2000", 1000), Some((990, 1000)));
+        assert_eq!(byte_range("bytes=1000-", 1000), None);
+        assert_eq!(byte_range("bytes=5-2", 1000), None);
+        assert_eq!(byte_range("bytes=-0", 1000), None);
+        assert_eq!(byte_range("items=0-1", 1000), None);
+    }
+
+    #[test]
+    fn failures_are_named_as_s3_names_them() {
+        assert_eq!(code(libc::ENOENT), "NoSuchKey");
+        assert_eq!(code(libc::EROFS), "AccessDenied");
+        assert_eq!(code(libc::EISDIR), "InvalidRequest");
+        assert_eq!(code(libc::ENAMETOOLONG), "InvalidArgument");
+        assert_eq!(code(libc::EDQUOT), "EntityTooLarge");
+        assert_eq!(code(libc::ESHUTDOWN), "ServiceUnavailable");
+        assert_eq!(code(libc::EIO), "InternalError");
+        // The HTTP status goes with the name
+        for errno in [libc::ENOENT, libc::EACCES, libc::EINVAL, libc::EFBIG, libc::EAGAIN, libc::EOPNOTSUPP] {
+            let (status, code) = (http::status(errno), code(errno));
+            assert!(status != "500 Internal Server Error" && code != "InternalError", "{} {}", status, code);
+        }
+    }
+}
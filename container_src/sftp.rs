@@ -0,0 +1,700 @@
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{CStr, OsStr};
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use fuser::{FileAttr, FileType};
+use tracing::{debug, info, warn};
+
+use crate::audit::{AuditOp, Caller};
+use crate::endpoints::{Endpoints, Mount, Resolved};
+use crate::error::Locked;
+use crate::metrics::METRICS;
+use crate::ssh::{self, AuthorizedKeys, HostKey, Malformed, Reader, Subsystem, Writer};
+use crate::{join, MAX_IO_SIZE};
+
+// Only those in the container may connect, as with NFS
+pub use crate::nfs::parse_listen;
+
+const VERSION: u32 = 3;
+
+const FXP_INIT: u8 = 1;
+const FXP_VERSION: u8 = 2;
+const FXP_OPEN: u8 = 3;
+const FXP_CLOSE: u8 = 4;
+const FXP_READ: u8 = 5;
+const FXP_WRITE: u8 = 6;
+const FXP_LSTAT: u8 = 7;
+const FXP_FSTAT: u8 = 8;
+const FXP_SETSTAT: u8 = 9;
+const FXP_FSETSTAT: u8 = 10;
+const FXP_OPENDIR: u8 = 11;
+const FXP_READDIR: u8 = 12;
+const FXP_REMOVE: u8 = 13;
+const FXP_REALPATH: u8 = 16;
+const FXP_STAT: u8 = 17;
+const FXP_STATUS: u8 = 101;
+const FXP_HANDLE: u8 = 102;
+const FXP_DATA: u8 = 103;
+const FXP_NAME: u8 = 104;
+const FXP_ATTRS: u8 = 105;
+const FXP_EXTENDED: u8 = 200;
+
+const FX_OK: u32 = 0;
+const FX_EOF: u32 = 1;
+const FX_NO_SUCH_FILE: u32 = 2;
+const FX_PERMISSION_DENIED: u32 = 3;
+const FX_FAILURE: u32 = 4;
+const FX_BAD_MESSAGE: u32 = 5;
+const FX_OP_UNSUPPORTED: u32 = 8;
+
+const ATTR_SIZE: u32 = 0x1;
+const ATTR_UIDGID: u32 = 0x2;
+const ATTR_PERMISSIONS: u32 = 0x4;
+const ATTR_ACMODTIME: u32 = 0x8;
+const ATTR_EXTENDED: u32 = 0x8000_0000;
+
+const FXF_WRITE: u32 = 0x2;
+const FXF_APPEND: u32 = 0x4;
+const FXF_CREAT: u32 = 0x8;
+const FXF_TRUNC: u32 = 0x10;
+const FXF_EXCL: u32 = 0x20;
+
+// The extension OpenSSH's client uses to ask for a file to be made durable
+const FSYNC: &str = "fsync@openssh.com";
+
+// The largest request taken, as OpenSSH's server has it
+const MAX_REQUEST: usize = 256 * 1024;
+// Directory entries sent in one reply
+const READDIR_BATCH: usize = 100;
+
+// Who SFTP clients act as, as the name they log in with proves nothing
+const CALLER: Caller = Caller { uid: 65534, gid: 65534, pid: 0 };
+
+impl From<Malformed> for i32 {
+    fn from(_: Malformed) -> Self {
+        libc::EBADMSG
+    }
+}
+
+// Serves the mounts of `endpoints` over SFTP on `address`, with `host_key`,
+// to clients logging in with one of the `authorized` keys as the user the
+// daemon runs as, or as anyone with `any_user`
+pub fn spawn(
+    address: &str,
+    endpoints: Arc<Endpoints>,
+    host_key: HostKey,
+    authorized: AuthorizedKeys,
+    any_user: bool,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    let user = (!any_user).then(|| daemon_user().0);
+    let who = user.as_deref().unwrap_or("any user");
+    info!(
+        "Serving SFTP on {} to {} with {} authorized keys, host key {}",
+        listener.local_addr()?,
+        who,
+        authorized.len(),
+        host_key.fingerprint()
+    );
+    let (host_key, authorized, user) = (Arc::new(host_key), Arc::new(authorized), Arc::new(user));
+    thread::spawn(move || loop {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                let (endpoints, host_key, authorized, user) =
+                    (endpoints.clone(), host_key.clone(), authorized.clone(), user.clone());
+                thread::spawn(move || {
+                    debug!("SFTP client {} connected", peer);
+                    let open = || Session::new(endpoints.clone());
+                    if let Err(e) = ssh::serve(stream, &host_key, user.as_deref(), &authorized, "sftp", open) {
+                        debug!("SFTP client {} went away: {}", peer, e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept an SFTP client: {}", e),
+        }
+    });
+    Ok(())
+}
+
+// The keys kept at `path`, or in the daemon user's ~/.ssh/authorized_keys
+// without one. A file with none that will do is an error, as no one could
+// log in.
+pub fn authorized_keys(path: Option<&Path>) -> io::Result<AuthorizedKeys> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => daemon_user().1.join(".ssh/authorized_keys"),
+    };
+    let error = |e: io::Error| io::Error::new(e.kind(), format!("SFTP authorized keys {}: {}", path.display(), e));
+    let authorized = AuthorizedKeys::load(&path).map_err(error)?;
+    if authorized.is_empty() {
+        return Err(error(io::Error::new(io::ErrorKind::InvalidData, "no keys without options to log in with")));
+    }
+    Ok(authorized)
+}
+
+// The name and home of the user the daemon runs as, or its uid and $HOME
+// if it has no entry in the password database
+fn daemon_user() -> (String, PathBuf) {
+    let uid = unsafe { libc::getuid() };
+    let entry = unsafe { libc::getpwuid(uid) };
+    match entry.is_null() {
+        true => (uid.to_string(), std::env::var_os("HOME").unwrap_or_default().into()),
+        false => {
+            let name = unsafe { CStr::from_ptr((*entry).pw_name) }.to_string_lossy().into_owned();
+            let home = OsStr::from_bytes(unsafe { CStr::from_ptr((*entry).pw_dir) }.to_bytes());
+            (name, home.into())
+        }
+    }
+}
+
+// What a client's handle refers to
+enum Handle {
+    // A file, by the path the client opened it with, and the filesystem's
+    // handle for it. Writes to one opened to append go at its end.
+    File { name: String, fh: u64, append: bool },
+    // A directory, with what hasn't been sent of its listing
+    Dir { entries: VecDeque<(String, Option<FileAttr>)> },
+}
+
+// The SFTP subsystem of one channel. Requests are answered in the order they
+// come.
+struct Session {
+    endpoints: Arc<Endpoints>,
+    // What has come of a request that isn't all here yet
+    partial: Vec<u8>,
+    // A request too big to take came, so where the next one starts is unknown
+    lost: bool,
+    handles: HashMap<Vec<u8>, Handle>,
+    next_handle: u64,
+}
+
+impl Subsystem for Session {
+    fn data(&mut self, data: &[u8]) -> Vec<u8> {
+        if self.lost {
+            return Vec::new();
+        }
+        self.partial.extend_from_slice(data);
+        let mut answers = Vec::new();
+        while self.partial.len() >= 4 {
+            let len = u32::from_be_bytes([self.partial[0], self.partial[1], self.partial[2], self.partial[3]]) as usize;
+            // There is no telling where the next request starts after one
+            // too big to take, so the rest of what the client sends is dropped
+            if len > MAX_REQUEST {
+                warn!("SFTP request of {} bytes is too large, ignoring the client from here on", len);
+                self.partial = Vec::new();
+                self.lost = true;
+                break;
+            }
+            if self.partial.len() < 4 + len {
+                break;
+            }
+            let request: Vec<u8> = self.partial.drain(..4 + len).skip(4).collect();
+            let answer = self.call(&request);
+            answers.extend_from_slice(&(answer.len() as u32).to_be_bytes());
+            answers.extend_from_slice(&answer);
+        }
+        answers
+    }
+}
+
+impl Drop for Session {
+    // Whatever the client left open is closed, sending what it wrote
+    fn drop(&mut self) {
+        for (_, handle) in self.handles.drain() {
+            if let Handle::File { name, fh, .. } = handle {
+                if let Ok((mount, path)) = locate(&self.endpoints, &name) {
+                    if let Err(e) = mount.fs.locked().release_handle(fh) {
+                        warn!("Failed to flush SFTP writes to {} when the client went away: {}", path, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Session {
+    fn new(endpoints: Arc<Endpoints>) -> Self {
+        Self { endpoints, partial: Vec::new(), lost: false, handles: HashMap::new(), next_handle: 0 }
+    }
+
+    fn call(&mut self, request: &[u8]) -> Vec<u8> {
+        let mut args = Reader::new(request);
+        let mut reply = Writer::default();
+        let (kind, id) = match (args.byte(), args.u32()) {
+            (Ok(FXP_INIT), _) => {
+                reply.byte(FXP_VERSION);
+                reply.u32(VERSION);
+                reply.text(FSYNC);
+                reply.text("1");
+                return reply.0;
+            }
+            (Ok(kind), Ok(id)) => (kind, id),
+            // Without an id, the answer can only go to the first
+            _ => return status(0, libc::EBADMSG),
+        };
+        match self.request(kind, id, &mut args, &mut reply) {
+            Ok(()) => reply.0,
+            Err(errno) => status(id, errno),
+        }
+    }
+
+    fn request(&mut self, kind: u8, id: u32, args: &mut Reader, reply: &mut Writer) -> Result<(), i32> {
+        match kind {
+            FXP_REALPATH => self.realpath(id, args, reply),
+            FXP_STAT | FXP_LSTAT => self.stat(id, args, reply),
+            FXP_FSTAT => self.fstat(id, args, reply),
+            FXP_OPEN => self.open(id, args, reply),
+            FXP_READ => self.read(id, args, reply),
+            FXP_WRITE => self.write(id, args, reply),
+            FXP_CLOSE => self.close(id, args, reply),
+            FXP_SETSTAT => {
+                let name = self.absolute(args.text()?)?;
+                self.setstat(id, &name, args, reply)
+            }
+            FXP_FSETSTAT => {
+                let name = self.file(args.string()?)?.0;
+                self.setstat(id, &name, args, reply)
+            }
+            FXP_OPENDIR => self.opendir(id, args, reply),
+            FXP_READDIR => self.readdir(id, args, reply),
+            FXP_REMOVE => self.remove(id, args, reply),
+            FXP_EXTENDED => self.extended(id, args, reply),
+            // Directories, renames and links, none of which the DO has
+            _ => Err(libc::EOPNOTSUPP),
+        }
+    }
+
+    // Clients start in the first mount, and make paths of their own absolute
+    // from where this says they are
+    fn realpath(&mut self, id: u32, args: &mut Reader, reply: &mut Writer) -> Result<(), i32> {
+        let name = self.absolute(args.text()?)?;
+        reply.byte(FXP_NAME);
+        reply.u32(id);
+        reply.u32(1);
+        reply.text(&name);
+        reply.text(&name);
+        reply.u32(0);
+        Ok(())
+    }
+
+    fn stat(&mut self, id: u32, args: &mut Reader, reply: &mut Writer) -> Result<(), i32> {
+        let name = self.absolute(args.text()?)?;
+        let attr = match self.endpoints.resolve(&name)?.ok_or(libc::ENOENT)? {
+            Resolved::Above(_) => None,
+            Resolved::In { mount, path } => {
+                Some(mount.fs.locked().buffered_attr(&path).map_err(|e| e.errno())?.ok_or(libc::ENOENT)?)
+            }
+        };
+        reply.byte(FXP_ATTRS);
+        reply.u32(id);
+        attrs(reply, attr.as_ref());
+        Ok(())
+    }
+
+    fn fstat(&mut self, id: u32, args: &mut Reader, reply: &mut Writer) -> Result<(), i32> {
+        let (name, _, _) = self.file(args.string()?)?;
+        let (mount, path) = locate(&self.endpoints, &name)?;
+        let attr = mount.fs.locked().buffered_attr(&path).map_err(|e| e.errno())?.ok_or(libc::ENOENT)?;
+        reply.byte(FXP_ATTRS);
+        reply.u32(id);
+        attrs(reply, Some(&attr));
+        Ok(())
+    }
+
+    fn open(&mut self, id: u32, args: &mut Reader, reply: &mut Writer) -> Result<(), i32> {
+        let (name, flags) = (self.absolute(args.text()?)?, args.u32()?);
+        let (_, permissions) = read_attrs(args)?;
+        let endpoints = self.endpoints.clone();
+        let (mount, path) = locate(&endpoints, &name)?;
+        let mut fs = mount.fs.locked();
+        if fs.draining() {
+            return Err(libc::ESHUTDOWN);
+        }
+        let fh = fs.next_handle();
+        if fs.fsctl.is(&path) {
+            let status = fs.control_status();
+            fs.fsctl.open(fh, status);
+        } else {
+            let existing = fs.path_attr(&path).map_err(|e| e.errno())?;
+            if existing.as_ref().is_some_and(|attr| attr.kind == FileType::Directory) {
+                return Err(libc::EISDIR);
+            }
+            if flags & FXF_WRITE != 0 {
+                fs.refuses_changes(&path)?;
+                let create = match &existing {
+                    Some(_) if flags & FXF_CREAT != 0 && flags & FXF_EXCL != 0 => return Err(libc::EEXIST),
+                    Some(attr) => flags & FXF_TRUNC != 0 && attr.size != 0,
+                    None if flags & FXF_CREAT != 0 => true,
+                    None => return Err(libc::ENOENT),
+                };
+                if create {
+                    // Writes buffered for what is being replaced go first
+                    let created = fs.sync_path(&path).and_then(|()| fs.create_file(&path, permissions));
+                    fs.audit(CALLER, AuditOp::Create, &path, None, None, created.as_ref().err().map(|e| e.errno()));
+                    created.map_err(|e| e.errno())?;
+                }
+            } else if existing.is_none() {
+                return Err(libc::ENOENT);
+            }
+        }
+        drop(fs);
+        let handle = self.handle(Handle::File { name, fh, append: flags & FXF_APPEND != 0 });
+        reply.byte(FXP_HANDLE);
+        reply.u32(id);
+        reply.string(&handle);
+        Ok(())
+    }
+
+    fn read(&mut self, id: u32, args: &mut Reader, reply: &mut Writer) -> Result<(), i32> {
+        let (handle, offset, len) = (args.string()?, args.u64()?, args.u32()?);
+        let (name, fh, _) = self.file(handle)?;
+        let (mount, path) = locate(&self.endpoints, &name)?;
+        let mut read = Err(libc::EIO);
+        mount.fs.locked().read_data(fh, &path, offset, len.min(MAX_IO_SIZE as u32) as u64, |result| {
+            read = match result {
+                // Nothing at the offset is the end of the file
+                Ok([]) => {
+                    *reply = Writer(eof(id));
+                    Ok(())
+                }
+                Ok(data) => {
+                    reply.byte(FXP_DATA);
+                    reply.u32(id);
+                    reply.string(data);
+                    Ok(())
+                }
+                Err(e) => Err(e.errno()),
+            }
+        });
+        read
+    }
+
+    fn write(&mut self, id: u32, args: &mut Reader, reply: &mut Writer) -> Result<(), i32> {
+        let (handle, offset, data) = (args.string()?, args.u64()?, args.string()?);
+        let (name, fh, append) = self.file(handle)?;
+        let (mount, path) = locate(&self.endpoints, &name)?;
+        let mut fs = mount.fs.locked();
+        if fs.fsctl.is_open(fh) {
+            let result = fs.control(&String::from_utf8_lossy(data));
+            let errno = result.as_ref().err().map(|(errno, _)| *errno);
+            fs.fsctl.answer(fh, &result.map_err(|(_, message)| message));
+            errno.map_or(Ok(()), Err)?;
+        } else {
+            fs.refuses_changes(&path)?;
+            let offset = match append {
+                true => fs.buffered_attr(&path).map_err(|e| e.errno())?.map_or(0, |attr| attr.size),
+                false => offset,
+            };
+            let result = fs.write_data(fh, &path, offset, data);
+            fs.audit(CALLER, AuditOp::Write, &path, Some(offset), Some(data.len() as u64), result.err());
+            result?;
+            METRICS.written(data.len());
+        }
+        *reply = Writer(status(id, 0));
+        Ok(())
+    }
+
+    // The handle is gone whether or not what it wrote could be sent
+    fn close(&mut self, id: u32, args: &mut Reader, reply: &mut Writer) -> Result<(), i32> {
+        let handle = self.handles.remove(args.string()?).ok_or(libc::EBADF)?;
+        if let Handle::File { name, fh, .. } = handle {
+            let (mount, _) = locate(&self.endpoints, &name)?;
+            mount.fs.locked().release_handle(fh).map_err(|e| e.errno())?;
+        }
+        *reply = Writer(status(id, 0));
+        Ok(())
+    }
+
+    // Only truncation is kept, which is how some clients replace a file, and
+    // permissions when the DO keeps modes. Owners and times are accepted and
+    // forgotten, as the DO has no place for them.
+    fn setstat(&mut self, id: u32, name: &str, args: &mut Reader, reply: &mut Writer) -> Result<(), i32> {
+        let (size, permissions) = read_attrs(args)?;
+        if size.is_some() || permissions.is_some() {
+            let (mount, path) = locate(&self.endpoints, name)?;
+            let mut fs = mount.fs.locked();
+            let control = fs.fsctl.is(&path);
+            if let Some(permissions) = permissions.filter(|_| !control && fs.client.has_feature("modes")) {
+                fs.chmod(&path, permissions)?;
+            }
+            if let Some(size) = size.filter(|_| !control) {
+                let attr = fs.buffered_attr(&path).map_err(|e| e.errno())?.ok_or(libc::ENOENT)?;
+                if size != attr.size {
+                    fs.refuses_changes(&path)?;
+                    fs.fits(size)?;
+                    if size != 0 || attr.kind != FileType::RegularFile {
+                        return Err(libc::EOPNOTSUPP);
+                    }
+                    fs.sync_path(&path).map_err(|e| e.errno())?;
+                    let created = fs.create_file(&path, None);
+                    fs.audit(CALLER, AuditOp::Create, &path, None, None, created.as_ref().err().map(|e| e.errno()));
+                    created.map_err(|e| e.errno())?;
+                }
+            }
+        }
+        *reply = Writer(status(id, 0));
+        Ok(())
+    }
+
+    // The whole listing is taken when the directory is opened, and handed
+    // out a batch at a time
+    fn opendir(&mut self, id: u32, args: &mut Reader, reply: &mut Writer) -> Result<(), i32> {
+        let name = self.absolute(args.text()?)?;
+        let entries = match self.endpoints.resolve(&name)?.ok_or(libc::ENOENT)? {
+            Resolved::Above(names) => names.into_iter().map(|name| (name, None)).collect(),
+            Resolved::In { mount, path } => {
+                let mut fs = mount.fs.locked();
+                match fs.path_attr(&path).map_err(|e| e.errno())?.ok_or(libc::ENOENT)? {
+                    attr if attr.kind != FileType::Directory => return Err(libc::ENOTDIR),
+                    _ => {}
+                }
+                let mut entries = VecDeque::new();
+                for (child, _) in fs.list_all(&path)? {
+                    // Gone since it was listed
+                    if let Ok(Some(attr)) = fs.path_attr(&join(&path, &child)) {
+                        entries.push_back((mount.name(&child), Some(attr)));
+                    }
+                }
+                entries
+            }
+        };
+        let handle = self.handle(Handle::Dir { entries });
+        reply.byte(FXP_HANDLE);
+        reply.u32(id);
+        reply.string(&handle);
+        Ok(())
+    }
+
+    fn readdir(&mut self, id: u32, args: &mut Reader, reply: &mut Writer) -> Result<(), i32> {
+        let Some(Handle::Dir { entries }) = self.handles.get_mut(args.string()?) else {
+            return Err(libc::EBADF);
+        };
+        if entries.is_empty() {
+            *reply = Writer(eof(id));
+            return Ok(());
+        }
+        let batch: Vec<_> = entries.drain(..entries.len().min(READDIR_BATCH)).collect();
+        reply.byte(FXP_NAME);
+        reply.u32(id);
+        reply.u32(batch.len() as u32);
+        for (name, attr) in &batch {
+            reply.text(name);
+            reply.text(&longname(name, attr.as_ref()));
+            attrs(reply, attr.as_ref());
+        }
+        Ok(())
+    }
+
+    // Directories can't be removed, as the DO has none
+    fn remove(&mut self, id: u32, args: &mut Reader, reply: &mut Writer) -> Result<(), i32> {
+        let name = self.absolute(args.text()?)?;
+        let (mount, path) = locate(&self.endpoints, &name)?;
+        let mut fs = mount.fs.locked();
+        let result = fs.refuses_changes(&path).and_then(|()| match fs.path_attr(&path) {
+            Ok(Some(attr)) if attr.kind == FileType::Directory => Err(libc::EPERM),
+            Ok(Some(_)) => fs.unlink_path(&path),
+            Ok(None) => Err(libc::ENOENT),
+            Err(e) => Err(e.errno()),
+        });
+        if result != Err(libc::ENOENT) {
+            fs.audit(CALLER, AuditOp::Delete, &path, None, None, result.err());
+        }
+        result?;
+        *reply = Writer(status(id, 0));
+        Ok(())
+    }
+
+    fn extended(&mut self, id: u32, args: &mut Reader, reply: &mut Writer) -> Result<(), i32> {
+        if args.text()? != FSYNC {
+            return Err(libc::EOPNOTSUPP);
+        }
+        let (name, _, _) = self.file(args.string()?)?;
+        let (mount, path) = locate(&self.endpoints, &name)?;
+        mount.fs.locked().sync_path(&path).map_err(|e| e.errno())?;
+        *reply = Writer(status(id, 0));
+        Ok(())
+    }
+
+    fn handle(&mut self, handle: Handle) -> Vec<u8> {
+        self.next_handle += 1;
+        let name = self.next_handle.to_string().into_bytes();
+        self.handles.insert(name.clone(), handle);
+        name
+    }
+
+    // The path a file handle was opened with, its filesystem handle, and
+    // whether it appends
+    fn file(&self, handle: &[u8]) -> Result<(String, u64, bool), i32> {
+        match self.handles.get(handle) {
+            Some(Handle::File { name, fh, append }) => Ok((name.clone(), *fh, *append)),
+            _ => Err(libc::EBADF),
+        }
+    }
+
+    // `name` as an absolute path without dots in it, a relative one being
+    // taken from the first mount point
+    fn absolute(&self, name: &str) -> Result<String, i32> {
+        if name.contains('\0') {
+            return Err(libc::EINVAL);
+        }
+        let base = match name.starts_with('/') {
+            true => "",
+            false => self.endpoints.mounts.first().map_or("/", |mount| mount.point.as_str()),
+        };
+        let mut parts = Vec::new();
+        for part in base.split('/').chain(name.split('/')) {
+            match part {
+                "" | "." => {}
+                ".." => {
+                    parts.pop();
+                }
+                part => parts.push(part),
+            }
+        }
+        Ok(format!("/{}", parts.join("/")))
+    }
+}
+
+// The mount the absolute path `name` is in, and the path on the DO. Above
+// the mounts there are only directories.
+fn locate<'a>(endpoints: &'a Endpoints, name: &str) -> Result<(&'a Mount, String), i32> {
+    match endpoints.resolve(name)? {
+        Some(Resolved::In { mount, path }) => Ok((mount, path)),
+        Some(Resolved::Above(_)) => Err(libc::EISDIR),
+        None => Err(libc::ENOENT),
+    }
+}
+
+fn status(id: u32, errno: i32) -> Vec<u8> {
+    let (code, message) = match errno {
+        0 => (FX_OK, "Success"),
+        libc::ENOENT => (FX_NO_SUCH_FILE, "No such file"),
+        libc::EACCES | libc::EPERM | libc::EROFS => (FX_PERMISSION_DENIED, "Permission denied"),
+        libc::EBADMSG => (FX_BAD_MESSAGE, "Bad message"),
+        libc::EOPNOTSUPP | libc::ENOSYS => (FX_OP_UNSUPPORTED, "Operation unsupported"),
+        _ => (FX_FAILURE, "Failure"),
+    };
+    status_message(id, code, message)
+}
+
+fn eof(id: u32) -> Vec<u8> {
+    status_message(id, FX_EOF, "End of file")
+}
+
+fn status_message(id: u32, code: u32, message: &str) -> Vec<u8> {
+    let mut reply = Writer::default();
+    reply.byte(FXP_STATUS);
+    reply.u32(id);
+    reply.u32(code);
+    reply.text(message);
+    reply.text("");
+    reply.0
+}
+
+// The attributes of a file, or of a directory above the mounts when there
+// are none
+fn attrs(reply: &mut Writer, attr: Option<&FileAttr>) {
+    let Some(attr) = attr else {
+        reply.u32(ATTR_PERMISSIONS);
+        reply.u32(libc::S_IFDIR | 0o755);
+        return;
+    };
+    reply.u32(ATTR_SIZE | ATTR_UIDGID | ATTR_PERMISSIONS | ATTR_ACMODTIME);
+    reply.u64(attr.size);
+    reply.u32(attr.uid);
+    reply.u32(attr.gid);
+    reply.u32(mode(attr));
+    reply.u32(seconds(attr.atime));
+    reply.u32(seconds(attr.mtime));
+}
+
+// The size and permissions a client asks a file to have, of the attributes
+// it sends. The rest have nowhere to go on the DO.
+fn read_attrs(args: &mut Reader) -> Result<(Option<u64>, Option<u32>), Malformed> {
+    let flags = args.u32()?;
+    let size = match flags & ATTR_SIZE {
+        0 => None,
+        _ => Some(args.u64()?),
+    };
+    if flags & ATTR_UIDGID != 0 {
+        args.fixed(8)?;
+    }
+    let permissions = match flags & ATTR_PERMISSIONS {
+        0 => None,
+        _ => Some(args.u32()?),
+    };
+    if flags & ATTR_ACMODTIME != 0 {
+        args.fixed(8)?;
+    }
+    if flags & ATTR_EXTENDED != 0 {
+        for _ in 0..args.u32()? {
+            args.string()?;
+            args.string()?;
+        }
+    }
+    Ok((size, permissions))
+}
+
+fn mode(attr: &FileAttr) -> u32 {
+    let kind = match attr.kind {
+        FileType::Directory => libc::S_IFDIR,
+        FileType::Symlink => libc::S_IFLNK,
+        _ => libc::S_IFREG,
+    };
+    kind | attr.perm as u32
+}
+
+fn seconds(time: SystemTime) -> u32 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs() as u32)
+}
+
+// The line `ls -l` would show for the entry, which clients show as it is
+fn longname(name: &str, attr: Option<&FileAttr>) -> String {
+    let (mode, nlink, uid, gid, size, mtime) = match attr {
+        Some(attr) => (mode(attr), attr.nlink, attr.uid, attr.gid, attr.size, attr.mtime),
+        None => (libc::S_IFDIR | 0o755, 1, 0, 0, 0, UNIX_EPOCH),
+    };
+    let kind = if mode & libc::S_IFMT == libc::S_IFDIR { 'd' } else { '-' };
+    let permissions: String = (0..9)
+        .map(|bit| match mode & (0o400 >> bit) {
+            0 => '-',
+            _ => ['r', 'w', 'x'][bit % 3],
+        })
+        .collect();
+    // Minutes are as fine as listings go
+    let time = humantime::format_rfc3339_seconds(mtime).to_string().replace('T', " ");
+    format!("{}{} {:>3} {:<8} {:<8} {:>10} {} {}", kind, permissions, nlink, uid, gid, size, &time[..16], name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes_and_listings_encode_as_clients_read_them() {
+        let mut encoded = Writer::default();
+        encoded.u32(ATTR_SIZE | ATTR_PERMISSIONS | ATTR_ACMODTIME | ATTR_EXTENDED);
+        encoded.u64(42);
+        encoded.u32(0o100644);
+        encoded.u32(1);
+        encoded.u32(2);
+        encoded.u32(1);
+        encoded.text("name@example.com");
+        encoded.text("value");
+        encoded.byte(7);
+        let mut args = Reader::new(&encoded.0);
+        assert_eq!(read_attrs(&mut args), Ok((Some(42), Some(0o100644))));
+        assert_eq!(args.byte(), Ok(7));
+        // Attributes cut short are malformed, and answered as such
+        assert_eq!(read_attrs(&mut Reader::new(&encoded.0[..10])), Err(Malformed));
+        assert_eq!(&status(9, libc::EBADMSG)[..13], [FXP_STATUS, 0, 0, 0, 9, 0, 0, 0, 5, 0, 0, 0, 11]);
+
+        assert_eq!(longname("docs", None), "drwxr-xr-x   1 0        0                 0 1970-01-01 00:00 docs");
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use crate::error::DaemonError;
+use crate::operation::FsOperation;
+use crate::{join, FSMessage, RemoteFSClient};
+
+// The directory at the root of every mount that the DO's snapshots show up in.
+// It is left out of the root's listing, so only those who know of it find it.
+pub const SNAPSHOTS_DIR: &str = ".snapshots";
+
+// How long the DO's list of snapshots is used before asking again. What is in
+// a snapshot never changes, so listings of its directories are kept until
+// there are too many.
+const SNAPSHOT_LIST_TTL: Duration = Duration::from_secs(5);
+const MAX_LISTINGS: usize = 256;
+
+// An entry of a directory in a snapshot, as the DO lists it
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct SnapshotEntry {
+    pub name: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(rename = "isDir", default)]
+    pub is_dir: bool,
+}
+
+// What a path under a mount's snapshot directory refers to
+#[derive(Debug, PartialEq)]
+pub enum SnapshotPath {
+    // The snapshot directory itself, listing every snapshot
+    Root,
+    // `path` on the DO as it was in the snapshot taken at `at`, in ms since
+    // the epoch. The snapshot's own directory is the mount's root.
+    In { at: u64, path: String },
+}
+
+// Snapshots are named for when they were taken, to the millisecond
+pub fn name(at: u64) -> String {
+    humantime::format_rfc3339_millis(UNIX_EPOCH + Duration::from_millis(at)).to_string()
+}
+
+// Only names as `name` writes them, so each snapshot has exactly one
+fn parse_name(name: &str) -> Option<u64> {
+    let time = humantime::parse_rfc3339(name).ok()?;
+    let at = time.duration_since(UNIX_EPOCH).ok()?.as_millis() as u64;
+    (self::name(at) == name).then_some(at)
+}
+
+// The snapshot tree of one mount, and what has been fetched of it
+pub struct Snapshots {
+    // The path on the DO the mount shows, which is what its snapshots show too
+    root: String,
+    dir: String,
+    list: Option<(Instant, Vec<u64>)>,
+    listings: HashMap<(u64, String), Vec<SnapshotEntry>>,
+}
+
+impl Snapshots {
+    pub fn new(root: &str) -> Self {
+        Self {
+            root: root.to_string(),
+            dir: join(root, SNAPSHOTS_DIR),
+            list: None,
+            listings: HashMap::new(),
+        }
+    }
+
+    // What a mount path refers to, or None when it is outside the snapshot tree
+    pub fn resolve(&self, path: &str) -> Option<SnapshotPath> {
+        if path == self.dir {
+            return Some(SnapshotPath::Root);
+        }
+        let rest = path.strip_prefix(&self.dir)?.strip_prefix('/')?;
+        let (snapshot, inner) = match rest.split_once('/') {
+            Some((snapshot, inner)) => (snapshot, join(&self.root, inner)),
+            None => (rest, self.root.clone()),
+        };
+        Some(SnapshotPath::In { at: parse_name(snapshot)?, path: inner })
+    }
+
+    // When each of the DO's snapshots was taken, oldest first
+    pub async fn list(&mut self, client: &RemoteFSClient) -> Result<Vec<u64>, DaemonError> {
+        if let Some((fetched, list)) = &self.list {
+            if fetched.elapsed() < SNAPSHOT_LIST_TTL {
+                return Ok(list.clone());
+            }
+        }
+        let response = client
+            .send_message(FSMessage {
+                operation: FsOperation::Snapshots,
+                path: "/".to_string(),
+                ..Default::default()
+            })
+            .await?;
+        let mut list = response.snapshots;
+        list.sort_unstable();
+        self.list = Some((Instant::now(), list.clone()));
+        Ok(list)
+    }
+
+    // The directory at `path` in the snapshot taken at `at`
+    pub async fn listing(
+        &mut self,
+        client: &RemoteFSClient,
+        at: u64,
+        path: &str,
+    ) -> Result<Vec<SnapshotEntry>, DaemonError> {
+        let key = (at, path.to_string());
+        if let Some(entries) = self.listings.get(&key) {
+            return Ok(entries.clone());
+        }
+        let response = client
+            .send_message(FSMessage {
+                operation: FsOperation::Snapshots,
+                path: path.to_string(),
+                snapshot: Some(at),
+                ..Default::default()
+            })
+            .await?;
+        if self.listings.len() >= MAX_LISTINGS {
+            self.listings.clear();
+        }
+        self.listings.insert(key, response.entries.clone());
+        Ok(response.entries)
+    }
+
+    // The entry for `path` in the snapshot taken at `at`, or None if the
+    // snapshot doesn't have it. The snapshot's root is always a directory.
+    pub async fn entry(
+        &mut self,
+        client: &RemoteFSClient,
+        at: u64,
+        path: &str,
+    ) -> Result<Option<SnapshotEntry>, DaemonError> {
+        if path == self.root {
+            return Ok(Some(SnapshotEntry { name: name(at), size: 0, is_dir: true }));
+        }
+        let (parent, file) = match path.rsplit_once('/') {
+            Some(("", file)) => ("/", file),
+            Some((parent, file)) => (parent, file),
+            None => return Ok(None),
+        };
+        let entries = self.listing(client, at, parent).await?;
+        Ok(entries.into_iter().find(|entry| entry.name == file))
+    }
+}
+
+// Up to `size` bytes from `offset` of `path` as it was in the snapshot taken at `at`
+pub async fn read(
+    client: &RemoteFSClient,
+    at: u64,
+    path: &str,
+    offset: u64,
+    size: u64,
+) -> Result<Vec<u8>, DaemonError> {
+    let response = client
+        .send_message(FSMessage {
+            operation: FsOperation::SnapshotRead,
+            path: path.to_string(),
+            snapshot: Some(at),
+            offset: Some(offset),
+            size: Some(size),
+            ..Default::default()
+        })
+        .await?;
+    Ok(response.data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_paths_resolve_under_the_mount_root() {
+        let at = 1_760_000_000_123;
+        let snapshot = name(at);
+        assert_eq!(snapshot, "2025-10-09T08:53:20.123Z");
+
+        let snapshots = Snapshots::new("/");
+        assert_eq!(snapshots.resolve("/.snapshots"), Some(SnapshotPath::Root));
+        assert_eq!(
+            snapshots.resolve(&format!("/.snapshots/{}", snapshot)),
+            Some(SnapshotPath::In { at, path: "/".to_string() })
+        );
+        assert_eq!(
+            snapshots.resolve(&format!("/.snapshots/{}/a/b.txt", snapshot)),
+            Some(SnapshotPath::In { at, path: "/a/b.txt".to_string() })
+        );
+        assert_eq!(snapshots.resolve("/.snapshots/yesterday"), None);
+        assert_eq!(snapshots.resolve("/.snapshots/2025-10-09T08:53:20Z"), None);
+        assert_eq!(snapshots.resolve("/.snapshotsx"), None);
+        assert_eq!(snapshots.resolve("/a.txt"), None);
+
+        let snapshots = Snapshots::new("/data");
+        assert_eq!(
+            snapshots.resolve(&format!("/data/.snapshots/{}/c", snapshot)),
+            Some(SnapshotPath::In { at, path: "/data/c".to_string() })
+        );
+        assert_eq!(snapshots.resolve("/.snapshots"), None);
+    }
+}
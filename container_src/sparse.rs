@@ -0,0 +1,49 @@
+// A DO that agreed to `sparse` leaves the all-zero runs of a file out of read
+// responses, listing them as holes: `[offset, length]` pairs in file offsets,
+// in order, within the range read. The data is what is between them.
+
+// The bytes a read of `size` at `offset` covers, with the holes of the response
+// filled back in with zeros
+pub fn fill(offset: u64, size: u64, data: &[u8], holes: &[[u64; 2]]) -> Result<Vec<u8>, String> {
+    let mut filled = Vec::with_capacity(size.min(data.len() as u64 + hole_bytes(holes)) as usize);
+    let mut rest = data;
+    let mut at = offset;
+    for &[start, len] in holes {
+        if start < at || start.saturating_add(len) > offset.saturating_add(size) {
+            return Err(format!("hole at {} of {} bytes is out of order or outside the read", start, len));
+        }
+        let before = (start - at) as usize;
+        if before > rest.len() {
+            return Err(format!("hole at {} leaves data missing before it", start));
+        }
+        let (data, after) = rest.split_at(before);
+        filled.extend_from_slice(data);
+        filled.resize(filled.len() + len as usize, 0);
+        rest = after;
+        at = start + len;
+    }
+    filled.extend_from_slice(rest);
+    Ok(filled)
+}
+
+// How many bytes the holes stand for
+pub fn hole_bytes(holes: &[[u64; 2]]) -> u64 {
+    holes.iter().map(|[_, len]| len).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holes_are_filled_with_zeros_between_the_data() {
+        assert_eq!(fill(10, 8, b"abc", &[[12, 3], [16, 2]]).unwrap(), b"ab\0\0\0c\0\0");
+        assert_eq!(fill(0, 4, b"", &[[0, 4]]).unwrap(), [0; 4]);
+        assert_eq!(fill(0, 4, b"abcd", &[]).unwrap(), b"abcd");
+        // A short read ends where the file does
+        assert_eq!(fill(0, 100, b"ab", &[[2, 3]]).unwrap(), b"ab\0\0\0");
+        assert!(fill(10, 8, b"abc", &[[16, 2], [12, 3]]).is_err());
+        assert!(fill(10, 8, b"abc", &[[16, 4]]).is_err());
+        assert!(fill(0, 8, b"a", &[[4, 1]]).is_err());
+    }
+}
@@ -0,0 +1,441 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use tracing::warn;
+
+use crate::error::Locked;
+
+// Spill files start with one of these, then a CRC-32 of the rest of the file
+const BLOCK_MAGIC: &[u8; 4] = b"FSB1";
+const DIRTY_MAGIC: &[u8; 4] = b"FSD1";
+
+// Spilled dirty data as (offset, bytes) pairs in the order they were written
+pub type Extents = Vec<(u64, Vec<u8>)>;
+
+struct SpilledBlock {
+    seq: u64,
+    size: u64,
+    last_used: u64,
+}
+
+// Dirty extents of one file written to disk to make room in memory
+pub struct DirtySegment {
+    pub seq: u64,
+    pub path: String,
+    // End of the furthest extent in the segment
+    pub end: u64,
+}
+
+#[derive(Default)]
+struct SpillState {
+    blocks: HashMap<(String, u64), SpilledBlock>,
+    lru: BTreeMap<u64, (String, u64)>,
+    clock: u64,
+    // Size on disk of each dirty segment, by sequence number
+    dirty: HashMap<u64, u64>,
+    // Dirty segments left behind by a previous run, oldest first
+    recovered: Vec<DirtySegment>,
+    used: u64,
+    next_seq: u64,
+}
+
+// Local disk overflow for the block cache and the write-back buffer. Each
+// file is written under a temporary name and renamed into place, and carries
+// its own key and checksum, so after a crash the index is rebuilt from the
+// directory and torn or damaged files are dropped instead of served. Clean
+// blocks are evicted to make room for dirty data, never the other way round.
+pub struct SpillDir {
+    blocks_dir: PathBuf,
+    dirty_dir: PathBuf,
+    budget: u64,
+    state: Mutex<SpillState>,
+}
+
+impl SpillDir {
+    pub fn open(dir: &str, budget: u64) -> io::Result<Self> {
+        let blocks_dir = Path::new(dir).join("blocks");
+        let dirty_dir = Path::new(dir).join("dirty");
+        fs::create_dir_all(&blocks_dir)?;
+        fs::create_dir_all(&dirty_dir)?;
+
+        let mut state = SpillState::default();
+        let mut blocks = Vec::new();
+        for (seq, file) in scan(&blocks_dir)? {
+            match read_file(&file, BLOCK_MAGIC).as_deref().and_then(parse_block) {
+                Some((key, index, _)) => blocks.push((seq, key, index, file.metadata()?.len())),
+                None => fs::remove_file(&file)?,
+            }
+            state.next_seq = state.next_seq.max(seq + 1);
+        }
+        // Older files are evicted first, as they would have been before the restart
+        blocks.sort_by_key(|(seq, ..)| *seq);
+        for (seq, key, index, size) in blocks {
+            state.clock += 1;
+            state.used += size;
+            state.lru.insert(state.clock, (key.clone(), index));
+            let block = SpilledBlock {
+                seq,
+                size,
+                last_used: state.clock,
+            };
+            state.blocks.insert((key, index), block);
+        }
+
+        for (seq, file) in scan(&dirty_dir)? {
+            match read_file(&file, DIRTY_MAGIC).as_deref().and_then(parse_dirty) {
+                Some((path, extents)) => {
+                    let end = extents.iter().map(|(offset, data)| offset + data.len() as u64).max();
+                    let size = file.metadata()?.len();
+                    state.used += size;
+                    state.dirty.insert(seq, size);
+                    state.recovered.push(DirtySegment {
+                        seq,
+                        path,
+                        end: end.unwrap_or(0),
+                    });
+                }
+                None => {
+                    warn!("Discarding damaged spilled write-back data in {}", file.display());
+                    fs::remove_file(&file)?;
+                }
+            }
+            state.next_seq = state.next_seq.max(seq + 1);
+        }
+        state.recovered.sort_by_key(|segment| segment.seq);
+
+        let spill = Self {
+            blocks_dir,
+            dirty_dir,
+            budget,
+            state: Mutex::new(state),
+        };
+        spill.make_room(&mut spill.state.locked(), 0);
+        Ok(spill)
+    }
+
+    // Keeps a block evicted from memory. Content keys never change meaning,
+    // so a block already on disk is only marked as recently used.
+    pub fn store_block(&self, key: &str, index: u64, data: &[u8]) {
+        let mut state = self.state.locked();
+        let id = (key.to_string(), index);
+        if state.blocks.contains_key(&id) {
+            Self::touch(&mut state, &id);
+            return;
+        }
+
+        let mut body = Vec::with_capacity(data.len() + key.len() + 12);
+        body.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        body.extend_from_slice(key.as_bytes());
+        body.extend_from_slice(&index.to_le_bytes());
+        body.extend_from_slice(data);
+        let size = (body.len() + 8) as u64;
+        if !self.make_room(&mut state, size) {
+            return;
+        }
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        if let Err(e) = write_file(&self.blocks_dir, seq, BLOCK_MAGIC, &body, false) {
+            warn!("Failed to spill cache block to disk: {}", e);
+            return;
+        }
+        state.clock += 1;
+        state.used += size;
+        let clock = state.clock;
+        state.lru.insert(clock, id.clone());
+        state.blocks.insert(
+            id,
+            SpilledBlock {
+                seq,
+                size,
+                last_used: clock,
+            },
+        );
+    }
+
+    pub fn load_block(&self, key: &str, index: u64) -> Option<Vec<u8>> {
+        let mut state = self.state.locked();
+        let id = (key.to_string(), index);
+        let seq = state.blocks.get(&id)?.seq;
+        let body = read_file(&self.blocks_dir.join(seq.to_string()), BLOCK_MAGIC);
+        match body.as_deref().and_then(parse_block) {
+            Some((stored_key, stored_index, data)) if stored_key == key && stored_index == index => {
+                Self::touch(&mut state, &id);
+                Some(data.to_vec())
+            }
+            _ => {
+                self.remove_block(&mut state, &id);
+                None
+            }
+        }
+    }
+
+    pub fn remove_blocks(&self, key: &str) {
+        let mut state = self.state.locked();
+        let ids: Vec<(String, u64)> = state.blocks.keys().filter(|(k, _)| k == key).cloned().collect();
+        for id in ids {
+            self.remove_block(&mut state, &id);
+        }
+    }
+
+    // Writes dirty extents durably, or returns None when they don't fit
+    pub fn store_dirty(&self, path: &str, extents: &BTreeMap<u64, Vec<u8>>) -> Option<DirtySegment> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        body.extend_from_slice(path.as_bytes());
+        for (offset, data) in extents {
+            body.extend_from_slice(&offset.to_le_bytes());
+            body.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            body.extend_from_slice(data);
+        }
+        let size = (body.len() + 8) as u64;
+
+        let mut state = self.state.locked();
+        if !self.make_room(&mut state, size) {
+            return None;
+        }
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        if let Err(e) = write_file(&self.dirty_dir, seq, DIRTY_MAGIC, &body, true) {
+            warn!("Failed to spill write-back data for {} to disk: {}", path, e);
+            return None;
+        }
+        state.used += size;
+        state.dirty.insert(seq, size);
+
+        let end = extents.iter().map(|(offset, data)| offset + data.len() as u64).max();
+        Some(DirtySegment {
+            seq,
+            path: path.to_string(),
+            end: end.unwrap_or(0),
+        })
+    }
+
+    pub fn load_dirty(&self, seq: u64) -> Option<Extents> {
+        let body = read_file(&self.dirty_dir.join(seq.to_string()), DIRTY_MAGIC)?;
+        parse_dirty(&body).map(|(_, extents)| extents)
+    }
+
+    // Deletes a segment once its data has reached the DO
+    pub fn remove_dirty(&self, seq: u64) {
+        let mut state = self.state.locked();
+        if let Some(size) = state.dirty.remove(&seq) {
+            state.used -= size;
+            let _ = fs::remove_file(self.dirty_dir.join(seq.to_string()));
+        }
+    }
+
+    // Dirty segments found at startup, handed out once so they can be replayed
+    pub fn take_recovered(&self) -> Vec<DirtySegment> {
+        std::mem::take(&mut self.state.locked().recovered)
+    }
+
+    fn touch(state: &mut SpillState, id: &(String, u64)) {
+        state.clock += 1;
+        let clock = state.clock;
+        if let Some(block) = state.blocks.get_mut(id) {
+            state.lru.remove(&block.last_used);
+            block.last_used = clock;
+            state.lru.insert(clock, id.clone());
+        }
+    }
+
+    fn remove_block(&self, state: &mut SpillState, id: &(String, u64)) {
+        if let Some(block) = state.blocks.remove(id) {
+            state.lru.remove(&block.last_used);
+            state.used -= block.size;
+            let _ = fs::remove_file(self.blocks_dir.join(block.seq.to_string()));
+        }
+    }
+
+    // Evicts clean blocks until `size` more bytes fit, false if they never will
+    fn make_room(&self, state: &mut SpillState, size: u64) -> bool {
+        while state.used + size > self.budget {
+            let Some((_, id)) = state.lru.pop_first() else {
+                return false;
+            };
+            if let Some(block) = state.blocks.remove(&id) {
+                state.used -= block.size;
+                let _ = fs::remove_file(self.blocks_dir.join(block.seq.to_string()));
+            }
+        }
+        true
+    }
+}
+
+// Files in a spill directory by sequence number; leftover temporaries are removed
+fn scan(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        match path.file_name().and_then(|name| name.to_str()?.parse().ok()) {
+            Some(seq) => files.push((seq, path)),
+            None => fs::remove_file(&path)?,
+        }
+    }
+    Ok(files)
+}
+
+fn write_file(dir: &Path, seq: u64, magic: &[u8; 4], body: &[u8], durable: bool) -> io::Result<()> {
+    let tmp = dir.join(format!("{}.tmp", seq));
+    let mut file = File::create(&tmp)?;
+    file.write_all(magic)?;
+    file.write_all(&crc32fast::hash(body).to_le_bytes())?;
+    file.write_all(body)?;
+    if durable {
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, dir.join(seq.to_string()))?;
+    if durable {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// The body of a spill file, if it is complete and undamaged
+fn read_file(path: &Path, magic: &[u8; 4]) -> Option<Vec<u8>> {
+    let mut contents = fs::read(path).ok()?;
+    if contents.len() < 8 || &contents[..4] != magic {
+        return None;
+    }
+    let checksum = u32::from_le_bytes(contents[4..8].try_into().ok()?);
+    let body = contents.split_off(8);
+    (crc32fast::hash(&body) == checksum).then_some(body)
+}
+
+fn take<'a>(body: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if body.len() < len {
+        return None;
+    }
+    let (taken, rest) = body.split_at(len);
+    *body = rest;
+    Some(taken)
+}
+
+fn take_u64(body: &mut &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(take(body, 8)?.try_into().ok()?))
+}
+
+fn take_string(body: &mut &[u8]) -> Option<String> {
+    let len = u32::from_le_bytes(take(body, 4)?.try_into().ok()?) as usize;
+    String::from_utf8(take(body, len)?.to_vec()).ok()
+}
+
+fn parse_block(mut body: &[u8]) -> Option<(String, u64, &[u8])> {
+    let key = take_string(&mut body)?;
+    let index = take_u64(&mut body)?;
+    Some((key, index, body))
+}
+
+fn parse_dirty(mut body: &[u8]) -> Option<(String, Extents)> {
+    let path = take_string(&mut body)?;
+    let mut extents = Vec::new();
+    while !body.is_empty() {
+        let offset = take_u64(&mut body)?;
+        let len = take_u64(&mut body)? as usize;
+        extents.push((offset, take(&mut body, len)?.to_vec()));
+    }
+    Some((path, extents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fresh directory under the system temp dir for one test
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fsdaemon-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn extents(list: &[(u64, &[u8])]) -> BTreeMap<u64, Vec<u8>> {
+        list.iter().map(|(offset, data)| (*offset, data.to_vec())).collect()
+    }
+
+    #[test]
+    fn parse_block_rejects_a_torn_body() {
+        let mut body = Vec::new();
+        body.extend_from_slice(&3u32.to_le_bytes());
+        body.extend_from_slice(b"f@1");
+        body.extend_from_slice(&7u64.to_le_bytes());
+        body.extend_from_slice(b"data");
+        assert_eq!(parse_block(&body), Some(("f@1".to_string(), 7, &b"data"[..])));
+        // Cut inside the key or the index
+        assert_eq!(parse_block(&body[..5]), None);
+        assert_eq!(parse_block(&body[..10]), None);
+    }
+
+    #[test]
+    fn parse_dirty_rejects_a_torn_extent() {
+        let mut body = Vec::new();
+        body.extend_from_slice(&2u32.to_le_bytes());
+        body.extend_from_slice(b"/f");
+        for (offset, data) in [(0u64, &b"abc"[..]), (10, b"de")] {
+            body.extend_from_slice(&offset.to_le_bytes());
+            body.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            body.extend_from_slice(data);
+        }
+        let expected = vec![(0, b"abc".to_vec()), (10, b"de".to_vec())];
+        assert_eq!(parse_dirty(&body), Some(("/f".to_string(), expected)));
+        for len in [body.len() - 1, body.len() - 3, body.len() - 12] {
+            assert_eq!(parse_dirty(&body[..len]), None, "cut at {}", len);
+        }
+    }
+
+    #[test]
+    fn read_file_rejects_damage() {
+        let dir = scratch("read-file");
+        fs::create_dir_all(&dir).unwrap();
+        write_file(&dir, 1, DIRTY_MAGIC, b"body", false).unwrap();
+        let path = dir.join("1");
+        assert_eq!(read_file(&path, DIRTY_MAGIC), Some(b"body".to_vec()));
+        assert_eq!(read_file(&path, BLOCK_MAGIC), None);
+
+        let mut contents = fs::read(&path).unwrap();
+        contents.truncate(contents.len() - 1);
+        fs::write(&path, &contents).unwrap();
+        assert_eq!(read_file(&path, DIRTY_MAGIC), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopening_recovers_dirty_data_and_drops_torn_files() {
+        let dir = scratch("reopen");
+        let spill = SpillDir::open(dir.to_str().unwrap(), 1 << 20).unwrap();
+        let segment = spill.store_dirty("/f", &extents(&[(4, b"abcd")])).unwrap();
+        assert_eq!(segment.end, 8);
+        spill.store_block("f@1", 0, b"block");
+        drop(spill);
+
+        // A torn segment and a temporary left by a crash mid-write
+        fs::write(dir.join("dirty").join("99"), b"FSD1\0\0").unwrap();
+        fs::write(dir.join("blocks").join("100.tmp"), b"FSB1").unwrap();
+
+        let spill = SpillDir::open(dir.to_str().unwrap(), 1 << 20).unwrap();
+        let recovered = spill.take_recovered();
+        assert_eq!(recovered.len(), 1);
+        assert_eq!((recovered[0].path.as_str(), recovered[0].end), ("/f", 8));
+        assert_eq!(spill.load_dirty(recovered[0].seq), Some(vec![(4, b"abcd".to_vec())]));
+        assert_eq!(spill.load_block("f@1", 0), Some(b"block".to_vec()));
+        assert!(!dir.join("dirty").join("99").exists());
+        assert!(!dir.join("blocks").join("100.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dirty_data_evicts_blocks_but_not_the_other_way_round() {
+        let dir = scratch("budget");
+        let spill = SpillDir::open(dir.to_str().unwrap(), 64).unwrap();
+        spill.store_block("f@1", 0, &[0; 24]);
+        assert!(spill.store_dirty("/f", &extents(&[(0, &[1; 24])])).is_some());
+        assert_eq!(spill.load_block("f@1", 0), None);
+        spill.store_block("f@1", 1, &[0; 8]);
+        assert_eq!(spill.load_block("f@1", 1), None);
+        assert!(spill.store_dirty("/f", &extents(&[(0, &[1; 24])])).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
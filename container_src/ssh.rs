@@ -0,0 +1,1154 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use ring::aead::chacha20_poly1305_openssh::{OpeningKey, SealingKey, KEY_LEN, TAG_LEN};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::digest::{self, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{
+    Ed25519KeyPair, KeyPair, RsaPublicKeyComponents, UnparsedPublicKey as PublicKey, ECDSA_P256_SHA256_FIXED,
+    ECDSA_P384_SHA384_FIXED, ED25519, RSA_PKCS1_2048_8192_SHA256, RSA_PKCS1_2048_8192_SHA512,
+};
+use tracing::debug;
+
+// The one of each kind of algorithm spoken, the ones OpenSSH prefers
+const KEX: [&str; 2] = ["curve25519-sha256", "curve25519-sha256@libssh.org"];
+const HOST_KEY: &str = "ssh-ed25519";
+const CIPHER: &str = "chacha20-poly1305@openssh.com";
+// Never used, as the cipher authenticates packets itself, but offered as
+// clients expect some MAC to be
+const MAC: &str = "hmac-sha2-256";
+const COMPRESSION: &str = "none";
+// Sequence numbers start over at every key exchange when both sides offer
+// these, so nothing can be slipped in before the first one
+const STRICT_CLIENT: &str = "kex-strict-c-v00@openssh.com";
+const STRICT_SERVER: &str = "kex-strict-s-v00@openssh.com";
+// A client offering this is told which signatures its key may make, so an
+// RSA one signs with SHA-2 rather than SHA-1
+const EXT_INFO_CLIENT: &str = "ext-info-c";
+// Clients log in only with a key, of one of these types, signing with one
+// of these algorithms
+const PUBLICKEY: &str = "publickey";
+const KEY_TYPES: [&str; 4] = ["ssh-ed25519", "ecdsa-sha2-nistp256", "ecdsa-sha2-nistp384", "ssh-rsa"];
+const SIGNATURES: &str = "ssh-ed25519,ecdsa-sha2-nistp256,ecdsa-sha2-nistp384,rsa-sha2-512,rsa-sha2-256";
+
+const MSG_DISCONNECT: u8 = 1;
+const MSG_IGNORE: u8 = 2;
+const MSG_UNIMPLEMENTED: u8 = 3;
+const MSG_DEBUG: u8 = 4;
+const MSG_SERVICE_REQUEST: u8 = 5;
+const MSG_SERVICE_ACCEPT: u8 = 6;
+const MSG_EXT_INFO: u8 = 7;
+const MSG_KEXINIT: u8 = 20;
+const MSG_NEWKEYS: u8 = 21;
+const MSG_KEX_ECDH_INIT: u8 = 30;
+const MSG_KEX_ECDH_REPLY: u8 = 31;
+const MSG_USERAUTH_REQUEST: u8 = 50;
+const MSG_USERAUTH_FAILURE: u8 = 51;
+const MSG_USERAUTH_SUCCESS: u8 = 52;
+const MSG_USERAUTH_PK_OK: u8 = 60;
+const MSG_GLOBAL_REQUEST: u8 = 80;
+const MSG_REQUEST_FAILURE: u8 = 82;
+const MSG_CHANNEL_OPEN: u8 = 90;
+const MSG_CHANNEL_OPEN_CONFIRMATION: u8 = 91;
+const MSG_CHANNEL_OPEN_FAILURE: u8 = 92;
+const MSG_CHANNEL_WINDOW_ADJUST: u8 = 93;
+const MSG_CHANNEL_DATA: u8 = 94;
+const MSG_CHANNEL_EXTENDED_DATA: u8 = 95;
+const MSG_CHANNEL_EOF: u8 = 96;
+const MSG_CHANNEL_CLOSE: u8 = 97;
+const MSG_CHANNEL_REQUEST: u8 = 98;
+const MSG_CHANNEL_SUCCESS: u8 = 99;
+const MSG_CHANNEL_FAILURE: u8 = 100;
+
+const DISCONNECT_PROTOCOL_ERROR: u32 = 2;
+const DISCONNECT_SERVICE_NOT_AVAILABLE: u32 = 7;
+const OPEN_UNKNOWN_CHANNEL_TYPE: u32 = 3;
+
+// The largest packet taken from a client, well past the 35000 bytes every
+// implementation has to take
+const MAX_PACKET: usize = 256 * 1024;
+// What a client may send on a channel before hearing back, and in one packet
+const CHANNEL_WINDOW: u32 = 2 * 1024 * 1024;
+const CHANNEL_PACKET: u32 = 64 * 1024;
+const BLOCK: usize = 8;
+
+// A message with a field cut short or of the wrong kind
+#[derive(Debug, PartialEq)]
+pub struct Malformed;
+
+impl From<Malformed> for io::Error {
+    fn from(_: Malformed) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, "malformed SSH message")
+    }
+}
+
+// Decoding of a message's fields, as SSH and SFTP both encode them
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn fixed(&mut self, len: usize) -> Result<&'a [u8], Malformed> {
+        if self.data.len() < len {
+            return Err(Malformed);
+        }
+        let (value, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(value)
+    }
+
+    pub fn byte(&mut self) -> Result<u8, Malformed> {
+        Ok(self.fixed(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Malformed> {
+        Ok(self.byte()? != 0)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Malformed> {
+        Ok(u32::from_be_bytes(self.fixed(4)?.try_into().map_err(|_| Malformed)?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Malformed> {
+        Ok(u64::from_be_bytes(self.fixed(8)?.try_into().map_err(|_| Malformed)?))
+    }
+
+    pub fn string(&mut self) -> Result<&'a [u8], Malformed> {
+        let len = self.u32()? as usize;
+        self.fixed(len)
+    }
+
+    pub fn text(&mut self) -> Result<&'a str, Malformed> {
+        std::str::from_utf8(self.string()?).map_err(|_| Malformed)
+    }
+
+    fn name_list(&mut self) -> Result<Vec<&'a str>, Malformed> {
+        Ok(self.text()?.split(',').filter(|name| !name.is_empty()).collect())
+    }
+}
+
+// Encoding of a message's fields
+#[derive(Default)]
+pub struct Writer(pub Vec<u8>);
+
+impl Writer {
+    pub fn byte(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.byte(value as u8);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn string(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.0.extend_from_slice(value);
+    }
+
+    pub fn text(&mut self, value: &str) {
+        self.string(value.as_bytes());
+    }
+
+    // An unsigned big-endian number, without the zeroes in front of it but
+    // with one where the top bit would make it negative
+    fn mpint(&mut self, value: &[u8]) {
+        let value = unsigned(value);
+        let sign = value.first().is_some_and(|byte| byte & 0x80 != 0);
+        self.u32((value.len() + sign as usize) as u32);
+        if sign {
+            self.byte(0);
+        }
+        self.0.extend_from_slice(value);
+    }
+}
+
+// A big-endian number without the zeroes in front of it
+fn unsigned(number: &[u8]) -> &[u8] {
+    &number[number.iter().take_while(|byte| **byte == 0).count()..]
+}
+
+fn message(kind: u8) -> Writer {
+    Writer(vec![kind])
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+fn crypto(_: ring::error::Unspecified) -> io::Error {
+    io::Error::other("SSH key exchange failed")
+}
+
+// The server's identity, which clients remember the first time they connect
+pub struct HostKey {
+    pair: Ed25519KeyPair,
+}
+
+impl HostKey {
+    // The key kept at `path`, made there the first time so clients see the
+    // same one across restarts, or one of its own for this run without a path
+    pub fn load(path: Option<&Path>) -> io::Result<Self> {
+        let rng = SystemRandom::new();
+        let generate = || {
+            Ed25519KeyPair::generate_pkcs8(&rng).map(|pkcs8| pkcs8.as_ref().to_vec()).map_err(crypto)
+        };
+        let pkcs8 = match path {
+            Some(path) => match fs::read(path) {
+                Ok(pkcs8) => pkcs8,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    let pkcs8 = generate()?;
+                    OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?.write_all(&pkcs8)?;
+                    pkcs8
+                }
+                Err(e) => return Err(e),
+            },
+            None => generate()?,
+        };
+        let pair = Ed25519KeyPair::from_pkcs8(&pkcs8)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("not an Ed25519 key: {}", e)))?;
+        Ok(Self { pair })
+    }
+
+    // The public key as SSH sends it
+    fn blob(&self) -> Vec<u8> {
+        let mut blob = Writer::default();
+        blob.text(HOST_KEY);
+        blob.string(self.pair.public_key().as_ref());
+        blob.0
+    }
+
+    // What ssh-keygen -l shows for the key, and clients when they first see it
+    pub fn fingerprint(&self) -> String {
+        format!("SHA256:{}", base64(digest::digest(&SHA256, &self.blob()).as_ref()))
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// Unpadded, as fingerprints are written
+fn base64(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            encoded.push(BASE64[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+// The bytes `text` encodes, padded or not, as authorized_keys has them
+fn unbase64(text: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let (mut bits, mut held) = (0u32, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        let value = BASE64.iter().position(|a| *a == c)? as u32;
+        bits = (bits << 6 | value) & 0xffff;
+        held += 6;
+        if held >= 8 {
+            held -= 8;
+            data.push((bits >> held) as u8);
+        }
+    }
+    Some(data)
+}
+
+// The keys clients may log in with, from a file in the format of OpenSSH's
+// authorized_keys. None of the options that may come before a key are
+// honoured, so keys with any are left out rather than let in more widely
+// than they were meant to be.
+#[derive(Default)]
+pub struct AuthorizedKeys(Vec<Vec<u8>>);
+
+impl AuthorizedKeys {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    fn parse(text: &str) -> Self {
+        let keys = text.lines().filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let key_type = fields.next().filter(|key_type| KEY_TYPES.contains(key_type))?;
+            let blob = unbase64(fields.next()?)?;
+            (Reader::new(&blob).text() == Ok(key_type)).then_some(blob)
+        });
+        Self(keys.collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+// What comes of one request to log in
+#[derive(Debug, PartialEq)]
+enum Verdict<'a> {
+    Refused,
+    // The client asked whether a key would do without signing with it yet
+    KeyAccepted { algorithm: &'a str, blob: &'a [u8] },
+    LoggedIn,
+}
+
+// Whether the rest of a request to log in as `user`, from the method on,
+// proves the client holds one of the `authorized` keys. Only the publickey
+// method is taken, with a signature over the session's id and the request.
+fn authenticate<'a>(
+    session_id: &[u8],
+    authorized: &AuthorizedKeys,
+    user: &str,
+    service: &str,
+    fields: &mut Reader<'a>,
+) -> Result<Verdict<'a>, Malformed> {
+    if service != "ssh-connection" || fields.text()? != PUBLICKEY {
+        return Ok(Verdict::Refused);
+    }
+    let (signed, algorithm, blob) = (fields.bool()?, fields.text()?, fields.string()?);
+    if !authorized.0.iter().any(|key| key == blob) {
+        return Ok(Verdict::Refused);
+    }
+    if !signed {
+        return Ok(Verdict::KeyAccepted { algorithm, blob });
+    }
+    let mut data = Writer::default();
+    data.string(session_id);
+    data.byte(MSG_USERAUTH_REQUEST);
+    for part in [user, service, PUBLICKEY] {
+        data.text(part);
+    }
+    data.bool(true);
+    data.text(algorithm);
+    data.string(blob);
+    match verify(algorithm, blob, &data.0, fields.string()?)? {
+        true => Ok(Verdict::LoggedIn),
+        false => Ok(Verdict::Refused),
+    }
+}
+
+// Whether `signature` was made over `data` by the key `blob` with
+// `algorithm`, which has to be one the key's type signs with
+fn verify(algorithm: &str, blob: &[u8], data: &[u8], signature: &[u8]) -> Result<bool, Malformed> {
+    let mut key = Reader::new(blob);
+    let key_type = key.text()?;
+    let mut signature = Reader::new(signature);
+    if signature.text()? != algorithm {
+        return Ok(false);
+    }
+    let signature = signature.string()?;
+    let verified = match (algorithm, key_type) {
+        ("ssh-ed25519", "ssh-ed25519") => PublicKey::new(&ED25519, key.string()?).verify(data, signature),
+        ("ecdsa-sha2-nistp256" | "ecdsa-sha2-nistp384", _) if key_type == algorithm => {
+            let (curve, len, verification) = match algorithm {
+                "ecdsa-sha2-nistp256" => ("nistp256", 32, &ECDSA_P256_SHA256_FIXED),
+                _ => ("nistp384", 48, &ECDSA_P384_SHA384_FIXED),
+            };
+            if key.text()? != curve {
+                return Ok(false);
+            }
+            let point = key.string()?;
+            // r and s, each padded out to the curve's size
+            let mut parts = Reader::new(signature);
+            let mut fixed = Vec::with_capacity(2 * len);
+            for _ in 0..2 {
+                let part = unsigned(parts.string()?);
+                if part.len() > len {
+                    return Ok(false);
+                }
+                fixed.resize(fixed.len() + len - part.len(), 0);
+                fixed.extend_from_slice(part);
+            }
+            PublicKey::new(verification, point).verify(data, &fixed)
+        }
+        ("rsa-sha2-256" | "rsa-sha2-512", "ssh-rsa") => {
+            let (e, n) = (unsigned(key.string()?), unsigned(key.string()?));
+            let verification = match algorithm {
+                "rsa-sha2-256" => &RSA_PKCS1_2048_8192_SHA256,
+                _ => &RSA_PKCS1_2048_8192_SHA512,
+            };
+            RsaPublicKeyComponents { n, e }.verify(verification, data, signature)
+        }
+        _ => return Ok(false),
+    };
+    Ok(verified.is_ok())
+}
+
+// What runs on a session channel once the client asks for it by name
+pub trait Subsystem {
+    // Takes what the client sent on the channel and gives what to send back,
+    // which may be nothing until more has come
+    fn data(&mut self, data: &[u8]) -> Vec<u8>;
+}
+
+// Binary packets in each direction, sealed once keys are agreed
+struct Transport {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    rng: SystemRandom,
+    sequence_in: u32,
+    sequence_out: u32,
+    opening: Option<OpeningKey>,
+    sealing: Option<SealingKey>,
+}
+
+impl Transport {
+    fn read(&mut self) -> io::Result<Vec<u8>> {
+        let mut length = [0; 4];
+        self.reader.read_exact(&mut length)?;
+        let plain = match &self.opening {
+            Some(key) => key.decrypt_packet_length(self.sequence_in, length),
+            None => length,
+        };
+        let len = u32::from_be_bytes(plain) as usize;
+        if !(BLOCK..=MAX_PACKET).contains(&len) {
+            return Err(invalid("SSH packet of a bad size"));
+        }
+        let mut packet = vec![0; 4 + len];
+        packet[..4].copy_from_slice(&length);
+        self.reader.read_exact(&mut packet[4..])?;
+        let sequence = self.sequence_in;
+        self.sequence_in = sequence.wrapping_add(1);
+        let plaintext: &[u8] = match &self.opening {
+            Some(key) => {
+                let mut tag = [0; TAG_LEN];
+                self.reader.read_exact(&mut tag)?;
+                key.open_in_place(sequence, &mut packet, &tag).map_err(|_| invalid("SSH packet failed to open"))?
+            }
+            None => &packet[4..],
+        };
+        let padding = plaintext[0] as usize;
+        if padding < 4 || padding >= plaintext.len() {
+            return Err(invalid("SSH packet with bad padding"));
+        }
+        Ok(plaintext[1..plaintext.len() - padding].to_vec())
+    }
+
+    fn write(&mut self, payload: &[u8]) -> io::Result<()> {
+        let packet = self.packet(payload)?;
+        self.writer.write_all(&packet)
+    }
+
+    // The packet carrying `payload`, which takes up the next sequence number
+    fn packet(&mut self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        // Once packets are sealed their length is left out of the whole
+        // blocks they are padded to
+        let counted = if self.sealing.is_some() { 1 + payload.len() } else { 5 + payload.len() };
+        let padding = 4 + (BLOCK - (counted + 4) % BLOCK) % BLOCK;
+        let len = 1 + payload.len() + padding;
+        let mut packet = Vec::with_capacity(4 + len + TAG_LEN);
+        packet.extend_from_slice(&(len as u32).to_be_bytes());
+        packet.push(padding as u8);
+        packet.extend_from_slice(payload);
+        let mut random = [0; 4 + BLOCK];
+        self.rng.fill(&mut random[..padding]).map_err(crypto)?;
+        packet.extend_from_slice(&random[..padding]);
+
+        let sequence = self.sequence_out;
+        self.sequence_out = sequence.wrapping_add(1);
+        if let Some(key) = &self.sealing {
+            let mut tag = [0; TAG_LEN];
+            key.seal_in_place(sequence, &mut packet, &mut tag);
+            packet.extend_from_slice(&tag);
+        }
+        Ok(packet)
+    }
+}
+
+// A session channel the client opened
+struct Channel<S> {
+    // The client's number for it
+    peer: u32,
+    // What the client may still send before it is told it may send more
+    window: u32,
+    // What may still be sent to the client, and in one packet
+    peer_window: u32,
+    peer_packet: u32,
+    subsystem: Option<S>,
+    // What the subsystem answered that the client hasn't room for yet
+    pending: Vec<u8>,
+    // The client has sent all it will, so the channel closes once what is
+    // pending has gone
+    eof: bool,
+    closed: bool,
+}
+
+// One client connection: the version exchange, keys and authentication,
+// then its channels
+struct Connection<'a, S, F> {
+    transport: Transport,
+    host_key: &'a HostKey,
+    client_version: Vec<u8>,
+    server_version: Vec<u8>,
+    session_id: Option<Vec<u8>>,
+    strict: bool,
+    // Who may log in, or None for anyone, and with which keys
+    user: Option<&'a str>,
+    authorized: &'a AuthorizedKeys,
+    authenticated: bool,
+    subsystem: &'a str,
+    open: F,
+    channels: HashMap<u32, Channel<S>>,
+    next_channel: u32,
+}
+
+// Serves one client on `stream`, starting a subsystem made by `open` for each
+// channel that asks for one named `subsystem`. A client logs in as `user`, or
+// as anyone without one, by signing with one of the `authorized` keys.
+pub fn serve<S: Subsystem>(
+    stream: TcpStream,
+    host_key: &HostKey,
+    user: Option<&str>,
+    authorized: &AuthorizedKeys,
+    subsystem: &str,
+    open: impl FnMut() -> S,
+) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let server_version = format!("SSH-2.0-{}_{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    let mut writer = stream.try_clone()?;
+    writer.write_all(format!("{}\r\n", server_version).as_bytes())?;
+    let mut reader = BufReader::new(stream);
+    let client_version = read_version(&mut reader)?;
+    debug!("SSH client is {}", String::from_utf8_lossy(&client_version));
+
+    let transport = Transport {
+        reader,
+        writer,
+        rng: SystemRandom::new(),
+        sequence_in: 0,
+        sequence_out: 0,
+        opening: None,
+        sealing: None,
+    };
+    let mut connection = Connection {
+        transport,
+        host_key,
+        client_version,
+        server_version: server_version.into_bytes(),
+        session_id: None,
+        strict: false,
+        user,
+        authorized,
+        authenticated: false,
+        subsystem,
+        open,
+        channels: HashMap::new(),
+        next_channel: 0,
+    };
+    connection.exchange(None)?;
+    connection.run()
+}
+
+// The client's version, after any lines it sends before it
+fn read_version(reader: &mut BufReader<TcpStream>) -> io::Result<Vec<u8>> {
+    for _ in 0..64 {
+        let mut line = Vec::new();
+        reader.by_ref().take(256).read_until(b'\n', &mut line)?;
+        if !line.ends_with(b"\n") {
+            return Err(invalid("SSH version line too long"));
+        }
+        let line = &line[..line.iter().rposition(|byte| !byte.is_ascii_whitespace()).map_or(0, |end| end + 1)];
+        if line.starts_with(b"SSH-2.0-") || line.starts_with(b"SSH-1.99-") {
+            return Ok(line.to_vec());
+        }
+        if line.starts_with(b"SSH-") {
+            return Err(invalid("client speaks an SSH version other than 2"));
+        }
+    }
+    Err(invalid("no SSH version from the client"))
+}
+
+// The key derived for one direction, from the secret, the exchange's hash
+// and the letter RFC 4253 gives it
+fn derive(secret: &[u8], hash: &[u8], letter: u8, session_id: &[u8]) -> [u8; KEY_LEN] {
+    let mut key = [0; KEY_LEN];
+    let mut first = digest::Context::new(&SHA256);
+    for part in [secret, hash, &[letter], session_id] {
+        first.update(part);
+    }
+    let first = first.finish();
+    let mut second = digest::Context::new(&SHA256);
+    for part in [secret, hash, first.as_ref()] {
+        second.update(part);
+    }
+    key[..32].copy_from_slice(first.as_ref());
+    key[32..].copy_from_slice(second.finish().as_ref());
+    key
+}
+
+impl<S: Subsystem, F: FnMut() -> S> Connection<'_, S, F> {
+    fn kexinit(&self) -> io::Result<Vec<u8>> {
+        let mut kexinit = message(MSG_KEXINIT);
+        let mut cookie = [0; 16];
+        self.transport.rng.fill(&mut cookie).map_err(crypto)?;
+        kexinit.0.extend_from_slice(&cookie);
+        kexinit.text(&[KEX[0], KEX[1], STRICT_SERVER].join(","));
+        kexinit.text(HOST_KEY);
+        for list in [CIPHER, CIPHER, MAC, MAC, COMPRESSION, COMPRESSION, "", ""] {
+            kexinit.text(list);
+        }
+        kexinit.bool(false);
+        kexinit.u32(0);
+        Ok(kexinit.0)
+    }
+
+    // Agrees on keys with the client, which may have started by sending its
+    // KEXINIT, and switches to them
+    fn exchange(&mut self, theirs: Option<Vec<u8>>) -> io::Result<()> {
+        let first = self.session_id.is_none();
+        let ours = self.kexinit()?;
+        self.transport.write(&ours)?;
+        let theirs = match theirs {
+            Some(theirs) => theirs,
+            None => self.read_kex(MSG_KEXINIT)?,
+        };
+        let mut offer = Reader::new(&theirs);
+        offer.fixed(17)?;
+        let lists = (0..10).map(|_| offer.name_list()).collect::<Result<Vec<_>, _>>()?;
+        let guessed = offer.bool()?;
+        let ext_info = first && lists[0].contains(&EXT_INFO_CLIENT);
+        if first && lists[0].contains(&STRICT_CLIENT) {
+            // The KEXINIT has to have been the first thing the client sent
+            if self.transport.sequence_in != 1 {
+                return Err(invalid("SSH client sent messages before its KEXINIT"));
+            }
+            self.strict = true;
+        }
+        let kex = lists[0].iter().find(|name| KEX.contains(name)).ok_or_else(|| invalid("no common SSH key exchange"))?;
+        let agreed = lists[1].contains(&HOST_KEY)
+            && lists[2].contains(&CIPHER)
+            && lists[3].contains(&CIPHER)
+            && lists[6].contains(&COMPRESSION)
+            && lists[7].contains(&COMPRESSION);
+        if !agreed {
+            return Err(invalid("no common SSH algorithms; chacha20-poly1305 and ssh-ed25519 are required"));
+        }
+        // A client that guessed wrong sent a packet for the wrong exchange
+        if guessed && (lists[0].first() != Some(kex) || lists[1].first() != Some(&HOST_KEY)) {
+            self.transport.read()?;
+        }
+
+        let init = self.read_kex(MSG_KEX_ECDH_INIT)?;
+        let mut init = Reader::new(&init[1..]);
+        let client_public = init.string()?;
+        let private = EphemeralPrivateKey::generate(&X25519, &self.transport.rng).map_err(crypto)?;
+        let server_public = private.compute_public_key().map_err(crypto)?;
+        let secret = agreement::agree_ephemeral(private, &UnparsedPublicKey::new(&X25519, client_public), |secret| {
+            let mut mpint = Writer::default();
+            mpint.mpint(secret);
+            mpint.0
+        })
+        .map_err(crypto)?;
+
+        let host_key = self.host_key.blob();
+        let mut exchanged = Writer::default();
+        for part in [&self.client_version, &self.server_version, &theirs, &ours, &host_key] {
+            exchanged.string(part);
+        }
+        exchanged.string(client_public);
+        exchanged.string(server_public.as_ref());
+        exchanged.0.extend_from_slice(&secret);
+        let hash = digest::digest(&SHA256, &exchanged.0);
+        let session_id = self.session_id.get_or_insert_with(|| hash.as_ref().to_vec()).clone();
+
+        let mut signature = Writer::default();
+        signature.text(HOST_KEY);
+        signature.string(self.host_key.pair.sign(hash.as_ref()).as_ref());
+        let mut reply = message(MSG_KEX_ECDH_REPLY);
+        reply.string(&host_key);
+        reply.string(server_public.as_ref());
+        reply.string(&signature.0);
+        self.transport.write(&reply.0)?;
+        self.transport.write(&[MSG_NEWKEYS])?;
+        self.transport.sealing = Some(SealingKey::new(&derive(&secret, hash.as_ref(), b'D', &session_id)));
+        if self.strict {
+            self.transport.sequence_out = 0;
+        }
+        if ext_info {
+            let mut extensions = message(MSG_EXT_INFO);
+            extensions.u32(1);
+            extensions.text("server-sig-algs");
+            extensions.text(SIGNATURES);
+            self.transport.write(&extensions.0)?;
+        }
+        self.read_kex(MSG_NEWKEYS)?;
+        self.transport.opening = Some(OpeningKey::new(&derive(&secret, hash.as_ref(), b'C', &session_id)));
+        if self.strict {
+            self.transport.sequence_in = 0;
+        }
+        Ok(())
+    }
+
+    // The next message of a key exchange, which has to be `kind`
+    fn read_kex(&mut self, kind: u8) -> io::Result<Vec<u8>> {
+        loop {
+            let packet = self.transport.read()?;
+            match packet.first() {
+                Some(next) if *next == kind => return Ok(packet),
+                Some(&(MSG_IGNORE | MSG_DEBUG)) if !self.strict => continue,
+                Some(&MSG_DISCONNECT) => return Err(io::ErrorKind::ConnectionAborted.into()),
+                _ => return Err(invalid("unexpected message during SSH key exchange")),
+            }
+        }
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        loop {
+            let packet = self.transport.read()?;
+            let mut fields = Reader::new(&packet);
+            let kind = fields.byte()?;
+            match kind {
+                MSG_DISCONNECT => return Ok(()),
+                MSG_IGNORE | MSG_DEBUG | MSG_UNIMPLEMENTED => {}
+                MSG_KEXINIT => self.exchange(Some(packet))?,
+                MSG_SERVICE_REQUEST => {
+                    let service = fields.text()?;
+                    if service != "ssh-userauth" {
+                        return self.disconnect(DISCONNECT_SERVICE_NOT_AVAILABLE, "no such service");
+                    }
+                    let mut accept = message(MSG_SERVICE_ACCEPT);
+                    accept.text(service);
+                    self.transport.write(&accept.0)?;
+                }
+                // Once in, a client has nothing more to prove
+                MSG_USERAUTH_REQUEST if self.authenticated => {}
+                MSG_USERAUTH_REQUEST => {
+                    let (name, service) = (fields.text()?, fields.text()?);
+                    let verdict = match self.user.is_some_and(|user| user != name) {
+                        true => None,
+                        false => {
+                            let session_id = self.session_id.as_deref().unwrap_or_default();
+                            Some(authenticate(session_id, self.authorized, name, service, &mut fields)?)
+                        }
+                    };
+                    match verdict {
+                        Some(Verdict::LoggedIn) => {
+                            debug!("SSH client logged in as {}", name);
+                            self.authenticated = true;
+                            self.transport.write(&[MSG_USERAUTH_SUCCESS])?;
+                        }
+                        Some(Verdict::KeyAccepted { algorithm, blob }) => {
+                            let mut accepted = message(MSG_USERAUTH_PK_OK);
+                            accepted.text(algorithm);
+                            accepted.string(blob);
+                            self.transport.write(&accepted.0)?;
+                        }
+                        // Any other user is refused whatever the method, so
+                        // none are offered to it
+                        refused => {
+                            debug!("Refusing SSH login as {}", name);
+                            let mut failure = message(MSG_USERAUTH_FAILURE);
+                            failure.text(if refused.is_some() { PUBLICKEY } else { "" });
+                            failure.bool(false);
+                            self.transport.write(&failure.0)?;
+                        }
+                    }
+                }
+                MSG_GLOBAL_REQUEST..=MSG_CHANNEL_FAILURE if !self.authenticated => {
+                    return self.disconnect(DISCONNECT_PROTOCOL_ERROR, "not logged in");
+                }
+                MSG_GLOBAL_REQUEST => {
+                    let (_name, want_reply) = (fields.string()?, fields.bool()?);
+                    if want_reply {
+                        self.transport.write(&[MSG_REQUEST_FAILURE])?;
+                    }
+                }
+                MSG_CHANNEL_OPEN => self.open(&mut fields)?,
+                MSG_CHANNEL_REQUEST => self.request(&mut fields)?,
+                MSG_CHANNEL_DATA | MSG_CHANNEL_EXTENDED_DATA => {
+                    let id = fields.u32()?;
+                    if kind == MSG_CHANNEL_EXTENDED_DATA {
+                        fields.u32()?;
+                    }
+                    let data = fields.string()?;
+                    let channel = self.channels.get_mut(&id).ok_or_else(|| invalid("no such SSH channel"))?;
+                    let window = channel.window.checked_sub(data.len() as u32);
+                    channel.window = window.ok_or_else(|| invalid("SSH channel overrun"))?;
+                    // Data on stderr means nothing to a subsystem
+                    if let (MSG_CHANNEL_DATA, Some(subsystem)) = (kind, &mut channel.subsystem) {
+                        let answer = subsystem.data(data);
+                        channel.pending.extend_from_slice(&answer);
+                    }
+                    self.flush(id)?;
+                }
+                MSG_CHANNEL_WINDOW_ADJUST => {
+                    let (id, more) = (fields.u32()?, fields.u32()?);
+                    let channel = self.channels.get_mut(&id).ok_or_else(|| invalid("no such SSH channel"))?;
+                    channel.peer_window = channel.peer_window.saturating_add(more);
+                    self.flush(id)?;
+                }
+                MSG_CHANNEL_EOF => {
+                    let id = fields.u32()?;
+                    self.channels.get_mut(&id).ok_or_else(|| invalid("no such SSH channel"))?.eof = true;
+                    self.flush(id)?;
+                }
+                MSG_CHANNEL_CLOSE => {
+                    let id = fields.u32()?;
+                    let channel = self.channels.remove(&id).ok_or_else(|| invalid("no such SSH channel"))?;
+                    if !channel.closed {
+                        let mut close = message(MSG_CHANNEL_CLOSE);
+                        close.u32(channel.peer);
+                        self.transport.write(&close.0)?;
+                    }
+                }
+                _ => {
+                    let mut unimplemented = message(MSG_UNIMPLEMENTED);
+                    unimplemented.u32(self.transport.sequence_in.wrapping_sub(1));
+                    self.transport.write(&unimplemented.0)?;
+                }
+            }
+        }
+    }
+
+    // Only session channels are opened
+    fn open(&mut self, fields: &mut Reader) -> io::Result<()> {
+        let (kind, peer, peer_window, peer_packet) = (fields.text()?, fields.u32()?, fields.u32()?, fields.u32()?);
+        if kind != "session" {
+            let mut failure = message(MSG_CHANNEL_OPEN_FAILURE);
+            failure.u32(peer);
+            failure.u32(OPEN_UNKNOWN_CHANNEL_TYPE);
+            failure.text("only session channels are served");
+            failure.text("");
+            return self.transport.write(&failure.0);
+        }
+        let id = self.next_channel;
+        self.next_channel += 1;
+        let channel = Channel {
+            peer,
+            window: CHANNEL_WINDOW,
+            peer_window,
+            peer_packet,
+            subsystem: None,
+            pending: Vec::new(),
+            eof: false,
+            closed: false,
+        };
+        self.channels.insert(id, channel);
+        let mut confirmation = message(MSG_CHANNEL_OPEN_CONFIRMATION);
+        confirmation.u32(peer);
+        confirmation.u32(id);
+        confirmation.u32(CHANNEL_WINDOW);
+        confirmation.u32(CHANNEL_PACKET);
+        self.transport.write(&confirmation.0)
+    }
+
+    // A channel does nothing but run the subsystem. Shells, commands,
+    // terminals and the environment they would have are refused.
+    fn request(&mut self, fields: &mut Reader) -> io::Result<()> {
+        let (id, kind, want_reply) = (fields.u32()?, fields.text()?, fields.bool()?);
+        let channel = self.channels.get_mut(&id).ok_or_else(|| invalid("no such SSH channel"))?;
+        let started = kind == "subsystem" && fields.text()? == self.subsystem && channel.subsystem.is_none();
+        if started {
+            channel.subsystem = Some((self.open)());
+        }
+        if want_reply {
+            let mut reply = message(if started { MSG_CHANNEL_SUCCESS } else { MSG_CHANNEL_FAILURE });
+            reply.u32(channel.peer);
+            self.transport.write(&reply.0)?;
+        }
+        Ok(())
+    }
+
+    // Sends what is pending on channel `id` as far as the client has room,
+    // then lets it send more once there is little left of its window and
+    // the answers to it aren't piling up
+    fn flush(&mut self, id: u32) -> io::Result<()> {
+        let Some(channel) = self.channels.get_mut(&id) else {
+            return Ok(());
+        };
+        while !channel.pending.is_empty() && channel.peer_window > 0 {
+            let len = channel.pending.len().min(channel.peer_window as usize).min(channel.peer_packet.max(1) as usize);
+            let mut data = message(MSG_CHANNEL_DATA);
+            data.u32(channel.peer);
+            data.string(&channel.pending[..len]);
+            self.transport.write(&data.0)?;
+            channel.pending.drain(..len);
+            channel.peer_window -= len as u32;
+        }
+        if channel.window < CHANNEL_WINDOW / 2 && channel.pending.len() < CHANNEL_WINDOW as usize && !channel.eof {
+            let mut adjust = message(MSG_CHANNEL_WINDOW_ADJUST);
+            adjust.u32(channel.peer);
+            adjust.u32(CHANNEL_WINDOW - channel.window);
+            self.transport.write(&adjust.0)?;
+            channel.window = CHANNEL_WINDOW;
+        }
+        if channel.eof && channel.pending.is_empty() && !channel.closed {
+            let mut close = message(MSG_CHANNEL_CLOSE);
+            close.u32(channel.peer);
+            self.transport.write(&close.0)?;
+            channel.closed = true;
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self, reason: u32, description: &str) -> io::Result<()> {
+        let mut disconnect = message(MSG_DISCONNECT);
+        disconnect.u32(reason);
+        disconnect.text(description);
+        disconnect.text("");
+        self.transport.write(&disconnect.0)?;
+        Err(invalid(description))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Shutdown, TcpListener};
+    use std::thread;
+
+    use super::*;
+
+    fn transport(stream: TcpStream) -> Transport {
+        Transport {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+            rng: SystemRandom::new(),
+            sequence_in: 0,
+            sequence_out: 0,
+            opening: None,
+            sealing: None,
+        }
+    }
+
+    // The two ends of a loopback connection, the client's first
+    fn pair() -> (Transport, Transport) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (transport(client), transport(server))
+    }
+
+    // The same key from the client to the server
+    fn sealed(client: &mut Transport, server: &mut Transport) {
+        client.sealing = Some(SealingKey::new(&[7; KEY_LEN]));
+        server.opening = Some(OpeningKey::new(&[7; KEY_LEN]));
+    }
+
+    // What the server makes of `bytes`, sent as they are before the client
+    // stops sending
+    fn read_raw(keyed: bool, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let (mut client, mut server) = pair();
+        if keyed {
+            sealed(&mut client, &mut server);
+        }
+        client.writer.write_all(bytes).unwrap();
+        client.writer.shutdown(Shutdown::Write).unwrap();
+        server.read()
+    }
+
+    #[test]
+    fn sealed_packets_open_only_untouched_and_in_sequence() {
+        let (mut client, mut server) = pair();
+        sealed(&mut client, &mut server);
+        client.write(&[MSG_IGNORE, 1, 2, 3]).unwrap();
+        assert_eq!(server.read().unwrap(), [MSG_IGNORE, 1, 2, 3]);
+
+        // A flipped bit anywhere past the length, tag included, fails the MAC
+        let (mut client, _) = pair();
+        client.sealing = Some(SealingKey::new(&[7; KEY_LEN]));
+        let packet = client.packet(&[MSG_IGNORE, 1, 2, 3]).unwrap();
+        for at in [5, packet.len() - 1] {
+            let mut tampered = packet.clone();
+            tampered[at] ^= 1;
+            let error = read_raw(true, &tampered).unwrap_err();
+            assert_eq!(error.to_string(), "SSH packet failed to open");
+        }
+        assert_eq!(read_raw(true, &packet).unwrap(), [MSG_IGNORE, 1, 2, 3]);
+
+        // Sequence numbers go into the keys, so a packet sent as another's,
+        // or one a strict key exchange didn't start over, fails too: its
+        // length comes out wrong or it fails to open
+        let skipped = client.packet(&[MSG_IGNORE]).unwrap();
+        assert_eq!(read_raw(true, &skipped).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn packets_cut_short_or_badly_framed_are_refused() {
+        let (mut client, _) = pair();
+        let packet = client.packet(&[MSG_IGNORE, 1, 2, 3]).unwrap();
+        assert_eq!(read_raw(false, &packet).unwrap(), [MSG_IGNORE, 1, 2, 3]);
+        for cut in [2, 4, packet.len() - 1] {
+            let error = read_raw(false, &packet[..cut]).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        }
+        client.sealing = Some(SealingKey::new(&[7; KEY_LEN]));
+        client.sequence_out = 0;
+        let sealed = client.packet(&[MSG_IGNORE]).unwrap();
+        let error = read_raw(true, &sealed[..sealed.len() - 1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        let sized = |len: u32| read_raw(false, &len.to_be_bytes()).unwrap_err().to_string();
+        assert_eq!(sized(BLOCK as u32 - 1), "SSH packet of a bad size");
+        assert_eq!(sized(MAX_PACKET as u32 + 1), "SSH packet of a bad size");
+        let padded = |padding: u8| read_raw(false, &[0, 0, 0, 8, padding, 0, 0, 0, 0, 0, 0, 0]).unwrap_err();
+        assert_eq!(padded(3).to_string(), "SSH packet with bad padding");
+        assert_eq!(padded(8).to_string(), "SSH packet with bad padding");
+    }
+
+    struct Echo;
+
+    impl Subsystem for Echo {
+        fn data(&mut self, data: &[u8]) -> Vec<u8> {
+            data.to_vec()
+        }
+    }
+
+    fn kexinit(strict: bool) -> Vec<u8> {
+        let mut kexinit = message(MSG_KEXINIT);
+        kexinit.0.extend_from_slice(&[0; 16]);
+        kexinit.text(&if strict { [KEX[0], STRICT_CLIENT].join(",") } else { KEX[0].to_string() });
+        for list in [HOST_KEY, CIPHER, CIPHER, MAC, MAC, COMPRESSION, COMPRESSION, "", ""] {
+            kexinit.text(list);
+        }
+        kexinit.bool(false);
+        kexinit.u32(0);
+        kexinit.0
+    }
+
+    // Why the server gave up on a client that sent `payloads` after its
+    // version, then stopped sending
+    fn refusal(payloads: &[Vec<u8>]) -> io::Error {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = transport(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        let (stream, _) = listener.accept().unwrap();
+        let server = thread::spawn(move || {
+            serve(stream, &HostKey::load(None).unwrap(), None, &AuthorizedKeys::default(), "sftp", || Echo)
+        });
+        client.writer.write_all(b"SSH-2.0-test\r\n").unwrap();
+        for payload in payloads {
+            client.write(payload).unwrap();
+        }
+        client.writer.shutdown(Shutdown::Write).unwrap();
+        server.join().unwrap().unwrap_err()
+    }
+
+    #[test]
+    fn strict_key_exchanges_take_nothing_out_of_sequence() {
+        let ignore = vec![MSG_IGNORE];
+        let error = refusal(&[ignore.clone(), kexinit(true)]);
+        assert_eq!(error.to_string(), "SSH client sent messages before its KEXINIT");
+        let error = refusal(&[kexinit(true), ignore.clone()]);
+        assert_eq!(error.to_string(), "unexpected message during SSH key exchange");
+        // Clients that aren't strict may send both, and are waited on for more
+        let error = refusal(&[ignore.clone(), kexinit(false), ignore]);
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn numbers_encode_as_ssh_has_them() {
+        let mpint = |value: &[u8]| {
+            let mut writer = Writer::default();
+            writer.mpint(value);
+            writer.0
+        };
+        assert_eq!(mpint(&[0, 0, 0x12, 0x34]), [0, 0, 0, 2, 0x12, 0x34]);
+        assert_eq!(mpint(&[0x80, 0]), [0, 0, 0, 3, 0, 0x80, 0]);
+        assert_eq!(mpint(&[0, 0]), [0, 0, 0, 0]);
+
+        let mut writer = Writer::default();
+        writer.text("a,b");
+        writer.u64(1 << 40);
+        let mut reader = Reader::new(&writer.0);
+        assert_eq!(reader.name_list(), Ok(vec!["a", "b"]));
+        assert_eq!(reader.u64(), Ok(1 << 40));
+        assert_eq!(reader.byte(), Err(Malformed));
+        // A length running past the end is malformed, not a panic
+        assert_eq!(Reader::new(&[0, 0, 0, 9, b'a']).string(), Err(Malformed));
+
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg");
+        assert_eq!(base64(b"fo"), "Zm8");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    // A client's key, and the line for it in authorized_keys
+    fn client_key() -> (Ed25519KeyPair, String) {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let mut blob = Writer::default();
+        blob.text("ssh-ed25519");
+        blob.string(pair.public_key().as_ref());
+        let line = format!("ssh-ed25519 {} someone@somewhere", base64(&blob.0));
+        (pair, line)
+    }
+
+    fn verdict<'a>(request: &'a [u8], authorized: &AuthorizedKeys) -> Result<Verdict<'a>, Malformed> {
+        authenticate(b"session", authorized, "me", "ssh-connection", &mut Reader::new(request))
+    }
+
+    #[test]
+    fn authorized_keys_take_only_keys_without_options() {
+        let (_, line) = client_key();
+        let text = format!("# a comment\n\n{}\nrestrict {}\nssh-rsa {}\nssh-ed25519 !!!\n", line, line, &line[12..]);
+        let authorized = AuthorizedKeys::parse(&text);
+        assert_eq!(authorized.len(), 1);
+        let blob = unbase64(line.split(' ').nth(1).unwrap()).unwrap();
+        assert_eq!(authorized.0[0], blob);
+        assert_eq!(unbase64("Zm9vYg=="), Some(b"foob".to_vec()));
+        assert_eq!(unbase64("Zm9v YmFy"), None);
+    }
+
+    #[test]
+    fn logins_need_a_signature_from_an_authorized_key() {
+        let (pair, line) = client_key();
+        let authorized = AuthorizedKeys::parse(&line);
+        let blob = authorized.0[0].clone();
+        let request = |method: &str, signature: Option<&[u8]>| {
+            let mut request = Writer::default();
+            request.text(method);
+            request.bool(signature.is_some());
+            request.text("ssh-ed25519");
+            request.string(&blob);
+            if let Some(signature) = signature {
+                let mut signed = Writer::default();
+                signed.text("ssh-ed25519");
+                signed.string(signature);
+                request.string(&signed.0);
+            }
+            request.0
+        };
+        assert_eq!(verdict(b"\0\0\0\x04none", &authorized), Ok(Verdict::Refused));
+        assert_eq!(verdict(b"\0\0\0\x08password\0\0\0\0\x01x", &authorized), Ok(Verdict::Refused));
+        assert_eq!(verdict(&request(PUBLICKEY, None), &AuthorizedKeys::default()), Ok(Verdict::Refused));
+        let accepted = Verdict::KeyAccepted { algorithm: "ssh-ed25519", blob: &blob };
+        assert_eq!(verdict(&request(PUBLICKEY, None), &authorized), Ok(accepted));
+
+        let sign = |session_id: &[u8], user: &str| {
+            let mut data = Writer::default();
+            data.string(session_id);
+            data.byte(MSG_USERAUTH_REQUEST);
+            for part in [user, "ssh-connection", PUBLICKEY, "ssh-ed25519"] {
+                data.text(part);
+                if part == PUBLICKEY {
+                    data.bool(true);
+                }
+            }
+            data.string(&blob);
+            pair.sign(&data.0).as_ref().to_vec()
+        };
+        let signed = request(PUBLICKEY, Some(&sign(b"session", "me")));
+        assert_eq!(verdict(&signed, &authorized), Ok(Verdict::LoggedIn));
+        // A signature made for another session or user doesn't do
+        assert_eq!(verdict(&request(PUBLICKEY, Some(&sign(b"other", "me"))), &authorized), Ok(Verdict::Refused));
+        assert_eq!(verdict(&request(PUBLICKEY, Some(&sign(b"session", "you"))), &authorized), Ok(Verdict::Refused));
+        assert_eq!(verdict(&request(PUBLICKEY, Some(&[0; 64])), &authorized), Ok(Verdict::Refused));
+    }
+}
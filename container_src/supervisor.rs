@@ -0,0 +1,187 @@
+use std::error::Error;
+use std::ffi::CString;
+use std::io;
+use std::time::{Duration, Instant};
+
+use fuser::BackgroundSession;
+use tracing::{error, info, warn};
+
+use crate::health::HEALTH;
+use crate::metrics::METRICS;
+
+pub const DEFAULT_REMOUNT_ATTEMPTS: usize = 3;
+pub const DEFAULT_REMOUNT_BACKOFF: Duration = Duration::from_secs(1);
+
+// How often the sessions are checked for having ended
+const SESSION_POLL: Duration = Duration::from_millis(500);
+
+// A mount that stays up this long is healthy again, and its next failure
+// gets the full set of attempts
+const REMOUNT_STABLE: Duration = Duration::from_secs(60);
+
+// How a mount that went away underneath the daemon is brought back
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Remount {
+    // Remounts tried in a row before giving up, 0 for never
+    pub attempts: usize,
+    // Wait before the first, doubling for each after it
+    pub backoff: Duration,
+}
+
+impl Default for Remount {
+    fn default() -> Self {
+        Self {
+            attempts: DEFAULT_REMOUNT_ATTEMPTS,
+            backoff: DEFAULT_REMOUNT_BACKOFF,
+        }
+    }
+}
+
+impl Remount {
+    fn delay(&self, attempt: usize) -> Duration {
+        self.backoff.saturating_mul(1 << attempt.min(16))
+    }
+}
+
+struct Mounted {
+    mount_point: String,
+    session: Option<BackgroundSession>,
+    since: Instant,
+    // Remounts in a row that didn't stay up
+    failures: usize,
+}
+
+// The FUSE sessions for every mount. One that ends without the daemon asking
+// for it, because the kernel dropped the connection or someone unmounted it,
+// is cleaned up and mounted again.
+pub struct Supervisor {
+    mounts: Vec<Mounted>,
+    remount: Remount,
+}
+
+impl Supervisor {
+    pub fn new(remount: Remount) -> Self {
+        Self {
+            mounts: Vec::new(),
+            remount,
+        }
+    }
+
+    // Supervises the session for the mount at the next index
+    pub fn add(&mut self, mount_point: &str, session: BackgroundSession) {
+        METRICS.mount_up(mount_point, true);
+        self.mounts.push(Mounted {
+            mount_point: mount_point.to_string(),
+            session: Some(session),
+            since: Instant::now(),
+            failures: 0,
+        });
+    }
+
+    // Remounts sessions that end, with `mount` building the session for the
+    // mount at an index afresh. Returns once one couldn't be brought back.
+    pub async fn watch<F>(&mut self, mut mount: F)
+    where
+        F: FnMut(usize) -> Result<BackgroundSession, Box<dyn Error>>,
+    {
+        loop {
+            tokio::time::sleep(SESSION_POLL).await;
+            for index in 0..self.mounts.len() {
+                let remount = self.remount;
+                let mounted = &mut self.mounts[index];
+                if !mounted.session.as_ref().is_some_and(|session| session.guard.is_finished()) {
+                    continue;
+                }
+                let Some(session) = mounted.session.take() else {
+                    continue;
+                };
+                HEALTH.set_mounted(false);
+                METRICS.mount_up(&mounted.mount_point, false);
+                match end(session) {
+                    Ok(()) => warn!("{} was unmounted underneath the daemon", mounted.mount_point),
+                    Err(e) => error!("The FUSE session for {} failed: {}", mounted.mount_point, e),
+                }
+                detach(&mounted.mount_point);
+                if mounted.since.elapsed() >= REMOUNT_STABLE {
+                    mounted.failures = 0;
+                }
+
+                while mounted.session.is_none() && mounted.failures < remount.attempts {
+                    tokio::time::sleep(remount.delay(mounted.failures)).await;
+                    mounted.failures += 1;
+                    info!("Remounting {}, attempt {} of {}", mounted.mount_point, mounted.failures, remount.attempts);
+                    match mount(index) {
+                        Ok(session) => {
+                            METRICS.remounted(&mounted.mount_point);
+                            METRICS.mount_up(&mounted.mount_point, true);
+                            mounted.session = Some(session);
+                            mounted.since = Instant::now();
+                        }
+                        Err(e) => {
+                            warn!("Failed to remount {}: {}", mounted.mount_point, e);
+                            detach(&mounted.mount_point);
+                        }
+                    }
+                }
+                if mounted.session.is_none() {
+                    error!("Giving up on {} after {} remounts", mounted.mount_point, mounted.failures);
+                    return;
+                }
+                HEALTH.set_mounted(true);
+            }
+        }
+    }
+
+    // Unmounts every mount and waits for its session, which flushes the
+    // mount's buffered writes on the way out
+    pub fn join(self) {
+        for mounted in self.mounts {
+            let Some(session) = mounted.session else {
+                continue;
+            };
+            METRICS.mount_up(&mounted.mount_point, false);
+            if let Err(e) = end(session) {
+                warn!("The FUSE session for {} failed: {}", mounted.mount_point, e);
+            }
+        }
+    }
+}
+
+// Unmounts a session and waits for its thread. fuser's own join panics if
+// the session failed, which is just when there is something to report.
+fn end(session: BackgroundSession) -> io::Result<()> {
+    // Dropping what's left of the session unmounts it
+    let guard = {
+        let session = session;
+        session.guard
+    };
+    match guard.join() {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::other("session thread panicked")),
+    }
+}
+
+// Detaches whatever is still mounted at the mount point, such as a FUSE
+// mount whose daemon side is gone and that fails everything with ENOTCONN.
+// Nothing being mounted there is the usual case and not an error.
+fn detach(mount_point: &str) {
+    let Ok(path) = CString::new(mount_point) else {
+        return;
+    };
+    if unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) } == 0 {
+        info!("Detached a stale mount at {}", mount_point);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remount_backoff_doubles() {
+        let remount = Remount { attempts: 3, backoff: Duration::from_secs(1) };
+        let delays: Vec<_> = (0..4).map(|attempt| remount.delay(attempt).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8]);
+        assert_eq!(Remount { backoff: Duration::MAX, ..remount }.delay(3), Duration::MAX);
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::ArgMatches;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{debug, info, warn};
+
+use crate::error::DaemonError;
+use crate::operation::FsOperation;
+use crate::{
+    join, Encoding, FileStat, RemoteFSClient, Transport, DEFAULT_MAX_FRAME_SIZE, DEFAULT_MAX_IN_FLIGHT,
+    READDIR_PAGE_SIZE,
+};
+
+// How much of a file is read into memory and sent at a time
+pub const PIECE_SIZE: u64 = 8 * 1024 * 1024;
+// How long --watch waits between looks at the directory
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(2);
+
+pub struct SyncOptions {
+    source: PathBuf,
+    prefix: String,
+    watch: bool,
+    interval: Duration,
+    delete: bool,
+    checksum: bool,
+    transport: Transport,
+}
+
+impl SyncOptions {
+    pub fn parse(matches: &ArgMatches) -> Self {
+        Self {
+            source: matches.get_one::<String>("source").map(PathBuf::from).expect("the source is required"),
+            prefix: matches.get_one::<String>("prefix").cloned().expect("the prefix is required"),
+            watch: matches.get_flag("watch"),
+            interval: matches.get_one("interval").copied().unwrap_or(DEFAULT_INTERVAL),
+            delete: matches.get_flag("delete"),
+            checksum: matches.get_flag("checksum"),
+            transport: Transport::parse(matches),
+        }
+    }
+}
+
+// A local file as it was when it was last known to match the DO's copy
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Seen {
+    size: u64,
+    mtime: SystemTime,
+}
+
+// What a pass did
+#[derive(Default)]
+struct Tally {
+    sent: usize,
+    bytes: u64,
+    unchanged: usize,
+    deleted: usize,
+    failed: usize,
+}
+
+// Mirrors the source directory to the prefix in the DO, once or, with
+// --watch, until signalled. The first pass compares every file with the DO's
+// copy; later ones only send what changed locally since.
+pub async fn run_sync(options: SyncOptions) -> Result<(), Box<dyn std::error::Error>> {
+    if !fs::metadata(&options.source)?.is_dir() {
+        return Err(format!("{} is not a directory", options.source.display()).into());
+    }
+    let client = RemoteFSClient::new(
+        Encoding::Json,
+        false,
+        1,
+        None,
+        DEFAULT_MAX_IN_FLIGHT,
+        DEFAULT_MAX_FRAME_SIZE,
+        &options.transport,
+    )?;
+    info!("Syncing {} to {}", options.source.display(), options.prefix);
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut known = HashMap::new();
+    let mut remote = Some(remote_files(&client, &options.prefix).await?);
+    loop {
+        let tally = sync_pass(&client, &options, &mut known, remote.take()).await?;
+        if tally.sent + tally.deleted + tally.failed > 0 || !options.watch {
+            info!(
+                sent = tally.sent,
+                bytes = tally.bytes,
+                unchanged = tally.unchanged,
+                deleted = tally.deleted,
+                failed = tally.failed,
+                "Synced {}",
+                options.source.display()
+            );
+        }
+        if !options.watch {
+            return match tally.failed {
+                0 => Ok(()),
+                failed => Err(format!("{} files failed to sync", failed).into()),
+            };
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(options.interval) => {}
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+        }
+    }
+    info!("Stopped syncing {}", options.source.display());
+    Ok(())
+}
+
+// Sends the files that differ from what the DO has, and with --delete removes
+// what is gone locally. `remote` is the DO's side, when it was listed; without
+// it, `known` is trusted to say what the DO has.
+async fn sync_pass(
+    client: &RemoteFSClient,
+    options: &SyncOptions,
+    known: &mut HashMap<String, Seen>,
+    remote: Option<BTreeMap<String, FileStat>>,
+) -> io::Result<Tally> {
+    let mut tally = Tally::default();
+    let local = local_files(&options.source)?;
+    for (name, seen) in &local {
+        let file = options.source.join(name);
+        let path = join(&options.prefix, name);
+        let matches = match remote.as_ref().and_then(|remote| remote.get(name)) {
+            Some(stat) => unchanged(client, options.checksum, &file, &path, seen, stat).await,
+            None => known.get(name) == Some(seen),
+        };
+        if matches {
+            tally.unchanged += 1;
+            known.insert(name.clone(), *seen);
+            continue;
+        }
+        let sent = match File::open(&file) {
+            Ok(file) => send(client, file, &path).await,
+            Err(e) => Err(e.into()),
+        };
+        match sent {
+            Ok(bytes) => {
+                debug!("Sent {} to {}", file.display(), path);
+                tally.sent += 1;
+                tally.bytes += bytes;
+                known.insert(name.clone(), *seen);
+            }
+            Err(e) => {
+                warn!("Failed to sync {} to {}: {}", file.display(), path, e);
+                tally.failed += 1;
+                known.remove(name);
+            }
+        }
+    }
+
+    if !options.delete {
+        return Ok(tally);
+    }
+    let mut gone: Vec<String> = known.keys().filter(|name| !local.contains_key(*name)).cloned().collect();
+    gone.extend(remote.iter().flat_map(|remote| remote.keys()).filter(|name| !local.contains_key(*name)).cloned());
+    gone.sort();
+    gone.dedup();
+    for name in gone {
+        let path = join(&options.prefix, &name);
+        match client.send_request(FsOperation::Unlink, &path, None, None, None).await {
+            Err(e) if e.errno() != libc::ENOENT => {
+                warn!("Failed to delete {}: {}", path, e);
+                tally.failed += 1;
+                continue;
+            }
+            _ => {
+                debug!("Deleted {}", path);
+                tally.deleted += 1;
+            }
+        }
+        known.remove(&name);
+    }
+    Ok(tally)
+}
+
+// Whether the DO's copy of a file can be taken to match the local one: the
+// same size, and either the same checksum, with --checksum, or written no
+// earlier than the local file last changed
+async fn unchanged(
+    client: &RemoteFSClient,
+    checksum: bool,
+    file: &Path,
+    path: &str,
+    seen: &Seen,
+    stat: &FileStat,
+) -> bool {
+    if stat.size != seen.size {
+        return false;
+    }
+    if !checksum {
+        let mtime = seen.mtime.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        return stat.mtime >= mtime;
+    }
+    let local = match local_checksum(file) {
+        Ok(local) => local,
+        Err(e) => {
+            debug!("Failed to checksum {}: {}", file.display(), e);
+            return false;
+        }
+    };
+    let mut hasher = crc32fast::Hasher::new();
+    let mut offset = 0;
+    while offset < stat.size {
+        match client.read_chunked(path, offset, PIECE_SIZE.min(stat.size - offset)).await {
+            Ok(data) if !data.is_empty() => {
+                hasher.update(&data);
+                offset += data.len() as u64;
+            }
+            // Shorter than it was, or unreadable: sending it again settles it
+            _ => return false,
+        }
+    }
+    hasher.finalize() == local
+}
+
+fn local_checksum(file: &Path) -> io::Result<u32> {
+    let mut file = File::open(file)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut piece = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut piece)? {
+            0 => return Ok(hasher.finalize()),
+            len => hasher.update(&piece[..len]),
+        }
+    }
+}
+
+// Replaces the file at `path` in the DO with what `reader` gives, piece by
+// piece, returning how many bytes were sent
+pub async fn send(client: &RemoteFSClient, mut reader: impl Read, path: &str) -> Result<u64, DaemonError> {
+    // Writing nothing at no offset truncates the file, or creates it
+    client.send_request(FsOperation::Write, path, Some(vec![]), None, None).await?;
+    let mut offset = 0;
+    let mut piece = Vec::new();
+    loop {
+        piece.clear();
+        (&mut reader).take(PIECE_SIZE).read_to_end(&mut piece)?;
+        if piece.is_empty() {
+            break;
+        }
+        if let (_, Some(e)) = client.write_chunked(path, offset, &piece).await {
+            return Err(e);
+        }
+        offset += piece.len() as u64;
+    }
+    client.sync(path).await?;
+    Ok(offset)
+}
+
+// Every regular file under `root`, by its path below it. Symlinks, devices and
+// names that aren't UTF-8 are left out, as are files gone by the time they are
+// looked at.
+fn local_files(root: &Path) -> io::Result<BTreeMap<String, Seen>> {
+    let mut files = BTreeMap::new();
+    let mut dirs = vec![String::new()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(root.join(&dir))? {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().into_string() else {
+                warn!("Skipping {}: the name isn't UTF-8", entry.path().display());
+                continue;
+            };
+            let name = match dir.as_str() {
+                "" => name,
+                dir => format!("{}/{}", dir, name),
+            };
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if metadata.is_dir() {
+                dirs.push(name);
+            } else if metadata.is_file() {
+                files.insert(name, Seen { size: metadata.len(), mtime: metadata.modified()? });
+            } else {
+                debug!("Skipping {}: not a regular file", entry.path().display());
+            }
+        }
+    }
+    Ok(files)
+}
+
+// Every file under `prefix` in the DO, by its path below it. Directories are
+// only the prefixes of files, so a name stat doesn't find as a file is one.
+pub async fn remote_files(client: &RemoteFSClient, prefix: &str) -> Result<BTreeMap<String, FileStat>, DaemonError> {
+    let mut files = BTreeMap::new();
+    let mut dirs = vec![String::new()];
+    while let Some(dir) = dirs.pop() {
+        let path = match dir.as_str() {
+            "" => prefix.to_string(),
+            dir => join(prefix, dir),
+        };
+        for name in list(client, &path).await? {
+            let child = join(&path, &name);
+            let name = match dir.as_str() {
+                "" => name,
+                dir => format!("{}/{}", dir, name),
+            };
+            match client.send_request(FsOperation::Stat, &child, None, None, None).await {
+                Ok(response) => match response.stat {
+                    Some(stat) if stat.is_file => {
+                        files.insert(name, stat);
+                    }
+                    _ => dirs.push(name),
+                },
+                Err(e) if e.errno() == libc::ENOENT => dirs.push(name),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(files)
+}
+
+// The names in a directory of the DO, a page at a time; none for one that
+// isn't there
+pub async fn list(client: &RemoteFSClient, path: &str) -> Result<Vec<String>, DaemonError> {
+    let mut names = Vec::new();
+    loop {
+        let position = Some(names.len() as u64);
+        let response =
+            match client.send_request(FsOperation::Readdir, path, None, position, Some(READDIR_PAGE_SIZE)).await {
+                Ok(response) => response,
+                Err(e) if e.errno() == libc::ENOENT => return Ok(names),
+                Err(e) => return Err(e),
+            };
+        let complete = (response.files.len() as u64) < READDIR_PAGE_SIZE;
+        names.extend(response.files);
+        if complete {
+            return Ok(names);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::*;
+
+    #[test]
+    fn only_regular_files_with_utf8_names_are_synced() {
+        let root = std::env::temp_dir().join(format!("fsdaemon-sync-{}", std::process::id()));
+        fs::create_dir_all(root.join("nested/deeper")).unwrap();
+        fs::write(root.join("top.txt"), b"top").unwrap();
+        fs::write(root.join("nested/deeper/low.txt"), b"below").unwrap();
+        fs::write(root.join(OsStr::from_bytes(b"bad-\xff.txt")), b"not utf-8").unwrap();
+        std::os::unix::fs::symlink("top.txt", root.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink("nowhere", root.join("dangling.txt")).unwrap();
+
+        let files = local_files(&root).unwrap();
+        let names: Vec<&str> = files.keys().map(String::as_str).collect();
+        assert_eq!(names, ["nested/deeper/low.txt", "top.txt"]);
+        assert_eq!(files["nested/deeper/low.txt"].size, 5);
+        assert_eq!(files["top.txt"].mtime, fs::metadata(root.join("top.txt")).unwrap().modified().unwrap());
+        assert_eq!(local_checksum(&root.join("top.txt")).unwrap(), crc32fast::hash(b"top"));
+        assert_eq!(local_files(&root.join("missing")).unwrap_err().kind(), io::ErrorKind::NotFound);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
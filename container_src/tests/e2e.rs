@@ -0,0 +1,956 @@
+// Runs the daemon binary against the simulated DO in `sim` and drives a real
+// FUSE mount of it, or its NFS and 9p frontends through the clients in `nfs`
+// and `ninep`. Mounting needs /dev/fuse and fusermount; where they are missing, as in most
+// sandboxes, the mount tests say so and pass without running.
+
+mod nfs;
+mod ninep;
+mod sim;
+
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sim::{Action, Simulator};
+
+// How long the daemon gets to connect and mount
+const STARTUP: Duration = Duration::from_secs(10);
+// How long it gets to unmount and exit once signalled
+const SHUTDOWN: Duration = Duration::from_secs(10);
+
+static MOUNTS: AtomicUsize = AtomicUsize::new(0);
+
+struct Daemon {
+    child: Child,
+    mount_point: PathBuf,
+}
+
+impl Daemon {
+    // Starts the daemon dialing `sim`, with a fresh mount point and caching
+    // turned down so reads and listings reach the DO
+    fn start(sim: &Simulator, args: &[&str]) -> Self {
+        let mount = MOUNTS.fetch_add(1, Ordering::Relaxed);
+        let mount_point = std::env::temp_dir().join(format!("fsdaemon-e2e-{}-{}", std::process::id(), mount));
+        fs::create_dir_all(&mount_point).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_fsdaemon"))
+            .arg(&mount_point)
+            .arg(format!("--connect={}", sim.address()))
+            .args(["--dir-cache-ttl=0s", "--direct-io", "--retry-backoff=10ms"])
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("start the daemon");
+        Self { child, mount_point }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.mount_point.join(name)
+    }
+
+    fn mounted(&self) -> bool {
+        let mounts = fs::read_to_string("/proc/self/mounts").unwrap_or_default();
+        let mount_point = self.mount_point.to_string_lossy();
+        mounts.lines().any(|line| line.split(' ').nth(1) == Some(&mount_point))
+    }
+
+    fn wait_mounted(mut self) -> Self {
+        let started = Instant::now();
+        while !self.mounted() {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                panic!("daemon exited before mounting: {}", status);
+            }
+            assert!(started.elapsed() < STARTUP, "daemon didn't mount within {:?}", STARTUP);
+            thread::sleep(Duration::from_millis(50));
+        }
+        self
+    }
+}
+
+// Signals the daemon to flush and unmount, and kills it if it doesn't
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        unsafe { libc::kill(self.child.id() as i32, libc::SIGTERM) };
+        let started = Instant::now();
+        while self.child.try_wait().ok().flatten().is_none() && started.elapsed() < SHUTDOWN {
+            thread::sleep(Duration::from_millis(50));
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir(&self.mount_point);
+    }
+}
+
+fn fuse_available() -> bool {
+    let on_path = |name: &str| {
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        std::env::split_paths(&path).any(|dir| dir.join(name).exists())
+    };
+    Path::new("/dev/fuse").exists() && (on_path("fusermount3") || on_path("fusermount"))
+}
+
+// Mounts a daemon dialing `sim`, or returns None where FUSE can't be mounted
+fn mount(sim: &Simulator, args: &[&str]) -> Option<Daemon> {
+    if !fuse_available() {
+        eprintln!("skipping: FUSE can't be mounted here (needs /dev/fuse and fusermount)");
+        return None;
+    }
+    Some(Daemon::start(sim, args).wait_mounted())
+}
+
+// Starts a daemon dialing `sim` that serves over NFS instead of FUSE, which
+// needs neither /dev/fuse nor root, and a client with the export's root handle
+fn serve_nfs(sim: &Simulator, args: &[&str]) -> (Daemon, nfs::Client, Vec<u8>) {
+    let address = free_address();
+    let listen = format!("--nfs-listen={}", address);
+    let daemon = Daemon::start(sim, &[&["--frontend=nfs", &listen], args].concat());
+    wait_for("the NFS frontend", || TcpStream::connect(&address).is_ok());
+    let mut client = nfs::Client::connect(&address).unwrap();
+    let root = client.mount(&daemon.mount_point.to_string_lossy());
+    (daemon, client, root)
+}
+
+// A loopback address nothing is listening on
+fn free_address() -> String {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string()
+}
+
+// Sends one request over a connection of its own, returning the status and body
+fn http(address: &str, method: &str, target: &str, body: &[u8]) -> (u16, String) {
+    let head = format!("{} {} HTTP/1.1\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", method, target, body.len());
+    http_raw(address, &[head.as_bytes(), body].concat()).unwrap()
+}
+
+// Sends `request` as it is and closes the sending side, returning the status
+// and body, or None if the connection closed without an answer
+fn http_raw(address: &str, request: &[u8]) -> Option<(u16, String)> {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(request).unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n")?;
+    Some((head[9..12].parse().unwrap(), body.to_string()))
+}
+
+fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+    let started = Instant::now();
+    while !done() {
+        assert!(started.elapsed() < STARTUP, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn daemon_says_hello_to_the_do() {
+    let sim = Simulator::start();
+    let _daemon = Daemon::start(&sim, &[]);
+    wait_for("the hello", || sim.seen("hello", "/") > 0);
+}
+
+#[test]
+fn reads_writes_and_listings_reach_the_do() {
+    let sim = Simulator::start();
+    sim.put("/existing.txt", b"from the DO");
+    let Some(daemon) = mount(&sim, &[]) else { return };
+
+    assert_eq!(fs::read(daemon.path("existing.txt")).unwrap(), b"from the DO");
+
+    fs::write(daemon.path("new.txt"), b"from the mount").unwrap();
+    assert_eq!(sim.file("/new.txt").unwrap(), b"from the mount");
+    assert_eq!(fs::read(daemon.path("new.txt")).unwrap(), b"from the mount");
+
+    let mut names: Vec<String> = fs::read_dir(&daemon.mount_point)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["existing.txt", "new.txt"]);
+
+    fs::remove_file(daemon.path("existing.txt")).unwrap();
+    assert_eq!(sim.file("/existing.txt"), None);
+    assert_eq!(fs::metadata(daemon.path("existing.txt")).unwrap_err().kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn namespaces_reach_their_own_do() {
+    let sim = Simulator::start();
+    let shared = Simulator::start();
+    shared.put("/team/notes.txt", b"shared notes");
+    let namespace = format!("--namespace=shared:/team,connect={}", shared.address());
+    let daemon = Daemon::start(&sim, &[&namespace]);
+    wait_for("the namespace's hello", || shared.seen("hello", "/") > 0);
+    if !fuse_available() {
+        eprintln!("skipping the mount: FUSE can't be mounted here (needs /dev/fuse and fusermount)");
+        return;
+    }
+    let daemon = daemon.wait_mounted();
+
+    assert_eq!(fs::read(daemon.path("shared/notes.txt")).unwrap(), b"shared notes");
+    fs::write(daemon.path("shared/new.txt"), b"for the team").unwrap();
+    assert_eq!(shared.file("/team/new.txt").unwrap(), b"for the team");
+    assert_eq!(sim.file("/shared/new.txt"), None);
+
+    fs::write(daemon.path("own.txt"), b"mine").unwrap();
+    assert_eq!(sim.file("/own.txt").unwrap(), b"mine");
+    let names: Vec<String> = fs::read_dir(&daemon.mount_point)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(names, ["shared", "own.txt"]);
+}
+
+#[test]
+fn the_dos_errnos_reach_applications() {
+    let sim = Simulator::start();
+    sim.put("/denied.txt", b"secret");
+    sim.script("stat", "/denied.txt", usize::MAX, Action::Fail(libc::EACCES));
+    let Some(daemon) = mount(&sim, &[]) else { return };
+
+    let error = fs::metadata(daemon.path("denied.txt")).unwrap_err();
+    assert_eq!(error.raw_os_error(), Some(libc::EACCES));
+}
+
+#[test]
+fn modes_are_kept_in_the_do() {
+    let sim = Simulator::start();
+    sim.put("/plain.txt", b"no mode");
+    let Some(daemon) = mount(&sim, &[]) else { return };
+
+    assert_eq!(fs::metadata(daemon.path("plain.txt")).unwrap().permissions().mode() & 0o7777, 0o644);
+    let script = daemon.path("run.sh");
+    fs::OpenOptions::new().write(true).create_new(true).mode(0o750).open(&script).unwrap();
+    // Less whatever the umask takes away
+    let created = fs::metadata(&script).unwrap().permissions().mode() & 0o7777;
+    assert_eq!(created & !0o750, 0);
+    assert_eq!(sim.mode("/run.sh"), Some(created));
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o700)).unwrap();
+    assert_eq!(sim.mode("/run.sh"), Some(0o700));
+    assert_eq!(fs::metadata(&script).unwrap().permissions().mode() & 0o7777, 0o700);
+}
+
+#[test]
+fn nfs_creates_and_setattrs_keep_modes() {
+    let sim = Simulator::start();
+    let (_daemon, mut client, root) = serve_nfs(&sim, &[]);
+
+    let (status, file) = client.create(&root, "run.sh", Some(0o750));
+    assert_eq!(status, 0);
+    assert_eq!(sim.mode("/run.sh"), Some(0o750));
+    assert_eq!(client.setattr(&file, Some(0o700), None), 0);
+    assert_eq!(sim.mode("/run.sh"), Some(0o700));
+    assert_eq!(client.getattr(&file).unwrap().mode & 0o7777, 0o700);
+}
+
+#[test]
+fn files_the_daemon_makes_up_are_owned_like_the_dos() {
+    let sim = Simulator::start();
+    let (_daemon, mut client, root) = serve_nfs(&sim, &["--owner=1234:5678"]);
+
+    let (status, file) = client.create(&root, "data", None);
+    assert_eq!(status, 0);
+    let control = client.lookup(&root, ".fsctl").unwrap();
+    for handle in [file, control] {
+        let attr = client.getattr(&handle).unwrap();
+        assert_eq!((attr.uid, attr.gid), (1234, 5678));
+    }
+}
+
+#[test]
+fn nfs_listings_page_by_cookie() {
+    let sim = Simulator::start();
+    let mut names: Vec<String> = (0..40).map(|i| format!("file-{:02}.txt", i)).collect();
+    for name in &names {
+        sim.put(&format!("/{}", name), b"listed");
+    }
+    let (_daemon, mut client, root) = serve_nfs(&sim, &[]);
+
+    let (mut listed, mut pages, mut cookie) = (Vec::new(), Vec::new(), 0);
+    loop {
+        let (entries, eof) = client.readdirplus(&root, cookie, 1024).unwrap();
+        assert!(!entries.is_empty() || eof);
+        pages.push((cookie, entries.iter().map(|entry| entry.name.clone()).collect::<Vec<_>>()));
+        for entry in &entries {
+            assert!(client.getattr(&entry.handle).is_ok(), "{}", entry.name);
+        }
+        cookie = entries.last().map_or(cookie, |entry| entry.cookie);
+        listed.extend(entries.into_iter().map(|entry| entry.name));
+        if eof {
+            break;
+        }
+    }
+    assert!(pages.len() > 2, "{} pages", pages.len());
+    listed.retain(|name| name != "." && name != "..");
+    listed.sort();
+    names.sort();
+    assert_eq!(listed, names);
+
+    // A cookie given out before picks up at the same place again
+    let (cookie, page) = &pages[1];
+    let (again, _) = client.readdirplus(&root, *cookie, 1024).unwrap();
+    assert_eq!(&again.into_iter().map(|entry| entry.name).collect::<Vec<_>>(), page);
+    // Past the end there is nothing more, and too little room for any entry fails
+    let (entries, eof) = client.readdirplus(&root, u64::MAX, 1024).unwrap();
+    assert!(entries.is_empty() && eof);
+    assert_eq!(client.readdirplus(&root, 0, 100).unwrap_err(), nfs::NFS3ERR_TOOSMALL);
+}
+
+#[test]
+fn nfs_handles_outlive_their_files_but_not_restarts() {
+    let sim = Simulator::start();
+    sim.put("/kept.txt", b"kept");
+    let (daemon, mut client, root) = serve_nfs(&sim, &[]);
+    let kept = client.lookup(&root, "kept.txt").unwrap();
+    let (status, removed) = client.create(&root, "removed.txt", None);
+    assert_eq!(status, 0);
+    assert_eq!(client.remove(&root, "removed.txt"), 0);
+    // The handle still names the file, which is gone
+    assert_eq!(client.getattr(&removed).unwrap_err(), nfs::NFS3ERR_NOENT);
+    assert!(client.getattr(&kept).is_ok());
+    drop(daemon);
+
+    let (_daemon, mut client, root) = serve_nfs(&sim, &[]);
+    assert_eq!(client.getattr(&kept).unwrap_err(), nfs::NFS3ERR_STALE);
+    assert!(client.getattr(&root).is_ok());
+    let kept = client.lookup(&root, "kept.txt").unwrap();
+    assert!(client.getattr(&kept).is_ok());
+}
+
+#[test]
+fn nfs_records_too_long_close_the_connection() {
+    let sim = Simulator::start();
+    let (_daemon, mut client, root) = serve_nfs(&sim, &[]);
+    assert!(client.getattr(&root).is_ok());
+    // Only the header is sent: the length alone is enough to refuse it
+    assert_eq!(client.header_only(64 * 1024 * 1024), None);
+}
+
+#[test]
+fn ninep_walks_stop_where_names_run_out_and_listings_page_by_offset() {
+    let sim = Simulator::start();
+    let names: Vec<String> = (0..30).map(|i| format!("file-{:02}.txt", i)).collect();
+    for name in &names {
+        sim.put(&format!("/{}", name), b"listed");
+    }
+    let address = free_address();
+    let listen = format!("--9p-listen={}", address);
+    let _daemon = Daemon::start(&sim, &["--frontend=9p", &listen]);
+    wait_for("the 9p frontend", || TcpStream::connect(&address).is_ok());
+    let mut client = ninep::Client::connect(&address).unwrap();
+    assert_eq!(client.attach(1, "/nowhere"), Err(libc::ENOENT));
+    client.attach(1, "").unwrap();
+
+    // A walk stopping part way says how far it got and makes no fid
+    assert_eq!(client.walk(1, 2, &["..", "file-00.txt", "inside"]), Ok(2));
+    assert_eq!(client.clunk(2), Err(libc::EBADF));
+    assert_eq!(client.walk(1, 2, &["missing"]), Err(libc::ENOENT));
+    assert_eq!(client.walk(1, 2, &["..", "file-00.txt"]), Ok(2));
+    assert_eq!(client.walk(1, 2, &[]), Err(libc::EBADF));
+    client.clunk(2).unwrap();
+    assert_eq!(client.walk(1, 2, &[]), Ok(0));
+    client.lopen(2, libc::O_RDONLY as u32).unwrap();
+
+    let (mut listed, mut pages, mut offset) = (Vec::new(), Vec::new(), 0);
+    loop {
+        let entries = client.readdir(2, offset, 256).unwrap();
+        if entries.is_empty() {
+            break;
+        }
+        pages.push((offset, entries.clone()));
+        offset = entries.last().unwrap().offset;
+        listed.extend(entries.into_iter().map(|entry| entry.name));
+    }
+    assert!(pages.len() > 2, "{} pages", pages.len());
+    listed.retain(|name| name != "." && name != "..");
+    assert_eq!(listed, names);
+    // An offset given out before picks up at the same place again
+    let (offset, page) = &pages[1];
+    assert_eq!(&client.readdir(2, *offset, 256).unwrap(), page);
+    assert_eq!(client.readdir(1, 0, 256), Err(libc::EBADF));
+    client.clunk(2).unwrap();
+    assert_eq!(client.readdir(2, 0, 256), Err(libc::EBADF));
+}
+
+#[test]
+fn endpoints_store_and_list_names_by_the_name_policy() {
+    let sim = Simulator::start();
+    sim.put("/a%0Ab", b"escaped");
+    let (webdav, s3) = (free_address(), free_address());
+    let listen = [format!("--webdav-listen={}", webdav), format!("--s3-listen={}", s3)];
+    let (daemon, _, _) = serve_nfs(&sim, &["--name-policy=escape", &listen[0], &listen[1]]);
+    wait_for("the WebDAV endpoint", || TcpStream::connect(&webdav).is_ok());
+    let mount = daemon.mount_point.to_string_lossy().into_owned();
+
+    assert_eq!(http(&webdav, "GET", &format!("{}/a%0Ab", mount), b""), (200, "escaped".to_string()));
+    assert_eq!(http(&webdav, "PUT", &format!("{}/100%25.txt", mount), b"percent").0, 201);
+    assert_eq!(sim.file("/100%25.txt").unwrap(), b"percent");
+    let (status, listing) = http(&webdav, "PROPFIND", &mount, b"");
+    assert_eq!(status, 207);
+    assert!(listing.contains("<D:displayname>100%.txt</D:displayname>"), "{}", listing);
+    assert!(listing.contains("<D:displayname>a\nb</D:displayname>"), "{}", listing);
+    let bucket = mount.rsplit('/').next().unwrap();
+    let (status, keys) = http(&s3, "GET", &format!("/{}?list-type=2", bucket), b"");
+    assert_eq!(status, 200);
+    assert!(keys.contains("<Key>100%.txt</Key>") && keys.contains("<Key>a\nb</Key>"), "{}", keys);
+    drop(daemon);
+
+    // Rejected names are bad requests
+    let writes = sim.seen_any("write");
+    let webdav = free_address();
+    let (daemon, _, _) = serve_nfs(&sim, &[&format!("--webdav-listen={}", webdav)]);
+    wait_for("the WebDAV endpoint", || TcpStream::connect(&webdav).is_ok());
+    let target = format!("{}/bell%07.txt", daemon.mount_point.to_string_lossy());
+    assert_eq!(http(&webdav, "PUT", &target, b"ding").0, 400);
+    assert_eq!(sim.seen_any("write"), writes);
+}
+
+#[test]
+fn webdav_answers_by_method_and_depth_and_refuses_bad_requests() {
+    let sim = Simulator::start();
+    sim.put("/a.txt", b"alpha");
+    sim.put("/b.txt", b"beta");
+    sim.script("stat", "/denied.txt", usize::MAX, Action::Fail(libc::EACCES));
+    let webdav = free_address();
+    let (daemon, _, _) = serve_nfs(&sim, &[&format!("--webdav-listen={}", webdav)]);
+    wait_for("the WebDAV endpoint", || TcpStream::connect(&webdav).is_ok());
+    let mount = daemon.mount_point.to_string_lossy().into_owned();
+    let propfind = |target: &str, depth: &str| {
+        let request = format!("PROPFIND {} HTTP/1.1\r\nDepth: {}\r\nConnection: close\r\n\r\n", target, depth);
+        let (status, body) = http_raw(&webdav, request.as_bytes()).unwrap();
+        assert_eq!(status, 207);
+        body.matches("<D:response>").count()
+    };
+
+    assert_eq!(propfind(&mount, "0"), 1);
+    assert_eq!(propfind(&mount, "1"), 3);
+    assert_eq!(propfind(&format!("{}/a.txt", mount), "1"), 1);
+    assert_eq!(propfind("/", "1"), 2);
+    assert_eq!(http(&webdav, "HEAD", &format!("{}/a.txt", mount), b""), (200, String::new()));
+    assert_eq!(http(&webdav, "GET", &format!("{}/missing.txt", mount), b"").0, 404);
+    assert_eq!(http(&webdav, "GET", "/nowhere/a.txt", b"").0, 404);
+    assert_eq!(http(&webdav, "GET", &format!("{}/denied.txt", mount), b"").0, 403);
+    assert_eq!(http(&webdav, "MKCOL", &format!("{}/dir", mount), b"").0, 405);
+    assert_eq!(http(&webdav, "PUT", "/", b"above").0, 405);
+    assert_eq!(http(&webdav, "DELETE", "/", b"").0, 403);
+    assert_eq!(http(&webdav, "DELETE", &format!("{}/missing.txt", mount), b"").0, 404);
+    assert_eq!(http(&webdav, "DELETE", &format!("{}/b.txt", mount), b"").0, 204);
+    assert_eq!(sim.file("/b.txt"), None);
+
+    // Paths that don't decode or climb out are bad requests
+    assert_eq!(http(&webdav, "GET", &format!("{}/a%zz", mount), b"").0, 400);
+    assert_eq!(http(&webdav, "GET", &format!("{}/../a.txt", mount), b"").0, 400);
+    // Requests without a whole request line or head get no answer at all
+    assert_eq!(http_raw(&webdav, b"GET\r\n\r\n"), None);
+    assert_eq!(http_raw(&webdav, format!("GET {}/a.txt HTTP/1.1\r\nHost: x", mount).as_bytes()), None);
+
+    // Chunked bodies are put together. One cut short goes unanswered, and
+    // like any write it leaves what got through.
+    let put = format!("PUT {}/c.txt HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n", mount);
+    let whole = [put.as_bytes(), b"3\r\ngam\r\n2\r\nma\r\n0\r\n\r\n"].concat();
+    assert_eq!(http_raw(&webdav, &whole).unwrap().0, 201);
+    assert_eq!(sim.file("/c.txt").unwrap(), b"gamma");
+    let cut = [put.as_bytes(), b"3\r\ndel\r\n9\r\nta"].concat();
+    assert_eq!(http_raw(&webdav, &cut), None);
+    assert_eq!(sim.file("/c.txt").unwrap(), b"delta");
+}
+
+// What each <name> element in `xml` holds, in order
+fn elements(xml: &str, name: &str) -> Vec<String> {
+    let (open, close) = (format!("<{}>", name), format!("</{}>", name));
+    xml.split(&open).skip(1).filter_map(|rest| rest.split_once(&close)).map(|(value, _)| value.to_string()).collect()
+}
+
+#[test]
+fn s3_listings_page_by_token_and_marker() {
+    let sim = Simulator::start();
+    let mut keys: Vec<String> = (0..7).map(|i| format!("log-{}.txt", i)).collect();
+    keys.extend(["a b.txt".to_string(), "other.txt".to_string()]);
+    for key in &keys {
+        sim.put(&format!("/{}", key), b"listed");
+    }
+    let s3 = free_address();
+    let (daemon, _, _) = serve_nfs(&sim, &[&format!("--s3-listen={}", s3)]);
+    wait_for("the S3 endpoint", || TcpStream::connect(&s3).is_ok());
+    let bucket = format!("/{}", daemon.mount_point.file_name().unwrap().to_string_lossy());
+    let list = |query: &str| {
+        let (status, xml) = http(&s3, "GET", &format!("{}?{}", bucket, query), b"");
+        assert_eq!(status, 200, "{}", xml);
+        xml
+    };
+
+    let (mut listed, mut pages, mut token) = (Vec::new(), Vec::new(), None::<String>);
+    loop {
+        let query = match &token {
+            Some(token) => format!("list-type=2&prefix=log-&max-keys=3&continuation-token={}", token),
+            None => "list-type=2&prefix=log-&max-keys=3".to_string(),
+        };
+        let xml = list(&query);
+        let page = elements(&xml, "Key");
+        assert_eq!(elements(&xml, "KeyCount"), [page.len().to_string()]);
+        assert_eq!(elements(&xml, "ContinuationToken"), token.iter().cloned().collect::<Vec<_>>());
+        listed.extend(page.iter().cloned());
+        pages.push((query, page));
+        token = elements(&xml, "NextContinuationToken").pop();
+        assert_eq!(elements(&xml, "IsTruncated"), [token.is_some().to_string()]);
+        if token.is_none() {
+            break;
+        }
+    }
+    assert_eq!(pages.len(), 3);
+    assert_eq!(listed, keys[..7]);
+    // A token given out before picks up at the same place again
+    let (query, page) = &pages[1];
+    assert_eq!(&elements(&list(query), "Key"), page);
+
+    // ListObjects pages by marker instead
+    let xml = list("marker=log-5.txt&max-keys=2");
+    assert_eq!(elements(&xml, "Key"), ["log-6.txt", "other.txt"]);
+    assert_eq!(elements(&xml, "NextMarker"), Vec::<String>::new());
+    let xml = list("max-keys=1&encoding-type=url");
+    assert_eq!(elements(&xml, "Key"), ["a%20b.txt"]);
+    assert_eq!(elements(&xml, "NextMarker"), ["a%20b.txt"]);
+    let xml = list("list-type=2&start-after=other.txt");
+    assert!(elements(&xml, "Key").is_empty() && elements(&xml, "IsTruncated") == ["false"], "{}", xml);
+
+    let (status, xml) = http(&s3, "GET", &format!("{}?list-type=2&max-keys=many", bucket), b"");
+    assert_eq!((status, elements(&xml, "Code")), (400, vec!["InvalidArgument".to_string()]));
+    // Parameters that aren't a listing's name subresources there are none of
+    let (status, xml) = http(&s3, "GET", &format!("{}?versioning", bucket), b"");
+    assert_eq!((status, elements(&xml, "Code")), (501, vec!["NotImplemented".to_string()]));
+}
+
+#[test]
+fn s3_objects_answer_with_s3_errors() {
+    let sim = Simulator::start();
+    sim.put("/a.txt", b"0123456789");
+    sim.script("stat", "/denied.txt", usize::MAX, Action::Fail(libc::EACCES));
+    let s3 = free_address();
+    let (daemon, _, _) = serve_nfs(&sim, &[&format!("--s3-listen={}", s3)]);
+    wait_for("the S3 endpoint", || TcpStream::connect(&s3).is_ok());
+    let bucket = format!("/{}", daemon.mount_point.file_name().unwrap().to_string_lossy());
+    let error = |method: &str, target: &str| {
+        let (status, xml) = http(&s3, method, target, b"");
+        (status, elements(&xml, "Code").concat())
+    };
+
+    assert_eq!(error("GET", "/nowhere/a.txt"), (404, "NoSuchBucket".to_string()));
+    assert_eq!(error("PUT", "/nowhere"), (501, "NotImplemented".to_string()));
+    assert_eq!(error("PUT", &bucket), (409, "BucketAlreadyOwnedByYou".to_string()));
+    assert_eq!(error("DELETE", "/"), (405, "MethodNotAllowed".to_string()));
+    assert_eq!(error("GET", &format!("{}/missing.txt", bucket)), (404, "NoSuchKey".to_string()));
+    assert_eq!(error("GET", &format!("{}/denied.txt", bucket)), (403, "AccessDenied".to_string()));
+    assert_eq!(error("GET", &format!("{}/.fsctl", bucket)), (403, "AccessDenied".to_string()));
+    assert_eq!(error("POST", &format!("{}/a.txt?uploads", bucket)), (501, "NotImplemented".to_string()));
+    assert_eq!(error("DELETE", &format!("{}/missing.txt", bucket)).0, 204);
+
+    let get = |range: &str| {
+        let request = format!("GET {}/a.txt HTTP/1.1\r\nRange: {}\r\nConnection: close\r\n\r\n", bucket, range);
+        http_raw(&s3, request.as_bytes()).unwrap()
+    };
+    assert_eq!(get("bytes=2-4"), (206, "234".to_string()));
+    assert_eq!(get("bytes=-3"), (206, "789".to_string()));
+    // Several ranges at once get all of it
+    assert_eq!(get("bytes=0-1,4-5"), (200, "0123456789".to_string()));
+    assert_eq!(get("bytes=10-").0, 416);
+
+    // Bodies an SDK signs chunk by chunk are taken apart; copies aren't made
+    let body = b"5;chunk-signature=aa\r\nhello\r\n0;chunk-signature=bb\r\n\r\n";
+    let put = format!(
+        "PUT {}/b.txt HTTP/1.1\r\nx-amz-content-sha256: STREAMING-AWS4-HMAC-SHA256-PAYLOAD\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        bucket,
+        body.len()
+    );
+    let request = [put.as_bytes(), body].concat();
+    assert_eq!(http_raw(&s3, &request).unwrap().0, 200);
+    assert_eq!(sim.file("/b.txt").unwrap(), b"hello");
+    let copy = format!(
+        "PUT {}/c.txt HTTP/1.1\r\nx-amz-copy-source: {}/a.txt\r\nConnection: close\r\n\r\n",
+        bucket, bucket
+    );
+    assert_eq!(http_raw(&s3, copy.as_bytes()).unwrap().0, 501);
+    assert_eq!(sim.file("/c.txt"), None);
+}
+
+// Whether OpenSSH's sftp, logging in to `address` as `user` with the private
+// key at `key` or with no key at all, got to list the first mount
+fn sftp_lists(address: &str, user: &str, key: Option<&Path>) -> bool {
+    let (host, port) = address.rsplit_once(':').unwrap();
+    let options = ["StrictHostKeyChecking=no", "UserKnownHostsFile=/dev/null", "BatchMode=yes", "LogLevel=ERROR"];
+    let mut command = Command::new("sftp");
+    for option in options {
+        command.args(["-o", option]);
+    }
+    match key {
+        Some(key) => command.args(["-o", "IdentitiesOnly=yes", "-i"]).arg(key),
+        None => command.args(["-o", "PubkeyAuthentication=no"]),
+    };
+    let mut child = command
+        .args(["-b", "-", "-P", port, &format!("{}@{}", user, host)])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("run sftp");
+    child.stdin.take().unwrap().write_all(b"ls\n").unwrap();
+    child.wait().unwrap().success()
+}
+
+// Keys of each type the SFTP endpoint takes, made with ssh-keygen in `dir`,
+// or None where there is no ssh-keygen or sftp to run
+fn ssh_keys(dir: &Path) -> Option<Vec<PathBuf>> {
+    let runs = |tool: &str| Command::new(tool).arg("-?").stderr(Stdio::null()).status().is_ok();
+    if !runs("ssh-keygen") || !runs("sftp") {
+        eprintln!("skipping: there is no ssh-keygen or sftp to run");
+        return None;
+    }
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir).unwrap();
+    let keys = ["ed25519", "ecdsa", "rsa", "stranger"].map(|name| {
+        let key = dir.join(name);
+        let key_type = if name == "stranger" { "ed25519" } else { name };
+        let made = Command::new("ssh-keygen").args(["-q", "-N", "", "-t", key_type, "-f"]).arg(&key).status();
+        assert!(made.unwrap().success());
+        key
+    });
+    Some(keys.to_vec())
+}
+
+#[test]
+fn sftp_lets_in_only_authorized_keys_of_the_daemons_user_unless_told_otherwise() {
+    let dir = std::env::temp_dir().join(format!("fsdaemon-e2e-sftp-{}", std::process::id()));
+    let Some(keys) = ssh_keys(&dir) else { return };
+    let authorized = dir.join("authorized_keys");
+    let public = |key: &PathBuf| fs::read_to_string(key.with_extension("pub")).unwrap();
+    fs::write(&authorized, keys[..3].iter().map(public).collect::<String>()).unwrap();
+    let authorized_keys = format!("--sftp-authorized-keys={}", authorized.display());
+
+    let sim = Simulator::start();
+    sim.put("/notes.txt", b"listed");
+    let user = String::from_utf8(Command::new("id").arg("-un").output().unwrap().stdout).unwrap();
+    let sftp = free_address();
+    let (daemon, _, _) = serve_nfs(&sim, &[&format!("--sftp-listen={}", sftp), &authorized_keys]);
+    wait_for("the SFTP endpoint", || TcpStream::connect(&sftp).is_ok());
+    for key in &keys[..3] {
+        assert!(sftp_lists(&sftp, user.trim(), Some(key)), "{} refused", key.display());
+    }
+    assert!(!sftp_lists(&sftp, user.trim(), Some(&keys[3])));
+    assert!(!sftp_lists(&sftp, user.trim(), None));
+    assert!(!sftp_lists(&sftp, "somebody-else", Some(&keys[0])));
+    drop(daemon);
+
+    let sftp = free_address();
+    let listen = format!("--sftp-listen={}", sftp);
+    let (_daemon, _, _) = serve_nfs(&sim, &[&listen, &authorized_keys, "--sftp-allow-any-user"]);
+    wait_for("the SFTP endpoint", || TcpStream::connect(&sftp).is_ok());
+    assert!(sftp_lists(&sftp, "somebody-else", Some(&keys[0])));
+    assert!(!sftp_lists(&sftp, "somebody-else", Some(&keys[3])));
+}
+
+#[test]
+fn files_stop_at_the_max_file_size() {
+    let sim = Simulator::start();
+    let Some(daemon) = mount(&sim, &["--max-file-size=8"]) else { return };
+
+    fs::write(daemon.path("small.txt"), b"12345678").unwrap();
+    assert_eq!(sim.file("/small.txt").unwrap(), b"12345678");
+    let error = fs::write(daemon.path("large.txt"), b"123456789").unwrap_err();
+    assert_eq!(error.raw_os_error(), Some(libc::EFBIG));
+    assert_eq!(sim.seen("write", "/large.txt"), 0);
+
+    // Truncating past the limit fails, and to nothing empties the file
+    let file = fs::OpenOptions::new().write(true).open(daemon.path("small.txt")).unwrap();
+    assert_eq!(file.set_len(9).unwrap_err().raw_os_error(), Some(libc::EFBIG));
+    file.set_len(0).unwrap();
+    assert_eq!(fs::metadata(daemon.path("small.txt")).unwrap().len(), 0);
+    assert_eq!(sim.file("/small.txt").unwrap(), b"");
+}
+
+#[test]
+fn nfs_truncates_to_nothing_but_not_past_the_max_file_size() {
+    let sim = Simulator::start();
+    sim.put("/log.txt", b"12345678");
+    let (_daemon, mut client, root) = serve_nfs(&sim, &["--max-file-size=8"]);
+
+    let file = client.lookup(&root, "log.txt").unwrap();
+    assert_eq!(client.setattr(&file, None, Some(9)), nfs::NFS3ERR_FBIG);
+    assert_eq!(sim.file("/log.txt").unwrap(), b"12345678");
+    assert_eq!(client.setattr(&file, None, Some(0)), 0);
+    assert_eq!(sim.file("/log.txt").unwrap(), b"");
+    assert_eq!(client.getattr(&file).unwrap().size, 0);
+}
+
+#[test]
+fn writes_the_do_rejects_give_their_quota_back() {
+    let sim = Simulator::start();
+    sim.put("/rejected.txt", b"");
+    sim.script("write", "/rejected.txt", 10, Action::Fail(libc::EIO));
+    let Some(daemon) = mount(&sim, &["--quota-bytes=8"]) else { return };
+
+    let mut file = fs::OpenOptions::new().write(true).open(daemon.path("rejected.txt")).unwrap();
+    file.write_all(b"12345678").unwrap();
+    assert!(file.sync_all().is_err());
+    drop(file);
+    fs::write(daemon.path("kept.txt"), b"12345678").unwrap();
+    assert_eq!(sim.file("/kept.txt").unwrap(), b"12345678");
+}
+
+#[test]
+fn nfs_creates_the_do_rejects_give_their_quota_back() {
+    let sim = Simulator::start();
+    sim.script("write", "/rejected", 1, Action::Fail(libc::EIO));
+    let (_daemon, mut client, root) = serve_nfs(&sim, &["--quota-files=1"]);
+
+    assert_ne!(client.create(&root, "rejected", None).0, 0);
+    assert_eq!(client.create(&root, "kept", None).0, 0);
+    assert!(sim.file("/kept").is_some());
+}
+
+#[test]
+fn requests_carry_the_id_of_their_operation() {
+    let sim = Simulator::start();
+    sim.put("/notes.txt", b"traced");
+    sim.script("read", "/broken.txt", 1, Action::Fail(libc::EIO));
+    sim.put("/broken.txt", b"unreadable");
+    let Some(daemon) = mount(&sim, &[]) else { return };
+
+    assert_eq!(fs::read(daemon.path("notes.txt")).unwrap(), b"traced");
+    assert!(fs::read(daemon.path("broken.txt")).is_err());
+    let [read, failed] = [sim.correlations("read", "/notes.txt"), sim.correlations("read", "/broken.txt")];
+    let ids: Vec<&String> = read.iter().chain(&failed).map(|cid| cid.as_ref().expect("a correlation id")).collect();
+    assert!(ids.iter().all(|cid| cid.len() == 16 && cid.chars().all(|c| c.is_ascii_hexdigit())));
+    // Each FUSE operation has an id of its own
+    assert!(read.iter().all(|cid| !failed.contains(cid)));
+}
+
+#[test]
+fn passing_failures_are_retried() {
+    let sim = Simulator::start();
+    sim.script("write", "/busy.txt", 1, Action::Fail(libc::EAGAIN));
+    let Some(daemon) = mount(&sim, &[]) else { return };
+
+    fs::write(daemon.path("busy.txt"), b"eventually").unwrap();
+    assert_eq!(sim.file("/busy.txt").unwrap(), b"eventually");
+    assert!(sim.seen("write", "/busy.txt") >= 2);
+}
+
+#[test]
+fn slow_answers_are_waited_for_up_to_the_timeout() {
+    let sim = Simulator::start();
+    sim.put("/slow.txt", b"worth the wait");
+    sim.put("/stuck.txt", b"never arrives");
+    sim.script("read", "/slow.txt", 1, Action::Delay(Duration::from_millis(500)));
+    sim.script("read", "/stuck.txt", usize::MAX, Action::Delay(Duration::from_secs(3)));
+    let Some(daemon) = mount(&sim, &["--read-timeout=1s", "--retry-attempts=0"]) else { return };
+
+    assert_eq!(fs::read(daemon.path("slow.txt")).unwrap(), b"worth the wait");
+    let error = fs::read(daemon.path("stuck.txt")).unwrap_err();
+    assert_eq!(error.raw_os_error(), Some(libc::EIO));
+}
+
+#[test]
+fn signalled_callers_stop_waiting_and_cancel_their_request() {
+    let sim = Simulator::start();
+    sim.put("/stuck.txt", b"too late");
+    sim.script("read", "/stuck.txt", 1, Action::Delay(Duration::from_secs(5)));
+    let Some(daemon) = mount(&sim, &["--read-timeout=30s"]) else { return };
+
+    let mut reader = Command::new("cat").arg(daemon.path("stuck.txt")).stdout(Stdio::null()).spawn().unwrap();
+    wait_for("the read", || sim.seen("read", "/stuck.txt") > 0);
+    unsafe { libc::kill(reader.id() as i32, libc::SIGTERM) };
+    let signalled = Instant::now();
+    reader.wait().unwrap();
+    assert!(signalled.elapsed() < Duration::from_secs(2), "the reader waited {:?}", signalled.elapsed());
+    wait_for("the cancel", || sim.seen_any("cancel") > 0);
+}
+
+#[test]
+fn requests_lost_with_the_connection_are_resent() {
+    let sim = Simulator::start();
+    sim.put("/dropped.txt", b"second time lucky");
+    sim.script("read", "/dropped.txt", 1, Action::Drop);
+    let Some(daemon) = mount(&sim, &[]) else { return };
+
+    assert_eq!(fs::read(daemon.path("dropped.txt")).unwrap(), b"second time lucky");
+    assert!(sim.seen("hello", "/") >= 2);
+}
+
+// Runs `fsdaemon sync` dialing `sim` until it exits, returning whether it succeeded
+fn sync(sim: &Simulator, args: &[&str]) -> bool {
+    Command::new(env!("CARGO_BIN_EXE_fsdaemon"))
+        .arg("sync")
+        .args(args)
+        .arg(format!("--connect={}", sim.address()))
+        .arg("--retry-backoff=10ms")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .expect("run the sync")
+        .success()
+}
+
+#[test]
+fn sync_sends_only_what_changed() {
+    let sim = Simulator::start();
+    sim.put("/backup/stale.txt", b"deleted locally");
+    let source = std::env::temp_dir().join(format!("fsdaemon-e2e-sync-{}", std::process::id()));
+    fs::create_dir_all(source.join("nested")).unwrap();
+    fs::write(source.join("same.txt"), b"unchanged").unwrap();
+    fs::write(source.join("nested/edited.txt"), b"first").unwrap();
+    let dir = source.to_string_lossy();
+
+    assert!(sync(&sim, &[&dir, "/backup", "--delete"]));
+    assert_eq!(sim.file("/backup/same.txt").unwrap(), b"unchanged");
+    assert_eq!(sim.file("/backup/nested/edited.txt").unwrap(), b"first");
+    assert_eq!(sim.file("/backup/stale.txt"), None);
+
+    let writes = sim.seen("write", "/backup/same.txt");
+    fs::write(source.join("nested/edited.txt"), b"second version").unwrap();
+    assert!(sync(&sim, &[&dir, "/backup", "--checksum"]));
+    assert_eq!(sim.seen("write", "/backup/same.txt"), writes);
+    assert_eq!(sim.file("/backup/nested/edited.txt").unwrap(), b"second version");
+    fs::remove_dir_all(source).unwrap();
+}
+
+#[test]
+fn sync_pages_through_long_listings_and_fails_on_what_it_couldnt_send() {
+    let sim = Simulator::start();
+    // More than a page of listing, in a directory only the names make
+    for i in 0..300 {
+        sim.put(&format!("/backup/old/{:03}.txt", i), b"deleted locally");
+    }
+    sim.put("/backup/same-size.txt", b"aaaa");
+    sim.script("write", "/backup/refused.txt", 1, Action::Fail(libc::EACCES));
+    let source = std::env::temp_dir().join(format!("fsdaemon-e2e-sync-failures-{}", std::process::id()));
+    fs::create_dir_all(&source).unwrap();
+    fs::write(source.join("same-size.txt"), b"bbbb").unwrap();
+    fs::write(source.join("refused.txt"), b"refused once").unwrap();
+    let dir = source.to_string_lossy();
+
+    assert!(!sync(&sim, &[&dir, "/backup", "--delete", "--checksum"]));
+    assert_eq!(sim.file("/backup/refused.txt"), None);
+    // The rest went through: same-sized content that differs, and every deletion
+    assert_eq!(sim.file("/backup/same-size.txt").unwrap(), b"bbbb");
+    assert_eq!(sim.seen_any("unlink"), 300);
+    assert_eq!(sim.file("/backup/old/299.txt"), None);
+
+    let writes = sim.seen("write", "/backup/same-size.txt");
+    assert!(sync(&sim, &[&dir, "/backup", "--delete", "--checksum"]));
+    assert_eq!(sim.file("/backup/refused.txt").unwrap(), b"refused once");
+    assert_eq!(sim.seen("write", "/backup/same-size.txt"), writes);
+    fs::remove_dir_all(source).unwrap();
+}
+
+#[test]
+fn sync_watch_sends_changes_until_signalled() {
+    let sim = Simulator::start();
+    let source = std::env::temp_dir().join(format!("fsdaemon-e2e-sync-watch-{}", std::process::id()));
+    fs::create_dir_all(&source).unwrap();
+    fs::write(source.join("kept.txt"), b"first").unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_fsdaemon"))
+        .args(["sync", &source.to_string_lossy(), "/backup", "--watch", "--interval=20ms", "--delete"])
+        .arg(format!("--connect={}", sim.address()))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    wait_for("the first pass", || sim.file("/backup/kept.txt").is_some());
+    fs::write(source.join("kept.txt"), b"second, longer").unwrap();
+    fs::write(source.join("added.txt"), b"added").unwrap();
+    wait_for("the change", || sim.file("/backup/kept.txt").as_deref() == Some(b"second, longer"));
+    wait_for("the new file", || sim.file("/backup/added.txt").is_some());
+    fs::remove_file(source.join("added.txt")).unwrap();
+    wait_for("the deletion", || sim.file("/backup/added.txt").is_none());
+    // Later passes go by what was sent, without asking the DO again
+    let writes = sim.seen("write", "/backup/kept.txt");
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(sim.seen("write", "/backup/kept.txt"), writes);
+
+    unsafe { libc::kill(child.id() as i32, libc::SIGTERM) };
+    assert!(child.wait().unwrap().success());
+    fs::remove_dir_all(source).unwrap();
+}
+
+#[test]
+fn dedup_skips_chunks_the_do_has() {
+    let sim = Simulator::start();
+    let source = std::env::temp_dir().join(format!("fsdaemon-e2e-dedup-{}", std::process::id()));
+    fs::create_dir_all(&source).unwrap();
+    let mut state: u64 = 1;
+    let data: Vec<u8> = (0..300_000)
+        .map(|_| {
+            // xorshift, so chunk boundaries fall as they would in real data
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    fs::write(source.join("first.bin"), &data).unwrap();
+    let dir = source.to_string_lossy();
+
+    assert!(sync(&sim, &[&dir, "/backup", "--dedup"]));
+    assert_eq!(sim.file("/backup/first.bin").unwrap(), data);
+    let uploaded = sim.seen_any("put-chunk");
+    assert!(uploaded > 1);
+    assert_eq!(sim.seen("write", "/backup/first.bin"), 1);
+
+    // A copy with a few bytes in front only needs its first chunk
+    let mut copy = b"header".to_vec();
+    copy.extend_from_slice(&data);
+    fs::write(source.join("second.bin"), &copy).unwrap();
+    assert!(sync(&sim, &[&dir, "/backup", "--dedup"]));
+    assert_eq!(sim.file("/backup/second.bin").unwrap(), copy);
+    assert!(sim.seen_any("put-chunk") - uploaded <= 2);
+    fs::remove_dir_all(source).unwrap();
+}
+
+// Runs the daemon binary as `fsctl` dialing `sim`, with `stdin`, returning
+// whether it succeeded and what it wrote to stdout
+fn fsctl(sim: &Simulator, args: &[&str], stdin: &[u8]) -> (bool, String) {
+    let dir = std::env::temp_dir().join(format!("fsdaemon-e2e-fsctl-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let tool = dir.join("fsctl");
+    if !tool.exists() {
+        std::os::unix::fs::symlink(env!("CARGO_BIN_EXE_fsdaemon"), &tool).unwrap();
+    }
+    let mut child = Command::new(&tool)
+        .arg(format!("--connect={}", sim.address()))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("run fsctl");
+    std::io::Write::write_all(&mut child.stdin.take().unwrap(), stdin).unwrap();
+    let output = child.wait_with_output().unwrap();
+    (output.status.success(), String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[test]
+fn fsctl_works_on_files_without_a_mount() {
+    let sim = Simulator::start();
+    sim.put("/logs/old.txt", b"from the DO");
+
+    assert_eq!(fsctl(&sim, &["put", "-", "/logs/new.txt"], b"from stdin"), (true, String::new()));
+    assert_eq!(sim.file("/logs/new.txt").unwrap(), b"from stdin");
+    assert_eq!(fsctl(&sim, &["ls", "/"], b""), (true, "logs/\n".to_string()));
+    assert_eq!(fsctl(&sim, &["ls", "/logs"], b""), (true, "new.txt\nold.txt\n".to_string()));
+    assert_eq!(fsctl(&sim, &["cat", "/logs/old.txt"], b""), (true, "from the DO".to_string()));
+    assert_eq!(fsctl(&sim, &["du", "/logs"], b""), (true, "21\t2\t/logs\n".to_string()));
+
+    assert_eq!(fsctl(&sim, &["rm", "/logs/old.txt"], b""), (true, String::new()));
+    assert_eq!(sim.file("/logs/old.txt"), None);
+    assert!(!fsctl(&sim, &["stat", "/logs/old.txt"], b"").0);
+}
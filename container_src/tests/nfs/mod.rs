@@ -0,0 +1,258 @@
+// A bare NFSv3 client for the end-to-end tests. It speaks ONC RPC over TCP to
+// the daemon's NFS frontend, so the frontend is tested without a kernel NFS
+// client, root or a mount.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+const MOUNT_PROGRAM: u32 = 100005;
+const NFS_PROGRAM: u32 = 100003;
+const VERSION: u32 = 3;
+
+const GETATTR: u32 = 1;
+const SETATTR: u32 = 2;
+const LOOKUP: u32 = 3;
+const CREATE: u32 = 8;
+const REMOVE: u32 = 12;
+const READDIRPLUS: u32 = 17;
+
+pub const NFS3ERR_NOENT: u32 = 2;
+pub const NFS3ERR_FBIG: u32 = 27;
+pub const NFS3ERR_STALE: u32 = 70;
+pub const NFS3ERR_TOOSMALL: u32 = 10020;
+
+// The arguments of a call, XDR-encoded a value at a time
+#[derive(Default)]
+pub struct Args(Vec<u8>);
+
+impl Args {
+    pub fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn bool(self, value: bool) -> Self {
+        self.u32(value as u32)
+    }
+
+    pub fn opaque(mut self, value: &[u8]) -> Self {
+        self = self.u32(value.len() as u32);
+        self.0.extend_from_slice(value);
+        self.0.resize(self.0.len().next_multiple_of(4), 0);
+        self
+    }
+
+    pub fn string(self, value: &str) -> Self {
+        self.opaque(value.as_bytes())
+    }
+
+    // A sattr3 setting only the mode and size given, and neither time
+    pub fn sattr(self, mode: Option<u32>, size: Option<u64>) -> Self {
+        let args = match mode {
+            Some(mode) => self.bool(true).u32(mode),
+            None => self.bool(false),
+        };
+        let args = args.bool(false).bool(false);
+        let args = match size {
+            Some(size) => args.bool(true).u64(size),
+            None => args.bool(false),
+        };
+        args.u32(0).u32(0)
+    }
+}
+
+// A reply's results, decoded a value at a time
+pub struct Reply {
+    data: Vec<u8>,
+    at: usize,
+}
+
+// The parts of a fattr3 the tests look at
+#[derive(Debug)]
+pub struct Attr {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+}
+
+// An entry of a READDIRPLUS listing
+#[derive(Debug)]
+pub struct Entry {
+    pub name: String,
+    pub cookie: u64,
+    pub handle: Vec<u8>,
+}
+
+impl Reply {
+    pub fn u32(&mut self) -> u32 {
+        let value = u32::from_be_bytes(self.data[self.at..self.at + 4].try_into().unwrap());
+        self.at += 4;
+        value
+    }
+
+    pub fn u64(&mut self) -> u64 {
+        (self.u32() as u64) << 32 | self.u32() as u64
+    }
+
+    pub fn bool(&mut self) -> bool {
+        self.u32() != 0
+    }
+
+    pub fn opaque(&mut self) -> Vec<u8> {
+        let len = self.u32() as usize;
+        let value = self.data[self.at..self.at + len].to_vec();
+        self.at += len.next_multiple_of(4);
+        value
+    }
+
+    pub fn post_op_attr(&mut self) -> Option<Attr> {
+        self.bool().then(|| self.fattr())
+    }
+
+    pub fn fattr(&mut self) -> Attr {
+        let (_kind, mode, _nlink, uid, gid) = (self.u32(), self.u32(), self.u32(), self.u32(), self.u32());
+        let size = self.u64();
+        // Then used, rdev, fsid, fileid and three times
+        self.at += 56;
+        Attr { mode, uid, gid, size }
+    }
+}
+
+pub struct Client {
+    stream: TcpStream,
+    xid: u32,
+}
+
+impl Client {
+    pub fn connect(address: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
+        Ok(Self { stream, xid: 0 })
+    }
+
+    // Makes a call as root, returning its results once the server accepted it
+    pub fn call(&mut self, program: u32, procedure: u32, args: Args) -> Reply {
+        self.xid += 1;
+        let credentials = Args::default().u32(0).string("e2e").u32(0).u32(0).u32(0);
+        let call = Args::default()
+            .u32(self.xid)
+            .u32(0)
+            .u32(2)
+            .u32(program)
+            .u32(VERSION)
+            .u32(procedure)
+            .u32(1)
+            .opaque(&credentials.0)
+            .u32(0)
+            .opaque(&[]);
+        let mut record = call.0;
+        record.extend_from_slice(&args.0);
+        let mut reply = Reply { data: self.record(&record).expect("a reply"), at: 0 };
+        assert_eq!(reply.u32(), self.xid);
+        // A reply, accepted, with a verifier, that succeeded
+        assert_eq!([reply.u32(), reply.u32()], [1, 0]);
+        reply.u32();
+        reply.opaque();
+        assert_eq!(reply.u32(), 0, "the call wasn't accepted");
+        reply
+    }
+
+    // Sends one record as it is and reads the one that answers it, or None
+    // when the server closes the connection instead
+    pub fn record(&mut self, body: &[u8]) -> Option<Vec<u8>> {
+        let mut record = (0x8000_0000 | body.len() as u32).to_be_bytes().to_vec();
+        record.extend_from_slice(body);
+        self.stream.write_all(&record).ok()?;
+        self.answer()
+    }
+
+    // Sends only the header of a record `len` bytes long, as `record` does
+    pub fn header_only(&mut self, len: u32) -> Option<Vec<u8>> {
+        self.stream.write_all(&(0x8000_0000 | len).to_be_bytes()).ok()?;
+        self.answer()
+    }
+
+    fn answer(&mut self) -> Option<Vec<u8>> {
+        let mut header = [0; 4];
+        self.stream.read_exact(&mut header).ok()?;
+        let mut reply = vec![0; (u32::from_be_bytes(header) & 0x7fff_ffff) as usize];
+        self.stream.read_exact(&mut reply).ok()?;
+        Some(reply)
+    }
+
+    // The handle of the export's root
+    pub fn mount(&mut self, export: &str) -> Vec<u8> {
+        let mut reply = self.call(MOUNT_PROGRAM, 1, Args::default().string(export));
+        assert_eq!(reply.u32(), 0, "the export wasn't mounted");
+        reply.opaque()
+    }
+
+    // The handle of `name` in the directory, or the NFS status it fails with
+    pub fn lookup(&mut self, dir: &[u8], name: &str) -> Result<Vec<u8>, u32> {
+        let mut reply = self.call(NFS_PROGRAM, LOOKUP, Args::default().opaque(dir).string(name));
+        match reply.u32() {
+            0 => Ok(reply.opaque()),
+            status => Err(status),
+        }
+    }
+
+    // Creates `name` in the directory, returning the NFS status and the new file's handle
+    pub fn create(&mut self, dir: &[u8], name: &str, mode: Option<u32>) -> (u32, Vec<u8>) {
+        let args = Args::default().opaque(dir).string(name).u32(0).sattr(mode, None);
+        let mut reply = self.call(NFS_PROGRAM, CREATE, args);
+        match reply.u32() {
+            0 => {
+                assert!(reply.bool());
+                (0, reply.opaque())
+            }
+            status => (status, Vec::new()),
+        }
+    }
+
+    // The NFS status of a SETATTR of the mode and size
+    pub fn setattr(&mut self, handle: &[u8], mode: Option<u32>, size: Option<u64>) -> u32 {
+        let args = Args::default().opaque(handle).sattr(mode, size).bool(false);
+        self.call(NFS_PROGRAM, SETATTR, args).u32()
+    }
+
+    // The NFS status of removing `name` from the directory
+    pub fn remove(&mut self, dir: &[u8], name: &str) -> u32 {
+        self.call(NFS_PROGRAM, REMOVE, Args::default().opaque(dir).string(name)).u32()
+    }
+
+    // The entries of the directory after `cookie` that fit in `count` bytes,
+    // and whether they are the last, or the NFS status it fails with
+    pub fn readdirplus(&mut self, dir: &[u8], cookie: u64, count: u32) -> Result<(Vec<Entry>, bool), u32> {
+        let args = Args::default().opaque(dir).u64(cookie).u64(0).u32(count).u32(count);
+        let mut reply = self.call(NFS_PROGRAM, READDIRPLUS, args);
+        match reply.u32() {
+            0 => {}
+            status => return Err(status),
+        }
+        reply.post_op_attr();
+        reply.u64();
+        let mut entries = Vec::new();
+        while reply.bool() {
+            let (_fileid, name, cookie) = (reply.u64(), reply.opaque(), reply.u64());
+            reply.post_op_attr();
+            let handle = if reply.bool() { reply.opaque() } else { Vec::new() };
+            entries.push(Entry { name: String::from_utf8(name).unwrap(), cookie, handle });
+        }
+        Ok((entries, reply.bool()))
+    }
+
+    // The attributes of the file, or the NFS status it fails with
+    pub fn getattr(&mut self, handle: &[u8]) -> Result<Attr, u32> {
+        let mut reply = self.call(NFS_PROGRAM, GETATTR, Args::default().opaque(handle));
+        match reply.u32() {
+            0 => Ok(reply.fattr()),
+            status => Err(status),
+        }
+    }
+}
@@ -0,0 +1,124 @@
+// A bare 9P2000.L client for the end-to-end tests, speaking to the daemon's
+// 9p frontend the way `nfs` speaks to its NFS one.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+const RLERROR: u8 = 7;
+const TLOPEN: u8 = 12;
+const TREADDIR: u8 = 40;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TWALK: u8 = 110;
+const TCLUNK: u8 = 120;
+
+// A message's fields, little-endian a value at a time
+#[derive(Default)]
+struct Fields(Vec<u8>);
+
+impl Fields {
+    fn u16(mut self, value: u16) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn string(mut self, value: &str) -> Self {
+        self = self.u16(value.len() as u16);
+        self.0.extend_from_slice(value.as_bytes());
+        self
+    }
+}
+
+// An Rreaddir entry's offset, which a later Treaddir picks up after, and name
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub offset: u64,
+    pub name: String,
+}
+
+pub struct Client {
+    stream: TcpStream,
+    tag: u16,
+}
+
+impl Client {
+    // Connects and agrees on 9P2000.L
+    pub fn connect(address: &str) -> io::Result<Self> {
+        let mut client = Client { stream: TcpStream::connect(address)?, tag: 0 };
+        let version = Fields::default().u32(65536).string("9P2000.L");
+        let reply = client.call(TVERSION, version).map_err(io::Error::from_raw_os_error)?;
+        assert_eq!(&reply[6..], b"9P2000.L");
+        Ok(client)
+    }
+
+    pub fn attach(&mut self, fid: u32, aname: &str) -> Result<(), i32> {
+        let fields = Fields::default().u32(fid).u32(!0).string("root").string(aname).u32(0);
+        self.call(TATTACH, fields).map(drop)
+    }
+
+    // How many of `names` the walk got through
+    pub fn walk(&mut self, fid: u32, newfid: u32, names: &[&str]) -> Result<usize, i32> {
+        let mut fields = Fields::default().u32(fid).u32(newfid).u16(names.len() as u16);
+        for name in names {
+            fields = fields.string(name);
+        }
+        let reply = self.call(TWALK, fields)?;
+        Ok(u16::from_le_bytes([reply[0], reply[1]]) as usize)
+    }
+
+    pub fn lopen(&mut self, fid: u32, flags: u32) -> Result<(), i32> {
+        self.call(TLOPEN, Fields::default().u32(fid).u32(flags)).map(drop)
+    }
+
+    pub fn readdir(&mut self, fid: u32, offset: u64, count: u32) -> Result<Vec<Entry>, i32> {
+        let reply = self.call(TREADDIR, Fields::default().u32(fid).u64(offset).u32(count))?;
+        let (mut entries, mut at) = (Vec::new(), 4);
+        while at < reply.len() {
+            // qid[13] offset[8] type[1] name[s]
+            let offset = u64::from_le_bytes(reply[at + 13..at + 21].try_into().unwrap());
+            let length = u16::from_le_bytes([reply[at + 22], reply[at + 23]]) as usize;
+            let name = String::from_utf8(reply[at + 24..at + 24 + length].to_vec()).unwrap();
+            entries.push(Entry { offset, name });
+            at += 24 + length;
+        }
+        Ok(entries)
+    }
+
+    pub fn clunk(&mut self, fid: u32) -> Result<(), i32> {
+        self.call(TCLUNK, Fields::default().u32(fid)).map(drop)
+    }
+
+    // Sends a T-message and returns the R-message's fields, or its errno
+    fn call(&mut self, kind: u8, fields: Fields) -> Result<Vec<u8>, i32> {
+        self.tag = self.tag.wrapping_add(1);
+        let mut message = (fields.0.len() as u32 + 7).to_le_bytes().to_vec();
+        message.push(kind);
+        message.extend_from_slice(&self.tag.to_le_bytes());
+        message.extend_from_slice(&fields.0);
+        self.stream.write_all(&message).unwrap();
+
+        let mut header = [0; 7];
+        self.stream.read_exact(&mut header).unwrap();
+        let size = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let mut reply = vec![0; size - 7];
+        self.stream.read_exact(&mut reply).unwrap();
+        assert_eq!(u16::from_le_bytes([header[5], header[6]]), self.tag);
+        match header[4] {
+            RLERROR => Err(i32::from_le_bytes(reply[..4].try_into().unwrap())),
+            answer => {
+                assert_eq!(answer, kind + 1);
+                Ok(reply)
+            }
+        }
+    }
+}
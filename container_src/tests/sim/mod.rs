@@ -0,0 +1,302 @@
+// A stand-in for the Durable Object that the end-to-end tests point the
+// daemon at. It speaks the wire protocol over TCP, keeps files in memory the
+// way the DO keeps them in storage, and can be scripted to delay, fail or
+// drop requests to see how the daemon copes.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+// What a scripted request gets in place of its usual answer
+#[derive(Clone, Copy, Debug)]
+pub enum Action {
+    // Answered as usual, this long late
+    Delay(Duration),
+    // Answered with this errno
+    Fail(i32),
+    // Never answered; the connection is closed under it
+    Drop,
+}
+
+struct Rule {
+    operation: String,
+    path: String,
+    // Requests the rule still applies to
+    times: usize,
+    action: Action,
+}
+
+#[derive(Default)]
+struct State {
+    files: BTreeMap<String, (Vec<u8>, u64)>,
+    // Chunks uploaded for deduplicated writes, by hash
+    chunks: HashMap<String, Vec<u8>>,
+    // Permission bits files were given, by path
+    modes: HashMap<String, u32>,
+    rules: Vec<Rule>,
+    // Every request taken, in order, as its operation, path and correlation id
+    seen: Vec<(String, String, Option<String>)>,
+    // Versions a file gets on its next change, so two in the same
+    // millisecond still differ
+    last_version: u64,
+}
+
+pub struct Simulator {
+    address: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl Simulator {
+    // Starts listening on a free port of the loopback interface
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind the simulator");
+        let address = listener.local_addr().unwrap().to_string();
+        let state = Arc::new(Mutex::new(State::default()));
+        let shared = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                let state = shared.clone();
+                thread::spawn(move || {
+                    let _ = answer(stream, &state);
+                });
+            }
+        });
+        Self { address, state }
+    }
+
+    // Where the daemon should dial
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    // Has the next `times` requests for `operation` on `path` get `action`
+    pub fn script(&self, operation: &str, path: &str, times: usize, action: Action) {
+        self.state.lock().unwrap().rules.push(Rule {
+            operation: operation.to_string(),
+            path: path.to_string(),
+            times,
+            action,
+        });
+    }
+
+    pub fn put(&self, path: &str, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let version = state.next_version();
+        state.files.insert(path.to_string(), (data.to_vec(), version));
+    }
+
+    pub fn file(&self, path: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().files.get(path).map(|(data, _)| data.clone())
+    }
+
+    pub fn mode(&self, path: &str) -> Option<u32> {
+        self.state.lock().unwrap().modes.get(path).copied()
+    }
+
+    // How many requests for `operation` on any path have come in
+    pub fn seen_any(&self, operation: &str) -> usize {
+        let state = self.state.lock().unwrap();
+        state.seen.iter().filter(|(op, _, _)| op == operation).count()
+    }
+
+    // How many requests for `operation` on `path` have come in
+    pub fn seen(&self, operation: &str, path: &str) -> usize {
+        let state = self.state.lock().unwrap();
+        state.seen.iter().filter(|(op, p, _)| op == operation && p == path).count()
+    }
+
+    // The correlation ids requests for `operation` on `path` carried, in
+    // order, None for those without one
+    pub fn correlations(&self, operation: &str, path: &str) -> Vec<Option<String>> {
+        let state = self.state.lock().unwrap();
+        state.seen.iter().filter(|(op, p, _)| op == operation && p == path).map(|(_, _, cid)| cid.clone()).collect()
+    }
+}
+
+impl State {
+    fn next_version(&mut self) -> u64 {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        self.last_version = now.max(self.last_version + 1);
+        self.last_version
+    }
+
+    // The action of the first rule that matches, using up one of its times
+    fn scripted(&mut self, operation: &str, path: &str) -> Option<Action> {
+        let rule = self
+            .rules
+            .iter_mut()
+            .find(|rule| rule.times > 0 && rule.operation == operation && rule.path == path)?;
+        rule.times -= 1;
+        Some(rule.action)
+    }
+}
+
+// Answers one connection's requests in order until it closes. Frames are
+// legacy JSON throughout: the hello agrees to version 1 and no features but
+// `dedup`, `modes`, `correlation` and `cancel`. Cancels are only taken note
+// of, as a request is answered before the next is read.
+fn answer(mut stream: TcpStream, state: &Mutex<State>) -> io::Result<()> {
+    loop {
+        let mut length = [0u8; 4];
+        stream.read_exact(&mut length)?;
+        let mut frame = vec![0u8; u32::from_le_bytes(length) as usize];
+        stream.read_exact(&mut frame)?;
+        let request: Value = serde_json::from_slice(&frame)?;
+        let operation = request["operation"].as_str().unwrap_or_default().to_string();
+        let path = request["path"].as_str().unwrap_or_default().to_string();
+        let correlation = request["correlation"].as_str().map(str::to_string);
+
+        let action = {
+            let mut state = state.lock().unwrap();
+            state.seen.push((operation.clone(), path.clone(), correlation.clone()));
+            state.scripted(&operation, &path)
+        };
+        if operation == "cancel" {
+            continue;
+        }
+        let reply = match action {
+            Some(Action::Drop) => return stream.shutdown(Shutdown::Both),
+            Some(Action::Fail(errno)) => failure(errno),
+            Some(Action::Delay(delay)) => {
+                thread::sleep(delay);
+                perform(&mut state.lock().unwrap(), &operation, &path, &request)
+            }
+            None => perform(&mut state.lock().unwrap(), &operation, &path, &request),
+        };
+        let mut reply = reply;
+        reply["id"] = request["id"].clone();
+        if let Some(correlation) = correlation {
+            reply["correlation"] = correlation.into();
+        }
+        let reply = serde_json::to_vec(&reply)?;
+        let mut out = (reply.len() as u32).to_le_bytes().to_vec();
+        out.extend_from_slice(&reply);
+        stream.write_all(&out)?;
+    }
+}
+
+fn perform(state: &mut State, operation: &str, path: &str, request: &Value) -> Value {
+    let offset = request["offset"].as_u64();
+    let size = request["size"].as_u64();
+    match operation {
+        "hello" => {
+            let offered = request["hello"]["features"].as_array().into_iter().flatten();
+            let agreed = ["dedup", "modes", "correlation", "cancel"];
+            let features: Vec<&Value> = offered.filter(|feature| agreed.iter().any(|name| *feature == name)).collect();
+            json!({ "hello": { "version": 1, "versions": [1], "features": features } })
+        }
+        "stat" => match state.files.get(path) {
+            Some((data, version)) => json!({
+                "stat": {
+                    "size": data.len(),
+                    "isFile": true,
+                    "isDir": false,
+                    "mtime": version,
+                    "version": version,
+                    "mode": state.modes.get(path),
+                }
+            }),
+            None => failure(libc::ENOENT),
+        },
+        "read" => match state.files.get(path) {
+            Some((data, _)) => {
+                let start = (offset.unwrap_or(0) as usize).min(data.len());
+                let end = size.map_or(data.len(), |size| (start + size as usize).min(data.len()));
+                json!({ "data": data[start..end] })
+            }
+            None => failure(libc::ENOENT),
+        },
+        "write" => {
+            let data: Vec<u8> = serde_json::from_value(request["data"].clone()).unwrap_or_default();
+            let version = state.next_version();
+            let (file, stamp) = state.files.entry(path.to_string()).or_default();
+            match offset {
+                Some(offset) => {
+                    let end = offset as usize + data.len();
+                    if file.len() < end {
+                        file.resize(end, 0);
+                    }
+                    file[offset as usize..end].copy_from_slice(&data);
+                }
+                None => *file = data.clone(),
+            }
+            *stamp = version;
+            if let Some(mode) = request["mode"].as_u64() {
+                state.modes.insert(path.to_string(), mode as u32);
+            }
+            json!({ "bytesWritten": data.len() })
+        }
+        "chmod" => match (state.files.contains_key(path), request["mode"].as_u64()) {
+            (true, Some(mode)) => {
+                state.modes.insert(path.to_string(), mode as u32);
+                json!({ "success": true })
+            }
+            (false, _) => failure(libc::ENOENT),
+            (true, None) => failure(libc::EINVAL),
+        },
+        // Directories exist only as the prefixes of the files in them
+        "readdir" => {
+            let prefix = if path.ends_with('/') { path.to_string() } else { format!("{}/", path) };
+            let mut names: Vec<&str> = state
+                .files
+                .keys()
+                .filter_map(|file| file.strip_prefix(&prefix))
+                .map(|rest| rest.split('/').next().unwrap_or(rest))
+                .collect();
+            names.dedup();
+            let names = names.into_iter().skip(offset.unwrap_or(0) as usize);
+            let names: Vec<&str> = names.take(size.map_or(usize::MAX, |size| size as usize)).collect();
+            json!({ "files": names })
+        }
+        "unlink" => {
+            state.modes.remove(path);
+            json!({ "success": state.files.remove(path).is_some() })
+        }
+        "has-chunks" => {
+            let asked: Vec<String> = serde_json::from_value(request["chunks"].clone()).unwrap_or_default();
+            let missing: Vec<String> = asked.into_iter().filter(|hash| !state.chunks.contains_key(hash)).collect();
+            json!({ "chunks": missing })
+        }
+        // Trusted to be what it is named after, which the DO checks
+        "put-chunk" => {
+            let data: Vec<u8> = serde_json::from_value(request["data"].clone()).unwrap_or_default();
+            let hash = request["chunks"][0].as_str().unwrap_or_default().to_string();
+            let len = data.len();
+            state.chunks.insert(hash, data);
+            json!({ "bytesWritten": len })
+        }
+        "write-chunks" => {
+            let hashes: Vec<String> = serde_json::from_value(request["chunks"].clone()).unwrap_or_default();
+            let Some(parts) = hashes.iter().map(|hash| state.chunks.get(hash)).collect::<Option<Vec<_>>>() else {
+                return failure(libc::ENOENT);
+            };
+            let data: Vec<u8> = parts.into_iter().flatten().copied().collect();
+            let offset = offset.unwrap_or(0) as usize;
+            let version = state.next_version();
+            let (file, stamp) = state.files.entry(path.to_string()).or_default();
+            if file.len() < offset + data.len() {
+                file.resize(offset + data.len(), 0);
+            }
+            file[offset..offset + data.len()].copy_from_slice(&data);
+            *stamp = version;
+            json!({ "bytesWritten": data.len() })
+        }
+        _ => failure(libc::ENOSYS),
+    }
+}
+
+// The messages the DO uses alongside the errno, which older daemons go by
+fn failure(errno: i32) -> Value {
+    let error = match errno {
+        libc::ENOENT => "File not found".to_string(),
+        libc::ENOSYS => "Unknown operation".to_string(),
+        _ => io::Error::from_raw_os_error(errno).to_string(),
+    };
+    json!({ "error": error, "errno": errno })
+}
@@ -0,0 +1,666 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+
+use crate::backend::{LocalStore, Store};
+use crate::error::Locked;
+use crate::sync::PIECE_SIZE;
+
+pub const DEFAULT_HIGH_WATERMARK: u64 = 1024 * 1024 * 1024;
+pub const DEFAULT_WRITEBACK_DELAY: Duration = Duration::from_secs(30);
+// How often the tier writes back what is due and evicts
+const TICK: Duration = Duration::from_secs(1);
+
+// The tiered store the daemon serves, when --backend is one, for `commit`
+// and the control file's status
+pub static TIERED: OnceLock<Arc<TieredStore>> = OnceLock::new();
+
+// How a tiered backend keeps its working set: where, how much of it, how
+// soon changes go back to the DO and how often a file is read before it is
+// worth keeping
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tiering {
+    pub dir: PathBuf,
+    // Local disk use evicting starts at, and what it evicts down to
+    pub high_watermark: u64,
+    pub low_watermark: Option<u64>,
+    // How long a change stays local before it is written back
+    pub writeback_delay: Duration,
+    // Reads of a file only the DO has before it is brought local
+    pub promote_after: u32,
+}
+
+impl Tiering {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            high_watermark: DEFAULT_HIGH_WATERMARK,
+            low_watermark: None,
+            writeback_delay: DEFAULT_WRITEBACK_DELAY,
+            promote_after: 1,
+        }
+    }
+
+    // Four fifths of the high watermark unless given
+    fn low_watermark(&self) -> u64 {
+        self.low_watermark.unwrap_or(self.high_watermark / 5 * 4)
+    }
+}
+
+// A file kept locally
+struct Entry {
+    size: u64,
+    // When it was last used, on the store's clock
+    last_used: u64,
+    // The DO's version of the file when it was brought local or last written
+    // back; None while the file is only here
+    version: Option<u64>,
+    // Set while changes wait to be written back, to when the first was made
+    dirty: Option<Instant>,
+    // Counts changes, so one made while the file is written back keeps it dirty
+    changes: u64,
+}
+
+#[derive(Default)]
+struct State {
+    local: HashMap<String, Entry>,
+    // Reads of files only the DO has, towards `promote_after`
+    uses: HashMap<String, u32>,
+    clock: u64,
+    used: u64,
+}
+
+// What outlasts a restart besides the files themselves
+#[derive(Serialize, Deserialize, Default)]
+struct Saved {
+    dirty: BTreeSet<String>,
+    versions: BTreeMap<String, u64>,
+}
+
+// The DO with a local working set in front of it. Files in use are brought
+// into the directory whole and served from there; changes are made there and
+// written back once they have settled for the write-back delay. Cold files
+// are only in the DO: once local files take more than the high watermark,
+// the least recently used clean ones are evicted down to the low watermark.
+// A clean local copy is checked against the DO's version whenever the file
+// is stat'ed and dropped when another client changed it.
+pub struct TieredStore {
+    tiering: Tiering,
+    files_dir: PathBuf,
+    local: LocalStore,
+    lower: Box<dyn Store>,
+    state: Mutex<State>,
+    saved_file: PathBuf,
+    // Held by a write-back pass and by unlinks, so a file unlinked while it
+    // is written back isn't brought back in the DO
+    flushing: Mutex<()>,
+}
+
+// What a write-back pass sent to the DO
+#[derive(Default)]
+struct WrittenBack {
+    files: usize,
+    bytes: u64,
+}
+
+impl TieredStore {
+    pub fn open(tiering: &Tiering, lower: Box<dyn Store>) -> io::Result<Self> {
+        let files_dir = tiering.dir.join("files");
+        fs::create_dir_all(&files_dir)?;
+        let saved_file = tiering.dir.join("tier.json");
+        let saved: Saved = match fs::read(&saved_file) {
+            Ok(json) => serde_json::from_slice(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Saved::default(),
+            Err(e) => return Err(e),
+        };
+        let local = LocalStore::open(&files_dir)?;
+        let mut state = State::default();
+        for (path, size) in local_files(&local)? {
+            let dirty = saved.dirty.contains(&path);
+            state.used += size;
+            state.local.insert(
+                path.clone(),
+                Entry {
+                    size,
+                    last_used: 0,
+                    version: saved.versions.get(&path).copied(),
+                    // Written back once the delay has passed again
+                    dirty: dirty.then(Instant::now),
+                    changes: 0,
+                },
+            );
+        }
+        let dirty = state.local.values().filter(|entry| entry.dirty.is_some()).count();
+        info!(
+            files = state.local.len(),
+            bytes = state.used,
+            dirty,
+            "Tiered backend in {} has its working set",
+            tiering.dir.display()
+        );
+        Ok(Self {
+            tiering: tiering.clone(),
+            files_dir,
+            local,
+            lower,
+            state: Mutex::new(state),
+            saved_file,
+            flushing: Mutex::new(()),
+        })
+    }
+
+    // Writes back and evicts in the background from now on
+    pub fn spawn_tiering(self: &Arc<Self>) {
+        let store = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(TICK);
+            // Over the high watermark, dirty files are written back early so
+            // they can be evicted too
+            let all = store.state.locked().used > store.tiering.high_watermark;
+            if let Err(e) = store.write_back(all) {
+                warn!("Failed to write local changes back to the DO, will retry: {}", e);
+            }
+            if let Err(e) = store.evict() {
+                warn!("Failed to evict from the local tier: {}", e);
+            }
+        });
+    }
+
+    // Writes every local change back to the DO now, for `commit`
+    pub fn commit(&self) -> Result<Value, String> {
+        let written = self.write_back(true).map_err(|e| e.to_string())?;
+        Ok(json!({ "files": written.files, "bytes": written.bytes }))
+    }
+
+    // The working set, for the control file's status
+    pub fn status(&self) -> Value {
+        let state = self.state.locked();
+        json!({
+            "files": state.local.len(),
+            "bytes": state.used,
+            "dirty": state.local.values().filter(|entry| entry.dirty.is_some()).count(),
+            "high_watermark": self.tiering.high_watermark,
+            "low_watermark": self.tiering.low_watermark(),
+        })
+    }
+
+    // Sends the changes that have waited out the delay, or all of them
+    fn write_back(&self, all: bool) -> io::Result<WrittenBack> {
+        let _flushing = self.flushing.locked();
+        let settled = |since: Instant| all || since.elapsed() >= self.tiering.writeback_delay;
+        let due: Vec<(String, u64)> = self
+            .state
+            .locked()
+            .local
+            .iter()
+            .filter(|(_, entry)| entry.dirty.is_some_and(settled))
+            .map(|(path, entry)| (path.clone(), entry.changes))
+            .collect();
+        let mut written = WrittenBack::default();
+        for (path, changes) in due {
+            let (size, _) = self.local.stat(&path)?;
+            // Pieces are written in place after the first replaces the file
+            let mut offset = 0;
+            loop {
+                let piece = self.local.read(&path, offset, Some(PIECE_SIZE))?;
+                self.lower.write(&path, (offset > 0).then_some(offset), &piece)?;
+                offset += piece.len() as u64;
+                if piece.is_empty() || offset >= size {
+                    break;
+                }
+            }
+            let (_, version) = self.lower.stat(&path)?;
+            let mut state = self.state.locked();
+            if let Some(entry) = state.local.get_mut(&path).filter(|entry| entry.changes == changes) {
+                entry.dirty = None;
+                entry.version = Some(version);
+            }
+            self.save(&state)?;
+            debug!(bytes = offset, "Wrote {} back to the DO", path);
+            written.files += 1;
+            written.bytes += offset;
+        }
+        Ok(written)
+    }
+
+    // Drops the least recently used clean files once the working set is over
+    // the high watermark, until it is under the low one
+    fn evict(&self) -> io::Result<()> {
+        let mut state = self.state.locked();
+        if state.used <= self.tiering.high_watermark {
+            return Ok(());
+        }
+        let mut clean: Vec<(u64, String)> = state
+            .local
+            .iter()
+            .filter(|(_, entry)| entry.dirty.is_none())
+            .map(|(path, entry)| (entry.last_used, path.clone()))
+            .collect();
+        clean.sort();
+        let mut evicted = 0;
+        for (_, path) in clean {
+            if state.used <= self.tiering.low_watermark() {
+                break;
+            }
+            self.remove_local(&mut state, &path)?;
+            evicted += 1;
+        }
+        self.save(&state)?;
+        debug!(files = evicted, bytes = state.used, "Evicted cold files from the local tier");
+        Ok(())
+    }
+
+    // Brings a file local, returning whether it is. One larger than the high
+    // watermark only comes when a write needs it.
+    fn promote(&self, path: &str, needed: bool) -> io::Result<bool> {
+        let (size, version) = match self.lower.stat(path) {
+            Ok(stat) => stat,
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) && needed => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if size > self.tiering.high_watermark && !needed {
+            return Ok(false);
+        }
+        let data = self.lower.read(path, 0, None)?;
+        let mut state = self.state.locked();
+        if state.local.contains_key(path) {
+            return Ok(true);
+        }
+        self.local.write(path, None, &data)?;
+        state.clock += 1;
+        let entry =
+            Entry { size: data.len() as u64, last_used: state.clock, version: Some(version), dirty: None, changes: 0 };
+        state.used += entry.size;
+        state.local.insert(path.to_string(), entry);
+        state.uses.remove(path);
+        self.save(&state)?;
+        debug!(bytes = data.len(), "Brought {} into the local tier", path);
+        Ok(true)
+    }
+
+    // Whether the file is local, counting the use
+    fn touch(&self, path: &str) -> bool {
+        let mut state = self.state.locked();
+        state.clock += 1;
+        let clock = state.clock;
+        match state.local.get_mut(path) {
+            Some(entry) => {
+                entry.last_used = clock;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Drops the local copy of a clean file whose version in the DO is no
+    // longer `version`
+    fn drop_stale(&self, path: &str, version: Option<u64>) -> io::Result<()> {
+        let mut state = self.state.locked();
+        if state.local.get(path).is_some_and(|entry| entry.dirty.is_none() && entry.version == version) {
+            debug!("Dropping the local copy of {}, changed in the DO", path);
+            self.remove_local(&mut state, path)?;
+            self.save(&state)?;
+        }
+        Ok(())
+    }
+
+    // Removes a local file, and the directories it leaves empty
+    fn remove_local(&self, state: &mut State, path: &str) -> io::Result<bool> {
+        if let Some(entry) = state.local.remove(path) {
+            state.used -= entry.size;
+        }
+        let removed = self.local.unlink(path)?;
+        let mut dir = Path::new(path).parent();
+        while let Some(parent) = dir.filter(|parent| *parent != Path::new("/")) {
+            if fs::remove_dir(self.files_dir.join(parent.strip_prefix("/").unwrap_or(parent))).is_err() {
+                break;
+            }
+            dir = parent.parent();
+        }
+        Ok(removed)
+    }
+
+    fn save(&self, state: &State) -> io::Result<()> {
+        let mut saved = Saved::default();
+        for (path, entry) in &state.local {
+            if entry.dirty.is_some() {
+                saved.dirty.insert(path.clone());
+            }
+            if let Some(version) = entry.version {
+                saved.versions.insert(path.clone(), version);
+            }
+        }
+        let json = serde_json::to_vec(&saved).map_err(io::Error::other)?;
+        let temporary = self.saved_file.with_extension("json.tmp");
+        fs::write(&temporary, json)?;
+        fs::rename(&temporary, &self.saved_file)
+    }
+}
+
+// Every file under the local store, with its size
+fn local_files(local: &LocalStore) -> io::Result<Vec<(String, u64)>> {
+    let mut files = Vec::new();
+    let mut dirs = vec!["/".to_string()];
+    while let Some(dir) = dirs.pop() {
+        for name in local.list(&dir)? {
+            let path = crate::join(&dir, &name);
+            match local.stat(&path) {
+                Ok((size, _)) => files.push((path, size)),
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => dirs.push(path),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn is_not_found(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOENT)
+}
+
+impl Store for TieredStore {
+    fn read(&self, path: &str, offset: u64, size: Option<u64>) -> io::Result<Vec<u8>> {
+        if self.touch(path) {
+            // Unless it was evicted since
+            match self.local.read(path, offset, size) {
+                Err(e) if is_not_found(&e) => {}
+                read => return read,
+            }
+        }
+        let uses = {
+            let mut state = self.state.locked();
+            let uses = state.uses.entry(path.to_string()).or_default();
+            *uses += 1;
+            *uses
+        };
+        if uses >= self.tiering.promote_after && self.promote(path, false)? {
+            return self.local.read(path, offset, size);
+        }
+        self.lower.read(path, offset, size)
+    }
+
+    // A write into a file that isn't local brings the rest of it first
+    fn write(&self, path: &str, offset: Option<u64>, data: &[u8]) -> io::Result<()> {
+        if offset.is_some() && !self.touch(path) {
+            self.promote(path, true)?;
+        }
+        let mut state = self.state.locked();
+        self.local.write(path, offset, data)?;
+        let (size, _) = self.local.stat(path)?;
+        state.clock += 1;
+        let clock = state.clock;
+        let entry = state.local.entry(path.to_string()).or_insert(Entry {
+            size: 0,
+            last_used: clock,
+            version: None,
+            dirty: None,
+            changes: 0,
+        });
+        let grown = size as i64 - entry.size as i64;
+        let first = entry.dirty.is_none();
+        entry.size = size;
+        entry.last_used = clock;
+        entry.dirty.get_or_insert_with(Instant::now);
+        entry.changes += 1;
+        state.used = state.used.saturating_add_signed(grown);
+        state.uses.remove(path);
+        if first {
+            self.save(&state)?;
+        }
+        Ok(())
+    }
+
+    fn stat(&self, path: &str) -> io::Result<(u64, u64)> {
+        let local = self.state.locked().local.get(path).map(|entry| (entry.dirty.is_some(), entry.version));
+        match local {
+            // Changes here win until they are written back
+            Some((true, _)) => self.local.stat(path),
+            Some((false, version)) => match self.lower.stat(path) {
+                Ok(stat) if Some(stat.1) == version => Ok(stat),
+                Ok(stat) => {
+                    self.drop_stale(path, version)?;
+                    Ok(stat)
+                }
+                Err(e) if is_not_found(&e) => {
+                    self.drop_stale(path, version)?;
+                    Err(e)
+                }
+                // The working set is still there while the DO isn't
+                Err(e) => match (self.local.stat(path), version) {
+                    (Ok((size, _)), Some(version)) => Ok((size, version)),
+                    _ => Err(e),
+                },
+            },
+            None => self.lower.stat(path),
+        }
+    }
+
+    fn list(&self, path: &str) -> io::Result<Vec<String>> {
+        let mut names = self.local.list(path)?;
+        match self.lower.list(path) {
+            Ok(lower) => names.extend(lower),
+            Err(e) if is_not_found(&e) => {}
+            Err(e) => return Err(e),
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    fn unlink(&self, path: &str) -> io::Result<bool> {
+        let _flushing = self.flushing.locked();
+        let local = {
+            let mut state = self.state.locked();
+            let removed = self.remove_local(&mut state, path)?;
+            self.save(&state)?;
+            removed
+        };
+        let lower = self.lower.unlink(path)?;
+        Ok(local || lower)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::backend::MemoryStore;
+
+    // What a test does to the DO's side under the tier
+    #[derive(Default)]
+    struct Faults {
+        down: AtomicBool,
+        // Run in the middle of the next write
+        during_write: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+    }
+
+    // A store that is another's, so the test can look at the DO's side
+    struct Shared(Arc<MemoryStore>, Arc<Faults>);
+
+    impl Shared {
+        fn up(&self) -> io::Result<()> {
+            match self.1.down.load(Ordering::SeqCst) {
+                true => Err(io::Error::from_raw_os_error(libc::EIO)),
+                false => Ok(()),
+            }
+        }
+    }
+
+    impl Store for Shared {
+        fn read(&self, path: &str, offset: u64, size: Option<u64>) -> io::Result<Vec<u8>> {
+            self.up()?;
+            self.0.read(path, offset, size)
+        }
+        fn write(&self, path: &str, offset: Option<u64>, data: &[u8]) -> io::Result<()> {
+            self.up()?;
+            let during = self.1.during_write.locked().take();
+            if let Some(during) = during {
+                during();
+            }
+            self.0.write(path, offset, data)
+        }
+        fn stat(&self, path: &str) -> io::Result<(u64, u64)> {
+            self.up()?;
+            self.0.stat(path)
+        }
+        fn list(&self, path: &str) -> io::Result<Vec<String>> {
+            self.up()?;
+            self.0.list(path)
+        }
+        fn unlink(&self, path: &str) -> io::Result<bool> {
+            self.up()?;
+            self.0.unlink(path)
+        }
+    }
+
+    fn tiering(name: &str, high_watermark: u64) -> Tiering {
+        let dir = std::env::temp_dir().join(format!("fsdaemon-tier-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Tiering { high_watermark, writeback_delay: Duration::ZERO, ..Tiering::new(dir) }
+    }
+
+    #[test]
+    fn hot_files_are_kept_locally_and_cold_ones_evicted() {
+        let dir = std::env::temp_dir().join(format!("fsdaemon-tier-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let lower = Arc::new(MemoryStore::default());
+        let faults = Arc::new(Faults::default());
+        lower.write("/a", None, &[1; 40]).unwrap();
+        lower.write("/b", None, &[2; 40]).unwrap();
+        let tiering = Tiering {
+            high_watermark: 100,
+            low_watermark: Some(50),
+            writeback_delay: Duration::ZERO,
+            promote_after: 2,
+            ..Tiering::new(dir.clone())
+        };
+        let tier = TieredStore::open(&tiering, Box::new(Shared(lower.clone(), faults))).unwrap();
+
+        // A second read brings a file local
+        tier.read("/a", 0, None).unwrap();
+        assert!(!tier.touch("/a"));
+        tier.read("/a", 0, None).unwrap();
+        assert!(tier.touch("/a"));
+
+        // Writes stay local until they are written back
+        tier.write("/b", Some(38), b"xyz").unwrap();
+        tier.write("/c", None, &[3; 30]).unwrap();
+        assert_eq!(tier.read("/b", 37, None).unwrap(), [2, b'x', b'y', b'z']);
+        assert_eq!(lower.stat("/b").unwrap().0, 40);
+        assert!(lower.stat("/c").is_err());
+        assert_eq!(tier.list("/").unwrap(), ["a", "b", "c"]);
+        assert_eq!(tier.commit().unwrap(), json!({ "files": 2, "bytes": 71 }));
+        assert_eq!(lower.read("/b", 37, None).unwrap(), [2, b'x', b'y', b'z']);
+
+        // Over the high watermark, the least recently used go first
+        tier.read("/b", 0, None).unwrap();
+        tier.read("/c", 0, None).unwrap();
+        tier.evict().unwrap();
+        assert_eq!(tier.status()["files"], 1);
+        assert!(tier.touch("/c"));
+        assert_eq!(tier.read("/a", 0, None).unwrap(), [1; 40]);
+
+        // A clean copy changed in the DO is dropped when next stat'ed
+        lower.write("/c", None, b"changed").unwrap();
+        assert_eq!(tier.stat("/c").unwrap().0, 7);
+        assert!(!tier.touch("/c"));
+        assert!(tier.unlink("/c").unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_working_set_outlasts_restarts_and_the_do_going_away() {
+        let tiering = tiering("restart", 1000);
+        let (lower, faults) = (Arc::new(MemoryStore::default()), Arc::new(Faults::default()));
+        let tier = TieredStore::open(&tiering, Box::new(Shared(lower.clone(), faults.clone()))).unwrap();
+        tier.write("/clean", None, b"sent").unwrap();
+        tier.commit().unwrap();
+        tier.write("/dirty", None, b"not yet sent").unwrap();
+        drop(tier);
+
+        let tier = TieredStore::open(&tiering, Box::new(Shared(lower.clone(), faults.clone()))).unwrap();
+        assert_eq!(tier.status()["files"], 2);
+        assert_eq!(tier.status()["dirty"], 1);
+        let version = lower.stat("/clean").unwrap().1;
+        assert_eq!(tier.stat("/clean").unwrap(), (4, version));
+
+        // Without the DO, clean copies keep the version they were sent as and
+        // changes wait to be written back
+        faults.down.store(true, Ordering::SeqCst);
+        assert_eq!(tier.stat("/clean").unwrap(), (4, version));
+        assert_eq!(tier.read("/clean", 0, None).unwrap(), b"sent");
+        assert_eq!(tier.stat("/dirty").unwrap().0, 12);
+        assert!(tier.commit().is_err());
+        assert!(lower.stat("/dirty").is_err());
+        faults.down.store(false, Ordering::SeqCst);
+        assert_eq!(tier.commit().unwrap(), json!({ "files": 1, "bytes": 12 }));
+        assert_eq!(lower.read("/dirty", 0, None).unwrap(), b"not yet sent");
+        assert_eq!(tier.status()["dirty"], 0);
+        drop(tier);
+
+        // What is saved of the working set has to make sense to be used
+        fs::write(tiering.dir.join("tier.json"), b"{\"dirty\":").unwrap();
+        let opened = TieredStore::open(&tiering, Box::new(Shared(lower, faults)));
+        assert_eq!(opened.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&tiering.dir).unwrap();
+    }
+
+    #[test]
+    fn changes_made_while_written_back_are_written_back_again() {
+        let tiering = tiering("changes", 1000);
+        let (lower, faults) = (Arc::new(MemoryStore::default()), Arc::new(Faults::default()));
+        let tier = Arc::new(TieredStore::open(&tiering, Box::new(Shared(lower.clone(), faults.clone()))).unwrap());
+        tier.write("/file", None, b"first").unwrap();
+        let writer = tier.clone();
+        *faults.during_write.locked() = Some(Box::new(move || writer.write("/file", Some(0), b"F").unwrap()));
+
+        assert_eq!(tier.commit().unwrap(), json!({ "files": 1, "bytes": 5 }));
+        assert_eq!(lower.read("/file", 0, None).unwrap(), b"first");
+        assert_eq!(tier.status()["dirty"], 1);
+        assert_eq!(tier.commit().unwrap(), json!({ "files": 1, "bytes": 5 }));
+        assert_eq!(lower.read("/file", 0, None).unwrap(), b"First");
+        assert_eq!(tier.status()["dirty"], 0);
+        fs::remove_dir_all(&tiering.dir).unwrap();
+    }
+
+    #[test]
+    fn files_too_big_to_keep_are_only_brought_local_to_be_written() {
+        let tiering = tiering("big", 100);
+        let (lower, faults) = (Arc::new(MemoryStore::default()), Arc::new(Faults::default()));
+        lower.write("/big", None, &[7; 200]).unwrap();
+        let tier = TieredStore::open(&tiering, Box::new(Shared(lower.clone(), faults))).unwrap();
+
+        assert_eq!(tier.read("/big", 190, None).unwrap(), [7; 10]);
+        assert!(!tier.touch("/big"));
+        tier.write("/big", Some(0), b"x").unwrap();
+        assert!(tier.touch("/big"));
+        // Dirty files stay, however far over the high watermark
+        tier.evict().unwrap();
+        assert_eq!(tier.status()["bytes"], 200);
+        tier.commit().unwrap();
+        tier.evict().unwrap();
+        assert_eq!(tier.status()["bytes"], 0);
+        assert_eq!(lower.read("/big", 0, Some(2)).unwrap(), [b'x', 7]);
+
+        // Unlinking takes the directories a local file leaves empty with it,
+        // and what was never written back doesn't come back
+        tier.write("/d/e/f", None, b"nested").unwrap();
+        tier.commit().unwrap();
+        tier.write("/d/e/f", None, b"changed").unwrap();
+        assert!(tier.unlink("/d/e/f").unwrap());
+        assert!(!tiering.dir.join("files/d").exists());
+        assert_eq!(tier.commit().unwrap(), json!({ "files": 0, "bytes": 0 }));
+        assert!(lower.stat("/d/e/f").is_err());
+        assert!(!tier.unlink("/d/e/f").unwrap());
+        fs::remove_dir_all(&tiering.dir).unwrap();
+    }
+}
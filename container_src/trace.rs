@@ -0,0 +1,116 @@
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Set by --trace-context
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+tokio::task_local! {
+    static CURRENT: Option<TraceContext>;
+}
+
+thread_local! {
+    // The correlation id of the FUSE operation the thread is handling, and
+    // the process that asked for it
+    static CORRELATION: Cell<Option<u64>> = const { Cell::new(None) };
+    static CALLER: Cell<Option<u32>> = const { Cell::new(None) };
+}
+
+// A W3C trace context (https://www.w3.org/TR/trace-context/) for one request
+// to the DO: the trace it starts and the id of the daemon's span, which the
+// DO's spans for the request become children of
+#[derive(Clone, Copy, Debug)]
+pub struct TraceContext {
+    trace_id: u128,
+    span_id: u64,
+}
+
+impl TraceContext {
+    pub fn enable() {
+        ENABLED.store(true, Ordering::Relaxed);
+    }
+
+    // A new trace, when tracing across the DO is on
+    pub fn start() -> Option<Self> {
+        ENABLED.load(Ordering::Relaxed).then(|| Self {
+            trace_id: (random() as u128) << 64 | random() as u128,
+            span_id: random(),
+        })
+    }
+
+    // The trace of the request being sent from the current task, if any
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(|trace| *trace).ok().flatten()
+    }
+
+    // Runs `f` as part of `trace`, so requests it sends carry it
+    pub async fn scope<F: Future>(trace: Option<Self>, f: F) -> F::Output {
+        CURRENT.scope(trace, f).await
+    }
+
+    pub fn trace_id(&self) -> String {
+        format!("{:032x}", self.trace_id)
+    }
+
+    // The `traceparent` header value: version 00, the trace, the daemon's
+    // span, and the sampled flag
+    pub fn traceparent(&self) -> String {
+        format!("00-{:032x}-{:016x}-01", self.trace_id, self.span_id)
+    }
+}
+
+// One FUSE operation, for as long as it is handled: an id that every request
+// it sends to the DO carries, and a `fuse` span with it that every log line
+// made meanwhile on the thread is within, so both sides' logs of it can be
+// matched up. Requests run on the thread through block_on, so see it too.
+pub struct Correlation {
+    previous: Option<u64>,
+    previous_caller: Option<u32>,
+    _span: tracing::span::EnteredSpan,
+}
+
+impl Correlation {
+    pub fn begin(operation: &'static str, caller: u32) -> Self {
+        let id = random();
+        let span = tracing::info_span!("fuse", op = operation, cid = %format_id(id)).entered();
+        Self {
+            previous: CORRELATION.replace(Some(id)),
+            previous_caller: CALLER.replace(Some(caller)),
+            _span: span,
+        }
+    }
+
+    // The id of the operation being handled on this thread, if any
+    pub fn current() -> Option<String> {
+        CORRELATION.get().map(format_id)
+    }
+
+    // The pid of the process whose operation is being handled on this thread
+    pub fn caller() -> Option<u32> {
+        CALLER.get()
+    }
+}
+
+impl Drop for Correlation {
+    fn drop(&mut self) {
+        CORRELATION.set(self.previous);
+        CALLER.set(self.previous_caller);
+    }
+}
+
+fn format_id(id: u64) -> String {
+    format!("{:016x}", id)
+}
+
+// Ids and retry jitter only need to vary, not be unpredictable; every
+// RandomState is keyed differently, and all zeroes is not a valid id
+pub fn random() -> u64 {
+    loop {
+        let value = RandomState::new().build_hasher().finish();
+        if value != 0 {
+            return value;
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use std::fs::File;
+use std::os::unix::fs::FileTypeExt;
+use std::io::{self, BufReader, Read, Write};
+use std::fmt;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection};
+
+use crate::error::Locked;
+use crate::vsock::{VsockListener, VsockStream};
+
+// Ciphertext read from the socket, or plaintext from the daemon, per pass
+const TLS_BUFFER_SIZE: usize = 64 * 1024;
+
+// How long the DO has to complete a TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Where to listen for or dial the DO
+#[derive(Clone, Debug, PartialEq)]
+pub enum Address {
+    Tcp(String),
+    Vsock { cid: u32, port: u32 },
+    Unix(PathBuf),
+}
+
+impl Address {
+    // host:port, vsock://<cid>:<port> where a listener's cid may be `any`, or
+    // unix://<path>
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if let Some(path) = value.strip_prefix("unix://") {
+            if path.is_empty() {
+                return Err(format!("invalid unix socket address '{}', expected unix://<path>", value));
+            }
+            return Ok(Address::Unix(PathBuf::from(path)));
+        }
+        let Some(vsock) = value.strip_prefix("vsock://") else {
+            return Ok(Address::Tcp(value.to_string()));
+        };
+        let invalid = || format!("invalid vsock address '{}', expected vsock://<cid>:<port>", value);
+        let (cid, port) = vsock.split_once(':').ok_or_else(invalid)?;
+        let cid = match cid {
+            "any" => libc::VMADDR_CID_ANY,
+            cid => cid.parse().map_err(|_| invalid())?,
+        };
+        let port = port.parse().map_err(|_| invalid())?;
+        Ok(Address::Vsock { cid, port })
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Address::Tcp(_) => "tcp",
+            Address::Vsock { .. } => "vsock",
+            Address::Unix(_) => "unix",
+        }
+    }
+
+    // What the DO's certificate is checked against when dialing it, the host
+    // part of host:port without the brackets of an IPv6 literal
+    fn host(&self) -> Option<&str> {
+        match self {
+            Address::Tcp(address) => {
+                let host = address.rsplit_once(':').map_or(address.as_str(), |(host, _)| host);
+                Some(host.trim_start_matches('[').trim_end_matches(']'))
+            }
+            Address::Vsock { .. } | Address::Unix(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(address) => f.write_str(address),
+            Address::Vsock { cid, port } if *cid == libc::VMADDR_CID_ANY => write!(f, "vsock://any:{}", port),
+            Address::Vsock { cid, port } => write!(f, "vsock://{}:{}", cid, port),
+            Address::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Vsock(VsockListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub fn bind(address: &Address) -> io::Result<Self> {
+        match address {
+            Address::Tcp(address) => TcpListener::bind(address).map(Listener::Tcp),
+            Address::Vsock { cid, port } => VsockListener::bind(*cid, *port).map(Listener::Vsock),
+            Address::Unix(path) => {
+                // A socket file left by an earlier run would make the bind fail
+                if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                    std::fs::remove_file(path)?;
+                }
+                UnixListener::bind(path).map(Listener::Unix)
+            }
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<Conn> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Conn::Tcp(stream)),
+            Listener::Vsock(listener) => listener.accept().map(Conn::Vsock),
+            Listener::Unix(listener) => listener.accept().map(|(stream, _)| Conn::Unix(stream)),
+        }
+    }
+}
+
+pub fn connect(address: &Address) -> io::Result<Conn> {
+    match address {
+        Address::Tcp(address) => TcpStream::connect(address).map(Conn::Tcp),
+        Address::Vsock { cid, port } => VsockStream::connect(*cid, *port).map(Conn::Vsock),
+        Address::Unix(path) => UnixStream::connect(path).map(Conn::Unix),
+    }
+}
+
+// A connection to the DO as the reader and writer threads see it: the socket
+// itself, or the plaintext end of a TLS session terminated in the daemon,
+// which is a Unix socket like a local connection
+pub enum Conn {
+    Tcp(TcpStream),
+    Vsock(VsockStream),
+    Unix(UnixStream),
+}
+
+impl Conn {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Conn::Tcp(stream) => stream.try_clone().map(Conn::Tcp),
+            Conn::Vsock(stream) => stream.try_clone().map(Conn::Vsock),
+            Conn::Unix(stream) => stream.try_clone().map(Conn::Unix),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Conn::Tcp(stream) => stream.set_read_timeout(timeout),
+            Conn::Vsock(stream) => stream.set_read_timeout(timeout),
+            Conn::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Conn::Tcp(stream) => stream.shutdown(how),
+            Conn::Vsock(stream) => stream.shutdown(how),
+            Conn::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for Conn {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Conn::Tcp(stream) => stream.read(buf),
+            Conn::Vsock(stream) => stream.read(buf),
+            Conn::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Conn::Tcp(stream) => stream.write(buf),
+            Conn::Vsock(stream) => stream.write(buf),
+            Conn::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Conn::Tcp(stream) => stream.flush(),
+            Conn::Vsock(stream) => stream.flush(),
+            Conn::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+// Certificate files for mutual TLS: our own chain and key, and the CA the
+// DO's certificate must chain to
+#[derive(Clone, Debug)]
+pub struct TlsFiles {
+    pub cert: String,
+    pub key: String,
+    pub ca: String,
+    // Name the DO's certificate must carry when dialing; the host of the
+    // dialed address when unset
+    pub server_name: Option<String>,
+}
+
+// TLS settings for one side of the connection. Listening, the daemon is the
+// TLS server and requires a client certificate; dialing, it is the client
+// and presents one.
+pub enum Tls {
+    Server(Arc<ServerConfig>),
+    Client(Arc<ClientConfig>, Option<String>),
+}
+
+impl Tls {
+    pub fn load(files: &TlsFiles, dialing: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&files.cert)?))
+            .collect::<Result<Vec<CertificateDer<'static>>, _>>()?;
+        let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut BufReader::new(File::open(&files.key)?))?
+            .ok_or_else(|| format!("no private key in {}", files.key))?;
+        let mut roots = RootCertStore::empty();
+        for ca in rustls_pemfile::certs(&mut BufReader::new(File::open(&files.ca)?)) {
+            roots.add(ca?)?;
+        }
+
+        if dialing {
+            let config = ClientConfig::builder().with_root_certificates(roots).with_client_auth_cert(certs, key)?;
+            Ok(Tls::Client(Arc::new(config), files.server_name.clone()))
+        } else {
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build()?;
+            let config = ServerConfig::builder().with_client_cert_verifier(verifier).with_single_cert(certs, key)?;
+            Ok(Tls::Server(Arc::new(config)))
+        }
+    }
+
+    // Completes a TLS handshake over `stream`, verifying the DO's certificate,
+    // and returns the plaintext end of the session. `address` is what was
+    // dialed, for the name the certificate must carry.
+    pub fn wrap(&self, mut stream: Conn, address: &Address) -> Result<Conn, Box<dyn std::error::Error>> {
+        let mut session: Connection = match self {
+            Tls::Server(config) => ServerConnection::new(config.clone())?.into(),
+            Tls::Client(config, server_name) => {
+                let name = match server_name.as_deref().or(address.host()) {
+                    Some(name) => name.to_string(),
+                    None => return Err(format!("TLS to {} needs --tls-server-name", address).into()),
+                };
+                ClientConnection::new(config.clone(), ServerName::try_from(name)?)?.into()
+            }
+        };
+        stream.set_read_timeout(Some(TLS_HANDSHAKE_TIMEOUT))?;
+        while session.is_handshaking() {
+            session.complete_io(&mut stream)?;
+        }
+        stream.set_read_timeout(None)?;
+        Ok(Conn::Unix(bridge(session, stream)?))
+    }
+}
+
+// Relays between the TLS session on `stream` and one end of a socket pair,
+// returning the other end. The session is shared by two threads, one per
+// direction, so neither has to wait for the other's blocking read. Either side
+// closing closes both.
+fn bridge(session: Connection, stream: Conn) -> io::Result<UnixStream> {
+    let (plain, inner) = UnixStream::pair()?;
+    let session = Arc::new(Mutex::new(session));
+
+    // From the DO: decrypt whatever arrives and pass the plaintext on
+    let (inbound_session, mut from_do, mut to_do, mut to_daemon) =
+        (session.clone(), stream.try_clone()?, stream.try_clone()?, inner.try_clone()?);
+    thread::spawn(move || {
+        let mut ciphertext = vec![0u8; TLS_BUFFER_SIZE];
+        let mut plaintext = Vec::new();
+        loop {
+            let read = match from_do.read(&mut ciphertext) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            let open = {
+                let mut session = inbound_session.locked();
+                let open = decrypt(&mut session, &ciphertext[..read], &mut plaintext);
+                // Alerts and key updates owed to the DO
+                open.and_then(|open| flush(&mut session, &mut to_do).map(|_| open))
+            };
+            if to_daemon.write_all(&plaintext).is_err() || !matches!(open, Ok(true)) {
+                break;
+            }
+            plaintext.clear();
+        }
+        let _ = to_daemon.shutdown(Shutdown::Both);
+        let _ = from_do.shutdown(Shutdown::Both);
+    });
+
+    // To the DO: encrypt what the daemon writes
+    let (mut from_daemon, mut to_do) = (inner, stream);
+    thread::spawn(move || {
+        let mut buffer = vec![0u8; TLS_BUFFER_SIZE];
+        loop {
+            let read = match from_daemon.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            let mut session = session.locked();
+            if session.writer().write_all(&buffer[..read]).is_err() || flush(&mut session, &mut to_do).is_err() {
+                break;
+            }
+        }
+        let mut session = session.locked();
+        session.send_close_notify();
+        let _ = flush(&mut session, &mut to_do);
+        let _ = to_do.shutdown(Shutdown::Both);
+        let _ = from_daemon.shutdown(Shutdown::Both);
+    });
+
+    Ok(plain)
+}
+
+// Feeds ciphertext to the session and appends the plaintext it yields.
+// Returns false once the DO has closed the session cleanly.
+fn decrypt(session: &mut Connection, mut ciphertext: &[u8], plaintext: &mut Vec<u8>) -> io::Result<bool> {
+    while !ciphertext.is_empty() {
+        session.read_tls(&mut ciphertext)?;
+        session
+            .process_new_packets()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        match session.reader().read_to_end(plaintext) {
+            Ok(_) => return Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+fn flush(session: &mut Connection, stream: &mut Conn) -> io::Result<()> {
+    while session.wants_write() {
+        session.write_tls(stream)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_parse_by_scheme() {
+        assert_eq!(Address::parse("10.0.0.1:8000"), Ok(Address::Tcp("10.0.0.1:8000".to_string())));
+        assert_eq!(Address::parse("unix:///run/fs.sock"), Ok(Address::Unix(PathBuf::from("/run/fs.sock"))));
+        assert_eq!(Address::parse("vsock://3:5000"), Ok(Address::Vsock { cid: 3, port: 5000 }));
+        assert_eq!(
+            Address::parse("vsock://any:5000"),
+            Ok(Address::Vsock {
+                cid: libc::VMADDR_CID_ANY,
+                port: 5000
+            })
+        );
+    }
+
+    #[test]
+    fn malformed_addresses_are_rejected() {
+        for value in ["unix://", "vsock://3", "vsock://x:1", "vsock://3:port", "vsock://3:99999999999"] {
+            assert!(Address::parse(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn addresses_display_as_they_parse() {
+        for value in ["[::1]:8000", "unix:///run/fs.sock", "vsock://3:5000", "vsock://any:5000"] {
+            assert_eq!(Address::parse(value).unwrap().to_string(), value);
+        }
+    }
+
+    #[test]
+    fn certificates_are_checked_against_the_bare_host() {
+        let host = |value: &str| Address::parse(value).unwrap().host().map(str::to_string);
+        assert_eq!(host("do.example:443"), Some("do.example".to_string()));
+        assert_eq!(host("[::1]:443"), Some("::1".to_string()));
+        assert_eq!(host("vsock://3:5000"), None);
+    }
+}
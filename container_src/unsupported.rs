@@ -0,0 +1,135 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+
+use fuser::FileType;
+
+// How the daemon answers a call the DO has no equivalent for
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Policy {
+    // Fail the call with ENOSYS
+    Enosys,
+    // Report success without doing anything
+    Succeed,
+    // Keep the state in the daemon, per mount and lost on restart
+    Emulate,
+}
+
+impl Policy {
+    fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "enosys" => Ok(Policy::Enosys),
+            "succeed" => Ok(Policy::Succeed),
+            "emulate" => Ok(Policy::Emulate),
+            other => Err(format!("unknown policy '{}', expected enosys, succeed or emulate", other)),
+        }
+    }
+}
+
+// Per-feature policies chosen by the operator
+#[derive(Clone, Copy, Debug)]
+pub struct UnsupportedPolicy {
+    // POSIX byte-range locks; emulated locks are the kernel's own local ones
+    pub locks: Policy,
+    pub xattrs: Policy,
+    // FIFOs, sockets and device nodes; regular files are always created in the DO
+    pub mknod: Policy,
+}
+
+impl Default for UnsupportedPolicy {
+    // The behavior before policies existed: the kernel keeps locks local and the rest fail
+    fn default() -> Self {
+        Self {
+            locks: Policy::Emulate,
+            xattrs: Policy::Enosys,
+            mknod: Policy::Enosys,
+        }
+    }
+}
+
+impl UnsupportedPolicy {
+    // Applies comma-separated feature=policy pairs, e.g. "locks=enosys,xattrs=emulate"
+    pub fn apply(&mut self, spec: &str) -> Result<(), String> {
+        for pair in spec.split(',') {
+            let (feature, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected feature=policy, got '{}'", pair))?;
+            let policy = Policy::parse(value)?;
+            match feature {
+                "locks" => self.locks = policy,
+                "xattrs" => self.xattrs = policy,
+                "mknod" => self.mknod = policy,
+                other => return Err(format!("unknown feature '{}', expected locks, xattrs or mknod", other)),
+            }
+        }
+        Ok(())
+    }
+}
+
+// Extended attributes kept in the daemon, keyed by path
+#[derive(Default)]
+pub struct XattrStore {
+    files: HashMap<String, BTreeMap<OsString, Vec<u8>>>,
+}
+
+impl XattrStore {
+    pub fn set(&mut self, path: &str, name: &OsStr, value: &[u8], flags: i32) -> Result<(), libc::c_int> {
+        let attrs = self.files.entry(path.to_string()).or_default();
+        let exists = attrs.contains_key(name);
+        if flags & libc::XATTR_CREATE != 0 && exists {
+            return Err(libc::EEXIST);
+        }
+        if flags & libc::XATTR_REPLACE != 0 && !exists {
+            return Err(libc::ENODATA);
+        }
+        attrs.insert(name.to_os_string(), value.to_vec());
+        Ok(())
+    }
+
+    pub fn get(&self, path: &str, name: &OsStr) -> Option<&[u8]> {
+        self.files.get(path)?.get(name).map(Vec::as_slice)
+    }
+
+    // Names as the kernel expects them, each terminated by a NUL
+    pub fn list(&self, path: &str) -> Vec<u8> {
+        let mut names = Vec::new();
+        for name in self.files.get(path).into_iter().flat_map(BTreeMap::keys) {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        names
+    }
+
+    pub fn remove(&mut self, path: &str, name: &OsStr) -> Result<(), libc::c_int> {
+        let attrs = self.files.get_mut(path).ok_or(libc::ENODATA)?;
+        attrs.remove(name).ok_or(libc::ENODATA)?;
+        if attrs.is_empty() {
+            self.files.remove(path);
+        }
+        Ok(())
+    }
+
+    // Drops every attribute of a path that was removed or replaced
+    pub fn forget(&mut self, path: &str) {
+        self.files.remove(path);
+    }
+}
+
+// A FIFO, socket or device node created by emulated mknod. These exist only
+// in the daemon and are found by name; directory listings come from the DO.
+pub struct SpecialNode {
+    pub kind: FileType,
+    pub perm: u16,
+    pub rdev: u32,
+}
+
+// The node type mknod is asked for, or None for a regular file
+pub fn special_kind(mode: u32) -> Option<FileType> {
+    match mode & libc::S_IFMT {
+        libc::S_IFIFO => Some(FileType::NamedPipe),
+        libc::S_IFCHR => Some(FileType::CharDevice),
+        libc::S_IFBLK => Some(FileType::BlockDevice),
+        libc::S_IFSOCK => Some(FileType::Socket),
+        _ => None,
+    }
+}
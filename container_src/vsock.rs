@@ -0,0 +1,134 @@
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::Duration;
+
+// Connections waiting to be accepted
+const BACKLOG: i32 = 128;
+
+// A virtio-vsock stream socket, for runtimes that reach the host over vsock
+// rather than a network path. Plain file descriptor calls; std has no type
+// for this address family.
+pub struct VsockStream(OwnedFd);
+
+pub struct VsockListener(OwnedFd);
+
+fn socket() -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn address(cid: u32, port: u32) -> libc::sockaddr_vm {
+    let mut address: libc::sockaddr_vm = unsafe { mem::zeroed() };
+    address.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    address.svm_cid = cid;
+    address.svm_port = port;
+    address
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+impl VsockStream {
+    pub fn connect(cid: u32, port: u32) -> io::Result<Self> {
+        let fd = socket()?;
+        let address = address(cid, port);
+        check(unsafe {
+            libc::connect(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_vm as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        })?;
+        Ok(Self(fd))
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        self.0.try_clone().map(Self)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let timeout = timeout.unwrap_or_default();
+        let value = libc::timeval {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+        };
+        check(unsafe {
+            libc::setsockopt(
+                self.0.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &value as *const libc::timeval as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        })?;
+        Ok(())
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let how = match how {
+            Shutdown::Read => libc::SHUT_RD,
+            Shutdown::Write => libc::SHUT_WR,
+            Shutdown::Both => libc::SHUT_RDWR,
+        };
+        check(unsafe { libc::shutdown(self.0.as_raw_fd(), how) })?;
+        Ok(())
+    }
+}
+
+impl Read for VsockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = unsafe { libc::read(self.0.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(read as usize)
+    }
+}
+
+impl Write for VsockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = unsafe { libc::write(self.0.as_raw_fd(), buf.as_ptr() as *const libc::c_void, buf.len()) };
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(written as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl VsockListener {
+    // Listens on `port` for connections to `cid`, which may be VMADDR_CID_ANY
+    pub fn bind(cid: u32, port: u32) -> io::Result<Self> {
+        let fd = socket()?;
+        let address = address(cid, port);
+        check(unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_vm as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        })?;
+        check(unsafe { libc::listen(fd.as_raw_fd(), BACKLOG) })?;
+        Ok(Self(fd))
+    }
+
+    pub fn accept(&self) -> io::Result<VsockStream> {
+        let fd = check(unsafe {
+            libc::accept4(self.0.as_raw_fd(), std::ptr::null_mut(), std::ptr::null_mut(), libc::SOCK_CLOEXEC)
+        })?;
+        Ok(VsockStream(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+}
@@ -0,0 +1,313 @@
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::thread;
+
+use fuser::{FileAttr, FileType};
+use serde_json::json;
+use tracing::{debug, info, warn};
+
+use crate::audit::{AuditOp, Caller};
+use crate::endpoints::{Endpoints, Resolved};
+use crate::error::Locked;
+use crate::http::{self, encode, escape, etag, http_date, respond, respond_head, status, write_head, Request};
+use crate::transport::{Address, Conn, Listener};
+use crate::join;
+
+// What is read of a control file command
+const MAX_COMMAND: u64 = 64 * 1024;
+
+// Class 1 only: there are no locks, so clients that need them mount read-only
+const ALLOW: &str = "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND";
+
+// Requests carry nothing the daemon could check, so their changes are
+// audited as nobody's
+const CALLER: Caller = Caller { uid: 65534, gid: 65534, pid: 0 };
+
+// The WebDAV endpoint, serving every mount under its mount point next to
+// whatever frontend the mounts have
+pub struct Server {
+    endpoints: Arc<Endpoints>,
+}
+
+// Serves the mounts of `endpoints` on `address`, each under its mount point
+pub fn spawn(address: &Address, endpoints: Arc<Endpoints>) -> io::Result<()> {
+    let listener = Listener::bind(address)?;
+    let server = Arc::new(Server { endpoints });
+    info!("Serving WebDAV on {}", address);
+
+    let accepting = server.clone();
+    thread::spawn(move || loop {
+        match listener.accept() {
+            Ok(stream) => {
+                let server = accepting.clone();
+                thread::spawn(move || {
+                    if let Err(e) = http::serve(stream, |request, body, out| server.answer(request, body, out)) {
+                        debug!("WebDAV client went away: {}", e);
+                    }
+                });
+            }
+            Err(e) => warn!("Failed to accept a WebDAV client: {}", e),
+        }
+    });
+    Ok(())
+}
+
+impl Server {
+    fn answer(&self, request: &Request, body: &mut dyn Read, out: &mut Conn) -> io::Result<()> {
+        let resolved = match self.endpoints.resolve(&request.path) {
+            Ok(Some(resolved)) => resolved,
+            Ok(None) => return respond(out, "404 Not Found", &[], b"not found\n"),
+            Err(errno) => return respond_errno(out, errno),
+        };
+        match request.method.as_str() {
+            "OPTIONS" => respond(out, "200 OK", &[("DAV", "1".to_string()), ("Allow", ALLOW.to_string())], b""),
+            "GET" | "HEAD" => self.get(request, resolved, out),
+            "PUT" => self.put(resolved, body, out),
+            "DELETE" => self.delete(resolved, out),
+            "PROPFIND" => self.propfind(request, resolved, out),
+            // Directories, renames, copies, locks and properties of one's own,
+            // none of which the DO has
+            _ => respond(out, "405 Method Not Allowed", &[("Allow", ALLOW.to_string())], b""),
+        }
+    }
+
+    fn get(&self, request: &Request, resolved: Resolved, out: &mut Conn) -> io::Result<()> {
+        let head = request.method == "HEAD";
+        let (mount, path) = match resolved {
+            Resolved::Above(names) => {
+                let entries: Vec<_> = names.iter().map(|name| (name.as_str(), true)).collect();
+                return respond_html(out, &request.path, &entries, head);
+            }
+            Resolved::In { mount, path } => (mount, path),
+        };
+        let mut fs = mount.fs.locked();
+        if fs.fsctl.is(&path) {
+            let mut status = serde_json::to_vec_pretty(&fs.control_status()).unwrap_or_default();
+            status.push(b'\n');
+            drop(fs);
+            let headers = [("Content-Type", "application/json".to_string())];
+            return respond_head(out, "200 OK", &headers, &status, head);
+        }
+        let attr = match fs.path_attr(&path) {
+            Ok(Some(attr)) => attr,
+            Ok(None) => return respond_errno(out, libc::ENOENT),
+            Err(e) => return respond_errno(out, e.errno()),
+        };
+        if attr.kind == FileType::Directory {
+            let children = match fs.list_all(&path) {
+                Ok(children) => children,
+                Err(errno) => return respond_errno(out, errno),
+            };
+            drop(fs);
+            let children: Vec<_> =
+                children.iter().map(|(name, kind)| (mount.name(name), *kind == FileType::Directory)).collect();
+            let entries: Vec<_> = children.iter().map(|(name, collection)| (name.as_str(), *collection)).collect();
+            return respond_html(out, &request.path, &entries, head);
+        }
+        drop(fs);
+
+        let headers = [
+            ("Content-Type", "application/octet-stream".to_string()),
+            ("Content-Length", attr.size.to_string()),
+            ("Last-Modified", http_date(attr.mtime)),
+            ("ETag", etag(&attr)),
+        ];
+        write_head(out, "200 OK", &headers)?;
+        if head {
+            return Ok(());
+        }
+        mount.send(&path, 0, attr.size, out)
+    }
+
+    // Replaces the file with the body, answering once the DO has all of it.
+    // The control file runs the body as commands and answers with the result.
+    fn put(&self, resolved: Resolved, body: &mut dyn Read, out: &mut Conn) -> io::Result<()> {
+        let Resolved::In { mount, path } = resolved else {
+            let allow = [("Allow", "OPTIONS, GET, HEAD, PROPFIND".to_string())];
+            return respond(out, "405 Method Not Allowed", &allow, b"");
+        };
+        if mount.fs.locked().fsctl.is(&path) {
+            let mut command = String::new();
+            Read::take(&mut *body, MAX_COMMAND).read_to_string(&mut command)?;
+            let result = mount.fs.locked().control(&command);
+            let (status, answer) = match result {
+                Ok(result) => ("200 OK", json!({ "ok": true, "result": result })),
+                Err((errno, error)) => (self::status(errno), json!({ "ok": false, "error": error })),
+            };
+            let mut answer = serde_json::to_vec_pretty(&answer).unwrap_or_default();
+            answer.push(b'\n');
+            return respond(out, status, &[("Content-Type", "application/json".to_string())], &answer);
+        }
+
+        match mount.replace(&path, body, CALLER) {
+            Ok(true) => respond(out, "201 Created", &[], b""),
+            Ok(false) => respond(out, "204 No Content", &[], b""),
+            Err(Ok(errno)) => respond_errno(out, errno),
+            // The client went away part way, so there is nobody to answer
+            Err(Err(e)) => Err(e),
+        }
+    }
+
+    fn delete(&self, resolved: Resolved, out: &mut Conn) -> io::Result<()> {
+        let Resolved::In { mount, path } = resolved else {
+            return respond_errno(out, libc::EPERM);
+        };
+        let mut fs = mount.fs.locked();
+        let result = fs.refuses_changes(&path).and_then(|()| match fs.path_attr(&path) {
+            Ok(Some(attr)) if attr.kind == FileType::Directory => Err(libc::EPERM),
+            Ok(Some(_)) => fs.unlink_path(&path),
+            Ok(None) => Err(libc::ENOENT),
+            Err(e) => Err(e.errno()),
+        });
+        if result != Err(libc::ENOENT) {
+            fs.audit(CALLER, AuditOp::Delete, &path, None, None, result.err());
+        }
+        drop(fs);
+        match result {
+            Ok(()) => respond(out, "204 No Content", &[], b""),
+            Err(errno) => respond_errno(out, errno),
+        }
+    }
+
+    // Depth 0 describes the path, anything else its children too. Every
+    // property there is comes back whatever was asked for.
+    fn propfind(&self, request: &Request, resolved: Resolved, out: &mut Conn) -> io::Result<()> {
+        let depth = request.header("Depth").map(str::trim) != Some("0");
+        let href = match request.path.as_str() {
+            "/" => "/".to_string(),
+            path => format!("{}/", encode(path)),
+        };
+        let mut entries = Vec::new();
+        match resolved {
+            Resolved::Above(names) => {
+                entries.push(property(&href, &request.path, None));
+                for name in names.iter().filter(|_| depth) {
+                    entries.push(property(&format!("{}{}/", href, encode(name)), name, None));
+                }
+            }
+            Resolved::In { mount, path } => {
+                let mut fs = mount.fs.locked();
+                let attr = match fs.path_attr(&path) {
+                    Ok(Some(attr)) => attr,
+                    Ok(None) => return respond_errno(out, libc::ENOENT),
+                    Err(e) => return respond_errno(out, e.errno()),
+                };
+                if attr.kind != FileType::Directory {
+                    entries.push(property(href.trim_end_matches('/'), &request.path, Some(&attr)));
+                } else {
+                    entries.push(property(&href, &request.path, Some(&attr)));
+                    let children = match depth {
+                        true => fs.list_all(&path),
+                        false => Ok(Vec::new()),
+                    };
+                    let children = match children {
+                        Ok(children) => children,
+                        Err(errno) => return respond_errno(out, errno),
+                    };
+                    for (name, _) in children {
+                        // Gone since it was listed
+                        let Ok(Some(attr)) = fs.path_attr(&join(&path, &name)) else {
+                            continue;
+                        };
+                        let slash = if attr.kind == FileType::Directory { "/" } else { "" };
+                        let name = mount.name(&name);
+                        entries.push(property(&format!("{}{}{}", href, encode(&name), slash), &name, Some(&attr)));
+                    }
+                }
+            }
+        }
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n{}</D:multistatus>\n",
+            entries.concat()
+        );
+        let headers = [("Content-Type", "application/xml; charset=utf-8".to_string())];
+        respond(out, "207 Multi-Status", &headers, body.as_bytes())
+    }
+}
+
+// One response of a PROPFIND, for a collection above the mounts when there
+// are no attributes
+fn property(href: &str, path: &str, attr: Option<&FileAttr>) -> String {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let mut props = format!("<D:displayname>{}</D:displayname>", escape(name));
+    match attr {
+        Some(attr) if attr.kind != FileType::Directory => {
+            props += "<D:resourcetype/><D:getcontenttype>application/octet-stream</D:getcontenttype>";
+            props += &format!("<D:getcontentlength>{}</D:getcontentlength>", attr.size);
+            props += &format!("<D:getetag>{}</D:getetag>", escape(&etag(attr)));
+        }
+        _ => props += "<D:resourcetype><D:collection/></D:resourcetype>",
+    }
+    if let Some(attr) = attr {
+        props += &format!("<D:getlastmodified>{}</D:getlastmodified>", http_date(attr.mtime));
+    }
+    format!(
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop>\
+         <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n",
+        escape(href),
+        props
+    )
+}
+
+fn respond_errno(out: &mut Conn, errno: i32) -> io::Result<()> {
+    let message = format!("{}\n", io::Error::from_raw_os_error(errno));
+    respond(out, status(errno), &[("Content-Type", "text/plain".to_string())], message.as_bytes())
+}
+
+// A page listing a collection, for browsers
+fn respond_html(out: &mut Conn, path: &str, entries: &[(&str, bool)], head: bool) -> io::Result<()> {
+    let href = match path {
+        "/" => "/".to_string(),
+        path => format!("{}/", encode(path)),
+    };
+    let mut page = format!("<!DOCTYPE html>\n<title>{0}</title>\n<h1>{0}</h1>\n<ul>\n", escape(path));
+    for (name, collection) in entries {
+        let slash = if *collection { "/" } else { "" };
+        page += &format!("<li><a href=\"{}{}{}\">{}{}</a></li>\n", href, encode(name), slash, escape(name), slash);
+    }
+    page += "</ul>\n";
+    let headers = [("Content-Type", "text/html; charset=utf-8".to_string())];
+    respond_head(out, "200 OK", &headers, page.as_bytes(), head)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    #[test]
+    fn properties_are_escaped_and_only_files_have_lengths() {
+        let time = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        let attr = FileAttr {
+            ino: 2,
+            size: 5,
+            blocks: 1,
+            atime: time,
+            mtime: time,
+            ctime: time,
+            crtime: time,
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        };
+        let file = property("/storage/a%20%26b.txt", "/storage/a &b.txt", Some(&attr));
+        assert!(file.contains("<D:href>/storage/a%20%26b.txt</D:href>"), "{}", file);
+        assert!(file.contains("<D:displayname>a &amp;b.txt</D:displayname>"), "{}", file);
+        assert!(file.contains("<D:resourcetype/>") && file.contains("<D:getcontentlength>5</D:getcontentlength>"));
+        assert!(file.contains("<D:getlastmodified>Sun, 06 Nov 1994 08:49:37 GMT</D:getlastmodified>"), "{}", file);
+
+        let dir = property("/storage/", "/storage", Some(&FileAttr { kind: FileType::Directory, ..attr }));
+        assert!(dir.contains("<D:collection/>") && !dir.contains("getcontentlength"), "{}", dir);
+        assert!(dir.contains("<D:getlastmodified>"), "{}", dir);
+        // Above the mounts there is nothing to say but the name
+        let above = property("/<mounts>/", "/<mounts>", None);
+        assert!(above.contains("<D:displayname>&lt;mounts&gt;</D:displayname>"), "{}", above);
+        assert!(above.contains("<D:collection/>") && !above.contains("getlastmodified"), "{}", above);
+    }
+}
@@ -0,0 +1,451 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use crate::error::{DaemonError, Locked};
+use crate::journal::Journal;
+use crate::spill::{DirtySegment, SpillDir};
+use crate::RemoteFSClient;
+
+// Dirty data older than this is flushed by the background thread
+pub const WRITEBACK_INTERVAL: Duration = Duration::from_secs(5);
+
+// Dirty bytes across all files before writers flush synchronously
+pub const WRITEBACK_MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+// Unflushed data for one file as non-overlapping extents keyed by offset.
+// Segments spilled to disk are older than the extents still in memory and
+// are flushed first, in order.
+#[derive(Default)]
+struct DirtyFile {
+    extents: BTreeMap<u64, Vec<u8>>,
+    spilled: Vec<DirtySegment>,
+    since: Option<Instant>,
+    // Journal records of the writes held, marked done once they are flushed
+    journaled: Vec<u64>,
+}
+
+impl DirtyFile {
+    // Overlays `data` at `offset`, merging with any extent it overlaps or touches
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        let mut start = offset;
+        let mut end = offset + data.len() as u64;
+        let touching: Vec<u64> = self
+            .extents
+            .range(..=end)
+            .filter(|(at, extent)| **at + extent.len() as u64 >= start)
+            .map(|(at, _)| *at)
+            .collect();
+        for at in &touching {
+            start = start.min(*at);
+            end = end.max(*at + self.extents[at].len() as u64);
+        }
+
+        let mut merged = vec![0u8; (end - start) as usize];
+        for at in touching {
+            let extent = self.extents.remove(&at).unwrap_or_default();
+            let from = (at - start) as usize;
+            merged[from..from + extent.len()].copy_from_slice(&extent);
+        }
+        let from = (offset - start) as usize;
+        merged[from..from + data.len()].copy_from_slice(data);
+        self.extents.insert(start, merged);
+        self.since.get_or_insert_with(Instant::now);
+    }
+
+    fn bytes(&self) -> usize {
+        self.extents.values().map(Vec::len).sum()
+    }
+
+    fn end(&self) -> Option<u64> {
+        self.extents
+            .iter()
+            .next_back()
+            .map(|(at, extent)| at + extent.len() as u64)
+            .into_iter()
+            .chain(self.spilled.iter().map(|segment| segment.end))
+            .max()
+    }
+}
+
+// Write-back buffer: writes are acknowledged once recorded here and reach
+// the DO later, from the background flusher (interval or close), from a
+// writer exceeding the memory limit, or from fsync and reads of the file.
+// With a spill directory, a writer over the limit moves data to disk instead.
+pub struct WriteBack {
+    files: Mutex<HashMap<String, DirtyFile>>,
+    // Bytes held in memory; spilled segments are accounted by the spill directory
+    dirty_bytes: AtomicUsize,
+    spill: Option<Arc<SpillDir>>,
+    journal: Option<Arc<Journal>>,
+    // Serializes flushes so a read waiting on a flush sees it completed
+    flush_lock: tokio::sync::Mutex<()>,
+    wakeups: Mutex<Option<mpsc::Sender<String>>>,
+}
+
+impl WriteBack {
+    pub fn new(spill: Option<Arc<SpillDir>>, journal: Option<Arc<Journal>>) -> Arc<Self> {
+        Arc::new(Self {
+            files: Mutex::new(HashMap::new()),
+            dirty_bytes: AtomicUsize::new(0),
+            spill,
+            journal,
+            flush_lock: tokio::sync::Mutex::new(()),
+            wakeups: Mutex::new(None),
+        })
+    }
+
+    // Records a write, and the journal record it has if any, returning the
+    // dirty bytes now held across all files
+    pub fn buffer(&self, path: &str, offset: u64, data: &[u8], journaled: Option<u64>) -> usize {
+        let mut files = self.files.locked();
+        let file = files.entry(path.to_string()).or_default();
+        let before = file.bytes();
+        file.write(offset, data);
+        file.journaled.extend(journaled);
+        // Overlaying never shrinks a file's dirty data
+        let added = file.bytes() - before;
+        self.dirty_bytes.fetch_add(added, Ordering::SeqCst) + added
+    }
+
+    // End of the furthest dirty extent, which may lie past the DO's idea of the size
+    pub fn dirty_end(&self, path: &str) -> Option<u64> {
+        self.files.locked().get(path).and_then(DirtyFile::end)
+    }
+
+    // Drops buffered data for a path that is being replaced wholesale
+    pub fn discard(&self, path: &str) {
+        if let Some(file) = self.files.locked().remove(path) {
+            self.dirty_bytes.fetch_sub(file.bytes(), Ordering::SeqCst);
+            self.remove_spilled(&file.spilled);
+            self.journaled_done(&file.journaled);
+        }
+    }
+
+    // Takes over dirty data a previous run spilled but never flushed,
+    // returning whether there was any
+    pub fn adopt_recovered(&self) -> bool {
+        let Some(spill) = &self.spill else {
+            return false;
+        };
+        let recovered = spill.take_recovered();
+        let found = !recovered.is_empty();
+        let mut files = self.files.locked();
+        for segment in recovered {
+            let file = files.entry(segment.path.clone()).or_default();
+            file.since.get_or_insert_with(Instant::now);
+            file.spilled.push(segment);
+        }
+        found
+    }
+
+    // Moves the oldest files' in-memory data to disk until under `limit`,
+    // returning false if there is no spill directory or it is full
+    pub fn spill_until_below(&self, limit: usize) -> bool {
+        let Some(spill) = &self.spill else {
+            return false;
+        };
+        let mut files = self.files.locked();
+        while self.dirty_bytes.load(Ordering::SeqCst) > limit {
+            let oldest = files
+                .iter_mut()
+                .filter(|(_, file)| !file.extents.is_empty())
+                .min_by_key(|(_, file)| file.since);
+            let Some((path, file)) = oldest else {
+                break;
+            };
+            let Some(segment) = spill.store_dirty(path, &file.extents) else {
+                return false;
+            };
+            self.dirty_bytes.fetch_sub(file.bytes(), Ordering::SeqCst);
+            file.extents.clear();
+            file.spilled.push(segment);
+        }
+        true
+    }
+
+    // Asks the background flusher to write a path out soon, e.g. on close
+    pub fn request_flush(&self, path: &str) {
+        if let Some(wakeups) = self.wakeups.locked().as_ref() {
+            let _ = wakeups.send(path.to_string());
+        }
+    }
+
+    pub async fn flush_path(&self, client: &RemoteFSClient, path: &str) -> Result<(), DaemonError> {
+        let _guard = self.flush_lock.lock().await;
+        let Some(file) = self.files.locked().remove(path) else {
+            return Ok(());
+        };
+        self.dirty_bytes.fetch_sub(file.bytes(), Ordering::SeqCst);
+
+        let mut spilled = file.spilled.into_iter();
+        while let Some(segment) = spilled.next() {
+            let spill = self.spill.as_ref().expect("spilled write-back data without a spill directory");
+            let Some(extents) = spill.load_dirty(segment.seq) else {
+                warn!("Dropping unreadable spilled write-back data for {}", path);
+                spill.remove_dirty(segment.seq);
+                continue;
+            };
+            for (offset, data) in extents {
+                if let (_, Some(error)) = client.write_chunked(path, offset, &data).await {
+                    // Positional writes are safe to repeat, so the whole segment goes back
+                    let unsent = DirtyFile {
+                        extents: file.extents,
+                        spilled: std::iter::once(segment).chain(spilled).collect(),
+                        since: file.since,
+                        journaled: file.journaled,
+                    };
+                    self.dirty_bytes.fetch_add(unsent.bytes(), Ordering::SeqCst);
+                    self.requeue(path, unsent);
+                    return Err(error);
+                }
+            }
+            spill.remove_dirty(segment.seq);
+        }
+
+        let mut extents = file.extents.into_iter();
+        while let Some((offset, data)) = extents.next() {
+            let (verified, error) = client.write_chunked(path, offset, &data).await;
+            if let Some(error) = error {
+                // Put back what didn't make it, under anything written since
+                let mut unsent = DirtyFile::default();
+                unsent.write(offset + verified as u64, &data[verified..]);
+                for (offset, data) in extents {
+                    unsent.write(offset, &data);
+                }
+                unsent.journaled = file.journaled;
+                self.dirty_bytes.fetch_add(unsent.bytes(), Ordering::SeqCst);
+                self.requeue(path, unsent);
+                return Err(error);
+            }
+        }
+        self.journaled_done(&file.journaled);
+        Ok(())
+    }
+
+    // Flushes files dirty for longer than `age`, or every file when `age` is zero
+    pub async fn flush_older_than(&self, client: &RemoteFSClient, age: Duration) -> Result<(), DaemonError> {
+        let due: Vec<String> = self
+            .files
+            .locked()
+            .iter()
+            .filter(|(_, file)| file.since.is_some_and(|since| since.elapsed() >= age))
+            .map(|(path, _)| path.clone())
+            .collect();
+        let mut result = Ok(());
+        for path in due {
+            if let Err(e) = self.flush_path(client, &path).await {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    // Flushes the oldest files until dirty data is back under `limit`, unless
+    // it can be spilled to disk instead
+    pub async fn flush_until_below(&self, client: &RemoteFSClient, limit: usize) -> Result<(), DaemonError> {
+        if self.spill_until_below(limit) {
+            return Ok(());
+        }
+        while self.dirty_bytes.load(Ordering::SeqCst) > limit {
+            let oldest = self
+                .files
+                .locked()
+                .iter()
+                .min_by_key(|(_, file)| file.since)
+                .map(|(path, _)| path.clone());
+            let Some(path) = oldest else {
+                break;
+            };
+            self.flush_path(client, &path).await?;
+        }
+        Ok(())
+    }
+
+    pub fn spawn_flusher(self: &Arc<Self>, client: Arc<RemoteFSClient>) {
+        let (wakeups, requests) = mpsc::channel();
+        *self.wakeups.locked() = Some(wakeups);
+
+        let writeback = self.clone();
+        thread::spawn(move || {
+            loop {
+                let requested = match requests.recv_timeout(WRITEBACK_INTERVAL) {
+                    Ok(path) => client.block_on(writeback.flush_path(&client, &path)),
+                    Err(mpsc::RecvTimeoutError::Timeout) => Ok(()),
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                };
+                // Steady close traffic must not starve files that were never closed
+                let expired = client.block_on(writeback.flush_older_than(&client, WRITEBACK_INTERVAL));
+                let result = requested.and(expired);
+                if let Err(e) = result {
+                    warn!("Write-back flush failed, will retry: {}", e);
+                }
+            }
+        });
+    }
+
+    // Puts back data a flush didn't send, ordered before anything buffered since.
+    // `unsent` must already be counted in `dirty_bytes`.
+    fn requeue(&self, path: &str, mut unsent: DirtyFile) {
+        let mut files = self.files.locked();
+        if let Some(mut newer) = files.remove(path) {
+            if !newer.spilled.is_empty() && !unsent.extents.is_empty() {
+                // Newer spilled data has to land after the older data still in
+                // memory, so that goes to disk too, or if it can't the newer
+                // segments come back into memory on top of it
+                match self.spill.as_ref().and_then(|spill| spill.store_dirty(path, &unsent.extents)) {
+                    Some(segment) => {
+                        self.dirty_bytes.fetch_sub(unsent.bytes(), Ordering::SeqCst);
+                        unsent.extents.clear();
+                        unsent.spilled.push(segment);
+                    }
+                    None => {
+                        for segment in std::mem::take(&mut newer.spilled) {
+                            self.unspill(path, &mut unsent, &segment);
+                        }
+                    }
+                }
+            }
+            // Overlaying never grows the total beyond what both already counted
+            let before = unsent.bytes() + newer.bytes();
+            unsent.spilled.extend(newer.spilled);
+            unsent.journaled.extend(newer.journaled);
+            for (offset, data) in newer.extents {
+                unsent.write(offset, &data);
+            }
+            self.dirty_bytes.fetch_sub(before - unsent.bytes(), Ordering::SeqCst);
+        }
+        files.insert(path.to_string(), unsent);
+    }
+
+    // Overlays a spilled segment on the data in memory and drops it from disk
+    fn unspill(&self, path: &str, file: &mut DirtyFile, segment: &DirtySegment) {
+        let Some(spill) = &self.spill else {
+            return;
+        };
+        match spill.load_dirty(segment.seq) {
+            Some(extents) => {
+                let before = file.bytes();
+                for (offset, data) in extents {
+                    file.write(offset, &data);
+                }
+                self.dirty_bytes.fetch_add(file.bytes() - before, Ordering::SeqCst);
+            }
+            None => warn!("Dropping unreadable spilled write-back data for {}", path),
+        }
+        spill.remove_dirty(segment.seq);
+    }
+
+    fn journaled_done(&self, seqs: &[u64]) {
+        if let Some(journal) = &self.journal {
+            journal.done(seqs);
+        }
+    }
+
+    fn remove_spilled(&self, segments: &[DirtySegment]) {
+        if let Some(spill) = &self.spill {
+            for segment in segments {
+                spill.remove_dirty(segment.seq);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extents(file: &DirtyFile) -> Vec<(u64, Vec<u8>)> {
+        file.extents.iter().map(|(at, data)| (*at, data.clone())).collect()
+    }
+
+    fn spill_dir(name: &str, budget: u64) -> (std::path::PathBuf, Arc<SpillDir>) {
+        let dir = std::env::temp_dir().join(format!("fsdaemon-writeback-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let spill = SpillDir::open(dir.to_str().unwrap(), budget).unwrap();
+        (dir, Arc::new(spill))
+    }
+
+    #[test]
+    fn writes_merge_with_extents_they_overlap_or_touch() {
+        let mut file = DirtyFile::default();
+        file.write(0, b"aaaa");
+        file.write(10, b"bb");
+        assert_eq!(extents(&file), vec![(0, b"aaaa".to_vec()), (10, b"bb".to_vec())]);
+
+        // Touching the first, overlapping the second
+        file.write(4, b"cccccccd");
+        assert_eq!(extents(&file), vec![(0, b"aaaacccccccd".to_vec())]);
+        file.write(2, b"x");
+        assert_eq!(extents(&file), vec![(0, b"aaxacccccccd".to_vec())]);
+        assert_eq!((file.bytes(), file.end()), (12, Some(12)));
+    }
+
+    #[test]
+    fn buffer_counts_only_new_bytes() {
+        let writeback = WriteBack::new(None, None);
+        assert_eq!(writeback.buffer("/f", 0, b"abcd", None), 4);
+        assert_eq!(writeback.buffer("/f", 2, b"xyz", None), 5);
+        assert_eq!(writeback.buffer("/g", 0, b"12", None), 7);
+        assert_eq!(writeback.dirty_end("/f"), Some(5));
+    }
+
+    #[test]
+    fn requeued_data_goes_under_newer_writes() {
+        let writeback = WriteBack::new(None, None);
+        writeback.buffer("/f", 2, b"new", None);
+        let mut unsent = DirtyFile::default();
+        unsent.write(0, b"old-data");
+        writeback.dirty_bytes.fetch_add(unsent.bytes(), Ordering::SeqCst);
+        writeback.requeue("/f", unsent);
+
+        let files = writeback.files.locked();
+        assert_eq!(extents(&files["/f"]), vec![(0, b"olnewata".to_vec())]);
+        assert_eq!(writeback.dirty_bytes.load(Ordering::SeqCst), 8);
+    }
+
+    #[test]
+    fn requeued_data_is_spilled_ahead_of_newer_spilled_segments() {
+        let (dir, spill) = spill_dir("spilled", 1 << 20);
+        let writeback = WriteBack::new(Some(spill.clone()), None);
+        writeback.buffer("/f", 0, b"new", None);
+        assert!(writeback.spill_until_below(0));
+        let mut unsent = DirtyFile::default();
+        unsent.write(0, b"old-data");
+        writeback.dirty_bytes.fetch_add(unsent.bytes(), Ordering::SeqCst);
+        writeback.requeue("/f", unsent);
+
+        let files = writeback.files.locked();
+        let file = &files["/f"];
+        assert!(file.extents.is_empty());
+        let order: Vec<_> = file.spilled.iter().map(|segment| spill.load_dirty(segment.seq).unwrap()).collect();
+        assert_eq!(order, vec![vec![(0, b"old-data".to_vec())], vec![(0, b"new".to_vec())]]);
+        assert_eq!(writeback.dirty_bytes.load(Ordering::SeqCst), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn newer_segments_come_back_on_top_when_requeued_data_cannot_spill() {
+        // Room for the newer segment only
+        let (dir, spill) = spill_dir("full", 40);
+        let writeback = WriteBack::new(Some(spill.clone()), None);
+        writeback.buffer("/f", 0, b"new", None);
+        assert!(writeback.spill_until_below(0));
+        let mut unsent = DirtyFile::default();
+        unsent.write(0, b"old-data");
+        writeback.dirty_bytes.fetch_add(unsent.bytes(), Ordering::SeqCst);
+        writeback.requeue("/f", unsent);
+
+        let files = writeback.files.locked();
+        let file = &files["/f"];
+        assert!(file.spilled.is_empty());
+        assert_eq!(extents(file), vec![(0, b"new-data".to_vec())]);
+        assert_eq!(writeback.dirty_bytes.load(Ordering::SeqCst), 8);
+        assert!(spill.take_recovered().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,18 +1,98 @@
 import { Container } from "@cloudflare/containers";
 import { Hono } from "hono";
+import * as lz4 from "./lz4";
+import * as msgpack from "./msgpack";
 
 interface FSMessage {
   id: number;
-  operation: "read" | "write" | "stat" | "readdir" | "unlink";
+  operation:
+    | "read"
+    | "write"
+    | "write-begin"
+    | "write-chunk"
+    | "write-end"
+    | "write-abort"
+    | "stat"
+    | "readdir"
+    | "unlink"
+    | "restore"
+    | "lease"
+    | "hello"
+    | "cancel"
+    | "ping"
+    | "audit"
+    | "goodbye"
+    | "sync"
+    | "snapshots"
+    | "snapshot-read"
+    | "has-chunks"
+    | "put-chunk"
+    | "write-chunks"
+    | "chmod";
   path: string;
-  data?: number[];
+  // A byte array in legacy JSON frames, the raw payload in binary frames
+  data?: number[] | Uint8Array;
   offset?: number;
   size?: number;
+  restore?: RestoreRequest;
+  inlineLimit?: number;
+  checksum?: number;
+  lease?: LeaseKind | "release";
+  hello?: { versions: number[]; features: string[]; session?: string; channel?: number; maxFrame?: number };
+  // For read: answer with a sequence of chunk frames rather than one frame
+  stream?: boolean;
+  // For write-chunk, write-end and write-abort: the streamed write they belong to
+  transfer?: number;
+  // For mutations: the same on every retry, so they are applied once
+  idempotencyKey?: string;
+  // For cancel: the request the daemon no longer wants answered
+  cancel?: number;
+  // Codec of a compressed payload and its size before compression
+  compression?: "lz4";
+  rawSize?: number;
+  // W3C trace context of the daemon's span for the request
+  traceparent?: string;
+  // With `correlation`: the id of the FUSE operation the request is part of
+  correlation?: string;
+  // For write and write-end: the file's write sequence number. For sync: the
+  // highest acknowledged write the daemon wants vouched for.
+  seq?: number;
+  // For snapshots and snapshot-read: when the snapshot was taken, in ms
+  snapshot?: number;
+  // For has-chunks and write-chunks: the SHA-256 of each of a write's chunks, in
+  // hex and in order. For put-chunk: the hash of the chunk sent.
+  chunks?: string[];
+  // For write and chmod, with `modes`: the file's permission bits. A write
+  // without one leaves them as they are.
+  mode?: number;
+}
+
+type LeaseKind = "read" | "write";
+
+// A streamed write being received: its chunks are staged until write-end
+// stores them all at once
+interface WriteTransfer {
+  origin?: FrameWriter;
+  path: string;
+  offset: number;
+  data: Uint8Array;
+  received: number;
+}
+
+interface RestoreRequest {
+  source: "replica" | "snapshot";
+  at: number;
+}
+
+interface RestoreProgress {
+  processed: number;
+  total: number;
+  done: boolean;
 }
 
 interface FSResponse {
   id: number;
-  data?: number[];
+  data?: Uint8Array;
   bytesWritten?: number;
   files?: string[];
   stat?: {
@@ -20,11 +100,662 @@ interface FSResponse {
     isFile: boolean;
     isDir: boolean;
     mtime: number;
+    version: number;
+    // With `sparse`: the bytes stored outside the file's holes
+    allocated?: number;
+    // With `modes`: the permission bits the file was given, if any
+    mode?: number;
   };
+  inline?: Uint8Array;
+  checksum?: number;
+  // Set on every chunk of a streamed read but the last
+  more?: boolean;
+  // For write-begin: the id the rest of the streamed write refers to
+  transfer?: number;
   success?: boolean;
+  restore?: RestoreProgress;
+  // For lease: the lease granted, absent when refused
+  lease?: LeaseKind;
+  // For hello: the chosen protocol version (absent if none is shared) and the agreed features
+  hello?: { version?: number; versions: number[]; features: string[]; maxFrame?: number; instance?: string };
+  // Pushed unprompted (id 0) when a path changes through another connection
+  invalidate?: Invalidation;
+  // Pushed unprompted (id 0) to make a holder flush and release its lease
+  recall?: { path: string };
+  error?: string;
+  // Linux errno for `error`, so the daemon can hand applications the right code
+  errno?: number;
+  // With `timing`: when the request came off the wire and when it was answered, in ms on the DO's clock
+  timing?: { received: number; sent: number };
+  // With `correlation`: the request's correlation id, echoed
+  correlation?: string;
+  // For snapshots: when each snapshot was taken, or the entries of the directory listed in one
+  snapshots?: number[];
+  entries?: SnapshotEntry[];
+  // For has-chunks: the chunks asked about that aren't stored
+  chunks?: string[];
+  // For read, with `sparse`: the all-zero runs left out of `data`, as [offset, length] in the file
+  holes?: [number, number][];
+}
+
+interface SnapshotEntry {
+  name: string;
+  size: number;
+  isDir: boolean;
+}
+
+interface Invalidation {
+  path: string;
+  // "modified" for content changes; "created" and "removed" also change the parent listing
+  change: "modified" | "created" | "removed";
+}
+
+type FrameWriter = WritableStreamDefaultWriter<Uint8Array>;
+
+// First byte of a binary frame body: a u32 header length, the header, then the
+// raw file data. Legacy frames are plain JSON, which never starts with either.
+const BINARY_FRAME_TAG = 1;
+const MSGPACK_FRAME_TAG = 2;
+// First byte of a batch frame body, followed by complete length-prefixed frames
+const BATCH_FRAME_TAG = 3;
+// First byte of a fragment frame body, followed by a flag set on the last
+// fragment and a slice of the body of a frame too long to send whole
+const FRAGMENT_FRAME_TAG = 4;
+
+// Longest frame body accepted from a daemon; longer messages must be fragmented
+const MAX_FRAME_SIZE = 1024 * 1024;
+// Longest message reassembled from fragments
+const MAX_MESSAGE_SIZE = 64 * 1024 * 1024;
+
+// "legacy" is plain JSON with byte arrays; the others are binary frames with a JSON or MessagePack header
+type FrameEncoding = "legacy" | "json" | "msgpack";
+
+// Payloads from this size up are LZ4-compressed for connections that agreed to it
+const COMPRESSION_THRESHOLD = 4096;
+
+// Linux errno values sent with errors
+const ENOENT = 2;
+const EIO = 5;
+const EAGAIN = 11;
+const EINVAL = 22;
+const ENOSYS = 38;
+
+// Size of each chunk frame of a streamed read
+const STREAM_CHUNK_SIZE = 32 * 1024;
+
+// Granularity of the holes of sparse files: aligned blocks this large that
+// are all zeros
+const HOLE_BLOCK_SIZE = 4096;
+
+// The request in a frame; `error` is set when its payload can't be used
+interface DecodedFrame {
+  message: FSMessage;
+  encoding: FrameEncoding;
   error?: string;
+  // When the frame came off the wire, for `timing`
+  received?: number;
+}
+
+function decodeFrame(body: Uint8Array): DecodedFrame {
+  if (body[0] !== BINARY_FRAME_TAG && body[0] !== MSGPACK_FRAME_TAG) {
+    return { message: JSON.parse(new TextDecoder().decode(body)), encoding: "legacy" };
+  }
+  const headerLength = new DataView(body.buffer, body.byteOffset).getUint32(1, true);
+  const header = body.subarray(5, 5 + headerLength);
+  const encoding = body[0] === MSGPACK_FRAME_TAG ? "msgpack" : "json";
+  const message = (
+    encoding === "msgpack" ? msgpack.decode(header) : JSON.parse(new TextDecoder().decode(header))
+  ) as FSMessage;
+  message.data = body.subarray(5 + headerLength);
+  if (message.compression !== undefined) {
+    try {
+      if (message.compression !== "lz4") throw new Error(`unknown codec ${message.compression}`);
+      message.data = lz4.decompress(message.data, message.rawSize ?? 0);
+    } catch (error) {
+      return { message, encoding, error: `Failed to decompress payload: ${error}` };
+    }
+  }
+  return { message, encoding };
+}
+
+// Length-prefixed frame in the encoding the daemon used for the request; pushed
+// frames carry no file data and stay legacy JSON so every daemon understands them
+function encodeFrame(response: FSResponse, encoding: FrameEncoding = "legacy", compress = false): Uint8Array {
+  const { data, inline, ...rest } = response;
+  if (encoding === "legacy") {
+    const legacy = {
+      ...rest,
+      data: data && Array.from(data),
+      inline: inline && Array.from(inline)
+    };
+    const responseBytes = new TextEncoder().encode(JSON.stringify(legacy));
+    const frame = new Uint8Array(4 + responseBytes.length);
+    new DataView(frame.buffer).setUint32(0, responseBytes.length, true);
+    frame.set(responseBytes, 4);
+    return frame;
+  }
+
+  let payload = data ?? inline ?? new Uint8Array();
+  const fields: Record<string, unknown> = { ...rest, payload: inline && !data ? "inline" : "data" };
+  if (compress && payload.length >= COMPRESSION_THRESHOLD) {
+    // Only worth sending compressed when it actually shrinks
+    const packed = lz4.compress(payload);
+    if (packed.length < payload.length) {
+      fields.compression = "lz4";
+      fields.rawSize = payload.length;
+      payload = packed;
+    }
+  }
+  const header =
+    encoding === "msgpack" ? msgpack.encode(fields) : new TextEncoder().encode(JSON.stringify(fields));
+  const frame = new Uint8Array(9 + header.length + payload.length);
+  const view = new DataView(frame.buffer);
+  view.setUint32(0, 5 + header.length + payload.length, true);
+  frame[4] = encoding === "msgpack" ? MSGPACK_FRAME_TAG : BINARY_FRAME_TAG;
+  view.setUint32(5, header.length, true);
+  frame.set(header, 9);
+  frame.set(payload, 9 + header.length);
+  return frame;
+}
+
+// Bodies of the frames packed into a batch frame body
+function splitBatch(body: Uint8Array): Uint8Array[] {
+  const view = new DataView(body.buffer, body.byteOffset, body.byteLength);
+  const frames = [];
+  let at = 1;
+  while (at + 4 <= body.length) {
+    const length = view.getUint32(at, true);
+    frames.push(body.subarray(at + 4, at + 4 + length));
+    at += 4 + length;
+  }
+  return frames;
+}
+
+// A length-prefixed frame as is, or as back-to-back fragment frames when its
+// body is longer than `limit`. Written with a single write, so nothing else
+// lands between the fragments.
+function fragmentFrame(frame: Uint8Array, limit?: number): Uint8Array {
+  const body = frame.subarray(4);
+  if (limit === undefined || body.length <= limit) {
+    return frame;
+  }
+  const piece = limit - 2;
+  const count = Math.ceil(body.length / piece);
+  const out = new Uint8Array(body.length + count * 6);
+  const view = new DataView(out.buffer);
+  let o = 0;
+  for (let at = 0; at < body.length; at += piece) {
+    const slice = body.subarray(at, at + piece);
+    view.setUint32(o, slice.length + 2, true);
+    out[o + 4] = FRAGMENT_FRAME_TAG;
+    out[o + 5] = at + piece >= body.length ? 1 : 0;
+    out.set(slice, o + 6);
+    o += 6 + slice.length;
+  }
+  return out;
+}
+
+// Packs complete frames into one batch frame
+function encodeBatch(frames: Uint8Array[]): Uint8Array {
+  const length = frames.reduce((total, frame) => total + frame.length, 1);
+  const batch = new Uint8Array(4 + length);
+  new DataView(batch.buffer).setUint32(0, length, true);
+  batch[4] = BATCH_FRAME_TAG;
+  let at = 5;
+  for (const frame of frames) {
+    batch.set(frame, at);
+    at += frame.length;
+  }
+  return batch;
+}
+
+const CRC32_TABLE = (() => {
+  const table = new Uint32Array(256);
+  for (let n = 0; n < 256; n++) {
+    let c = n;
+    for (let k = 0; k < 8; k++) {
+      c = c & 1 ? 0xedb88320 ^ (c >>> 1) : c >>> 1;
+    }
+    table[n] = c >>> 0;
+  }
+  return table;
+})();
+
+// SHA-256 in hex, which chunks of deduplicated writes are stored under
+async function sha256(bytes: Uint8Array): Promise<string> {
+  const digest = new Uint8Array(await crypto.subtle.digest("SHA-256", bytes));
+  return Array.from(digest, (byte) => byte.toString(16).padStart(2, "0")).join("");
+}
+
+// The all-zero blocks of `bytes`, read from `offset` of a file, as
+// [offset, length] runs in file offsets
+function findHoles(bytes: Uint8Array, offset: number): [number, number][] {
+  const holes: [number, number][] = [];
+  let block = Math.ceil(offset / HOLE_BLOCK_SIZE) * HOLE_BLOCK_SIZE;
+  for (; block + HOLE_BLOCK_SIZE <= offset + bytes.length; block += HOLE_BLOCK_SIZE) {
+    const start = block - offset;
+    if (!bytes.subarray(start, start + HOLE_BLOCK_SIZE).every((byte) => byte === 0)) continue;
+    const last = holes[holes.length - 1];
+    if (last && last[0] + last[1] === block) {
+      last[1] += HOLE_BLOCK_SIZE;
+    } else {
+      holes.push([block, HOLE_BLOCK_SIZE]);
+    }
+  }
+  return holes;
+}
+
+// What of `bytes`, read from `offset`, is outside the holes
+function withoutHoles(bytes: Uint8Array, offset: number, holes: [number, number][]): Uint8Array {
+  const parts: Uint8Array[] = [];
+  let at = 0;
+  for (const [start, length] of holes) {
+    parts.push(bytes.subarray(at, start - offset));
+    at = start - offset + length;
+  }
+  parts.push(bytes.subarray(at));
+  return concat(parts);
+}
+
+function concat(parts: Uint8Array[]): Uint8Array {
+  const joined = new Uint8Array(parts.reduce((total, part) => total + part.length, 0));
+  let at = 0;
+  for (const part of parts) {
+    joined.set(part, at);
+    at += part.length;
+  }
+  return joined;
+}
+
+// CRC-32 (IEEE), matching the daemon's crc32fast
+function crc32(bytes: Uint8Array): number {
+  let crc = 0xffffffff;
+  for (const byte of bytes) {
+    crc = CRC32_TABLE[(crc ^ byte) & 0xff] ^ (crc >>> 8);
+  }
+  return (crc ^ 0xffffffff) >>> 0;
+}
+
+// A traceparent value: version 00, trace id, parent span id, flags
+const TRACEPARENT = /^00-([0-9a-f]{32})-([0-9a-f]{16})-([0-9a-f]{2})$/;
+
+// Logs the DO's handling of a request as a span of the daemon's trace, one JSON
+// object per line, so log pipelines can join it to the daemon's spans
+function logSpan(traceparent: string, message: FSMessage, response: FSResponse, start: number): void {
+  const parsed = TRACEPARENT.exec(traceparent);
+  // Unsampled traces are the daemon's to drop
+  if (!parsed || (parseInt(parsed[3], 16) & 1) === 0) {
+    return;
+  }
+  const spanId = Array.from(crypto.getRandomValues(new Uint8Array(8)), (byte) => byte.toString(16).padStart(2, "0"));
+  console.log(
+    JSON.stringify({
+      traceId: parsed[1],
+      parentId: parsed[2],
+      spanId: spanId.join(""),
+      name: `fs.${message.operation}`,
+      path: message.path,
+      start,
+      end: Date.now(),
+      error: response.error
+    })
+  );
+}
+
+// Optional R2 bucket used as a warm standby copy of every container filesystem
+declare global {
+  namespace Cloudflare {
+    interface Env {
+      REPLICA_BUCKET?: R2Bucket;
+    }
+  }
+}
+
+// A mutation waiting to reach the replica, kept under `replication:<seq>`
+// until it has so it survives the DO restarting. A write carries the file
+// as it was when written, so a later change or unlink doesn't alter what
+// the log records for it; its bytes are kept apart, in parts under
+// `replication-data:<seq>:<part>`, as a file may be larger than one value.
+interface ReplicationRecord {
+  seq: number;
+  operation: "write" | "unlink";
+  path: string;
+  at: number;
+  // The file's length, and its bytes once read back
+  size?: number;
+  data?: Uint8Array;
+}
+
+// Most bytes of a queued write kept under one key, well inside the 2 MB a value may be
+const REPLICATION_PART_SIZE = 1024 * 1024;
+
+// How long replication waits after a failure before trying again, if no
+// mutation has set it going sooner
+const REPLICATION_RETRY_MS = 30_000;
+
+interface ReplicationStatus {
+  enabled: boolean;
+  pending: number;
+  lagMs: number;
+  replicated: number;
+  failures: number;
+  lastReplicatedAt?: number;
+  lastCatchUpAt?: number;
+}
+
+// Mirrors acknowledged mutations to a secondary R2 backend. Current file
+// contents live under `files/<path>` and every mutation is appended under
+// `log/<at>-<seq>` so the replica can be replayed to a point in time.
+class Replicator {
+  private queue: ReplicationRecord[] = [];
+  private seq = 0;
+  private draining = false;
+  private retry?: ReturnType<typeof setTimeout>;
+  private replicated = 0;
+  private failures = 0;
+  private lastReplicatedAt?: number;
+  private lastCatchUpAt?: number;
+
+  constructor(
+    private bucket: R2Bucket,
+    private ctx: DurableObjectState,
+    private files: Map<string, Uint8Array>,
+  ) {
+    // Records that hadn't reached the replica when the DO last stopped go
+    // first, and new ones are numbered after them
+    ctx.blockConcurrencyWhile(async () => {
+      const stored = await ctx.storage.list<ReplicationRecord>({ prefix: "replication:" });
+      const parts = await ctx.storage.list<Uint8Array>({ prefix: "replication-data:" });
+      for (const record of stored.values()) {
+        if (record.operation === "write") {
+          const keys = this.partKeys(record, record.size ?? 0);
+          record.data = concat(keys.map((key) => parts.get(key) ?? new Uint8Array()));
+        }
+        this.queue.push(record);
+      }
+      this.seq = this.queue.reduce((seq, record) => Math.max(seq, record.seq), 0);
+      ctx.waitUntil(this.drain());
+    });
+  }
+
+  async enqueue(operation: ReplicationRecord["operation"], path: string) {
+    const record: ReplicationRecord = { seq: ++this.seq, operation, path, at: Date.now() };
+    if (operation === "write") {
+      const data = (this.files.get(path) ?? new Uint8Array()).slice();
+      record.size = data.length;
+      for (const [part, key] of this.partKeys(record, data.length).entries()) {
+        const start = part * REPLICATION_PART_SIZE;
+        await this.ctx.storage.put(key, data.slice(start, start + REPLICATION_PART_SIZE));
+      }
+      record.data = data;
+    }
+    await this.ctx.storage.put(this.key(record), { ...record, data: undefined });
+    this.queue.push(record);
+    this.ctx.waitUntil(this.drain());
+  }
+
+  private key(record: ReplicationRecord): string {
+    return `replication:${String(record.seq).padStart(10, "0")}`;
+  }
+
+  // Where the `size` bytes of a queued write are kept, in order
+  private partKeys(record: ReplicationRecord, size: number): string[] {
+    const seq = String(record.seq).padStart(10, "0");
+    const parts = Math.ceil(size / REPLICATION_PART_SIZE);
+    return Array.from({ length: parts }, (_, part) => `replication-data:${seq}:${String(part).padStart(6, "0")}`);
+  }
+
+  status(): ReplicationStatus {
+    const oldest = this.queue[0];
+    return {
+      enabled: true,
+      pending: this.queue.length,
+      lagMs: oldest ? Date.now() - oldest.at : 0,
+      replicated: this.replicated,
+      failures: this.failures,
+      lastReplicatedAt: this.lastReplicatedAt,
+      lastCatchUpAt: this.lastCatchUpAt,
+    };
+  }
+
+  private async drain() {
+    if (this.draining) return;
+    this.draining = true;
+    clearTimeout(this.retry);
+    this.retry = undefined;
+    try {
+      while (this.queue.length > 0) {
+        const record = this.queue[0];
+        try {
+          await this.replicate(record);
+        } catch (error) {
+          // Leave the record at the head of the queue for the next mutation or the retry
+          this.failures++;
+          console.error(`Replication of ${record.operation} ${record.path} failed:`, error);
+          this.retry = setTimeout(() => this.ctx.waitUntil(this.drain()), REPLICATION_RETRY_MS);
+          return;
+        }
+        await this.ctx.storage.delete([this.key(record), ...this.partKeys(record, record.size ?? 0)]);
+        this.queue.shift();
+        this.replicated++;
+        this.lastReplicatedAt = Date.now();
+      }
+    } finally {
+      this.draining = false;
+    }
+  }
+
+  private async replicate(record: ReplicationRecord) {
+    const logKey = `log/${String(record.at).padStart(15, "0")}-${String(record.seq).padStart(10, "0")}`;
+    const customMetadata = { operation: record.operation, path: record.path, at: String(record.at) };
+
+    if (record.operation === "write") {
+      const data = record.data ?? new Uint8Array();
+      await this.bucket.put(`files${record.path}`, data, { customMetadata });
+      await this.bucket.put(logKey, data, { customMetadata });
+    } else {
+      await this.bucket.delete(`files${record.path}`);
+      await this.bucket.put(logKey, new Uint8Array(), { customMetadata });
+    }
+  }
+
+  // Reconcile the replica with the primary, covering mutations lost while
+  // replication was unavailable (e.g. before the bucket was bound).
+  async catchUp(): Promise<{ copied: number; removed: number }> {
+    let copied = 0;
+    let removed = 0;
+    const replicaSizes = new Map<string, number>();
+
+    let cursor: string | undefined;
+    do {
+      const listing = await this.bucket.list({ prefix: "files/", cursor });
+      for (const object of listing.objects) {
+        replicaSizes.set(object.key.slice("files".length), object.size);
+      }
+      cursor = listing.truncated ? listing.cursor : undefined;
+    } while (cursor);
+
+    for (const [path, data] of this.files) {
+      if (replicaSizes.get(path) !== data.length) {
+        await this.enqueue("write", path);
+        copied++;
+      }
+      replicaSizes.delete(path);
+    }
+    for (const path of replicaSizes.keys()) {
+      await this.enqueue("unlink", path);
+      removed++;
+    }
+
+    this.lastCatchUpAt = Date.now();
+    return { copied, removed };
+  }
+
+  // Copy the current filesystem to `snapshots/<at>/<path>` in the replica
+  async snapshot(): Promise<{ at: number; files: number }> {
+    const at = Date.now();
+    const prefix = `snapshots/${String(at).padStart(15, "0")}`;
+    let files = 0;
+    for (const [path, data] of this.files) {
+      await this.bucket.put(`${prefix}${path}`, data);
+      files++;
+    }
+    return { at, files };
+  }
+
+  // Objects that rebuild the filesystem as of `at`, in the order they must be applied
+  async restorePlan(request: RestoreRequest): Promise<RestoreStep[]> {
+    if (request.source === "replica") {
+      const steps: RestoreStep[] = [];
+      for (const object of await this.listAll("log/")) {
+        const at = Number(object.customMetadata?.at);
+        if (at > request.at) break;
+        const operation = object.customMetadata?.operation === "unlink" ? "unlink" : "write";
+        steps.push({ operation, path: object.customMetadata?.path ?? "", key: object.key });
+      }
+      return steps;
+    }
+
+    // Pick the newest snapshot taken at or before the requested time
+    const snapshots = await this.bucket.list({ prefix: "snapshots/", delimiter: "/" });
+    const prefix = snapshots.delimitedPrefixes
+      .filter((p) => Number(p.slice("snapshots/".length, -1)) <= request.at)
+      .sort()
+      .pop();
+    if (!prefix) {
+      throw new Error("No snapshot at or before the requested time");
+    }
+    return (await this.listAll(prefix)).map((object) => ({
+      operation: "write" as const,
+      path: object.key.slice(prefix.length - 1),
+      key: object.key,
+    }));
+  }
+
+  async fetch(key: string): Promise<Uint8Array> {
+    const object = await this.bucket.get(key);
+    if (!object) {
+      throw new Error(`Replica object ${key} is missing`);
+    }
+    return new Uint8Array(await object.arrayBuffer());
+  }
+
+  // When each snapshot was taken, oldest first
+  async snapshots(): Promise<number[]> {
+    const snapshots: number[] = [];
+    let cursor: string | undefined;
+    do {
+      const listing = await this.bucket.list({ prefix: "snapshots/", delimiter: "/", cursor });
+      snapshots.push(...listing.delimitedPrefixes.map((p) => Number(p.slice("snapshots/".length, -1))));
+      cursor = listing.truncated ? listing.cursor : undefined;
+    } while (cursor);
+    return snapshots.sort((a, b) => a - b);
+  }
+
+  // The files and directories directly under `path` in the snapshot taken at `at`
+  async snapshotListing(at: number, path: string): Promise<SnapshotEntry[]> {
+    const prefix = `snapshots/${String(at).padStart(15, "0")}${path === "/" ? "/" : `${path}/`}`;
+    const entries: SnapshotEntry[] = [];
+    let cursor: string | undefined;
+    do {
+      const listing = await this.bucket.list({ prefix, delimiter: "/", cursor });
+      for (const object of listing.objects) {
+        entries.push({ name: object.key.slice(prefix.length), size: object.size, isDir: false });
+      }
+      for (const directory of listing.delimitedPrefixes) {
+        entries.push({ name: directory.slice(prefix.length, -1), size: 0, isDir: true });
+      }
+      cursor = listing.truncated ? listing.cursor : undefined;
+    } while (cursor);
+    return entries.sort((a, b) => (a.name < b.name ? -1 : 1));
+  }
+
+  // Up to `size` bytes from `offset` of `path` in the snapshot taken at `at`,
+  // or undefined if the snapshot doesn't have the file
+  async snapshotRead(at: number, path: string, offset: number, size: number): Promise<Uint8Array | undefined> {
+    const key = `snapshots/${String(at).padStart(15, "0")}${path}`;
+    const head = await this.bucket.head(key);
+    if (!head) return undefined;
+    // R2 refuses ranges that start past the end
+    if (offset >= head.size || size === 0) return new Uint8Array();
+    const object = await this.bucket.get(key, { range: { offset, length: Math.min(size, head.size - offset) } });
+    if (!object) return undefined;
+    return new Uint8Array(await object.arrayBuffer());
+  }
+
+  private async listAll(prefix: string): Promise<R2Object[]> {
+    const objects: R2Object[] = [];
+    let cursor: string | undefined;
+    do {
+      const listing = await this.bucket.list({ prefix, cursor, include: ["customMetadata"] });
+      objects.push(...listing.objects);
+      cursor = listing.truncated ? listing.cursor : undefined;
+    } while (cursor);
+    return objects.sort((a, b) => (a.key < b.key ? -1 : 1));
+  }
+}
+
+interface RestoreStep {
+  operation: "write" | "unlink";
+  path: string;
+  key: string;
 }
 
+// Largest file this DO will inline into a stat response, whatever the daemon asks for
+const MAX_INLINE_SIZE = 4096;
+
+// Number of restore steps applied per `restore` request, keeping each well inside the daemon timeout
+const RESTORE_BATCH_SIZE = 64;
+
+// The restore in progress, stored under `restore:plan`, with its steps under
+// `restore:<batch>` so a later batch still finds them after the DO restarts
+interface RestorePlan {
+  source: RestoreRequest["source"];
+  at: number;
+  total: number;
+}
+
+// How long a conflicting operation waits for a recalled lease before revoking it anyway
+const LEASE_RECALL_TIMEOUT_MS = 5000;
+
+// Wire protocol versions this DO speaks, and the optional features it can agree to in a hello
+const PROTOCOL_VERSIONS = [1];
+const PROTOCOL_FEATURES = [
+  "push",
+  "leases",
+  "binary-frames",
+  "msgpack",
+  "batch",
+  "pool",
+  "lz4",
+  "checksums",
+  "stream-reads",
+  "stream-writes",
+  "idempotency",
+  "cancel",
+  "heartbeat",
+  "fragments",
+  "timing",
+  "audit",
+  "trace-context",
+  "goodbye",
+  "sync",
+  "snapshots",
+  "dedup",
+  "sparse",
+  "modes",
+  "correlation",
+];
+
+// Outcomes of keyed mutations remembered for retries, oldest forgotten first
+const IDEMPOTENCY_CACHE_SIZE = 4096;
+
+// Cancelled request ids remembered per daemon until the request shows up
+const CANCELLED_LIMIT = 1024;
+
+// Connections opened to each daemon; the first carries metadata and pushes, the rest bulk transfers
+const FS_POOL_SIZE = 3;
+
 interface Connection {
   opened: Promise<any>;
   readable: ReadableStream<Uint8Array>;
@@ -32,7 +763,7 @@ interface Connection {
 }
 
 // Global map to store TCP connections by container ID
-const containerConnections = new Map<string, Connection>();
+const containerConnections = new Map<string, Connection[]>();
 
 export class MyContainer extends Container<Env> {
   // Port the container listens on
@@ -46,72 +777,761 @@ export class MyContainer extends Container<Env> {
   };
 
   public fileSystemStorage = new Map<string, Uint8Array>();
+  // Content version per path, bumped on every change so daemons can key caches by it
+  private fileVersions = new Map<string, number>();
+  private lastVersion = 0;
+  // Audit batches stored so far, keeping keys unique within a millisecond
+  private auditBatches = 0;
   private containerId?: string;
+  private replicator = this.env.REPLICA_BUCKET
+    ? new Replicator(this.env.REPLICA_BUCKET, this.ctx, this.fileSystemStorage)
+    : undefined;
+  // Connected daemons, so changes made through one can be pushed to the others
+  private fsWriters = new Set<FrameWriter>();
+  // Optional features agreed with each connection in its hello
+  private fsFeatures = new Map<FrameWriter, Set<string>>();
+  // First connection of each daemon session, and the session's other pooled
+  // connections mapped to it. Leases, pushes and invalidation origins all use
+  // the first, so a daemon is one client however many sockets it has.
+  private fsSessions = new Map<string, FrameWriter>();
+  private fsPrimary = new Map<FrameWriter, FrameWriter>();
+  // Longest frame body each connection that agreed to `fragments` accepts
+  private fsMaxFrame = new Map<FrameWriter, number>();
+  // Lease each connection holds on a path. A write lease excludes every other
+  // holder; read leases exclude only writers.
+  private leases = new Map<string, Map<FrameWriter, LeaseKind>>();
+  // Resolvers of recalls waiting on a holder's release, by path then holder
+  private pendingRecalls = new Map<string, Map<FrameWriter, () => void>>();
+  // Streamed writes between write-begin and write-end, by transfer id
+  private transfers = new Map<number, WriteTransfer>();
+  private lastTransfer = 0;
+  // Keyed mutations, running or done, so a retry gets the first attempt's outcome
+  private applied = new Map<string, Promise<FSResponse>>();
+  // Requests each daemon abandoned that haven't run or finished streaming yet
+  private cancelled = new Map<FrameWriter, Set<number>>();
+  // Highest write sequence number committed for each file, by daemon session
+  private committedSeqs = new Map<string, Map<string, number>>();
+  // Files stored as the chunks of deduplicated writes, under `manifest:<path>`
+  // rather than `fs:<path>`, and how many files use each stored chunk. Chunks
+  // uploaded for a write that never came have none and go at the next start.
+  private manifests = new Map<string, string[]>();
+  private chunkRefs = new Map<string, number>();
+  // Permission bits given to files by daemons that agreed to `modes`, kept
+  // under `mode:<path>`. Files without one are reported without a mode.
+  private fileModes = new Map<string, number>();
+
+  // Tells every daemon except the one that made the change to drop what it cached for `path`
+  private pushInvalidation(path: string, change: Invalidation["change"], origin?: FrameWriter) {
+    const frame = encodeFrame({ id: 0, invalidate: { path, change } });
+    for (const writer of this.fsWriters) {
+      if (writer !== origin && this.fsFeatures.get(writer)?.has("push")) {
+        writer.write(frame).catch(() => this.fsWriters.delete(writer));
+      }
+    }
+  }
+
+  private grantLease(path: string, holder: FrameWriter, kind: LeaseKind): LeaseKind | undefined {
+    const holders = this.leases.get(path) ?? new Map<FrameWriter, LeaseKind>();
+    const others = Array.from(holders).filter(([other]) => other !== holder);
+    const conflict = kind === "write" ? others.length > 0 : others.some(([, held]) => held === "write");
+    if (conflict) {
+      return undefined;
+    }
+    holders.set(holder, kind);
+    this.leases.set(path, holders);
+    return kind;
+  }
+
+  private releaseLease(path: string, holder: FrameWriter) {
+    const holders = this.leases.get(path);
+    holders?.delete(holder);
+    if (holders?.size === 0) {
+      this.leases.delete(path);
+    }
+    const recalls = this.pendingRecalls.get(path);
+    const resolve = recalls?.get(holder);
+    if (resolve) {
+      recalls!.delete(holder);
+      if (recalls!.size === 0) {
+        this.pendingRecalls.delete(path);
+      }
+      resolve();
+    }
+  }
+
+  // Recalls leases on `path` that conflict with an access by `origin` and waits
+  // until their holders have flushed and released them, or the recall times out
+  private async recallLeases(path: string, origin: FrameWriter | undefined, write: boolean) {
+    const holders = this.leases.get(path);
+    if (!holders) {
+      return;
+    }
+    const recalled = Array.from(holders)
+      .filter(([holder, kind]) => holder !== origin && (write || kind === "write"))
+      .map(([holder]) => holder);
+    await Promise.all(recalled.map((holder) => new Promise<void>((resolve) => {
+      const recalls = this.pendingRecalls.get(path) ?? new Map<FrameWriter, () => void>();
+      recalls.set(holder, resolve);
+      this.pendingRecalls.set(path, recalls);
+      holder.write(encodeFrame({ id: 0, recall: { path } })).catch(() => resolve());
+      setTimeout(resolve, LEASE_RECALL_TIMEOUT_MS);
+    })));
+    // Holders that didn't answer in time lose the lease regardless
+    for (const holder of recalled) {
+      this.releaseLease(path, holder);
+    }
+  }
+
+  // Records that a numbered write from a daemon's session is in storage
+  private noteCommitted(origin: FrameWriter | undefined, path: string, seq: number | undefined) {
+    const session = this.sessionOf(origin);
+    if (session === undefined || seq === undefined) return;
+    const files = this.committedSeqs.get(session) ?? new Map<string, number>();
+    files.set(path, Math.max(files.get(path) ?? 0, seq));
+    this.committedSeqs.set(session, files);
+  }
+
+  private sessionOf(origin: FrameWriter | undefined): string | undefined {
+    for (const [session, primary] of this.fsSessions) {
+      if (primary === origin) return session;
+    }
+    return undefined;
+  }
+
+  // Forgets every lease held over a connection that has gone away
+  private dropLeases(holder: FrameWriter) {
+    for (const path of Array.from(this.leases.keys())) {
+      this.releaseLease(path, holder);
+    }
+  }
 
-  async performFileSystemOperation(message: FSMessage): Promise<FSResponse> {
+  // Versions are strictly increasing and seeded from the clock, so versions
+  // handed out after a restart never collide with ones cached before it
+  private touch(path: string): number {
+    this.lastVersion = Math.max(Date.now(), this.lastVersion + 1);
+    this.fileVersions.set(path, this.lastVersion);
+    return this.lastVersion;
+  }
+
+  // Gives `path` permission bits, or forgets them
+  private async setMode(path: string, mode?: number) {
+    if (mode !== undefined) {
+      this.fileModes.set(path, mode & 0o7777);
+      await this.ctx.storage.put(`mode:${path}`, mode & 0o7777);
+    } else if (this.fileModes.delete(path)) {
+      await this.ctx.storage.delete(`mode:${path}`);
+    }
+  }
+
+  // Points `path` at the chunks it is stored as, or at none once it is stored
+  // whole, and deletes the chunks no file uses any more
+  private async setManifest(path: string, hashes?: string[]) {
+    const previous = this.manifests.get(path);
+    if (!previous && !hashes) return;
+    for (const hash of hashes ?? []) {
+      this.chunkRefs.set(hash, (this.chunkRefs.get(hash) ?? 0) + 1);
+    }
+    if (hashes) {
+      this.manifests.set(path, hashes);
+      await this.ctx.storage.put(`manifest:${path}`, hashes);
+    } else {
+      this.manifests.delete(path);
+      await this.ctx.storage.delete(`manifest:${path}`);
+    }
+    for (const hash of previous ?? []) {
+      const refs = (this.chunkRefs.get(hash) ?? 1) - 1;
+      if (refs > 0) {
+        this.chunkRefs.set(hash, refs);
+      } else {
+        this.chunkRefs.delete(hash);
+        await this.ctx.storage.delete(`chunk:${hash}`);
+      }
+    }
+  }
+
+  // Stores a write and tells the other daemons, returning the range now stored.
+  // Positional writes (including at offset 0) patch the file; no offset replaces it.
+  // A file given `manifest`, the chunks it is now made of, is kept as those.
+  private async storeWrite(
+    path: string,
+    offset: number | undefined,
+    writeData: Uint8Array,
+    origin?: FrameWriter,
+    manifest?: string[]
+  ): Promise<Uint8Array> {
+    const created = !this.fileSystemStorage.has(path);
+    if (offset !== undefined) {
+      const existing = this.fileSystemStorage.get(path) || new Uint8Array();
+      const newData = new Uint8Array(Math.max(existing.length, offset + writeData.length));
+      newData.set(existing);
+      newData.set(writeData, offset);
+      this.fileSystemStorage.set(path, newData);
+    } else {
+      this.fileSystemStorage.set(path, writeData);
+    }
+    const stored = this.fileSystemStorage.get(path)!;
+    this.touch(path);
+    if (manifest) {
+      await this.setManifest(path, manifest);
+      await this.ctx.storage.delete(`fs:${path}`);
+    } else {
+      await this.ctx.storage.put(`fs:${path}`, stored);
+      await this.setManifest(path);
+    }
+    await this.replicator?.enqueue("write", path);
+    this.pushInvalidation(path, created ? "created" : "modified", origin);
+    return stored.subarray(offset || 0, (offset || 0) + writeData.length);
+  }
+
+  replicationStatus(): ReplicationStatus {
+    return this.replicator?.status() ?? {
+      enabled: false,
+      pending: 0,
+      lagMs: 0,
+      replicated: 0,
+      failures: 0,
+    };
+  }
+
+  async replicationCatchUp(): Promise<{ copied: number; removed: number }> {
+    if (!this.replicator) {
+      throw new Error("Replication is not configured");
+    }
+    return await this.replicator.catchUp();
+  }
+
+  async replicationSnapshot(): Promise<{ at: number; files: number }> {
+    if (!this.replicator) {
+      throw new Error("Replication is not configured");
+    }
+    return await this.replicator.snapshot();
+  }
+
+  // Applies one batch of a restore starting at `cursor`. The first batch
+  // computes the plan and wipes the primary so the result matches `at` exactly.
+  // Later batches only continue that plan, and fail if there is none rather
+  // than wipe what the earlier ones restored.
+  async restoreBatch(request: RestoreRequest, cursor: number, origin?: FrameWriter): Promise<RestoreProgress> {
+    if (!this.replicator) {
+      throw new Error("Replication is not configured");
+    }
+
+    let plan: RestorePlan | undefined;
+    if (cursor === 0) {
+      for (const path of Array.from(this.leases.keys())) {
+        await this.recallLeases(path, origin, true);
+      }
+      const steps = await this.replicator.restorePlan(request);
+      await this.clearRestorePlan();
+      plan = { source: request.source, at: request.at, total: steps.length };
+      for (let batch = 0; batch * RESTORE_BATCH_SIZE < steps.length; batch++) {
+        const start = batch * RESTORE_BATCH_SIZE;
+        await this.ctx.storage.put(`restore:${batch}`, steps.slice(start, start + RESTORE_BATCH_SIZE));
+      }
+      await this.ctx.storage.put("restore:plan", plan);
+      for (const path of Array.from(this.fileSystemStorage.keys())) {
+        this.fileSystemStorage.delete(path);
+        this.fileVersions.delete(path);
+        await this.ctx.storage.delete(`fs:${path}`);
+        await this.setManifest(path);
+        await this.setMode(path);
+        await this.replicator.enqueue("unlink", path);
+        this.pushInvalidation(path, "removed", origin);
+      }
+    }
+
+    plan ??= await this.ctx.storage.get<RestorePlan>("restore:plan");
+    if (!plan || plan.source !== request.source || plan.at !== request.at || cursor % RESTORE_BATCH_SIZE !== 0) {
+      throw new Error(`No restore is in progress at ${cursor}; start again from the beginning`);
+    }
+    const batch = cursor / RESTORE_BATCH_SIZE;
+    const steps = cursor < plan.total ? await this.ctx.storage.get<RestoreStep[]>(`restore:${batch}`) : [];
+    if (!steps) {
+      throw new Error(`Restore batch ${batch} is missing; start again from the beginning`);
+    }
+    const end = Math.min(cursor + steps.length, plan.total);
+    for (const step of steps) {
+      if (step.operation === "write") {
+        const data = await this.replicator.fetch(step.key);
+        const existed = this.fileSystemStorage.has(step.path);
+        this.fileSystemStorage.set(step.path, data);
+        this.touch(step.path);
+        await this.ctx.storage.put(`fs:${step.path}`, data);
+        await this.setManifest(step.path);
+        this.pushInvalidation(step.path, existed ? "modified" : "created", origin);
+      } else {
+        this.fileSystemStorage.delete(step.path);
+        this.fileVersions.delete(step.path);
+        await this.ctx.storage.delete(`fs:${step.path}`);
+        await this.setManifest(step.path);
+        await this.setMode(step.path);
+        this.pushInvalidation(step.path, "removed", origin);
+      }
+      await this.replicator.enqueue(step.operation, step.path);
+    }
+
+    const done = end >= plan.total;
+    if (done) {
+      await this.clearRestorePlan();
+    }
+    return { processed: end, total: plan.total, done };
+  }
+
+  private async clearRestorePlan(): Promise<void> {
+    const keys = Array.from((await this.ctx.storage.list({ prefix: "restore:" })).keys());
+    // Storage deletes at most 128 keys at a time
+    for (let start = 0; start < keys.length; start += 128) {
+      await this.ctx.storage.delete(keys.slice(start, start + 128));
+    }
+  }
+
+  async performFileSystemOperation(message: FSMessage, origin?: FrameWriter): Promise<FSResponse> {
     const { id, operation, path, data, offset, size } = message;
 
+    // Other daemons' conflicting leases are recalled before the path is touched
+    if (operation === "read" || operation === "stat") {
+      await this.recallLeases(path, origin, false);
+    } else if (["write", "write-begin", "write-chunks", "unlink"].includes(operation)) {
+      await this.recallLeases(path, origin, true);
+    }
+
+    // Daemons that agreed to it get a CRC-32 of the file data in read and stat responses
+    const checksums = origin !== undefined && (this.fsFeatures.get(origin)?.has("checksums") ?? false);
+    // and reads of sparse files without their holes, which count against neither
+    const sparse = origin !== undefined && (this.fsFeatures.get(origin)?.has("sparse") ?? false);
+    // and the modes of files, which only they set
+    const modes = origin !== undefined && (this.fsFeatures.get(origin)?.has("modes") ?? false);
+
     switch (operation) {
       case "read":
         const fileData = this.fileSystemStorage.get(path);
         if (!fileData) {
-          return { id, error: "File not found" };
+          return { id, error: "File not found", errno: ENOENT };
         }
         const readData = fileData.slice(offset || 0, (offset || 0) + (size || fileData.length));
-        return { id, data: Array.from(readData) };
+        const readChecksum = checksums ? crc32(readData) : undefined;
+        // Streamed reads are cut into frames as they are, so keep their zeros
+        const readHoles = sparse && !message.stream ? findHoles(readData, offset || 0) : [];
+        if (readHoles.length === 0) {
+          return { id, data: readData, checksum: readChecksum };
+        }
+        return { id, data: withoutHoles(readData, offset || 0, readHoles), holes: readHoles, checksum: readChecksum };
 
       case "write":
         const writeData = new Uint8Array(data || []);
-        if (offset) {
-          const existing = this.fileSystemStorage.get(path) || new Uint8Array();
-          const newData = new Uint8Array(Math.max(existing.length, offset + writeData.length));
-          newData.set(existing);
-          newData.set(writeData, offset);
-          this.fileSystemStorage.set(path, newData);
-        } else {
-          this.fileSystemStorage.set(path, writeData);
-        }
-        await this.ctx.storage.put(`fs:${path}`, writeData);
-        return { id, bytesWritten: writeData.length };
+        // Refuse chunks that were damaged on the way rather than storing them
+        if (message.checksum !== undefined && crc32(writeData) !== message.checksum) {
+          return { id, error: "Checksum mismatch", errno: EIO };
+        }
+        const written = await this.storeWrite(path, offset, writeData, origin);
+        this.noteCommitted(origin, path, message.seq);
+        if (modes && message.mode !== undefined) {
+          await this.setMode(path, message.mode);
+        }
+        // Echo the checksum of what is now stored so the daemon can mark the chunk verified
+        return {
+          id,
+          bytesWritten: writeData.length,
+          checksum: message.checksum !== undefined ? crc32(written) : undefined
+        };
+
+      case "write-begin":
+        const transfer = ++this.lastTransfer;
+        this.transfers.set(transfer, { origin, path, offset: offset || 0, data: new Uint8Array(size || 0), received: 0 });
+        return { id, transfer };
+
+      case "write-chunk":
+        const staging = this.transfers.get(message.transfer ?? -1);
+        if (!staging || staging.origin !== origin) {
+          return { id, error: "Unknown write stream", errno: EINVAL };
+        }
+        const chunk = new Uint8Array(data || []);
+        const at = (offset ?? staging.offset) - staging.offset;
+        if (message.checksum !== undefined && crc32(chunk) !== message.checksum) {
+          return { id, error: "Checksum mismatch", errno: EIO };
+        }
+        if (at < 0 || at + chunk.length > staging.data.length) {
+          return { id, error: "Chunk outside the write stream", errno: EINVAL };
+        }
+        staging.data.set(chunk, at);
+        staging.received += chunk.length;
+        // Acknowledging the chunk opens the daemon's window for the next one
+        return {
+          id,
+          bytesWritten: chunk.length,
+          checksum: message.checksum !== undefined ? crc32(staging.data.subarray(at, at + chunk.length)) : undefined
+        };
+
+      case "write-end":
+      case "write-abort":
+        const finished = this.transfers.get(message.transfer ?? -1);
+        if (!finished || finished.origin !== origin) {
+          return { id, error: "Unknown write stream", errno: EINVAL };
+        }
+        this.transfers.delete(message.transfer!);
+        if (operation === "write-abort") {
+          return { id, success: true };
+        }
+        if (finished.received !== finished.data.length) {
+          return { id, error: "Incomplete write stream", errno: EIO };
+        }
+        const committed = await this.storeWrite(finished.path, finished.offset, finished.data, origin);
+        this.noteCommitted(origin, finished.path, message.seq);
+        return {
+          id,
+          bytesWritten: finished.data.length,
+          checksum: message.checksum !== undefined ? crc32(committed) : undefined
+        };
 
       case "stat":
         const statData = this.fileSystemStorage.get(path);
         if (!statData) {
-          return { id, error: "File not found" };
+          return { id, error: "File not found", errno: ENOENT };
         }
+        // Tiny files ride along with the stat so the daemon can skip the read
+        const inlineLimit = Math.min(message.inlineLimit || 0, MAX_INLINE_SIZE);
+        const version = this.fileVersions.get(path) ?? this.touch(path);
+        const inline = statData.length <= inlineLimit ? statData : undefined;
+        const allocated = sparse
+          ? findHoles(statData, 0).reduce((stored, [, length]) => stored - length, statData.length)
+          : undefined;
         return {
           id,
           stat: {
             size: statData.length,
             isFile: true,
             isDir: false,
-            mtime: Date.now()
-          }
+            mtime: version,
+            version,
+            allocated,
+            mode: modes ? this.fileModes.get(path) : undefined
+          },
+          inline,
+          checksum: inline && checksums ? crc32(inline) : undefined
         };
 
       case "readdir":
         const files = Array.from(this.fileSystemStorage.keys())
           .filter((key: string) => key.startsWith(path === "/" ? "" : path))
           .map((key: string) => key.slice(path.length).split("/")[0])
-          .filter((name, index, arr) => arr.indexOf(name) === index && name);
-        return { id, files };
+          .filter((name, index, arr) => arr.indexOf(name) === index && name)
+          .sort();
+        // Paged listing: `offset` entries are skipped and at most `size` returned
+        const start = offset || 0;
+        return { id, files: size ? files.slice(start, start + size) : files.slice(start) };
 
       case "unlink":
         const existed = this.fileSystemStorage.has(path);
         this.fileSystemStorage.delete(path);
+        this.fileVersions.delete(path);
         await this.ctx.storage.delete(`fs:${path}`);
+        await this.setManifest(path);
+        await this.setMode(path);
+        if (existed) {
+          await this.replicator?.enqueue("unlink", path);
+          this.pushInvalidation(path, "removed", origin);
+        }
         return { id, success: existed };
 
+      case "chmod":
+        if (!modes || message.mode === undefined) {
+          return { id, error: "Unknown operation", errno: ENOSYS };
+        }
+        if (!this.fileSystemStorage.has(path)) {
+          return { id, error: "File not found", errno: ENOENT };
+        }
+        await this.setMode(path, message.mode);
+        // Others drop the attributes they cached; the contents and version are as they were
+        this.pushInvalidation(path, "modified", origin);
+        return { id, success: true };
+
+      case "has-chunks":
+        return { id, chunks: (message.chunks ?? []).filter((hash) => !this.chunkRefs.has(hash)) };
+
+      case "put-chunk":
+        const chunkData = new Uint8Array(data || []);
+        if (message.checksum !== undefined && crc32(chunkData) !== message.checksum) {
+          return { id, error: "Checksum mismatch", errno: EIO };
+        }
+        const hash = await sha256(chunkData);
+        if (hash !== message.chunks?.[0]) {
+          return { id, error: "Chunk doesn't match its hash", errno: EIO };
+        }
+        if (!this.chunkRefs.has(hash)) {
+          await this.ctx.storage.put(`chunk:${hash}`, chunkData);
+          this.chunkRefs.set(hash, 0);
+        }
+        return {
+          id,
+          bytesWritten: chunkData.length,
+          checksum: message.checksum !== undefined ? crc32(chunkData) : undefined
+        };
+
+      case "write-chunks":
+        const hashes = message.chunks ?? [];
+        const parts: Uint8Array[] = [];
+        for (const hash of hashes) {
+          const part = this.chunkRefs.has(hash) ? await this.ctx.storage.get<Uint8Array>(`chunk:${hash}`) : undefined;
+          // Deleted since has-chunks said it was here; the daemon sends the data instead
+          if (!part) {
+            return { id, error: `Missing chunk ${hash}`, errno: ENOENT };
+          }
+          parts.push(part);
+        }
+        const assembled = concat(parts);
+        if (message.checksum !== undefined && crc32(assembled) !== message.checksum) {
+          return { id, error: "Checksum mismatch", errno: EIO };
+        }
+        // The file stays a list of chunks while it is written whole or
+        // appended to; a write into the middle stores it whole again
+        const length = this.fileSystemStorage.get(path)?.length ?? 0;
+        const manifest = this.manifests.get(path);
+        const chunked = (offset || 0) === 0 && assembled.length >= length
+          ? hashes
+          : manifest && offset === length ? [...manifest, ...hashes] : undefined;
+        const assembledRange = await this.storeWrite(path, offset || 0, assembled, origin, chunked);
+        this.noteCommitted(origin, path, message.seq);
+        return {
+          id,
+          bytesWritten: assembled.length,
+          checksum: message.checksum !== undefined ? crc32(assembledRange) : undefined
+        };
+
+      case "audit":
+        // Mirrored audit records, one JSON object per line, stored under keys that sort by arrival
+        const records = new TextDecoder().decode(new Uint8Array(data || []));
+        const batch = String(++this.auditBatches).padStart(12, "0");
+        await this.ctx.storage.put(`audit:${String(Date.now()).padStart(15, "0")}:${batch}`, records);
+        return { id, success: true };
+
+      case "lease":
+        if (!origin || !message.lease) {
+          return { id, error: "Missing lease mode", errno: EINVAL };
+        }
+        if (message.lease === "release") {
+          this.releaseLease(path, origin);
+          return { id, success: true };
+        }
+        // Recalls are pushed, so leases only go to connections that agreed to both
+        if (!this.fsFeatures.get(origin)?.has("leases")) {
+          return { id };
+        }
+        return { id, lease: this.grantLease(path, origin, message.lease) };
+
+      case "sync":
+        // Writes are acknowledged once their put resolves, so by now every
+        // acknowledged write has been handed to storage; this waits for it to
+        // be on disk and checks none the daemon counts on went missing
+        await this.ctx.storage.sync();
+        const committedSeq = this.committedSeqs.get(this.sessionOf(origin) ?? "")?.get(path);
+        if (committedSeq !== undefined && committedSeq < (message.seq ?? 0)) {
+          return { id, error: `Writes after ${committedSeq} were not committed`, errno: EIO };
+        }
+        return { id, success: true };
+
+      case "goodbye":
+        // A daemon shutting down cleanly has flushed, so its leases can go before its connections do
+        if (origin) {
+          this.dropLeases(origin);
+        }
+        return { id, success: true };
+
+      case "snapshots":
+        if (!this.replicator) {
+          return { id, error: "Replication is not configured", errno: ENOSYS };
+        }
+        if (message.snapshot === undefined) {
+          return { id, snapshots: await this.replicator.snapshots() };
+        }
+        return { id, entries: await this.replicator.snapshotListing(message.snapshot, path) };
+
+      case "snapshot-read":
+        if (!this.replicator) {
+          return { id, error: "Replication is not configured", errno: ENOSYS };
+        }
+        if (message.snapshot === undefined) {
+          return { id, error: "Missing snapshot", errno: EINVAL };
+        }
+        const snapshotData = await this.replicator.snapshotRead(message.snapshot, path, offset || 0, size || 0);
+        if (!snapshotData) {
+          return { id, error: "File not found", errno: ENOENT };
+        }
+        return { id, data: snapshotData, checksum: checksums ? crc32(snapshotData) : undefined };
+
+      case "restore":
+        if (!message.restore) {
+          return { id, error: "Missing restore parameters", errno: EINVAL };
+        }
+        try {
+          return { id, restore: await this.restoreBatch(message.restore, offset || 0, origin) };
+        } catch (error) {
+          return { id, error: String(error), errno: EIO };
+        }
+
+      case "hello":
+        if (!origin || !message.hello) {
+          return { id, error: "Missing hello", errno: EINVAL };
+        }
+        const shared = message.hello.versions.filter((version) => PROTOCOL_VERSIONS.includes(version));
+        // Snapshots live in the replica, so there are none to show without one
+        const features = message.hello.features.filter(
+          (feature) => PROTOCOL_FEATURES.includes(feature) && (feature !== "snapshots" || this.replicator !== undefined)
+        );
+        const agreed = new Set(features.filter((feature) => feature !== "leases" || features.includes("push")));
+        this.fsFeatures.set(origin, agreed);
+        if (agreed.has("fragments") && message.hello.maxFrame) {
+          this.fsMaxFrame.set(origin, message.hello.maxFrame);
+        }
+        const { session, channel } = message.hello;
+        const primary = session !== undefined ? this.fsSessions.get(session) : undefined;
+        if (session !== undefined && !channel) {
+          // A daemon replacing its first connection keeps its other pooled ones
+          for (const [secondary, previous] of this.fsPrimary) {
+            if (previous === primary) this.fsPrimary.set(secondary, origin);
+          }
+          this.fsSessions.set(session, origin);
+        } else if (primary && agreed.has("pool")) {
+          this.fsPrimary.set(origin, primary);
+          this.fsWriters.delete(origin);
+        }
+        return {
+          id,
+          hello: {
+            version: shared.length > 0 ? Math.max(...shared) : undefined,
+            versions: PROTOCOL_VERSIONS,
+            features: Array.from(agreed),
+            maxFrame: agreed.has("fragments") ? MAX_FRAME_SIZE : undefined,
+            // Lets a daemon serving several DOs tell their connections apart
+            instance: this.ctx.id.toString()
+          }
+        };
+
       default:
-        return { id, error: "Unknown operation" };
+        return { id, error: "Unknown operation", errno: ENOSYS };
+    }
+  }
+
+  // Runs a mutation carrying an idempotency key at most once: a retry gets the
+  // original outcome, waiting for it if the first attempt is still running.
+  // Failures are forgotten so that a retry runs again.
+  private async performOnce(message: FSMessage, origin: FrameWriter): Promise<FSResponse> {
+    const key = message.idempotencyKey;
+    if (key === undefined || !this.fsFeatures.get(origin)?.has("idempotency")) {
+      return this.performCaught(message, origin);
+    }
+    let outcome = this.applied.get(key);
+    if (!outcome) {
+      outcome = this.performCaught(message, origin);
+      this.applied.set(key, outcome);
+      if (this.applied.size > IDEMPOTENCY_CACHE_SIZE) {
+        this.applied.delete(this.applied.keys().next().value!);
+      }
+    }
+    const response = await outcome;
+    if (response.error) {
+      this.applied.delete(key);
+    }
+    return { ...response, id: message.id };
+  }
+
+  // Runs an operation, answering one that threw, say because storage failed,
+  // with EAGAIN so the daemon knows trying again may work
+  private performCaught(message: FSMessage, origin: FrameWriter): Promise<FSResponse> {
+    return this.performFileSystemOperation(message, origin).catch(
+      (error): FSResponse => ({ id: message.id, error: String(error), errno: EAGAIN })
+    );
+  }
+
+  // Handles control frames as soon as they arrive, ahead of the requests
+  // queued before them, and returns whether the frame was one. A ping is
+  // answered at once so a slow request doesn't make the connection look dead;
+  // a cancel is recorded for the request it names.
+  private takeControl(frame: DecodedFrame, writer: FrameWriter): boolean {
+    const { message } = frame;
+    if (!frame.error && message.operation === "ping") {
+      writer.write(encodeFrame({ id: message.id }, frame.encoding)).catch(() => {});
+      return true;
+    }
+    if (frame.error || message.operation !== "cancel" || message.cancel === undefined) {
+      return false;
+    }
+    const origin = this.fsPrimary.get(writer) ?? writer;
+    const cancelled = this.cancelled.get(origin) ?? new Set<number>();
+    cancelled.add(message.cancel);
+    if (cancelled.size > CANCELLED_LIMIT) {
+      cancelled.delete(cancelled.values().next().value!);
+    }
+    this.cancelled.set(origin, cancelled);
+    return true;
+  }
+
+  // Whether the daemon abandoned a request, forgetting the cancel once seen
+  private wasCancelled(origin: FrameWriter, id: number): boolean {
+    return this.cancelled.get(origin)?.delete(id) ?? false;
+  }
+
+  // Processes one request and returns the length-prefixed response, framed the
+  // way the request was, or nothing for a request the daemon cancelled. A
+  // streamed read writes its leading chunks straight to the connection and
+  // returns the last one.
+  private async handleFrame(frame: DecodedFrame, writer: FrameWriter): Promise<Uint8Array | undefined> {
+    const { message, encoding, error } = frame;
+    const features = this.fsFeatures.get(writer);
+    const compress = features?.has("lz4") ?? false;
+    const maxFrame = this.fsMaxFrame.get(writer);
+    if (error) {
+      return encodeFrame({ id: message.id, error, errno: EIO }, encoding, compress);
+    }
+    const origin = this.fsPrimary.get(writer) ?? writer;
+    if (this.wasCancelled(origin, message.id)) {
+      return undefined;
+    }
+    const response = await this.performOnce(message, origin);
+    // Stamped on the final frame only, so a streamed read's covers all of it
+    const received = frame.received ?? Date.now();
+    if (message.traceparent !== undefined && features?.has("trace-context")) {
+      logSpan(message.traceparent, message, response, received);
     }
+    // Failures are logged under the daemon's id for the operation, and the id
+    // goes back with the answer, so both sides' logs of it line up
+    const correlation = features?.has("correlation") ? message.correlation : undefined;
+    if (correlation !== undefined && response.error) {
+      console.error(`[${correlation}] ${message.operation} ${message.path} failed:`, response.error);
+    }
+    const timed = (last: FSResponse): FSResponse => {
+      const tagged = correlation !== undefined ? { ...last, correlation } : last;
+      return features?.has("timing") ? { ...tagged, timing: { received, sent: Date.now() } } : tagged;
+    };
+    const data = response.data;
+    if (!message.stream || !features?.has("stream-reads") || !data || data.length <= STREAM_CHUNK_SIZE) {
+      return encodeFrame(timed(response), encoding, compress);
+    }
+    const chunkOf = (at: number): FSResponse => {
+      const chunk = data.subarray(at, at + STREAM_CHUNK_SIZE);
+      return { id: response.id, data: chunk, checksum: response.checksum !== undefined ? crc32(chunk) : undefined };
+    };
+    let at = 0;
+    for (; at + STREAM_CHUNK_SIZE < data.length; at += STREAM_CHUNK_SIZE) {
+      if (this.wasCancelled(origin, message.id)) {
+        return undefined;
+      }
+      await writer.write(fragmentFrame(encodeFrame({ ...chunkOf(at), more: true }, encoding, compress), maxFrame));
+    }
+    return encodeFrame(timed(chunkOf(at)), encoding, compress);
   }
 
   async handleFilesystemConnection(conn: Connection): Promise<void> {
     const reader = conn.readable.getReader();
     const writer = conn.writable.getWriter();
+    this.fsWriters.add(writer);
 
     let buffer = new Uint8Array();
+    // Fragments of a message still being received, and their total size
+    let fragments: Uint8Array[] = [];
+    let fragmentBytes = 0;
+    // Requests run one after another in arrival order while reading carries
+    // on, so pings and cancels can overtake the requests queued ahead of them
+    let queue = Promise.resolve();
 
     try {
       while (true) {
@@ -127,32 +1547,79 @@ export class MyContainer extends Container<Env> {
         // Try to parse complete messages (length-prefixed)
         while (buffer.length >= 4) {
           const messageLength = new DataView(buffer.buffer).getUint32(0, true);
+          // A corrupt or hostile length would have us buffer without end
+          if (messageLength > MAX_FRAME_SIZE) {
+            throw new Error(`frame of ${messageLength} bytes is over the ${MAX_FRAME_SIZE} byte limit`);
+          }
           if (buffer.length >= 4 + messageLength) {
-            const messageBytes = buffer.slice(4, 4 + messageLength);
-            const message = JSON.parse(new TextDecoder().decode(messageBytes)) as FSMessage;
-
-            // Process the filesystem operation
-            const response = await this.performFileSystemOperation(message);
-            const responseBytes = new TextEncoder().encode(JSON.stringify(response));
-
-            // Send length-prefixed response
-            const responseBuffer = new ArrayBuffer(4 + responseBytes.length);
-            const view = new DataView(responseBuffer);
-            view.setUint32(0, responseBytes.length, true);
-            new Uint8Array(responseBuffer, 4).set(responseBytes);
-
-            await writer.write(new Uint8Array(responseBuffer));
-
+            let messageBytes = buffer.slice(4, 4 + messageLength);
             // Remove processed message from buffer
             buffer = buffer.slice(4 + messageLength);
+
+            if (messageBytes[0] === FRAGMENT_FRAME_TAG) {
+              fragments.push(messageBytes.subarray(2));
+              fragmentBytes += messageBytes.length - 2;
+              if (fragmentBytes > MAX_MESSAGE_SIZE) {
+                throw new Error(`fragmented message is over the ${MAX_MESSAGE_SIZE} byte limit`);
+              }
+              if (messageBytes[1] !== 1) continue;
+              messageBytes = new Uint8Array(fragmentBytes);
+              let at = 0;
+              for (const fragment of fragments) {
+                messageBytes.set(fragment, at);
+                at += fragment.length;
+              }
+              fragments = [];
+              fragmentBytes = 0;
+            }
+
+            if (messageBytes[0] === BATCH_FRAME_TAG) {
+              // Batched requests run in order and are answered with one batch of responses
+              const received = Date.now();
+              const frames = splitBatch(messageBytes)
+                .map((body) => ({ ...decodeFrame(body), received }))
+                .filter((frame) => !this.takeControl(frame, writer));
+              queue = queue.then(async () => {
+                const responses = [];
+                for (const frame of frames) {
+                  const response = await this.handleFrame(frame, writer);
+                  if (response) responses.push(response);
+                }
+                if (responses.length > 0) await writer.write(fragmentFrame(encodeBatch(responses), this.fsMaxFrame.get(writer)));
+              });
+            } else {
+              const frame = { ...decodeFrame(messageBytes), received: Date.now() };
+              if (!this.takeControl(frame, writer)) {
+                queue = queue.then(async () => {
+                  const response = await this.handleFrame(frame, writer);
+                  if (response) await writer.write(fragmentFrame(response, this.fsMaxFrame.get(writer)));
+                });
+              }
+            }
           } else {
             break; // Wait for more data
           }
         }
       }
+      await queue;
     } catch (error) {
       console.error("Filesystem stream error:", error);
     } finally {
+      this.fsWriters.delete(writer);
+      this.fsFeatures.delete(writer);
+      this.fsPrimary.delete(writer);
+      this.fsMaxFrame.delete(writer);
+      for (const [session, primary] of this.fsSessions) {
+        if (primary === writer) {
+          this.fsSessions.delete(session);
+          this.committedSeqs.delete(session);
+        }
+      }
+      this.dropLeases(writer);
+      for (const [transfer, staging] of this.transfers) {
+        if (staging.origin === writer) this.transfers.delete(transfer);
+      }
+      this.cancelled.delete(writer);
       reader.releaseLock();
       writer.releaseLock();
     }
@@ -169,13 +1636,46 @@ export class MyContainer extends Container<Env> {
       const path = key.slice(3); // Remove "fs:" prefix
       this.fileSystemStorage.set(path, value as Uint8Array);
     }
-    
+    // Files kept as the chunks of deduplicated writes are put back together
+    const chunks = await this.ctx.storage.list<Uint8Array>({ prefix: "chunk:" });
+    for (const key of chunks.keys()) {
+      this.chunkRefs.set(key.slice("chunk:".length), 0);
+    }
+    const manifests = await this.ctx.storage.list<string[]>({ prefix: "manifest:" });
+    for (const [key, hashes] of manifests) {
+      const path = key.slice("manifest:".length);
+      const parts = hashes.map((hash) => chunks.get(`chunk:${hash}`) ?? new Uint8Array());
+      this.fileSystemStorage.set(path, concat(parts));
+      this.manifests.set(path, hashes);
+      for (const hash of hashes) {
+        this.chunkRefs.set(hash, (this.chunkRefs.get(hash) ?? 0) + 1);
+      }
+    }
+    const modes = await this.ctx.storage.list<number>({ prefix: "mode:" });
+    for (const [key, mode] of modes) {
+      this.fileModes.set(key.slice("mode:".length), mode);
+    }
+    // Chunks uploaded for writes that never came
+    for (const [hash, refs] of Array.from(this.chunkRefs)) {
+      if (refs === 0) {
+        this.chunkRefs.delete(hash);
+        await this.ctx.storage.delete(`chunk:${hash}`);
+      }
+    }
+
+    // Bring the standby copy up to date with anything missed while we were down
+    if (this.replicator) {
+      this.ctx.waitUntil(this.replicator.catchUp());
+    }
+
     // Check for TCP connections for all possible container IDs
     // Try to find a connection that matches this DO instance
-    for (const [id, connection] of containerConnections.entries()) {
-      // Start handling the connection for this DO instance
-      console.log(`Starting filesystem connection handler for container ${id}`);
-      this.handleFilesystemConnection(connection);
+    for (const [id, connections] of containerConnections.entries()) {
+      // Start handling the connections for this DO instance
+      console.log(`Starting ${connections.length} filesystem connection handlers for container ${id}`);
+      for (const connection of connections) {
+        this.handleFilesystemConnection(connection);
+      }
       // Remove from map once handled
       containerConnections.delete(id);
       break; // Only handle one connection per DO instance
@@ -202,24 +1702,57 @@ const app = new Hono<{
 app.get("/", (c) => {
   return c.text(
     "Available endpoints:\n" +
-    "GET /container/<ID> - Start a container for each ID with a 2m timeout\n"
+    "GET /container/<ID> - Start a container for each ID with a 2m timeout\n" +
+    "GET /container/<ID>/replication - Replication lag and counters for a container\n" +
+    "POST /container/<ID>/replication/catch-up - Reconcile the replica with the primary\n" +
+    "POST /container/<ID>/replication/snapshot - Take a point-in-time snapshot in the replica\n"
   );
 });
 
+app.get("/container/:id/replication", async (c) => {
+  const id = c.req.param("id");
+  const container = c.env.MY_CONTAINER.get(c.env.MY_CONTAINER.idFromName(`/container/${id}`));
+  return c.json(await container.replicationStatus());
+});
+
+app.post("/container/:id/replication/catch-up", async (c) => {
+  const id = c.req.param("id");
+  const container = c.env.MY_CONTAINER.get(c.env.MY_CONTAINER.idFromName(`/container/${id}`));
+  try {
+    return c.json(await container.replicationCatchUp());
+  } catch (error) {
+    return c.json({ error: String(error) }, 400);
+  }
+});
+
+app.post("/container/:id/replication/snapshot", async (c) => {
+  const id = c.req.param("id");
+  const container = c.env.MY_CONTAINER.get(c.env.MY_CONTAINER.idFromName(`/container/${id}`));
+  try {
+    return c.json(await container.replicationSnapshot());
+  } catch (error) {
+    return c.json({ error: String(error) }, 400);
+  }
+});
+
 // Route requests to a specific container using the container ID
 app.get("/container/:id", async (c) => {
   const id = c.req.param("id");
   const containerId = c.env.MY_CONTAINER.idFromName(`/container/${id}`);
   const container = c.env.MY_CONTAINER.get(containerId);
 
-  // Initialize filesystem connection for this container instance
+  // Initialize filesystem connections for this container instance
   try {
-    const conn = container.connect('10.0.0.1:8000') as Connection;
-    await conn.opened;
-    console.log(`Filesystem connection established for container ${id}`);
+    const connections: Connection[] = [];
+    for (let i = 0; i < FS_POOL_SIZE; i++) {
+      const conn = container.connect('10.0.0.1:8000') as Connection;
+      await conn.opened;
+      connections.push(conn);
+    }
+    console.log(`Filesystem connections established for container ${id}`);
     
-    // Store connection for the DO to pick up
-    containerConnections.set(id, conn);
+    // Store connections for the DO to pick up
+    containerConnections.set(id, connections);
   } catch (error) {
     console.error(`Failed to connect to filesystem for container ${id}:`, error);
   }
@@ -0,0 +1,107 @@
+// LZ4 block format (no frame header), interoperable with lz4_flex::block on
+// the daemon side. The compressor is a simple greedy matcher: it trades some
+// ratio for speed, which is what payload compression on the wire wants.
+
+// Bytes at the end of a block that are always literals, and the latest a match may start
+const LAST_LITERALS = 5;
+const MATCH_FIND_LIMIT = 12;
+const MIN_MATCH = 4;
+const MAX_OFFSET = 65535;
+const HASH_BITS = 16;
+
+function read32(data: Uint8Array, at: number): number {
+  return (data[at] | (data[at + 1] << 8) | (data[at + 2] << 16) | (data[at + 3] << 24)) >>> 0;
+}
+
+export function compress(input: Uint8Array): Uint8Array {
+  // Worst case: everything is literals, plus one length byte per 255 of them
+  const out = new Uint8Array(input.length + Math.ceil(input.length / 255) + 16);
+  const table = new Int32Array(1 << HASH_BITS).fill(-1);
+  let o = 0;
+  let anchor = 0;
+  let i = 0;
+
+  const writeLength = (length: number) => {
+    for (; length >= 255; length -= 255) out[o++] = 255;
+    out[o++] = length;
+  };
+  const writeSequence = (literalEnd: number, offset: number, matchLength: number) => {
+    const literals = literalEnd - anchor;
+    const token = o++;
+    out[token] = Math.min(literals, 15) << 4;
+    if (literals >= 15) writeLength(literals - 15);
+    out.set(input.subarray(anchor, literalEnd), o);
+    o += literals;
+    if (matchLength > 0) {
+      out[o++] = offset & 0xff;
+      out[o++] = offset >>> 8;
+      const extra = matchLength - MIN_MATCH;
+      out[token] |= Math.min(extra, 15);
+      if (extra >= 15) writeLength(extra - 15);
+    }
+  };
+
+  while (i < input.length - MATCH_FIND_LIMIT) {
+    const sequence = read32(input, i);
+    const hash = Math.imul(sequence, 2654435761) >>> (32 - HASH_BITS);
+    const candidate = table[hash];
+    table[hash] = i;
+    if (candidate < 0 || i - candidate > MAX_OFFSET || read32(input, candidate) !== sequence) {
+      i++;
+      continue;
+    }
+    let length = MIN_MATCH;
+    while (i + length < input.length - LAST_LITERALS && input[candidate + length] === input[i + length]) {
+      length++;
+    }
+    writeSequence(i, i - candidate, length);
+    i += length;
+    anchor = i;
+  }
+  writeSequence(input.length, 0, 0);
+  return out.subarray(0, o);
+}
+
+export function decompress(input: Uint8Array, rawSize: number): Uint8Array {
+  const out = new Uint8Array(rawSize);
+  let i = 0;
+  let o = 0;
+
+  const readLength = (length: number) => {
+    if (length !== 15) return length;
+    let byte;
+    do {
+      byte = input[i++];
+      length += byte;
+    } while (byte === 255 && i < input.length);
+    return length;
+  };
+
+  while (i < input.length) {
+    const token = input[i++];
+    const literals = readLength(token >>> 4);
+    if (o + literals > rawSize || i + literals > input.length) {
+      throw new Error("Corrupt LZ4 block");
+    }
+    out.set(input.subarray(i, i + literals), o);
+    i += literals;
+    o += literals;
+    // The last sequence has literals only
+    if (i >= input.length) break;
+
+    const offset = input[i] | (input[i + 1] << 8);
+    i += 2;
+    const length = readLength(token & 0x0f) + MIN_MATCH;
+    if (offset === 0 || offset > o || o + length > rawSize) {
+      throw new Error("Corrupt LZ4 block");
+    }
+    // Byte by byte, since a match may overlap the bytes it produces
+    for (let k = 0; k < length; k++, o++) {
+      out[o] = out[o - offset];
+    }
+  }
+  if (o !== rawSize) {
+    throw new Error("LZ4 block size mismatch");
+  }
+  return out;
+}
@@ -0,0 +1,165 @@
+// Minimal MessagePack codec for protocol headers: nil, booleans, numbers,
+// strings, binary, arrays and string-keyed maps. Undefined map values are
+// skipped, as JSON.stringify does.
+
+type Packable = null | undefined | boolean | number | string | Uint8Array | Packable[] | { [key: string]: Packable };
+
+export function encode(value: unknown): Uint8Array {
+  const out: number[] = [];
+  write(out, value as Packable);
+  return new Uint8Array(out);
+}
+
+export function decode(bytes: Uint8Array): unknown {
+  const reader = { view: new DataView(bytes.buffer, bytes.byteOffset, bytes.byteLength), pos: 0, bytes };
+  const value = read(reader);
+  if (reader.pos !== bytes.length) {
+    throw new Error("Trailing bytes after MessagePack value");
+  }
+  return value;
+}
+
+function pushUint(out: number[], value: number, bytes: number) {
+  for (let shift = (bytes - 1) * 8; shift >= 0; shift -= 8) {
+    out.push(Math.floor(value / 2 ** shift) & 0xff);
+  }
+}
+
+// Writes a length header: the fix form when there is one and the length fits,
+// otherwise the smallest of the 8, 16 and 32-bit forms the type has
+function writeLength(out: number[], length: number, fix: { code: number; limit: number } | null, codes: (number | null)[]) {
+  if (fix && length < fix.limit) {
+    out.push(fix.code | length);
+  } else if (codes[0] !== null && length < 0x100) {
+    out.push(codes[0], length);
+  } else if (length < 0x10000) {
+    out.push(codes[1]!);
+    pushUint(out, length, 2);
+  } else {
+    out.push(codes[2]!);
+    pushUint(out, length, 4);
+  }
+}
+
+function write(out: number[], value: Packable) {
+  if (value === null || value === undefined) {
+    out.push(0xc0);
+  } else if (typeof value === "boolean") {
+    out.push(value ? 0xc3 : 0xc2);
+  } else if (typeof value === "number") {
+    writeNumber(out, value);
+  } else if (typeof value === "string") {
+    const utf8 = new TextEncoder().encode(value);
+    writeLength(out, utf8.length, { code: 0xa0, limit: 32 }, [0xd9, 0xda, 0xdb]);
+    for (const byte of utf8) out.push(byte);
+  } else if (value instanceof Uint8Array) {
+    writeLength(out, value.length, null, [0xc4, 0xc5, 0xc6]);
+    for (const byte of value) out.push(byte);
+  } else if (Array.isArray(value)) {
+    writeLength(out, value.length, { code: 0x90, limit: 16 }, [null, 0xdc, 0xdd]);
+    for (const item of value) write(out, item);
+  } else {
+    const entries = Object.entries(value).filter(([, item]) => item !== undefined);
+    writeLength(out, entries.length, { code: 0x80, limit: 16 }, [null, 0xde, 0xdf]);
+    for (const [key, item] of entries) {
+      write(out, key);
+      write(out, item);
+    }
+  }
+}
+
+function writeNumber(out: number[], value: number) {
+  if (!Number.isSafeInteger(value)) {
+    const view = new DataView(new ArrayBuffer(8));
+    view.setFloat64(0, value);
+    out.push(0xcb, ...new Uint8Array(view.buffer));
+  } else if (value >= 0 && value < 0x80) {
+    out.push(value);
+  } else if (value >= 0) {
+    const bytes = value < 0x100 ? 1 : value < 0x10000 ? 2 : value < 0x100000000 ? 4 : 8;
+    out.push({ 1: 0xcc, 2: 0xcd, 4: 0xce, 8: 0xcf }[bytes]!);
+    pushUint(out, value, bytes);
+  } else if (value >= -32) {
+    out.push(value & 0xff);
+  } else {
+    const view = new DataView(new ArrayBuffer(8));
+    view.setBigInt64(0, BigInt(value));
+    out.push(0xd3, ...new Uint8Array(view.buffer));
+  }
+}
+
+interface Reader {
+  view: DataView;
+  pos: number;
+  bytes: Uint8Array;
+}
+
+function take(reader: Reader, length: number): number {
+  const at = reader.pos;
+  if (at + length > reader.bytes.length) {
+    throw new Error("Truncated MessagePack value");
+  }
+  reader.pos += length;
+  return at;
+}
+
+function readBytes(reader: Reader, length: number): Uint8Array {
+  const at = take(reader, length);
+  return reader.bytes.subarray(at, at + length);
+}
+
+function readString(reader: Reader, length: number): string {
+  return new TextDecoder().decode(readBytes(reader, length));
+}
+
+function readArray(reader: Reader, length: number): unknown[] {
+  const items = [];
+  for (let i = 0; i < length; i++) items.push(read(reader));
+  return items;
+}
+
+function readMap(reader: Reader, length: number): Record<string, unknown> {
+  const map: Record<string, unknown> = {};
+  for (let i = 0; i < length; i++) {
+    const key = read(reader);
+    map[String(key)] = read(reader);
+  }
+  return map;
+}
+
+function read(reader: Reader): unknown {
+  const { view } = reader;
+  const code = view.getUint8(take(reader, 1));
+  if (code < 0x80) return code;
+  if (code >= 0xe0) return code - 0x100;
+  if ((code & 0xf0) === 0x80) return readMap(reader, code & 0x0f);
+  if ((code & 0xf0) === 0x90) return readArray(reader, code & 0x0f);
+  if ((code & 0xe0) === 0xa0) return readString(reader, code & 0x1f);
+
+  switch (code) {
+    case 0xc0: return null;
+    case 0xc2: return false;
+    case 0xc3: return true;
+    case 0xc4: return readBytes(reader, view.getUint8(take(reader, 1)));
+    case 0xc5: return readBytes(reader, view.getUint16(take(reader, 2)));
+    case 0xc6: return readBytes(reader, view.getUint32(take(reader, 4)));
+    case 0xca: return view.getFloat32(take(reader, 4));
+    case 0xcb: return view.getFloat64(take(reader, 8));
+    case 0xcc: return view.getUint8(take(reader, 1));
+    case 0xcd: return view.getUint16(take(reader, 2));
+    case 0xce: return view.getUint32(take(reader, 4));
+    case 0xcf: return Number(view.getBigUint64(take(reader, 8)));
+    case 0xd0: return view.getInt8(take(reader, 1));
+    case 0xd1: return view.getInt16(take(reader, 2));
+    case 0xd2: return view.getInt32(take(reader, 4));
+    case 0xd3: return Number(view.getBigInt64(take(reader, 8)));
+    case 0xd9: return readString(reader, view.getUint8(take(reader, 1)));
+    case 0xda: return readString(reader, view.getUint16(take(reader, 2)));
+    case 0xdb: return readString(reader, view.getUint32(take(reader, 4)));
+    case 0xdc: return readArray(reader, view.getUint16(take(reader, 2)));
+    case 0xdd: return readArray(reader, view.getUint32(take(reader, 4)));
+    case 0xde: return readMap(reader, view.getUint16(take(reader, 2)));
+    case 0xdf: return readMap(reader, view.getUint32(take(reader, 4)));
+    default: throw new Error(`Unsupported MessagePack type 0x${code.toString(16)}`);
+  }
+}